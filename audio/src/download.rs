@@ -0,0 +1,37 @@
+use hyper::{Body, Method, Request};
+
+use librespot_core::{cache::CacheError, cdn_url::CdnUrl, Error, FileId, Session};
+
+/// Downloads a complete audio file into the cache of `session`, encrypted as it is served,
+/// and pins it so that it isn't evicted. Does nothing but pin it if it is cached already.
+/// When the download fails, the file is unpinned again unless it was pinned before.
+pub async fn download_to_cache(session: &Session, file_id: FileId) -> Result<(), Error> {
+    let cache = session.cache().ok_or(CacheError::Path)?;
+    let was_pinned = cache.is_file_pinned(file_id);
+    // Pinned first, so that the size limit doesn't evict the file right after it is saved.
+    cache.pin_file(file_id)?;
+    if cache.is_file_cached(file_id) {
+        return Ok(());
+    }
+
+    let result = download(session, file_id).await;
+    if result.is_err() && !was_pinned {
+        cache.unpin_file(file_id)?;
+    }
+    result
+}
+
+async fn download(session: &Session, file_id: FileId) -> Result<(), Error> {
+    let cache = session.cache().ok_or(CacheError::Path)?;
+    let cdn_url = CdnUrl::new(file_id).resolve_audio(session).await?;
+    let request = Request::builder()
+        .method(&Method::GET)
+        .uri(cdn_url.try_get_url()?)
+        .body(Body::empty())?;
+
+    debug!("Downloading file {} to the cache", file_id);
+    let data = session.http_client().request_body(request).await?;
+    cache.save_file(file_id, &mut &data[..])?;
+
+    Ok(())
+}
@@ -18,7 +18,13 @@ use tempfile::NamedTempFile;
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot, Semaphore};
 
-use librespot_core::{cdn_url::CdnUrl, Error, FileId, Session};
+use librespot_core::{
+    cdn_url::CdnUrl,
+    error::{ErrorCode, ErrorKind, Recovery},
+    metrics::{Counter, Metrics},
+    supervisor::contain,
+    Error, FileId, Session,
+};
 
 use self::receive::audio_file_fetch;
 
@@ -45,12 +51,33 @@ pub enum AudioFileError {
 impl From<AudioFileError> for Error {
     fn from(err: AudioFileError) -> Self {
         match err {
-            AudioFileError::Channel => Error::aborted(err),
-            AudioFileError::Header => Error::unavailable(err),
-            AudioFileError::NoData => Error::unavailable(err),
-            AudioFileError::Output => Error::aborted(err),
-            AudioFileError::StatusCode(_) => Error::failed_precondition(err),
-            AudioFileError::WaitTimeout => Error::deadline_exceeded(err),
+            AudioFileError::Channel => Error::with_code(ErrorKind::Aborted, err),
+            AudioFileError::Header => Error::with_code(ErrorKind::Unavailable, err),
+            AudioFileError::NoData => Error::with_code(ErrorKind::Unavailable, err),
+            AudioFileError::Output => Error::with_code(ErrorKind::Aborted, err),
+            AudioFileError::StatusCode(_) => Error::with_code(ErrorKind::FailedPrecondition, err),
+            AudioFileError::WaitTimeout => Error::with_code(ErrorKind::DeadlineExceeded, err),
+        }
+    }
+}
+
+impl ErrorCode for AudioFileError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Channel => "audio_file.channel",
+            Self::Header => "audio_file.header",
+            Self::NoData => "audio_file.no_data",
+            Self::Output => "audio_file.output",
+            Self::StatusCode(_) => "audio_file.status_code",
+            Self::WaitTimeout => "audio_file.wait_timeout",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            // the CDN URL may have expired
+            (Self::StatusCode(_), ErrorKind::FailedPrecondition) => Some(Recovery::Retry),
+            _ => None,
         }
     }
 }
@@ -92,6 +119,52 @@ pub const PREFETCH_THRESHOLD_FACTOR: f32 = 4.0;
 pub const DOWNLOAD_TIMEOUT: Duration =
     Duration::from_secs((MINIMUM_DOWNLOAD_SIZE / MINIMUM_THROUGHPUT) as u64);
 
+/// The priority of a stream when competing for bandwidth with other streams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamPriority {
+    /// The stream of the track that is playing.
+    Playback,
+    /// The stream of a track that is preloaded. It only pre-fetches data while none of the
+    /// playback streams are short of their read ahead.
+    Preload,
+}
+
+impl Default for StreamPriority {
+    fn default() -> Self {
+        Self::Playback
+    }
+}
+
+/// Shared by the streams of a player, so that the track that is playing is prioritized
+/// over the track that is preloaded.
+#[derive(Debug, Default)]
+pub struct StreamScheduler {
+    starving_streams: AtomicUsize,
+}
+
+impl StreamScheduler {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Whether any playback stream has less data downloaded ahead of its read position
+    /// than it wants to have during playback.
+    pub fn is_playback_starving(&self) -> bool {
+        self.starving_streams.load(Ordering::Acquire) > 0
+    }
+}
+
+/// Statistics of a streamed file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    /// The number of reads from the file.
+    pub reads: usize,
+    /// The number of reads that had to wait for data to be downloaded.
+    pub stalled_reads: usize,
+    /// The average download throughput in bytes per second.
+    pub throughput: usize,
+}
+
 pub enum AudioFile {
     Cached(fs::File),
     Streaming(AudioFileStreaming),
@@ -156,6 +229,11 @@ impl StreamLoaderController {
         self.stream_shared.as_ref().map(|shared| shared.ping_time())
     }
 
+    /// The statistics of the stream, or `None` if the file was read from the cache.
+    pub fn stream_stats(&self) -> Option<StreamStats> {
+        self.stream_shared.as_ref().map(|shared| shared.stats())
+    }
+
     fn send_stream_loader_command(&self, command: StreamLoaderCommand) {
         if let Some(ref channel) = self.channel_tx {
             // Ignore the error in case the channel has been closed already.
@@ -228,11 +306,41 @@ impl StreamLoaderController {
                 };
                 self.fetch(request_range);
 
-                let wait_range = Range {
+                // If the data at the read position has been downloaded already, e.g. after
+                // seeking back, reading can continue without waiting for the network. Reads
+                // wait for the data that is still missing by themselves.
+                if shared.download_status.lock().downloaded.contains(start) {
+                    return Ok(());
+                }
+
+                self.fetch_blocking(Range {
                     start,
                     length: wait_length,
-                };
-                self.fetch_blocking(wait_range)
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`fetch_next_and_wait`](Self::fetch_next_and_wait), but waits for `wait_length`
+    /// also when the data at the read position has been downloaded already, e.g. to buffer
+    /// before playback starts.
+    pub fn fetch_next_and_buffer(
+        &self,
+        request_length: usize,
+        wait_length: usize,
+    ) -> AudioFileResult {
+        match self.stream_shared {
+            Some(ref shared) => {
+                let start = shared.read_position();
+                self.fetch(Range {
+                    start,
+                    length: request_length.max(wait_length),
+                });
+                self.fetch_blocking(Range {
+                    start,
+                    length: wait_length,
+                })
             }
             None => Ok(()),
         }
@@ -245,6 +353,12 @@ impl StreamLoaderController {
         }
     }
 
+    pub fn set_priority(&self, priority: StreamPriority) {
+        if let Some(ref shared) = self.stream_shared {
+            shared.set_priority(priority)
+        }
+    }
+
     pub fn set_stream_mode(&self) {
         // optimise download strategy for streaming
         if let Some(ref shared) = self.stream_shared {
@@ -278,9 +392,15 @@ struct AudioFileShared {
     download_status: Mutex<AudioFileDownloadStatus>,
     download_streaming: AtomicBool,
     download_slots: Semaphore,
+    scheduler: Arc<StreamScheduler>,
+    is_preload: AtomicBool,
+    is_starving: AtomicBool,
     ping_time_ms: AtomicUsize,
     read_position: AtomicUsize,
     throughput: AtomicUsize,
+    reads: AtomicUsize,
+    stalled_reads: AtomicUsize,
+    metrics: Metrics,
 }
 
 impl AudioFileShared {
@@ -292,6 +412,63 @@ impl AudioFileShared {
         self.download_streaming.store(streaming, Ordering::Release)
     }
 
+    fn priority(&self) -> StreamPriority {
+        if self.is_preload.load(Ordering::Acquire) {
+            StreamPriority::Preload
+        } else {
+            StreamPriority::Playback
+        }
+    }
+
+    fn set_priority(&self, priority: StreamPriority) {
+        let is_preload = priority == StreamPriority::Preload;
+        self.is_preload.store(is_preload, Ordering::Release);
+        if is_preload {
+            self.set_starving(false);
+        }
+    }
+
+    fn set_starving(&self, starving: bool) {
+        if self.is_starving.swap(starving, Ordering::AcqRel) != starving {
+            if starving {
+                self.scheduler
+                    .starving_streams
+                    .fetch_add(1, Ordering::AcqRel);
+            } else {
+                self.scheduler
+                    .starving_streams
+                    .fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    // A playback stream starves while it has less downloaded ahead of the read position
+    // than it wants during playback, unless the rest of the file is downloaded.
+    fn update_starving(&self) {
+        if self.priority() != StreamPriority::Playback {
+            return;
+        }
+
+        let read_position = self.read_position();
+        let bytes_ahead = self
+            .download_status
+            .lock()
+            .downloaded
+            .contained_length_from_value(read_position);
+        let read_ahead =
+            (READ_AHEAD_DURING_PLAYBACK.as_secs_f32() * self.bytes_per_second as f32) as usize;
+
+        self.set_starving(
+            self.is_download_streaming()
+                && bytes_ahead < min(read_ahead, self.file_size.saturating_sub(read_position)),
+        );
+    }
+
+    // A preloading stream holds back while a playback stream starves.
+    fn is_yielding_bandwidth(&self) -> bool {
+        self.priority() == StreamPriority::Preload && self.scheduler.is_playback_starving()
+    }
+
     fn ping_time(&self) -> Duration {
         let ping_time_ms = self.ping_time_ms.load(Ordering::Acquire);
         if ping_time_ms > 0 {
@@ -322,6 +499,14 @@ impl AudioFileShared {
         self.read_position
             .store(position as usize, Ordering::Release)
     }
+
+    fn stats(&self) -> StreamStats {
+        StreamStats {
+            reads: self.reads.load(Ordering::Acquire),
+            stalled_reads: self.stalled_reads.load(Ordering::Acquire),
+            throughput: self.throughput(),
+        }
+    }
 }
 
 impl AudioFile {
@@ -329,18 +514,47 @@ impl AudioFile {
         session: &Session,
         file_id: FileId,
         bytes_per_second: usize,
+    ) -> Result<AudioFile, Error> {
+        Self::open_with_priority(
+            session,
+            file_id,
+            bytes_per_second,
+            StreamScheduler::new(),
+            StreamPriority::default(),
+        )
+        .await
+    }
+
+    /// Opens a file whose stream competes for bandwidth with the other streams that share
+    /// `scheduler`.
+    pub async fn open_with_priority(
+        session: &Session,
+        file_id: FileId,
+        bytes_per_second: usize,
+        scheduler: Arc<StreamScheduler>,
+        priority: StreamPriority,
     ) -> Result<AudioFile, Error> {
         if let Some(file) = session.cache().and_then(|cache| cache.file(file_id)) {
             debug!("File {} already in cache", file_id);
+            session.metrics().increment(Counter::CacheHits, 1);
             return Ok(AudioFile::Cached(file));
         }
+        if session.cache().is_some() {
+            session.metrics().increment(Counter::CacheMisses, 1);
+        }
 
         debug!("Downloading file {}", file_id);
 
         let (complete_tx, complete_rx) = oneshot::channel();
 
-        let streaming =
-            AudioFileStreaming::open(session.clone(), file_id, complete_tx, bytes_per_second);
+        let streaming = AudioFileStreaming::open(
+            session.clone(),
+            file_id,
+            complete_tx,
+            bytes_per_second,
+            scheduler,
+            priority,
+        );
 
         let session_ = session.clone();
         session.spawn(complete_rx.map_ok(move |mut file| {
@@ -388,6 +602,8 @@ impl AudioFileStreaming {
         file_id: FileId,
         complete_tx: oneshot::Sender<NamedTempFile>,
         bytes_per_second: usize,
+        scheduler: Arc<StreamScheduler>,
+        priority: StreamPriority,
     ) -> Result<AudioFileStreaming, Error> {
         let cdn_url = CdnUrl::new(file_id).resolve_audio(&session).await?;
 
@@ -446,9 +662,15 @@ impl AudioFileStreaming {
             }),
             download_streaming: AtomicBool::new(false),
             download_slots: Semaphore::new(1),
+            scheduler,
+            is_preload: AtomicBool::new(priority == StreamPriority::Preload),
+            is_starving: AtomicBool::new(false),
             ping_time_ms: AtomicUsize::new(0),
             read_position: AtomicUsize::new(0),
             throughput: AtomicUsize::new(0),
+            reads: AtomicUsize::new(0),
+            stalled_reads: AtomicUsize::new(0),
+            metrics: session.metrics().clone(),
         });
 
         let write_file = NamedTempFile::new_in(session.config().tmp_dir.clone())?;
@@ -459,13 +681,18 @@ impl AudioFileStreaming {
         let (stream_loader_command_tx, stream_loader_command_rx) =
             mpsc::unbounded_channel::<StreamLoaderCommand>();
 
-        session.spawn(audio_file_fetch(
-            session.clone(),
-            shared.clone(),
-            initial_request,
-            write_file,
-            stream_loader_command_rx,
-            complete_tx,
+        // The fetch owns the file being written, so it can't be restarted after a panic.
+        // Containing it drops the command channel, which fails further reads instead.
+        session.spawn(contain(
+            format!("audio fetch {}", file_id),
+            audio_file_fetch(
+                session.clone(),
+                shared.clone(),
+                initial_request,
+                write_file,
+                stream_loader_command_rx,
+                complete_tx,
+            ),
         ));
 
         Ok(AudioFileStreaming {
@@ -509,10 +736,24 @@ impl Read for AudioFileStreaming {
         ranges_to_request.subtract_range_set(&download_status.downloaded);
         ranges_to_request.subtract_range_set(&download_status.requested);
 
+        let available = download_status.downloaded.contains(offset);
         for &range in ranges_to_request.iter() {
-            self.stream_loader_command_tx
+            if let Err(err) = self
+                .stream_loader_command_tx
                 .send(StreamLoaderCommand::Fetch(range))
-                .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+            {
+                // Data that was downloaded already can still be read while the loader is gone.
+                if !available {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, err));
+                }
+                break;
+            }
+        }
+
+        self.shared.reads.fetch_add(1, Ordering::AcqRel);
+        if !available {
+            self.shared.stalled_reads.fetch_add(1, Ordering::AcqRel);
+            self.shared.metrics.increment(Counter::Underruns, 1);
         }
 
         while !download_status.downloaded.contains(offset) {
@@ -606,3 +847,216 @@ impl Seek for AudioFile {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const FILE_SIZE: usize = 1000;
+
+    fn stream(
+        downloaded: &[Range],
+        scheduler: Arc<StreamScheduler>,
+        priority: StreamPriority,
+    ) -> Arc<AudioFileShared> {
+        let mut download_status = AudioFileDownloadStatus {
+            requested: RangeSet::new(),
+            downloaded: RangeSet::new(),
+        };
+        for range in downloaded {
+            download_status.downloaded.add_range(range);
+        }
+
+        Arc::new(AudioFileShared {
+            cdn_url: CdnUrl::new(FileId([0; 20])),
+            file_size: FILE_SIZE,
+            bytes_per_second: 100,
+            cond: Condvar::new(),
+            download_status: Mutex::new(download_status),
+            download_streaming: AtomicBool::new(true),
+            download_slots: Semaphore::new(1),
+            scheduler,
+            is_preload: AtomicBool::new(priority == StreamPriority::Preload),
+            is_starving: AtomicBool::new(false),
+            ping_time_ms: AtomicUsize::new(0),
+            read_position: AtomicUsize::new(0),
+            throughput: AtomicUsize::new(0),
+            reads: AtomicUsize::new(0),
+            stalled_reads: AtomicUsize::new(0),
+            metrics: Metrics::default(),
+        })
+    }
+
+    fn controller(
+        downloaded: &[Range],
+    ) -> (
+        StreamLoaderController,
+        Arc<AudioFileShared>,
+        mpsc::UnboundedReceiver<StreamLoaderCommand>,
+    ) {
+        let shared = stream(downloaded, StreamScheduler::new(), StreamPriority::Playback);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let controller = StreamLoaderController {
+            channel_tx: Some(tx),
+            stream_shared: Some(shared.clone()),
+            file_size: FILE_SIZE,
+        };
+        (controller, shared, rx)
+    }
+
+    fn requested(rx: &mut mpsc::UnboundedReceiver<StreamLoaderCommand>) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        while let Ok(command) = rx.try_recv() {
+            if let StreamLoaderCommand::Fetch(range) = command {
+                ranges.push((range.start, range.length));
+            }
+        }
+        ranges
+    }
+
+    #[test]
+    fn checks_the_downloaded_ranges() {
+        let (controller, shared, _rx) = controller(&[Range::new(0, 100), Range::new(500, 500)]);
+
+        assert!(controller.range_available(Range::new(0, 100)));
+        assert!(controller.range_available(Range::new(600, 50)));
+        assert!(!controller.range_available(Range::new(50, 100)));
+        assert!(!controller.range_available(Range::new(200, 1)));
+
+        assert!(!controller.range_to_end_available());
+        shared.set_read_position(500);
+        assert!(controller.range_to_end_available());
+    }
+
+    #[test]
+    fn reads_downloaded_data_without_waiting() {
+        // Only the start of the data to wait for has been downloaded, as after seeking back
+        // to where the download stopped.
+        let (controller, shared, mut rx) = controller(&[Range::new(0, 300)]);
+        shared.set_read_position(250);
+
+        controller.fetch_next_and_wait(400, 200).unwrap();
+
+        assert_eq!(requested(&mut rx), [(250, 400)]);
+    }
+
+    #[test]
+    fn waits_for_data_that_is_not_downloaded() {
+        let (controller, shared, mut rx) = controller(&[Range::new(0, 300)]);
+        shared.set_read_position(600);
+
+        let download = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                let mut download_status = shared.download_status.lock();
+                download_status.downloaded.add_range(&Range::new(600, 100));
+                shared.cond.notify_all();
+                drop(download_status);
+
+                thread::sleep(Duration::from_millis(50));
+                let mut download_status = shared.download_status.lock();
+                download_status.downloaded.add_range(&Range::new(700, 300));
+                shared.cond.notify_all();
+            })
+        };
+
+        // Near the end of the file, it waits for the data up to its end.
+        controller.fetch_next_and_wait(800, 800).unwrap();
+        assert!(controller.range_to_end_available());
+        download.join().unwrap();
+
+        let requested = requested(&mut rx);
+        assert_eq!(requested[0], (600, 800));
+        assert!(requested[1..].iter().all(|&range| range == (600, 400)));
+    }
+
+    #[test]
+    fn buffers_even_if_the_read_position_is_downloaded() {
+        let (controller, shared, _rx) = controller(&[Range::new(0, 300)]);
+        shared.set_read_position(250);
+
+        let download = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                let mut download_status = shared.download_status.lock();
+                download_status.downloaded.add_range(&Range::new(300, 200));
+                shared.cond.notify_all();
+            })
+        };
+
+        controller.fetch_next_and_buffer(400, 200).unwrap();
+        assert!(controller.range_available(Range::new(250, 200)));
+        download.join().unwrap();
+    }
+
+    #[test]
+    fn preloading_yields_to_a_starving_playback_stream() {
+        // reads ahead 500 bytes at 100 bytes per second
+        let scheduler = StreamScheduler::new();
+        let playing = stream(
+            &[Range::new(0, 600)],
+            scheduler.clone(),
+            StreamPriority::Playback,
+        );
+        let preloading = stream(&[], scheduler.clone(), StreamPriority::Preload);
+
+        playing.update_starving();
+        assert!(!scheduler.is_playback_starving());
+
+        playing.set_read_position(200);
+        playing.update_starving();
+        playing.update_starving();
+        assert!(scheduler.is_playback_starving());
+        assert!(preloading.is_yielding_bandwidth());
+        assert!(!playing.is_yielding_bandwidth());
+
+        // only the rest of the file is needed near its end
+        playing
+            .download_status
+            .lock()
+            .downloaded
+            .add_range(&Range::new(600, 400));
+        playing.set_read_position(700);
+        playing.update_starving();
+        assert!(!scheduler.is_playback_starving());
+        assert!(!preloading.is_yielding_bandwidth());
+    }
+
+    #[test]
+    fn only_streaming_playback_streams_starve() {
+        let scheduler = StreamScheduler::new();
+        let playing = stream(&[], scheduler.clone(), StreamPriority::Playback);
+        let preloading = stream(&[], scheduler.clone(), StreamPriority::Preload);
+
+        preloading.update_starving();
+        assert!(!scheduler.is_playback_starving());
+
+        playing.update_starving();
+        assert!(scheduler.is_playback_starving());
+        // e.g. when playing the track that was preloaded, the old one is preloaded again
+        playing.set_priority(StreamPriority::Preload);
+        assert!(!scheduler.is_playback_starving());
+
+        playing.set_priority(StreamPriority::Playback);
+        playing.download_streaming.store(false, Ordering::Release);
+        playing.update_starving();
+        assert!(!scheduler.is_playback_starving());
+    }
+
+    #[test]
+    fn classifies_errors() {
+        // the CDN URL may have expired
+        let err = Error::from(AudioFileError::StatusCode(StatusCode::FORBIDDEN));
+        assert_eq!(err.code(), "audio_file.status_code");
+        assert_eq!(err.kind, ErrorKind::FailedPrecondition);
+        assert!(err.is_retryable());
+
+        let err = Error::from(AudioFileError::WaitTimeout);
+        assert_eq!(err.code(), "audio_file.wait_timeout");
+        assert!(err.is_retryable());
+    }
+}
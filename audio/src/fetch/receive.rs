@@ -1,5 +1,6 @@
 use std::{
     cmp::{max, min},
+    future::Future,
     io::{Seek, SeekFrom, Write},
     sync::Arc,
     time::{Duration, Instant},
@@ -10,8 +11,9 @@ use futures_util::StreamExt;
 use hyper::StatusCode;
 use tempfile::NamedTempFile;
 use tokio::sync::{mpsc, oneshot};
+use tracing::{debug_span, Instrument};
 
-use librespot_core::{http_client::HttpClient, session::Session, Error};
+use librespot_core::{http_client::HttpClient, metrics::Counter, session::Session, Error};
 
 use crate::range_set::{Range, RangeSet};
 
@@ -34,7 +36,22 @@ enum ReceivedData {
 
 const ONE_SECOND: Duration = Duration::from_secs(1);
 
-async fn receive_data(
+// Downloads a chunk in a `chunk_download` span, to see how long chunks take.
+fn receive_data(
+    shared: Arc<AudioFileShared>,
+    file_data_tx: mpsc::UnboundedSender<ReceivedData>,
+    request: StreamingRequest,
+) -> impl Future<Output = AudioFileResult> {
+    let span = debug_span!(
+        "chunk_download",
+        file = %shared.cdn_url.file_id,
+        offset = request.offset,
+        length = request.length,
+    );
+    download_chunk(shared, file_data_tx, request).instrument(span)
+}
+
+async fn download_chunk(
     shared: Arc<AudioFileShared>,
     file_data_tx: mpsc::UnboundedSender<ReceivedData>,
     mut request: StreamingRequest,
@@ -43,6 +60,11 @@ async fn receive_data(
     let mut actual_length = 0;
 
     let permit = shared.download_slots.acquire().await?;
+    let _cdn_download = shared
+        .cdn_url
+        .try_get_url()
+        .ok()
+        .map(|url| shared.metrics.cdn_download(url));
 
     let request_time = Instant::now();
     let mut measure_ping_time = true;
@@ -104,6 +126,9 @@ async fn receive_data(
         };
 
         let data_size = data.len();
+        shared
+            .metrics
+            .increment(Counter::BytesDownloaded, data_size as u64);
         file_data_tx.send(ReceivedData::Data(PartialFileData { offset, data }))?;
 
         actual_length += data_size;
@@ -173,7 +198,9 @@ impl AudioFileFetch {
         // If we are in streaming mode (so not seeking) then start downloading as large
         // of chunks as possible for better throughput and improved CPU usage, while
         // still being reasonably responsive (~1 second) in case we want to seek.
-        if self.shared.is_download_streaming() {
+        // A preloading stream keeps its requests small while the playing track needs
+        // the bandwidth.
+        if self.shared.is_download_streaming() && !self.shared.is_yielding_bandwidth() {
             let throughput = self.shared.throughput();
             length = max(length, throughput);
         }
@@ -397,6 +424,13 @@ impl AudioFileFetch {
     }
 }
 
+impl Drop for AudioFileFetch {
+    fn drop(&mut self) {
+        // a stream that stopped downloading cannot hold back the other streams
+        self.shared.set_starving(false);
+    }
+}
+
 pub(super) async fn audio_file_fetch(
     session: Session,
     shared: Arc<AudioFileShared>,
@@ -458,7 +492,12 @@ pub(super) async fn audio_file_fetch(
             else => (),
         }
 
-        if fetch.shared.is_download_streaming() && fetch.has_download_slots_available() {
+        fetch.shared.update_starving();
+
+        if fetch.shared.is_download_streaming()
+            && fetch.has_download_slots_available()
+            && !fetch.shared.is_yielding_bandwidth()
+        {
             let bytes_pending: usize = {
                 let download_status = fetch.shared.download_status.lock();
 
@@ -2,10 +2,14 @@
 extern crate log;
 
 mod decrypt;
+mod download;
 mod fetch;
 
 mod range_set;
 
-pub use decrypt::AudioDecrypt;
-pub use fetch::{AudioFile, AudioFileError, StreamLoaderController};
+pub use decrypt::{AudioDecrypt, AUDIO_AESIV};
+pub use download::download_to_cache;
+pub use fetch::{
+    AudioFile, AudioFileError, StreamLoaderController, StreamPriority, StreamScheduler, StreamStats,
+};
 pub use fetch::{MINIMUM_DOWNLOAD_SIZE, READ_AHEAD_BEFORE_PLAYBACK, READ_AHEAD_DURING_PLAYBACK};
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+
+use futures_util::future::join_all;
+
+use crate::{
+    core::{
+        spotify_id::{ArtistId, TrackId},
+        Error, Session, SpotifyId,
+    },
+    metadata::{Artist, Metadata, Track},
+    protocol::spirc::TrackRef,
+};
+
+/// Constraints on the tracks that autoplay continues a finished context with, for when
+/// predictable output matters more than variety, e.g. background music in a venue.
+///
+/// The station endpoint has no parameters for these, so they are applied to the tracks it
+/// returns, compared to the tracks of the finished context.
+#[derive(Clone, Debug, Default)]
+pub struct AutoplayConstraints {
+    /// Only play tracks by the artists of the finished context.
+    pub same_artist: bool,
+    /// Only play tracks by artists that share a genre with the artists of the finished
+    /// context.
+    pub same_genre: bool,
+    pub exclude_explicit: bool,
+    /// The largest difference between the popularity (0-100) of a track and the average
+    /// popularity of the finished context.
+    pub max_popularity_drift: Option<u8>,
+}
+
+impl AutoplayConstraints {
+    pub fn is_constrained(&self) -> bool {
+        self.same_artist
+            || self.same_genre
+            || self.exclude_explicit
+            || self.max_popularity_drift.is_some()
+    }
+}
+
+/// What autoplay should stay close to, taken from the finished context.
+#[derive(Debug, Default)]
+pub(crate) struct AutoplaySeed {
+    artists: HashSet<SpotifyId>,
+    genres: HashSet<String>,
+    popularity: Option<i32>,
+    // The genres of every artist seen so far, as station pages often repeat them.
+    artist_genres: HashMap<SpotifyId, Vec<String>>,
+}
+
+async fn get_tracks(session: &Session, ids: impl Iterator<Item = SpotifyId>) -> Vec<Track> {
+    join_all(ids.map(|id| async move { Track::get(session, &TrackId::try_from(id)?).await }))
+        .await
+        .into_iter()
+        .filter_map(|track| {
+            track
+                .map_err(|e| warn!("Unable to get autoplay track metadata: {}", e))
+                .ok()
+        })
+        .collect()
+}
+
+impl AutoplaySeed {
+    pub(crate) async fn new(
+        session: &Session,
+        constraints: &AutoplayConstraints,
+        tracks: &[SpotifyId],
+    ) -> Self {
+        let mut seed = Self::default();
+        if !constraints.is_constrained() {
+            return seed;
+        }
+
+        let tracks = get_tracks(session, tracks.iter().copied()).await;
+
+        seed.artists = tracks
+            .iter()
+            .flat_map(|track| track.artists.iter().map(|artist| artist.id))
+            .collect();
+
+        if !tracks.is_empty() {
+            let total: i32 = tracks.iter().map(|track| track.popularity).sum();
+            seed.popularity = Some(total / tracks.len() as i32);
+        }
+
+        if constraints.same_genre {
+            let artists: Vec<SpotifyId> = seed.artists.iter().copied().collect();
+            seed.fetch_genres(session, &artists).await;
+            seed.genres = artists
+                .iter()
+                .filter_map(|artist| seed.artist_genres.get(artist))
+                .flatten()
+                .cloned()
+                .collect();
+        }
+
+        debug!(
+            "Autoplay seeded with {} artists, {} genres and popularity {:?}",
+            seed.artists.len(),
+            seed.genres.len(),
+            seed.popularity
+        );
+        seed
+    }
+
+    async fn fetch_genres(&mut self, session: &Session, artists: &[SpotifyId]) {
+        let missing = artists
+            .iter()
+            .filter(|artist| !self.artist_genres.contains_key(artist));
+        let fetched = join_all(
+            missing.map(|id| async move { Artist::get(session, &ArtistId::try_from(id)?).await }),
+        );
+
+        for artist in fetched.await {
+            match artist {
+                Ok(artist) => {
+                    self.artist_genres.insert(artist.id, artist.genre);
+                }
+                Err(e) => warn!("Unable to get autoplay artist metadata: {}", e),
+            }
+        }
+    }
+
+    fn allows(&self, constraints: &AutoplayConstraints, track: &Track) -> bool {
+        if constraints.exclude_explicit && track.is_explicit {
+            return false;
+        }
+
+        if constraints.same_artist
+            && !track
+                .artists
+                .iter()
+                .any(|artist| self.artists.contains(&artist.id))
+        {
+            return false;
+        }
+
+        if constraints.same_genre
+            && !track.artists.iter().any(|artist| {
+                self.artist_genres.get(&artist.id).map_or(false, |genres| {
+                    genres.iter().any(|genre| self.genres.contains(genre))
+                })
+            })
+        {
+            return false;
+        }
+
+        match (constraints.max_popularity_drift, self.popularity) {
+            (Some(drift), Some(popularity)) => {
+                (track.popularity - popularity).abs() <= i32::from(drift)
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns the tracks that satisfy `constraints`, in their original order. Tracks
+    /// without metadata are left out.
+    pub(crate) async fn filter(
+        &mut self,
+        session: &Session,
+        constraints: &AutoplayConstraints,
+        tracks: Vec<TrackRef>,
+    ) -> Result<Vec<TrackRef>, Error> {
+        if !constraints.is_constrained() {
+            return Ok(tracks);
+        }
+
+        let ids = tracks
+            .iter()
+            .map(SpotifyId::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let metadata: HashMap<SpotifyId, Track> = get_tracks(session, ids.iter().copied())
+            .await
+            .into_iter()
+            .map(|track| (track.id, track))
+            .collect();
+
+        if constraints.same_genre {
+            let artists: Vec<SpotifyId> = metadata
+                .values()
+                .flat_map(|track| track.artists.iter().map(|artist| artist.id))
+                .collect();
+            self.fetch_genres(session, &artists).await;
+        }
+
+        let before = tracks.len();
+        let allowed: Vec<TrackRef> = tracks
+            .into_iter()
+            .zip(ids)
+            .filter(|(_, id)| {
+                metadata
+                    .get(id)
+                    .map_or(false, |track| self.allows(constraints, track))
+            })
+            .map(|(track, _)| track)
+            .collect();
+
+        debug!(
+            "Autoplay constraints left {} of {} tracks",
+            allowed.len(),
+            before
+        );
+        Ok(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::metadata::{
+        Album as AlbumMessage, Artist as ArtistMessage, Track as TrackMessage,
+    };
+
+    fn track(popularity: i32, is_explicit: bool, artists: &[u8]) -> Track {
+        let mut msg = TrackMessage::new();
+        msg.set_gid(vec![1; 16]);
+        msg.set_popularity(popularity);
+        msg.set_explicit(is_explicit);
+        let mut album = AlbumMessage::new();
+        album.set_gid(vec![2; 16]);
+        msg.album = Some(album).into();
+        for &artist in artists {
+            let mut artist_msg = ArtistMessage::new();
+            artist_msg.set_gid(vec![artist; 16]);
+            msg.artist.push(artist_msg);
+        }
+        Track::try_from(&msg).unwrap()
+    }
+
+    fn artist(n: u8) -> SpotifyId {
+        track(0, false, &[n]).artists[0].id
+    }
+
+    // Seeded with tracks by artist 1, which plays rock, and a popularity of 50.
+    fn seed() -> AutoplaySeed {
+        let mut seed = AutoplaySeed {
+            artists: [artist(1)].into_iter().collect(),
+            genres: ["rock".to_owned()].into_iter().collect(),
+            popularity: Some(50),
+            artist_genres: HashMap::new(),
+        };
+        for (n, genres) in [(1, &["rock"][..]), (2, &["rock", "pop"]), (3, &["jazz"])] {
+            seed.artist_genres
+                .insert(artist(n), genres.iter().map(|g| g.to_string()).collect());
+        }
+        seed
+    }
+
+    #[test]
+    fn allows_anything_without_constraints() {
+        let constraints = AutoplayConstraints::default();
+        assert!(!constraints.is_constrained());
+        assert!(seed().allows(&constraints, &track(100, true, &[4])));
+    }
+
+    #[test]
+    fn excludes_explicit_tracks() {
+        let constraints = AutoplayConstraints {
+            exclude_explicit: true,
+            ..Default::default()
+        };
+        assert!(seed().allows(&constraints, &track(50, false, &[4])));
+        assert!(!seed().allows(&constraints, &track(50, true, &[1])));
+    }
+
+    #[test]
+    fn keeps_to_the_same_artists() {
+        let constraints = AutoplayConstraints {
+            same_artist: true,
+            ..Default::default()
+        };
+        assert!(seed().allows(&constraints, &track(50, false, &[1])));
+        // one of the artists is enough
+        assert!(seed().allows(&constraints, &track(50, false, &[2, 1])));
+        assert!(!seed().allows(&constraints, &track(50, false, &[2])));
+        assert!(!seed().allows(&constraints, &track(50, false, &[])));
+    }
+
+    #[test]
+    fn keeps_to_the_same_genres() {
+        let constraints = AutoplayConstraints {
+            same_genre: true,
+            ..Default::default()
+        };
+        assert!(seed().allows(&constraints, &track(50, false, &[2])));
+        assert!(seed().allows(&constraints, &track(50, false, &[3, 2])));
+        assert!(!seed().allows(&constraints, &track(50, false, &[3])));
+        // artists of unknown genres aren't allowed
+        assert!(!seed().allows(&constraints, &track(50, false, &[4])));
+    }
+
+    #[test]
+    fn keeps_close_to_the_popularity() {
+        let constraints = AutoplayConstraints {
+            max_popularity_drift: Some(10),
+            ..Default::default()
+        };
+        assert!(seed().allows(&constraints, &track(40, false, &[4])));
+        assert!(seed().allows(&constraints, &track(60, false, &[4])));
+        assert!(!seed().allows(&constraints, &track(39, false, &[4])));
+        assert!(!seed().allows(&constraints, &track(61, false, &[4])));
+
+        // without the popularity of the finished context, there's nothing to drift from
+        let seed = AutoplaySeed {
+            popularity: None,
+            ..seed()
+        };
+        assert!(seed.allows(&constraints, &track(0, false, &[4])));
+    }
+
+    #[test]
+    fn combines_the_constraints() {
+        let constraints = AutoplayConstraints {
+            same_genre: true,
+            exclude_explicit: true,
+            max_popularity_drift: Some(10),
+            ..Default::default()
+        };
+        assert!(seed().allows(&constraints, &track(55, false, &[2])));
+        assert!(!seed().allows(&constraints, &track(55, true, &[2])));
+        assert!(!seed().allows(&constraints, &track(70, false, &[2])));
+        assert!(!seed().allows(&constraints, &track(55, false, &[3])));
+    }
+}
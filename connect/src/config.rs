@@ -1,4 +1,9 @@
-use crate::core::config::DeviceType;
+use std::sync::Arc;
+
+use crate::{
+    autoplay::AutoplayConstraints, core::config::DeviceType, state_store::ConnectStateStore,
+    trace::SpircTraceMode,
+};
 
 #[derive(Clone, Debug)]
 pub struct ConnectConfig {
@@ -6,6 +11,15 @@ pub struct ConnectConfig {
     pub device_type: DeviceType,
     pub initial_volume: Option<u16>,
     pub has_volume_ctrl: bool,
+    pub trace: Option<SpircTraceMode>,
+    /// Hides the device from the public device list. It can still be controlled through
+    /// the local network, e.g. with zeroconf discovery.
+    pub hidden: bool,
+    /// Doesn't share the listening history with Spotify, e.g. to seed autoplay.
+    pub private_session: bool,
+    pub autoplay_constraints: AutoplayConstraints,
+    /// Where to keep what is playing, so that it is loaded paused when Spirc starts again.
+    pub state_store: Option<Arc<dyn ConnectStateStore>>,
 }
 
 impl Default for ConnectConfig {
@@ -15,6 +29,11 @@ impl Default for ConnectConfig {
             device_type: DeviceType::default(),
             initial_volume: Some(50),
             has_volume_ctrl: true,
+            trace: None,
+            hidden: false,
+            private_session: false,
+            autoplay_constraints: AutoplayConstraints::default(),
+            state_store: None,
         }
     }
 }
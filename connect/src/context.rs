@@ -1,12 +1,11 @@
 // TODO : move to metadata
 
+use std::collections::HashMap;
+
 use crate::core::spotify_id::SpotifyId;
 use crate::protocol::spirc::TrackRef;
 
-use serde::{
-    de::{Error, Unexpected},
-    Deserialize,
-};
+use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Default, Clone)]
 pub struct StationContext {
@@ -26,14 +25,59 @@ pub struct StationContext {
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
+#[serde(from = "RawPageContext")]
 pub struct PageContext {
-    #[serde(deserialize_with = "deserialize_protobuf_TrackRef")]
     pub tracks: Vec<TrackRef>,
     pub next_page_url: String,
     pub correlation_id: String,
+    /// The items of enhanced contexts that aren't playable but carry metadata, like the
+    /// commentary of the DJ, by URI.
+    pub segments: HashMap<String, ContextSegment>,
 }
 
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct RawPageContext {
+    tracks: Vec<TrackContext>,
+    next_page_url: String,
+    correlation_id: String,
+}
+
+impl From<RawPageContext> for PageContext {
+    fn from(context: RawPageContext) -> Self {
+        let segments = context
+            .tracks
+            .iter()
+            .filter(|track| !track.is_playable() && !track.metadata.is_empty())
+            .map(|track| {
+                let segment = ContextSegment {
+                    uri: track.uri.clone(),
+                    metadata: track.metadata.clone(),
+                };
+                (track.uri.clone(), segment)
+            })
+            .collect();
+
+        Self {
+            tracks: context.tracks.iter().map(track_ref).collect(),
+            next_page_url: context.next_page_url,
+            correlation_id: context.correlation_id,
+            segments,
+        }
+    }
+}
+
+/// An item of a context that is announced rather than played, e.g. the DJ introducing the
+/// tracks that follow.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ContextSegment {
+    pub uri: String,
+    pub metadata: HashMap<String, String>,
+}
+
+// Enhanced contexts mix tracks with other items, so every field is optional.
 #[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
 pub struct TrackContext {
     pub uri: String,
     pub uid: String,
@@ -41,10 +85,25 @@ pub struct TrackContext {
     pub album_uri: String,
     #[serde(rename = "original_gid")]
     pub gid: String,
-    pub metadata: MetadataContext,
+    #[serde(deserialize_with = "string_map")]
+    pub metadata: HashMap<String, String>,
     pub name: String,
 }
 
+impl TrackContext {
+    // `from_base62` accepts shorter input, which would turn a missing gid into a zeroed one.
+    fn id(&self) -> Option<SpotifyId> {
+        if self.gid.len() != SpotifyId::SIZE_BASE62 {
+            return None;
+        }
+        SpotifyId::from_base62(&self.gid).ok()
+    }
+
+    fn is_playable(&self) -> bool {
+        self.id().is_some() || SpotifyId::from_uri(&self.uri).map_or(false, |id| id.is_playable())
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -57,21 +116,6 @@ pub struct ArtistContext {
     artist_uri: String,
 }
 
-#[allow(dead_code)]
-#[derive(Deserialize, Debug, Default, Clone)]
-pub struct MetadataContext {
-    album_title: String,
-    artist_name: String,
-    artist_uri: String,
-    image_url: String,
-    title: String,
-    #[serde(deserialize_with = "bool_from_string")]
-    is_explicit: bool,
-    #[serde(deserialize_with = "bool_from_string")]
-    is_promotional: bool,
-    decision_id: String,
-}
-
 #[allow(dead_code)]
 #[derive(Deserialize, Debug, Default, Clone)]
 pub struct SubtitleContext {
@@ -79,18 +123,29 @@ pub struct SubtitleContext {
     uri: String,
 }
 
-fn bool_from_string<'de, D>(de: D) -> Result<bool, D::Error>
+// The metadata values are strings, but other values shouldn't fail the whole context.
+fn string_map<'d, D>(de: D) -> Result<HashMap<String, String>, D::Error>
 where
-    D: serde::Deserializer<'de>,
+    D: serde::Deserializer<'d>,
 {
-    match String::deserialize(de)?.as_ref() {
-        "true" => Ok(true),
-        "false" => Ok(false),
-        other => Err(D::Error::invalid_value(
-            Unexpected::Str(other),
-            &"true or false",
-        )),
+    let map: HashMap<String, serde_json::Value> = Deserialize::deserialize(de)?;
+    Ok(map
+        .into_iter()
+        .filter_map(|(key, value)| match value {
+            serde_json::Value::String(value) => Some((key, value)),
+            _ => None,
+        })
+        .collect())
+}
+
+// Items without a valid gid keep their URI, so that they can be skipped when playing.
+fn track_ref(track: &TrackContext) -> TrackRef {
+    let mut t = TrackRef::new();
+    if let Some(id) = track.id() {
+        t.set_gid(id.to_raw().to_vec());
     }
+    t.set_uri(track.uri.to_owned());
+    t
 }
 
 #[allow(non_snake_case)]
@@ -99,23 +154,39 @@ where
     D: serde::Deserializer<'d>,
 {
     let v: Vec<TrackContext> = serde::Deserialize::deserialize(de)?;
-    v.iter()
-        .map(|v| {
-            let mut t = TrackRef::new();
-            //  This has got to be the most round about way of doing this.
-            t.set_gid(
-                SpotifyId::from_base62(&v.gid)
-                    .map_err(|_| {
-                        D::Error::invalid_value(
-                            Unexpected::Str(&v.gid),
-                            &"a Base-62 encoded Spotify ID",
-                        )
-                    })?
-                    .to_raw()
-                    .to_vec(),
-            );
-            t.set_uri(v.uri.to_owned());
-            Ok(t)
-        })
-        .collect::<Result<Vec<TrackRef>, D::Error>>()
+    Ok(v.iter().map(track_ref).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enhanced_context() {
+        let json = r#"{
+            "tracks": [
+                {
+                    "uri": "spotify:narration:dj-intro",
+                    "metadata": { "title": "Up next, some classics", "duration_ms": 5000 }
+                },
+                {
+                    "uri": "spotify:track:5sWHDYs0csV6RS48xBl0tH",
+                    "original_gid": "5sWHDYs0csV6RS48xBl0tH",
+                    "metadata": { "is_explicit": "false" }
+                },
+                { "uri": "spotify:meta:page:1" }
+            ],
+            "next_page_url": "hm://radio-apollo/v3/tracks/spotify:playlist:37i9dQZF1EYkqdzj48dyYq"
+        }"#;
+
+        let context: PageContext = serde_json::from_str(json).unwrap();
+        assert_eq!(context.tracks.len(), 3);
+        assert!(context.tracks[0].gid().is_empty());
+        assert_eq!(context.tracks[1].gid().len(), 16);
+
+        assert_eq!(context.segments.len(), 1);
+        let segment = &context.segments["spotify:narration:dj-intro"];
+        assert_eq!(segment.metadata["title"], "Up next, some classics");
+        assert!(!segment.metadata.contains_key("duration_ms"));
+    }
 }
@@ -2,9 +2,17 @@
 extern crate log;
 
 use librespot_core as core;
+use librespot_metadata as metadata;
 use librespot_playback as playback;
 use librespot_protocol as protocol;
 
+pub mod autoplay;
 pub mod config;
 pub mod context;
+pub mod presets;
+pub mod remote;
+pub mod restrictions;
+pub mod shuffle;
 pub mod spirc;
+pub mod state_store;
+pub mod trace;
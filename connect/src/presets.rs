@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::core::{cache::Cache, Error};
+
+#[derive(Debug, Error)]
+pub enum PresetsError {
+    #[error("preset {0} is not set")]
+    NotSet(u8),
+    #[error("not a context URI: {0}")]
+    InvalidContextUri(String),
+}
+
+impl From<PresetsError> for Error {
+    fn from(err: PresetsError) -> Self {
+        match err {
+            PresetsError::NotSet(_) => Error::not_found(err),
+            PresetsError::InvalidContextUri(_) => Error::invalid_argument(err),
+        }
+    }
+}
+
+/// Presets map small numbers to context URIs, like the station buttons of a radio, so that
+/// devices with physical buttons can start e.g. a playlist with a single press.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Presets {
+    presets: BTreeMap<u8, String>,
+}
+
+impl Presets {
+    /// Reads the presets saved in the cache, if any.
+    pub fn load(cache: &Cache) -> Self {
+        Self {
+            presets: cache.presets().unwrap_or_default(),
+        }
+    }
+
+    pub fn save(&self, cache: &Cache) {
+        cache.save_presets(&self.presets);
+    }
+
+    pub fn get(&self, preset: u8) -> Option<&str> {
+        self.presets.get(&preset).map(String::as_str)
+    }
+
+    /// Assigns `context_uri` to `preset`, replacing what was assigned to it before.
+    pub fn set(&mut self, preset: u8, context_uri: &str) -> Result<(), Error> {
+        Self::validate(context_uri)?;
+        self.presets.insert(preset, context_uri.to_owned());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, preset: u8) -> Option<String> {
+        self.presets.remove(&preset)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &str)> {
+        self.presets
+            .iter()
+            .map(|(preset, context_uri)| (*preset, context_uri.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.presets.is_empty()
+    }
+
+    /// Checks that `context_uri` is something that can be played as a context. Local files
+    /// are not supported for playback.
+    pub fn validate(context_uri: &str) -> Result<(), Error> {
+        let is_context = context_uri.strip_prefix("spotify:").map_or(false, |rest| {
+            !rest.is_empty() && !rest.starts_with("local-files")
+        });
+        if is_context {
+            Ok(())
+        } else {
+            Err(PresetsError::InvalidContextUri(context_uri.to_owned()).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_remove() {
+        let mut presets = Presets::default();
+        presets
+            .set(1, "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M")
+            .unwrap();
+        presets
+            .set(3, "spotify:artist:0OdUWJ0sBjDrqHygGUXeCF")
+            .unwrap();
+        presets
+            .set(1, "spotify:album:6G9fHYDCoyEErUkHrFYfs4")
+            .unwrap();
+
+        assert_eq!(
+            presets.iter().collect::<Vec<_>>(),
+            [
+                (1, "spotify:album:6G9fHYDCoyEErUkHrFYfs4"),
+                (3, "spotify:artist:0OdUWJ0sBjDrqHygGUXeCF")
+            ]
+        );
+
+        assert!(presets.remove(3).is_some());
+        assert_eq!(presets.get(3), None);
+        assert!(presets.set(2, "spotify:local-files").is_err());
+        assert!(presets.set(2, "https://open.spotify.com").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::{convert::TryFrom, time::Duration};
+
+use protobuf::Message;
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::{
+    context::PageContext,
+    core::{
+        mercury::{MercuryResponse, MercurySender},
+        util::SeqGenerator,
+        Error, Session, SpotifyId,
+    },
+    protocol::spirc::{Frame, MessageType, PlayStatus, State, TrackRef},
+};
+
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("no Connect device named \"{0}\"")]
+    NoSuchDevice(String),
+    #[error("no Connect device is active")]
+    NoActiveDevice,
+    #[error("context <{0}> has no tracks")]
+    EmptyContext(String),
+}
+
+impl From<RemoteError> for Error {
+    fn from(err: RemoteError) -> Self {
+        use RemoteError::*;
+        match err {
+            NoSuchDevice(_) | NoActiveDevice => Error::not_found(err),
+            EmptyContext(_) => Error::unavailable(err),
+        }
+    }
+}
+
+/// A Connect device of the user, as announced in answer to [`Remote::devices`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteDevice {
+    pub ident: String,
+    pub name: String,
+    pub is_active: bool,
+    pub is_playing: bool,
+    pub volume: u32,
+}
+
+/// Controls the other Connect devices of the user with the same messages that [`Spirc`]
+/// answers to, without becoming a device itself. This is meant for one-shot actions like
+/// starting playback on a speaker from a script.
+///
+/// [`Spirc`]: crate::spirc::Spirc
+pub struct Remote {
+    session: Session,
+    ident: String,
+    sequence: SeqGenerator<u32>,
+    sender: MercurySender,
+    updates: mpsc::UnboundedReceiver<MercuryResponse>,
+}
+
+impl Remote {
+    /// Creates a remote for a connected `session`.
+    pub async fn new(session: Session) -> Self {
+        let updates = session.mercury().listen_for("hm://remote/user/").await;
+
+        let username: String =
+            form_urlencoded::byte_serialize(session.username().as_bytes()).collect();
+        let sender = session
+            .mercury()
+            .sender(format!("hm://remote/user/{username}/"));
+
+        Self {
+            ident: session.device_id().to_owned(),
+            sequence: SeqGenerator::new(1),
+            session,
+            sender,
+            updates,
+        }
+    }
+
+    /// Asks all devices to announce themselves, and returns those that did so within
+    /// `timeout`.
+    pub async fn devices(&mut self, timeout: Duration) -> Result<Vec<RemoteDevice>, Error> {
+        self.send(MessageType::kMessageTypeHello, None, None)
+            .await?;
+
+        let mut devices: Vec<RemoteDevice> = Vec::new();
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+
+        loop {
+            let response = tokio::select! {
+                _ = &mut deadline => break,
+                response = self.updates.recv() => match response {
+                    Some(response) => response,
+                    None => break,
+                },
+            };
+
+            let frame = match response
+                .payload
+                .first()
+                .map(|data| Frame::parse_from_bytes(data))
+            {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    debug!("Ignoring invalid frame: {}", e);
+                    continue;
+                }
+                None => continue,
+            };
+
+            if frame.typ() != MessageType::kMessageTypeNotify || frame.ident() == self.ident {
+                continue;
+            }
+
+            let device = RemoteDevice {
+                ident: frame.ident().to_owned(),
+                name: frame.device_state.name().to_owned(),
+                is_active: frame.device_state.is_active(),
+                is_playing: frame.device_state.is_active()
+                    && frame.state.status() == PlayStatus::kPlayStatusPlay,
+                volume: frame.device_state.volume(),
+            };
+
+            // Devices notify again whenever their state changes, so keep the latest.
+            match devices.iter_mut().find(|d| d.ident == device.ident) {
+                Some(known) => *known = device,
+                None => devices.push(device),
+            }
+        }
+
+        Ok(devices)
+    }
+
+    /// Finds the device with the given name, ignoring case, or else the active device.
+    pub async fn find_device(
+        &mut self,
+        name: Option<&str>,
+        timeout: Duration,
+    ) -> Result<RemoteDevice, Error> {
+        let devices = self.devices(timeout).await?;
+        let device = match name {
+            Some(name) => devices
+                .into_iter()
+                .find(|device| device.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| RemoteError::NoSuchDevice(name.to_owned()))?,
+            None => devices
+                .into_iter()
+                .find(|device| device.is_active)
+                .ok_or(RemoteError::NoActiveDevice)?,
+        };
+        Ok(device)
+    }
+
+    pub async fn play(&mut self, device: &RemoteDevice) -> Result<(), Error> {
+        self.send(MessageType::kMessageTypePlay, Some(device), None)
+            .await
+    }
+
+    pub async fn pause(&mut self, device: &RemoteDevice) -> Result<(), Error> {
+        self.send(MessageType::kMessageTypePause, Some(device), None)
+            .await
+    }
+
+    pub async fn next(&mut self, device: &RemoteDevice) -> Result<(), Error> {
+        self.send(MessageType::kMessageTypeNext, Some(device), None)
+            .await
+    }
+
+    pub async fn prev(&mut self, device: &RemoteDevice) -> Result<(), Error> {
+        self.send(MessageType::kMessageTypePrev, Some(device), None)
+            .await
+    }
+
+    /// Starts playing `uri` on `device`: a single track or episode, or else the tracks of a
+    /// context like an album or a playlist.
+    pub async fn load(&mut self, device: &RemoteDevice, uri: &str) -> Result<(), Error> {
+        let tracks = match SpotifyId::from_uri(uri) {
+            Ok(id) if id.is_playable() => {
+                let mut track = TrackRef::new();
+                track.set_gid(id.to_raw().to_vec());
+                track.set_uri(uri.to_owned());
+                vec![track]
+            }
+            _ => self.resolve_context(uri).await?,
+        };
+
+        let mut state = State::new();
+        state.set_context_uri(uri.to_owned());
+        state.set_status(PlayStatus::kPlayStatusPlay);
+        state.set_playing_track_index(0);
+        state.track = tracks;
+
+        self.send(MessageType::kMessageTypeLoad, Some(device), Some(state))
+            .await
+    }
+
+    async fn resolve_context(&self, uri: &str) -> Result<Vec<TrackRef>, Error> {
+        let context = self
+            .session
+            .spclient()
+            .get_apollo_station("tracks", uri, None, Vec::new(), false)
+            .await?;
+        let context: PageContext = serde_json::from_slice(&context)?;
+
+        let tracks: Vec<TrackRef> = context
+            .tracks
+            .into_iter()
+            .filter(|track| SpotifyId::try_from(track).is_ok())
+            .collect();
+        if tracks.is_empty() {
+            return Err(RemoteError::EmptyContext(uri.to_owned()).into());
+        }
+        Ok(tracks)
+    }
+
+    async fn send(
+        &mut self,
+        typ: MessageType,
+        recipient: Option<&RemoteDevice>,
+        state: Option<State>,
+    ) -> Result<(), Error> {
+        let mut frame = Frame::new();
+        frame.set_version(1);
+        frame.set_protocol_version("2.0.0".to_string());
+        frame.set_ident(self.ident.clone());
+        frame.set_seq_nr(self.sequence.get());
+        frame.set_typ(typ);
+        if let Some(recipient) = recipient {
+            frame.recipient.push(recipient.ident.clone());
+        }
+        if let Some(state) = state {
+            *frame.state.mut_or_insert_default() = state;
+        }
+
+        frame.set_state_update_id(self.session.server_time().as_timestamp_ms());
+
+        self.sender.send(frame.write_to_bytes()?)?;
+        self.sender.flush().await
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use crate::protocol::player::{PlayerState, Restrictions as RestrictionsMessage};
+
+/// The playback actions that the Connect state can disallow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlaybackAction {
+    Pause,
+    Resume,
+    Seek,
+    PeekPrev,
+    PeekNext,
+    SkipPrev,
+    SkipNext,
+    ToggleRepeatContext,
+    ToggleRepeatTrack,
+    ToggleShuffle,
+    SetQueue,
+    InterruptPlayback,
+    TransferPlayback,
+    RemoteControl,
+    InsertIntoNextTracks,
+    InsertIntoContextTracks,
+    ReorderInNextTracks,
+    ReorderInContextTracks,
+    RemoveFromNextTracks,
+    RemoveFromContextTracks,
+    UpdateContext,
+}
+
+/// The actions that are currently disallowed, with the reasons given for them, e.g. `ad`
+/// while an ad is playing. An action is allowed as long as no reason disallows it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Restrictions {
+    reasons: HashMap<PlaybackAction, Vec<String>>,
+}
+
+impl Restrictions {
+    pub fn is_allowed(&self, action: PlaybackAction) -> bool {
+        self.reasons(action).is_empty()
+    }
+
+    pub fn reasons(&self, action: PlaybackAction) -> &[String] {
+        self.reasons.get(&action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn disallow(&mut self, action: PlaybackAction, reason: &str) {
+        let reasons = self.reasons.entry(action).or_default();
+        if !reasons.iter().any(|r| r == reason) {
+            reasons.push(reason.to_owned());
+        }
+    }
+
+    /// Adds the reasons of `other`, e.g. to combine the restrictions of the context with
+    /// those of the playing track.
+    pub fn extend(&mut self, other: &Restrictions) {
+        for (action, reasons) in &other.reasons {
+            for reason in reasons {
+                self.disallow(*action, reason);
+            }
+        }
+    }
+
+    /// The restrictions of the context and of the playing track combined.
+    pub fn from_player_state(state: &PlayerState) -> Self {
+        let mut restrictions = Self::from(state.context_restrictions.get_or_default());
+        restrictions.extend(&state.restrictions.get_or_default().into());
+        restrictions
+    }
+}
+
+impl From<&RestrictionsMessage> for Restrictions {
+    fn from(msg: &RestrictionsMessage) -> Self {
+        use PlaybackAction::*;
+
+        let mut restrictions = Self::default();
+        for (action, reasons) in [
+            (Pause, &msg.disallow_pausing_reasons),
+            (Resume, &msg.disallow_resuming_reasons),
+            (Seek, &msg.disallow_seeking_reasons),
+            (PeekPrev, &msg.disallow_peeking_prev_reasons),
+            (PeekNext, &msg.disallow_peeking_next_reasons),
+            (SkipPrev, &msg.disallow_skipping_prev_reasons),
+            (SkipNext, &msg.disallow_skipping_next_reasons),
+            (
+                ToggleRepeatContext,
+                &msg.disallow_toggling_repeat_context_reasons,
+            ),
+            (
+                ToggleRepeatTrack,
+                &msg.disallow_toggling_repeat_track_reasons,
+            ),
+            (ToggleShuffle, &msg.disallow_toggling_shuffle_reasons),
+            (SetQueue, &msg.disallow_set_queue_reasons),
+            (
+                InterruptPlayback,
+                &msg.disallow_interrupting_playback_reasons,
+            ),
+            (
+                TransferPlayback,
+                &msg.disallow_transferring_playback_reasons,
+            ),
+            (RemoteControl, &msg.disallow_remote_control_reasons),
+            (
+                InsertIntoNextTracks,
+                &msg.disallow_inserting_into_next_tracks_reasons,
+            ),
+            (
+                InsertIntoContextTracks,
+                &msg.disallow_inserting_into_context_tracks_reasons,
+            ),
+            (
+                ReorderInNextTracks,
+                &msg.disallow_reordering_in_next_tracks_reasons,
+            ),
+            (
+                ReorderInContextTracks,
+                &msg.disallow_reordering_in_context_tracks_reasons,
+            ),
+            (
+                RemoveFromNextTracks,
+                &msg.disallow_removing_from_next_tracks_reasons,
+            ),
+            (
+                RemoveFromContextTracks,
+                &msg.disallow_removing_from_context_tracks_reasons,
+            ),
+            (UpdateContext, &msg.disallow_updating_context_reasons),
+        ] {
+            for reason in reasons {
+                restrictions.disallow(action, reason);
+            }
+        }
+        restrictions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_player_state() {
+        let mut state = PlayerState::new();
+        let context = state.context_restrictions.mut_or_insert_default();
+        context
+            .disallow_toggling_shuffle_reasons
+            .push("dj".to_owned());
+        context.disallow_seeking_reasons.push("dj".to_owned());
+        let track = state.restrictions.mut_or_insert_default();
+        track.disallow_seeking_reasons.push("ad".to_owned());
+        track.disallow_seeking_reasons.push("dj".to_owned());
+
+        let restrictions = Restrictions::from_player_state(&state);
+        assert_eq!(restrictions.reasons(PlaybackAction::Seek), ["dj", "ad"]);
+        assert_eq!(restrictions.reasons(PlaybackAction::ToggleShuffle), ["dj"]);
+        assert!(!restrictions.is_allowed(PlaybackAction::Seek));
+        assert!(restrictions.is_allowed(PlaybackAction::Pause));
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::protocol::spirc::TrackRef;
+
+/// The order of the tracks before they were shuffled, so that unshuffling can restore it.
+#[derive(Clone, Debug)]
+pub struct Shuffle {
+    seed: u64,
+    unshuffled: Vec<TrackRef>,
+}
+
+fn track_key(track: &TrackRef) -> (Vec<u8>, String) {
+    (track.gid().to_vec(), track.uri().to_owned())
+}
+
+// The playing track is followed by the tracks the user queued, if any.
+fn queue_end(tracks: &[TrackRef], playing_index: usize) -> usize {
+    let start = playing_index + 1;
+    start
+        + tracks[start..]
+            .iter()
+            .take_while(|track| track.queued())
+            .count()
+}
+
+impl Shuffle {
+    /// Shuffles `tracks` with the given seed, so that the same seed always results in the same
+    /// order. The playing track is moved to the front, followed by the queued tracks in
+    /// their original order.
+    pub fn apply(tracks: &mut Vec<TrackRef>, playing_index: usize, seed: u64) -> Self {
+        let unshuffled = tracks.clone();
+
+        if !tracks.is_empty() {
+            let playing_index = playing_index.min(tracks.len() - 1);
+            let end = queue_end(tracks, playing_index);
+
+            let mut shuffled: Vec<TrackRef> = tracks.drain(playing_index..end).collect();
+            let mut rng = StdRng::seed_from_u64(seed);
+            tracks.shuffle(&mut rng);
+            shuffled.append(tracks);
+            *tracks = shuffled;
+        }
+
+        Self { seed, unshuffled }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns `tracks` in the order from before shuffling and the new index of the playing
+    /// track.
+    ///
+    /// Tracks that were removed while shuffled are left out, and tracks that were added are
+    /// appended. The queued tracks keep following the playing track.
+    pub fn restore(&self, tracks: &[TrackRef], playing_index: usize) -> (Vec<TrackRef>, usize) {
+        if tracks.is_empty() {
+            return (Vec::new(), 0);
+        }
+
+        let playing_index = playing_index.min(tracks.len() - 1);
+        let end = queue_end(tracks, playing_index);
+
+        // A queued track that is playing is removed once it has finished, so it belongs
+        // with the queue and the track played before it is what we restore around.
+        let (anchor, queue) = if tracks[playing_index].queued() {
+            (playing_index.checked_sub(1), &tracks[playing_index..end])
+        } else {
+            (Some(playing_index), &tracks[playing_index + 1..end])
+        };
+
+        let mut remaining: HashMap<(Vec<u8>, String), usize> = HashMap::new();
+        for track in tracks.iter().filter(|track| !track.queued()) {
+            *remaining.entry(track_key(track)).or_default() += 1;
+        }
+
+        let mut take = |track: &TrackRef| match remaining.get_mut(&track_key(track)) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        };
+
+        let mut restored: Vec<TrackRef> = self
+            .unshuffled
+            .iter()
+            .filter(|track| !track.queued() && take(track))
+            .cloned()
+            .collect();
+        let added: Vec<TrackRef> = tracks
+            .iter()
+            .filter(|track| !track.queued() && take(track))
+            .cloned()
+            .collect();
+        restored.extend(added);
+
+        let anchor_position = anchor.and_then(|anchor| {
+            let key = track_key(&tracks[anchor]);
+            restored.iter().position(|track| track_key(track) == key)
+        });
+
+        let (queue_position, new_index) = match anchor_position {
+            Some(position) if tracks[playing_index].queued() => (position + 1, position + 1),
+            Some(position) => (position + 1, position),
+            None => (0, 0),
+        };
+        restored.splice(queue_position..queue_position, queue.iter().cloned());
+
+        (restored, new_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: u8, queued: bool) -> TrackRef {
+        let mut track = TrackRef::new();
+        track.set_gid(vec![id]);
+        track.set_queued(queued);
+        track
+    }
+
+    fn ids(tracks: &[TrackRef]) -> Vec<u8> {
+        tracks.iter().map(|track| track.gid()[0]).collect()
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        let tracks: Vec<TrackRef> = (0..20).map(|id| track(id, false)).collect();
+
+        let mut first = tracks.clone();
+        let mut second = tracks.clone();
+        Shuffle::apply(&mut first, 3, 42);
+        Shuffle::apply(&mut second, 3, 42);
+
+        assert_eq!(ids(&first), ids(&second));
+        assert_eq!(first[0].gid(), [3]);
+        assert_ne!(ids(&first), ids(&tracks));
+    }
+
+    #[test]
+    fn unshuffle_restores_order() {
+        let mut tracks = vec![
+            track(0, false),
+            track(1, false),
+            track(10, true),
+            track(11, true),
+            track(2, false),
+            track(3, false),
+            track(4, false),
+        ];
+        let original = tracks.clone();
+
+        let shuffle = Shuffle::apply(&mut tracks, 1, 7);
+        assert_eq!(ids(&tracks[..3]), [1, 10, 11]);
+
+        let (restored, index) = shuffle.restore(&tracks, 0);
+        assert_eq!(ids(&restored), ids(&original));
+        assert_eq!(index, 1);
+
+        // Move on to a later track, which is now playing with the queue after it.
+        let playing = tracks.iter().position(|track| track.gid() == [3]).unwrap();
+        let queue: Vec<TrackRef> = tracks.drain(1..3).collect();
+        tracks.splice(playing - 1..playing - 1, queue);
+        let (restored, index) = shuffle.restore(&tracks, playing - 2);
+        assert_eq!(ids(&restored), [0, 1, 2, 3, 10, 11, 4]);
+        assert_eq!(index, 3);
+    }
+
+    #[test]
+    fn unshuffle_while_playing_queued_track() {
+        let mut tracks = vec![track(0, false), track(1, false), track(2, false)];
+        let shuffle = Shuffle::apply(&mut tracks, 0, 1);
+
+        tracks.insert(1, track(10, true));
+        tracks.insert(2, track(11, true));
+        let (restored, index) = shuffle.restore(&tracks, 1);
+        assert_eq!(ids(&restored), [0, 10, 11, 1, 2]);
+        assert_eq!(index, 1);
+    }
+}
@@ -1,37 +1,55 @@
 use std::{
+    collections::HashMap,
     convert::TryFrom,
     future::Future,
     pin::Pin,
     sync::atomic::{AtomicUsize, Ordering},
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::Instant,
 };
 
-use futures_util::{stream::FusedStream, FutureExt, StreamExt};
+use futures_util::{
+    stream::{self, FusedStream},
+    FutureExt, StreamExt,
+};
 
 use protobuf::{self, Message};
-use rand::prelude::SliceRandom;
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 use crate::{
+    autoplay::{AutoplayConstraints, AutoplaySeed},
     config::ConnectConfig,
     context::PageContext,
     core::{
-        authentication::Credentials, mercury::MercurySender, session::UserAttributes,
-        util::SeqGenerator, version, Error, Session, SpotifyId,
+        anomaly::{self, ProtocolAnomaly},
+        authentication::Credentials,
+        cache::DeviceRegistration,
+        mercury::MercurySender,
+        session::{SessionEvent, UserAttributes},
+        spotify_id::SpotifyCollection,
+        util::SeqGenerator,
+        version, Error, Session, SpotifyId,
     },
+    metadata::library::get_collection_tracks_page,
     playback::{
+        config::{EventOverflowPolicy, LoadFailurePolicy},
+        content_policy::BlockReason,
         mixer::Mixer,
         player::{Player, PlayerEvent, PlayerEventChannel},
     },
+    presets::{Presets, PresetsError},
     protocol::{
         self,
         explicit_content_pubsub::UserAttributesUpdate,
         spirc::{DeviceState, Frame, MessageType, PlayStatus, State, TrackRef},
         user_attributes::UserAttributesMutation,
     },
+    restrictions::{PlaybackAction, Restrictions},
+    shuffle::Shuffle,
+    state_store::{ConnectStateSnapshot, ConnectStateStore},
+    trace::{SpircTrace, SpircTraceMode, SpircTraceRecorder},
 };
 
 #[derive(Debug, Error)]
@@ -93,15 +111,46 @@ struct SpircTask {
     connection_id_update: BoxedStream<Result<String, Error>>,
     user_attributes_update: BoxedStream<Result<UserAttributesUpdate, Error>>,
     user_attributes_mutation: BoxedStream<Result<UserAttributesMutation, Error>>,
+    trace_recorder: Option<SpircTraceRecorder>,
+    replaying: bool,
     sender: MercurySender,
     commands: Option<mpsc::UnboundedReceiver<SpircCommand>>,
     player_events: Option<PlayerEventChannel>,
+    session_events: mpsc::UnboundedReceiver<SessionEvent>,
 
     shutdown: bool,
     session: Session,
     resolve_context: Option<String>,
     autoplay_context: bool,
+    autoplay_constraints: AutoplayConstraints,
+    autoplay_seed: Option<AutoplaySeed>,
+    private_session: bool,
+    shuffle: Option<Shuffle>,
     context: Option<PageContext>,
+    // The tracks that were marked as unavailable, to restore them when that may change.
+    unavailable_tracks: HashMap<Vec<u8>, TrackRef>,
+    remote_seq_nrs: RemoteSeqNrs,
+    restrictions: watch::Sender<Restrictions>,
+    presets: watch::Sender<Presets>,
+    // When the command to play the context being resolved was received, to start playing
+    // it as soon as its tracks are known, because it was loaded without any tracks, e.g.
+    // from a preset.
+    play_when_resolved: Option<Instant>,
+    // When the command or remote update being handled was received, so that the player
+    // can report the latency of the loads it causes.
+    command_received_at: Option<Instant>,
+    // Whether the tracks being played were resolved by us, e.g. for a preset, so that the
+    // next page of the context is resolved when the end of them is near.
+    paged_context: bool,
+    // Whether the context being resolved is the next page of the tracks being played.
+    resolving_next_page: bool,
+    // Whether the end of the tracks was reached before their next page was resolved, to
+    // skip to the next track once it is.
+    next_when_resolved: bool,
+    // The token to request the next page of the collection being played with.
+    collection_page_token: Option<String>,
+    // The latest snapshot to save to the state store, which is done in the background.
+    state_saver: Option<watch::Sender<Option<ConnectStateSnapshot>>>,
 
     spirc_id: usize,
 }
@@ -119,12 +168,17 @@ pub enum SpircCommand {
     VolumeDown,
     Shutdown,
     Shuffle(bool),
+    ShuffleWithSeed(u64),
     Repeat(bool),
     Disconnect,
     SetPosition(u32),
     SetVolume(u16),
     Activate,
     Load(SpircLoadCommand),
+    SetRestrictions(Restrictions),
+    SetPreset(u8, Option<String>),
+    PlayPreset(u8),
+    Handover(oneshot::Sender<Option<SpircLoadCommand>>),
 }
 
 #[derive(Debug)]
@@ -133,8 +187,13 @@ pub struct SpircLoadCommand {
     /// Whether the given tracks should immediately start playing, or just be initially loaded.
     pub start_playing: bool,
     pub shuffle: bool,
+    /// Shuffles the given tracks with this seed, if `shuffle` is set. Otherwise the tracks
+    /// are expected to be in shuffled order already.
+    pub shuffle_seed: Option<u64>,
     pub repeat: bool,
     pub playing_track_index: u32,
+    /// Where to start playing the track at `playing_track_index`.
+    pub position_ms: u32,
     pub tracks: Vec<TrackRef>,
 }
 
@@ -150,6 +209,7 @@ impl From<SpircLoadCommand> for State {
         state.set_shuffle(command.shuffle);
         state.set_repeat(command.repeat);
         state.set_playing_track_index(command.playing_track_index);
+        state.set_position_ms(command.position_ms);
         state.track = command.tracks;
         state
     }
@@ -157,12 +217,57 @@ impl From<SpircLoadCommand> for State {
 
 const CONTEXT_TRACKS_HISTORY: usize = 10;
 const CONTEXT_FETCH_THRESHOLD: u32 = 5;
+// Constrained autoplay asks for more tracks per page, and skips pages without any allowed.
+const CONSTRAINED_AUTOPLAY_COUNT: usize = 100;
+const CONSTRAINED_AUTOPLAY_MAX_PAGES: usize = 5;
 
 const VOLUME_STEPS: i64 = 64;
 const VOLUME_STEP_SIZE: u16 = 1024; // (u16::MAX + 1) / VOLUME_STEPS
 
+// Late frames are only a few behind. A device whose sequence number drops by more than this
+// has restarted, e.g. after the app was killed, and counts from the start again.
+const SEQ_NR_RESTART_GAP: u32 = 100;
+
+// The sequence number of the last frame of each device, to tell frames that arrive out of
+// order.
+#[derive(Debug, Default)]
+struct RemoteSeqNrs(HashMap<String, u32>);
+
+impl RemoteSeqNrs {
+    // Returns the sequence number of the last frame of `ident` if the frame with `seq_nr` is
+    // out of order. A device that says hello has reconnected and counts from the start again.
+    fn update(&mut self, ident: &str, seq_nr: u32, typ: MessageType) -> Option<u32> {
+        match self.0.get(ident).copied() {
+            // Keep the highest sequence number, a late frame doesn't move it back.
+            Some(last_seq_nr)
+                if seq_nr <= last_seq_nr
+                    && typ != MessageType::kMessageTypeHello
+                    && last_seq_nr - seq_nr <= SEQ_NR_RESTART_GAP =>
+            {
+                Some(last_seq_nr)
+            }
+            _ => {
+                self.0.insert(ident.to_owned(), seq_nr);
+                None
+            }
+        }
+    }
+
+    // Forgets a device that said goodbye.
+    fn remove(&mut self, ident: &str) {
+        self.0.remove(ident);
+    }
+
+    // After reconnecting, the frames of all devices may have been missed.
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
 pub struct Spirc {
     commands: mpsc::UnboundedSender<SpircCommand>,
+    restrictions: watch::Receiver<Restrictions>,
+    presets: watch::Receiver<Presets>,
 }
 
 fn initial_state() -> State {
@@ -201,10 +306,9 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
         protocol::spirc::CapabilityType::kGaiaEqConnectId,
         1,
     ));
-    // TODO: implement logout
     msg.capabilities.push(int_capability(
         protocol::spirc::CapabilityType::kSupportsLogout,
-        0,
+        1,
     ));
     msg.capabilities.push(int_capability(
         protocol::spirc::CapabilityType::kIsObservable,
@@ -241,10 +345,11 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
         protocol::spirc::CapabilityType::kRestrictToLocal,
         0,
     ));
-    // TODO: what does this hide, or who do we hide from?
-    // May be an interesting privacy toggle.
-    msg.capabilities
-        .push(int_capability(protocol::spirc::CapabilityType::kHidden, 0));
+    // Hides the device from the public device list, while local control keeps working.
+    msg.capabilities.push(int_capability(
+        protocol::spirc::CapabilityType::kHidden,
+        config.hidden as i64,
+    ));
     let mut supported_types = protocol::spirc::Capability::new();
     supported_types.set_typ(protocol::spirc::CapabilityType::kSupportedTypes);
     supported_types
@@ -281,23 +386,45 @@ impl Spirc {
 
         let ident = session.device_id().to_owned();
 
-        let remote_update = Box::pin(
-            session
-                .mercury()
-                .listen_for("hm://remote/user/")
-                .map(UnboundedReceiverStream::new)
-                .flatten_stream()
-                .map(|response| -> Result<(String, Frame), Error> {
-                    let uri_split: Vec<&str> = response.uri.split('/').collect();
-                    let username = match uri_split.get(4) {
-                        Some(s) => s.to_string(),
-                        None => String::new(),
-                    };
+        let remote_update: BoxedStream<Result<(String, Frame), Error>> = match config.trace {
+            Some(SpircTraceMode::Replay(ref path)) => {
+                info!("Replaying remote updates from {:?}", path);
+                // Keep the stream open after the trace is exhausted, so that spirc keeps
+                // running as if it were still connected.
+                Box::pin(
+                    SpircTrace::open(path)?
+                        .into_stream(&ident)?
+                        .chain(stream::pending())
+                        .fuse(),
+                )
+            }
+            _ => Box::pin(
+                session
+                    .mercury()
+                    .listen_for("hm://remote/user/")
+                    .map(UnboundedReceiverStream::new)
+                    .flatten_stream()
+                    .map(|response| -> Result<(String, Frame), Error> {
+                        let uri_split: Vec<&str> = response.uri.split('/').collect();
+                        let username = match uri_split.get(4) {
+                            Some(s) => s.to_string(),
+                            None => String::new(),
+                        };
 
-                    let data = response.payload.first().ok_or(SpircError::NoData)?;
-                    Ok((username, Frame::parse_from_bytes(data)?))
-                }),
-        );
+                        let data = response.payload.first().ok_or(SpircError::NoData)?;
+                        Ok((username, Frame::parse_from_bytes(data)?))
+                    }),
+            ),
+        };
+
+        let replaying = matches!(config.trace, Some(SpircTraceMode::Replay(_)));
+        let trace_recorder = match config.trace {
+            Some(SpircTraceMode::Record(ref path)) => {
+                info!("Recording remote updates to {:?}", path);
+                Some(SpircTraceRecorder::create(path, &ident)?)
+            }
+            _ => None,
+        };
 
         let connection_id_update = Box::pin(
             session
@@ -348,12 +475,36 @@ impl Spirc {
         let sender = session.mercury().sender(sender_uri);
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (restrictions_tx, restrictions_rx) = watch::channel(Restrictions::default());
+        let presets = session
+            .cache()
+            .map(|cache| Presets::load(cache))
+            .unwrap_or_default();
+        let (presets_tx, presets_rx) = watch::channel(presets);
 
         let initial_volume = config.initial_volume;
+        let private_session = config.private_session;
+        let state_store = config.state_store.clone();
+        let autoplay_constraints = config.autoplay_constraints.clone();
 
         let device = initial_device_state(config);
 
-        let player_events = player.get_player_event_channel();
+        // Remembered to be announced with the same identity after a restart.
+        if let Some(cache) = session.cache() {
+            let registration = DeviceRegistration {
+                device_id: session.device_id().to_owned(),
+                name: device.name().to_owned(),
+            };
+            if cache.device_registration().as_ref() != Some(&registration) {
+                cache.save_device_registration(&registration);
+            }
+        }
+
+        // Blocking the player while this task is behind, e.g. waiting for the network, would
+        // stall playback. Coalescing keeps the latest of the events that report state, which
+        // is all the Connect state needs of them.
+        let player_events =
+            player.get_player_event_channel_with_policy(256, EventOverflowPolicy::Coalesce);
 
         let mut task = SpircTask {
             player,
@@ -372,16 +523,34 @@ impl Spirc {
             connection_id_update,
             user_attributes_update,
             user_attributes_mutation,
+            trace_recorder,
+            replaying,
             sender,
             commands: Some(cmd_rx),
             player_events: Some(player_events),
+            session_events: session.get_session_event_channel(),
 
             shutdown: false,
             session,
 
             resolve_context: None,
             autoplay_context: false,
+            autoplay_constraints,
+            autoplay_seed: None,
+            private_session,
+            shuffle: None,
             context: None,
+            unavailable_tracks: HashMap::new(),
+            remote_seq_nrs: RemoteSeqNrs::default(),
+            restrictions: restrictions_tx,
+            presets: presets_tx,
+            play_when_resolved: None,
+            command_received_at: None,
+            paged_context: false,
+            resolving_next_page: false,
+            next_when_resolved: false,
+            collection_page_token: None,
+            state_saver: state_store.clone().map(spawn_state_saver),
 
             spirc_id,
         };
@@ -393,10 +562,25 @@ impl Spirc {
             task.set_volume(current_volume);
         }
 
-        let spirc = Spirc { commands: cmd_tx };
+        let spirc = Spirc {
+            commands: cmd_tx,
+            restrictions: restrictions_rx,
+            presets: presets_rx,
+        };
 
         task.hello()?;
 
+        if let Some(store) = &state_store {
+            match store.load() {
+                Ok(Some(snapshot)) => {
+                    info!("Restoring <{}>", snapshot.context_uri);
+                    task.restore(&SpircLoadCommand::from(snapshot).into());
+                }
+                Ok(None) => (),
+                Err(e) => warn!("Unable to load the Connect state: {}", e),
+            }
+        }
+
         Ok((spirc, task.run()))
     }
 
@@ -427,6 +611,9 @@ impl Spirc {
     pub fn shuffle(&self, shuffle: bool) -> Result<(), Error> {
         Ok(self.commands.send(SpircCommand::Shuffle(shuffle))?)
     }
+    pub fn shuffle_with_seed(&self, seed: u64) -> Result<(), Error> {
+        Ok(self.commands.send(SpircCommand::ShuffleWithSeed(seed))?)
+    }
     pub fn repeat(&self, repeat: bool) -> Result<(), Error> {
         Ok(self.commands.send(SpircCommand::Repeat(repeat))?)
     }
@@ -445,6 +632,49 @@ impl Spirc {
     pub fn load(&self, command: SpircLoadCommand) -> Result<(), Error> {
         Ok(self.commands.send(SpircCommand::Load(command))?)
     }
+    /// Replaces the restrictions of the Connect state, e.g. with those of the cluster as
+    /// parsed by [`Restrictions::from_player_state`]. Disallowed commands are ignored.
+    pub fn set_restrictions(&self, restrictions: Restrictions) -> Result<(), Error> {
+        Ok(self
+            .commands
+            .send(SpircCommand::SetRestrictions(restrictions))?)
+    }
+    /// The current restrictions, which can also be watched for changes to update the
+    /// controls of a UI.
+    pub fn restrictions(&self) -> watch::Receiver<Restrictions> {
+        self.restrictions.clone()
+    }
+    /// Assigns a context URI to a preset, which is saved in the cache.
+    pub fn set_preset(&self, preset: u8, context_uri: &str) -> Result<(), Error> {
+        Presets::validate(context_uri)?;
+        Ok(self.commands.send(SpircCommand::SetPreset(
+            preset,
+            Some(context_uri.to_owned()),
+        ))?)
+    }
+    pub fn remove_preset(&self, preset: u8) -> Result<(), Error> {
+        Ok(self.commands.send(SpircCommand::SetPreset(preset, None))?)
+    }
+    /// The current presets, which can also be watched for changes to update e.g. a display.
+    pub fn presets(&self) -> watch::Receiver<Presets> {
+        self.presets.clone()
+    }
+    /// Starts playing the context of a preset from its first track, activating this device
+    /// if needed.
+    pub fn play_preset(&self, preset: u8) -> Result<(), Error> {
+        if self.presets.borrow().get(preset).is_none() {
+            return Err(PresetsError::NotSet(preset).into());
+        }
+        Ok(self.commands.send(SpircCommand::PlayPreset(preset))?)
+    }
+    /// Returns what is playing, to continue on another [`Spirc`], e.g. one of another
+    /// account after switching sessions, by [`Spirc::load`]ing it there. It is `None` when
+    /// this device isn't active.
+    pub async fn handover(&self) -> Result<Option<SpircLoadCommand>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(SpircCommand::Handover(tx))?;
+        Ok(rx.await?)
+    }
 }
 
 impl SpircTask {
@@ -456,11 +686,21 @@ impl SpircTask {
                 remote_update = self.remote_update.next() => match remote_update {
                     Some(result) => match result {
                         Ok((username, frame)) => {
-                            if username != self.session.username() {
+                            self.command_received_at = Some(Instant::now());
+
+                            if let Some(recorder) = self.trace_recorder.as_ref() {
+                                if let Err(e) = recorder.record_remote_update(&username, &frame) {
+                                    warn!("could not record remote update: {}", e);
+                                }
+                            }
+
+                            // A replayed trace may well have been recorded with another account.
+                            if !self.replaying && username != self.session.username() {
                                 warn!("could not dispatch remote update: frame was intended for {}", username);
                             } else if let Err(e) = self.handle_remote_update(frame) {
                                 error!("could not dispatch remote update: {}", e);
                             }
+                            self.command_received_at = None;
                         },
                         Err(e) => error!("could not parse remote update: {}", e),
                     }
@@ -500,15 +740,22 @@ impl SpircTask {
                     }
                 },
                 cmd = async { commands?.recv().await }, if commands.is_some() => if let Some(cmd) = cmd {
+                    self.command_received_at = Some(Instant::now());
                     if let Err(e) = self.handle_command(cmd) {
                         debug!("could not dispatch command: {}", e);
                     }
+                    self.command_received_at = None;
                 },
                 event = async { player_events?.recv().await }, if player_events.is_some() => if let Some(event) = event {
                     if let Err(e) = self.handle_player_event(event) {
                         error!("could not dispatch player event: {}", e);
                     }
                 },
+                event = self.session_events.recv() => match event {
+                    Some(SessionEvent::CountryChanged { .. }) => self.handle_country_changed(),
+                    Some(SessionEvent::Connected { .. }) => self.handle_reconnected(),
+                    _ => (),
+                },
                 result = self.sender.flush(), if !self.sender.is_flushed() => if result.is_err() {
                     error!("Cannot flush spirc event sender.");
                     break;
@@ -519,12 +766,19 @@ impl SpircTask {
                         continue; // not supported by apollo stations
                     }
 
+                    // Apollo doesn't know the collection of the user either.
+                    if !self.autoplay_context {
+                        if let Ok(collection) = SpotifyCollection::from_uri(&context_uri) {
+                            let context = self.resolve_collection(collection).await;
+                            self.handle_context_resolved(context);
+                            continue;
+                        }
+                    }
+
                     let context = if context_uri.starts_with("hm://") {
                         self.session.spclient().get_next_page(&context_uri).await
                     } else {
-                        // only send previous tracks that were before the current playback position
-                        let current_position = self.state.playing_track_index() as usize;
-                        let previous_tracks = self.state.track[..current_position].iter().filter_map(|t| SpotifyId::try_from(t).ok()).collect();
+                        let previous_tracks = self.previous_tracks();
 
                         let scope = if self.autoplay_context {
                             "stations" // this returns a `StationContext` but we deserialize it into a `PageContext`
@@ -532,30 +786,54 @@ impl SpircTask {
                             "tracks" // this returns a `PageContext`
                         };
 
-                        self.session.spclient().get_apollo_station(scope, &context_uri, None, previous_tracks, self.autoplay_context).await
+                        let count = if self.autoplay_context && self.autoplay_constraints.is_constrained() {
+                            Some(CONSTRAINED_AUTOPLAY_COUNT)
+                        } else {
+                            None
+                        };
+
+                        self.session.spclient().get_apollo_station(scope, &context_uri, count, previous_tracks, self.autoplay_context).await
                     };
 
-                    match context {
+                    let context = match context {
                         Ok(value) => {
-                            self.context = match serde_json::from_slice::<PageContext>(&value) {
+                            match serde_json::from_slice::<PageContext>(&value) {
                                 Ok(context) => {
+                                    let context = if self.autoplay_context {
+                                        self.constrain_autoplay(context).await
+                                    } else {
+                                        context
+                                    };
                                     info!(
                                         "Resolved {:?} tracks from <{:?}>",
                                         context.tracks.len(),
                                         self.state.context_uri(),
                                     );
+                                    if !context.segments.is_empty() {
+                                        debug!(
+                                            "Context has {} announced segments",
+                                            context.segments.len()
+                                        );
+                                    }
                                     Some(context)
                                 }
                                 Err(e) => {
                                     error!("Unable to parse JSONContext {:?}", e);
                                     None
                                 }
-                            };
+                            }
                         },
                         Err(err) => {
-                            error!("ContextError: {:?}", err)
+                            error!("ContextError: {:?}", err);
+                            // keep the context we have, unless that is a page of the tracks
+                            if self.resolving_next_page {
+                                None
+                            } else {
+                                self.context.take()
+                            }
                         }
-                    }
+                    };
+                    self.handle_context_resolved(context);
                 },
                 else => break
             }
@@ -567,12 +845,7 @@ impl SpircTask {
     }
 
     fn now_ms(&mut self) -> i64 {
-        let dur = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(dur) => dur,
-            Err(err) => err.duration(),
-        };
-
-        dur.as_millis() as i64 + 1000 * self.session.time_delta()
+        self.session.server_time().as_timestamp_ms()
     }
 
     fn update_state_position(&mut self, position_ms: u32) {
@@ -591,27 +864,42 @@ impl SpircTask {
                 rx.close()
             }
             Ok(())
+        } else if let SpircCommand::Handover(tx) = cmd {
+            trace!("Received SpircCommand::Handover");
+            let command = self.device.is_active().then(|| self.load_command());
+            let _ = tx.send(command);
+            Ok(())
         } else if self.device.is_active() {
             trace!("Received SpircCommand::{:?}", cmd);
             match cmd {
                 SpircCommand::Play => {
-                    self.handle_play();
+                    if self.is_allowed(PlaybackAction::Resume) {
+                        self.handle_play();
+                    }
                     self.notify(None)
                 }
                 SpircCommand::PlayPause => {
-                    self.handle_play_pause();
+                    if self.is_allowed(self.play_pause_action()) {
+                        self.handle_play_pause();
+                    }
                     self.notify(None)
                 }
                 SpircCommand::Pause => {
-                    self.handle_pause();
+                    if self.is_allowed(PlaybackAction::Pause) {
+                        self.handle_pause();
+                    }
                     self.notify(None)
                 }
                 SpircCommand::Prev => {
-                    self.handle_prev();
+                    if self.is_allowed(PlaybackAction::SkipPrev) {
+                        self.handle_prev();
+                    }
                     self.notify(None)
                 }
                 SpircCommand::Next => {
-                    self.handle_next();
+                    if self.is_allowed(PlaybackAction::SkipNext) {
+                        self.handle_next();
+                    }
                     self.notify(None)
                 }
                 SpircCommand::VolumeUp => {
@@ -627,15 +915,27 @@ impl SpircTask {
                     self.notify(None)
                 }
                 SpircCommand::Shuffle(shuffle) => {
-                    self.state.set_shuffle(shuffle);
+                    if self.is_allowed(PlaybackAction::ToggleShuffle) {
+                        self.set_shuffle(shuffle, None);
+                    }
+                    self.notify(None)
+                }
+                SpircCommand::ShuffleWithSeed(seed) => {
+                    if self.is_allowed(PlaybackAction::ToggleShuffle) {
+                        self.set_shuffle(true, Some(seed));
+                    }
                     self.notify(None)
                 }
                 SpircCommand::Repeat(repeat) => {
-                    self.state.set_repeat(repeat);
+                    if self.is_allowed(PlaybackAction::ToggleRepeatContext) {
+                        self.state.set_repeat(repeat);
+                    }
                     self.notify(None)
                 }
                 SpircCommand::SetPosition(position) => {
-                    self.handle_seek(position);
+                    if self.is_allowed(PlaybackAction::Seek) {
+                        self.handle_seek(position);
+                    }
                     self.notify(None)
                 }
                 SpircCommand::SetVolume(volume) => {
@@ -643,7 +943,30 @@ impl SpircTask {
                     self.notify(None)
                 }
                 SpircCommand::Load(command) => {
-                    self.handle_load(&command.into())?;
+                    if self.is_allowed(PlaybackAction::InterruptPlayback) {
+                        let shuffle_seed = if command.shuffle {
+                            command.shuffle_seed
+                        } else {
+                            None
+                        };
+                        self.handle_load(&command.into())?;
+                        if let Some(seed) = shuffle_seed {
+                            self.set_shuffle(true, Some(seed));
+                        }
+                    }
+                    self.notify(None)
+                }
+                SpircCommand::SetRestrictions(restrictions) => {
+                    self.restrictions.send_replace(restrictions);
+                    Ok(())
+                }
+                SpircCommand::SetPreset(preset, context_uri) => {
+                    self.handle_set_preset(preset, context_uri)
+                }
+                SpircCommand::PlayPreset(preset) => {
+                    if self.is_allowed(PlaybackAction::InterruptPlayback) {
+                        self.handle_play_preset(preset)?;
+                    }
                     self.notify(None)
                 }
                 _ => Ok(()),
@@ -655,6 +978,18 @@ impl SpircTask {
                     self.handle_activate();
                     self.notify(None)
                 }
+                SpircCommand::SetRestrictions(restrictions) => {
+                    self.restrictions.send_replace(restrictions);
+                    Ok(())
+                }
+                SpircCommand::SetPreset(preset, context_uri) => {
+                    self.handle_set_preset(preset, context_uri)
+                }
+                SpircCommand::PlayPreset(preset) => {
+                    trace!("Received SpircCommand::{:?}", cmd);
+                    self.handle_play_preset(preset)?;
+                    self.notify(None)
+                }
                 _ => {
                     warn!("SpircCommand::{:?} will be ignored while Not Active", cmd);
                     Ok(())
@@ -773,7 +1108,42 @@ impl SpircTask {
                     }
                     PlayerEvent::Unavailable { track_id, .. } => {
                         self.handle_unavailable(track_id);
-                        Ok(())
+                        // the player has given up on the track it was loading
+                        match self.play_status {
+                            SpircPlayStatus::LoadingPlay { .. }
+                            | SpircPlayStatus::LoadingPause { .. } => {
+                                match self.player.load_failure_policy() {
+                                    LoadFailurePolicy::Skip => self.handle_next(),
+                                    LoadFailurePolicy::Stop => self.handle_stop(),
+                                }
+                                self.notify(None)
+                            }
+                            _ => Ok(()),
+                        }
+                    }
+                    // Outside of the allowed hours every track is blocked, until they begin.
+                    PlayerEvent::Blocked {
+                        reason: BlockReason::OutsideAllowedHours,
+                        ..
+                    } => match self.play_status {
+                        SpircPlayStatus::LoadingPlay { .. }
+                        | SpircPlayStatus::LoadingPause { .. } => {
+                            self.handle_stop();
+                            self.notify(None)
+                        }
+                        _ => Ok(()),
+                    },
+                    PlayerEvent::Blocked { track_id, .. } => {
+                        self.handle_unavailable(track_id);
+                        // unlike tracks that failed to load, blocked tracks are always skipped
+                        match self.play_status {
+                            SpircPlayStatus::LoadingPlay { .. }
+                            | SpircPlayStatus::LoadingPause { .. } => {
+                                self.handle_next();
+                                self.notify(None)
+                            }
+                            _ => Ok(()),
+                        }
                     }
                     _ => Ok(()),
                 }
@@ -854,6 +1224,20 @@ impl SpircTask {
             return Err(SpircError::Ident(ident.to_string()).into());
         }
 
+        let strict = self.session.config().strict_protocol;
+        anomaly::check_unknown_fields(strict, &update)?;
+        let seq_nr = update.seq_nr();
+        if update.typ() == MessageType::kMessageTypeGoodbye {
+            self.remote_seq_nrs.remove(ident);
+        } else if let Some(last_seq_nr) = self.remote_seq_nrs.update(ident, seq_nr, update.typ()) {
+            let anomaly = ProtocolAnomaly::OutOfOrder {
+                ident: ident.to_owned(),
+                seq_nr,
+                last_seq_nr,
+            };
+            anomaly::report_message(strict, anomaly, &update)?;
+        }
+
         let old_client_id = self.session.client_id();
 
         for entry in update.device_state.metadata.iter() {
@@ -882,32 +1266,46 @@ impl SpircTask {
             MessageType::kMessageTypeHello => self.notify(Some(ident)),
 
             MessageType::kMessageTypeLoad => {
-                self.handle_load(update.state.get_or_default())?;
+                // Restrictions are only set while active, so this is about a load that would
+                // replace what is playing.
+                if self.is_allowed(PlaybackAction::InterruptPlayback) {
+                    self.handle_load(update.state.get_or_default())?;
+                }
                 self.notify(None)
             }
 
             MessageType::kMessageTypePlay => {
-                self.handle_play();
+                if self.is_allowed(PlaybackAction::Resume) {
+                    self.handle_play();
+                }
                 self.notify(None)
             }
 
             MessageType::kMessageTypePlayPause => {
-                self.handle_play_pause();
+                if self.is_allowed(self.play_pause_action()) {
+                    self.handle_play_pause();
+                }
                 self.notify(None)
             }
 
             MessageType::kMessageTypePause => {
-                self.handle_pause();
+                if self.is_allowed(PlaybackAction::Pause) {
+                    self.handle_pause();
+                }
                 self.notify(None)
             }
 
             MessageType::kMessageTypeNext => {
-                self.handle_next();
+                if self.is_allowed(PlaybackAction::SkipNext) {
+                    self.handle_next();
+                }
                 self.notify(None)
             }
 
             MessageType::kMessageTypePrev => {
-                self.handle_prev();
+                if self.is_allowed(PlaybackAction::SkipPrev) {
+                    self.handle_prev();
+                }
                 self.notify(None)
             }
 
@@ -922,40 +1320,35 @@ impl SpircTask {
             }
 
             MessageType::kMessageTypeRepeat => {
-                let repeat = update.state.repeat();
-                self.state.set_repeat(repeat);
+                if self.is_allowed(PlaybackAction::ToggleRepeatContext) {
+                    let repeat = update.state.repeat();
+                    self.state.set_repeat(repeat);
 
-                self.player.emit_repeat_changed_event(repeat);
+                    self.player.emit_repeat_changed_event(repeat);
+                }
 
                 self.notify(None)
             }
 
             MessageType::kMessageTypeShuffle => {
-                let shuffle = update.state.shuffle();
-                self.state.set_shuffle(shuffle);
-                if shuffle {
-                    let current_index = self.state.playing_track_index();
-                    let tracks = &mut self.state.track;
-                    if !tracks.is_empty() {
-                        tracks.swap(0, current_index as usize);
-                        if let Some((_, rest)) = tracks.split_first_mut() {
-                            let mut rng = rand::thread_rng();
-                            rest.shuffle(&mut rng);
-                        }
-                        self.state.set_playing_track_index(0);
-                    }
+                if self.is_allowed(PlaybackAction::ToggleShuffle) {
+                    self.set_shuffle(update.state.shuffle(), None);
                 }
-                self.player.emit_shuffle_changed_event(shuffle);
-
                 self.notify(None)
             }
 
             MessageType::kMessageTypeSeek => {
-                self.handle_seek(update.position());
+                if self.is_allowed(PlaybackAction::Seek) {
+                    self.handle_seek(update.position());
+                }
                 self.notify(None)
             }
 
             MessageType::kMessageTypeReplace => {
+                if !self.is_allowed(PlaybackAction::SetQueue) {
+                    return self.notify(None);
+                }
+
                 let context_uri = update.state.context_uri().to_owned();
 
                 // completely ignore local playback.
@@ -991,6 +1384,8 @@ impl SpircTask {
                 self.notify(None)
             }
 
+            MessageType::kMessageTypeLogout => self.handle_logout(),
+
             MessageType::kMessageTypeNotify => {
                 if self.device.is_active()
                     && update.device_state.is_active()
@@ -1007,12 +1402,23 @@ impl SpircTask {
 
     fn handle_disconnect(&mut self) {
         self.device.set_is_active(false);
+        self.restrictions.send_replace(Restrictions::default());
         self.handle_stop();
 
         self.player
             .emit_session_disconnected_event(self.session.connection_id(), self.session.username());
     }
 
+    fn handle_logout(&mut self) -> Result<(), Error> {
+        info!("Logged out remotely");
+        // The credentials are revoked whether or not the other devices hear about it.
+        let goodbye = CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
+        self.handle_disconnect();
+        self.session.logout();
+        self.shutdown = true;
+        goodbye
+    }
+
     fn handle_stop(&mut self) {
         self.player.stop();
     }
@@ -1039,7 +1445,8 @@ impl SpircTask {
         self.player
             .emit_filter_explicit_content_changed_event(self.session.filter_explicit_content());
 
-        self.player.emit_shuffle_changed_event(self.state.shuffle());
+        self.player
+            .emit_shuffle_changed_event(self.state.shuffle(), self.shuffle_seed());
 
         self.player.emit_repeat_changed_event(self.state.repeat());
     }
@@ -1069,6 +1476,109 @@ impl SpircTask {
         Ok(())
     }
 
+    // Loads a saved state paused, without becoming the active device: that happens once the
+    // user starts playing it, or another device takes over.
+    fn restore(&mut self, state: &State) {
+        if state.context_uri().starts_with("spotify:local-files") {
+            return;
+        }
+
+        self.update_tracks(state);
+        if !self.state.track.is_empty() {
+            self.load_track(false, state.position_ms());
+        }
+    }
+
+    fn handle_set_preset(&mut self, preset: u8, context_uri: Option<String>) -> Result<(), Error> {
+        let mut presets = self.presets.borrow().clone();
+        match context_uri {
+            Some(context_uri) => presets.set(preset, &context_uri)?,
+            None => {
+                presets.remove(preset);
+            }
+        }
+
+        if let Some(cache) = self.session.cache() {
+            presets.save(cache);
+        }
+        self.presets.send_replace(presets);
+        Ok(())
+    }
+
+    fn handle_play_preset(&mut self, preset: u8) -> Result<(), Error> {
+        let context_uri = self
+            .presets
+            .borrow()
+            .get(preset)
+            .map(str::to_owned)
+            .ok_or(PresetsError::NotSet(preset))?;
+        info!("Playing preset {} <{}>", preset, context_uri);
+
+        // Load the context without tracks, which are added once it is resolved.
+        let mut state = State::new();
+        state.set_context_uri(context_uri);
+        state.set_status(PlayStatus::kPlayStatusPlay);
+        self.handle_load(&state)?;
+        self.play_when_resolved = Some(self.command_received_at.unwrap_or_else(Instant::now));
+        Ok(())
+    }
+
+    fn handle_context_resolved(&mut self, context: Option<PageContext>) {
+        if std::mem::replace(&mut self.resolving_next_page, false) {
+            match context {
+                Some(context) => {
+                    self.context = Some(context);
+                    self.update_tracks_from_context();
+                }
+                None => {
+                    // The context ends with the tracks we have then. Its last page is kept
+                    // for autoplay to follow on from.
+                    warn!(
+                        "Unable to resolve the next page of <{}>",
+                        self.state.context_uri()
+                    );
+                    self.paged_context = false;
+                }
+            }
+            if std::mem::take(&mut self.next_when_resolved) {
+                self.handle_next();
+            }
+            if let Err(e) = self.notify(None) {
+                error!("Unable to notify about the next page of the context: {}", e);
+            }
+            return;
+        }
+
+        self.context = context;
+
+        let command_received_at = match self.play_when_resolved.take() {
+            Some(command_received_at) => command_received_at,
+            None => return,
+        };
+
+        if self.context.is_none() {
+            warn!("Unable to start playing an unresolved context");
+            return;
+        }
+        // Only the first page is resolved upfront, so that large contexts don't take long
+        // to start or take up a lot of memory.
+        self.paged_context = true;
+        self.update_tracks_from_context();
+
+        if self.state.track.is_empty() {
+            warn!("Context <{}> has no tracks", self.state.context_uri());
+            return;
+        }
+
+        self.state.set_playing_track_index(0);
+        self.command_received_at = Some(command_received_at);
+        self.load_track(true, 0);
+        self.command_received_at = None;
+        if let Err(e) = self.notify(None) {
+            error!("Unable to notify about the loaded context: {}", e);
+        }
+    }
+
     fn handle_play(&mut self) {
         match self.play_status {
             SpircPlayStatus::Paused {
@@ -1096,6 +1606,26 @@ impl SpircTask {
         self.set_volume(current_volume);
     }
 
+    // Commands that are disallowed are ignored, but still answered with a notify so that
+    // the remote resyncs its state.
+    fn is_allowed(&self, action: PlaybackAction) -> bool {
+        let restrictions = self.restrictions.borrow();
+        let reasons = restrictions.reasons(action);
+        if !reasons.is_empty() {
+            warn!("{:?} is disallowed: {}", action, reasons.join(", "));
+        }
+        reasons.is_empty()
+    }
+
+    fn play_pause_action(&self) -> PlaybackAction {
+        match self.play_status {
+            SpircPlayStatus::Playing { .. } | SpircPlayStatus::LoadingPlay { .. } => {
+                PlaybackAction::Pause
+            }
+            _ => PlaybackAction::Resume,
+        }
+    }
+
     fn handle_play_pause(&mut self) {
         match self.play_status {
             SpircPlayStatus::Paused { .. } | SpircPlayStatus::LoadingPause { .. } => {
@@ -1198,11 +1728,13 @@ impl SpircTask {
     fn handle_unavailable(&mut self, track_id: SpotifyId) {
         let unavailables = self.get_track_index_for_spotify_id(&track_id, 0);
         for &index in unavailables.iter() {
+            let gid = self.state.track[index].gid().to_vec();
             let mut unplayable_track_ref = TrackRef::new();
-            unplayable_track_ref.set_gid(self.state.track[index].gid().to_vec());
+            unplayable_track_ref.set_gid(gid.clone());
             // Misuse context field to flag the track
             unplayable_track_ref.set_context(String::from("NonPlayable"));
             std::mem::swap(&mut self.state.track[index], &mut unplayable_track_ref);
+            self.unavailable_tracks.insert(gid, unplayable_track_ref);
             debug!(
                 "Marked <{:?}> at {:?} as NonPlayable",
                 self.state.track[index], index,
@@ -1211,14 +1743,68 @@ impl SpircTask {
         self.handle_preload_next_track();
     }
 
+    // The session reconnected after losing its connection, during which the other devices
+    // may have dropped this one. The Mercury subscriptions were renewed by the session.
+    fn handle_reconnected(&mut self) {
+        self.remote_seq_nrs.clear();
+        if let Err(e) = self.hello().and_then(|_| self.notify(None)) {
+            error!("could not announce device after reconnecting: {}", e);
+        }
+    }
+
+    // The tracks played before the current one, to seed the context with. Only those before
+    // the current playback position, and none at all in a private session.
+    fn previous_tracks(&self) -> Vec<SpotifyId> {
+        let current_position = if self.private_session {
+            0
+        } else {
+            self.state.playing_track_index() as usize
+        };
+        self.state.track[..current_position]
+            .iter()
+            .filter_map(|t| SpotifyId::try_from(t).ok())
+            .collect()
+    }
+
+    // Tracks that were unavailable in the previous country may be available now.
+    fn handle_country_changed(&mut self) {
+        for track in self.state.track.iter_mut() {
+            if track.context() == "NonPlayable" {
+                if let Some(original) = self.unavailable_tracks.get(track.gid()) {
+                    debug!("Unmarking <{:?}> as NonPlayable", original);
+                    *track = original.clone();
+                }
+            }
+        }
+        self.unavailable_tracks.clear();
+
+        // The player discarded the preloaded track, which may not be the next one anymore.
+        if let SpircPlayStatus::Playing {
+            preloading_of_next_track_triggered: true,
+            ..
+        }
+        | SpircPlayStatus::Paused {
+            preloading_of_next_track_triggered: true,
+            ..
+        } = self.play_status
+        {
+            if let Some(track_id) = self.preview_next_track() {
+                self.player.preload(track_id);
+            }
+        }
+    }
+
     fn handle_next(&mut self) {
         let context_uri = self.state.context_uri().to_owned();
         let mut tracks_len = self.state.track.len() as u32;
         let mut new_index = self.consume_queued_track() as u32;
         let mut continue_playing = self.state.status() == PlayStatus::kPlayStatusPlay;
 
-        let update_tracks =
-            self.autoplay_context && tracks_len - new_index < CONTEXT_FETCH_THRESHOLD;
+        let near_end = tracks_len.saturating_sub(new_index) < CONTEXT_FETCH_THRESHOLD;
+        let update_tracks = self.autoplay_context && near_end;
+        if self.paged_context && !self.autoplay_context && near_end {
+            self.resolve_next_page();
+        }
 
         debug!(
             "At track {:?} of {:?} <{:?}> update [{}]",
@@ -1232,11 +1818,20 @@ impl SpircTask {
         if update_tracks {
             if let Some(ref context) = self.context {
                 self.resolve_context = Some(context.next_page_url.to_owned());
+                let index = self.state.playing_track_index();
                 self.update_tracks_from_context();
+                new_index -= index - self.state.playing_track_index();
                 tracks_len = self.state.track.len() as u32;
             }
         }
 
+        // The next page is late, so play on from it once it is resolved.
+        if new_index >= tracks_len && self.resolving_next_page {
+            debug!("Waiting for the next page of <{}>", context_uri);
+            self.next_when_resolved = true;
+            return;
+        }
+
         // When not in autoplay, either start autoplay or loop back to the start
         if new_index >= tracks_len {
             // for some contexts there is no autoplay, such as shows and episodes
@@ -1246,6 +1841,8 @@ impl SpircTask {
                 debug!("Starting autoplay for <{}>", context_uri);
                 // force reloading the current context with an autoplay context
                 self.autoplay_context = true;
+                self.autoplay_seed = None;
+                self.resolving_next_page = false;
                 self.resolve_context = Some(self.state.context_uri().to_owned());
                 self.update_tracks_from_context();
                 self.player.set_auto_normalise_as_album(false);
@@ -1316,11 +1913,57 @@ impl SpircTask {
         self.set_volume(volume);
     }
 
+    fn set_shuffle(&mut self, shuffle: bool, seed: Option<u64>) {
+        // Go back to the original order first, so that shuffling again with the
+        // same seed results in the same order.
+        if let Some(previous) = self.shuffle.take() {
+            let (tracks, index) =
+                previous.restore(&self.state.track, self.state.playing_track_index() as usize);
+            self.state.track = tracks;
+            self.state.set_playing_track_index(index as u32);
+        }
+
+        if shuffle {
+            let seed = seed.unwrap_or_else(rand::random);
+            debug!("Shuffling tracks with seed {}", seed);
+            let index = self.state.playing_track_index() as usize;
+            self.shuffle = Some(Shuffle::apply(&mut self.state.track, index, seed));
+            self.state.set_playing_track_index(0);
+        }
+
+        self.state.set_shuffle(shuffle);
+        self.player
+            .emit_shuffle_changed_event(shuffle, self.shuffle_seed());
+    }
+
+    fn shuffle_seed(&self) -> Option<u64> {
+        self.shuffle.as_ref().map(Shuffle::seed)
+    }
+
     fn handle_end_of_track(&mut self) -> Result<(), Error> {
         self.handle_next();
         self.notify(None)
     }
 
+    // The current state as a command to load it elsewhere.
+    fn load_command(&mut self) -> SpircLoadCommand {
+        let start_playing = matches!(
+            self.play_status,
+            SpircPlayStatus::Playing { .. } | SpircPlayStatus::LoadingPlay { .. }
+        );
+        SpircLoadCommand {
+            context_uri: self.state.context_uri().to_owned(),
+            start_playing,
+            // the tracks are in shuffled order already
+            shuffle: self.state.shuffle(),
+            shuffle_seed: None,
+            repeat: self.state.repeat(),
+            playing_track_index: self.state.playing_track_index(),
+            position_ms: self.position(),
+            tracks: self.state.track.clone(),
+        }
+    }
+
     fn position(&mut self) -> u32 {
         match self.play_status {
             SpircPlayStatus::Stopped => 0,
@@ -1333,27 +1976,142 @@ impl SpircTask {
         }
     }
 
+    // Leaves out the autoplay tracks that don't satisfy the constraints, fetching further
+    // pages if none do.
+    async fn constrain_autoplay(&mut self, mut context: PageContext) -> PageContext {
+        if !self.autoplay_constraints.is_constrained() {
+            return context;
+        }
+
+        if self.autoplay_seed.is_none() {
+            let tracks = &self.state.track;
+            let current = (self.state.playing_track_index() as usize).min(tracks.len());
+            let finished = if current > 0 {
+                &tracks[..current]
+            } else {
+                &tracks[..]
+            };
+            let finished: Vec<SpotifyId> = finished
+                .iter()
+                .filter_map(|t| SpotifyId::try_from(t).ok())
+                .collect();
+
+            self.autoplay_seed =
+                Some(AutoplaySeed::new(&self.session, &self.autoplay_constraints, &finished).await);
+        }
+        let seed = self.autoplay_seed.get_or_insert_with(Default::default);
+
+        for _ in 0..CONSTRAINED_AUTOPLAY_MAX_PAGES {
+            let tracks = std::mem::take(&mut context.tracks);
+            match seed
+                .filter(&self.session, &self.autoplay_constraints, tracks)
+                .await
+            {
+                Ok(tracks) => context.tracks = tracks,
+                Err(e) => warn!("Unable to apply autoplay constraints: {}", e),
+            }
+
+            if !context.tracks.is_empty() || context.next_page_url.is_empty() {
+                break;
+            }
+
+            debug!("No autoplay tracks satisfy the constraints, trying the next page");
+            let next_page = match self
+                .session
+                .spclient()
+                .get_next_page(&context.next_page_url)
+                .await
+            {
+                Ok(next_page) => next_page,
+                Err(e) => {
+                    warn!("Unable to get the next autoplay page: {}", e);
+                    break;
+                }
+            };
+            match serde_json::from_slice::<PageContext>(&next_page) {
+                Ok(next_page) => context = next_page,
+                Err(e) => {
+                    warn!("Unable to parse the next autoplay page: {}", e);
+                    break;
+                }
+            }
+        }
+
+        context
+    }
+
+    // Resolves a page of a collection: the first, or the next one when its tracks are
+    // being played.
+    async fn resolve_collection(&mut self, collection: SpotifyCollection) -> Option<PageContext> {
+        let page_token = if self.resolving_next_page {
+            self.collection_page_token.take()
+        } else {
+            None
+        };
+        let ids = match get_collection_tracks_page(&self.session, collection, page_token.as_deref())
+            .await
+        {
+            Ok((ids, next_page_token)) => {
+                self.collection_page_token = next_page_token;
+                ids
+            }
+            Err(e) => {
+                error!("Unable to resolve <{}>: {}", collection, e);
+                return None;
+            }
+        };
+
+        let tracks: Vec<TrackRef> = ids
+            .iter()
+            .filter_map(|id| {
+                let mut track = TrackRef::new();
+                track.set_gid(id.to_raw().to_vec());
+                track.set_uri(id.to_uri().ok()?);
+                Some(track)
+            })
+            .collect();
+        info!("Resolved {:?} tracks from <{}>", tracks.len(), collection);
+
+        Some(PageContext {
+            tracks,
+            ..Default::default()
+        })
+    }
+
+    // Resolves the next page of the context being played, unless there is none or it is
+    // being resolved already.
+    fn resolve_next_page(&mut self) {
+        if self.resolving_next_page || self.resolve_context.is_some() {
+            return;
+        }
+
+        let next_page = match &self.context {
+            Some(context) if !context.next_page_url.is_empty() => context.next_page_url.clone(),
+            Some(_) if self.collection_page_token.is_some() => self.state.context_uri().to_owned(),
+            _ => return,
+        };
+        debug!("Resolving the next page of <{}>", self.state.context_uri());
+        // The current page is only replaced once the next one is resolved, so that autoplay
+        // can still follow on from it if that fails.
+        self.resolving_next_page = true;
+        self.resolve_context = Some(next_page);
+    }
+
     fn update_tracks_from_context(&mut self) {
         if let Some(ref context) = self.context {
             let new_tracks = &context.tracks;
 
             debug!("Adding {:?} tracks from context to frame", new_tracks.len());
 
-            let mut track_vec = self.state.track.clone();
-            if let Some(head) = track_vec.len().checked_sub(CONTEXT_TRACKS_HISTORY) {
-                track_vec.drain(0..head);
-            }
-            track_vec.extend_from_slice(new_tracks);
-            self.state.track = track_vec;
-
-            // Update playing index
-            if let Some(new_index) = self
-                .state
-                .playing_track_index()
-                .checked_sub(CONTEXT_TRACKS_HISTORY as u32)
-            {
-                self.state.set_playing_track_index(new_index);
-            }
+            // Only keep a few of the tracks before the playing one, so that the tracks don't
+            // grow without bounds while paging through a large context.
+            let index = self.state.playing_track_index();
+            let head = (index as usize)
+                .saturating_sub(CONTEXT_TRACKS_HISTORY)
+                .min(self.state.track.len());
+            self.state.track.drain(0..head);
+            self.state.track.extend_from_slice(new_tracks);
+            self.state.set_playing_track_index(index - head as u32);
         } else {
             warn!("No context to update from!");
         }
@@ -1371,6 +2129,13 @@ impl SpircTask {
         // First the tracks from the requested context, without autoplay.
         // We will transition into autoplay after the latest track of this context.
         self.autoplay_context = false;
+        self.autoplay_seed = None;
+        self.unavailable_tracks.clear();
+        self.play_when_resolved = None;
+        self.paged_context = false;
+        self.resolving_next_page = false;
+        self.next_when_resolved = false;
+        self.collection_page_token = None;
         self.resolve_context = Some(context_uri.to_owned());
 
         self.player
@@ -1378,6 +2143,8 @@ impl SpircTask {
 
         self.state.set_playing_track_index(index);
         self.state.track = tracks.to_vec();
+        // A new track list replaces the one we could restore when unshuffling.
+        self.shuffle = None;
         self.state.set_context_uri(context_uri.to_owned());
         // has_shuffle/repeat seem to always be true in these replace msgs,
         // but to replicate the behaviour of the Android client we have to
@@ -1410,6 +2177,26 @@ impl SpircTask {
         track_ref.context() == "NonPlayable"
     }
 
+    // The playable tracks after `index`, without wrapping around, as many as the player
+    // prefetches.
+    fn prefetch_upcoming_tracks(&self, index: u32) {
+        let prefetch_count = self.player.prefetch_count();
+        if prefetch_count == 0 {
+            return;
+        }
+
+        let track_ids = self
+            .state
+            .track
+            .iter()
+            .skip(index as usize + 1)
+            .filter(|track_ref| !self.track_ref_is_unavailable(track_ref))
+            .filter_map(|track_ref| SpotifyId::try_from(track_ref).ok())
+            .take(prefetch_count)
+            .collect();
+        self.player.prefetch(track_ids);
+    }
+
     fn get_track_id_to_play_from_playlist(&self, index: u32) -> Option<(SpotifyId, u32)> {
         let tracks_len = self.state.track.len();
 
@@ -1460,14 +2247,41 @@ impl SpircTask {
         }
     }
 
+    // Enhanced contexts announce the tracks with segments, which are skipped as unplayable,
+    // but reported when passing them.
+    fn emit_skipped_segments(&self, from: usize, to: usize) {
+        let context = match self.context {
+            Some(ref context) if !context.segments.is_empty() => context,
+            _ => return,
+        };
+
+        let tracks_len = self.state.track.len();
+        let mut index = if from < tracks_len { from } else { 0 };
+        while index != to {
+            if let Some(segment) = context.segments.get(self.state.track[index].uri()) {
+                self.player
+                    .emit_context_segment_event(segment.uri.clone(), segment.metadata.clone());
+            }
+            index = (index + 1) % tracks_len;
+        }
+    }
+
     fn load_track(&mut self, start_playing: bool, position_ms: u32) {
         let index = self.state.playing_track_index();
 
         match self.get_track_id_to_play_from_playlist(index) {
-            Some((track, index)) => {
-                self.state.set_playing_track_index(index);
+            Some((track, new_index)) => {
+                self.emit_skipped_segments(index as usize, new_index as usize);
+                self.state.set_playing_track_index(new_index);
 
-                self.player.load(track, start_playing, position_ms);
+                match self.command_received_at {
+                    Some(received_at) => {
+                        self.player
+                            .load_for_command(track, start_playing, position_ms, received_at)
+                    }
+                    None => self.player.load(track, start_playing, position_ms),
+                }
+                self.prefetch_upcoming_tracks(new_index);
 
                 self.update_state_position(position_ms);
                 if start_playing {
@@ -1484,6 +2298,23 @@ impl SpircTask {
         }
     }
 
+    fn save_state(&mut self) {
+        if self.state_saver.is_none() || self.state.track.is_empty() {
+            return;
+        }
+
+        let snapshot = ConnectStateSnapshot::from(&self.load_command());
+        if let Some(state_saver) = &self.state_saver {
+            state_saver.send_if_modified(|saved| {
+                if saved.as_ref() == Some(&snapshot) {
+                    return false;
+                }
+                *saved = Some(snapshot);
+                true
+            });
+        }
+    }
+
     fn hello(&mut self) -> Result<(), Error> {
         CommandSender::new(self, MessageType::kMessageTypeHello).send()
     }
@@ -1499,6 +2330,10 @@ impl SpircTask {
             return Ok(());
         }
 
+        if self.device.is_active() {
+            self.save_state();
+        }
+
         trace!("Sending status to server: [{:?}]", status);
         let mut cs = CommandSender::new(self, MessageType::kMessageTypeNotify);
         if let Some(s) = recipient {
@@ -1523,6 +2358,32 @@ impl SpircTask {
     }
 }
 
+// Saves the snapshots sent to it with `store` one after the other, skipping those that were
+// replaced in the meantime. It stops once the last one was saved after the sender is dropped.
+fn spawn_state_saver(
+    store: Arc<dyn ConnectStateStore>,
+) -> watch::Sender<Option<ConnectStateSnapshot>> {
+    let (saver, mut snapshots) = watch::channel(None);
+
+    tokio::spawn(async move {
+        while snapshots.changed().await.is_ok() {
+            let snapshot = match snapshots.borrow_and_update().clone() {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+
+            let store = store.clone();
+            match tokio::task::spawn_blocking(move || store.save(&snapshot)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => warn!("Unable to save the Connect state: {}", e),
+                Err(e) => warn!("Unable to save the Connect state: {}", e),
+            }
+        }
+    });
+
+    saver
+}
+
 impl Drop for SpircTask {
     fn drop(&mut self) {
         debug!("drop Spirc[{}]", self.spirc_id);
@@ -1569,3 +2430,487 @@ impl<'a> CommandSender<'a> {
         self.spirc.sender.send(self.frame.write_to_bytes()?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        core::SessionConfig,
+        playback::{
+            audio_backend::{Sink, SinkResult},
+            config::PlayerConfig,
+            convert::Converter,
+            decoder::AudioPacket,
+            mixer::{softmixer::SoftMixer, MixerConfig},
+        },
+    };
+
+    const DEVICE: &str = "f6b1e3d2";
+    const CONTEXT_URI: &str = "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M";
+
+    struct NoSink;
+
+    impl Sink for NoSink {
+        fn write(&mut self, _: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            Ok(())
+        }
+    }
+
+    // A task that isn't connected, so that everything it sends is dropped. The session
+    // needs a runtime.
+    fn task(autoplay: bool) -> SpircTask {
+        let session = Session::new(
+            SessionConfig {
+                autoplay: Some(autoplay),
+                ..Default::default()
+            },
+            None,
+        );
+        let mixer = Arc::new(SoftMixer::open(MixerConfig::default()));
+        let player = Player::new(
+            PlayerConfig::default(),
+            session.clone(),
+            mixer.get_soft_volume(),
+            || Box::new(NoSink),
+        );
+        let (restrictions, _) = watch::channel(Restrictions::default());
+        let (presets, _) = watch::channel(Presets::default());
+
+        SpircTask {
+            player,
+            mixer,
+            sequence: SeqGenerator::new(1),
+            ident: String::new(),
+            device: initial_device_state(ConnectConfig::default()),
+            state: initial_state(),
+            play_request_id: None,
+            play_status: SpircPlayStatus::Stopped,
+            remote_update: Box::pin(stream::pending()),
+            connection_id_update: Box::pin(stream::pending()),
+            user_attributes_update: Box::pin(stream::pending()),
+            user_attributes_mutation: Box::pin(stream::pending()),
+            trace_recorder: None,
+            replaying: false,
+            sender: session.mercury().sender(String::new()),
+            commands: None,
+            player_events: None,
+            session_events: session.get_session_event_channel(),
+            shutdown: false,
+            session,
+            resolve_context: None,
+            autoplay_context: false,
+            autoplay_constraints: AutoplayConstraints::default(),
+            autoplay_seed: None,
+            private_session: false,
+            shuffle: None,
+            context: None,
+            unavailable_tracks: HashMap::new(),
+            remote_seq_nrs: RemoteSeqNrs::default(),
+            restrictions,
+            presets,
+            play_when_resolved: None,
+            command_received_at: None,
+            paged_context: false,
+            resolving_next_page: false,
+            next_when_resolved: false,
+            collection_page_token: None,
+            state_saver: None,
+            spirc_id: 0,
+        }
+    }
+
+    fn page(first: u8, count: u8, next_page_url: &str) -> PageContext {
+        PageContext {
+            tracks: (first..first + count)
+                .map(|n| {
+                    let mut track = TrackRef::new();
+                    track.set_gid(vec![n; 16]);
+                    track
+                })
+                .collect(),
+            next_page_url: next_page_url.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Plays the first page of a context that was resolved by us, at `index`.
+    fn playing_page(task: &mut SpircTask, page: PageContext, index: u32) {
+        task.state.set_context_uri(CONTEXT_URI.to_owned());
+        task.state.set_status(PlayStatus::kPlayStatusPlay);
+        task.state.track = page.tracks.clone();
+        task.state.set_playing_track_index(index);
+        task.context = Some(page);
+        task.paged_context = true;
+    }
+
+    fn playing_gid(task: &SpircTask) -> u8 {
+        task.state.track[task.state.playing_track_index() as usize].gid()[0]
+    }
+
+    #[tokio::test]
+    async fn resolves_the_next_page_near_the_end() {
+        let mut task = task(false);
+        playing_page(&mut task, page(0, 8, "hm://next/1"), 2);
+
+        task.handle_next();
+        assert!(!task.resolving_next_page);
+        task.handle_next();
+        assert!(task.resolving_next_page);
+        assert_eq!(task.resolve_context.as_deref(), Some("hm://next/1"));
+        // the current page is kept until the next one is there
+        assert_eq!(task.context.as_ref().unwrap().next_page_url, "hm://next/1");
+        assert_eq!(playing_gid(&task), 4);
+
+        // and is only resolved once
+        task.resolve_context = None;
+        task.handle_next();
+        assert_eq!(task.resolve_context, None);
+
+        task.handle_context_resolved(Some(page(8, 8, "hm://next/2")));
+        assert!(!task.resolving_next_page);
+        assert_eq!(task.state.track.len(), 16);
+        assert_eq!(playing_gid(&task), 5);
+        assert_eq!(task.context.as_ref().unwrap().next_page_url, "hm://next/2");
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_late_page() {
+        let mut task = task(true);
+        playing_page(&mut task, page(0, 4, "hm://next/1"), 3);
+
+        task.handle_next();
+        assert!(task.resolving_next_page);
+        assert!(!task.autoplay_context);
+        assert_eq!(playing_gid(&task), 3);
+
+        task.handle_context_resolved(Some(page(4, 4, "")));
+        assert!(!task.autoplay_context);
+        assert_eq!(playing_gid(&task), 4);
+        assert_eq!(task.state.status(), PlayStatus::kPlayStatusPlay);
+    }
+
+    #[tokio::test]
+    async fn autoplays_when_the_next_page_fails() {
+        let mut task = task(true);
+        playing_page(&mut task, page(0, 4, "hm://next/1"), 3);
+
+        task.handle_next();
+        assert!(task.resolving_next_page);
+        task.resolve_context = None;
+
+        task.handle_context_resolved(None);
+        assert!(!task.paged_context);
+        assert!(task.autoplay_context);
+        assert_eq!(task.resolve_context.as_deref(), Some(CONTEXT_URI));
+    }
+
+    #[tokio::test]
+    async fn loops_back_when_the_next_page_fails_without_autoplay() {
+        let mut task = task(false);
+        task.state.set_repeat(true);
+        playing_page(&mut task, page(0, 4, "hm://next/1"), 3);
+
+        task.handle_next();
+        task.handle_context_resolved(None);
+        assert!(!task.autoplay_context);
+        assert_eq!(playing_gid(&task), 0);
+        assert_eq!(task.state.track.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_few_tracks_of_the_previous_pages() {
+        let mut task = task(false);
+        playing_page(&mut task, page(0, 20, "hm://next/1"), 18);
+
+        task.handle_next();
+        task.handle_context_resolved(Some(page(20, 20, "hm://next/2")));
+        assert_eq!(playing_gid(&task), 19);
+        assert_eq!(
+            task.state.track.len(),
+            CONTEXT_TRACKS_HISTORY + 1 + 20,
+            "{:?}",
+            task.state
+                .track
+                .iter()
+                .map(|t| t.gid()[0])
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(task.state.track[0].gid()[0], 9);
+    }
+
+    #[tokio::test]
+    async fn retries_unavailable_tracks_when_the_country_changes() {
+        let mut task = task(false);
+        playing_page(&mut task, page(0, 4, ""), 0);
+        let original = task.state.track[2].clone();
+
+        task.handle_unavailable(SpotifyId::from_raw(&[2; 16]).unwrap());
+        assert!(task.track_ref_is_unavailable(&task.state.track[2]));
+        assert!(!task.track_ref_is_unavailable(&task.state.track[1]));
+
+        task.handle_country_changed();
+        assert_eq!(task.state.track[2], original);
+        assert!(task.unavailable_tracks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn plays_a_preset_once_resolved_with_the_time_of_its_command() {
+        let mut task = task(false);
+        let mut presets = Presets::default();
+        presets.set(1, CONTEXT_URI).unwrap();
+        task.presets.send_replace(presets);
+
+        let received_at = Instant::now();
+        task.command_received_at = Some(received_at);
+        task.handle_play_preset(1).unwrap();
+        task.command_received_at = None;
+        assert_eq!(task.play_when_resolved, Some(received_at));
+
+        task.handle_context_resolved(Some(page(0, 4, "")));
+        assert_eq!(task.play_when_resolved, None);
+        assert_eq!(task.command_received_at, None);
+        assert_eq!(task.state.track.len(), 4);
+        assert_eq!(playing_gid(&task), 0);
+    }
+
+    #[test]
+    fn hides_the_device_from_the_public_device_list() {
+        let hidden = |config: ConnectConfig| {
+            initial_device_state(config)
+                .capabilities
+                .iter()
+                .find(|c| c.typ() == protocol::spirc::CapabilityType::kHidden)
+                .map(|c| c.intValue.clone())
+        };
+
+        assert_eq!(hidden(ConnectConfig::default()), Some(vec![0]));
+        assert_eq!(
+            hidden(ConnectConfig {
+                hidden: true,
+                ..Default::default()
+            }),
+            Some(vec![1])
+        );
+    }
+
+    #[tokio::test]
+    async fn shares_no_listening_history_in_a_private_session() {
+        let mut task = task(true);
+        playing_page(&mut task, page(0, 4, ""), 2);
+        let gids = |tracks: Vec<SpotifyId>| -> Vec<u8> {
+            tracks.iter().map(|id| id.to_raw()[0]).collect()
+        };
+
+        assert_eq!(gids(task.previous_tracks()), vec![0, 1]);
+
+        task.private_session = true;
+        assert!(task.previous_tracks().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shuts_down_when_logged_out_remotely() {
+        let mut task = task(false);
+        task.ident = DEVICE.to_owned();
+        task.device.set_is_active(true);
+        let mut events = task.session.get_session_event_channel();
+
+        // saying goodbye fails, as the task isn't connected
+        let _ = task.handle_remote_update(remote_frame(MessageType::kMessageTypeLogout));
+
+        assert!(task.shutdown);
+        assert!(!task.device.is_active());
+        assert!(task.session.is_logged_out());
+        assert_eq!(events.try_recv().ok(), Some(SessionEvent::LoggedOut));
+    }
+
+    #[tokio::test]
+    async fn replays_a_trace() {
+        let path = std::env::temp_dir().join(format!(
+            "librespot-spirc-replay-{}.jsonl",
+            std::process::id()
+        ));
+        let frame = |seq_nr: u32, recipient: &str, volume: u32| {
+            let mut frame = Frame::new();
+            frame.set_ident("phone".to_owned());
+            frame.set_seq_nr(seq_nr);
+            frame.set_typ(MessageType::kMessageTypeVolume);
+            frame.set_volume(volume);
+            frame.recipient.push(recipient.to_owned());
+            frame
+        };
+
+        // recorded by another device and account
+        let recorder = SpircTraceRecorder::create(&path, "recorder").unwrap();
+        for frame in [
+            frame(1, "recorder", 1000),
+            frame(2, "elsewhere", 2000),
+            frame(3, "recorder", 65535),
+            frame(4, "elsewhere", 0),
+        ] {
+            recorder.record_remote_update("someone", &frame).unwrap();
+        }
+        drop(recorder);
+        let trace = SpircTrace::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut task = task(false);
+        task.ident = DEVICE.to_owned();
+        task.remote_update = Box::pin(trace.into_stream(DEVICE).unwrap().fuse());
+        task.replaying = true;
+        let mixer = task.mixer.clone();
+
+        // runs until the trace is exhausted
+        task.run().await;
+        assert_eq!(mixer.volume(), 65535);
+    }
+
+    // The task isn't connected, so the tests ignore that notifying the remotes fails.
+    fn remote_frame(typ: MessageType) -> Frame {
+        let mut frame = Frame::new();
+        frame.set_ident("phone".to_owned());
+        frame.set_typ(typ);
+        frame.recipient.push(DEVICE.to_owned());
+        frame
+    }
+
+    fn restricted(action: PlaybackAction) -> Restrictions {
+        let mut restrictions = Restrictions::default();
+        restrictions.disallow(action, "ad");
+        restrictions
+    }
+
+    #[tokio::test]
+    async fn ignores_disallowed_commands() {
+        let mut task = task(false);
+        task.ident = DEVICE.to_owned();
+        task.device.set_is_active(true);
+        playing_page(&mut task, page(0, 4, ""), 1);
+        // as watched through Spirc::restrictions
+        let restrictions = task.restrictions.subscribe();
+
+        let _ = task.handle_command(SpircCommand::SetRestrictions(restricted(
+            PlaybackAction::SkipNext,
+        )));
+        assert!(!restrictions.borrow().is_allowed(PlaybackAction::SkipNext));
+
+        let _ = task.handle_command(SpircCommand::Next);
+        let _ = task.handle_remote_update(remote_frame(MessageType::kMessageTypeNext));
+        assert_eq!(playing_gid(&task), 1);
+
+        // other actions are still allowed
+        let _ = task.handle_command(SpircCommand::Repeat(true));
+        assert!(task.state.repeat());
+
+        let _ = task.handle_command(SpircCommand::SetRestrictions(Restrictions::default()));
+        let _ = task.handle_remote_update(remote_frame(MessageType::kMessageTypeNext));
+        assert_eq!(playing_gid(&task), 2);
+    }
+
+    #[tokio::test]
+    async fn ignores_loads_and_queues_that_are_disallowed() {
+        let mut task = task(false);
+        task.ident = DEVICE.to_owned();
+        task.device.set_is_active(true);
+        playing_page(&mut task, page(0, 4, ""), 1);
+
+        let mut load = remote_frame(MessageType::kMessageTypeLoad);
+        load.state.mut_or_insert_default().track = page(8, 2, "").tracks;
+        let mut replace = remote_frame(MessageType::kMessageTypeReplace);
+        replace.state.mut_or_insert_default().track = page(16, 2, "").tracks;
+
+        let _ = task.handle_command(SpircCommand::SetRestrictions(restricted(
+            PlaybackAction::InterruptPlayback,
+        )));
+        let _ = task.handle_remote_update(load.clone());
+        assert_eq!(task.state.track.len(), 4);
+        assert_eq!(playing_gid(&task), 1);
+
+        let _ = task.handle_command(SpircCommand::SetRestrictions(restricted(
+            PlaybackAction::SetQueue,
+        )));
+        let _ = task.handle_remote_update(replace);
+        assert_eq!(task.state.track.len(), 4);
+
+        // a load is fine while only the queue is restricted
+        let _ = task.handle_remote_update(load);
+        assert_eq!(task.state.track.len(), 2);
+        assert_eq!(playing_gid(&task), 8);
+    }
+
+    #[test]
+    fn tells_frames_out_of_order() {
+        let mut seq_nrs = RemoteSeqNrs::default();
+        assert_eq!(
+            seq_nrs.update(DEVICE, 5, MessageType::kMessageTypeNotify),
+            None
+        );
+        assert_eq!(
+            seq_nrs.update(DEVICE, 7, MessageType::kMessageTypeNotify),
+            None
+        );
+        // late, and doesn't move the last sequence number back
+        assert_eq!(
+            seq_nrs.update(DEVICE, 6, MessageType::kMessageTypeNotify),
+            Some(7)
+        );
+        assert_eq!(
+            seq_nrs.update(DEVICE, 7, MessageType::kMessageTypeNotify),
+            Some(7)
+        );
+        assert_eq!(
+            seq_nrs.update(DEVICE, 8, MessageType::kMessageTypeNotify),
+            None
+        );
+        // other devices count on their own
+        assert_eq!(
+            seq_nrs.update("b7c9", 1, MessageType::kMessageTypeNotify),
+            None
+        );
+    }
+
+    #[test]
+    fn resets_when_a_device_restarts() {
+        let mut seq_nrs = RemoteSeqNrs::default();
+        assert_eq!(
+            seq_nrs.update(DEVICE, 500, MessageType::kMessageTypeNotify),
+            None
+        );
+
+        // reconnected and says hello again
+        assert_eq!(
+            seq_nrs.update(DEVICE, 1, MessageType::kMessageTypeHello),
+            None
+        );
+        assert_eq!(
+            seq_nrs.update(DEVICE, 2, MessageType::kMessageTypeNotify),
+            None
+        );
+
+        // a sharp drop without a hello
+        assert_eq!(
+            seq_nrs.update(DEVICE, 400, MessageType::kMessageTypeNotify),
+            None
+        );
+        assert_eq!(
+            seq_nrs.update(DEVICE, 3, MessageType::kMessageTypeNotify),
+            None
+        );
+        assert_eq!(
+            seq_nrs.update(DEVICE, 2, MessageType::kMessageTypeNotify),
+            Some(3)
+        );
+
+        // said goodbye, or missed while reconnecting
+        seq_nrs.remove(DEVICE);
+        assert_eq!(
+            seq_nrs.update(DEVICE, 1, MessageType::kMessageTypeNotify),
+            None
+        );
+        seq_nrs.clear();
+        assert_eq!(
+            seq_nrs.update(DEVICE, 1, MessageType::kMessageTypeNotify),
+            None
+        );
+    }
+}
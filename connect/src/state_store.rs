@@ -0,0 +1,235 @@
+//! Snapshots of what Spotify Connect is playing, to restore it when librespot starts again.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::Engine as _;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{cache::write_atomic, Error},
+    protocol::spirc::TrackRef,
+    spirc::SpircLoadCommand,
+};
+
+/// A track of the context or the queue, as in the Connect state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotTrack {
+    /// In base64.
+    pub gid: Option<String>,
+    pub uri: Option<String>,
+    pub queued: bool,
+}
+
+/// The active context, the position in it and the queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectStateSnapshot {
+    pub context_uri: String,
+    pub tracks: Vec<SnapshotTrack>,
+    pub playing_track_index: u32,
+    pub position_ms: u32,
+    /// Whether the tracks are in shuffled order.
+    pub shuffle: bool,
+    pub repeat: bool,
+}
+
+impl From<&SpircLoadCommand> for ConnectStateSnapshot {
+    fn from(command: &SpircLoadCommand) -> Self {
+        let tracks = command
+            .tracks
+            .iter()
+            .map(|track| SnapshotTrack {
+                gid: track.has_gid().then(|| BASE64.encode(track.gid())),
+                uri: track.has_uri().then(|| track.uri().to_owned()),
+                queued: track.queued(),
+            })
+            .collect();
+
+        Self {
+            context_uri: command.context_uri.clone(),
+            tracks,
+            playing_track_index: command.playing_track_index,
+            position_ms: command.position_ms,
+            shuffle: command.shuffle,
+            repeat: command.repeat,
+        }
+    }
+}
+
+impl From<ConnectStateSnapshot> for SpircLoadCommand {
+    /// Loads the snapshot paused.
+    fn from(snapshot: ConnectStateSnapshot) -> Self {
+        let tracks = snapshot
+            .tracks
+            .into_iter()
+            .map(|track| {
+                let mut track_ref = TrackRef::new();
+                if let Some(gid) = track.gid.and_then(|gid| BASE64.decode(gid).ok()) {
+                    track_ref.set_gid(gid);
+                }
+                if let Some(uri) = track.uri {
+                    track_ref.set_uri(uri);
+                }
+                if track.queued {
+                    track_ref.set_queued(true);
+                }
+                track_ref
+            })
+            .collect();
+
+        Self {
+            context_uri: snapshot.context_uri,
+            start_playing: false,
+            shuffle: snapshot.shuffle,
+            shuffle_seed: None,
+            repeat: snapshot.repeat,
+            playing_track_index: snapshot.playing_track_index,
+            position_ms: snapshot.position_ms,
+            tracks,
+        }
+    }
+}
+
+/// Keeps the latest snapshot, e.g. in a file or in the database of an application.
+///
+/// Spirc saves a snapshot in the background whenever the state changes while the device is
+/// active, and loads the snapshot paused when it starts, see [`ConnectConfig::state_store`].
+///
+/// [`ConnectConfig::state_store`]: crate::config::ConnectConfig::state_store
+pub trait ConnectStateStore: fmt::Debug + Send + Sync {
+    /// Returns `None` if no snapshot was saved.
+    fn load(&self) -> Result<Option<ConnectStateSnapshot>, Error>;
+    fn save(&self, snapshot: &ConnectStateSnapshot) -> Result<(), Error>;
+    fn clear(&self) -> Result<(), Error>;
+}
+
+/// Keeps the snapshot as JSON in a file, which is replaced at once when saving.
+#[derive(Debug, Clone)]
+pub struct FileConnectStateStore {
+    location: PathBuf,
+}
+
+impl FileConnectStateStore {
+    pub fn new<P: AsRef<Path>>(location: P) -> Self {
+        Self {
+            location: location.as_ref().to_owned(),
+        }
+    }
+}
+
+impl ConnectStateStore for FileConnectStateStore {
+    fn load(&self) -> Result<Option<ConnectStateSnapshot>, Error> {
+        match fs::read_to_string(&self.location) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, snapshot: &ConnectStateSnapshot) -> Result<(), Error> {
+        let data = serde_json::to_string(snapshot)?;
+        write_atomic(&self.location, data)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.location) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps the snapshot in memory, e.g. to restore the state of one Spirc in the next one
+/// without restarting.
+#[derive(Debug, Default)]
+pub struct MemoryConnectStateStore {
+    snapshot: Mutex<Option<ConnectStateSnapshot>>,
+}
+
+impl MemoryConnectStateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ConnectStateStore for MemoryConnectStateStore {
+    fn load(&self) -> Result<Option<ConnectStateSnapshot>, Error> {
+        Ok(self.snapshot.lock().clone())
+    }
+
+    fn save(&self, snapshot: &ConnectStateSnapshot) -> Result<(), Error> {
+        *self.snapshot.lock() = Some(snapshot.clone());
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        *self.snapshot.lock() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut track = TrackRef::new();
+        track.set_gid(vec![0xab; 16]);
+        let mut queued = TrackRef::new();
+        queued.set_uri("spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_owned());
+        queued.set_queued(true);
+
+        let command = SpircLoadCommand {
+            context_uri: "spotify:album:79dL7FLiJFOO0EoehUHQBv".to_owned(),
+            start_playing: true,
+            shuffle: false,
+            shuffle_seed: None,
+            repeat: true,
+            playing_track_index: 1,
+            position_ms: 1234,
+            tracks: vec![track, queued],
+        };
+        let snapshot = ConnectStateSnapshot::from(&command);
+
+        let store = MemoryConnectStateStore::new();
+        store.save(&snapshot).unwrap();
+        let restored = SpircLoadCommand::from(store.load().unwrap().unwrap());
+
+        assert!(!restored.start_playing);
+        assert_eq!(restored.position_ms, 1234);
+        assert_eq!(restored.tracks, command.tracks);
+    }
+
+    #[test]
+    fn file_store_replaces_snapshot() {
+        let path = std::env::temp_dir().join(format!("librespot-state-{}", std::process::id()));
+        let store = FileConnectStateStore::new(&path);
+        let mut snapshot = ConnectStateSnapshot {
+            context_uri: "spotify:album:79dL7FLiJFOO0EoehUHQBv".to_owned(),
+            tracks: Vec::new(),
+            playing_track_index: 0,
+            position_ms: 0,
+            shuffle: false,
+            repeat: false,
+        };
+
+        let empty = store.load().unwrap();
+        store.save(&snapshot).unwrap();
+        snapshot.position_ms = 1234;
+        store.save(&snapshot).unwrap();
+        let saved = store.load().unwrap();
+        store.clear().unwrap();
+        let cleared = store.load().unwrap();
+
+        assert_eq!(empty, None);
+        assert_eq!(saved, Some(snapshot));
+        assert_eq!(cleared, None);
+        assert!(store.clear().is_ok());
+    }
+}
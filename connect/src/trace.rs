@@ -0,0 +1,171 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::Engine as _;
+use futures_util::stream;
+use parking_lot::Mutex;
+use protobuf::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::{core::Error, protocol::spirc::Frame};
+
+/// Whether `Spirc` should record the remote updates it receives, or replay previously
+/// recorded ones instead of listening to the Spotify servers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpircTraceMode {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// A single line of a trace file.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SpircTraceEntry {
+    /// Written once at the start of a recording.
+    Start { ident: String, version: String },
+    /// A remote update frame, protobuf encoded as base64.
+    RemoteUpdate {
+        elapsed_ms: u64,
+        username: String,
+        frame: String,
+    },
+}
+
+/// Appends the remote updates received by `Spirc` to a trace file, one JSON object per line.
+pub struct SpircTraceRecorder {
+    writer: Mutex<BufWriter<File>>,
+    started_at: Instant,
+}
+
+impl SpircTraceRecorder {
+    pub fn create(path: impl AsRef<Path>, ident: &str) -> Result<Self, Error> {
+        let recorder = Self {
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+            started_at: Instant::now(),
+        };
+
+        recorder.write(&SpircTraceEntry::Start {
+            ident: ident.to_owned(),
+            version: crate::core::version::SEMVER.to_owned(),
+        })?;
+
+        Ok(recorder)
+    }
+
+    pub fn record_remote_update(&self, username: &str, frame: &Frame) -> Result<(), Error> {
+        self.write(&SpircTraceEntry::RemoteUpdate {
+            elapsed_ms: self.started_at.elapsed().as_millis() as u64,
+            username: username.to_owned(),
+            frame: BASE64.encode(frame.write_to_bytes()?),
+        })
+    }
+
+    fn write(&self, entry: &SpircTraceEntry) -> Result<(), Error> {
+        let mut writer = self.writer.lock();
+        serde_json::to_writer(&mut *writer, entry)?;
+        writer.write_all(b"\n")?;
+        // flush every entry so that the trace is usable even after a crash
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A recorded trace of remote updates that can be replayed against `Spirc`.
+#[derive(Clone, Debug, Default)]
+pub struct SpircTrace {
+    /// The device ident of the recording device, if known.
+    pub ident: Option<String>,
+    pub entries: Vec<SpircTraceEntry>,
+}
+
+impl SpircTrace {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let reader = BufReader::new(File::open(path)?);
+
+        let mut trace = Self::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: SpircTraceEntry = serde_json::from_str(&line)?;
+            if let SpircTraceEntry::Start { ref ident, .. } = entry {
+                trace.ident = Some(ident.clone());
+            }
+            trace.entries.push(entry);
+        }
+
+        Ok(trace)
+    }
+
+    /// Decodes the recorded remote updates. Frames that were addressed at the recording
+    /// device are re-addressed at `ident`, so that a trace can be replayed on another device.
+    pub fn remote_updates(&self, ident: &str) -> Result<Vec<(String, Frame)>, Error> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match entry {
+                SpircTraceEntry::RemoteUpdate {
+                    username, frame, ..
+                } => Some((username, frame)),
+                _ => None,
+            })
+            .map(|(username, frame)| {
+                let mut frame = Frame::parse_from_bytes(&BASE64.decode(frame)?)?;
+                if let Some(recorded_ident) = self.ident.as_deref() {
+                    for recipient in frame.recipient.iter_mut() {
+                        if recipient == recorded_ident {
+                            *recipient = ident.to_owned();
+                        }
+                    }
+                }
+                Ok((username.clone(), frame))
+            })
+            .collect()
+    }
+
+    pub(crate) fn into_stream(
+        self,
+        ident: &str,
+    ) -> Result<impl stream::Stream<Item = Result<(String, Frame), Error>>, Error> {
+        let updates = self.remote_updates(ident)?;
+        Ok(stream::iter(updates.into_iter().map(Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!(
+            "librespot-spirc-trace-{}.jsonl",
+            std::process::id()
+        ));
+
+        let mut frame = Frame::new();
+        frame.set_ident("phone".to_owned());
+        frame.recipient.push("recorder".to_owned());
+
+        let recorder = SpircTraceRecorder::create(&path, "recorder").unwrap();
+        recorder.record_remote_update("user", &frame).unwrap();
+        drop(recorder);
+
+        let trace = SpircTrace::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(trace.ident.as_deref(), Some("recorder"));
+
+        let updates = trace.remote_updates("replayer").unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].0, "user");
+        assert_eq!(updates[0].1.ident(), "phone");
+        assert_eq!(updates[0].1.recipient, vec!["replayer".to_owned()]);
+    }
+}
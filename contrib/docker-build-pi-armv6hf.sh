@@ -14,4 +14,4 @@ PI1_LIB_DIRS=(
 )
 export RUSTFLAGS="-C linker=$PI1_TOOLS_DIR/bin/arm-linux-gnueabihf-gcc ${PI1_LIB_DIRS[@]/#/-L}"
 
-cargo build --release --target arm-unknown-linux-gnueabihf --no-default-features --features "alsa-backend"
+cargo build --release --target arm-unknown-linux-gnueabihf --no-default-features --features "alsa-backend connect discovery"
@@ -35,6 +35,14 @@ elif player_event == 'filter_explicit_content_changed':
 elif player_event == 'volume_changed':
     json_dict['volume'] = os.environ['VOLUME']
 
+elif player_event == 'audio_format_changed':
+    json_dict['sample_rate'] = os.environ['SAMPLE_RATE']
+    json_dict['source_sample_rate'] = os.environ['SOURCE_SAMPLE_RATE']
+
+elif player_event == 'context_segment':
+    json_dict['uri'] = os.environ['URI']
+    json_dict['metadata'] = dict(line.split('=', 1) for line in os.environ['METADATA'].splitlines())
+
 elif player_event in ('seeked', 'position_correction', 'playing', 'paused'):
     json_dict['track_id'] = os.environ['TRACK_ID']
     json_dict['position_ms'] = os.environ['POSITION_MS']
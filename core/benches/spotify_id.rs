@@ -0,0 +1,55 @@
+//! Benchmarks of encoding and parsing Spotify IDs and URIs, which happens for every track in
+//! every context, queue and metadata response.
+//!
+//! Run with `cargo bench -p librespot-core --features bench`. To compare a change against
+//! the code before it, save a baseline first with `-- --save-baseline before`, then run
+//! again with `-- --baseline before`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use librespot_core::spotify_id::{SpotifyId, SpotifyItemType};
+
+const BASE62: &str = "4GNcXTGWmnZ3ySrqvol3o4";
+const URI: &str = "spotify:track:4GNcXTGWmnZ3ySrqvol3o4";
+const NAMED_URI: &str = "spotify:user:spotify:playlist:37i9dQZF1DXcBWIGoYBM5M";
+const SHARE_URL: &str = "https://open.spotify.com/track/4GNcXTGWmnZ3ySrqvol3o4?si=0123456789abcdef";
+
+fn base62(c: &mut Criterion) {
+    let mut group = c.benchmark_group("base62");
+
+    group.bench_function("decode", |b| {
+        b.iter(|| SpotifyId::from_base62(black_box(BASE62)))
+    });
+
+    let id = SpotifyId::from_base62(BASE62).expect("valid base62");
+    group.bench_function("encode", |b| b.iter(|| black_box(id).to_base62()));
+    group.bench_function("write", |b| {
+        let mut dst = [0u8; SpotifyId::SIZE_BASE62];
+        b.iter(|| black_box(id).write_base62(&mut dst).len())
+    });
+
+    group.finish();
+}
+
+fn uri(c: &mut Criterion) {
+    let mut group = c.benchmark_group("uri");
+
+    group.bench_function("parse", |b| b.iter(|| SpotifyId::from_uri(black_box(URI))));
+    group.bench_function("parse_named", |b| {
+        b.iter(|| SpotifyId::from_uri(black_box(NAMED_URI)))
+    });
+    group.bench_function("parse_share_url", |b| {
+        b.iter(|| SpotifyId::from_uri(black_box(SHARE_URL)))
+    });
+
+    let id = SpotifyId {
+        item_type: SpotifyItemType::Track,
+        ..SpotifyId::from_base62(BASE62).expect("valid base62")
+    };
+    group.bench_function("format", |b| b.iter(|| black_box(id).to_uri()));
+
+    group.finish();
+}
+
+criterion_group!(benches, base62, uri);
+criterion_main!(benches);
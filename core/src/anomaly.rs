@@ -0,0 +1,160 @@
+//! Anomalies in what Spotify sends, like messages nobody handles, fields that are not in the
+//! protocol definitions or frames that arrive out of order. They are tolerated, unless
+//! [`SessionConfig::strict_protocol`] is set, to notice changes of the protocol early.
+//!
+//! [`SessionConfig::strict_protocol`]: crate::config::SessionConfig::strict_protocol
+
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine as _};
+use protobuf::{
+    reflect::{ReflectFieldRef, ReflectValueRef},
+    MessageDyn,
+};
+use thiserror::Error;
+
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    Error,
+};
+
+#[derive(Debug, Error)]
+pub enum ProtocolAnomaly {
+    #[error("no subscriber for dealer message {uri}")]
+    UnhandledMessage { uri: String },
+    #[error("no handler for dealer request {message_ident}")]
+    UnhandledRequest { message_ident: String },
+    #[error("invalid dealer message: {0}")]
+    InvalidMessage(String),
+    #[error("{message} has unknown fields {}", .fields.join(", "))]
+    UnknownFields {
+        message: String,
+        fields: Vec<String>,
+    },
+    #[error("frame {seq_nr} of {ident} arrived after frame {last_seq_nr}")]
+    OutOfOrder {
+        ident: String,
+        seq_nr: u32,
+        last_seq_nr: u32,
+    },
+}
+
+impl From<ProtocolAnomaly> for Error {
+    fn from(err: ProtocolAnomaly) -> Self {
+        Self::with_code(ErrorKind::Unimplemented, err)
+    }
+}
+
+impl ErrorCode for ProtocolAnomaly {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::UnhandledMessage { .. } => "protocol.unhandled_message",
+            Self::UnhandledRequest { .. } => "protocol.unhandled_request",
+            Self::InvalidMessage(_) => "protocol.invalid_message",
+            Self::UnknownFields { .. } => "protocol.unknown_fields",
+            Self::OutOfOrder { .. } => "protocol.out_of_order",
+        }
+    }
+}
+
+/// Tolerates `anomaly`, or in `strict` mode logs it together with the `payload` it was found
+/// in and returns it as an error.
+pub fn report(strict: bool, anomaly: ProtocolAnomaly, payload: &[u8]) -> Result<(), Error> {
+    if !strict {
+        debug!("Tolerating protocol anomaly: {}", anomaly);
+        return Ok(());
+    }
+
+    warn!(
+        "Protocol anomaly: {}, payload (base64): {}",
+        anomaly,
+        BASE64.encode(payload)
+    );
+    Err(anomaly.into())
+}
+
+/// In `strict` mode, reports the fields of `message` and its nested messages that were not
+/// known when it was parsed. Their values are kept, so that the payload is re-encoded.
+pub fn check_unknown_fields(strict: bool, message: &dyn MessageDyn) -> Result<(), Error> {
+    if !strict {
+        return Ok(());
+    }
+
+    let mut fields = Vec::new();
+    unknown_fields(message, "", &mut fields);
+    fields.dedup();
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    let anomaly = ProtocolAnomaly::UnknownFields {
+        message: message.descriptor_dyn().full_name().to_owned(),
+        fields,
+    };
+    report_message(strict, anomaly, message)
+}
+
+/// Like [`report`], with `message` encoded again as the payload.
+pub fn report_message(
+    strict: bool,
+    anomaly: ProtocolAnomaly,
+    message: &dyn MessageDyn,
+) -> Result<(), Error> {
+    let payload = if strict {
+        message.write_to_bytes_dyn()?
+    } else {
+        Vec::new()
+    };
+    report(strict, anomaly, &payload)
+}
+
+// Adds the unknown fields of `message` as paths like `state.track.7` to `fields`.
+fn unknown_fields(message: &dyn MessageDyn, path: &str, fields: &mut Vec<String>) {
+    for (number, _) in message.unknown_fields_dyn() {
+        fields.push(format!("{path}{number}"));
+    }
+
+    for field in message.descriptor_dyn().fields() {
+        let path = format!("{path}{}.", field.name());
+        match field.get_reflect(message) {
+            ReflectFieldRef::Optional(value) => {
+                if let Some(ReflectValueRef::Message(nested)) = value.value() {
+                    unknown_fields(&*nested, &path, fields);
+                }
+            }
+            ReflectFieldRef::Repeated(values) => {
+                for value in values {
+                    if let ReflectValueRef::Message(nested) = value {
+                        unknown_fields(&*nested, &path, fields);
+                    }
+                }
+            }
+            ReflectFieldRef::Map(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use protobuf::{Message, UnknownValue};
+
+    use super::*;
+    use crate::protocol::spirc::{Frame, State, TrackRef};
+
+    #[test]
+    fn finds_nested_unknown_fields() {
+        let mut track = TrackRef::new();
+        track
+            .mut_unknown_fields()
+            .add_value(42, UnknownValue::Varint(1));
+        let mut state = State::new();
+        state.track.push(track);
+        let mut frame = Frame::new();
+        frame.state = Some(state).into();
+
+        let bytes = frame.write_to_bytes().unwrap();
+        let frame = Frame::parse_from_bytes(&bytes).unwrap();
+
+        assert!(check_unknown_fields(false, &frame).is_ok());
+        let err = check_unknown_fields(true, &frame).unwrap_err();
+        assert!(err.to_string().contains("state.track.42"), "{err}");
+    }
+}
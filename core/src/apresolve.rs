@@ -1,9 +1,20 @@
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
+use futures_util::future::join_all;
 use hyper::{Body, Method, Request};
 use serde::Deserialize;
+use tokio::net::TcpStream;
 
-use crate::Error;
+use crate::{
+    config::{AccessPointOrdering, AccessPointResolvePolicy},
+    Error,
+};
+
+// How long to wait for a TCP connection when ordering access points by latency.
+const LATENCY_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub type SocketAddress = (String, u16);
 
@@ -38,11 +49,18 @@ impl AccessPoints {
     fn is_any_empty(&self) -> bool {
         self.accesspoint.is_empty() || self.dealer.is_empty() || self.spclient.is_empty()
     }
+
+    fn extend(&mut self, other: AccessPoints) {
+        self.accesspoint.extend(other.accesspoint);
+        self.dealer.extend(other.dealer);
+        self.spclient.extend(other.spclient);
+    }
 }
 
 component! {
     ApResolver : ApResolverInner {
         data: AccessPoints = AccessPoints::default(),
+        resolved_at: Option<Instant> = None,
     }
 }
 
@@ -93,37 +111,99 @@ impl ApResolver {
         Ok(data)
     }
 
+    // The configured access points, then the resolved ones unless resolving is disabled,
+    // then the fallbacks for endpoints that still have none.
     async fn apresolve(&self) {
-        let result = self.try_apresolve().await;
+        let config = self.session().config().access_points.clone();
 
-        self.lock(|inner| {
-            let (data, error) = match result {
-                Ok(data) => (data, None),
-                Err(e) => (ApResolveData::default(), Some(e)),
-            };
+        let mut data = self.parse_resolve_to_access_points(ApResolveData {
+            accesspoint: config.accesspoint,
+            dealer: config.dealer,
+            spclient: config.spclient,
+        });
 
-            inner.data = self.parse_resolve_to_access_points(data);
+        let mut error = None;
+        if config.resolve != AccessPointResolvePolicy::Never {
+            match self.try_apresolve().await {
+                Ok(resolved) => data.extend(self.parse_resolve_to_access_points(resolved)),
+                Err(e) => error = Some(e),
+            }
+        }
 
-            if inner.data.is_any_empty() {
+        if data.is_any_empty() {
+            if config.resolve != AccessPointResolvePolicy::Never {
                 warn!("Failed to resolve all access points, using fallbacks");
-                if let Some(error) = error {
-                    warn!("Resolve access points error: {}", error);
-                }
+            }
+            if let Some(error) = error {
+                warn!("Resolve access points error: {}", error);
+            }
 
-                let fallback = self.parse_resolve_to_access_points(ApResolveData::fallback());
-                inner.data.accesspoint.extend(fallback.accesspoint);
-                inner.data.dealer.extend(fallback.dealer);
-                inner.data.spclient.extend(fallback.spclient);
+            let fallback = self.parse_resolve_to_access_points(ApResolveData::fallback());
+            if data.accesspoint.is_empty() {
+                data.accesspoint = fallback.accesspoint;
+            }
+            if data.dealer.is_empty() {
+                data.dealer = fallback.dealer;
             }
+            if data.spclient.is_empty() {
+                data.spclient = fallback.spclient;
+            }
+        }
+
+        // behind a proxy, the latency to the access points can't be measured
+        if config.ordering == AccessPointOrdering::Latency
+            && self.session().config().proxy.is_none()
+        {
+            data.accesspoint = Self::order_by_latency(data.accesspoint).await;
+            data.dealer = Self::order_by_latency(data.dealer).await;
+            data.spclient = Self::order_by_latency(data.spclient).await;
+        }
+
+        self.lock(|inner| {
+            inner.data = data;
+            inner.resolved_at = Some(Instant::now());
         })
     }
 
-    fn is_any_empty(&self) -> bool {
-        self.lock(|inner| inner.data.is_any_empty())
+    // Sorts by the time it takes to connect, with those that can't be reached at the end.
+    async fn order_by_latency(access_points: VecDeque<SocketAddress>) -> VecDeque<SocketAddress> {
+        let probes = access_points.into_iter().map(|(host, port)| async move {
+            let started_at = Instant::now();
+            let connect = TcpStream::connect((host.as_str(), port));
+            let latency = match tokio::time::timeout(LATENCY_PROBE_TIMEOUT, connect).await {
+                Ok(Ok(_)) => Some(started_at.elapsed()),
+                _ => None,
+            };
+            trace!("Latency of access point {}:{}: {:?}", host, port, latency);
+            (latency, (host, port))
+        });
+
+        let mut probed = join_all(probes).await;
+        // `None` is less than any `Some`, but should come last
+        probed.sort_by_key(|(latency, _)| (latency.is_none(), *latency));
+        probed
+            .into_iter()
+            .map(|(_, access_point)| access_point)
+            .collect()
+    }
+
+    fn needs_resolve(&self) -> bool {
+        let max_age = match self.session().config().access_points.resolve {
+            AccessPointResolvePolicy::MaxAge(max_age) => Some(max_age),
+            _ => None,
+        };
+
+        self.lock(|inner| {
+            let expired = match (max_age, inner.resolved_at) {
+                (Some(max_age), Some(resolved_at)) => resolved_at.elapsed() > max_age,
+                _ => false,
+            };
+            expired || inner.data.is_any_empty()
+        })
     }
 
     pub async fn resolve(&self, endpoint: &str) -> Result<SocketAddress, Error> {
-        if self.is_any_empty() {
+        if self.needs_resolve() {
             self.apresolve().await;
         }
 
@@ -150,3 +230,90 @@ impl ApResolver {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        config::{AccessPointConfig, SessionConfig},
+        Session,
+    };
+
+    fn session(access_points: AccessPointConfig) -> Session {
+        Session::new(
+            SessionConfig {
+                access_points,
+                ..Default::default()
+            },
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_resolving() {
+        let session = session(AccessPointConfig {
+            accesspoint: vec!["ap.example:4070".into(), "ap.example:443".into()],
+            resolve: AccessPointResolvePolicy::Never,
+            ..Default::default()
+        });
+        let resolver = session.apresolver();
+
+        let resolve = |endpoint| async move { resolver.resolve(endpoint).await.unwrap() };
+        assert_eq!(resolve("accesspoint").await, ("ap.example".into(), 4070));
+        assert_eq!(resolve("accesspoint").await, ("ap.example".into(), 443));
+        // tried again from the start once all were tried
+        assert_eq!(resolve("accesspoint").await, ("ap.example".into(), 4070));
+        assert_eq!(resolve("dealer").await, ("dealer.spotify.com".into(), 443));
+        assert_eq!(
+            resolve("spclient").await,
+            ("spclient.wg.spotify.com".into(), 443)
+        );
+        assert!(resolver.resolve("unknown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn resolves_again_when_too_old() {
+        let max_age = Duration::from_millis(10);
+        let aged = |resolve| {
+            let session = session(AccessPointConfig {
+                resolve,
+                ..Default::default()
+            });
+            let resolver = session.apresolver();
+            resolver.lock(|inner| {
+                inner
+                    .data
+                    .extend(resolver.parse_resolve_to_access_points(ApResolveData::fallback()));
+                inner.resolved_at = Some(Instant::now());
+            });
+            assert!(!resolver.needs_resolve());
+            std::thread::sleep(2 * max_age);
+            resolver.needs_resolve()
+        };
+
+        assert!(aged(AccessPointResolvePolicy::MaxAge(max_age)));
+        assert!(!aged(AccessPointResolvePolicy::WhenExhausted));
+        assert!(!aged(AccessPointResolvePolicy::Never));
+    }
+
+    #[tokio::test]
+    async fn orders_by_latency() {
+        let address = |listener: &TcpListener| {
+            (
+                "127.0.0.1".to_owned(),
+                listener.local_addr().unwrap().port(),
+            )
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = address(&listener);
+        // a port that was just free is most likely still closed
+        let unreachable = address(&TcpListener::bind("127.0.0.1:0").await.unwrap());
+
+        let ordered =
+            ApResolver::order_by_latency(VecDeque::from([unreachable.clone(), reachable.clone()]))
+                .await;
+        assert_eq!(ordered, [reachable, unreachable]);
+    }
+}
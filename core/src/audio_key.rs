@@ -5,7 +5,12 @@ use bytes::Bytes;
 use thiserror::Error;
 use tokio::sync::oneshot;
 
-use crate::{packet::PacketType, util::SeqGenerator, Error, FileId, SpotifyId};
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    packet::PacketType,
+    util::SeqGenerator,
+    Error, FileId, SpotifyId,
+};
 
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
 pub struct AudioKey(pub [u8; 16]);
@@ -25,10 +30,21 @@ pub enum AudioKeyError {
 impl From<AudioKeyError> for Error {
     fn from(err: AudioKeyError) -> Self {
         match err {
-            AudioKeyError::AesKey => Error::unavailable(err),
-            AudioKeyError::Channel => Error::aborted(err),
-            AudioKeyError::Sequence(_) => Error::aborted(err),
-            AudioKeyError::Packet(_) => Error::unimplemented(err),
+            AudioKeyError::AesKey => Error::with_code(ErrorKind::Unavailable, err),
+            AudioKeyError::Channel => Error::with_code(ErrorKind::Aborted, err),
+            AudioKeyError::Sequence(_) => Error::with_code(ErrorKind::Aborted, err),
+            AudioKeyError::Packet(_) => Error::with_code(ErrorKind::Unimplemented, err),
+        }
+    }
+}
+
+impl ErrorCode for AudioKeyError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::AesKey => "audio_key.aes_key",
+            Self::Channel => "audio_key.channel",
+            Self::Packet(_) => "audio_key.packet",
+            Self::Sequence(_) => "audio_key.sequence",
         }
     }
 }
@@ -40,6 +56,18 @@ component! {
     }
 }
 
+struct PendingGuard<'a> {
+    manager: &'a AudioKeyManager,
+    seq: u32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let seq = self.seq;
+        self.manager.lock(|inner| inner.pending.remove(&seq));
+    }
+}
+
 impl AudioKeyManager {
     pub(crate) fn dispatch(&self, cmd: PacketType, mut data: Bytes) -> Result<(), Error> {
         let seq = BigEndian::read_u32(data.split_to(4).as_ref());
@@ -79,7 +107,17 @@ impl AudioKeyManager {
         Ok(())
     }
 
+    /// Requests the key to decrypt `file`, unless it was kept in the cache for offline
+    /// playback. Dropping the returned future cancels the request.
     pub async fn request(&self, track: SpotifyId, file: FileId) -> Result<AudioKey, Error> {
+        if let Some(key) = self
+            .session()
+            .cache()
+            .and_then(|cache| cache.audio_key(file))
+        {
+            return Ok(key);
+        }
+
         let (tx, rx) = oneshot::channel();
 
         let seq = self.lock(move |inner| {
@@ -88,6 +126,9 @@ impl AudioKeyManager {
             seq
         });
 
+        // Stops waiting for the key when the request fails or is cancelled.
+        let _pending = PendingGuard { manager: self, seq };
+
         self.send_key_request(seq, track, file)?;
         rx.await?
     }
@@ -101,4 +142,44 @@ impl AudioKeyManager {
 
         self.session().send_packet(PacketType::RequestKey, data)
     }
+
+    /// Fails the requests that are waiting for a key, which won't arrive after the
+    /// connection was lost.
+    pub(crate) fn fail_pending(&self) {
+        self.lock(|inner| inner.pending.clear());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{config::SessionConfig, Session};
+
+    #[tokio::test]
+    async fn cancelled_request_is_removed() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+
+        let track = SpotifyId::from_raw(&[0; 16]).unwrap();
+        let request = session.audio_key().request(track, FileId([0; 20]));
+        let result = tokio::time::timeout(Duration::from_millis(10), request).await;
+
+        assert!(result.is_err());
+        assert!(packets.try_recv().is_ok());
+        assert!(session.audio_key().lock(|inner| inner.pending.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn failed_request_is_removed() {
+        // not connected, so that sending fails
+        let session = Session::new(SessionConfig::default(), None);
+
+        let track = SpotifyId::from_raw(&[0; 16]).unwrap();
+        let result = session.audio_key().request(track, FileId([0; 20])).await;
+
+        assert!(result.is_err());
+        assert!(session.audio_key().lock(|inner| inner.pending.is_empty()));
+    }
 }
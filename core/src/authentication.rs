@@ -1,16 +1,35 @@
-use std::io::{self, Read};
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
 
 use aes::Aes192;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::engine::Engine as _;
 use byteorder::{BigEndian, ByteOrder};
+use hyper::{
+    header::{HeaderValue, ACCEPT, CONTENT_TYPE},
+    Body, Method, Request,
+};
 use pbkdf2::pbkdf2_hmac;
-use protobuf::Enum;
+use protobuf::{Enum, Message};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use thiserror::Error;
 
-use crate::{protocol::authentication::AuthenticationType, Error};
+use crate::{
+    client_token::CLIENT_TOKEN,
+    error::{ErrorCode, ErrorKind, Recovery},
+    hashcash,
+    protocol::{
+        authentication::AuthenticationType,
+        login5::{ChallengeSolution, LoginError, LoginRequest, LoginResponse},
+    },
+    Error, Session,
+};
+
+const LOGIN5_URL: &str = "https://login5.spotify.com/v3/login";
+const LOGIN5_MAX_TRIES: u8 = 3;
 
 #[derive(Debug, Error)]
 pub enum AuthenticationError {
@@ -22,7 +41,72 @@ pub enum AuthenticationError {
 
 impl From<AuthenticationError> for Error {
     fn from(err: AuthenticationError) -> Self {
-        Error::invalid_argument(err)
+        Error::with_code(ErrorKind::InvalidArgument, err)
+    }
+}
+
+impl ErrorCode for AuthenticationError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::AuthType(_) => "authentication.auth_type",
+            Self::Key => "authentication.key",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Login5Error {
+    #[error("login5 only supports passwords, stored credentials and Facebook tokens")]
+    UnsupportedCredentials,
+    #[error("login5 failed: {0:?}")]
+    Login(LoginError),
+    #[error("login5 presented an unsupported challenge")]
+    UnsupportedChallenge,
+    #[error("login5 did not accept the solutions to its challenges")]
+    TooManyChallenges,
+}
+
+impl From<Login5Error> for Error {
+    fn from(err: Login5Error) -> Self {
+        match err {
+            Login5Error::UnsupportedCredentials => {
+                Error::with_code(ErrorKind::InvalidArgument, err)
+            }
+            Login5Error::Login(LoginError::INVALID_CREDENTIALS)
+            | Login5Error::Login(LoginError::UNKNOWN_IDENTIFIER) => {
+                Error::with_code(ErrorKind::PermissionDenied, err)
+            }
+            Login5Error::Login(LoginError::TOO_MANY_ATTEMPTS)
+            | Login5Error::Login(LoginError::TRY_AGAIN_LATER) => {
+                Error::with_code(ErrorKind::ResourceExhausted, err)
+            }
+            Login5Error::Login(_) | Login5Error::UnsupportedChallenge => {
+                Error::with_code(ErrorKind::FailedPrecondition, err)
+            }
+            Login5Error::TooManyChallenges => Error::with_code(ErrorKind::Aborted, err),
+        }
+    }
+}
+
+impl ErrorCode for Login5Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::UnsupportedCredentials => "login5.unsupported_credentials",
+            Self::Login(_) => "login5.login",
+            Self::UnsupportedChallenge => "login5.unsupported_challenge",
+            Self::TooManyChallenges => "login5.too_many_challenges",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            (Self::Login(LoginError::INVALID_CREDENTIALS), ErrorKind::PermissionDenied)
+            | (Self::Login(LoginError::UNKNOWN_IDENTIFIER), ErrorKind::PermissionDenied) => {
+                Some(Recovery::Reauthenticate)
+            }
+            (Self::TooManyChallenges, ErrorKind::Aborted) => Some(Recovery::GiveUp),
+            _ => None,
+        }
     }
 }
 
@@ -58,6 +142,18 @@ impl Credentials {
         }
     }
 
+    /// Intialize these credentials from an OAuth access token with the `streaming` scope, e.g.
+    /// as sent by Spotify Connect clients that log in with a token through zeroconf.
+    ///
+    /// The access point logs in the user the token was issued to, so no username is needed.
+    pub fn with_access_token(token: impl Into<String>) -> Credentials {
+        Credentials {
+            username: String::new(),
+            auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+            auth_data: token.into().into_bytes(),
+        }
+    }
+
     pub fn with_blob(
         username: impl Into<String>,
         encrypted_blob: impl AsRef<[u8]>,
@@ -144,6 +240,135 @@ impl Credentials {
     }
 }
 
+/// Whether [`login5`] can log in with `credentials`. Access tokens and stored Facebook
+/// credentials are only accepted by the access point.
+pub fn supports_login5(credentials: &Credentials) -> bool {
+    matches!(
+        credentials.auth_type,
+        AuthenticationType::AUTHENTICATION_USER_PASS
+            | AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS
+            | AuthenticationType::AUTHENTICATION_FACEBOOK_TOKEN
+    )
+}
+
+/// Logs in with the login5 API, which replaces logging in with a password at the access
+/// point. Returns stored credentials for the access point.
+pub async fn login5(session: &Session, credentials: &Credentials) -> Result<Credentials, Error> {
+    let mut request = login5_login_request(session.client_id(), session.device_id(), credentials)?;
+
+    for _ in 0..LOGIN5_MAX_TRIES {
+        let response = login5_request(session, &request).await?;
+        let challenge_budget = session.config().challenge_budget;
+        if let Some(credentials) =
+            handle_login5_response(&mut request, response, challenge_budget).await?
+        {
+            return Ok(credentials);
+        }
+    }
+
+    Err(Login5Error::TooManyChallenges.into())
+}
+
+fn login5_login_request(
+    client_id: String,
+    device_id: &str,
+    credentials: &Credentials,
+) -> Result<LoginRequest, Error> {
+    let mut request = LoginRequest::new();
+    let client_info = request.client_info.mut_or_insert_default();
+    client_info.client_id = client_id;
+    client_info.device_id = device_id.to_owned();
+
+    match credentials.auth_type {
+        AuthenticationType::AUTHENTICATION_USER_PASS => {
+            let password = request.mut_password();
+            password.id = credentials.username.clone();
+            password.password = String::from_utf8(credentials.auth_data.clone())?;
+        }
+        AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS => {
+            let stored_credential = request.mut_stored_credential();
+            stored_credential.username = credentials.username.clone();
+            stored_credential.data = credentials.auth_data.clone();
+        }
+        AuthenticationType::AUTHENTICATION_FACEBOOK_TOKEN => {
+            let facebook_access_token = request.mut_facebook_access_token();
+            facebook_access_token.fb_uid = credentials.username.clone();
+            facebook_access_token.access_token = String::from_utf8(credentials.auth_data.clone())?;
+        }
+        _ => return Err(Login5Error::UnsupportedCredentials.into()),
+    }
+
+    Ok(request)
+}
+
+// Returns the stored credentials once logged in, or `None` after solving the challenges of
+// `response` into `request`, which is to be sent again then.
+async fn handle_login5_response(
+    request: &mut LoginRequest,
+    response: LoginResponse,
+    challenge_budget: Duration,
+) -> Result<Option<Credentials>, Error> {
+    if response.has_ok() {
+        let ok = response.ok();
+        debug!("Logged in with login5 as \"{}\"", ok.username);
+        return Ok(Some(Credentials {
+            username: ok.username.clone(),
+            auth_type: AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+            auth_data: ok.stored_credential.clone(),
+        }));
+    }
+    if response.has_error() {
+        return Err(Login5Error::Login(response.error()).into());
+    }
+
+    debug!("Received login5 challenges, solving...");
+    let solutions = request.challenge_solutions.mut_or_insert_default();
+    solutions.solutions.clear();
+    for challenge in &response.challenges().challenges {
+        if !challenge.has_hashcash() {
+            return Err(Login5Error::UnsupportedChallenge.into());
+        }
+        let challenge = challenge.hashcash();
+        let solved = hashcash::solve(
+            &response.login_context,
+            &challenge.prefix,
+            challenge.length,
+            challenge_budget,
+        )
+        .await?;
+
+        let mut solution = ChallengeSolution::new();
+        let hashcash_solution = solution.mut_hashcash();
+        hashcash_solution.suffix = solved.suffix.to_vec();
+        let duration = hashcash_solution.duration.mut_or_insert_default();
+        duration.seconds = solved.duration.as_secs() as i64;
+        duration.nanos = solved.duration.subsec_nanos() as i32;
+        solutions.solutions.push(solution);
+    }
+    request.login_context = response.login_context;
+
+    Ok(None)
+}
+
+async fn login5_request(session: &Session, request: &LoginRequest) -> Result<LoginResponse, Error> {
+    let client_token = session.client_token_provider().get_token().await?;
+    let body = request.write_to_bytes()?;
+
+    let request = Request::builder()
+        .method(&Method::POST)
+        .uri(LOGIN5_URL)
+        .header(ACCEPT, HeaderValue::from_static("application/x-protobuf"))
+        .header(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-protobuf"),
+        )
+        .header(CLIENT_TOKEN, client_token)
+        .body(Body::from(body))?;
+
+    let response = session.http_client().request_body(request).await?;
+    Ok(LoginResponse::parse_from_bytes(&response)?)
+}
+
 fn serialize_protobuf_enum<T, S>(v: &T, ser: S) -> Result<S::Ok, S::Error>
 where
     T: Enum,
@@ -178,3 +403,178 @@ where
         .decode(v)
         .map_err(|e| serde::de::Error::custom(e.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::login5::{Challenge, LoginOk};
+
+    use super::*;
+
+    fn login_request(credentials: &Credentials) -> Result<LoginRequest, Error> {
+        login5_login_request("client".to_owned(), "device", credentials)
+    }
+
+    fn hashcash_challenge(length: i32) -> Challenge {
+        let mut challenge = Challenge::new();
+        let hashcash = challenge.mut_hashcash();
+        hashcash.prefix = vec![1, 2, 3, 4];
+        hashcash.length = length;
+        challenge
+    }
+
+    #[test]
+    fn logs_in_with_each_credential_type() {
+        let credentials = Credentials::with_password("alice", "secret");
+        assert!(supports_login5(&credentials));
+        let request = login_request(&credentials).unwrap();
+        assert_eq!(request.client_info.client_id, "client");
+        assert_eq!(request.client_info.device_id, "device");
+        assert_eq!(request.password().id, "alice");
+        assert_eq!(request.password().password, "secret");
+
+        let credentials = Credentials {
+            username: "alice".to_owned(),
+            auth_type: AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+            auth_data: vec![1, 2, 3],
+        };
+        assert!(supports_login5(&credentials));
+        let request = login_request(&credentials).unwrap();
+        assert_eq!(request.stored_credential().username, "alice");
+        assert_eq!(request.stored_credential().data, [1, 2, 3]);
+
+        let credentials = Credentials {
+            username: "1234".to_owned(),
+            auth_type: AuthenticationType::AUTHENTICATION_FACEBOOK_TOKEN,
+            auth_data: b"token".to_vec(),
+        };
+        assert!(supports_login5(&credentials));
+        let request = login_request(&credentials).unwrap();
+        assert_eq!(request.facebook_access_token().fb_uid, "1234");
+        assert_eq!(request.facebook_access_token().access_token, "token");
+
+        // Access tokens go to the access point directly.
+        let credentials = Credentials::with_access_token("token");
+        assert!(!supports_login5(&credentials));
+        let e = login_request(&credentials).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn returns_stored_credentials() {
+        let mut request = login_request(&Credentials::with_password("alice", "secret")).unwrap();
+
+        let mut response = LoginResponse::new();
+        let mut ok = LoginOk::new();
+        ok.username = "alice".to_owned();
+        ok.stored_credential = vec![4, 5, 6];
+        response.set_ok(ok);
+
+        let credentials = handle_login5_response(&mut request, response, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            credentials,
+            Some(Credentials {
+                username: "alice".to_owned(),
+                auth_type: AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+                auth_data: vec![4, 5, 6],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_login_errors() {
+        for (error, kind) in [
+            (LoginError::INVALID_CREDENTIALS, ErrorKind::PermissionDenied),
+            (LoginError::TRY_AGAIN_LATER, ErrorKind::ResourceExhausted),
+            (LoginError::BAD_REQUEST, ErrorKind::FailedPrecondition),
+        ] {
+            let mut request =
+                login_request(&Credentials::with_password("alice", "secret")).unwrap();
+            let mut response = LoginResponse::new();
+            response.set_error(error);
+
+            let e = handle_login5_response(&mut request, response, Duration::from_secs(5))
+                .await
+                .unwrap_err();
+            assert_eq!(e.kind, kind, "{error:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn solves_hashcash_challenges() {
+        let mut request = login_request(&Credentials::with_password("alice", "secret")).unwrap();
+
+        let mut response = LoginResponse::new();
+        response.login_context = vec![9, 9];
+        let challenges = response.mut_challenges();
+        challenges.challenges.push(hashcash_challenge(8));
+        challenges.challenges.push(hashcash_challenge(4));
+
+        let credentials = handle_login5_response(&mut request, response, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(credentials.is_none());
+        assert_eq!(request.login_context, [9, 9]);
+
+        let solutions = &request.challenge_solutions.solutions;
+        assert_eq!(solutions.len(), 2);
+        for (solution, length) in solutions.iter().zip([8, 4]) {
+            let mut hasher = Sha1::new();
+            hasher.update([1, 2, 3, 4]);
+            hasher.update(&solution.hashcash().suffix);
+            let md = hasher.finalize();
+            assert!(BigEndian::read_i64(&md[12..20]).trailing_zeros() >= length);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_other_challenges() {
+        let mut request = login_request(&Credentials::with_password("alice", "secret")).unwrap();
+
+        let mut response = LoginResponse::new();
+        let mut challenge = Challenge::new();
+        challenge.mut_code();
+        response.mut_challenges().challenges.push(challenge);
+
+        let e = handle_login5_response(&mut request, response, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::FailedPrecondition);
+    }
+
+    #[test]
+    fn classifies_login5_errors() {
+        for (err, kind, recovery) in [
+            (
+                Login5Error::Login(LoginError::INVALID_CREDENTIALS),
+                ErrorKind::PermissionDenied,
+                Recovery::Reauthenticate,
+            ),
+            (
+                Login5Error::Login(LoginError::TRY_AGAIN_LATER),
+                ErrorKind::ResourceExhausted,
+                Recovery::Retry,
+            ),
+            (
+                Login5Error::UnsupportedChallenge,
+                ErrorKind::FailedPrecondition,
+                Recovery::GiveUp,
+            ),
+            (
+                Login5Error::TooManyChallenges,
+                ErrorKind::Aborted,
+                Recovery::GiveUp,
+            ),
+        ] {
+            let code = err.code();
+            let err = Error::from(err);
+            assert_eq!(err.code(), code);
+            assert_eq!((err.kind, err.recovery()), (kind, recovery), "{}", code);
+        }
+
+        let err = Error::from(AuthenticationError::Key);
+        assert_eq!(err.code(), "authentication.key");
+        assert_eq!(err.recovery(), Recovery::GiveUp);
+    }
+}
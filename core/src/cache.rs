@@ -1,18 +1,30 @@
 use std::{
     cmp::Reverse,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File},
     io::{self, Read, Write},
-    path::{Path, PathBuf},
-    sync::Arc,
-    time::SystemTime,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use parking_lot::Mutex;
 use priority_queue::PriorityQueue;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 use thiserror::Error;
 
-use crate::{authentication::Credentials, error::ErrorKind, Error, FileId};
+use crate::{
+    audio_key::AudioKey,
+    authentication::Credentials,
+    credential_store::{CredentialStore, FileCredentialStore},
+    error::{ErrorCode, ErrorKind},
+    Error, FileId,
+};
 
 #[derive(Debug, Error)]
 pub enum CacheError {
@@ -22,16 +34,144 @@ pub enum CacheError {
 
 impl From<CacheError> for Error {
     fn from(err: CacheError) -> Self {
-        Error::failed_precondition(err)
+        Error::with_code(ErrorKind::FailedPrecondition, err)
+    }
+}
+
+impl ErrorCode for CacheError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Path => "cache.path",
+        }
+    }
+}
+
+const ARCHIVE_AUDIO_DIR: &str = "audio";
+const ARCHIVE_METADATA_DIR: &str = "metadata";
+const ARCHIVE_VOLUME: &str = "volume";
+
+// In the root of the audio cache: when the audio files were last accessed, because many
+// file systems don't update the access time, the checksums of the audio files, and the
+// audio files being added. Files being written have a prefix in all cache directories.
+const ACCESS_INDEX: &str = "index";
+const CHECKSUMS: &str = "checksums";
+const JOURNAL: &str = "journal";
+const TEMP_PREFIX: &str = "tmp-";
+
+// Opening a file makes it the most recently used one. Instead of saving the access index every
+// time, changes are saved at most this often, and when the cache is closed.
+const ACCESS_INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Tells apart the temporary files of concurrent writes to the same file.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let count = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!("{TEMP_PREFIX}{count}-{name}"))
+}
+
+// Makes a rename in the directory durable. Directories cannot be opened for that on Windows,
+// where renames are durable once the call returns.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Replaces the file at `path` with `contents` at once: they are written to a temporary file
+/// next to it, flushed to disk and renamed over it, so that a power cut leaves either the old
+/// or the new contents behind, but never a truncated file.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    sync_dir(path.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+// Removes what is left over from writes that were interrupted.
+pub(crate) fn remove_temp_files(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Could not read directory {:?} in cache dir: {}", dir, e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+            let path = entry.path();
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Could not remove file {:?} from cache dir: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Removes the file at `location` if its contents aren't `valid`, so that it is written again
+/// instead of failing every time it is read. Files written before they were replaced at once
+/// may have been truncated by a power cut.
+pub(crate) fn remove_if_invalid<F: FnOnce(&str) -> bool>(location: &Path, what: &str, valid: F) {
+    let valid = match fs::read(location) {
+        Ok(contents) => match std::str::from_utf8(&contents) {
+            Ok(contents) => valid(contents),
+            Err(_) => false,
+        },
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Error reading {} from cache: {}", what, e);
+            }
+            return;
+        }
+    };
+
+    if !valid {
+        warn!("Removing invalid {} from cache", what);
+        if let Err(e) = fs::remove_file(location) {
+            warn!("Could not remove file {:?} from cache dir: {}", location, e);
+        }
     }
 }
 
+/// Called with every audio file that is evicted from the cache to stay within its size
+/// limit, and the number of bytes it took.
+pub type EvictionCallback = Box<dyn Fn(FileId, u64) + Send + Sync>;
+
+/// The identity a Connect device was last announced with, to announce it with the same one
+/// after a restart, so that it keeps its entry in the device picker even when renamed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceRegistration {
+    pub device_id: String,
+    pub name: String,
+}
+
+/// What was written to or read from a cache archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheArchiveSummary {
+    pub audio_files: usize,
+    pub audio_bytes: u64,
+    pub volume: bool,
+    pub metadata_entries: usize,
+}
+
 /// Some kind of data structure that holds some paths, the size of these files and a timestamp.
 /// It keeps track of the file sizes and is able to pop the path with the oldest timestamp if
-/// a given limit is exceeded.
+/// a given limit is exceeded. Pinned paths count towards the limit, but are never popped.
 struct SizeLimiter {
     queue: PriorityQueue<PathBuf, Reverse<SystemTime>>,
     sizes: HashMap<PathBuf, u64>,
+    pinned: HashSet<PathBuf>,
     size_limit: u64,
     in_use: u64,
 }
@@ -42,6 +182,7 @@ impl SizeLimiter {
         Self {
             queue: PriorityQueue::new(),
             sizes: HashMap::new(),
+            pinned: HashSet::new(),
             size_limit: limit,
             in_use: 0,
         }
@@ -52,7 +193,9 @@ impl SizeLimiter {
     /// If this file is already contained, it will be updated accordingly.
     fn add(&mut self, file: &Path, size: u64, accessed: SystemTime) {
         self.in_use += size;
-        self.queue.push(file.to_owned(), Reverse(accessed));
+        if !self.pinned.contains(file) {
+            self.queue.push(file.to_owned(), Reverse(accessed));
+        }
         if let Some(old_size) = self.sizes.insert(file.to_owned(), size) {
             // It's important that decreasing happens after
             // increasing the size, to prevent an overflow.
@@ -65,22 +208,29 @@ impl SizeLimiter {
         self.in_use > self.size_limit
     }
 
-    /// Returns the least recently accessed file if the size of the cache exceeds
+    /// Returns the least recently accessed file and its size if the size of the cache exceeds
     /// the limit.
     ///
     /// The entry is removed from the data structure, but the caller is responsible
     /// to delete the file in the file system.
-    fn pop(&mut self) -> Option<PathBuf> {
+    fn pop(&mut self) -> Option<(PathBuf, u64)> {
         if self.exceeds_limit() {
             if let Some((next, _)) = self.queue.pop() {
-                if let Some(size) = self.sizes.remove(&next) {
-                    self.in_use -= size;
+                let size = match self.sizes.remove(&next) {
+                    Some(size) => size,
+                    None => {
+                        error!("`queue` and `sizes` should have the same keys.");
+                        0
+                    }
+                };
+                self.in_use -= size;
+                Some((next, size))
+            } else {
+                if self.pinned.is_empty() {
+                    error!("in_use was > 0, so the queue should have contained an item.");
                 } else {
-                    error!("`queue` and `sizes` should have the same keys.");
+                    warn!("Cache dir exceeds limit, but all remaining files are pinned.");
                 }
-                Some(next)
-            } else {
-                error!("in_use was > 0, so the queue should have contained an item.");
                 None
             }
         } else {
@@ -90,6 +240,9 @@ impl SizeLimiter {
 
     /// Updates the timestamp of an existing element. Returns `true` if the item did exist.
     fn update(&mut self, file: &Path, access_time: SystemTime) -> bool {
+        if self.pinned.contains(file) {
+            return self.sizes.contains_key(file);
+        }
         self.queue
             .change_priority(file, Reverse(access_time))
             .is_some()
@@ -97,22 +250,51 @@ impl SizeLimiter {
 
     /// Removes an element with the specified path. Returns `true` if the item did exist.
     fn remove(&mut self, file: &Path) -> bool {
-        if self.queue.remove(file).is_none() {
-            return false;
-        }
+        let queued = self.queue.remove(file).is_some();
 
         if let Some(size) = self.sizes.remove(file) {
             self.in_use -= size;
+            true
         } else {
-            error!("`queue` and `sizes` should have the same keys.");
+            if queued {
+                error!("`queue` and `sizes` should have the same keys.");
+            }
+            false
+        }
+    }
+
+    /// Protects the path from being popped, whether or not it is contained yet.
+    fn pin(&mut self, file: &Path) {
+        self.pinned.insert(file.to_owned());
+        self.queue.remove(file);
+    }
+
+    /// Makes the path poppable again, as if it had been accessed at `access_time`.
+    fn unpin(&mut self, file: &Path, access_time: SystemTime) {
+        if self.pinned.remove(file) && self.sizes.contains_key(file) {
+            self.queue.push(file.to_owned(), Reverse(access_time));
         }
+    }
 
-        true
+    /// The poppable paths and when they were last accessed.
+    fn access_times(&self) -> impl Iterator<Item = (&Path, SystemTime)> {
+        self.queue
+            .iter()
+            .map(|(file, Reverse(accessed))| (file.as_path(), *accessed))
     }
 }
 
 struct FsSizeLimiter {
     limiter: Mutex<SizeLimiter>,
+    root: PathBuf,
+    on_evict: Mutex<Option<EvictionCallback>>,
+    index: Mutex<AccessIndexState>,
+}
+
+struct AccessIndexState {
+    saved_at: Instant,
+    // whether access times changed since the index was saved
+    dirty: bool,
 }
 
 impl FsSizeLimiter {
@@ -136,8 +318,9 @@ impl FsSizeLimiter {
         Ok((access_time, size))
     }
 
-    /// Recursively search a directory for files and add them to the `limiter` struct.
-    fn init_dir(limiter: &mut SizeLimiter, path: &Path) {
+    /// Recursively search a directory for files and add them to the `limiter` struct, as
+    /// accessed when the file system or the access index says, whichever is later.
+    fn init_dir(limiter: &mut SizeLimiter, path: &Path, index: &HashMap<PathBuf, SystemTime>) {
         let list_dir = match fs::read_dir(path) {
             Ok(list_dir) => list_dir,
             Err(e) => {
@@ -157,12 +340,29 @@ impl FsSizeLimiter {
 
             match entry.file_type() {
                 Ok(file_type) if file_type.is_dir() || file_type.is_symlink() => {
-                    Self::init_dir(limiter, &entry.path())
+                    Self::init_dir(limiter, &entry.path(), index)
                 }
                 Ok(file_type) if file_type.is_file() => {
                     let path = entry.path();
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    if name == ACCESS_INDEX || name == CHECKSUMS || name == JOURNAL {
+                        continue;
+                    }
+                    if name.starts_with(TEMP_PREFIX) {
+                        // left over from a write that was interrupted
+                        if let Err(e) = fs::remove_file(&path) {
+                            warn!("Could not remove file {:?} from cache dir: {}", path, e);
+                        }
+                        continue;
+                    }
+
                     match Self::get_metadata(&path) {
                         Ok((access_time, size)) => {
+                            let access_time = match index.get(&path) {
+                                Some(indexed) => access_time.max(*indexed),
+                                None => access_time,
+                            };
                             limiter.add(&path, size, access_time);
                         }
                         Err(e) => {
@@ -188,24 +388,119 @@ impl FsSizeLimiter {
         }
     }
 
+    // The access index has a line `{path}\t{seconds since the epoch}` per file, with the
+    // path relative to the root of the audio cache. The seconds have a fraction with the
+    // milliseconds since the index is saved less often.
+    fn read_index(root: &Path) -> HashMap<PathBuf, SystemTime> {
+        let contents = match fs::read_to_string(root.join(ACCESS_INDEX)) {
+            Ok(contents) => contents,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Error reading access index from cache: {}", e);
+                }
+                return HashMap::new();
+            }
+        };
+
+        contents
+            .lines()
+            .filter_map(|line| {
+                let (path, accessed) = line.split_once('\t')?;
+                let accessed = accessed.parse::<f64>().ok().filter(|secs| *secs >= 0.0)?;
+                let accessed = UNIX_EPOCH + Duration::from_secs_f64(accessed);
+                Some((root.join(path), accessed))
+            })
+            .collect()
+    }
+
+    // Replaces the access index at once, so that it is never read half written.
+    fn save_index(&self, limiter: &SizeLimiter) {
+        let contents: String = limiter
+            .access_times()
+            .filter_map(|(file, accessed)| {
+                let path = file.strip_prefix(&self.root).ok()?.to_str()?;
+                let accessed = accessed.duration_since(UNIX_EPOCH).ok()?;
+                Some(format!(
+                    "{path}\t{}.{:03}\n",
+                    accessed.as_secs(),
+                    accessed.subsec_millis()
+                ))
+            })
+            .collect();
+
+        if let Err(e) = write_atomic(&self.root.join(ACCESS_INDEX), contents) {
+            warn!("Cannot save access index to cache: {}", e);
+        }
+    }
+
+    // Saves the access index if it wasn't saved for a while, and leaves the change to a later
+    // save otherwise. Losing it to a crash only makes eviction less accurate.
+    fn index_changed(&self, limiter: &SizeLimiter) {
+        let mut index = self.index.lock();
+        if index.saved_at.elapsed() >= ACCESS_INDEX_SAVE_INTERVAL {
+            self.save_index(limiter);
+            index.saved_at = Instant::now();
+            index.dirty = false;
+        } else {
+            index.dirty = true;
+        }
+    }
+
+    fn flush_index(&self) {
+        let limiter = self.limiter.lock();
+        let mut index = self.index.lock();
+        if index.dirty {
+            self.save_index(&limiter);
+            index.saved_at = Instant::now();
+            index.dirty = false;
+        }
+    }
+
     fn add(&self, file: &Path, size: u64) {
-        self.limiter.lock().add(file, size, SystemTime::now())
+        let mut limiter = self.limiter.lock();
+        limiter.add(file, size, SystemTime::now());
+        self.index_changed(&limiter);
     }
 
     fn touch(&self, file: &Path) -> bool {
-        self.limiter.lock().update(file, SystemTime::now())
+        let mut limiter = self.limiter.lock();
+        let touched = limiter.update(file, SystemTime::now());
+        if touched {
+            self.index_changed(&limiter);
+        }
+        touched
     }
 
     fn remove(&self, file: &Path) -> bool {
-        self.limiter.lock().remove(file)
+        let mut limiter = self.limiter.lock();
+        let removed = limiter.remove(file);
+        if removed {
+            self.index_changed(&limiter);
+        }
+        removed
     }
 
-    fn prune_internal<F: FnMut() -> Option<PathBuf>>(mut pop: F) -> Result<(), Error> {
+    fn pin(&self, file: &Path) {
+        self.limiter.lock().pin(file)
+    }
+
+    fn unpin(&self, file: &Path) {
+        self.limiter.lock().unpin(file, SystemTime::now())
+    }
+
+    fn set_eviction_callback(&self, callback: Option<EvictionCallback>) {
+        *self.on_evict.lock() = callback;
+    }
+
+    fn prune_internal<F: FnMut() -> Option<(PathBuf, u64)>, E: FnMut(&Path, u64)>(
+        mut pop: F,
+        mut on_evict: E,
+    ) -> Result<(), Error> {
         let mut first = true;
         let mut count = 0;
         let mut last_error = None;
 
-        while let Some(file) = pop() {
+        while let Some((file, size)) = pop() {
             if first {
                 debug!("Cache dir exceeds limit, removing least recently used files.");
                 first = false;
@@ -217,6 +512,7 @@ impl FsSizeLimiter {
                 last_error = Some(e);
             } else {
                 count += 1;
+                on_evict(&file, size);
             }
         }
 
@@ -232,58 +528,397 @@ impl FsSizeLimiter {
     }
 
     fn prune(&self) -> Result<(), Error> {
-        Self::prune_internal(|| self.limiter.lock().pop())
+        let mut evicted = false;
+        let result = Self::prune_internal(
+            || self.limiter.lock().pop(),
+            |file, size| {
+                evicted = true;
+                if let (Some(callback), Some(file)) = (&*self.on_evict.lock(), file_id(file)) {
+                    callback(file, size);
+                }
+            },
+        );
+
+        if evicted {
+            self.index_changed(&self.limiter.lock());
+        }
+        result
     }
 
-    fn new(path: &Path, limit: u64) -> Result<Self, Error> {
+    fn new(path: &Path, limit: u64, pinned: &[PathBuf]) -> Result<Self, Error> {
         let mut limiter = SizeLimiter::new(limit);
 
-        Self::init_dir(&mut limiter, path);
-        Self::prune_internal(|| limiter.pop())?;
+        for file in pinned {
+            limiter.pin(file);
+        }
+        let index = Self::read_index(path);
+        Self::init_dir(&mut limiter, path, &index);
+        Self::prune_internal(|| limiter.pop(), |_, _| ())?;
 
-        Ok(Self {
+        let limiter = Self {
             limiter: Mutex::new(limiter),
-        })
+            root: path.to_owned(),
+            on_evict: Mutex::new(None),
+            index: Mutex::new(AccessIndexState {
+                saved_at: Instant::now(),
+                dirty: false,
+            }),
+        };
+        limiter.save_index(&limiter.limiter.lock());
+        Ok(limiter)
+    }
+}
+
+impl Drop for FsSizeLimiter {
+    fn drop(&mut self) {
+        self.flush_index();
+    }
+}
+
+/// The SHA-1 checksums of the cached audio files, to tell when a file got corrupted on disk.
+/// Otherwise that shows as decoder errors, which look like network problems.
+struct FileChecksums {
+    location: PathBuf,
+    state: Mutex<FileChecksumsState>,
+}
+
+#[derive(Default)]
+struct FileChecksumsState {
+    checksums: HashMap<FileId, String>,
+    // the files that were saved or verified since the cache was opened
+    verified: HashSet<FileId>,
+}
+
+impl FileChecksums {
+    // Has a line `{file id}\t{checksum}` per file, both in hex. Checksums are appended as files
+    // are added, so the last line of a file wins. Those of files that are not in the cache
+    // anymore, e.g. because they were evicted, are dropped when the cache is opened, and the
+    // file is compacted.
+    fn new(root: &Path) -> Self {
+        let location = root.join(CHECKSUMS);
+        let checksums = match fs::read_to_string(&location) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| {
+                    let (file, checksum) = line.split_once('\t')?;
+                    let file = FileId::from_base16(file).ok()?;
+                    // a line may have been cut short by a power cut
+                    let valid = checksum.len() == 40 && hex::decode(checksum).is_ok();
+                    valid.then(|| (file, checksum.to_owned()))
+                })
+                .collect::<HashMap<_, _>>()
+                .into_iter()
+                .filter(|(file, _)| {
+                    audio_file_path(root, *file).map_or(false, |path| path.is_file())
+                })
+                .collect(),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Error reading checksums from cache: {}", e);
+                }
+                HashMap::new()
+            }
+        };
+
+        let contents: String = checksums
+            .iter()
+            .filter_map(|(file, checksum)| Self::line(*file, checksum))
+            .collect();
+        if let Err(e) = write_atomic(&location, contents) {
+            warn!("Cannot save checksums to cache: {}", e);
+        }
+
+        Self {
+            location,
+            state: Mutex::new(FileChecksumsState {
+                checksums,
+                verified: HashSet::new(),
+            }),
+        }
+    }
+
+    fn line(file: FileId, checksum: &str) -> Option<String> {
+        Some(format!("{}\t{checksum}\n", file.to_base16().ok()?))
+    }
+
+    fn append(&self, file: FileId, checksum: &str) -> io::Result<()> {
+        let line = Self::line(file, checksum).unwrap_or_default();
+        let mut checksums = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.location)?;
+        checksums.write_all(line.as_bytes())?;
+        checksums.sync_data()
+    }
+
+    fn get(&self, file: FileId) -> Option<String> {
+        self.state.lock().checksums.get(&file).cloned()
+    }
+
+    // Returns whether `file` still has to be verified, and leaves that to the caller.
+    fn begin_verification(&self, file: FileId) -> bool {
+        self.state.lock().verified.insert(file)
+    }
+
+    fn insert(&self, file: FileId, checksum: String) {
+        let mut state = self.state.lock();
+        if let Err(e) = self.append(file, &checksum) {
+            warn!("Cannot save checksum to cache: {}", e);
+        }
+        state.verified.insert(file);
+        state.checksums.insert(file, checksum);
+    }
+
+    // The checksum stays in the file until the cache is opened again, when it is dropped
+    // because the file is gone, or a newer checksum of the same file is appended.
+    fn remove(&self, file: FileId) {
+        let mut state = self.state.lock();
+        state.checksums.remove(&file);
+        state.verified.remove(&file);
     }
 }
 
-/// A cache for volume, credentials and audio files.
+/// The audio files being added to the cache, with their checksums. Adding one updates the
+/// file, its checksum and the access index, which cannot be replaced at once together. When
+/// that was interrupted, the file is kept if it is intact and removed otherwise.
+struct Journal {
+    location: PathBuf,
+    pending: Mutex<HashMap<FileId, String>>,
+}
+
+impl Journal {
+    // Has a line `{file id}\t{checksum}` per file, like the checksums.
+    fn new(root: &Path) -> Self {
+        Self {
+            location: root.join(JOURNAL),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn save(&self, pending: &HashMap<FileId, String>) {
+        let result = if pending.is_empty() {
+            match fs::remove_file(&self.location) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        } else {
+            let contents: String = pending
+                .iter()
+                .filter_map(|(file, checksum)| {
+                    Some(format!("{}\t{checksum}\n", file.to_base16().ok()?))
+                })
+                .collect();
+            write_atomic(&self.location, contents)
+        };
+        if let Err(e) = result {
+            warn!("Cannot save journal to cache: {}", e);
+        }
+    }
+
+    fn begin(&self, file: FileId, checksum: String) {
+        let mut pending = self.pending.lock();
+        pending.insert(file, checksum);
+        self.save(&pending);
+    }
+
+    fn commit(&self, file: FileId) {
+        let mut pending = self.pending.lock();
+        if pending.remove(&file).is_some() {
+            self.save(&pending);
+        }
+    }
+
+    /// Completes or rolls back the additions that were interrupted, before the files in the
+    /// cache are counted towards its size limit.
+    fn recover(&self, root: &Path, checksums: &FileChecksums) {
+        let contents = match fs::read_to_string(&self.location) {
+            Ok(contents) => contents,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Error reading journal from cache: {}", e);
+                }
+                return;
+            }
+        };
+
+        let pending = contents.lines().filter_map(|line| {
+            let (file, expected) = line.split_once('\t')?;
+            let file = FileId::from_base16(file).ok()?;
+            Some((file, expected, audio_file_path(root, file)?))
+        });
+
+        for (file, expected, path) in pending {
+            match checksum(&path) {
+                Ok(actual) if actual == expected => checksums.insert(file, actual),
+                Ok(_) => {
+                    warn!("Removing incompletely saved audio file {} from cache", file);
+                    if let Err(e) = fs::remove_file(&path) {
+                        warn!("Could not remove file {:?} from cache dir: {}", path, e);
+                    }
+                    checksums.remove(file);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => checksums.remove(file),
+                Err(e) => warn!("Could not read file {:?} in cache dir: {}", path, e),
+            }
+        }
+
+        self.save(&HashMap::new());
+    }
+}
+
+// Computes the checksum of what is read through it.
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: Sha1,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha1::new(),
+        }
+    }
+
+    fn checksum(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn checksum(path: &Path) -> io::Result<String> {
+    let mut reader = ChecksumReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.checksum())
+}
+
+/// A cache for volume, presets, credentials, metadata and audio files.
 #[derive(Clone)]
 pub struct Cache {
-    credentials_location: Option<PathBuf>,
+    credential_store: Option<Arc<dyn CredentialStore>>,
     volume_location: Option<PathBuf>,
+    presets_location: Option<PathBuf>,
+    metadata_location: Option<PathBuf>,
     audio_location: Option<PathBuf>,
+    pinned_location: Option<PathBuf>,
+    device_location: Option<PathBuf>,
+    pinned: Arc<Mutex<HashSet<FileId>>>,
     size_limiter: Option<Arc<FsSizeLimiter>>,
+    checksums: Option<Arc<FileChecksums>>,
+    journal: Option<Arc<Journal>>,
+}
+
+// The inverse of `audio_file_path`.
+fn file_id(path: &Path) -> Option<FileId> {
+    let name = path.file_name()?.to_str()?;
+    let prefix = path.parent()?.file_name()?.to_str()?;
+    FileId::from_base16(&format!("{prefix}{name}")).ok()
+}
+
+fn audio_file_path(location: &Path, file: FileId) -> Option<PathBuf> {
+    match file.to_base16() {
+        Ok(name) => {
+            let mut path = location.join(&name[0..2]);
+            path.push(&name[2..]);
+            Some(path)
+        }
+        Err(e) => {
+            warn!("Invalid FileId: {}", e);
+            None
+        }
+    }
 }
 
 impl Cache {
+    /// Creates a cache that keeps the credentials in `credentials.json` in `credentials_path`.
     pub fn new<P: AsRef<Path>>(
         credentials_path: Option<P>,
         volume_path: Option<P>,
         audio_path: Option<P>,
         size_limit: Option<u64>,
+    ) -> Result<Self, Error> {
+        let credential_store = match credentials_path {
+            Some(path) => {
+                Some(Arc::new(FileCredentialStore::new(path)?) as Arc<dyn CredentialStore>)
+            }
+            None => None,
+        };
+        Self::with_credential_store(credential_store, volume_path, audio_path, size_limit)
+    }
+
+    /// Creates a cache that keeps the credentials in `credential_store`, e.g. a
+    /// [`KeyringCredentialStore`](crate::credential_store::KeyringCredentialStore).
+    pub fn with_credential_store<P: AsRef<Path>>(
+        credential_store: Option<Arc<dyn CredentialStore>>,
+        volume_path: Option<P>,
+        audio_path: Option<P>,
+        size_limit: Option<u64>,
     ) -> Result<Self, Error> {
         let mut size_limiter = None;
+        let mut checksums = None;
+        let mut journal = None;
 
-        if let Some(location) = &credentials_path {
+        if let Some(location) = &volume_path {
             fs::create_dir_all(location)?;
+            remove_temp_files(location.as_ref());
         }
 
-        let credentials_location = credentials_path
+        let volume_location = volume_path.as_ref().map(|p| p.as_ref().join("volume"));
+        let presets_location = volume_path
             .as_ref()
-            .map(|p| p.as_ref().join("credentials.json"));
+            .map(|p| p.as_ref().join("presets.json"));
+        let pinned_location = volume_path.as_ref().map(|p| p.as_ref().join("pinned"));
+        let device_location = volume_path.as_ref().map(|p| p.as_ref().join("device.json"));
+        let pinned = pinned_location
+            .as_deref()
+            .map(Self::read_pinned)
+            .unwrap_or_default();
+        let metadata_location = volume_path.as_ref().map(|p| p.as_ref().join("metadata"));
+
+        if let Some(location) = &volume_location {
+            remove_if_invalid(location, "volume", |contents| {
+                contents.parse::<u16>().is_ok()
+            });
+        }
+        if let Some(location) = &presets_location {
+            remove_if_invalid(location, "presets", |contents| {
+                serde_json::from_str::<BTreeMap<u8, String>>(contents).is_ok()
+            });
+        }
+        if let Some(location) = &device_location {
+            remove_if_invalid(location, "device registration", |contents| {
+                serde_json::from_str::<DeviceRegistration>(contents).is_ok()
+            });
+        }
 
-        if let Some(location) = &volume_path {
+        if let Some(location) = &metadata_location {
             fs::create_dir_all(location)?;
+            remove_temp_files(location);
         }
 
-        let volume_location = volume_path.as_ref().map(|p| p.as_ref().join("volume"));
-
         if let Some(location) = &audio_path {
             fs::create_dir_all(location)?;
+            remove_temp_files(location.as_ref());
+
+            let file_checksums = FileChecksums::new(location.as_ref());
+            let file_journal = Journal::new(location.as_ref());
+            file_journal.recover(location.as_ref(), &file_checksums);
+            checksums = Some(Arc::new(file_checksums));
+            journal = Some(Arc::new(file_journal));
 
             if let Some(limit) = size_limit {
-                let limiter = FsSizeLimiter::new(location.as_ref(), limit)?;
+                let pinned_paths: Vec<PathBuf> = pinned
+                    .iter()
+                    .filter_map(|file| audio_file_path(location.as_ref(), *file))
+                    .collect();
+                let limiter = FsSizeLimiter::new(location.as_ref(), limit, &pinned_paths)?;
                 size_limiter = Some(Arc::new(limiter));
             }
         }
@@ -291,52 +926,48 @@ impl Cache {
         let audio_location = audio_path.map(|p| p.as_ref().to_owned());
 
         let cache = Cache {
-            credentials_location,
+            credential_store,
             volume_location,
+            presets_location,
+            metadata_location,
             audio_location,
+            pinned_location,
+            device_location,
+            pinned: Arc::new(Mutex::new(pinned)),
             size_limiter,
+            checksums,
+            journal,
         };
 
         Ok(cache)
     }
 
     pub fn credentials(&self) -> Option<Credentials> {
-        let location = self.credentials_location.as_ref()?;
-
-        // This closure is just convencience to enable the question mark operator
-        let read = || -> Result<Credentials, Error> {
-            let mut file = File::open(location)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            Ok(serde_json::from_str(&contents)?)
-        };
-
-        match read() {
-            Ok(c) => Some(c),
+        match self.credential_store.as_ref()?.load() {
+            Ok(credentials) => credentials,
             Err(e) => {
-                // If the file did not exist, the file was probably not written
-                // before. Otherwise, log the error.
-                if e.kind != ErrorKind::NotFound {
-                    warn!("Error reading credentials from cache: {}", e);
-                }
+                warn!("Error reading credentials from cache: {}", e);
                 None
             }
         }
     }
 
     pub fn save_credentials(&self, cred: &Credentials) {
-        if let Some(location) = &self.credentials_location {
-            let result = File::create(location).and_then(|mut file| {
-                let data = serde_json::to_string(cred)?;
-                write!(file, "{data}")
-            });
-
-            if let Err(e) = result {
+        if let Some(store) = &self.credential_store {
+            if let Err(e) = store.save(cred) {
                 warn!("Cannot save credentials to cache: {}", e)
             }
         }
     }
 
+    pub fn remove_credentials(&self) {
+        if let Some(store) = &self.credential_store {
+            if let Err(e) = store.remove() {
+                warn!("Cannot remove credentials from cache: {}", e)
+            }
+        }
+    }
+
     pub fn volume(&self) -> Option<u16> {
         let location = self.volume_location.as_ref()?;
 
@@ -360,37 +991,282 @@ impl Cache {
 
     pub fn save_volume(&self, volume: u16) {
         if let Some(ref location) = self.volume_location {
-            let result = File::create(location).and_then(|mut file| write!(file, "{volume}"));
-            if let Err(e) = result {
+            if let Err(e) = write_atomic(location, volume.to_string()) {
                 warn!("Cannot save volume to cache: {}", e);
             }
         }
     }
 
-    pub fn file_path(&self, file: FileId) -> Option<PathBuf> {
-        match file.to_base16() {
-            Ok(name) => self.audio_location.as_ref().map(|location| {
-                let mut path = location.join(&name[0..2]);
-                path.push(&name[2..]);
-                path
-            }),
+    /// The saved presets, mapping preset numbers to context URIs.
+    pub fn presets(&self) -> Option<BTreeMap<u8, String>> {
+        let location = self.presets_location.as_ref()?;
+
+        let read = || -> Result<BTreeMap<u8, String>, Error> {
+            let contents = fs::read_to_string(location)?;
+            Ok(serde_json::from_str(&contents)?)
+        };
+
+        match read() {
+            Ok(presets) => Some(presets),
             Err(e) => {
-                warn!("Invalid FileId: {}", e);
+                if e.kind != ErrorKind::NotFound {
+                    warn!("Error reading presets from cache: {}", e);
+                }
                 None
             }
         }
     }
 
+    pub fn save_presets(&self, presets: &BTreeMap<u8, String>) {
+        if let Some(location) = &self.presets_location {
+            let result = serde_json::to_string(presets)
+                .map_err(io::Error::from)
+                .and_then(|data| write_atomic(location, data));
+
+            if let Err(e) = result {
+                warn!("Cannot save presets to cache: {}", e);
+            }
+        }
+    }
+
+    pub fn device_registration(&self) -> Option<DeviceRegistration> {
+        let location = self.device_location.as_ref()?;
+
+        let read = || -> Result<DeviceRegistration, Error> {
+            let contents = fs::read_to_string(location)?;
+            Ok(serde_json::from_str(&contents)?)
+        };
+
+        match read() {
+            Ok(registration) => Some(registration),
+            Err(e) => {
+                if e.kind != ErrorKind::NotFound {
+                    warn!("Error reading device registration from cache: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn save_device_registration(&self, registration: &DeviceRegistration) {
+        if let Some(location) = &self.device_location {
+            let result = serde_json::to_string(registration)
+                .map_err(io::Error::from)
+                .and_then(|data| write_atomic(location, data));
+
+            if let Err(e) = result {
+                warn!("Cannot save device registration to cache: {}", e);
+            }
+        }
+    }
+
+    fn metadata_path(&self, key: &str) -> Option<PathBuf> {
+        // keys become file names, so they must not be able to escape the metadata directory
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            warn!("Invalid metadata cache key: {:?}", key);
+            return None;
+        }
+        self.metadata_location.as_ref().map(|p| p.join(key))
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.metadata_path(key)?;
+        match fs::read(path) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Error reading metadata from cache: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn save_metadata(&self, key: &str, data: &[u8]) {
+        if let Some(path) = self.metadata_path(key) {
+            if let Err(e) = write_atomic(&path, data) {
+                warn!("Cannot save metadata to cache: {}", e);
+            }
+        }
+    }
+
+    pub fn remove_metadata(&self, key: &str) {
+        if let Some(path) = self.metadata_path(key) {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Cannot remove metadata from cache: {}", e);
+                }
+            }
+        }
+    }
+
+    fn audio_key_cache_key(file: FileId) -> Option<String> {
+        file.to_base16()
+            .ok()
+            .map(|name| format!("audio-key-{name}"))
+    }
+
+    /// Returns the key of an audio file that was downloaded for offline playback.
+    pub fn audio_key(&self, file: FileId) -> Option<AudioKey> {
+        let data = self.metadata(&Self::audio_key_cache_key(file)?)?;
+        Some(AudioKey(data.try_into().ok()?))
+    }
+
+    /// Keeps the key of an audio file, so that it can be decrypted without a connection.
+    pub fn save_audio_key(&self, file: FileId, key: AudioKey) {
+        if let Some(cache_key) = Self::audio_key_cache_key(file) {
+            self.save_metadata(&cache_key, &key.0);
+        }
+    }
+
+    pub fn remove_audio_key(&self, file: FileId) {
+        if let Some(cache_key) = Self::audio_key_cache_key(file) {
+            self.remove_metadata(&cache_key);
+        }
+    }
+
+    fn seek_table_cache_key(file: FileId) -> Option<String> {
+        file.to_base16()
+            .ok()
+            .map(|name| format!("seek-table-{name}"))
+    }
+
+    /// Returns where the pages of a cached audio file start, as saved by the player, so
+    /// that it can be opened and seeked without reading the end of the file first.
+    pub fn seek_table(&self, file: FileId) -> Option<Vec<u8>> {
+        self.metadata(&Self::seek_table_cache_key(file)?)
+    }
+
+    pub fn save_seek_table(&self, file: FileId, data: &[u8]) {
+        if let Some(cache_key) = Self::seek_table_cache_key(file) {
+            self.save_metadata(&cache_key, data);
+        }
+    }
+
+    pub fn remove_seek_table(&self, file: FileId) {
+        if let Some(cache_key) = Self::seek_table_cache_key(file) {
+            self.remove_metadata(&cache_key);
+        }
+    }
+
+    pub fn file_path(&self, file: FileId) -> Option<PathBuf> {
+        audio_file_path(self.audio_location.as_ref()?, file)
+    }
+
+    /// Returns the number of bytes of the audio file in the cache, if it is cached.
+    pub fn file_size(&self, file: FileId) -> Option<u64> {
+        let metadata = fs::metadata(self.file_path(file)?).ok()?;
+        if metadata.is_file() {
+            Some(metadata.len())
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether the audio file is cached. Audio files are only added to the cache once
+    /// they have been downloaded completely, so a cached file is always complete.
+    pub fn is_file_cached(&self, file: FileId) -> bool {
+        self.file_size(file).is_some()
+    }
+
+    /// Protects the audio file from being evicted when the cache exceeds its size limit, also
+    /// if it is only cached later on. Pins are kept next to the volume, so they persist if
+    /// the cache has a volume location. Removing the file with [`Cache::remove_file`] does
+    /// not unpin it.
+    pub fn pin_file(&self, file: FileId) -> Result<(), Error> {
+        let path = self.file_path(file).ok_or(CacheError::Path)?;
+
+        let mut pinned = self.pinned.lock();
+        if pinned.insert(file) {
+            self.save_pinned(&pinned);
+        }
+        if let Some(limiter) = self.size_limiter.as_deref() {
+            limiter.pin(&path);
+        }
+
+        Ok(())
+    }
+
+    /// Lets the audio file be evicted again, which may happen right away if the cache
+    /// exceeds its size limit.
+    pub fn unpin_file(&self, file: FileId) -> Result<(), Error> {
+        let path = self.file_path(file).ok_or(CacheError::Path)?;
+
+        {
+            let mut pinned = self.pinned.lock();
+            if pinned.remove(&file) {
+                self.save_pinned(&pinned);
+            }
+        }
+        if let Some(limiter) = self.size_limiter.as_deref() {
+            limiter.unpin(&path);
+            limiter.prune()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_file_pinned(&self, file: FileId) -> bool {
+        self.pinned.lock().contains(&file)
+    }
+
+    pub fn pinned_files(&self) -> Vec<FileId> {
+        self.pinned.lock().iter().copied().collect()
+    }
+
+    fn read_pinned(location: &Path) -> HashSet<FileId> {
+        match fs::read_to_string(location) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| FileId::from_base16(line.trim()).ok())
+                .collect(),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Error reading pinned files from cache: {}", e);
+                }
+                HashSet::new()
+            }
+        }
+    }
+
+    fn save_pinned(&self, pinned: &HashSet<FileId>) {
+        if let Some(location) = &self.pinned_location {
+            let contents: String = pinned
+                .iter()
+                .filter_map(|file| file.to_base16().ok())
+                .map(|name| name + "\n")
+                .collect();
+            if let Err(e) = write_atomic(location, contents) {
+                warn!("Cannot save pinned files to cache: {}", e);
+            }
+        }
+    }
+
+    /// Opens a cached audio file. The first time a file is opened after the cache was opened,
+    /// it is verified against the checksum it was saved with in the background, without
+    /// holding up playback. A corrupted file is removed, so that it is downloaded again the
+    /// next time. Files that were cached before checksums were kept get theirs then.
     pub fn file(&self, file: FileId) -> Option<File> {
         let path = self.file_path(file)?;
         match File::open(&path) {
-            Ok(file) => {
+            Ok(opened) => {
+                if let Some(checksums) = self.checksums.as_deref() {
+                    if checksums.begin_verification(file) {
+                        let cache = self.clone();
+                        let path = path.clone();
+                        thread::spawn(move || cache.verify_file(file, &path));
+                    }
+                }
                 if let Some(limiter) = self.size_limiter.as_deref() {
                     if !limiter.touch(&path) {
                         error!("limiter could not touch {:?}", path);
                     }
                 }
-                Some(file)
+                Some(opened)
             }
             Err(e) => {
                 if e.kind() != io::ErrorKind::NotFound {
@@ -401,24 +1277,88 @@ impl Cache {
         }
     }
 
+    fn verify_file(&self, file: FileId, path: &Path) {
+        let checksums = match self.checksums.as_deref() {
+            Some(checksums) => checksums,
+            None => return,
+        };
+        match (checksum(path), checksums.get(file)) {
+            (Ok(actual), Some(expected)) if actual != expected => {
+                warn!("Cached audio file {} is corrupted, removing it", file);
+                if let Err(e) = self.remove_file(file) {
+                    warn!("Could not remove corrupted file {:?}: {}", path, e);
+                }
+            }
+            (Ok(actual), None) => checksums.insert(file, actual),
+            (Ok(_), Some(_)) => (),
+            (Err(e), _) => warn!("Could not verify cached audio file {}: {}", file, e),
+        }
+    }
+
+    /// Saves an audio file. It is written under a temporary name first, so that it is only
+    /// found in the cache once it is complete, also when interrupted. Until its checksum and
+    /// access time are saved too, it is journaled, so that an interrupted addition is
+    /// completed or rolled back when the cache is opened again.
     pub fn save_file<F: Read>(&self, file: FileId, contents: &mut F) -> Result<PathBuf, Error> {
-        if let Some(path) = self.file_path(file) {
+        if let (Some(path), Some(location), Ok(name)) =
+            (self.file_path(file), &self.audio_location, file.to_base16())
+        {
             if let Some(parent) = path.parent() {
-                if let Ok(size) = fs::create_dir_all(parent)
-                    .and_then(|_| File::create(&path))
-                    .and_then(|mut file| io::copy(contents, &mut file))
-                {
-                    if let Some(limiter) = self.size_limiter.as_deref() {
-                        limiter.add(&path, size);
-                        limiter.prune()?;
+                let temp = location.join(format!("{TEMP_PREFIX}{name}"));
+                let mut contents = ChecksumReader::new(contents);
+                match fs::create_dir_all(parent)
+                    .and_then(|_| File::create(&temp))
+                    .and_then(|mut file| {
+                        let size = io::copy(&mut contents, &mut file)?;
+                        file.sync_all()?;
+                        Ok(size)
+                    }) {
+                    Ok(size) => {
+                        let checksum = contents.checksum();
+                        if let Some(journal) = self.journal.as_deref() {
+                            journal.begin(file, checksum.clone());
+                        }
+                        let renamed = fs::rename(&temp, &path).and_then(|_| sync_dir(parent));
+                        if let Err(e) = renamed {
+                            warn!("Cannot save file to cache: {}", e);
+                            let _ = fs::remove_file(&temp);
+                            if let Some(journal) = self.journal.as_deref() {
+                                journal.commit(file);
+                            }
+                            return Err(e.into());
+                        }
+
+                        if let Some(checksums) = self.checksums.as_deref() {
+                            checksums.insert(file, checksum);
+                        }
+                        if let Some(limiter) = self.size_limiter.as_deref() {
+                            limiter.add(&path, size);
+                        }
+                        if let Some(journal) = self.journal.as_deref() {
+                            journal.commit(file);
+                        }
+                        if let Some(limiter) = self.size_limiter.as_deref() {
+                            limiter.prune()?;
+                        }
+                        return Ok(path);
+                    }
+                    Err(_) => {
+                        let _ = fs::remove_file(&temp);
                     }
-                    return Ok(path);
                 }
             }
         }
         Err(CacheError::Path.into())
     }
 
+    /// Calls `callback` with every audio file that is evicted to stay within the size limit
+    /// from now on. Replaces the previous callback.
+    pub fn set_eviction_callback(&self, callback: EvictionCallback) {
+        if let Some(limiter) = self.size_limiter.as_deref() {
+            limiter.set_eviction_callback(Some(callback));
+        }
+    }
+
     pub fn remove_file(&self, file: FileId) -> Result<(), Error> {
         let path = self.file_path(file).ok_or(CacheError::Path)?;
 
@@ -426,20 +1366,141 @@ impl Cache {
         if let Some(limiter) = self.size_limiter.as_deref() {
             limiter.remove(&path);
         }
+        if let Some(checksums) = self.checksums.as_deref() {
+            checksums.remove(file);
+        }
+        self.remove_seek_table(file);
 
         Ok(())
     }
+
+    /// Writes the audio files, metadata and the volume of this cache to a tar archive, so that other
+    /// devices can be seeded with [`Cache::import`]. Credentials are left out, because they
+    /// are bound to the device they were issued to.
+    pub fn export<W: Write>(&self, writer: W) -> Result<CacheArchiveSummary, Error> {
+        let mut archive = tar::Builder::new(writer);
+        let mut summary = CacheArchiveSummary::default();
+
+        if let Some(volume) = self.volume() {
+            let data = volume.to_string();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, ARCHIVE_VOLUME, data.as_bytes())?;
+            summary.volume = true;
+        }
+
+        if let Some(location) = &self.metadata_location {
+            for file in fs::read_dir(location)? {
+                let file = file?;
+                if file.file_type()?.is_file() {
+                    let name = Path::new(ARCHIVE_METADATA_DIR).join(file.file_name());
+                    archive.append_path_with_name(file.path(), name)?;
+                    summary.metadata_entries += 1;
+                }
+            }
+        }
+
+        if let Some(location) = &self.audio_location {
+            for dir in fs::read_dir(location)? {
+                let dir = dir?;
+                // audio files are stored in directories named after their first two hex digits
+                if !dir.file_type()?.is_dir() || dir.file_name().len() != 2 {
+                    continue;
+                }
+
+                for file in fs::read_dir(dir.path())? {
+                    let file = file?;
+                    if !file.file_type()?.is_file() {
+                        continue;
+                    }
+
+                    let name = Path::new(ARCHIVE_AUDIO_DIR)
+                        .join(dir.file_name())
+                        .join(file.file_name());
+                    archive.append_path_with_name(file.path(), name)?;
+
+                    summary.audio_files += 1;
+                    summary.audio_bytes += file.metadata()?.len();
+                }
+            }
+        }
+
+        archive.into_inner()?.flush()?;
+
+        Ok(summary)
+    }
+
+    /// Reads a tar archive that was written by [`Cache::export`] into this cache. Audio files
+    /// are subject to the size limit of this cache.
+    pub fn import<R: Read>(&self, reader: R) -> Result<CacheArchiveSummary, Error> {
+        let mut archive = tar::Archive::new(reader);
+        let mut summary = CacheArchiveSummary::default();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+
+            if path == Path::new(ARCHIVE_VOLUME) {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents)?;
+                self.save_volume(contents.trim().parse()?);
+                summary.volume = true;
+            } else if let Some(key) = path
+                .strip_prefix(ARCHIVE_METADATA_DIR)
+                .ok()
+                .and_then(Path::to_str)
+            {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                self.save_metadata(key, &data);
+                summary.metadata_entries += 1;
+            } else if let Some(file) = Self::archived_file_id(&path) {
+                let size = entry.size();
+                self.save_file(file, &mut entry)?;
+                summary.audio_files += 1;
+                summary.audio_bytes += size;
+            } else {
+                warn!("Ignoring unknown entry {:?} in cache archive", path);
+            }
+        }
+
+        Ok(summary)
+    }
+
+    // Audio files are archived as `audio/ab/cdef...`, like they are laid out in the cache.
+    fn archived_file_id(path: &Path) -> Option<FileId> {
+        let mut components = path.components();
+        match (
+            components.next()?,
+            components.next()?,
+            components.next()?,
+            components.next(),
+        ) {
+            (Component::Normal(root), Component::Normal(prefix), Component::Normal(rest), None)
+                if root == ARCHIVE_AUDIO_DIR =>
+            {
+                let name = format!("{}{}", prefix.to_str()?, rest.to_str()?);
+                FileId::from_base16(&name).ok()
+            }
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::time::Duration;
 
     fn ordered_time(v: u64) -> SystemTime {
         SystemTime::UNIX_EPOCH + Duration::from_secs(v)
     }
 
+    fn pop(limiter: &mut SizeLimiter) -> Option<PathBuf> {
+        limiter.pop().map(|(file, _)| file)
+    }
+
     #[test]
     fn test_size_limiter() {
         let mut limiter = SizeLimiter::new(1000);
@@ -455,17 +1516,17 @@ mod test {
 
         // b (500) -> a (500) -> c (1000)  => sum: 2000 > 1000
         assert!(limiter.exceeds_limit());
-        assert_eq!(limiter.pop().as_deref(), Some(Path::new("b")));
+        assert_eq!(pop(&mut limiter).as_deref(), Some(Path::new("b")));
         // a (500) -> c (1000)  => sum: 1500 > 1000
-        assert_eq!(limiter.pop().as_deref(), Some(Path::new("a")));
+        assert_eq!(pop(&mut limiter).as_deref(), Some(Path::new("a")));
         // c (1000)   => sum: 1000 <= 1000
-        assert_eq!(limiter.pop().as_deref(), None);
+        assert_eq!(pop(&mut limiter).as_deref(), None);
 
         limiter.add(Path::new("d"), 5, ordered_time(2));
         // d (5) -> c (1000) => sum: 1005 > 1000
-        assert_eq!(limiter.pop().as_deref(), Some(Path::new("d")));
+        assert_eq!(pop(&mut limiter).as_deref(), Some(Path::new("d")));
         // c (1000)   => sum: 1000 <= 1000
-        assert_eq!(limiter.pop().as_deref(), None);
+        assert_eq!(pop(&mut limiter).as_deref(), None);
 
         // Test updating
 
@@ -473,7 +1534,7 @@ mod test {
         //  c (1000) -> e (500)  => sum: 1500 > 1000
         assert!(limiter.update(Path::new("c"), ordered_time(4)));
         // e (500) -> c (1000)  => sum: 1500 > 1000
-        assert_eq!(limiter.pop().as_deref(), Some(Path::new("e")));
+        assert_eq!(pop(&mut limiter).as_deref(), Some(Path::new("e")));
         // c (1000)  => sum: 1000 <= 1000
 
         // Test removing
@@ -481,4 +1542,295 @@ mod test {
         assert!(limiter.remove(Path::new("c")));
         assert!(!limiter.exceeds_limit());
     }
+
+    #[test]
+    fn test_size_limiter_pinned() {
+        let mut limiter = SizeLimiter::new(1000);
+
+        limiter.pin(Path::new("a"));
+        limiter.add(Path::new("a"), 500, ordered_time(1));
+        limiter.add(Path::new("b"), 500, ordered_time(2));
+        limiter.add(Path::new("c"), 500, ordered_time(3));
+
+        // a (500, pinned) -> b (500) -> c (500)  => sum: 1500 > 1000
+        assert_eq!(pop(&mut limiter).as_deref(), Some(Path::new("b")));
+        assert_eq!(pop(&mut limiter).as_deref(), None);
+
+        limiter.pin(Path::new("c"));
+        limiter.add(Path::new("d"), 500, ordered_time(4));
+        // a (500, pinned) -> c (500, pinned) -> d (500)  => sum: 1500 > 1000
+        assert_eq!(pop(&mut limiter).as_deref(), Some(Path::new("d")));
+
+        limiter.add(Path::new("e"), 1, ordered_time(5));
+        limiter.pin(Path::new("e"));
+        // Only pinned files are left, which exceed the limit: 1001 > 1000
+        assert!(limiter.exceeds_limit());
+        assert_eq!(limiter.pop(), None);
+
+        limiter.unpin(Path::new("a"), ordered_time(6));
+        assert_eq!(pop(&mut limiter).as_deref(), Some(Path::new("a")));
+        assert!(!limiter.exceeds_limit());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let dir = std::env::temp_dir().join(format!("librespot-eviction-{}", std::process::id()));
+        let cache = Cache::new(None, None, Some(&dir), Some(8)).expect("cache");
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let on_evict = evicted.clone();
+        cache.set_eviction_callback(Box::new(move |file, size| {
+            on_evict.lock().push((file, size));
+        }));
+
+        let (first, second) = (FileId([1; 20]), FileId([2; 20]));
+        cache
+            .save_file(first, &mut &b"first"[..])
+            .expect("saved file");
+        cache
+            .save_file(second, &mut &b"second"[..])
+            .expect("saved file");
+
+        // the access index doesn't count towards the limit
+        let reopened = Cache::new(None, None, Some(&dir), Some(8)).expect("reopened cache");
+        let cached = (
+            reopened.is_file_cached(first),
+            reopened.is_file_cached(second),
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(*evicted.lock(), vec![(first, 5)]);
+        assert_eq!(cached, (false, true));
+    }
+
+    #[test]
+    fn test_access_order_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("librespot-lru-{}", std::process::id()));
+        let cache = Cache::new(None, None, Some(&dir), Some(15)).expect("cache");
+
+        let files = [FileId([10; 20]), FileId([11; 20]), FileId([12; 20])];
+        for file in files {
+            cache
+                .save_file(file, &mut &b"audio"[..])
+                .expect("saved file");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let index = fs::read_to_string(dir.join(ACCESS_INDEX)).expect("index");
+        // the first file becomes the most recently used one
+        let opened = cache.file(files[0]).is_some();
+        let debounced = fs::read_to_string(dir.join(ACCESS_INDEX)).expect("index") == index;
+        drop(cache);
+
+        let reopened = Cache::new(None, None, Some(&dir), Some(15)).expect("reopened cache");
+        reopened
+            .save_file(FileId([13; 20]), &mut &b"audio"[..])
+            .expect("saved file");
+        let cached: Vec<_> = files
+            .iter()
+            .map(|file| reopened.is_file_cached(*file))
+            .collect();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(opened);
+        assert!(debounced);
+        assert_eq!(cached, [true, false, true]);
+    }
+
+    #[test]
+    fn test_audio_key() {
+        let dir = std::env::temp_dir().join(format!("librespot-keys-{}", std::process::id()));
+        let cache = Cache::new(None, Some(&dir), None, None).expect("cache");
+
+        let file = FileId([4; 20]);
+        let key = AudioKey([5; 16]);
+        let missing = cache.audio_key(file);
+        cache.save_audio_key(file, key);
+        let saved = cache.audio_key(file);
+        cache.remove_audio_key(file);
+        let removed = cache.audio_key(file);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(missing, None);
+        assert_eq!(saved, Some(key));
+        assert_eq!(removed, None);
+    }
+
+    #[test]
+    fn test_corrupted_file() {
+        let dir = std::env::temp_dir().join(format!("librespot-checksums-{}", std::process::id()));
+        let cache = Cache::new(None, None, Some(&dir), None).expect("cache");
+
+        let file = FileId([3; 20]);
+        let path = cache
+            .save_file(file, &mut &b"audio"[..])
+            .expect("saved file");
+        let intact = cache.file(file).is_some();
+
+        fs::write(&path, b"aud1o").expect("corrupted file");
+        // the checksum is kept across restarts, and the file is verified in the background
+        let reopened = Cache::new(None, None, Some(&dir), None).expect("reopened cache");
+        let opened = reopened.file(file).is_some();
+        let removed = wait_until(|| !path.exists());
+        let corrupted = reopened.file(file).is_some();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(intact);
+        assert!(opened);
+        assert!(removed);
+        assert!(!corrupted);
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        condition()
+    }
+
+    #[test]
+    fn test_checksums_are_appended() {
+        let dir = std::env::temp_dir().join(format!("librespot-append-{}", std::process::id()));
+        let cache = Cache::new(None, None, Some(&dir), None).expect("cache");
+
+        let (first, second) = (FileId([8; 20]), FileId([9; 20]));
+        cache
+            .save_file(first, &mut &b"first"[..])
+            .expect("saved file");
+        cache
+            .save_file(second, &mut &b"second"[..])
+            .expect("saved file");
+        cache
+            .save_file(first, &mut &b"first again"[..])
+            .expect("saved file");
+        let lines = fs::read_to_string(dir.join(CHECKSUMS))
+            .expect("checksums")
+            .lines()
+            .count();
+
+        // a power cut while appending leaves a line cut short
+        let mut checksums = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(CHECKSUMS))
+            .expect("checksums");
+        checksums
+            .write_all(format!("{}\t12ab", second.to_base16().expect("file id")).as_bytes())
+            .expect("torn line");
+        drop(checksums);
+
+        let reopened = Cache::new(None, None, Some(&dir), None).expect("reopened cache");
+        let compacted = fs::read_to_string(dir.join(CHECKSUMS))
+            .expect("checksums")
+            .lines()
+            .count();
+        let latest = reopened.checksums.as_deref().and_then(|c| c.get(first));
+        let kept = reopened.checksums.as_deref().and_then(|c| c.get(second));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(lines, 3);
+        assert_eq!(compacted, 2);
+        assert_eq!(latest, Some(hex::encode(Sha1::digest(b"first again"))));
+        assert_eq!(kept, Some(hex::encode(Sha1::digest(b"second"))));
+    }
+
+    #[test]
+    fn test_interrupted_save() {
+        let dir = std::env::temp_dir().join(format!("librespot-journal-{}", std::process::id()));
+        let cache = Cache::new(None, Some(&dir), Some(&dir), Some(1000)).expect("cache");
+
+        let (intact, truncated) = (FileId([6; 20]), FileId([7; 20]));
+        let intact_path = cache
+            .save_file(intact, &mut &b"intact"[..])
+            .expect("saved file");
+        let truncated_path = cache
+            .save_file(truncated, &mut &b"truncated"[..])
+            .expect("saved file");
+        cache.save_volume(21);
+
+        // power cut while adding both files, truncating the second, and writing the volume
+        let journal = format!(
+            "{}\t{}\n{}\t{}\n",
+            intact.to_base16().expect("file id"),
+            checksum(&intact_path).expect("checksum"),
+            truncated.to_base16().expect("file id"),
+            checksum(&truncated_path).expect("checksum"),
+        );
+        fs::write(dir.join(JOURNAL), journal).expect("journal");
+        fs::write(&truncated_path, b"trunc").expect("truncated file");
+        fs::write(dir.join("volume"), b"").expect("truncated volume");
+        let temp = dir.join(format!("{TEMP_PREFIX}0-volume"));
+        fs::write(&temp, b"2").expect("temporary file");
+
+        let reopened = Cache::new(None, Some(&dir), Some(&dir), Some(1000)).expect("reopened");
+        let cached = (
+            reopened.is_file_cached(intact),
+            reopened.is_file_cached(truncated),
+        );
+        let volume = reopened.volume();
+        let cleaned_up = !temp.exists() && !dir.join(JOURNAL).exists();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(cached, (true, false));
+        assert_eq!(volume, None);
+        assert!(cleaned_up);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let root = std::env::temp_dir().join(format!("librespot-cache-{}", std::process::id()));
+        let source_dir = root.join("source");
+        let target_dir = root.join("target");
+
+        let source = Cache::new(
+            Some(&source_dir),
+            Some(&source_dir),
+            Some(&source_dir),
+            None,
+        )
+        .expect("source cache");
+        let target = Cache::new(
+            Some(&target_dir),
+            Some(&target_dir),
+            Some(&target_dir),
+            None,
+        )
+        .expect("target cache");
+
+        let file = FileId([0xab; 20]);
+        let registration = DeviceRegistration {
+            device_id: "device".to_owned(),
+            name: "Kitchen".to_owned(),
+        };
+        source.save_volume(42);
+        source.save_device_registration(&registration);
+        source
+            .save_file(file, &mut &b"audio"[..])
+            .expect("saved file");
+
+        let mut archive = Vec::new();
+        let exported = source.export(&mut archive).expect("exported cache");
+        let imported = target.import(&archive[..]).expect("imported cache");
+
+        let mut contents = String::new();
+        target
+            .file(file)
+            .expect("imported file")
+            .read_to_string(&mut contents)
+            .expect("read imported file");
+        let volume = target.volume();
+        let saved_registration = source.device_registration();
+        let imported_registration = target.device_registration();
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(exported, imported);
+        assert_eq!(imported.audio_files, 1);
+        assert_eq!(imported.audio_bytes, 5);
+        assert_eq!(contents, "audio");
+        assert_eq!(volume, Some(42));
+        // the identity of a device is not copied to others
+        assert_eq!(saved_registration, Some(registration));
+        assert_eq!(imported_registration, None);
+    }
 }
@@ -8,12 +8,18 @@ use thiserror::Error;
 use time::Duration;
 use url::Url;
 
-use super::{date::Date, Error, FileId, Session};
+use super::{
+    date::Date,
+    error::{ErrorCode, ErrorKind, Recovery},
+    Error, FileId, Session,
+};
 
 use librespot_protocol as protocol;
 use protocol::storage_resolve::storage_resolve_response::Result as StorageResolveResponse_Result;
 use protocol::storage_resolve::StorageResolveResponse as CdnUrlMessage;
 
+/// A URL and when it expires, by the system clock once resolved with
+/// [`CdnUrl::resolve_audio`].
 #[derive(Debug, Clone)]
 pub struct MaybeExpiringUrl(pub String, pub Option<Date>);
 
@@ -48,8 +54,28 @@ pub enum CdnUrlError {
 impl From<CdnUrlError> for Error {
     fn from(err: CdnUrlError) -> Self {
         match err {
-            CdnUrlError::Expired => Error::deadline_exceeded(err),
-            CdnUrlError::Storage | CdnUrlError::Unresolved => Error::unavailable(err),
+            CdnUrlError::Expired => Error::with_code(ErrorKind::DeadlineExceeded, err),
+            CdnUrlError::Storage | CdnUrlError::Unresolved => {
+                Error::with_code(ErrorKind::Unavailable, err)
+            }
+        }
+    }
+}
+
+impl ErrorCode for CdnUrlError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Expired => "cdn_url.expired",
+            Self::Storage => "cdn_url.storage",
+            Self::Unresolved => "cdn_url.unresolved",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            // resolving the URLs again doesn't make them usable
+            (Self::Storage, ErrorKind::Unavailable) => Some(Recovery::GiveUp),
+            _ => None,
         }
     }
 }
@@ -72,7 +98,15 @@ impl CdnUrl {
         let file_id = self.file_id;
         let response = session.spclient().get_audio_storage(&file_id).await?;
         let msg = CdnUrlMessage::parse_from_bytes(&response)?;
-        let urls = MaybeExpiringUrls::try_from(msg)?;
+        let mut urls = MaybeExpiringUrls::try_from(msg)?;
+
+        // URLs expire by the clock of the server, which the system clock may be off from
+        let time_delta = Duration::seconds(session.time_delta());
+        for MaybeExpiringUrl(_, expiry) in urls.iter_mut() {
+            if let Some(expiry) = expiry {
+                *expiry = Date(expiry.saturating_sub(time_delta));
+            }
+        }
 
         let cdn_url = Self { file_id, urls };
 
@@ -81,6 +115,10 @@ impl CdnUrl {
         Ok(cdn_url)
     }
 
+    pub fn urls(&self) -> &MaybeExpiringUrls {
+        &self.urls
+    }
+
     pub fn try_get_url(&self) -> Result<&str, Error> {
         if self.urls.is_empty() {
             return Err(CdnUrlError::Unresolved.into());
@@ -209,4 +247,19 @@ mod test {
             timestamp_margin.whole_milliseconds()
         );
     }
+
+    #[test]
+    fn classifies_errors() {
+        let err = Error::from(CdnUrlError::Expired);
+        assert_eq!(err.code(), "cdn_url.expired");
+        assert!(err.is_retryable());
+
+        let err = Error::from(CdnUrlError::Unresolved);
+        assert!(err.is_retryable());
+
+        // unlike other unavailable errors
+        let err = Error::from(CdnUrlError::Storage);
+        assert_eq!(err.kind, ErrorKind::Unavailable);
+        assert_eq!(err.recovery(), Recovery::GiveUp);
+    }
 }
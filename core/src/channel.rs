@@ -14,7 +14,12 @@ use num_traits::FromPrimitive;
 use thiserror::Error;
 use tokio::sync::mpsc;
 
-use crate::{packet::PacketType, util::SeqGenerator, Error};
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    packet::PacketType,
+    util::SeqGenerator,
+    Error,
+};
 
 component! {
     ChannelManager : ChannelManagerInner {
@@ -34,7 +39,13 @@ pub struct ChannelError;
 
 impl From<ChannelError> for Error {
     fn from(err: ChannelError) -> Self {
-        Error::aborted(err)
+        Error::with_code(ErrorKind::Aborted, err)
+    }
+}
+
+impl ErrorCode for ChannelError {
+    fn code(&self) -> &'static str {
+        "channel.error"
     }
 }
 
@@ -106,10 +117,15 @@ impl ChannelManager {
             inner.download_measurement_bytes += data.len();
 
             if let Entry::Occupied(entry) = inner.channels.entry(id) {
-                entry
-                    .get()
-                    .send((cmd as u8, data))
-                    .map_err(|_| ChannelError)?;
+                // An empty packet or an error ends the channel.
+                let is_last = data.is_empty() || matches!(cmd, PacketType::ChannelError);
+                if entry.get().send((cmd as u8, data)).is_err() {
+                    // The channel was dropped before it ended.
+                    entry.remove();
+                    return Err(ChannelError.into());
+                } else if is_last {
+                    entry.remove();
+                }
             }
 
             Ok(())
@@ -120,6 +136,11 @@ impl ChannelManager {
         self.lock(|inner| inner.download_rate_estimate)
     }
 
+    /// Ends the channels, which won't receive any more data after the connection was lost.
+    pub(crate) fn fail_pending(&self) {
+        self.lock(|inner| inner.channels.clear());
+    }
+
     pub(crate) fn shutdown(&self) {
         self.lock(|inner| {
             inner.invalid = true;
@@ -227,3 +248,58 @@ impl Stream for ChannelHeaders {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::SessionConfig, Session};
+
+    fn packet(id: u16, data: &[u8]) -> Bytes {
+        [&id.to_be_bytes()[..], data].concat().into()
+    }
+
+    fn is_open(session: &Session, id: u16) -> bool {
+        session
+            .channel()
+            .lock(|inner| inner.channels.contains_key(&id))
+    }
+
+    #[tokio::test]
+    async fn ended_channels_are_removed() {
+        let session = Session::new(SessionConfig::default(), None);
+        let channels = session.channel();
+
+        let (data, _channel) = channels.allocate();
+        channels
+            .dispatch(PacketType::StreamChunkRes, packet(data, b"data"))
+            .unwrap();
+        assert!(is_open(&session, data));
+        channels
+            .dispatch(PacketType::StreamChunkRes, packet(data, b""))
+            .unwrap();
+        assert!(!is_open(&session, data));
+
+        let (error, _channel) = channels.allocate();
+        channels
+            .dispatch(PacketType::ChannelError, packet(error, &[0, 1]))
+            .unwrap();
+        assert!(!is_open(&session, error));
+    }
+
+    #[tokio::test]
+    async fn dropped_channels_are_removed() {
+        let session = Session::new(SessionConfig::default(), None);
+        let channels = session.channel();
+
+        let (id, channel) = channels.allocate();
+        drop(channel);
+        assert!(channels
+            .dispatch(PacketType::StreamChunkRes, packet(id, b"data"))
+            .is_err());
+        assert!(!is_open(&session, id));
+        // data that arrives afterwards is dropped
+        assert!(channels
+            .dispatch(PacketType::StreamChunkRes, packet(id, b"data"))
+            .is_ok());
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    env::consts::OS,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use hyper::{
+    header::{HeaderName, HeaderValue, ACCEPT},
+    Body, Method, Request,
+};
+use protobuf::{Enum, Message};
+use sysinfo::{System, SystemExt};
+use tokio::sync::Mutex;
+
+use crate::{
+    config::SessionConfig,
+    hashcash,
+    protocol::clienttoken_http::{
+        ChallengeAnswer, ChallengeType, ClientTokenRequest, ClientTokenRequestType,
+        ClientTokenResponse, ClientTokenResponseType,
+    },
+    token::Token,
+    version::spotify_version,
+    Error,
+};
+
+component! {
+    ClientTokenProvider : ClientTokenProviderInner {
+        token: Option<Token> = None,
+        // held while requesting a token, so that concurrent requests share one
+        requesting: Arc<Mutex<()>> = Arc::new(Mutex::new(())),
+    }
+}
+
+/// The header that carries the client token.
+#[allow(clippy::declare_interior_mutable_const)]
+pub const CLIENT_TOKEN: HeaderName = HeaderName::from_static("client-token");
+
+impl ClientTokenProvider {
+    fn cached_token(&self) -> Option<String> {
+        self.lock(|inner| {
+            if inner.token.as_ref().map_or(false, Token::is_expired) {
+                inner.token = None;
+            }
+            inner.token.as_ref().map(|token| token.access_token.clone())
+        })
+    }
+
+    /// Returns the client token, requesting a new one when there is none yet or when it is
+    /// due for a refresh.
+    pub async fn get_token(&self) -> Result<String, Error> {
+        if let Some(token) = self.cached_token() {
+            return Ok(token);
+        }
+
+        let requesting = self.lock(|inner| inner.requesting.clone());
+        let _requesting = requesting.lock().await;
+
+        // Another caller may have requested it in the meantime.
+        if let Some(token) = self.cached_token() {
+            return Ok(token);
+        }
+
+        debug!("Client token unavailable or expired, requesting new token.");
+        let token = self.request_token().await?;
+        let access_token = token.access_token.clone();
+        self.lock(|inner| inner.token = Some(token));
+
+        Ok(access_token)
+    }
+
+    /// Drops the client token, e.g. because it was rejected, so that the next call to
+    /// [`get_token`](Self::get_token) requests a new one.
+    pub fn invalidate(&self) {
+        self.lock(|inner| inner.token = None);
+    }
+
+    async fn client_token_request<M: Message>(&self, message: &M) -> Result<Bytes, Error> {
+        let body = message.write_to_bytes()?;
+
+        let request = Request::builder()
+            .method(&Method::POST)
+            .uri("https://clienttoken.spotify.com/v1/clienttoken")
+            .header(ACCEPT, HeaderValue::from_static("application/x-protobuf"))
+            .body(Body::from(body))?;
+
+        self.session().http_client().request_body(request).await
+    }
+
+    async fn request_token(&self) -> Result<Token, Error> {
+        let mut request = ClientTokenRequest::new();
+        request.request_type = ClientTokenRequestType::REQUEST_CLIENT_DATA_REQUEST.into();
+
+        let client_data = request.mut_client_data();
+
+        client_data.client_version = spotify_version();
+
+        // Current state of affairs: keymaster ID works on all tested platforms, but may be phased out,
+        // so it seems a good idea to mimick the real clients. `self.session().client_id()` returns the
+        // ID of the client that last connected, but requesting a client token with this ID only works
+        // on macOS and Windows. On Android and iOS we can send a platform-specific client ID and are
+        // then presented with a hash cash challenge. On Linux, we have to pass the old keymaster ID.
+        // We delegate most of this logic to `SessionConfig`.
+        let client_id = match OS {
+            "macos" | "windows" => self.session().client_id(),
+            _ => SessionConfig::default().client_id,
+        };
+        client_data.client_id = client_id;
+
+        let connectivity_data = client_data.mut_connectivity_sdk_data();
+        connectivity_data.device_id = self.session().device_id().to_string();
+
+        let platform_data = connectivity_data
+            .platform_specific_data
+            .mut_or_insert_default();
+
+        let sys = System::new();
+        let os_version = sys.os_version().unwrap_or_else(|| String::from("0"));
+        let kernel_version = sys.kernel_version().unwrap_or_else(|| String::from("0"));
+
+        match OS {
+            "windows" => {
+                let os_version = os_version.parse::<f32>().unwrap_or(10.) as i32;
+                let kernel_version = kernel_version.parse::<i32>().unwrap_or(21370);
+
+                let (pe, image_file) = match std::env::consts::ARCH {
+                    "arm" => (448, 452),
+                    "aarch64" => (43620, 452),
+                    "x86_64" => (34404, 34404),
+                    _ => (332, 332), // x86
+                };
+
+                let windows_data = platform_data.mut_desktop_windows();
+                windows_data.os_version = os_version;
+                windows_data.os_build = kernel_version;
+                windows_data.platform_id = 2;
+                windows_data.unknown_value_6 = 9;
+                windows_data.image_file_machine = image_file;
+                windows_data.pe_machine = pe;
+                windows_data.unknown_value_10 = true;
+            }
+            "ios" => {
+                let ios_data = platform_data.mut_ios();
+                ios_data.user_interface_idiom = 0;
+                ios_data.target_iphone_simulator = false;
+                ios_data.hw_machine = "iPhone14,5".to_string();
+                ios_data.system_version = os_version;
+            }
+            "android" => {
+                let android_data = platform_data.mut_android();
+                android_data.android_version = os_version;
+                android_data.api_version = 31;
+                android_data.device_name = "Pixel".to_owned();
+                android_data.model_str = "GF5KQ".to_owned();
+                android_data.vendor = "Google".to_owned();
+            }
+            "macos" => {
+                let macos_data = platform_data.mut_desktop_macos();
+                macos_data.system_version = os_version;
+                macos_data.hw_model = "iMac21,1".to_string();
+                macos_data.compiled_cpu_type = std::env::consts::ARCH.to_string();
+            }
+            _ => {
+                let linux_data = platform_data.mut_desktop_linux();
+                linux_data.system_name = "Linux".to_string();
+                linux_data.system_release = kernel_version;
+                linux_data.system_version = os_version;
+                linux_data.hardware = std::env::consts::ARCH.to_string();
+            }
+        }
+
+        let mut response = self.client_token_request(&request).await?;
+        let mut count = 0;
+        const MAX_TRIES: u8 = 3;
+
+        let token_response = loop {
+            count += 1;
+
+            let message = ClientTokenResponse::parse_from_bytes(&response)?;
+
+            match ClientTokenResponseType::from_i32(message.response_type.value()) {
+                // depending on the platform, you're either given a token immediately
+                // or are presented a hash cash challenge to solve first
+                Some(ClientTokenResponseType::RESPONSE_GRANTED_TOKEN_RESPONSE) => break message,
+                Some(ClientTokenResponseType::RESPONSE_CHALLENGES_RESPONSE) => {
+                    debug!("Received a hash cash challenge, solving...");
+
+                    let challenges = message.challenges().clone();
+                    let state = challenges.state;
+                    if let Some(challenge) = challenges.challenges.first() {
+                        let hash_cash_challenge = challenge.evaluate_hashcash_parameters();
+
+                        let prefix = hex::decode(&hash_cash_challenge.prefix).map_err(|e| {
+                            Error::failed_precondition(format!(
+                                "Unable to decode hash cash challenge: {e}"
+                            ))
+                        })?;
+                        let length = hash_cash_challenge.length;
+
+                        let budget = self.session().config().challenge_budget;
+                        let answer = hashcash::solve(&[], &prefix, length, budget).await;
+
+                        match answer {
+                            Ok(solution) => {
+                                // the suffix must be in uppercase
+                                let suffix = hex::encode(solution.suffix).to_uppercase();
+
+                                let mut answer_message = ClientTokenRequest::new();
+                                answer_message.request_type =
+                                    ClientTokenRequestType::REQUEST_CHALLENGE_ANSWERS_REQUEST
+                                        .into();
+
+                                let challenge_answers = answer_message.mut_challenge_answers();
+
+                                let mut challenge_answer = ChallengeAnswer::new();
+                                challenge_answer.mut_hash_cash().suffix = suffix.to_string();
+                                challenge_answer.ChallengeType =
+                                    ChallengeType::CHALLENGE_HASH_CASH.into();
+
+                                challenge_answers.state = state.to_string();
+                                challenge_answers.answers.push(challenge_answer);
+
+                                trace!("Answering hash cash challenge");
+                                match self.client_token_request(&answer_message).await {
+                                    Ok(token) => {
+                                        response = token;
+                                        continue;
+                                    }
+                                    Err(e) => {
+                                        trace!(
+                                            "Answer not accepted {}/{}: {}",
+                                            count,
+                                            MAX_TRIES,
+                                            e
+                                        );
+                                    }
+                                }
+                            }
+                            Err(e) => trace!(
+                                "Unable to solve hash cash challenge {}/{}: {}",
+                                count,
+                                MAX_TRIES,
+                                e
+                            ),
+                        }
+
+                        if count < MAX_TRIES {
+                            response = self.client_token_request(&request).await?;
+                        } else {
+                            return Err(Error::failed_precondition(format!(
+                                "Unable to solve any of {MAX_TRIES} hash cash challenges"
+                            )));
+                        }
+                    } else {
+                        return Err(Error::failed_precondition("No challenges found"));
+                    }
+                }
+
+                Some(unknown) => {
+                    return Err(Error::unimplemented(format!(
+                        "Unknown client token response type: {unknown:?}"
+                    )))
+                }
+                None => return Err(Error::failed_precondition("No client token response type")),
+            }
+        };
+
+        let granted_token = token_response.granted_token();
+        trace!(
+            "Got client token, refresh after {} s",
+            granted_token.refresh_after_seconds
+        );
+
+        Ok(Token {
+            access_token: granted_token.token.to_owned(),
+            // Refresh when asked to, rather than when it expires.
+            expires_in: Duration::from_secs(
+                granted_token
+                    .refresh_after_seconds
+                    .try_into()
+                    .unwrap_or(7200),
+            ),
+            token_type: "client-token".to_string(),
+            scopes: granted_token
+                .domains
+                .iter()
+                .map(|d| d.domain.clone())
+                .collect(),
+            timestamp: Instant::now(),
+        })
+    }
+}
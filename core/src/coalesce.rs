@@ -0,0 +1,204 @@
+//! Shares the response of a request among everyone who makes the same request while it is in
+//! flight, e.g. when the player prefetches the metadata of a track that is also being shown.
+//! Used for Mercury and spclient `GET` requests.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::{
+    future::{BoxFuture, Shared, WeakShared},
+    FutureExt, TryFutureExt,
+};
+use parking_lot::Mutex;
+
+use crate::{
+    error::{ErrorCode, ErrorKind, Recovery},
+    Error,
+};
+
+type Request<T> = BoxFuture<'static, Result<T, Arc<Error>>>;
+type SharedRequest<T> = Shared<Request<T>>;
+
+/// The requests in flight by key. Only weak references are kept, so that a request is
+/// cancelled when everyone waiting for it gave up.
+pub(crate) struct InFlight<K, T: Clone>(Arc<Mutex<HashMap<K, WeakShared<Request<T>>>>>);
+
+impl<K, T: Clone> Clone for InFlight<K, T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<K, T: Clone> Default for InFlight<K, T> {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+}
+
+impl<K, T> InFlight<K, T>
+where
+    K: Eq + Hash,
+    T: Clone + Send + Sync + 'static,
+{
+    /// Joins the request for `key` that is in flight, or starts it with `start`. An error
+    /// of `start` is returned to the caller only, and nothing is shared.
+    pub fn join_or_start<F>(
+        &self,
+        key: K,
+        start: impl FnOnce() -> Result<F, Error>,
+    ) -> Result<Coalesced<T>, Error>
+    where
+        F: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        let mut requests = self.0.lock();
+        // Completed requests are dropped too, as their response may be outdated.
+        requests.retain(|_, request| {
+            request
+                .upgrade()
+                .map_or(false, |request| request.peek().is_none())
+        });
+
+        if let Some(request) = requests.get(&key).and_then(WeakShared::upgrade) {
+            trace!("Joining a request in flight");
+            return Ok(Coalesced(request));
+        }
+
+        let request = start()?.map_err(Arc::new).boxed().shared();
+        if let Some(weak) = request.downgrade() {
+            requests.insert(key, weak);
+        }
+        Ok(Coalesced(request))
+    }
+}
+
+/// Resolves to the response of a request that may be shared with other callers. Dropping it
+/// cancels the request if no one else waits for it.
+#[must_use = "futures do nothing unless polled"]
+pub struct Coalesced<T: Clone>(SharedRequest<T>);
+
+impl<T: Clone> Future for Coalesced<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0
+            .poll_unpin(cx)
+            .map_err(|error| Error::with_code(error.kind, SharedError(error)))
+    }
+}
+
+// The error of a shared request, which every caller gets a copy of.
+#[derive(Debug)]
+struct SharedError(Arc<Error>);
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.error.fmt(f)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.error.source()
+    }
+}
+
+// Keeps the code and recovery of the error of the request.
+impl ErrorCode for SharedError {
+    fn code(&self) -> &'static str {
+        self.0.code()
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        (kind == self.0.kind).then(|| self.0.recovery())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    fn start(
+        started: &Arc<AtomicUsize>,
+        response: oneshot::Receiver<u32>,
+    ) -> impl FnOnce() -> Result<BoxFuture<'static, Result<u32, Error>>, Error> {
+        let started = started.clone();
+        move || {
+            started.fetch_add(1, Ordering::SeqCst);
+            Ok(async move { response.await.map_err(Error::from) }.boxed())
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_response_in_flight() {
+        let in_flight = InFlight::default();
+        let started = Arc::new(AtomicUsize::new(0));
+
+        let (tx, rx) = oneshot::channel();
+        let first = in_flight.join_or_start("uri", start(&started, rx)).unwrap();
+        let (_unused_tx, unused_rx) = oneshot::channel();
+        let second = in_flight
+            .join_or_start("uri", start(&started, unused_rx))
+            .unwrap();
+
+        tx.send(7).unwrap();
+        assert_eq!(first.await.unwrap(), 7);
+        assert_eq!(second.await.unwrap(), 7);
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+
+        // a completed request isn't joined
+        let (tx, rx) = oneshot::channel();
+        let third = in_flight.join_or_start("uri", start(&started, rx)).unwrap();
+        tx.send(8).unwrap();
+        assert_eq!(third.await.unwrap(), 8);
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn restarts_abandoned_request() {
+        let in_flight = InFlight::default();
+        let started = Arc::new(AtomicUsize::new(0));
+
+        let (_tx, rx) = oneshot::channel();
+        drop(in_flight.join_or_start("uri", start(&started, rx)).unwrap());
+
+        let (tx, rx) = oneshot::channel();
+        let request = in_flight.join_or_start("uri", start(&started, rx)).unwrap();
+        tx.send(9).unwrap();
+        assert_eq!(request.await.unwrap(), 9);
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn shares_errors() {
+        let in_flight: InFlight<_, u32> = InFlight::default();
+        let started = Arc::new(AtomicUsize::new(0));
+
+        let (tx, rx) = oneshot::channel();
+        let first = in_flight.join_or_start("uri", start(&started, rx)).unwrap();
+        let second = in_flight
+            .join_or_start(
+                "uri",
+                || -> Result<BoxFuture<'static, Result<u32, Error>>, Error> {
+                    unreachable!("the request is in flight")
+                },
+            )
+            .unwrap();
+
+        drop(tx);
+        let (first, second) = (first.await.unwrap_err(), second.await.unwrap_err());
+        assert_eq!(first.kind, second.kind);
+        assert_eq!(first.code(), second.code());
+        assert_eq!(first.to_string(), second.to_string());
+    }
+}
@@ -0,0 +1,593 @@
+//! A local HTTP endpoint that hands out access tokens with limited scopes to a paired
+//! companion app, so that e.g. a web frontend can fetch artwork and search the catalogue
+//! without knowing the user's credentials.
+//!
+//! Pairing needs the consent of the user: the companion app has to send the current pairing
+//! code, which the application shows to the user from [`CompanionServer::pairing_codes`], and
+//! receives a secret that it can request tokens with from then on. The codes are never logged.
+//! Pairing is locked for a while after too many invalid codes, and browsers may only call the
+//! endpoint from the origins in [`CompanionConfig::allowed_origins`].
+//!
+//! ```text
+//! POST /pair    code=<pairing code>                           -> {"secret": "..."}
+//! GET  /token   Authorization: Bearer <secret> [?scope=a,b]   -> {"accessToken": "...", ...}
+//! ```
+
+use std::{
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::{FutureExt, TryFutureExt};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use parking_lot::Mutex;
+use rand::Rng;
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::{oneshot, watch};
+
+use crate::{
+    error::{ErrorCode, ErrorKind, Recovery},
+    Error, Session,
+};
+
+/// Scopes that are enough to show what is playing and the user's library.
+pub const DEFAULT_COMPANION_SCOPES: &str =
+    "user-read-private,user-read-playback-state,user-read-currently-playing,user-library-read";
+
+// Limits guessing the six digit pairing code: after this many invalid codes, pairing is
+// locked for a while, twice as long after each lockout up to the maximum.
+const MAX_PAIRING_ATTEMPTS: usize = 5;
+const PAIRING_LOCKOUT: Duration = Duration::from_secs(60);
+const MAX_PAIRING_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug)]
+pub struct CompanionConfig {
+    /// The address to listen on. Only the local host can connect by default.
+    pub address: SocketAddr,
+    /// The scopes a companion app may request tokens for.
+    pub scopes: Vec<String>,
+    /// The origins of web pages that may call the endpoint, like `http://localhost:8080`.
+    /// Requests from other web pages are rejected, while apps that aren't run in a browser
+    /// don't send an origin.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CompanionConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            scopes: DEFAULT_COMPANION_SCOPES
+                .split(',')
+                .map(ToOwned::to_owned)
+                .collect(),
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CompanionError {
+    #[error("invalid pairing code")]
+    InvalidPairingCode,
+    #[error("pairing is locked after too many invalid pairing codes")]
+    PairingLocked,
+    #[error("origin {0} is not allowed")]
+    OriginNotAllowed(String),
+    #[error("not paired")]
+    NotPaired,
+    #[error("scope {0} is not allowed for companion apps")]
+    ScopeNotAllowed(String),
+    #[error("no session")]
+    NoSession,
+}
+
+impl From<CompanionError> for Error {
+    fn from(err: CompanionError) -> Self {
+        use CompanionError::*;
+        match err {
+            InvalidPairingCode | NotPaired => Error::with_code(ErrorKind::Unauthenticated, err),
+            PairingLocked => Error::with_code(ErrorKind::ResourceExhausted, err),
+            ScopeNotAllowed(_) | OriginNotAllowed(_) => {
+                Error::with_code(ErrorKind::PermissionDenied, err)
+            }
+            NoSession => Error::with_code(ErrorKind::Unavailable, err),
+        }
+    }
+}
+
+impl ErrorCode for CompanionError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidPairingCode => "companion.invalid_pairing_code",
+            Self::PairingLocked => "companion.pairing_locked",
+            Self::OriginNotAllowed(_) => "companion.origin_not_allowed",
+            Self::NotPaired => "companion.not_paired",
+            Self::ScopeNotAllowed(_) => "companion.scope_not_allowed",
+            Self::NoSession => "companion.no_session",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            // pairing again doesn't help with a wrong code
+            (Self::InvalidPairingCode, ErrorKind::Unauthenticated) => Some(Recovery::GiveUp),
+            _ => None,
+        }
+    }
+}
+
+impl CompanionError {
+    fn status(&self) -> StatusCode {
+        use CompanionError::*;
+        match self {
+            InvalidPairingCode | NotPaired => StatusCode::UNAUTHORIZED,
+            PairingLocked => StatusCode::TOO_MANY_REQUESTS,
+            ScopeNotAllowed(_) | OriginNotAllowed(_) => StatusCode::FORBIDDEN,
+            NoSession => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+fn new_pairing_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+// Compares in constant time, so that the secrets can't be guessed byte by byte.
+fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+struct CompanionState {
+    session: Option<Session>,
+    pairing_code: String,
+    failed_attempts: usize,
+    // The number of lockouts since the last successful pairing, and when the current one ends.
+    // Unlike the failed attempts, they are kept when the pairing code changes.
+    lockouts: u32,
+    locked_until: Option<Instant>,
+    secrets: Vec<String>,
+}
+
+struct RequestHandler {
+    scopes: Vec<String>,
+    allowed_origins: Vec<String>,
+    state: Mutex<CompanionState>,
+    pairing_codes: watch::Sender<String>,
+}
+
+impl RequestHandler {
+    fn new(config: CompanionConfig) -> Self {
+        let pairing_code = new_pairing_code();
+        let (pairing_codes, _) = watch::channel(pairing_code.clone());
+
+        Self {
+            scopes: config.scopes,
+            allowed_origins: config.allowed_origins,
+            state: Mutex::new(CompanionState {
+                session: None,
+                pairing_code,
+                failed_attempts: 0,
+                lockouts: 0,
+                locked_until: None,
+                secrets: Vec::new(),
+            }),
+            pairing_codes,
+        }
+    }
+
+    fn change_pairing_code(&self, state: &mut CompanionState) {
+        state.pairing_code = new_pairing_code();
+        state.failed_attempts = 0;
+        self.pairing_codes.send_replace(state.pairing_code.clone());
+    }
+
+    fn pair(&self, code: Option<&str>, now: Instant) -> Result<String, CompanionError> {
+        let mut state = self.state.lock();
+
+        if matches!(state.locked_until, Some(until) if now < until) {
+            return Err(CompanionError::PairingLocked);
+        }
+
+        if !code.map_or(false, |code| secrets_match(code, &state.pairing_code)) {
+            state.failed_attempts += 1;
+            if state.failed_attempts >= MAX_PAIRING_ATTEMPTS {
+                let lockout = PAIRING_LOCKOUT
+                    .saturating_mul(2u32.saturating_pow(state.lockouts))
+                    .min(MAX_PAIRING_LOCKOUT);
+                warn!(
+                    "Too many invalid companion app pairing codes, pairing is locked for {} s",
+                    lockout.as_secs()
+                );
+                state.lockouts += 1;
+                state.locked_until = Some(now + lockout);
+                self.change_pairing_code(&mut state);
+            }
+            return Err(CompanionError::InvalidPairingCode);
+        }
+
+        // Every pairing code can be used only once.
+        let secret = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        state.secrets.push(secret.clone());
+        state.lockouts = 0;
+        state.locked_until = None;
+        self.change_pairing_code(&mut state);
+        info!("Paired a companion app");
+
+        Ok(secret)
+    }
+
+    // Browsers send the origin of the web page that makes a request. Only the allowed origins
+    // may call the endpoint, so that no other web page can pair or fetch tokens.
+    fn check_origin(&self, request: &Request<Body>) -> Result<Option<String>, CompanionError> {
+        let origin = match request.headers().get(header::ORIGIN) {
+            Some(origin) => String::from_utf8_lossy(origin.as_bytes()).into_owned(),
+            None => return Ok(None),
+        };
+
+        if self.allowed_origins.contains(&origin) {
+            Ok(Some(origin))
+        } else {
+            Err(CompanionError::OriginNotAllowed(origin))
+        }
+    }
+
+    fn authorize(&self, request: &Request<Body>) -> Result<Session, CompanionError> {
+        let secret = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(CompanionError::NotPaired)?;
+
+        let state = self.state.lock();
+        if !state
+            .secrets
+            .iter()
+            .any(|known| secrets_match(known, secret))
+        {
+            return Err(CompanionError::NotPaired);
+        }
+
+        state.session.clone().ok_or(CompanionError::NoSession)
+    }
+
+    fn scopes(&self, requested: Option<&str>) -> Result<String, CompanionError> {
+        match requested {
+            Some(requested) => {
+                for scope in requested.split(',') {
+                    if !self.scopes.iter().any(|allowed| allowed == scope) {
+                        return Err(CompanionError::ScopeNotAllowed(scope.to_owned()));
+                    }
+                }
+                Ok(requested.to_owned())
+            }
+            None => Ok(self.scopes.join(",")),
+        }
+    }
+
+    async fn handle_token(&self, request: &Request<Body>) -> Result<Response<Body>, Error> {
+        let session = self.authorize(request)?;
+
+        let requested = request.uri().query().and_then(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "scope")
+                .map(|(_, value)| value.into_owned())
+        });
+        let scopes = self.scopes(requested.as_deref())?;
+
+        let token = session.token_provider().get_token(&scopes).await?;
+        let expires_in = token
+            .expires_in
+            .saturating_sub(Instant::now().saturating_duration_since(token.timestamp));
+
+        let body = json!({
+            "accessToken": token.access_token,
+            "expiresIn": expires_in.as_secs(),
+            "tokenType": token.token_type,
+            "scope": token.scopes,
+        });
+        Ok(Response::new(Body::from(body.to_string())))
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+        let method = request.method().clone();
+        let path = request.uri().path().to_owned();
+
+        match (method, path.as_str()) {
+            (Method::OPTIONS, _) => Ok(Response::default()),
+            (Method::POST, "/pair") => {
+                let body = hyper::body::to_bytes(request.into_body()).await?;
+                let code = form_urlencoded::parse(&body)
+                    .find(|(key, _)| key == "code")
+                    .map(|(_, value)| value);
+                let secret = self.pair(code.as_deref(), Instant::now())?;
+
+                let body = json!({ "secret": secret });
+                Ok(Response::new(Body::from(body.to_string())))
+            }
+            (Method::GET, "/token") => self.handle_token(&request).await,
+            _ => {
+                let mut response = Response::default();
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                Ok(response)
+            }
+        }
+    }
+
+    async fn respond(self: Arc<Self>, request: Request<Body>) -> Response<Body> {
+        let (origin, result) = match self.check_origin(&request) {
+            Ok(origin) => (origin, self.handle(request).await),
+            Err(e) => (None, Err(e.into())),
+        };
+
+        let mut response = result.unwrap_or_else(|e| {
+            let status = e
+                .error
+                .downcast_ref::<CompanionError>()
+                .map_or(StatusCode::BAD_GATEWAY, CompanionError::status);
+            debug!("Companion app request failed: {}", e);
+
+            let mut response =
+                Response::new(Body::from(json!({ "error": e.to_string() }).to_string()));
+            *response.status_mut() = status;
+            response
+        });
+
+        // The companion app is usually served from another origin, which browsers only let
+        // call the endpoint if it is allowed.
+        let origin = match origin.map(header::HeaderValue::try_from) {
+            Some(Ok(origin)) => origin,
+            _ => return response,
+        };
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(header::VARY, header::HeaderValue::from_static("Origin"));
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            header::HeaderValue::from_static("Authorization, Content-Type"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header::HeaderValue::from_static("GET, POST"),
+        );
+        response
+    }
+}
+
+/// Serves access tokens to paired companion apps until it is dropped.
+pub struct CompanionServer {
+    handler: Arc<RequestHandler>,
+    address: SocketAddr,
+    _close_tx: oneshot::Sender<Infallible>,
+}
+
+impl CompanionServer {
+    pub fn launch(config: CompanionConfig) -> Result<Self, Error> {
+        let address = config.address;
+        let handler = Arc::new(RequestHandler::new(config));
+
+        let (close_tx, close_rx) = oneshot::channel();
+
+        let service_handler = handler.clone();
+        let make_service = make_service_fn(move |_| {
+            let handler = service_handler.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request| {
+                    handler.clone().respond(request).map(Ok::<_, Infallible>)
+                }))
+            }
+        });
+
+        let server = hyper::Server::try_bind(&address)?.serve(make_service);
+        let address = server.local_addr();
+        info!("Companion app endpoint listening on {}", address);
+
+        tokio::spawn(
+            server
+                .with_graceful_shutdown(close_rx.map(|_| ()))
+                .map_err(|e| warn!("Companion app endpoint failed: {}", e)),
+        );
+
+        Ok(Self {
+            handler,
+            address,
+            _close_tx: close_tx,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The code a companion app has to send to pair. It changes after every pairing, and
+    /// after too many invalid codes.
+    pub fn pairing_code(&self) -> String {
+        self.handler.state.lock().pairing_code.clone()
+    }
+
+    /// The current pairing code and its changes, to show them to the user.
+    pub fn pairing_codes(&self) -> watch::Receiver<String> {
+        self.handler.pairing_codes.subscribe()
+    }
+
+    /// Sets the session that tokens are requested from, e.g. after reconnecting.
+    pub fn set_session(&self, session: Session) {
+        self.handler.state.lock().session = Some(session);
+    }
+
+    /// Revokes the secrets of all paired companion apps.
+    pub fn unpair_all(&self) {
+        self.handler.state.lock().secrets.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn handler(allowed_origins: &[&str]) -> RequestHandler {
+        RequestHandler::new(CompanionConfig {
+            allowed_origins: allowed_origins.iter().map(|&o| o.to_owned()).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn wrong_code(handler: &RequestHandler) -> String {
+        let code = handler.state.lock().pairing_code.clone();
+        if code == "000000" {
+            "000001".to_owned()
+        } else {
+            "000000".to_owned()
+        }
+    }
+
+    fn fail(handler: &RequestHandler, attempts: usize, now: Instant) {
+        for _ in 0..attempts {
+            let code = wrong_code(handler);
+            assert!(matches!(
+                handler.pair(Some(&code), now),
+                Err(CompanionError::InvalidPairingCode)
+            ));
+        }
+    }
+
+    #[test]
+    fn pairs_once_per_code() {
+        let handler = handler(&[]);
+        let mut codes = handler.pairing_codes.subscribe();
+        let code = codes.borrow_and_update().clone();
+        let now = Instant::now();
+
+        let secret = handler.pair(Some(&code), now).unwrap();
+        assert_eq!(handler.state.lock().secrets, vec![secret]);
+
+        assert!(codes.has_changed().unwrap());
+        assert_ne!(*codes.borrow(), code);
+        assert!(handler.pair(Some(&code), now).is_err());
+        assert!(handler.pair(None, now).is_err());
+    }
+
+    #[test]
+    fn locks_pairing_after_too_many_invalid_codes() {
+        let handler = handler(&[]);
+        let now = Instant::now();
+
+        fail(&handler, MAX_PAIRING_ATTEMPTS - 1, now);
+        let code = handler.state.lock().pairing_code.clone();
+        fail(&handler, 1, now);
+
+        // The code has changed, and even the new one is refused until the lockout ends.
+        let new_code = handler.state.lock().pairing_code.clone();
+        assert_ne!(new_code, code);
+        assert!(matches!(
+            handler.pair(Some(&new_code), now + PAIRING_LOCKOUT / 2),
+            Err(CompanionError::PairingLocked)
+        ));
+        assert!(handler.pair(Some(&new_code), now + PAIRING_LOCKOUT).is_ok());
+    }
+
+    #[test]
+    fn lockouts_grow_until_pairing_succeeds() {
+        let handler = handler(&[]);
+        let mut now = Instant::now();
+
+        let mut expected = PAIRING_LOCKOUT;
+        for _ in 0..10 {
+            fail(&handler, MAX_PAIRING_ATTEMPTS, now);
+            assert_eq!(handler.state.lock().locked_until, Some(now + expected));
+            now += expected;
+            expected = (expected * 2).min(MAX_PAIRING_LOCKOUT);
+        }
+        assert_eq!(expected, MAX_PAIRING_LOCKOUT);
+
+        let code = handler.state.lock().pairing_code.clone();
+        handler.pair(Some(&code), now).unwrap();
+
+        fail(&handler, MAX_PAIRING_ATTEMPTS, now);
+        assert_eq!(
+            handler.state.lock().locked_until,
+            Some(now + PAIRING_LOCKOUT)
+        );
+    }
+
+    #[test]
+    fn authorizes_paired_secrets() {
+        let handler = handler(&[]);
+        let code = handler.state.lock().pairing_code.clone();
+        let secret = handler.pair(Some(&code), Instant::now()).unwrap();
+
+        let request = |secret: &str| {
+            Request::get("/token")
+                .header(header::AUTHORIZATION, format!("Bearer {}", secret))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // There is no session to request tokens from yet.
+        assert!(matches!(
+            handler.authorize(&request(&secret)),
+            Err(CompanionError::NoSession)
+        ));
+        assert!(matches!(
+            handler.authorize(&request("guess")),
+            Err(CompanionError::NotPaired)
+        ));
+
+        handler.state.lock().secrets.clear();
+        assert!(matches!(
+            handler.authorize(&request(&secret)),
+            Err(CompanionError::NotPaired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn restricts_origins() {
+        let handler = Arc::new(handler(&["http://localhost:8080"]));
+
+        let request = |origin: Option<&str>| {
+            let mut request = Request::options("/pair");
+            if let Some(origin) = origin {
+                request = request.header(header::ORIGIN, origin);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = handler.clone().respond(request(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let response = handler
+            .clone()
+            .respond(request(Some("http://localhost:8080")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:8080"
+        );
+
+        let response = handler
+            .clone()
+            .respond(request(Some("https://example.com")))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+}
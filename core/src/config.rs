@@ -1,7 +1,13 @@
-use std::{fmt, path::PathBuf, str::FromStr};
+use std::{fmt, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
+use rustls::ClientConfig;
 use url::Url;
 
+use crate::{
+    connection::resolver::{Resolver, SystemResolver},
+    state::StateStore,
+};
+
 pub(crate) const KEYMASTER_CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
 pub(crate) const ANDROID_CLIENT_ID: &str = "9a8d2f0ce77a4e248bb71fefcb557637";
 pub(crate) const IOS_CLIENT_ID: &str = "58bd3c95768941ea9eb4350aaa033eb3";
@@ -10,10 +16,33 @@ pub(crate) const IOS_CLIENT_ID: &str = "58bd3c95768941ea9eb4350aaa033eb3";
 pub struct SessionConfig {
     pub client_id: String,
     pub device_id: String,
+    /// Proxy for all connections: `http://` or `https://` for an HTTP proxy, `socks5://` or
+    /// `socks5h://` (resolving host names on the proxy) for a SOCKS5 proxy, optionally with
+    /// `user:password@` credentials.
     pub proxy: Option<Url>,
+    /// Credentials for the proxy, which take precedence over those in the proxy URL.
+    pub proxy_credentials: Option<ProxyCredentials>,
     pub ap_port: Option<u16>,
     pub tmp_dir: PathBuf,
     pub autoplay: Option<bool>,
+    pub tls: TlsConfig,
+    /// Reconnect with the same credentials when the connection to the access point is lost,
+    /// instead of invalidating the session.
+    pub auto_reconnect: bool,
+    pub access_points: AccessPointConfig,
+    pub happy_eyeballs: HappyEyeballsConfig,
+    pub keep_alive: KeepAliveConfig,
+    /// Resolves the host names of all connections, including those of the proxy.
+    pub resolver: Arc<dyn Resolver>,
+    /// Where the session, Spotify Connect and the player record the state of the device.
+    /// Sessions made from clones of this config share it, so it outlives reconnects.
+    pub state: StateStore,
+    /// Surfaces anomalies in what Spotify sends as warnings with their payload and errors,
+    /// instead of tolerating them, see [`anomaly`](crate::anomaly). Meant for development.
+    pub strict_protocol: bool,
+    /// How long solving a hash cash challenge of login5 or the client token service may take
+    /// before logging in fails. Slow devices may need more than the default.
+    pub challenge_budget: Duration,
 }
 
 impl Default for SessionConfig {
@@ -30,13 +59,170 @@ impl Default for SessionConfig {
             client_id,
             device_id,
             proxy: None,
+            proxy_credentials: None,
             ap_port: None,
             tmp_dir: std::env::temp_dir(),
             autoplay: None,
+            tls: TlsConfig::default(),
+            auto_reconnect: false,
+            access_points: AccessPointConfig::default(),
+            happy_eyeballs: HappyEyeballsConfig::default(),
+            keep_alive: KeepAliveConfig::default(),
+            resolver: Arc::new(SystemResolver),
+            state: StateStore::new(),
+            strict_protocol: false,
+            challenge_budget: Duration::from_secs(5),
         }
     }
 }
 
+/// How connections to hosts with several addresses are made, in the manner of Happy Eyeballs
+/// (RFC 8305): IPv6 and IPv4 addresses are tried alternately, and the next address is tried
+/// whenever the previous attempt didn't connect within the attempt delay, without abandoning
+/// it. This avoids waiting for the TCP timeout on networks where one address family is broken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HappyEyeballsConfig {
+    /// RFC 8305 recommends 250 ms.
+    pub attempt_delay: Duration,
+    /// How long an attempt may take before it fails, or `None` to leave it to the system.
+    pub connect_timeout: Option<Duration>,
+}
+
+impl Default for HappyEyeballsConfig {
+    fn default() -> Self {
+        Self {
+            attempt_delay: Duration::from_millis(250),
+            connect_timeout: None,
+        }
+    }
+}
+
+/// How the connection to the access point is kept alive, and when it is considered dead.
+///
+/// The access point sends a ping every 2 minutes, which is answered with a pong and
+/// acknowledged with a pong ack. Some NAT gateways drop idle connections sooner without
+/// closing them, so that the connection is only found dead when the next ping doesn't come.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepAliveConfig {
+    /// Sends TCP keep-alive probes after the connection was idle for this long, which keeps
+    /// NAT mappings open between pings, or `None` to leave it to the system.
+    pub interval: Option<Duration>,
+    /// How long to wait for the next ping, or the first one after connecting, before the
+    /// connection is considered dead.
+    pub ping_timeout: Duration,
+    /// How long to wait for a pong to be acknowledged before the connection is considered
+    /// dead, or `None` to not wait for acknowledgements.
+    pub pong_ack_timeout: Option<Duration>,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            // pings are sent every 2 minutes and a 5 second margin should be fine
+            ping_timeout: Duration::from_secs(125),
+            pong_ack_timeout: None,
+        }
+    }
+}
+
+/// Which access points are connected to, for the connection to Spotify (`accesspoint`), the
+/// dealer websocket (`dealer`) and the HTTP API (`spclient`).
+///
+/// By default they are resolved with apresolve, which may return access points that aren't
+/// reachable from some networks.
+#[derive(Clone, Debug, Default)]
+pub struct AccessPointConfig {
+    /// Access points as `host:port` that are tried before the resolved ones.
+    pub accesspoint: Vec<String>,
+    pub dealer: Vec<String>,
+    pub spclient: Vec<String>,
+    pub ordering: AccessPointOrdering,
+    pub resolve: AccessPointResolvePolicy,
+}
+
+/// The order in which access points are tried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessPointOrdering {
+    /// The configured ones in the given order, then the resolved ones in the order of
+    /// Spotify's preference.
+    AsListed,
+    /// By the time it takes to open a TCP connection to them, with those that can't be
+    /// reached last. Not applied when connecting through a proxy.
+    Latency,
+}
+
+/// When the access points are resolved again with apresolve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessPointResolvePolicy {
+    /// Once all access points of an endpoint were tried.
+    WhenExhausted,
+    /// Once all were tried, or when they were resolved longer ago than this.
+    MaxAge(Duration),
+    /// Never, only the configured access points are used and tried again from the start once
+    /// all were tried. Endpoints without configured ones use Spotify's fallback hosts.
+    Never,
+}
+
+impl Default for AccessPointOrdering {
+    fn default() -> Self {
+        Self::AsListed
+    }
+}
+
+impl Default for AccessPointResolvePolicy {
+    fn default() -> Self {
+        Self::WhenExhausted
+    }
+}
+
+impl FromStr for AccessPointOrdering {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "listed" => Ok(Self::AsListed),
+            "latency" => Ok(Self::Latency),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Credentials for a proxy that requires authentication. HTTP proxies may ask for either
+/// Basic or Digest authentication.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for ProxyCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyCredentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Settings for the TLS connections to Spotify's HTTPS and WebSocket endpoints, for example
+/// to run behind a TLS-inspecting proxy.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// PEM files with certificates to trust in addition to the system's root certificates.
+    pub extra_root_certificates: Vec<PathBuf>,
+    /// SHA-256 fingerprints of the certificates that servers are pinned to. The certificate
+    /// chain of a server is checked against the root certificates and the host name as usual,
+    /// and must then contain a pinned certificate: the server's own, or one that the chain is
+    /// valid up to.
+    pub certificate_pins: Vec<[u8; 32]>,
+    /// A client configuration to use instead of building one, e.g. with a custom root store
+    /// or certificate verifier on devices without system root certificates. The other
+    /// settings are ignored then. It is used for all HTTPS and WebSocket connections,
+    /// including apresolve, spclient, the dealer and the CDN.
+    pub client_config: Option<Arc<ClientConfig>>,
+}
+
 #[derive(Clone, Copy, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub enum DeviceType {
     Unknown = 0,
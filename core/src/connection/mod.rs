@@ -1,19 +1,30 @@
 mod codec;
 mod handshake;
+pub mod resolver;
 
 pub use self::{codec::ApCodec, handshake::handshake};
 
-use std::io;
+use self::resolver::Resolver;
+
+use std::{io, time::Duration};
 
 use futures_util::{SinkExt, StreamExt};
 use num_traits::FromPrimitive;
 use protobuf::{self, Message};
+use socket2::{SockRef, TcpKeepalive};
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
+use tracing::{debug_span, Instrument};
 use url::Url;
 
-use crate::{authentication::Credentials, packet::PacketType, version, Error};
+use crate::{
+    authentication::Credentials,
+    config::{HappyEyeballsConfig, ProxyCredentials},
+    error::{self, ErrorKind, Recovery},
+    packet::PacketType,
+    version, Error,
+};
 
 use crate::protocol::keyexchange::{APLoginFailed, ErrorCode};
 
@@ -49,23 +60,72 @@ pub enum AuthenticationError {
 impl From<AuthenticationError> for Error {
     fn from(err: AuthenticationError) -> Self {
         match err {
-            AuthenticationError::LoginFailed(_) => Error::permission_denied(err),
-            AuthenticationError::Packet(_) => Error::unimplemented(err),
-            AuthenticationError::Transport => Error::unavailable(err),
+            AuthenticationError::LoginFailed(_) => {
+                Error::with_code(ErrorKind::PermissionDenied, err)
+            }
+            AuthenticationError::Packet(_) => Error::with_code(ErrorKind::Unimplemented, err),
+            AuthenticationError::Transport => Error::with_code(ErrorKind::Unavailable, err),
         }
     }
 }
 
+impl error::ErrorCode for AuthenticationError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::LoginFailed(_) => "ap.login_failed",
+            Self::Packet(_) => "ap.packet",
+            Self::Transport => "ap.transport",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            (Self::LoginFailed(code), ErrorKind::PermissionDenied) => Some(login_recovery(code)),
+            _ => None,
+        }
+    }
+}
+
+/// What to do when the access point rejected the login with `code`.
+pub(crate) fn login_recovery(code: &ErrorCode) -> Recovery {
+    match code {
+        ErrorCode::BadCredentials => Recovery::Reauthenticate,
+        ErrorCode::TryAnotherAP => Recovery::Retry,
+        _ => Recovery::GiveUp,
+    }
+}
+
 impl From<APLoginFailed> for AuthenticationError {
     fn from(login_failure: APLoginFailed) -> Self {
         Self::LoginFailed(login_failure.error_code())
     }
 }
 
-pub async fn connect(host: &str, port: u16, proxy: Option<&Url>) -> io::Result<Transport> {
-    let socket = crate::socket::connect(host, port, proxy).await?;
+pub async fn connect(
+    host: &str,
+    port: u16,
+    proxy: Option<&Url>,
+    proxy_credentials: Option<&ProxyCredentials>,
+    happy_eyeballs: &HappyEyeballsConfig,
+    resolver: &dyn Resolver,
+    keep_alive_interval: Option<Duration>,
+) -> io::Result<Transport> {
+    let socket = crate::socket::connect(
+        host,
+        port,
+        proxy,
+        proxy_credentials,
+        happy_eyeballs,
+        resolver,
+    )
+    .instrument(debug_span!("socket_connect"))
+    .await?;
+
+    if let Some(interval) = keep_alive_interval {
+        SockRef::from(&socket).set_tcp_keepalive(&TcpKeepalive::new().with_time(interval))?;
+    }
 
-    handshake(socket).await
+    handshake(socket).instrument(debug_span!("handshake")).await
 }
 
 pub async fn authenticate(
@@ -0,0 +1,124 @@
+//! Resolving host names for all connections: to the access point, the dealer, apresolve,
+//! spclient, the CDN and proxies, see [`SessionConfig::resolver`].
+//!
+//! [`SessionConfig::resolver`]: crate::config::SessionConfig::resolver
+
+use std::{fmt, future::Future, io, net::SocketAddr, pin::Pin};
+
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// Returns the addresses of `host` with `port`, in the order they should be tried.
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+/// Resolves host names like the standard library, with `getaddrinfo` on Unix.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+/// Resolves host names with DNS over HTTPS, e.g. where the DNS server of the network
+/// is unreliable or filters Spotify.
+#[cfg(feature = "with-dns-over-https")]
+#[derive(Clone)]
+pub struct DohResolver {
+    resolver: trust_dns_resolver::TokioAsyncResolver,
+    name: &'static str,
+}
+
+#[cfg(feature = "with-dns-over-https")]
+impl DohResolver {
+    pub fn cloudflare() -> Self {
+        Self::new(
+            trust_dns_resolver::config::ResolverConfig::cloudflare_https(),
+            "Cloudflare",
+        )
+    }
+
+    pub fn google() -> Self {
+        Self::new(
+            trust_dns_resolver::config::ResolverConfig::google_https(),
+            "Google",
+        )
+    }
+
+    pub fn quad9() -> Self {
+        Self::new(
+            trust_dns_resolver::config::ResolverConfig::quad9_https(),
+            "Quad9",
+        )
+    }
+
+    fn new(config: trust_dns_resolver::config::ResolverConfig, name: &'static str) -> Self {
+        let opts = trust_dns_resolver::config::ResolverOpts::default();
+        Self {
+            resolver: trust_dns_resolver::TokioAsyncResolver::tokio(config, opts),
+            name,
+        }
+    }
+}
+
+#[cfg(feature = "with-dns-over-https")]
+impl fmt::Debug for DohResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DohResolver").field(&self.name).finish()
+    }
+}
+
+#[cfg(feature = "with-dns-over-https")]
+impl Resolver for DohResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            // proxies and the like may be configured by address
+            if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+                return Ok(vec![SocketAddr::new(ip, port)]);
+            }
+
+            let lookup = self
+                .resolver
+                .lookup_ip(host)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+            Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn system_resolver() {
+        let addrs = SystemResolver
+            .resolve("127.0.0.1", 4070)
+            .await
+            .expect("resolved address");
+        assert_eq!(addrs, ["127.0.0.1:4070".parse().expect("address")]);
+
+        let addrs = SystemResolver
+            .resolve("localhost", 443)
+            .await
+            .expect("resolved host");
+        assert!(!addrs.is_empty());
+        assert!(addrs
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 443));
+    }
+
+    // The HTTP client resolves with port 0 and sets the port of the URI afterwards.
+    #[tokio::test]
+    async fn system_resolver_port_zero() {
+        let addrs = SystemResolver
+            .resolve("localhost", 0)
+            .await
+            .expect("resolved host");
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(|addr| addr.port() == 0));
+    }
+}
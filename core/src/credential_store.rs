@@ -0,0 +1,134 @@
+//! Where the reusable credentials are kept between sessions, see [`Cache::credentials`].
+//!
+//! [`Cache::credentials`]: crate::cache::Cache::credentials
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    authentication::Credentials,
+    cache::{remove_if_invalid, remove_temp_files, write_atomic},
+    Error,
+};
+
+/// Loads, saves and removes the credentials of a device.
+pub trait CredentialStore: Send + Sync {
+    /// Returns `None` if no credentials were saved.
+    fn load(&self) -> Result<Option<Credentials>, Error>;
+    fn save(&self, credentials: &Credentials) -> Result<(), Error>;
+    fn remove(&self) -> Result<(), Error>;
+}
+
+/// Keeps the credentials as JSON in `credentials.json` in a directory.
+#[derive(Debug, Clone)]
+pub struct FileCredentialStore {
+    location: PathBuf,
+}
+
+impl FileCredentialStore {
+    /// Removes the credentials if they cannot be read, e.g. because they were truncated, so
+    /// that a new login is asked for instead of failing to authenticate.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        fs::create_dir_all(&dir)?;
+        remove_temp_files(dir.as_ref());
+        let location = dir.as_ref().join("credentials.json");
+        remove_if_invalid(&location, "credentials", |contents| {
+            serde_json::from_str::<Credentials>(contents).is_ok()
+        });
+        Ok(Self { location })
+    }
+}
+
+impl CredentialStore for FileCredentialStore {
+    fn load(&self) -> Result<Option<Credentials>, Error> {
+        match fs::read_to_string(&self.location) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, credentials: &Credentials) -> Result<(), Error> {
+        let data = serde_json::to_string(credentials)?;
+        write_atomic(&self.location, data)?;
+        Ok(())
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.location) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps the credentials in the keyring of the OS: the Secret Service on Linux, the
+/// Keychain on macOS or the Credential Manager on Windows.
+#[cfg(feature = "with-keyring")]
+pub struct KeyringCredentialStore {
+    entry: keyring::Entry,
+}
+
+#[cfg(feature = "with-keyring")]
+impl KeyringCredentialStore {
+    pub const DEFAULT_SERVICE: &'static str = "librespot";
+    pub const DEFAULT_USER: &'static str = "credentials";
+
+    /// Stores the credentials under `service` and `user`, which must differ between devices
+    /// on the same host that are logged in separately.
+    pub fn new(service: &str, user: &str) -> Result<Self, Error> {
+        let entry = keyring::Entry::new(service, user).map_err(Error::unavailable)?;
+        Ok(Self { entry })
+    }
+}
+
+#[cfg(feature = "with-keyring")]
+impl CredentialStore for KeyringCredentialStore {
+    fn load(&self) -> Result<Option<Credentials>, Error> {
+        match self.entry.get_password() {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(Error::unavailable(e)),
+        }
+    }
+
+    fn save(&self, credentials: &Credentials) -> Result<(), Error> {
+        let data = serde_json::to_string(credentials)?;
+        self.entry.set_password(&data).map_err(Error::unavailable)
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        match self.entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(Error::unavailable(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("librespot-credentials-{}", std::process::id()));
+        let store = FileCredentialStore::new(&dir).expect("store");
+        let credentials = Credentials::with_password("user", "password");
+
+        let empty = store.load().expect("loaded");
+        store.save(&credentials).expect("saved");
+        let saved = store.load().expect("loaded");
+        store.remove().expect("removed");
+        let removed = store.load().expect("loaded");
+        let removed_again = store.remove();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(empty, None);
+        assert_eq!(saved, Some(credentials));
+        assert_eq!(removed, None);
+        assert!(removed_again.is_ok());
+    }
+}
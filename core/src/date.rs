@@ -1,8 +1,8 @@
 use std::{convert::TryFrom, fmt::Debug, ops::Deref};
 
 use time::{
-    error::ComponentRange, format_description::well_known::Iso8601, Date as _Date, OffsetDateTime,
-    PrimitiveDateTime, Time,
+    error::ComponentRange, format_description, format_description::well_known::Iso8601,
+    Date as _Date, OffsetDateTime, PrimitiveDateTime, Time,
 };
 
 use crate::Error;
@@ -52,6 +52,16 @@ impl Date {
         let date_time = OffsetDateTime::parse(input, &Iso8601::DEFAULT)?;
         Ok(Self(date_time))
     }
+
+    /// Parses a date as in HTTP headers, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+    pub fn from_http_date(input: &str) -> Result<Self, Error> {
+        let format = format_description::parse(
+            "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT",
+        )
+        .map_err(Error::internal)?;
+        let date_time = PrimitiveDateTime::parse(input, &format)?;
+        Ok(Self::from_utc(date_time))
+    }
 }
 
 impl TryFrom<&DateMessage> for Date {
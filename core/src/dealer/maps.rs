@@ -2,7 +2,10 @@ use std::collections::HashMap;
 
 use thiserror::Error;
 
-use crate::Error;
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    Error,
+};
 
 #[derive(Debug, Error)]
 pub enum HandlerMapError {
@@ -12,7 +15,15 @@ pub enum HandlerMapError {
 
 impl From<HandlerMapError> for Error {
     fn from(err: HandlerMapError) -> Self {
-        Error::aborted(err)
+        Error::with_code(ErrorKind::Aborted, err)
+    }
+}
+
+impl ErrorCode for HandlerMapError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::AlreadyHandled => "dealer.already_handled",
+        }
     }
 }
 
@@ -1,8 +1,9 @@
 mod maps;
 pub mod protocol;
+mod replies;
 
 use std::{
-    iter,
+    io, iter,
     pin::Pin,
     sync::{
         atomic::{self, AtomicBool},
@@ -22,17 +23,24 @@ use tokio::{
         mpsc::{self, UnboundedReceiver},
         Semaphore,
     },
-    task::JoinHandle,
+    task::{JoinError, JoinHandle},
 };
-use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::{tungstenite, Connector};
 use tungstenite::error::UrlError;
 use url::Url;
 
 use self::maps::*;
 use self::protocol::*;
+use self::replies::Replies;
 
 use crate::{
+    anomaly::{self, ProtocolAnomaly},
+    config::{HappyEyeballsConfig, SessionConfig, TlsConfig},
+    connection::resolver::Resolver,
+    error::{ErrorCode, ErrorKind},
     socket,
+    supervisor::{panic_message, supervise, Backoff},
+    tls,
     util::{keep_flushing, CancelOnDrop, TimeoutOnDrop},
     Error,
 };
@@ -46,8 +54,6 @@ const WEBSOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
 const PING_INTERVAL: Duration = Duration::from_secs(30);
 const PING_TIMEOUT: Duration = Duration::from_secs(3);
 
-const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
-
 pub struct Response {
     pub success: bool,
 }
@@ -55,32 +61,23 @@ pub struct Response {
 pub struct Responder {
     key: String,
     tx: mpsc::UnboundedSender<WsMessage>,
+    replies: Arc<Replies>,
     sent: bool,
 }
 
 impl Responder {
-    fn new(key: String, tx: mpsc::UnboundedSender<WsMessage>) -> Self {
+    fn new(key: String, tx: mpsc::UnboundedSender<WsMessage>, replies: Arc<Replies>) -> Self {
         Self {
             key,
             tx,
+            replies,
             sent: false,
         }
     }
 
     // Should only be called once
     fn send_internal(&mut self, response: Response) {
-        let response = serde_json::json!({
-            "type": "reply",
-            "key": &self.key,
-            "payload": {
-                "success": response.success,
-            }
-        })
-        .to_string();
-
-        if let Err(e) = self.tx.send(WsMessage::Text(response)) {
-            warn!("Wasn't able to reply to dealer request: {}", e);
-        }
+        self.replies.reply(&self.key, response.success, &self.tx);
     }
 
     pub fn send(mut self, response: Response) {
@@ -179,8 +176,17 @@ pub enum AddHandlerError {
 impl From<AddHandlerError> for Error {
     fn from(err: AddHandlerError) -> Self {
         match err {
-            AddHandlerError::AlreadyHandled => Error::aborted(err),
-            AddHandlerError::InvalidUri(_) => Error::invalid_argument(err),
+            AddHandlerError::AlreadyHandled => Error::with_code(ErrorKind::Aborted, err),
+            AddHandlerError::InvalidUri(_) => Error::with_code(ErrorKind::InvalidArgument, err),
+        }
+    }
+}
+
+impl ErrorCode for AddHandlerError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::AlreadyHandled => "dealer.handler_exists",
+            Self::InvalidUri(_) => "dealer.invalid_handler_uri",
         }
     }
 }
@@ -193,7 +199,15 @@ pub enum SubscriptionError {
 
 impl From<SubscriptionError> for Error {
     fn from(err: SubscriptionError) -> Self {
-        Error::invalid_argument(err)
+        Error::with_code(ErrorKind::InvalidArgument, err)
+    }
+}
+
+impl ErrorCode for SubscriptionError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidUri(_) => "dealer.invalid_subscription_uri",
+        }
     }
 }
 
@@ -228,6 +242,7 @@ fn subscribe(
 pub struct Builder {
     message_handlers: SubscriberMap<MessageHandler>,
     request_handlers: HandlerMap<Box<dyn RequestHandler>>,
+    strict_protocol: bool,
 }
 
 macro_rules! create_dealer {
@@ -237,6 +252,8 @@ macro_rules! create_dealer {
                 let shared = Arc::new(DealerShared {
                     message_handlers: Mutex::new(builder.message_handlers),
                     request_handlers: Mutex::new(builder.request_handlers),
+                    replies: Arc::default(),
+                    strict_protocol: builder.strict_protocol,
                     notify_drop: Semaphore::new(0),
                 });
 
@@ -267,26 +284,54 @@ impl Builder {
         subscribe(&mut self.message_handlers, uris)
     }
 
-    pub fn launch_in_background<Fut, F>(self, get_url: F, proxy: Option<Url>) -> Dealer
+    /// Connects like the session, through its proxy and with its TLS, Happy Eyeballs and
+    /// resolver settings. Messages and requests that aren't handled or can't be parsed are
+    /// logged with their payload when
+    /// [`SessionConfig::strict_protocol`](crate::config::SessionConfig::strict_protocol) is set.
+    pub fn launch_in_background<Fut, F>(mut self, get_url: F, config: &SessionConfig) -> Dealer
     where
         Fut: Future<Output = Url> + Send + 'static,
         F: (FnMut() -> Fut) + Send + 'static,
     {
-        create_dealer!(self, shared -> run(shared, None, get_url, proxy))
+        self.strict_protocol = config.strict_protocol;
+        let proxy = config.proxy.clone();
+        let tls_config = config.tls.clone();
+        let happy_eyeballs = config.happy_eyeballs;
+        let resolver = config.resolver.clone();
+        create_dealer!(self, shared -> run(shared, None, get_url, proxy, tls_config, happy_eyeballs, resolver))
     }
 
-    pub async fn launch<Fut, F>(self, mut get_url: F, proxy: Option<Url>) -> WsResult<Dealer>
+    /// Like [`launch_in_background`](Self::launch_in_background), once the first connection
+    /// was established.
+    pub async fn launch<Fut, F>(
+        mut self,
+        mut get_url: F,
+        config: &SessionConfig,
+    ) -> WsResult<Dealer>
     where
         Fut: Future<Output = Url> + Send + 'static,
         F: (FnMut() -> Fut) + Send + 'static,
     {
+        self.strict_protocol = config.strict_protocol;
+        let proxy = config.proxy.clone();
+        let tls_config = config.tls.clone();
+        let happy_eyeballs = config.happy_eyeballs;
+        let resolver = config.resolver.clone();
         let dealer = create_dealer!(self, shared -> {
             // Try to connect.
             let url = get_url().await;
-            let tasks = connect(&url, proxy.as_ref(), &shared).await?;
+            let tasks = connect(
+                &url,
+                proxy.as_ref(),
+                &tls_config,
+                &happy_eyeballs,
+                &*resolver,
+                &shared,
+            )
+            .await?;
 
             // If a connection is established, continue in a background task.
-            run(shared, Some(tasks), get_url, proxy)
+            run(shared, Some(tasks), get_url, proxy, tls_config, happy_eyeballs, resolver)
         });
 
         Ok(dealer)
@@ -296,6 +341,8 @@ impl Builder {
 struct DealerShared {
     message_handlers: Mutex<SubscriberMap<MessageHandler>>,
     request_handlers: Mutex<HandlerMap<Box<dyn RequestHandler>>>,
+    replies: Arc<Replies>,
+    strict_protocol: bool,
 
     // Semaphore with 0 permits. By closing this semaphore, we indicate
     // that the actual Dealer struct has been dropped.
@@ -303,17 +350,37 @@ struct DealerShared {
 }
 
 impl DealerShared {
-    fn dispatch_message(&self, msg: Message) {
+    fn dispatch_message(&self, msg: Message, payload: &str) {
+        let mut delivered = false;
         if let Some(split) = split_uri(&msg.uri) {
-            self.message_handlers
-                .lock()
-                .retain(split, &mut |tx| tx.send(msg.clone()).is_ok());
+            self.message_handlers.lock().retain(split, &mut |tx| {
+                let sent = tx.send(msg.clone()).is_ok();
+                delivered |= sent;
+                sent
+            });
+        }
+
+        if !delivered {
+            self.report_anomaly(ProtocolAnomaly::UnhandledMessage { uri: msg.uri }, payload);
         }
     }
 
-    fn dispatch_request(&self, request: Request, send_tx: &mpsc::UnboundedSender<WsMessage>) {
+    fn dispatch_request(
+        &self,
+        request: Request,
+        payload: &str,
+        send_tx: &mpsc::UnboundedSender<WsMessage>,
+    ) {
+        if !self.replies.deliver(&request.key, send_tx) {
+            return;
+        }
+
         // ResponseSender will automatically send "success: false" if it is dropped without an answer.
-        let responder = Responder::new(request.key.clone(), send_tx.clone());
+        let responder = Responder::new(
+            request.key.clone(),
+            send_tx.clone(),
+            Arc::clone(&self.replies),
+        );
 
         let split = if let Some(split) = split_uri(&request.message_ident) {
             split
@@ -335,12 +402,29 @@ impl DealerShared {
         }
 
         warn!("No handler for message_ident: {}", &request.message_ident);
+        self.report_anomaly(
+            ProtocolAnomaly::UnhandledRequest {
+                message_ident: request.message_ident,
+            },
+            payload,
+        );
+    }
+
+    fn dispatch(&self, payload: &str, send_tx: &mpsc::UnboundedSender<WsMessage>) {
+        match serde_json::from_str(payload) {
+            Ok(MessageOrRequest::Message(m)) => self.dispatch_message(m, payload),
+            Ok(MessageOrRequest::Request(r)) => self.dispatch_request(r, payload, send_tx),
+            Err(e) => {
+                info!("Received invalid message: {}", e);
+                self.report_anomaly(ProtocolAnomaly::InvalidMessage(e.to_string()), payload);
+            }
+        }
     }
 
-    fn dispatch(&self, m: MessageOrRequest, send_tx: &mpsc::UnboundedSender<WsMessage>) {
-        match m {
-            MessageOrRequest::Message(m) => self.dispatch_message(m),
-            MessageOrRequest::Request(r) => self.dispatch_request(r, send_tx),
+    // Only logs the anomaly in strict mode, as there is no one to return an error to.
+    fn report_anomaly(&self, anomaly: ProtocolAnomaly, payload: impl AsRef<[u8]>) {
+        if self.strict_protocol {
+            let _ = anomaly::report(true, anomaly, payload.as_ref());
         }
     }
 
@@ -355,6 +439,9 @@ impl DealerShared {
     }
 }
 
+/// A connection to the dealer, which is re-established with an increasing delay when it is
+/// lost. Handlers and subscriptions are kept, so that they see the messages of the new
+/// connection, including the one with its connection id.
 pub struct Dealer {
     shared: Arc<DealerShared>,
     handle: TimeoutOnDrop<()>,
@@ -393,6 +480,9 @@ impl Dealer {
 async fn connect(
     address: &Url,
     proxy: Option<&Url>,
+    tls_config: &TlsConfig,
+    happy_eyeballs: &HappyEyeballsConfig,
+    resolver: &dyn Resolver,
     shared: &Arc<DealerShared>,
 ) -> WsResult<(JoinHandle<()>, JoinHandle<()>)> {
     let host = address
@@ -407,12 +497,20 @@ async fn connect(
 
     let port = address.port().unwrap_or(default_port);
 
-    let stream = socket::connect(host, port, proxy).await?;
+    let tls_config = tls::client_config(tls_config)
+        .map_err(|e| WsError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
 
-    let (mut ws_tx, ws_rx) = tokio_tungstenite::client_async_tls(address, stream)
-        .await?
-        .0
-        .split();
+    let stream = socket::connect(host, port, proxy, None, happy_eyeballs, resolver).await?;
+
+    let (mut ws_tx, ws_rx) = tokio_tungstenite::client_async_tls_with_config(
+        address,
+        stream,
+        None,
+        Some(Connector::Rustls(Arc::new(tls_config))),
+    )
+    .await?
+    .0
+    .split();
 
     let (send_tx, mut send_rx) = mpsc::unbounded_channel::<WsMessage>();
 
@@ -482,12 +580,13 @@ async fn connect(
             loop {
                 match ws_rx.next().await {
                     Some(Ok(msg)) => match msg {
-                        WsMessage::Text(t) => match serde_json::from_str(&t) {
-                            Ok(m) => shared.dispatch(m, &send_tx),
-                            Err(e) => info!("Received invalid message: {}", e),
-                        },
-                        WsMessage::Binary(_) => {
+                        WsMessage::Text(t) => shared.dispatch(&t, &send_tx),
+                        WsMessage::Binary(b) => {
                             info!("Received invalid binary message");
+                            shared.report_anomaly(
+                                ProtocolAnomaly::InvalidMessage("binary message".into()),
+                                b,
+                            );
                         }
                         WsMessage::Pong(_) => {
                             debug!("Received pong");
@@ -550,12 +649,46 @@ async fn connect(
     Ok((send_task, receive_task))
 }
 
-/// The main background task for `Dealer`, which coordinates reconnecting.
+/// The main background task for `Dealer`, which is restarted with backoff if it panics.
 async fn run<F, Fut>(
+    shared: Arc<DealerShared>,
+    mut initial_tasks: Option<(JoinHandle<()>, JoinHandle<()>)>,
+    get_url: F,
+    proxy: Option<Url>,
+    tls_config: TlsConfig,
+    happy_eyeballs: HappyEyeballsConfig,
+    resolver: Arc<dyn Resolver>,
+) where
+    Fut: Future<Output = Url> + Send + 'static,
+    F: (FnMut() -> Fut) + Send + 'static,
+{
+    // Shared by the restarted tasks.
+    let get_url = Arc::new(Mutex::new(get_url));
+
+    supervise("dealer", Backoff::default(), || {
+        let get_url = Arc::clone(&get_url);
+        reconnect(
+            Arc::clone(&shared),
+            initial_tasks.take(),
+            move || get_url.lock()(),
+            proxy.clone(),
+            tls_config.clone(),
+            happy_eyeballs,
+            Arc::clone(&resolver),
+        )
+    })
+    .await
+}
+
+/// Coordinates reconnecting until the `Dealer` is closed.
+async fn reconnect<F, Fut>(
     shared: Arc<DealerShared>,
     initial_tasks: Option<(JoinHandle<()>, JoinHandle<()>)>,
     mut get_url: F,
     proxy: Option<Url>,
+    tls_config: TlsConfig,
+    happy_eyeballs: HappyEyeballsConfig,
+    resolver: Arc<dyn Resolver>,
 ) where
     Fut: Future<Output = Url> + Send + 'static,
     F: (FnMut() -> Fut) + Send + 'static,
@@ -568,26 +701,36 @@ async fn run<F, Fut>(
         (None, None)
     };
 
+    // The delay grows while connecting fails or connections are lost right away, and starts
+    // over once a connection lasted a while.
+    let mut backoff = Backoff::default();
+    let mut reconnecting = tasks.0.is_some();
+
     while !shared.is_closed() {
         match &mut tasks {
             (Some(t0), Some(t1)) => {
                 select! {
                     () = shared.closed() => break,
                     r = t0 => {
-                        if let Err(e) = r {
-                            error!("timeout on task 0: {}", e);
-                        }
+                        log_task_result("send", r);
                         tasks.0.take();
                     },
                     r = t1 => {
-                        if let Err(e) = r {
-                            error!("timeout on task 1: {}", e);
-                        }
+                        log_task_result("receive", r);
                         tasks.1.take();
                     }
                 }
             }
             _ => {
+                if std::mem::replace(&mut reconnecting, true) {
+                    let delay = backoff.next_delay();
+                    warn!("Reconnecting dealer in {:?}", delay);
+                    select! {
+                        () = shared.closed() => break,
+                        () = tokio::time::sleep(delay) => (),
+                    }
+                }
+
                 let url = select! {
                     () = shared.closed() => {
                         break
@@ -595,12 +738,18 @@ async fn run<F, Fut>(
                     e = get_url() => e
                 };
 
-                match connect(&url, proxy.as_ref(), &shared).await {
+                match connect(
+                    &url,
+                    proxy.as_ref(),
+                    &tls_config,
+                    &happy_eyeballs,
+                    &*resolver,
+                    &shared,
+                )
+                .await
+                {
                     Ok((s, r)) => tasks = (init_task(s), init_task(r)),
-                    Err(e) => {
-                        error!("Error while connecting: {}", e);
-                        tokio::time::sleep(RECONNECT_INTERVAL).await;
-                    }
+                    Err(e) => error!("Error while connecting: {}", e),
                 }
             }
         }
@@ -610,3 +759,19 @@ async fn run<F, Fut>(
 
     let _ = join_all(tasks).await;
 }
+
+/// Logs why a connection task failed.
+fn log_task_result(name: &str, result: Result<(), JoinError>) {
+    match result {
+        Ok(()) => debug!("Dealer {} task finished", name),
+        Err(e) if e.is_panic() => {
+            let payload = e.into_panic();
+            error!(
+                "Dealer {} task panicked: {}",
+                name,
+                panic_message(&*payload)
+            );
+        }
+        Err(e) => error!("timeout on {} task: {}", name, e),
+    }
+}
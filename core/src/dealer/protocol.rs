@@ -1,10 +1,67 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+};
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::Engine as _;
+use flate2::read::{GzDecoder, ZlibDecoder};
 use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    Error,
+};
 
 pub type JsonValue = serde_json::Value;
 pub type JsonObject = serde_json::Map<String, JsonValue>;
 
+// Larger payloads are rejected, so that a small compressed message can't exhaust the memory.
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum PayloadError {
+    #[error("message has no payload")]
+    Missing,
+    #[error("payload fragment is not a string")]
+    InvalidFragment,
+    #[error("payload is not valid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("unsupported transfer encoding {0}")]
+    UnsupportedEncoding(String),
+    #[error("unable to decompress payload: {0}")]
+    Decompression(#[from] io::Error),
+    #[error("payload is larger than {} bytes", MAX_PAYLOAD_SIZE)]
+    TooLarge,
+}
+
+impl From<PayloadError> for Error {
+    fn from(err: PayloadError) -> Self {
+        match err {
+            PayloadError::Missing | PayloadError::InvalidFragment | PayloadError::Base64(_) => {
+                Error::with_code(ErrorKind::InvalidArgument, err)
+            }
+            PayloadError::UnsupportedEncoding(_) => Error::with_code(ErrorKind::Unimplemented, err),
+            PayloadError::Decompression(_) => Error::with_code(ErrorKind::DataLoss, err),
+            PayloadError::TooLarge => Error::with_code(ErrorKind::ResourceExhausted, err),
+        }
+    }
+}
+
+impl ErrorCode for PayloadError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Missing => "dealer.payload_missing",
+            Self::InvalidFragment => "dealer.invalid_fragment",
+            Self::Base64(_) => "dealer.base64",
+            Self::UnsupportedEncoding(_) => "dealer.unsupported_encoding",
+            Self::Decompression(_) => "dealer.decompression",
+            Self::TooLarge => "dealer.payload_too_large",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Payload {
     pub message_id: i32,
@@ -31,9 +88,120 @@ pub struct Message {
     pub uri: String,
 }
 
+impl Message {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the payload of the message. Large payloads are sent base64 encoded in
+    /// fragments, which are reassembled, and then decompressed according to the
+    /// `Transfer-Encoding` header. A single JSON payload is returned as is.
+    pub fn payload(&self) -> Result<Vec<u8>, Error> {
+        let encoded = match self.payloads.as_slice() {
+            [] => return Err(PayloadError::Missing.into()),
+            [JsonValue::String(fragment)] => fragment.clone(),
+            [json] => return Ok(serde_json::to_vec(json)?),
+            fragments => {
+                let mut encoded = String::new();
+                for fragment in fragments {
+                    let fragment = fragment.as_str().ok_or(PayloadError::InvalidFragment)?;
+                    encoded.push_str(fragment);
+                }
+                encoded
+            }
+        };
+
+        // 4 base64 characters encode 3 bytes.
+        if encoded.len() / 4 * 3 > MAX_PAYLOAD_SIZE {
+            return Err(PayloadError::TooLarge.into());
+        }
+        let data = BASE64.decode(encoded).map_err(PayloadError::Base64)?;
+
+        let decoder: Box<dyn Read + '_> = match self.header("Transfer-Encoding") {
+            None => return Ok(data),
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+                Box::new(GzDecoder::new(data.as_slice()))
+            }
+            Some(encoding) if encoding.eq_ignore_ascii_case("deflate") => {
+                Box::new(ZlibDecoder::new(data.as_slice()))
+            }
+            Some(encoding) => {
+                return Err(PayloadError::UnsupportedEncoding(encoding.to_owned()).into())
+            }
+        };
+
+        let mut payload = Vec::new();
+        decoder
+            .take(MAX_PAYLOAD_SIZE as u64 + 1)
+            .read_to_end(&mut payload)
+            .map_err(PayloadError::Decompression)?;
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(PayloadError::TooLarge.into());
+        }
+
+        Ok(payload)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub(super) enum MessageOrRequest {
     Message(Message),
     Request(Request),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn message(headers: &[(&str, &str)], payloads: Vec<JsonValue>) -> Message {
+        Message {
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            method: None,
+            payloads,
+            uri: String::from("hm://connect-state/v1/cluster"),
+        }
+    }
+
+    #[test]
+    fn reassembles_and_decompresses_payload() {
+        let data = b"{\"cluster\": \"large\"}".repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let encoded = BASE64.encode(encoder.finish().unwrap());
+
+        // Fragments aren't necessarily split at base64 boundaries.
+        let (first, second) = encoded.split_at(encoded.len() / 2 + 1);
+        let payloads = vec![first.into(), second.into()];
+
+        let msg = message(&[("Transfer-Encoding", "gzip")], payloads.clone());
+        assert_eq!(msg.payload().unwrap(), data);
+
+        let msg = message(&[("Transfer-Encoding", "br")], payloads);
+        assert!(msg.payload().is_err());
+
+        let msg = message(&[], vec![]);
+        assert!(msg.payload().is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let data = vec![0; MAX_PAYLOAD_SIZE + 1];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let payloads = vec![BASE64.encode(encoder.finish().unwrap()).into()];
+
+        let msg = message(&[("Transfer-Encoding", "gzip")], payloads);
+        assert!(msg.payload().is_err());
+    }
+}
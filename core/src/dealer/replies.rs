@@ -0,0 +1,143 @@
+//! The replies to recent dealer requests. The dealer delivers a request again after a
+//! reconnect if it didn't get the reply, which is then answered without handling it twice.
+
+use std::collections::{HashMap, VecDeque};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+
+use super::WsMessage;
+
+// how many requests are remembered
+const RECENT_REQUESTS: usize = 64;
+
+struct Entry {
+    // the connection the request arrived on last
+    tx: mpsc::UnboundedSender<WsMessage>,
+    success: Option<bool>,
+}
+
+#[derive(Default)]
+struct RepliesInner {
+    entries: HashMap<String, Entry>,
+    order: VecDeque<String>,
+}
+
+#[derive(Default)]
+pub(super) struct Replies(Mutex<RepliesInner>);
+
+impl Replies {
+    /// Records that the request `key` arrived on the connection of `tx`, and returns whether
+    /// it has to be handled. A request that arrived before is not; its reply is sent to `tx`
+    /// instead, right away or once it is known.
+    pub fn deliver(&self, key: &str, tx: &mpsc::UnboundedSender<WsMessage>) -> bool {
+        let mut inner = self.0.lock();
+
+        if let Some(entry) = inner.entries.get_mut(key) {
+            entry.tx = tx.clone();
+            match entry.success {
+                Some(success) => {
+                    debug!("Replaying the reply to dealer request {}", key);
+                    send(key, success, tx);
+                }
+                None => debug!("Dealer request {} arrived again while handling it", key),
+            }
+            return false;
+        }
+
+        if inner.order.len() >= RECENT_REQUESTS {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.entries.remove(&oldest);
+            }
+        }
+        inner.order.push_back(key.to_owned());
+        inner.entries.insert(
+            key.to_owned(),
+            Entry {
+                tx: tx.clone(),
+                success: None,
+            },
+        );
+        true
+    }
+
+    /// Sends the reply to the request `key` over the connection it arrived on last, or `tx`
+    /// if it was forgotten in the meantime, and keeps it in case the request arrives again.
+    pub fn reply(&self, key: &str, success: bool, tx: &mpsc::UnboundedSender<WsMessage>) {
+        let mut inner = self.0.lock();
+
+        match inner.entries.get_mut(key) {
+            Some(entry) => {
+                entry.success = Some(success);
+                send(key, success, &entry.tx);
+            }
+            None => send(key, success, tx),
+        }
+    }
+}
+
+fn send(key: &str, success: bool, tx: &mpsc::UnboundedSender<WsMessage>) {
+    let response = serde_json::json!({
+        "type": "reply",
+        "key": key,
+        "payload": {
+            "success": success,
+        }
+    })
+    .to_string();
+
+    // The connection may be lost, the reply is sent again if the request is.
+    if tx.send(WsMessage::Text(response)).is_err() {
+        debug!("Wasn't able to reply to dealer request {}", key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reply_key(rx: &mut mpsc::UnboundedReceiver<WsMessage>) -> Option<String> {
+        match rx.try_recv() {
+            Ok(WsMessage::Text(text)) => {
+                let reply: serde_json::Value = serde_json::from_str(&text).unwrap();
+                reply["key"].as_str().map(str::to_owned)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replays_replies_to_redelivered_requests() {
+        let replies = Replies::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        assert!(replies.deliver("a", &tx));
+        replies.reply("a", true, &tx);
+        assert_eq!(reply_key(&mut rx).as_deref(), Some("a"));
+
+        // after a reconnect, an answered request is replied to without handling it
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert!(!replies.deliver("a", &tx));
+        assert_eq!(reply_key(&mut rx).as_deref(), Some("a"));
+
+        // the reply to a request that is still handled goes to the new connection
+        let (old_tx, mut old_rx) = mpsc::unbounded_channel();
+        assert!(replies.deliver("b", &old_tx));
+        assert!(!replies.deliver("b", &tx));
+        replies.reply("b", false, &old_tx);
+        assert_eq!(reply_key(&mut rx).as_deref(), Some("b"));
+        assert_eq!(reply_key(&mut old_rx), None);
+    }
+
+    #[test]
+    fn forgets_old_requests() {
+        let replies = Replies::default();
+        let (tx, _rx) = mpsc::unbounded_channel();
+
+        for i in 0..=RECENT_REQUESTS {
+            assert!(replies.deliver(&i.to_string(), &tx));
+        }
+        assert!(replies.deliver("0", &tx));
+        assert!(!replies.deliver(&RECENT_REQUESTS.to_string(), &tx));
+    }
+}
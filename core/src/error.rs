@@ -1,4 +1,6 @@
 use std::{
+    any::TypeId,
+    collections::HashMap,
     error, fmt,
     num::{ParseIntError, TryFromIntError},
     str::Utf8Error,
@@ -12,6 +14,8 @@ use http::{
     status::InvalidStatusCode,
     uri::{InvalidUri, InvalidUriParts},
 };
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
 use protobuf::Error as ProtobufError;
 use thiserror::Error;
 use tokio::sync::{
@@ -82,6 +86,85 @@ pub enum ErrorKind {
     DoNotUse = -1,
 }
 
+impl ErrorKind {
+    /// The name of the kind, like `unavailable`, which doesn't change between versions.
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Cancelled => "cancelled",
+            Self::Unknown => "unknown",
+            Self::InvalidArgument => "invalid_argument",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::NotFound => "not_found",
+            Self::AlreadyExists => "already_exists",
+            Self::PermissionDenied => "permission_denied",
+            Self::Unauthenticated => "unauthenticated",
+            Self::ResourceExhausted => "resource_exhausted",
+            Self::FailedPrecondition => "failed_precondition",
+            Self::Aborted => "aborted",
+            Self::OutOfRange => "out_of_range",
+            Self::Unimplemented => "unimplemented",
+            Self::Internal => "internal",
+            Self::Unavailable => "unavailable",
+            Self::DataLoss => "data_loss",
+            Self::DoNotUse => "do_not_use",
+        }
+    }
+
+    /// What usually helps against an error of this kind.
+    pub fn recovery(self) -> Recovery {
+        match self {
+            Self::DeadlineExceeded
+            | Self::ResourceExhausted
+            | Self::Aborted
+            | Self::Unavailable => Recovery::Retry,
+            Self::Unauthenticated => Recovery::Reauthenticate,
+            _ => Recovery::GiveUp,
+        }
+    }
+}
+
+/// What to do about an [`Error`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Recovery {
+    /// The same request may succeed later, e.g. after a timeout or a dropped connection.
+    Retry,
+    /// The credentials or token were rejected, and the request may succeed after logging in
+    /// again.
+    Reauthenticate,
+    /// The request will fail again, e.g. because the content is not available to the user.
+    GiveUp,
+}
+
+/// Implemented by the error types of librespot, to tell an [`Error`] made from them apart
+/// from others of the same [`ErrorKind`], see [`Error::with_code`].
+pub trait ErrorCode {
+    /// A name like `audio_file.wait_timeout`, which doesn't change between versions.
+    fn code(&self) -> &'static str;
+
+    /// What to do about the error while it is of `kind`, if that differs from what the kind
+    /// suggests. It only applies to the kind the error is made with, so that it doesn't
+    /// once the kind of the [`Error`] is changed.
+    fn recovery(&self, _kind: ErrorKind) -> Option<Recovery> {
+        None
+    }
+}
+
+// Gets at the `ErrorCode` of a boxed error of one of the types that `Error::with_code` was
+// used with. The boxed error can only be downcast to its own type, and keeping the code in
+// `Error` would stop it from being built with a struct literal.
+type AsErrorCode = for<'a> fn(&'a (dyn error::Error + 'static)) -> Option<&'a dyn ErrorCode>;
+
+static ERROR_CODES: Lazy<RwLock<HashMap<TypeId, AsErrorCode>>> = Lazy::new(Default::default);
+
+fn as_error_code<'a, E>(error: &'a (dyn error::Error + 'static)) -> Option<&'a dyn ErrorCode>
+where
+    E: ErrorCode + error::Error + 'static,
+{
+    error
+        .downcast_ref::<E>()
+        .map(|error| error as &dyn ErrorCode)
+}
+
 #[derive(Debug, Error)]
 struct ErrorMessage(String);
 
@@ -102,174 +185,170 @@ impl Error {
         }
     }
 
+    /// Like [`Error::new`], with the code and recovery of `error`. It stays downcastable to
+    /// its own type.
+    pub fn with_code<E>(kind: ErrorKind, error: E) -> Error
+    where
+        E: ErrorCode + error::Error + Send + Sync + 'static,
+    {
+        let type_id = TypeId::of::<E>();
+        if !ERROR_CODES.read().contains_key(&type_id) {
+            ERROR_CODES
+                .write()
+                .insert(type_id, as_error_code::<E> as AsErrorCode);
+        }
+        Self::new(kind, error)
+    }
+
+    fn error_code(&self) -> Option<&dyn ErrorCode> {
+        let error: &(dyn error::Error + 'static) = &*self.error;
+        ERROR_CODES
+            .read()
+            .values()
+            .find_map(|as_error_code| as_error_code(error))
+    }
+
+    /// The code of the error it was made from, or else the code of its kind.
+    pub fn code(&self) -> &'static str {
+        match self.error_code() {
+            Some(error) => error.code(),
+            None => self.kind.code(),
+        }
+    }
+
+    /// What to do about the error it was made from while it is of its kind, or else what
+    /// its kind suggests.
+    pub fn recovery(&self) -> Recovery {
+        self.error_code()
+            .and_then(|error| error.recovery(self.kind))
+            .unwrap_or_else(|| self.kind.recovery())
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.recovery() == Recovery::Retry
+    }
+
+    pub fn requires_reauth(&self) -> bool {
+        self.recovery() == Recovery::Reauthenticate
+    }
+
     pub fn aborted<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::Aborted,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::Aborted, error)
     }
 
     pub fn already_exists<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::AlreadyExists,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::AlreadyExists, error)
     }
 
     pub fn cancelled<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::Cancelled,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::Cancelled, error)
     }
 
     pub fn data_loss<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::DataLoss,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::DataLoss, error)
     }
 
     pub fn deadline_exceeded<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::DeadlineExceeded,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::DeadlineExceeded, error)
     }
 
     pub fn do_not_use<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::DoNotUse,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::DoNotUse, error)
     }
 
     pub fn failed_precondition<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::FailedPrecondition,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::FailedPrecondition, error)
     }
 
     pub fn internal<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::Internal,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::Internal, error)
     }
 
     pub fn invalid_argument<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::InvalidArgument,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::InvalidArgument, error)
     }
 
     pub fn not_found<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::NotFound,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::NotFound, error)
     }
 
     pub fn out_of_range<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::OutOfRange,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::OutOfRange, error)
     }
 
     pub fn permission_denied<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::PermissionDenied,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::PermissionDenied, error)
     }
 
     pub fn resource_exhausted<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::ResourceExhausted,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::ResourceExhausted, error)
     }
 
     pub fn unauthenticated<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::Unauthenticated,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::Unauthenticated, error)
     }
 
     pub fn unavailable<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::Unavailable,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::Unavailable, error)
     }
 
     pub fn unimplemented<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::Unimplemented,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::Unimplemented, error)
     }
 
     pub fn unknown<E>(error: E) -> Error
     where
         E: Into<Box<dyn error::Error + Send + Sync>>,
     {
-        Self {
-            kind: ErrorKind::Unknown,
-            error: error.into(),
-        }
+        Self::new(ErrorKind::Unknown, error)
     }
 }
 
@@ -446,28 +525,19 @@ impl From<RecvError> for Error {
 
 impl<T> From<SendError<T>> for Error {
     fn from(err: SendError<T>) -> Self {
-        Self {
-            kind: ErrorKind::Internal,
-            error: ErrorMessage(err.to_string()).into(),
-        }
+        Self::new(ErrorKind::Internal, ErrorMessage(err.to_string()))
     }
 }
 
 impl From<AcquireError> for Error {
     fn from(err: AcquireError) -> Self {
-        Self {
-            kind: ErrorKind::ResourceExhausted,
-            error: ErrorMessage(err.to_string()).into(),
-        }
+        Self::new(ErrorKind::ResourceExhausted, ErrorMessage(err.to_string()))
     }
 }
 
 impl From<TryAcquireError> for Error {
     fn from(err: TryAcquireError) -> Self {
-        Self {
-            kind: ErrorKind::ResourceExhausted,
-            error: ErrorMessage(err.to_string()).into(),
-        }
+        Self::new(ErrorKind::ResourceExhausted, ErrorMessage(err.to_string()))
     }
 }
 
@@ -482,3 +552,126 @@ impl From<Utf8Error> for Error {
         Self::new(ErrorKind::FailedPrecondition, err)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Error)]
+    #[error("token rejected")]
+    struct TokenRejected;
+
+    impl ErrorCode for TokenRejected {
+        fn code(&self) -> &'static str {
+            "test.token_rejected"
+        }
+
+        fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+            match kind {
+                ErrorKind::PermissionDenied => Some(Recovery::Reauthenticate),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let err = Error::unavailable("connection reset");
+        assert_eq!(err.code(), "unavailable");
+        assert!(err.is_retryable());
+        assert!(!err.requires_reauth());
+
+        let err = Error::with_code(ErrorKind::PermissionDenied, TokenRejected);
+        assert_eq!(err.code(), "test.token_rejected");
+        assert!(err.requires_reauth());
+        assert!(err.error.downcast_ref::<TokenRejected>().is_some());
+
+        let err = Error::not_found("no such track");
+        assert_eq!(err.recovery(), Recovery::GiveUp);
+    }
+
+    #[test]
+    fn builds_with_a_struct_literal() {
+        let err = Error {
+            kind: ErrorKind::DeadlineExceeded,
+            error: "timed out".into(),
+        };
+        assert_eq!(err.code(), "deadline_exceeded");
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn follows_a_changed_kind() {
+        let mut err = Error::with_code(ErrorKind::PermissionDenied, TokenRejected);
+        assert_eq!(err.recovery(), Recovery::Reauthenticate);
+
+        err.kind = ErrorKind::Unavailable;
+        assert_eq!(err.code(), "test.token_rejected");
+        assert_eq!(err.recovery(), Recovery::Retry);
+
+        err.kind = ErrorKind::NotFound;
+        assert_eq!(err.recovery(), Recovery::GiveUp);
+    }
+
+    #[test]
+    fn maps_io_errors() {
+        use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+        for (io_kind, kind, recovery) in [
+            (IoErrorKind::NotFound, ErrorKind::NotFound, Recovery::GiveUp),
+            (
+                IoErrorKind::ConnectionRefused,
+                ErrorKind::Unavailable,
+                Recovery::Retry,
+            ),
+            (
+                IoErrorKind::ConnectionReset,
+                ErrorKind::Aborted,
+                Recovery::Retry,
+            ),
+            (
+                IoErrorKind::TimedOut,
+                ErrorKind::DeadlineExceeded,
+                Recovery::Retry,
+            ),
+            (
+                IoErrorKind::InvalidData,
+                ErrorKind::FailedPrecondition,
+                Recovery::GiveUp,
+            ),
+            (
+                IoErrorKind::UnexpectedEof,
+                ErrorKind::FailedPrecondition,
+                Recovery::GiveUp,
+            ),
+            (
+                IoErrorKind::PermissionDenied,
+                ErrorKind::PermissionDenied,
+                Recovery::GiveUp,
+            ),
+            (IoErrorKind::Other, ErrorKind::Unknown, Recovery::GiveUp),
+        ] {
+            let err = Error::from(IoError::from(io_kind));
+            assert_eq!(
+                (err.kind, err.recovery()),
+                (kind, recovery),
+                "{:?}",
+                io_kind
+            );
+        }
+    }
+
+    #[test]
+    fn maps_parse_errors() {
+        let err = Error::from(serde_json::from_str::<u8>("x").unwrap_err());
+        assert_eq!(err.kind, ErrorKind::FailedPrecondition);
+        assert_eq!(err.code(), "failed_precondition");
+        assert_eq!(err.recovery(), Recovery::GiveUp);
+
+        let err = Error::from("x".parse::<u8>().unwrap_err());
+        assert_eq!(err.kind, ErrorKind::FailedPrecondition);
+
+        let err = Error::from(url::Url::parse("no url").unwrap_err());
+        assert_eq!(err.kind, ErrorKind::FailedPrecondition);
+    }
+}
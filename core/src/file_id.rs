@@ -1,23 +1,110 @@
-use std::fmt;
+use std::{convert::TryFrom, fmt};
 
 use librespot_protocol as protocol;
 
-use crate::{spotify_id::to_base16, Error};
+use crate::{
+    spotify_id::{to_base16, SpotifyIdError, BASE62_DIGITS},
+    Error,
+};
+
+const IMAGE_URI_PREFIX: &str = "spotify:image:";
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct FileId(pub [u8; 20]);
 
 impl FileId {
+    const SIZE: usize = 20;
+    const SIZE_BASE62: usize = 27;
+
+    /// Panics if `src` isn't `FileId::SIZE` (20) bytes long.
+    #[deprecated(note = "use `FileId::try_from`, which returns an error instead of panicking")]
     pub fn from_raw(src: &[u8]) -> FileId {
-        let mut dst = [0u8; 20];
+        let mut dst = [0u8; Self::SIZE];
         dst.clone_from_slice(src);
         FileId(dst)
     }
 
+    /// Parses a base16 (hex) encoded, 40 characters long file ID.
+    pub fn from_base16(src: &str) -> Result<FileId, Error> {
+        let mut dst = [0u8; Self::SIZE];
+        hex::decode_to_slice(src, &mut dst).map_err(|_| SpotifyIdError::InvalidId)?;
+        Ok(FileId(dst))
+    }
+
+    /// Parses a base62 encoded, `FileId::SIZE_BASE62` (27) characters long file ID, using
+    /// the same alphabet as [`SpotifyId`](crate::SpotifyId).
+    pub fn from_base62(src: &str) -> Result<FileId, Error> {
+        if src.len() != Self::SIZE_BASE62 {
+            return Err(SpotifyIdError::InvalidId.into());
+        }
+
+        let mut dst = [0u8; Self::SIZE];
+        for c in src.bytes() {
+            let digit = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'z' => c - b'a' + 10,
+                b'A'..=b'Z' => c - b'A' + 36,
+                _ => return Err(SpotifyIdError::InvalidId.into()),
+            };
+
+            // dst = dst * 62 + digit, in big-endian order
+            let mut carry = digit as u32;
+            for b in dst.iter_mut().rev() {
+                carry += *b as u32 * 62;
+                *b = carry as u8;
+                carry >>= 8;
+            }
+
+            if carry != 0 {
+                return Err(SpotifyIdError::InvalidId.into());
+            }
+        }
+
+        Ok(FileId(dst))
+    }
+
+    /// Parses a `spotify:image:{base16}` URI, as used by playlist annotations.
+    pub fn from_uri(src: &str) -> Result<FileId, Error> {
+        if !src.starts_with("spotify:") {
+            return Err(SpotifyIdError::InvalidRoot.into());
+        }
+
+        match src.strip_prefix(IMAGE_URI_PREFIX) {
+            Some(id) => Self::from_base16(id),
+            None => Err(SpotifyIdError::InvalidFormat.into()),
+        }
+    }
+
     #[allow(clippy::wrong_self_convention)]
     pub fn to_base16(&self) -> Result<String, Error> {
         to_base16(&self.0, &mut [0u8; 40])
     }
+
+    /// Returns the file ID base62 encoded, padded to `FileId::SIZE_BASE62` (27) characters.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_base62(&self) -> Result<String, Error> {
+        let mut n = self.0;
+        let mut dst = [0u8; Self::SIZE_BASE62];
+
+        for digit in dst.iter_mut().rev() {
+            // n, remainder = n / 62, n % 62
+            let mut remainder = 0u32;
+            for b in n.iter_mut() {
+                let acc = (remainder << 8) | *b as u32;
+                *b = (acc / 62) as u8;
+                remainder = acc % 62;
+            }
+            *digit = BASE62_DIGITS[remainder as usize];
+        }
+
+        String::from_utf8(dst.to_vec()).map_err(|_| SpotifyIdError::InvalidId.into())
+    }
+
+    /// Returns the `spotify:image:{base16}` URI of an image.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_uri(&self) -> Result<String, Error> {
+        Ok(format!("{}{}", IMAGE_URI_PREFIX, self.to_base16()?))
+    }
 }
 
 impl fmt::Debug for FileId {
@@ -32,25 +119,105 @@ impl fmt::Display for FileId {
     }
 }
 
-impl From<&[u8]> for FileId {
-    fn from(src: &[u8]) -> Self {
-        Self::from_raw(src)
+/// Serialized as base16 (hex).
+#[cfg(feature = "with-serde")]
+impl serde::Serialize for FileId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
     }
 }
-impl From<&protocol::metadata::Image> for FileId {
-    fn from(image: &protocol::metadata::Image) -> Self {
-        Self::from(image.file_id())
+
+#[cfg(feature = "with-serde")]
+impl<'de> serde::Deserialize<'de> for FileId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let base16 = String::deserialize(deserializer)?;
+        let mut dst = [0u8; 20];
+        hex::decode_to_slice(base16, &mut dst).map_err(D::Error::custom)?;
+        Ok(FileId(dst))
+    }
+}
+
+impl TryFrom<&[u8]> for FileId {
+    type Error = crate::Error;
+    fn try_from(src: &[u8]) -> Result<Self, Self::Error> {
+        <[u8; Self::SIZE]>::try_from(src)
+            .map(FileId)
+            .map_err(|_| SpotifyIdError::InvalidId.into())
+    }
+}
+
+impl TryFrom<&protocol::metadata::Image> for FileId {
+    type Error = crate::Error;
+    fn try_from(image: &protocol::metadata::Image) -> Result<Self, Self::Error> {
+        Self::try_from(image.file_id())
+    }
+}
+
+impl TryFrom<&protocol::metadata::AudioFile> for FileId {
+    type Error = crate::Error;
+    fn try_from(file: &protocol::metadata::AudioFile) -> Result<Self, Self::Error> {
+        Self::try_from(file.file_id())
+    }
+}
+
+impl TryFrom<&protocol::metadata::VideoFile> for FileId {
+    type Error = crate::Error;
+    fn try_from(video: &protocol::metadata::VideoFile) -> Result<Self, Self::Error> {
+        Self::try_from(video.file_id())
     }
 }
 
-impl From<&protocol::metadata::AudioFile> for FileId {
-    fn from(file: &protocol::metadata::AudioFile) -> Self {
-        Self::from(file.file_id())
+impl TryFrom<&protocol::playlist_annotate3::TranscodedPicture> for FileId {
+    type Error = crate::Error;
+    fn try_from(
+        picture: &protocol::playlist_annotate3::TranscodedPicture,
+    ) -> Result<Self, Self::Error> {
+        Self::from_uri(picture.uri())
     }
 }
 
-impl From<&protocol::metadata::VideoFile> for FileId {
-    fn from(video: &protocol::metadata::VideoFile) -> Self {
-        Self::from(video.file_id())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_uri() {
+        let uri = "spotify:image:ab67706c0000da84fcb8b92f2615d3261b8eb146";
+        let id = FileId::from_uri(uri).unwrap();
+
+        assert_eq!(id.0[..4], [0xab, 0x67, 0x70, 0x6c]);
+        assert_eq!(id.to_uri().unwrap(), uri);
+
+        assert!(FileId::from_uri("spotify:track:5sWHDYs0csV6RS48xBl0tH").is_err());
+        assert!(FileId::from_uri("spotify:image:ab67706c").is_err());
+        assert!(FileId::from_uri("https://i.scdn.co/image/ab67706c").is_err());
+    }
+
+    #[test]
+    fn conversions() {
+        let raw = [
+            0xab, 0x67, 0x70, 0x6c, 0x00, 0x00, 0xda, 0x84, 0xfc, 0xb8, 0xb9, 0x2f, 0x26, 0x15,
+            0xd3, 0x26, 0x1b, 0x8e, 0xb1, 0x46,
+        ];
+        let id = FileId::try_from(&raw[..]).unwrap();
+        assert_eq!(id.0, raw);
+        assert!(FileId::try_from(&raw[..19]).is_err());
+        assert!(FileId::try_from(&[0u8; 21][..]).is_err());
+
+        let base16 = id.to_base16().unwrap();
+        assert_eq!(FileId::from_base16(&base16).unwrap(), id);
+        assert!(FileId::from_base16(&base16[..38]).is_err());
+
+        let base62 = id.to_base62().unwrap();
+        assert_eq!(base62.len(), 27);
+        assert_eq!(FileId::from_base62(&base62).unwrap(), id);
+        assert_eq!(FileId([0; 20]).to_base62().unwrap(), "0".repeat(27));
+        let max = FileId([0xff; 20]);
+        assert_eq!(FileId::from_base62(&max.to_base62().unwrap()).unwrap(), max);
+        assert!(FileId::from_base62("ZZZZZZZZZZZZZZZZZZZZZZZZZZZ").is_err());
+        assert!(FileId::from_base62("0000000000000000000000000-0").is_err());
+        assert!(FileId::from_base62(&base62[1..]).is_err());
     }
 }
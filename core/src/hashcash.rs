@@ -0,0 +1,181 @@
+//! Hash cash challenges, a proof of work that login5 and the client token service can ask
+//! for before they answer. Solving one takes up to a few seconds of CPU time, so it runs on a
+//! blocking thread within the budget of [`SessionConfig::challenge_budget`].
+//!
+//! [`SessionConfig::challenge_budget`]: crate::config::SessionConfig::challenge_budget
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{
+    error::{ErrorCode, ErrorKind, Recovery},
+    Error,
+};
+
+// how many hashes are tried between checks of the budget
+const CHECK_INTERVAL: i64 = 0x1000;
+
+#[derive(Debug, Error)]
+pub enum HashCashError {
+    #[error("a hash cash challenge of {0} bits cannot be solved")]
+    Length(i32),
+    #[error("hash cash challenge of {length} bits not solved within {budget:?}")]
+    BudgetExceeded { length: i32, budget: Duration },
+    #[error("solving the hash cash challenge was abandoned")]
+    Cancelled,
+}
+
+impl From<HashCashError> for Error {
+    fn from(err: HashCashError) -> Self {
+        match err {
+            HashCashError::Length(_) => Error::with_code(ErrorKind::FailedPrecondition, err),
+            HashCashError::BudgetExceeded { .. } => {
+                Error::with_code(ErrorKind::DeadlineExceeded, err)
+            }
+            HashCashError::Cancelled => Error::with_code(ErrorKind::Cancelled, err),
+        }
+    }
+}
+
+impl ErrorCode for HashCashError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Length(_) => "hashcash.length",
+            Self::BudgetExceeded { .. } => "hashcash.budget_exceeded",
+            Self::Cancelled => "hashcash.cancelled",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            // a new challenge won't be easier on this device, the budget has to be raised
+            (Self::BudgetExceeded { .. }, ErrorKind::DeadlineExceeded) => Some(Recovery::GiveUp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HashCashSolution {
+    pub suffix: [u8; 16],
+    pub duration: Duration,
+}
+
+/// Finds a suffix for which the SHA-1 of `prefix` and the suffix ends in `length` zero bits,
+/// starting from a seed derived from `context`. Dropping the future abandons the search.
+pub(crate) async fn solve(
+    context: &[u8],
+    prefix: &[u8],
+    length: i32,
+    budget: Duration,
+) -> Result<HashCashSolution, Error> {
+    if !(0..=64).contains(&length) {
+        return Err(HashCashError::Length(length).into());
+    }
+
+    let seed = BigEndian::read_i64(&Sha1::digest(context)[12..20]);
+    let prefix = prefix.to_vec();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+
+    tokio::task::spawn_blocking(move || search(seed, &prefix, length, budget, &cancelled))
+        .await
+        .map_err(Error::internal)?
+        .map_err(Error::from)
+}
+
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn search(
+    seed: i64,
+    prefix: &[u8],
+    length: i32,
+    budget: Duration,
+    cancelled: &AtomicBool,
+) -> Result<HashCashSolution, HashCashError> {
+    let started_at = Instant::now();
+    let mut counter: i64 = 0;
+    loop {
+        if counter % CHECK_INTERVAL == 0 {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(HashCashError::Cancelled);
+            }
+            if started_at.elapsed() >= budget {
+                return Err(HashCashError::BudgetExceeded { length, budget });
+            }
+        }
+
+        let mut suffix = [0; 16];
+        BigEndian::write_i64(&mut suffix[..8], seed.wrapping_add(counter));
+        BigEndian::write_i64(&mut suffix[8..], counter);
+
+        let mut hasher = Sha1::new();
+        hasher.update(prefix);
+        hasher.update(suffix);
+        let md = hasher.finalize();
+
+        if BigEndian::read_i64(&md[12..20]).trailing_zeros() >= length as u32 {
+            return Ok(HashCashSolution {
+                suffix,
+                duration: started_at.elapsed(),
+            });
+        }
+
+        counter += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn solves_challenge() {
+        let solution = solve(b"context", b"prefix", 10, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let md = Sha1::new()
+            .chain_update(b"prefix")
+            .chain_update(solution.suffix)
+            .finalize();
+        assert!(BigEndian::read_i64(&md[12..20]).trailing_zeros() >= 10);
+
+        let seed = BigEndian::read_i64(&Sha1::digest(b"context")[12..20]);
+        let counter = BigEndian::read_i64(&solution.suffix[8..]);
+        assert_eq!(
+            BigEndian::read_i64(&solution.suffix[..8]),
+            seed.wrapping_add(counter)
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_beyond_budget() {
+        let err = solve(b"context", b"prefix", 64, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "hashcash.budget_exceeded");
+        assert_eq!(err.kind, ErrorKind::DeadlineExceeded);
+        assert_eq!(err.recovery(), Recovery::GiveUp);
+
+        let err = solve(b"context", b"prefix", 65, Duration::from_secs(60))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FailedPrecondition);
+    }
+}
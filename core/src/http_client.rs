@@ -1,18 +1,27 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     env::consts::OS,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
     time::{Duration, Instant},
 };
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::Engine as _;
 use bytes::Bytes;
-use futures_util::{future::IntoStream, FutureExt};
+use futures_util::{future::IntoStream, FutureExt, TryFutureExt};
 use governor::{
     clock::MonotonicClock, middleware::NoOpMiddleware, state::InMemoryState, Quota, RateLimiter,
 };
 use http::{header::HeaderValue, Uri};
 use hyper::{
-    client::{HttpConnector, ResponseFuture},
-    header::USER_AGENT,
+    client::{connect::dns::Name, HttpConnector, ResponseFuture},
+    header::{PROXY_AUTHORIZATION, RETRY_AFTER, USER_AGENT, WWW_AUTHENTICATE},
+    service::Service,
     Body, Client, HeaderMap, Request, Response, StatusCode,
 };
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
@@ -22,10 +31,15 @@ use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 use sysinfo::{System, SystemExt};
 use thiserror::Error;
+use tokio::net::TcpStream;
 use url::Url;
 
 use crate::{
+    config::{HappyEyeballsConfig, ProxyCredentials, TlsConfig},
+    connection::resolver::Resolver,
     date::Date,
+    error::{ErrorCode, ErrorKind, Recovery},
+    socket, tls,
     version::{spotify_version, FALLBACK_USER_AGENT, VERSION_STRING},
     Error,
 };
@@ -37,10 +51,28 @@ pub const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(30);
 pub const RATE_LIMIT_MAX_WAIT: Duration = Duration::from_secs(10);
 pub const RATE_LIMIT_CALLS_PER_INTERVAL: u32 = 300;
 
+/// How often a request is retried after `429 Too Many Requests`.
+pub const RATE_LIMIT_MAX_RETRIES: u32 = 3;
+/// How many retries after `429 Too Many Requests` all requests together may make per
+/// [`RATE_LIMIT_INTERVAL`], so that retrying doesn't add to the load that caused them.
+pub const RATE_LIMIT_RETRY_BUDGET: usize = 30;
+
 #[derive(Debug, Error)]
 pub enum HttpClientError {
     #[error("Response status code: {0}")]
     StatusCode(hyper::StatusCode),
+    /// A `401 Unauthorized` or `403 Forbidden` response, with what the server said about
+    /// why: the `WWW-Authenticate` challenge, and the message of the body.
+    #[error("Response status code: {code}: {message}")]
+    Unauthorized {
+        code: hyper::StatusCode,
+        challenge: Option<String>,
+        message: String,
+    },
+    /// A `429 Too Many Requests` response that wasn't retried, or a request that was held
+    /// back for longer than [`RATE_LIMIT_MAX_WAIT`] to not be rate limited.
+    #[error("Rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Option<Duration> },
 }
 
 impl From<HttpClientError> for Error {
@@ -50,27 +82,33 @@ impl From<HttpClientError> for Error {
                 // not exhaustive, but what reasonably could be expected
                 match code {
                     StatusCode::GATEWAY_TIMEOUT | StatusCode::REQUEST_TIMEOUT => {
-                        Error::deadline_exceeded(err)
+                        Error::with_code(ErrorKind::DeadlineExceeded, err)
                     }
                     StatusCode::GONE
                     | StatusCode::NOT_FOUND
                     | StatusCode::MOVED_PERMANENTLY
                     | StatusCode::PERMANENT_REDIRECT
-                    | StatusCode::TEMPORARY_REDIRECT => Error::not_found(err),
+                    | StatusCode::TEMPORARY_REDIRECT => Error::with_code(ErrorKind::NotFound, err),
                     StatusCode::FORBIDDEN | StatusCode::PAYMENT_REQUIRED => {
-                        Error::permission_denied(err)
+                        Error::with_code(ErrorKind::PermissionDenied, err)
                     }
                     StatusCode::NETWORK_AUTHENTICATION_REQUIRED
                     | StatusCode::PROXY_AUTHENTICATION_REQUIRED
-                    | StatusCode::UNAUTHORIZED => Error::unauthenticated(err),
+                    | StatusCode::UNAUTHORIZED => Error::with_code(ErrorKind::Unauthenticated, err),
                     StatusCode::EXPECTATION_FAILED
                     | StatusCode::PRECONDITION_FAILED
-                    | StatusCode::PRECONDITION_REQUIRED => Error::failed_precondition(err),
-                    StatusCode::RANGE_NOT_SATISFIABLE => Error::out_of_range(err),
+                    | StatusCode::PRECONDITION_REQUIRED => {
+                        Error::with_code(ErrorKind::FailedPrecondition, err)
+                    }
+                    StatusCode::RANGE_NOT_SATISFIABLE => {
+                        Error::with_code(ErrorKind::OutOfRange, err)
+                    }
                     StatusCode::INTERNAL_SERVER_ERROR
                     | StatusCode::MISDIRECTED_REQUEST
                     | StatusCode::SERVICE_UNAVAILABLE
-                    | StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS => Error::unavailable(err),
+                    | StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS => {
+                        Error::with_code(ErrorKind::Unavailable, err)
+                    }
                     StatusCode::BAD_REQUEST
                     | StatusCode::HTTP_VERSION_NOT_SUPPORTED
                     | StatusCode::LENGTH_REQUIRED
@@ -79,31 +117,191 @@ impl From<HttpClientError> for Error {
                     | StatusCode::PAYLOAD_TOO_LARGE
                     | StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
                     | StatusCode::UNSUPPORTED_MEDIA_TYPE
-                    | StatusCode::URI_TOO_LONG => Error::invalid_argument(err),
-                    StatusCode::TOO_MANY_REQUESTS => Error::resource_exhausted(err),
-                    StatusCode::NOT_IMPLEMENTED => Error::unimplemented(err),
-                    _ => Error::unknown(err),
+                    | StatusCode::URI_TOO_LONG => Error::with_code(ErrorKind::InvalidArgument, err),
+                    StatusCode::TOO_MANY_REQUESTS => {
+                        Error::with_code(ErrorKind::ResourceExhausted, err)
+                    }
+                    StatusCode::NOT_IMPLEMENTED => Error::with_code(ErrorKind::Unimplemented, err),
+                    _ => Error::with_code(ErrorKind::Unknown, err),
                 }
             }
+            HttpClientError::Unauthorized { code, .. } if code == StatusCode::FORBIDDEN => {
+                Error::with_code(ErrorKind::PermissionDenied, err)
+            }
+            HttpClientError::Unauthorized { .. } => {
+                Error::with_code(ErrorKind::Unauthenticated, err)
+            }
+            HttpClientError::RateLimited { .. } => {
+                Error::with_code(ErrorKind::ResourceExhausted, err)
+            }
+        }
+    }
+}
+
+impl ErrorCode for HttpClientError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::StatusCode(_) => "http.status_code",
+            Self::Unauthorized { .. } => "http.unauthorized",
+            Self::RateLimited { .. } => "http.rate_limited",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            (Self::StatusCode(code), ErrorKind::Unavailable)
+                if *code == StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS =>
+            {
+                Some(Recovery::GiveUp)
+            }
+            _ => None,
         }
     }
 }
 
-type HyperClient = Client<ProxyConnector<HttpsConnector<HttpConnector>>, Body>;
+impl HttpClientError {
+    async fn unauthorized(response: Response<Body>) -> Self {
+        let code = response.status();
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+
+        // Web API style errors come as `{"error": {"status": 401, "message": "..."}}`.
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap_or_default();
+        let message = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|json| {
+                let error = json.get("error")?;
+                let message = error.get("message").unwrap_or(error);
+                message.as_str().map(str::to_owned)
+            })
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).trim().to_owned());
+
+        Self::Unauthorized {
+            code,
+            challenge,
+            message,
+        }
+    }
+}
+
+type HyperClient = Client<ProxyConnector<HttpsConnector<TcpConnector>>, Body>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Resolves host names for the `HttpConnector` with the configured resolver.
+#[derive(Clone)]
+struct HttpResolver(Arc<dyn Resolver>);
+
+impl Service<Name> for HttpResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.0.clone();
+        // the connector sets the port of the URI
+        Box::pin(async move { Ok(resolver.resolve(name.as_str(), 0).await?.into_iter()) })
+    }
+}
+
+/// Opens the TCP connections for the HTTP client: directly, or tunneled through the proxy.
+/// Plain HTTP requests to an HTTP proxy are forwarded by the `ProxyConnector` wrapping this
+/// instead.
+#[derive(Clone)]
+struct TcpConnector {
+    http: HttpConnector<HttpResolver>,
+    proxy_url: Option<Url>,
+    proxy_credentials: Option<ProxyCredentials>,
+    happy_eyeballs: HappyEyeballsConfig,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl Service<Uri> for TcpConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(BoxError::from)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let proxy_url = match &self.proxy_url {
+            Some(proxy_url)
+                if socket::is_socks5(proxy_url) || uri.scheme_str() == Some("https") =>
+            {
+                proxy_url.clone()
+            }
+            _ => return Box::pin(self.http.call(uri).map_err(BoxError::from)),
+        };
+        let proxy_credentials = self.proxy_credentials.clone();
+        let happy_eyeballs = self.happy_eyeballs;
+        let resolver = self.resolver.clone();
+
+        Box::pin(async move {
+            let host = uri.host().ok_or("URI without host")?;
+            let port = match uri.port_u16() {
+                Some(port) => port,
+                None if uri.scheme_str() == Some("https") => 443,
+                None => 80,
+            };
+            let socket = socket::connect(
+                host,
+                port,
+                Some(&proxy_url),
+                proxy_credentials.as_ref(),
+                &happy_eyeballs,
+                &*resolver,
+            )
+            .await?;
+            Ok::<_, BoxError>(socket)
+        })
+    }
+}
+
+#[derive(Default)]
+struct Backpressure {
+    // until when domains are not requested, as asked by `429 Too Many Requests` responses
+    blocked_until: HashMap<String, Instant>,
+    // when requests were retried, within the last `RATE_LIMIT_INTERVAL`
+    retries: VecDeque<Instant>,
+}
 
 pub struct HttpClient {
     user_agent: HeaderValue,
     proxy_url: Option<Url>,
+    proxy_credentials: Option<ProxyCredentials>,
+    // Basic credentials for plain HTTP requests, which an HTTP proxy forwards instead of
+    // tunneling and which therefore cannot answer a Digest challenge.
+    proxy_authorization: Option<HeaderValue>,
+    tls_config: TlsConfig,
+    happy_eyeballs: HappyEyeballsConfig,
+    resolver: Arc<dyn Resolver>,
     hyper_client: OnceCell<HyperClient>,
 
     // while the DashMap variant is more performant, our level of concurrency
     // is pretty low so we can save pulling in that extra dependency
     rate_limiter:
         RateLimiter<String, Mutex<HashMap<String, InMemoryState>>, MonotonicClock, NoOpMiddleware>,
+    backpressure: Mutex<Backpressure>,
 }
 
 impl HttpClient {
-    pub fn new(proxy_url: Option<&Url>) -> Self {
+    pub fn new(
+        proxy_url: Option<&Url>,
+        proxy_credentials: Option<&ProxyCredentials>,
+        tls_config: &TlsConfig,
+        happy_eyeballs: &HappyEyeballsConfig,
+        resolver: Arc<dyn Resolver>,
+    ) -> Self {
         let zero_str = String::from("0");
         let os_version = System::new()
             .os_version()
@@ -137,28 +335,65 @@ impl HttpClient {
             .allow_burst(nonzero![RATE_LIMIT_CALLS_PER_INTERVAL]);
         let rate_limiter = RateLimiter::keyed(quota);
 
+        let proxy_authorization = proxy_url
+            .filter(|url| !socket::is_socks5(url))
+            .and_then(|url| socket::proxy_credentials(url, proxy_credentials))
+            .and_then(|(username, password)| {
+                let credentials = BASE64.encode(format!("{username}:{password}"));
+                let mut value = HeaderValue::from_str(&format!("Basic {credentials}")).ok()?;
+                value.set_sensitive(true);
+                Some(value)
+            });
+
         Self {
             user_agent,
             proxy_url: proxy_url.cloned(),
+            proxy_credentials: proxy_credentials.cloned(),
+            proxy_authorization,
+            tls_config: tls_config.clone(),
+            happy_eyeballs: *happy_eyeballs,
+            resolver,
             hyper_client: OnceCell::new(),
             rate_limiter,
+            backpressure: Mutex::new(Backpressure::default()),
         }
     }
 
-    fn try_create_hyper_client(proxy_url: Option<&Url>) -> Result<HyperClient, Error> {
+    fn try_create_hyper_client(
+        proxy_url: Option<&Url>,
+        proxy_credentials: Option<&ProxyCredentials>,
+        tls_config: &TlsConfig,
+        happy_eyeballs: &HappyEyeballsConfig,
+        resolver: &Arc<dyn Resolver>,
+    ) -> Result<HyperClient, Error> {
         // configuring TLS is expensive and should be done once per process
+        let mut http = HttpConnector::new_with_resolver(HttpResolver(resolver.clone()));
+        http.enforce_http(false);
+        http.set_happy_eyeballs_timeout(Some(happy_eyeballs.attempt_delay));
+        http.set_connect_timeout(happy_eyeballs.connect_timeout);
+        let tcp_connector = TcpConnector {
+            http,
+            proxy_url: proxy_url.cloned(),
+            proxy_credentials: proxy_credentials.cloned(),
+            happy_eyeballs: *happy_eyeballs,
+            resolver: resolver.clone(),
+        };
+
         let https_connector = HttpsConnectorBuilder::new()
-            .with_native_roots()
+            .with_tls_config(tls::client_config(tls_config)?)
             .https_or_http()
             .enable_http1()
             .enable_http2()
-            .build();
+            .wrap_connector(tcp_connector);
 
-        // When not using a proxy a dummy proxy is configured that will not intercept any traffic.
-        // This prevents needing to carry the Client Connector generics through the whole project
+        // When not using an HTTP proxy a dummy proxy is configured that will not intercept any
+        // traffic. This prevents needing to carry the Client Connector generics through the
+        // whole project
         let proxy = match &proxy_url {
-            Some(proxy_url) => Proxy::new(Intercept::All, proxy_url.to_string().parse()?),
-            None => Proxy::new(Intercept::None, Uri::from_static("0.0.0.0")),
+            Some(proxy_url) if !socket::is_socks5(proxy_url) => {
+                Proxy::new(Intercept::Http, proxy_url.to_string().parse()?)
+            }
+            _ => Proxy::new(Intercept::None, Uri::from_static("0.0.0.0")),
         };
         let proxy_connector = ProxyConnector::from_proxy(https_connector, proxy)?;
 
@@ -169,10 +404,24 @@ impl HttpClient {
     }
 
     fn hyper_client(&self) -> Result<&HyperClient, Error> {
-        self.hyper_client
-            .get_or_try_init(|| Self::try_create_hyper_client(self.proxy_url.as_ref()))
+        self.hyper_client.get_or_try_init(|| {
+            Self::try_create_hyper_client(
+                self.proxy_url.as_ref(),
+                self.proxy_credentials.as_ref(),
+                &self.tls_config,
+                &self.happy_eyeballs,
+                &self.resolver,
+            )
+        })
     }
 
+    /// Requests `req`, retrying it after `429 Too Many Requests` responses as long as the
+    /// service asks to wait at most [`RATE_LIMIT_MAX_WAIT`], up to [`RATE_LIMIT_MAX_RETRIES`]
+    /// times and within the [`RATE_LIMIT_RETRY_BUDGET`] shared by all requests. Otherwise it
+    /// fails with [`HttpClientError::RateLimited`].
+    ///
+    /// Until the time the service asked to wait for has passed, further requests to the same
+    /// domain wait as well, see [`backpressure`](Self::backpressure).
     pub async fn request(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
         debug!("Requesting {}", req.uri().to_string());
 
@@ -184,6 +433,9 @@ impl HttpClient {
             .await
             .unwrap_or_else(|_| Bytes::new());
 
+        let rate_limit_key = Self::rate_limit_key(&parts.uri);
+        let mut retries = 0;
+
         loop {
             let mut req = Request::builder()
                 .method(parts.method.clone())
@@ -192,29 +444,40 @@ impl HttpClient {
                 .body(Body::from(body_as_bytes.clone()))?;
             *req.headers_mut() = parts.headers.clone();
 
-            let request = self.request_fut(req)?;
-            let response = request.await;
+            self.wait_for_rate_limit(&rate_limit_key).await?;
+            let response = self.send(req)?.await?;
+            let code = response.status();
+
+            if code == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = Self::parse_retry_after(response.headers());
+                // without `Retry-After`, back off exponentially
+                let wait = retry_after.unwrap_or_else(|| Duration::from_secs(1 << retries));
+                self.block(&rate_limit_key, wait);
+
+                if wait <= RATE_LIMIT_MAX_WAIT
+                    && retries < RATE_LIMIT_MAX_RETRIES
+                    && self.take_retry()
+                {
+                    retries += 1;
+                    warn!(
+                        "Rate limited by service, retrying in {} seconds...",
+                        wait.as_secs()
+                    );
+                    continue;
+                }
 
-            if let Ok(response) = &response {
-                let code = response.status();
+                return Err(HttpClientError::RateLimited { retry_after }.into());
+            }
 
-                if code == StatusCode::TOO_MANY_REQUESTS {
-                    if let Some(duration) = Self::get_retry_after(response.headers()) {
-                        warn!(
-                            "Rate limited by service, retrying in {} seconds...",
-                            duration.as_secs()
-                        );
-                        tokio::time::sleep(duration).await;
-                        continue;
-                    }
-                }
+            if code == StatusCode::UNAUTHORIZED || code == StatusCode::FORBIDDEN {
+                return Err(HttpClientError::unauthorized(response).await.into());
+            }
 
-                if code != StatusCode::OK {
-                    return Err(HttpClientError::StatusCode(code).into());
-                }
+            if code != StatusCode::OK {
+                return Err(HttpClientError::StatusCode(code).into());
             }
 
-            return Ok(response?);
+            return Ok(response);
         }
     }
 
@@ -227,14 +490,34 @@ impl HttpClient {
         Ok(self.request_fut(req)?.into_stream())
     }
 
-    pub fn request_fut(&self, mut req: Request<Body>) -> Result<ResponseFuture, Error> {
+    /// Starts the request right away, or fails with [`HttpClientError::RateLimited`] if it
+    /// would have to wait for the rate limits.
+    pub fn request_fut(&self, req: Request<Body>) -> Result<ResponseFuture, Error> {
+        self.try_acquire(&Self::rate_limit_key(req.uri()))
+            .map_err(|wait| HttpClientError::RateLimited {
+                retry_after: Some(wait),
+            })?;
+        self.send(req)
+    }
+
+    fn send(&self, mut req: Request<Body>) -> Result<ResponseFuture, Error> {
+        let is_plain_http = req.uri().scheme_str() == Some("http");
         let headers_mut = req.headers_mut();
         headers_mut.insert(USER_AGENT, self.user_agent.clone());
+        if let Some(proxy_authorization) = &self.proxy_authorization {
+            if is_plain_http {
+                headers_mut.insert(PROXY_AUTHORIZATION, proxy_authorization.clone());
+            }
+        }
 
-        // For rate limiting we cannot *just* depend on Spotify sending us HTTP/429
-        // Retry-After headers. For example, when there is a service interruption
-        // and HTTP/500 is returned, we don't want to DoS the Spotify infrastructure.
-        let domain = match req.uri().host() {
+        Ok(self.hyper_client()?.request(req))
+    }
+
+    // For rate limiting we cannot *just* depend on Spotify sending us HTTP/429
+    // Retry-After headers. For example, when there is a service interruption
+    // and HTTP/500 is returned, we don't want to DoS the Spotify infrastructure.
+    fn rate_limit_key(uri: &Uri) -> String {
+        match uri.host() {
             Some(host) => {
                 // strip the prefix from *.domain.tld (assume rate limit is per domain, not subdomain)
                 let mut parts = host
@@ -245,18 +528,102 @@ impl HttpClient {
                 parts.drain(n..).collect()
             }
             None => String::from(""),
-        };
-        self.rate_limiter.check_key(&domain).map_err(|e| {
-            Error::resource_exhausted(format!(
-                "rate limited for at least another {} seconds",
-                e.wait_time_from(Instant::now()).as_secs()
-            ))
-        })?;
+        }
+    }
 
-        Ok(self.hyper_client()?.request(req))
+    // Takes a request from the rate limit of the domain, or returns how long to wait for one.
+    fn try_acquire(&self, key: &str) -> Result<(), Duration> {
+        if let Some(wait) = self.blocked_for(key) {
+            return Err(wait);
+        }
+        self.rate_limiter
+            .check_key(&key.to_owned())
+            .map_err(|e| e.wait_time_from(Instant::now()))
     }
 
+    async fn wait_for_rate_limit(&self, key: &str) -> Result<(), Error> {
+        loop {
+            match self.try_acquire(key) {
+                Ok(()) => return Ok(()),
+                Err(wait) if wait <= RATE_LIMIT_MAX_WAIT => {
+                    debug!("Holding back request for {:?} to not be rate limited", wait);
+                    tokio::time::sleep(wait).await;
+                }
+                Err(wait) => {
+                    return Err(HttpClientError::RateLimited {
+                        retry_after: Some(wait),
+                    }
+                    .into())
+                }
+            }
+        }
+    }
+
+    fn blocked_for(&self, key: &str) -> Option<Duration> {
+        let mut backpressure = self.backpressure.lock();
+        let blocked_until = *backpressure.blocked_until.get(key)?;
+        let now = Instant::now();
+        if blocked_until > now {
+            Some(blocked_until - now)
+        } else {
+            backpressure.blocked_until.remove(key);
+            None
+        }
+    }
+
+    fn block(&self, key: &str, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut backpressure = self.backpressure.lock();
+        let blocked_until = backpressure
+            .blocked_until
+            .entry(key.to_owned())
+            .or_insert(until);
+        *blocked_until = (*blocked_until).max(until);
+    }
+
+    // Takes a retry from the budget that all requests share.
+    fn take_retry(&self) -> bool {
+        let mut backpressure = self.backpressure.lock();
+        let now = Instant::now();
+        while let Some(&retried_at) = backpressure.retries.front() {
+            if now.duration_since(retried_at) < RATE_LIMIT_INTERVAL {
+                break;
+            }
+            backpressure.retries.pop_front();
+        }
+
+        if backpressure.retries.len() >= RATE_LIMIT_RETRY_BUDGET {
+            debug!("Retry budget for rate limited requests is exhausted");
+            return false;
+        }
+        backpressure.retries.push_back(now);
+        true
+    }
+
+    /// How long requests to the domain of `uri` are held back because the service responded
+    /// with `429 Too Many Requests`, so that callers can defer what isn't urgent.
+    pub fn backpressure(&self, uri: &Uri) -> Option<Duration> {
+        self.blocked_for(&Self::rate_limit_key(uri))
+    }
+
+    /// How long the service asked to wait, if at most [`RATE_LIMIT_MAX_WAIT`].
     pub fn get_retry_after(headers: &HeaderMap<HeaderValue>) -> Option<Duration> {
+        let duration = Self::parse_retry_after(headers)?;
+        if duration <= RATE_LIMIT_MAX_WAIT {
+            Some(duration)
+        } else {
+            debug!(
+                "Waiting {} seconds would exceed {} second limit",
+                duration.as_secs(),
+                RATE_LIMIT_MAX_WAIT.as_secs()
+            );
+            None
+        }
+    }
+
+    /// How long the service asked to wait, from the rate limit headers of the CDNs or
+    /// `Retry-After` in seconds or as a date.
+    pub fn parse_retry_after(headers: &HeaderMap<HeaderValue>) -> Option<Duration> {
         let now = Date::now_utc().as_timestamp_ms();
 
         let mut retry_after_ms = None;
@@ -274,28 +641,266 @@ impl HttpClient {
                     retry_after_ms = Some(target.saturating_sub(now))
                 }
             }
-        } else if let Some(header_val) = headers.get("Retry-After") {
+        } else if let Some(header_val) = headers.get(RETRY_AFTER) {
             // Generic RFC compliant (including *.spotify.com)
             if let Ok(retry_after) = header_val.to_str() {
                 if let Ok(duration) = retry_after.parse::<i64>() {
-                    retry_after_ms = Some(duration * 1000)
+                    retry_after_ms = Some(duration.saturating_mul(1000))
+                } else if let Ok(target) = Date::from_http_date(retry_after) {
+                    retry_after_ms = Some(target.as_timestamp_ms().saturating_sub(now))
                 }
             }
         }
 
-        if let Some(retry_after) = retry_after_ms {
-            let duration = Duration::from_millis(retry_after as u64);
-            if duration <= RATE_LIMIT_MAX_WAIT {
-                return Some(duration);
-            } else {
-                debug!(
-                    "Waiting {} seconds would exceed {} second limit",
-                    duration.as_secs(),
-                    RATE_LIMIT_MAX_WAIT.as_secs()
-                );
+        // a time in the past means that there is no need to wait
+        retry_after_ms.map(|retry_after| Duration::from_millis(retry_after.max(0) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::connection::resolver::ResolveFuture;
+
+    // Resolves every host name to the loopback address and records what it was asked for.
+    #[derive(Debug, Default)]
+    struct LoopbackResolver(Mutex<Vec<(String, u16)>>);
+
+    impl Resolver for LoopbackResolver {
+        fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+            self.0.lock().push((host.to_owned(), port));
+            Box::pin(async move { Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn http_resolver() {
+        let resolver = Arc::new(LoopbackResolver::default());
+        let mut http_resolver = HttpResolver(resolver.clone());
+
+        let name = Name::from_str("spclient.wg.spotify.com").expect("name");
+        let addrs: Vec<_> = http_resolver.call(name).await.expect("resolved").collect();
+
+        assert_eq!(addrs, [SocketAddr::from(([127, 0, 0, 1], 0))]);
+        assert_eq!(
+            *resolver.0.lock(),
+            [("spclient.wg.spotify.com".to_owned(), 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn http_resolver_connects_to_the_port_of_the_uri() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener");
+        let port = listener.local_addr().expect("address").port();
+
+        let resolver = Arc::new(LoopbackResolver::default());
+        let mut http = HttpConnector::new_with_resolver(HttpResolver(resolver.clone()));
+        let uri = Uri::from_str(&format!("http://librespot.test:{port}/")).expect("uri");
+
+        let (connected, accepted) = tokio::join!(http.call(uri), listener.accept());
+        assert!(connected.is_ok());
+        assert!(accepted.is_ok());
+        assert_eq!(*resolver.0.lock(), [("librespot.test".to_owned(), 0)]);
+    }
+
+    #[test]
+    fn retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(
+            HttpClient::parse_retry_after(&headers),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(HttpClient::get_retry_after(&headers), None);
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+        );
+        assert_eq!(
+            HttpClient::parse_retry_after(&headers),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn classifies_status_codes() {
+        for (code, kind, recovery) in [
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorKind::Unavailable,
+                Recovery::Retry,
+            ),
+            (
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                ErrorKind::Unavailable,
+                Recovery::GiveUp,
+            ),
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                ErrorKind::DeadlineExceeded,
+                Recovery::Retry,
+            ),
+            (StatusCode::NOT_FOUND, ErrorKind::NotFound, Recovery::GiveUp),
+            (
+                StatusCode::UNAUTHORIZED,
+                ErrorKind::Unauthenticated,
+                Recovery::Reauthenticate,
+            ),
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorKind::ResourceExhausted,
+                Recovery::Retry,
+            ),
+            (
+                StatusCode::BAD_REQUEST,
+                ErrorKind::InvalidArgument,
+                Recovery::GiveUp,
+            ),
+        ] {
+            let err = Error::from(HttpClientError::StatusCode(code));
+            assert_eq!(err.code(), "http.status_code");
+            assert_eq!((err.kind, err.recovery()), (kind, recovery), "{}", code);
+        }
+
+        let err = Error::from(HttpClientError::RateLimited { retry_after: None });
+        assert_eq!(
+            (err.code(), err.recovery()),
+            ("http.rate_limited", Recovery::Retry)
+        );
+    }
+
+    // Serves `responses` in order, repeating the last one, and counts the requests.
+    async fn serve(responses: &'static [&'static str]) -> (Uri, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener");
+        let port = listener.local_addr().expect("address").port();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let served = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let n = served.fetch_add(1, Ordering::SeqCst);
+                let response = responses[n.min(responses.len() - 1)];
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response = format!(
+                        "HTTP/1.1 {response}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
             }
+        });
+
+        let uri = Uri::from_str(&format!("http://librespot.test:{port}/")).expect("uri");
+        (uri, requests)
+    }
+
+    fn client() -> HttpClient {
+        HttpClient::new(
+            None,
+            None,
+            &TlsConfig::default(),
+            &HappyEyeballsConfig::default(),
+            Arc::new(LoopbackResolver::default()),
+        )
+    }
+
+    fn get(uri: &Uri) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).expect("request")
+    }
+
+    fn requested_wait(err: Error) -> Option<Duration> {
+        match err.error.downcast_ref::<HttpClientError>() {
+            Some(HttpClientError::RateLimited { retry_after }) => *retry_after,
+            _ => panic!("not rate limited: {err}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_when_rate_limited() {
+        const RATE_LIMITED: &str = "429 Too Many Requests\r\nRetry-After: 0";
+        let (uri, requests) = serve(&[RATE_LIMITED, RATE_LIMITED, "200 OK"]).await;
+
+        let response = client().request(get(&uri)).await.expect("response");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_a_few_retries() {
+        let (uri, requests) = serve(&["429 Too Many Requests\r\nRetry-After: 0"]).await;
+
+        let err = client().request(get(&uri)).await.expect_err("rate limited");
+        assert_eq!(requested_wait(err), Some(Duration::ZERO));
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            1 + RATE_LIMIT_MAX_RETRIES as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn holds_back_requests_while_blocked() {
+        let (uri, requests) = serve(&["429 Too Many Requests\r\nRetry-After: 60", "200 OK"]).await;
+        let client = client();
+
+        // too long to wait for, so the request fails right away ...
+        let err = client.request(get(&uri)).await.expect_err("rate limited");
+        assert_eq!(requested_wait(err), Some(Duration::from_secs(60)));
+        let backpressure = client.backpressure(&uri).expect("blocked");
+        assert!(backpressure > Duration::from_secs(59));
+
+        // ... and so do the next ones to the domain, without being sent
+        let err = client.request(get(&uri)).await.expect_err("blocked");
+        assert!(requested_wait(err).expect("wait") > Duration::from_secs(59));
+        assert!(client.request_fut(get(&uri)).is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_short_block() {
+        let (uri, requests) = serve(&["200 OK"]).await;
+        let client = client();
+
+        let wait = Duration::from_millis(200);
+        client.block(&HttpClient::rate_limit_key(&uri), wait);
+        assert!(client.request_fut(get(&uri)).is_err());
+
+        let started_at = Instant::now();
+        let response = client.request(get(&uri)).await.expect("response");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started_at.elapsed() >= wait);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(client.backpressure(&uri), None);
+    }
+
+    #[test]
+    fn shares_a_retry_budget() {
+        let client = client();
+        for _ in 0..RATE_LIMIT_RETRY_BUDGET {
+            assert!(client.take_retry());
         }
+        assert!(!client.take_retry());
 
-        None
+        // until the retries are older than the interval
+        client.backpressure.lock().retries.pop_front();
+        assert!(client.take_retry());
+        assert!(!client.take_retry());
     }
 }
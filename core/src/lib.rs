@@ -6,36 +6,49 @@ use librespot_protocol as protocol;
 #[macro_use]
 mod component;
 
+pub mod anomaly;
 pub mod apresolve;
 pub mod audio_key;
 pub mod authentication;
 pub mod cache;
 pub mod cdn_url;
 pub mod channel;
+pub mod client_token;
+pub mod coalesce;
+pub mod companion;
 pub mod config;
 mod connection;
+pub mod credential_store;
 pub mod date;
 #[allow(dead_code)]
 mod dealer;
+pub mod diagnostics;
 #[doc(hidden)]
 pub mod diffie_hellman;
 pub mod error;
 pub mod file_id;
+pub mod hashcash;
 pub mod http_client;
 pub mod mercury;
+pub mod metrics;
 pub mod packet;
 mod proxytunnel;
 pub mod session;
+pub mod session_manager;
 mod socket;
 #[allow(dead_code)]
 pub mod spclient;
 pub mod spotify_id;
+pub mod state;
+pub mod supervisor;
+mod tls;
 pub mod token;
 #[doc(hidden)]
 pub mod util;
 pub mod version;
 
 pub use config::SessionConfig;
+pub use connection::resolver;
 pub use error::Error;
 pub use file_id::FileId;
 pub use session::Session;
@@ -3,6 +3,7 @@ use std::{
     future::Future,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use byteorder::{BigEndian, ByteOrder};
@@ -10,8 +11,17 @@ use bytes::Bytes;
 use futures_util::FutureExt;
 use protobuf::Message;
 use tokio::sync::{mpsc, oneshot};
-
-use crate::{packet::PacketType, protocol, util::SeqGenerator, Error};
+use tracing::{debug_span, Span};
+
+use crate::{
+    coalesce::{Coalesced, InFlight},
+    metrics::Counter,
+    packet::PacketType,
+    protocol,
+    supervisor::Backoff,
+    util::SeqGenerator,
+    Error,
+};
 
 mod types;
 pub use self::types::*;
@@ -23,26 +33,127 @@ component! {
     MercuryManager : MercuryManagerInner {
         sequence: SeqGenerator<u64> = SeqGenerator::new(0),
         pending: HashMap<Vec<u8>, MercuryPending> = HashMap::new(),
-        subscriptions: Vec<(String, mpsc::UnboundedSender<MercuryResponse>)> = Vec::new(),
+        subscriptions: Vec<(String, Subscriber)> = Vec::new(),
+        // The URIs that were subscribed to, to renew the subscriptions after reconnecting.
+        subscribed_uris: Vec<(String, Subscriber)> = Vec::new(),
+        // The `GET` requests in flight by URI, which are shared by everyone requesting them.
+        in_flight: InFlight<String, MercuryResponse> = InFlight::default(),
+        // Counts the reconnects, so that renewing the subscriptions for an earlier one stops.
+        resubscriptions: u64 = 0,
         invalid: bool = false,
     }
 }
 
+const RESUBSCRIBE_TRIES: usize = 5;
+const RESUBSCRIBE_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RESUBSCRIBE_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// What is received from [`MercuryManager::subscribe_events`] and
+/// [`MercuryManager::listen_for_events`].
+#[derive(Debug, Clone)]
+pub enum SubscriptionEvent {
+    Message(MercuryResponse),
+    /// The connection was lost and the subscription is in place again. The events of the
+    /// meantime were missed, so what they would have updated should be fetched again.
+    Gap,
+    /// The connection was lost and the subscription could not be renewed, so no more events
+    /// arrive. The channel is closed after this.
+    Ended,
+}
+
+// How renewing a subscription after reconnecting turned out.
+#[derive(Debug, PartialEq, Eq)]
+enum Resubscribed {
+    Renewed,
+    Failed,
+    // Nobody is subscribed anymore, the session was shut down, or it reconnected again and
+    // the subscription is renewed for that connection instead.
+    Abandoned,
+}
+
+#[derive(Clone)]
+enum Subscriber {
+    Responses(mpsc::UnboundedSender<MercuryResponse>),
+    Events(mpsc::UnboundedSender<SubscriptionEvent>),
+}
+
+impl Subscriber {
+    // Returns whether the subscriber is still there.
+    fn send(&self, response: MercuryResponse) -> bool {
+        match self {
+            Self::Responses(tx) => tx.send(response).is_ok(),
+            Self::Events(tx) => tx.send(SubscriptionEvent::Message(response)).is_ok(),
+        }
+    }
+
+    fn send_gap(&self) {
+        if let Self::Events(tx) = self {
+            let _ = tx.send(SubscriptionEvent::Gap);
+        }
+    }
+
+    fn send_ended(&self) {
+        if let Self::Events(tx) = self {
+            let _ = tx.send(SubscriptionEvent::Ended);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Responses(tx) => tx.is_closed(),
+            Self::Events(tx) => tx.is_closed(),
+        }
+    }
+
+    fn same_channel(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Responses(tx), Self::Responses(other)) => tx.same_channel(other),
+            (Self::Events(tx), Self::Events(other)) => tx.same_channel(other),
+            _ => false,
+        }
+    }
+}
+
 pub struct MercuryPending {
     parts: Vec<Vec<u8>>,
     partial: Option<Vec<u8>>,
     callback: Option<oneshot::Sender<Result<MercuryResponse, Error>>>,
 }
 
+/// Resolves to the response of a Mercury request. Dropping it cancels the request, so that
+/// a response arriving later is ignored. The request is traced with a `mercury_request` span
+/// that lasts until the future is dropped.
 pub struct MercuryFuture<T> {
     receiver: oneshot::Receiver<Result<T, Error>>,
+    manager: MercuryManager,
+    seq: Vec<u8>,
+    span: Span,
 }
 
 impl<T> Future for MercuryFuture<T> {
     type Output = Result<T, Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.receiver.poll_unpin(cx)?
+        let this = &mut *self;
+        let _entered = this.span.enter();
+        this.receiver.poll_unpin(cx)?
+    }
+}
+
+impl<T> Drop for MercuryFuture<T> {
+    fn drop(&mut self) {
+        // A no-op if the response was already dispatched.
+        let seq = &self.seq;
+        self.manager.lock(|inner| inner.pending.remove(seq));
+    }
+}
+
+impl MercuryManagerInner {
+    // Subscriptions whose receiver was dropped are otherwise only removed when an event
+    // for them arrives.
+    fn prune_subscriptions(&mut self) {
+        self.subscriptions.retain(|(_, tx)| !tx.is_closed());
+        self.subscribed_uris.retain(|(_, tx)| !tx.is_closed());
     }
 }
 
@@ -69,19 +180,40 @@ impl MercuryManager {
             }
         });
 
+        let span = debug_span!(
+            "mercury_request",
+            method = ?req.method,
+            uri = %req.uri,
+            seq = %hex::encode(&seq),
+        );
+
+        // Removes the pending request again if sending it fails.
+        let future = MercuryFuture {
+            receiver: rx,
+            manager: self.clone(),
+            seq: seq.clone(),
+            span,
+        };
+
         let cmd = req.method.command();
         let data = req.encode(&seq)?;
 
         self.session().send_packet(cmd, data)?;
-        Ok(MercuryFuture { receiver: rx })
+        Ok(future)
     }
 
-    pub fn get<T: Into<String>>(&self, uri: T) -> Result<MercuryFuture<MercuryResponse>, Error> {
-        self.request(MercuryRequest {
-            method: MercuryMethod::Get,
-            uri: uri.into(),
-            content_type: None,
-            payload: Vec::new(),
+    /// Requests `uri`, or joins the request for it that is already in flight, so that both
+    /// callers get the same response.
+    pub fn get<T: Into<String>>(&self, uri: T) -> Result<Coalesced<MercuryResponse>, Error> {
+        let uri = uri.into();
+        let in_flight = self.lock(|inner| inner.in_flight.clone());
+        in_flight.join_or_start(uri.clone(), || {
+            self.request(MercuryRequest {
+                method: MercuryMethod::Get,
+                uri,
+                content_type: None,
+                payload: Vec::new(),
+            })
         })
     }
 
@@ -102,12 +234,38 @@ impl MercuryManager {
         MercurySender::new(self.clone(), uri.into())
     }
 
+    /// Subscribes to events for `uri`. Dropping the returned receiver ends the subscription.
+    /// The subscription is renewed after reconnecting, but the events of the meantime are
+    /// missed without notice, see [`subscribe_events`](Self::subscribe_events). When it
+    /// cannot be renewed, the receiver is closed.
     pub fn subscribe<T: Into<String>>(
         &self,
         uri: T,
     ) -> impl Future<Output = Result<mpsc::UnboundedReceiver<MercuryResponse>, Error>> + 'static
     {
-        let uri = uri.into();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribe_with(uri.into(), Subscriber::Responses(tx))
+            .map(|result| result.map(|_| rx))
+    }
+
+    /// Like [`subscribe`](Self::subscribe), with a [`SubscriptionEvent::Gap`] once the
+    /// subscription was renewed after reconnecting, or a [`SubscriptionEvent::Ended`] when
+    /// it could not be renewed.
+    pub fn subscribe_events<T: Into<String>>(
+        &self,
+        uri: T,
+    ) -> impl Future<Output = Result<mpsc::UnboundedReceiver<SubscriptionEvent>, Error>> + 'static
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribe_with(uri.into(), Subscriber::Events(tx))
+            .map(|result| result.map(|_| rx))
+    }
+
+    fn subscribe_with(
+        &self,
+        uri: String,
+        tx: Subscriber,
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
         let request = self.request(MercuryRequest {
             method: MercuryMethod::Sub,
             uri: uri.clone(),
@@ -119,10 +277,10 @@ impl MercuryManager {
         async move {
             let response = request?.await?;
 
-            let (tx, rx) = mpsc::unbounded_channel();
-
             manager.lock(move |inner| {
                 if !inner.invalid {
+                    inner.prune_subscriptions();
+                    inner.subscribed_uris.push((uri.clone(), tx.clone()));
                     debug!("subscribed uri={} count={}", uri, response.payload.len());
                     if !response.payload.is_empty() {
                         // Old subscription protocol, watch the provided list of URIs
@@ -147,7 +305,7 @@ impl MercuryManager {
                 }
             });
 
-            Ok(rx)
+            Ok(())
         }
     }
 
@@ -155,24 +313,37 @@ impl MercuryManager {
         &self,
         uri: T,
     ) -> impl Future<Output = mpsc::UnboundedReceiver<MercuryResponse>> + 'static {
-        let uri = uri.into();
-
-        let manager = self.clone();
-        async move {
-            let (tx, rx) = mpsc::unbounded_channel();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.listen_with(uri.into(), Subscriber::Responses(tx));
+        async move { rx }
+    }
 
-            manager.lock(move |inner| {
-                if !inner.invalid {
-                    debug!("listening to uri={}", uri);
-                    inner.subscriptions.push((uri, tx));
-                }
-            });
+    /// Like [`listen_for`](Self::listen_for), with a [`SubscriptionEvent::Gap`] after
+    /// reconnecting.
+    pub fn listen_for_events<T: Into<String>>(
+        &self,
+        uri: T,
+    ) -> impl Future<Output = mpsc::UnboundedReceiver<SubscriptionEvent>> + 'static {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.listen_with(uri.into(), Subscriber::Events(tx));
+        async move { rx }
+    }
 
-            rx
-        }
+    fn listen_with(&self, uri: String, tx: Subscriber) {
+        self.lock(move |inner| {
+            if !inner.invalid {
+                inner.prune_subscriptions();
+                debug!("listening to uri={}", uri);
+                inner.subscriptions.push((uri, tx));
+            }
+        });
     }
 
     pub(crate) fn dispatch(&self, cmd: PacketType, mut data: Bytes) -> Result<(), Error> {
+        self.session()
+            .metrics()
+            .increment(Counter::MetadataBytesDownloaded, data.len() as u64);
+
         let seq_len = BigEndian::read_u16(data.split_to(2).as_ref()) as usize;
         let seq = data.split_to(seq_len).as_ref().to_owned();
 
@@ -268,7 +439,7 @@ impl MercuryManager {
 
                         // if send fails, remove from list of subs
                         // TODO: send unsub message
-                        sub.send(response.clone()).is_ok()
+                        sub.send(response.clone())
                     } else {
                         // URI doesn't match
                         true
@@ -292,12 +463,305 @@ impl MercuryManager {
         }
     }
 
+    /// Subscribes again to all URIs that still have subscribers, because the access point
+    /// forgets the subscriptions when the connection is lost. Those who asked for it are told
+    /// about the gap once their subscription is renewed, or at once when they only listen.
+    /// Subscriptions that cannot be renewed are ended, which closes their channels.
+    pub(crate) fn resubscribe(&self) {
+        let (generation, mut uris, listeners) = self.lock(|inner| {
+            inner.prune_subscriptions();
+            inner.resubscriptions += 1;
+            let uris: Vec<String> = inner
+                .subscribed_uris
+                .iter()
+                .map(|(uri, _)| uri.clone())
+                .collect();
+            let listeners: Vec<Subscriber> = inner
+                .subscriptions
+                .iter()
+                .map(|(_, sub)| sub)
+                .filter(|sub| {
+                    !inner
+                        .subscribed_uris
+                        .iter()
+                        .any(|(_, subscribed)| subscribed.same_channel(sub))
+                })
+                .cloned()
+                .collect();
+            (inner.resubscriptions, uris, listeners)
+        });
+        uris.sort();
+        uris.dedup();
+
+        for listener in listeners {
+            listener.send_gap();
+        }
+
+        for uri in uris {
+            let manager = self.clone();
+            self.session().spawn(async move {
+                match manager.resubscribe_uri(&uri, generation).await {
+                    Resubscribed::Renewed => manager.lock(|inner| {
+                        for (subscribed_uri, sub) in &inner.subscribed_uris {
+                            if *subscribed_uri == uri {
+                                sub.send_gap();
+                            }
+                        }
+                    }),
+                    Resubscribed::Failed => manager.end_subscription(&uri),
+                    Resubscribed::Abandoned => (),
+                }
+            });
+        }
+    }
+
+    // Renews the subscription to `uri` for the reconnect `generation`, trying again with
+    // backoff.
+    async fn resubscribe_uri(&self, uri: &str, generation: u64) -> Resubscribed {
+        let mut backoff = Backoff::new(RESUBSCRIBE_INITIAL_DELAY, RESUBSCRIBE_MAX_DELAY);
+        for tries in 1..=RESUBSCRIBE_TRIES {
+            let request = self.request(MercuryRequest {
+                method: MercuryMethod::Sub,
+                uri: uri.to_owned(),
+                content_type: None,
+                payload: Vec::new(),
+            });
+            match async { request?.await }.await {
+                Ok(_) => {
+                    debug!("resubscribed uri={}", uri);
+                    return Resubscribed::Renewed;
+                }
+                Err(e) => warn!(
+                    "could not resubscribe to {} ({}/{}): {}",
+                    uri, tries, RESUBSCRIBE_TRIES, e
+                ),
+            }
+            if tries == RESUBSCRIBE_TRIES {
+                break;
+            }
+
+            tokio::time::sleep(backoff.next_delay()).await;
+            let abandoned = self.lock(|inner| {
+                inner.prune_subscriptions();
+                inner.invalid
+                    || inner.resubscriptions != generation
+                    || !inner.subscribed_uris.iter().any(|(sub, _)| sub == uri)
+            });
+            if abandoned {
+                return Resubscribed::Abandoned;
+            }
+        }
+
+        let superseded = self.lock(|inner| inner.invalid || inner.resubscriptions != generation);
+        if superseded {
+            Resubscribed::Abandoned
+        } else {
+            Resubscribed::Failed
+        }
+    }
+
+    // Drops the subscribers of `uri`, which closes their channels, after telling those who
+    // asked for it.
+    fn end_subscription(&self, uri: &str) {
+        error!("gave up resubscribing to {}", uri);
+        self.lock(|inner| {
+            let (ended, kept) = inner
+                .subscribed_uris
+                .drain(..)
+                .partition::<Vec<_>, _>(|(subscribed_uri, _)| subscribed_uri == uri);
+            inner.subscribed_uris = kept;
+            for (_, sub) in &ended {
+                sub.send_ended();
+                // also the URIs that the old subscription protocol watches for it
+                inner
+                    .subscriptions
+                    .retain(|(_, subscriber)| !subscriber.same_channel(sub));
+            }
+        });
+    }
+
+    /// Fails the requests that are waiting for a response, which won't arrive after the
+    /// connection was lost. The subscriptions are kept.
+    pub(crate) fn fail_pending(&self) {
+        self.lock(|inner| inner.pending.clear());
+    }
+
     pub(crate) fn shutdown(&self) {
         self.lock(|inner| {
             inner.invalid = true;
             // destroy the sending halves of the channels to signal everyone who is waiting for something.
             inner.pending.clear();
             inner.subscriptions.clear();
+            inner.subscribed_uris.clear();
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{config::SessionConfig, Session};
+
+    // Answers the next request that was sent with `status_code`.
+    async fn respond(
+        session: &Session,
+        packets: &mut mpsc::UnboundedReceiver<(u8, Vec<u8>)>,
+        status_code: i32,
+    ) {
+        let (_, request) = packets.recv().await.expect("request");
+        let mut request = Bytes::from(request);
+        let seq_len = BigEndian::read_u16(&request.split_to(2)) as usize;
+        let seq = request.split_to(seq_len);
+
+        let mut header = protocol::mercury::Header::new();
+        header.set_uri("hm://test".to_owned());
+        header.set_status_code(status_code);
+        let header = header.write_to_bytes().expect("header");
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&(seq.len() as u16).to_be_bytes());
+        response.extend_from_slice(&seq);
+        response.push(1); // final
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&(header.len() as u16).to_be_bytes());
+        response.extend_from_slice(&header);
+
+        // errors are only logged
+        let _ = session
+            .mercury()
+            .dispatch(PacketType::MercurySub, response.into());
+    }
+
+    async fn subscribed(
+        session: &Session,
+        packets: &mut mpsc::UnboundedReceiver<(u8, Vec<u8>)>,
+    ) -> (
+        mpsc::UnboundedReceiver<SubscriptionEvent>,
+        mpsc::UnboundedReceiver<MercuryResponse>,
+    ) {
+        let events = session.mercury().subscribe_events("hm://test");
+        respond(session, packets, 200).await;
+        let events = events.await.expect("subscribed");
+        let responses = session.mercury().subscribe("hm://test");
+        respond(session, packets, 200).await;
+        (events, responses.await.expect("subscribed"))
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_is_removed() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+
+        let request = session.mercury().get("hm://test").unwrap();
+        let result = tokio::time::timeout(Duration::from_millis(10), request).await;
+
+        assert!(result.is_err());
+        assert!(packets.try_recv().is_ok());
+        assert!(session.mercury().lock(|inner| inner.pending.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn failed_request_is_removed() {
+        // not connected, so that sending fails
+        let session = Session::new(SessionConfig::default(), None);
+
+        assert!(session.mercury().get("hm://test").is_err());
+        assert!(session.mercury().lock(|inner| inner.pending.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn late_response_to_a_cancelled_request_is_ignored() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+
+        drop(session.mercury().get("hm://test").unwrap());
+        respond(&session, &mut packets, 200).await;
+
+        let request = session.mercury().get("hm://test").unwrap();
+        respond(&session, &mut packets, 404).await;
+        assert!(request.await.is_err());
+        assert!(session.mercury().lock(|inner| inner.pending.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn dropped_subscriptions_are_pruned() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+
+        drop(subscribed(&session, &mut packets).await);
+        let _subscribed = subscribed(&session, &mut packets).await;
+        assert_eq!(session.mercury().lock(|inner| inner.subscriptions.len()), 2);
+    }
+
+    #[tokio::test]
+    async fn listeners_are_told_about_gaps() {
+        let session = Session::new(SessionConfig::default(), None);
+        let _packets = session.connect_for_testing();
+
+        let mut events = session.mercury().listen_for_events("hm://test").await;
+        let mut responses = session.mercury().listen_for("hm://test").await;
+        session.mercury().resubscribe();
+
+        assert!(matches!(events.try_recv(), Ok(SubscriptionEvent::Gap)));
+        assert!(responses.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriptions_are_renewed() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+        let (mut events, _responses) = subscribed(&session, &mut packets).await;
+
+        session.mercury().resubscribe();
+        // the first try fails, the second one renews the subscription
+        respond(&session, &mut packets, 503).await;
+        respond(&session, &mut packets, 200).await;
+
+        assert!(matches!(events.recv().await, Some(SubscriptionEvent::Gap)));
+        assert!(packets.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriptions_that_cannot_be_renewed_end() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+        let (mut events, mut responses) = subscribed(&session, &mut packets).await;
+
+        session.mercury().resubscribe();
+        for _ in 0..RESUBSCRIBE_TRIES {
+            respond(&session, &mut packets, 503).await;
+        }
+
+        assert!(matches!(
+            events.recv().await,
+            Some(SubscriptionEvent::Ended)
+        ));
+        assert!(events.recv().await.is_none());
+        assert!(responses.recv().await.is_none());
+        assert!(session
+            .mercury()
+            .lock(|inner| inner.subscriptions.is_empty() && inner.subscribed_uris.is_empty()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnecting_again_stops_renewing_for_the_earlier_connection() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+        let (mut events, _responses) = subscribed(&session, &mut packets).await;
+
+        session.mercury().resubscribe();
+        respond(&session, &mut packets, 503).await;
+        // reconnected while the first renewal waits to try again
+        session.mercury().resubscribe();
+        respond(&session, &mut packets, 200).await;
+        assert!(matches!(events.recv().await, Some(SubscriptionEvent::Gap)));
+
+        tokio::time::sleep(RESUBSCRIBE_MAX_DELAY * RESUBSCRIBE_TRIES as u32).await;
+        assert!(packets.try_recv().is_err());
+        assert!(events.try_recv().is_err());
+    }
+}
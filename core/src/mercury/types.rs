@@ -4,7 +4,11 @@ use byteorder::{BigEndian, WriteBytesExt};
 use protobuf::Message;
 use thiserror::Error;
 
-use crate::{packet::PacketType, protocol, Error};
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    packet::PacketType,
+    protocol, Error,
+};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum MercuryMethod {
@@ -42,9 +46,19 @@ pub enum MercuryError {
 impl From<MercuryError> for Error {
     fn from(err: MercuryError) -> Self {
         match err {
-            MercuryError::Channel => Error::aborted(err),
-            MercuryError::Command(_) => Error::unimplemented(err),
-            MercuryError::Response(_) => Error::unavailable(err),
+            MercuryError::Channel => Error::with_code(ErrorKind::Aborted, err),
+            MercuryError::Command(_) => Error::with_code(ErrorKind::Unimplemented, err),
+            MercuryError::Response(_) => Error::with_code(ErrorKind::Unavailable, err),
+        }
+    }
+}
+
+impl ErrorCode for MercuryError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Channel => "mercury.channel",
+            Self::Command(_) => "mercury.command",
+            Self::Response(_) => "mercury.response",
         }
     }
 }
@@ -0,0 +1,290 @@
+//! Counters and gauges of a session and the players using it, for applications to export to
+//! their monitoring instead of scraping the logs, see [`Session::metrics`]. The transfers are
+//! also summed up in [`SessionStats`], for applications that only show them.
+//!
+//! [`Session::metrics`]: crate::session::Session::metrics
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Mutex, RwLock};
+use url::Url;
+
+// the throughput is averaged over this window, in slots of `THROUGHPUT_SLOT`
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+const THROUGHPUT_SLOT: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Counter {
+    /// Bytes of audio files downloaded from the CDN.
+    BytesDownloaded,
+    /// Bytes of metadata and other responses received from Mercury and spclient.
+    MetadataBytesDownloaded,
+    /// Audio files opened from the cache.
+    CacheHits,
+    /// Audio files that were not cached and had to be downloaded.
+    CacheMisses,
+    /// Attempts to connect to the access point again, after the connection was lost or the
+    /// access point asked to try another one.
+    Reconnects,
+    /// Reads of audio files that had to wait for data to be downloaded.
+    Underruns,
+    /// Packets that could not be decoded, skipping the rest of the track.
+    DecodeErrors,
+}
+
+impl Counter {
+    /// A name for exporters, in `snake_case`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BytesDownloaded => "bytes_downloaded",
+            Self::MetadataBytesDownloaded => "metadata_bytes_downloaded",
+            Self::CacheHits => "cache_hits",
+            Self::CacheMisses => "cache_misses",
+            Self::Reconnects => "reconnects",
+            Self::Underruns => "underruns",
+            Self::DecodeErrors => "decode_errors",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Gauge {
+    /// The share of audio files opened from the cache, from `0.0` to `1.0`.
+    CacheHitRate,
+}
+
+impl Gauge {
+    /// A name for exporters, in `snake_case`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CacheHitRate => "cache_hit_rate",
+        }
+    }
+}
+
+/// Receives the metrics as they change. It is called from the threads and tasks where they
+/// change, so it should return quickly.
+pub trait MetricsSink: Send + Sync {
+    /// Adds `value` to `counter`.
+    fn increment(&self, counter: Counter, value: u64);
+    /// Sets `gauge` to `value`.
+    fn set(&self, gauge: Gauge, value: f64);
+}
+
+/// A snapshot of the transfers of a session, see [`Session::stats`].
+///
+/// [`Session::stats`]: crate::session::Session::stats
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Bytes of audio files downloaded from the CDN.
+    pub audio_bytes: u64,
+    /// Bytes of metadata and other responses received from Mercury and spclient.
+    pub metadata_bytes: u64,
+    /// Bytes per second of audio and metadata, over the last few seconds.
+    pub throughput: u64,
+    /// The CDN hosts that audio files are being downloaded from, sorted.
+    pub cdn_hosts: Vec<String>,
+}
+
+#[derive(Default)]
+struct MetricsInner {
+    sink: RwLock<Option<Arc<dyn MetricsSink>>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    audio_bytes: AtomicU64,
+    metadata_bytes: AtomicU64,
+    // bytes received per slot, oldest first
+    throughput: Mutex<VecDeque<(Instant, u64)>>,
+    // the number of downloads from each host
+    cdn_hosts: Mutex<HashMap<String, usize>>,
+}
+
+impl MetricsInner {
+    fn record_transfer(&self, total: &AtomicU64, bytes: u64) {
+        total.fetch_add(bytes, Ordering::Relaxed);
+
+        let now = Instant::now();
+        let mut slots = self.throughput.lock();
+        prune_slots(&mut slots, now);
+        match slots.back_mut() {
+            Some((start, slot)) if now.duration_since(*start) < THROUGHPUT_SLOT => *slot += bytes,
+            _ => slots.push_back((now, bytes)),
+        }
+    }
+}
+
+fn prune_slots(slots: &mut VecDeque<(Instant, u64)>, now: Instant) {
+    while matches!(slots.front(), Some((start, _)) if now.duration_since(*start) > THROUGHPUT_WINDOW)
+    {
+        slots.pop_front();
+    }
+}
+
+/// Passes the metrics of a session on to its [`MetricsSink`], and derives the gauges from
+/// the counters. Metrics are dropped while there is no sink.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<MetricsInner>);
+
+impl Metrics {
+    /// Passes the metrics on to `sink` from now on. Replaces the previous sink.
+    pub fn set_sink(&self, sink: Arc<dyn MetricsSink>) {
+        *self.0.sink.write() = Some(sink);
+    }
+
+    pub fn remove_sink(&self) {
+        *self.0.sink.write() = None;
+    }
+
+    pub fn increment(&self, counter: Counter, value: u64) {
+        let hit_rate = match counter {
+            Counter::CacheHits => {
+                let hits = self.0.cache_hits.fetch_add(value, Ordering::AcqRel) + value;
+                Some((hits, self.0.cache_misses.load(Ordering::Acquire)))
+            }
+            Counter::CacheMisses => {
+                let misses = self.0.cache_misses.fetch_add(value, Ordering::AcqRel) + value;
+                Some((self.0.cache_hits.load(Ordering::Acquire), misses))
+            }
+            Counter::BytesDownloaded => {
+                self.0.record_transfer(&self.0.audio_bytes, value);
+                None
+            }
+            Counter::MetadataBytesDownloaded => {
+                self.0.record_transfer(&self.0.metadata_bytes, value);
+                None
+            }
+            _ => None,
+        }
+        .map(|(hits, misses)| hits as f64 / (hits + misses) as f64);
+
+        if let Some(sink) = self.0.sink.read().as_ref() {
+            sink.increment(counter, value);
+            if let Some(hit_rate) = hit_rate.filter(|rate| rate.is_finite()) {
+                sink.set(Gauge::CacheHitRate, hit_rate);
+            }
+        }
+    }
+
+    /// Marks the host of `url` as in use for downloading audio files, until the returned
+    /// guard is dropped.
+    pub fn cdn_download(&self, url: &str) -> CdnDownload {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned));
+        if let Some(host) = &host {
+            *self.0.cdn_hosts.lock().entry(host.clone()).or_default() += 1;
+        }
+        CdnDownload {
+            metrics: self.clone(),
+            host,
+        }
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        let bytes: u64 = {
+            let mut slots = self.0.throughput.lock();
+            prune_slots(&mut slots, Instant::now());
+            slots.iter().map(|(_, bytes)| bytes).sum()
+        };
+
+        let mut cdn_hosts: Vec<_> = self.0.cdn_hosts.lock().keys().cloned().collect();
+        cdn_hosts.sort();
+
+        SessionStats {
+            audio_bytes: self.0.audio_bytes.load(Ordering::Relaxed),
+            metadata_bytes: self.0.metadata_bytes.load(Ordering::Relaxed),
+            throughput: bytes / THROUGHPUT_WINDOW.as_secs(),
+            cdn_hosts,
+        }
+    }
+}
+
+/// A download from a CDN host, see [`Metrics::cdn_download`].
+pub struct CdnDownload {
+    metrics: Metrics,
+    host: Option<String>,
+}
+
+impl Drop for CdnDownload {
+    fn drop(&mut self) {
+        if let Some(host) = self.host.take() {
+            let mut hosts = self.metrics.0.cdn_hosts.lock();
+            if let Some(count) = hosts.get_mut(&host) {
+                *count -= 1;
+                if *count == 0 {
+                    hosts.remove(&host);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<(Counter, u64)>>, Mutex<Vec<f64>>);
+
+    impl MetricsSink for RecordingSink {
+        fn increment(&self, counter: Counter, value: u64) {
+            self.0.lock().push((counter, value));
+        }
+
+        fn set(&self, _: Gauge, value: f64) {
+            self.1.lock().push(value);
+        }
+    }
+
+    #[test]
+    fn cache_hit_rate() {
+        let metrics = Metrics::default();
+        let sink = Arc::new(RecordingSink::default());
+
+        // dropped without a sink, but counted towards the hit rate
+        metrics.increment(Counter::CacheMisses, 1);
+        metrics.set_sink(sink.clone());
+        metrics.increment(Counter::CacheHits, 3);
+        metrics.increment(Counter::BytesDownloaded, 1024);
+
+        assert_eq!(
+            *sink.0.lock(),
+            vec![(Counter::CacheHits, 3), (Counter::BytesDownloaded, 1024)]
+        );
+        assert_eq!(*sink.1.lock(), vec![0.75]);
+    }
+
+    #[test]
+    fn transfer_stats() {
+        let metrics = Metrics::default();
+        metrics.increment(Counter::BytesDownloaded, 8000);
+        metrics.increment(Counter::MetadataBytesDownloaded, 2000);
+
+        let first = metrics.cdn_download("https://audio-b.example.com/audio/1?token=x");
+        let second = metrics.cdn_download("https://audio-a.example.com/audio/2");
+        let third = metrics.cdn_download("https://audio-a.example.com/audio/3");
+
+        let stats = metrics.stats();
+        assert_eq!(stats.audio_bytes, 8000);
+        assert_eq!(stats.metadata_bytes, 2000);
+        assert_eq!(stats.throughput, 2000);
+        assert_eq!(
+            stats.cdn_hosts,
+            vec!["audio-a.example.com", "audio-b.example.com"]
+        );
+
+        drop((first, second));
+        assert_eq!(metrics.stats().cdn_hosts, vec!["audio-a.example.com"]);
+        drop(third);
+        assert!(metrics.stats().cdn_hosts.is_empty());
+    }
+}
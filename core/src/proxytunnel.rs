@@ -1,21 +1,36 @@
-use std::io;
+use std::{collections::HashMap, fmt::Write as _, io, net::SocketAddr};
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::Engine as _;
+use md5::Md5;
+use rand::Rng;
+use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// The outcome of a CONNECT request to an HTTP proxy.
+pub enum ProxyConnect<T> {
+    Connected(T),
+    /// The proxy requires authentication, with the challenges of its `Proxy-Authenticate`
+    /// headers.
+    AuthenticationRequired(Vec<String>),
+}
+
 pub async fn proxy_connect<T: AsyncRead + AsyncWrite + Unpin>(
     mut proxy_connection: T,
     connect_host: &str,
     connect_port: &str,
-) -> io::Result<T> {
-    let mut buffer = Vec::new();
-    buffer.extend_from_slice(b"CONNECT ");
-    buffer.extend_from_slice(connect_host.as_bytes());
-    buffer.push(b':');
-    buffer.extend_from_slice(connect_port.as_bytes());
-    buffer.extend_from_slice(b" HTTP/1.1\r\n\r\n");
+    authorization: Option<&str>,
+) -> io::Result<ProxyConnect<T>> {
+    let target = format!("{connect_host}:{connect_port}");
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(authorization) = authorization {
+        let _ = write!(request, "Proxy-Authorization: {authorization}\r\n");
+    }
+    request.push_str("\r\n");
 
-    proxy_connection.write_all(buffer.as_ref()).await?;
+    proxy_connection.write_all(request.as_bytes()).await?;
 
+    let mut buffer = request.into_bytes();
     buffer.resize(buffer.capacity(), 0);
 
     let mut offset = 0;
@@ -35,7 +50,17 @@ pub async fn proxy_connect<T: AsyncRead + AsyncWrite + Unpin>(
 
         if status.is_complete() {
             return match response.code {
-                Some(200) => Ok(proxy_connection), // Proxy says all is well
+                // Proxy says all is well
+                Some(200) => Ok(ProxyConnect::Connected(proxy_connection)),
+                Some(407) => {
+                    let challenges = response
+                        .headers
+                        .iter()
+                        .filter(|header| header.name.eq_ignore_ascii_case("Proxy-Authenticate"))
+                        .map(|header| String::from_utf8_lossy(header.value).into_owned())
+                        .collect();
+                    Ok(ProxyConnect::AuthenticationRequired(challenges))
+                }
                 Some(code) => {
                     let reason = response.reason.unwrap_or("no reason");
                     let msg = format!("Proxy responded with {code}: {reason}");
@@ -53,3 +78,366 @@ pub async fn proxy_connect<T: AsyncRead + AsyncWrite + Unpin>(
         }
     }
 }
+
+/// Answers one of the `challenges` of an HTTP proxy to CONNECT to `target` (`host:port`),
+/// returning the value of the `Proxy-Authorization` header. Digest authentication is
+/// preferred over Basic authentication, which sends the password in the clear.
+pub fn proxy_authorization(
+    challenges: &[String],
+    username: &str,
+    password: &str,
+    target: &str,
+) -> io::Result<String> {
+    let scheme_params = |challenge: &str, scheme: &str| {
+        let (name, params) = challenge.trim().split_once(' ').unwrap_or((challenge, ""));
+        if name.eq_ignore_ascii_case(scheme) {
+            Some(parse_auth_params(params))
+        } else {
+            None
+        }
+    };
+
+    if let Some(params) = challenges
+        .iter()
+        .find_map(|challenge| scheme_params(challenge, "Digest"))
+    {
+        let cnonce = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        digest_authorization(&params, username, password, target, &cnonce)
+    } else if challenges
+        .iter()
+        .any(|challenge| scheme_params(challenge, "Basic").is_some())
+    {
+        let credentials = BASE64.encode(format!("{username}:{password}"));
+        Ok(format!("Basic {credentials}"))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unsupported proxy authentication: {challenges:?}"),
+        ))
+    }
+}
+
+// Parses the `name=value` or `name="quoted value"` parameters of an authentication challenge.
+fn parse_auth_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((name, after)) = rest.split_once('=') {
+        let name = name.trim().to_ascii_lowercase();
+        let after = after.trim_start();
+
+        let (value, after) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut value = String::new();
+            let mut end = quoted.len();
+            let mut escaped = false;
+            for (i, c) in quoted.char_indices() {
+                match c {
+                    _ if escaped => {
+                        value.push(c);
+                        escaped = false;
+                    }
+                    '\\' => escaped = true,
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    _ => value.push(c),
+                }
+            }
+            (value, &quoted[end..])
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].trim().to_owned(), &after[end..])
+        };
+
+        parsed.insert(name, value);
+        rest = after.trim_start().trim_start_matches(',').trim_start();
+    }
+    parsed
+}
+
+// See RFC 7616. Only the `auth` quality of protection is supported, as a CONNECT request has
+// no body to protect anyway.
+fn digest_authorization(
+    params: &HashMap<String, String>,
+    username: &str,
+    password: &str,
+    uri: &str,
+    cnonce: &str,
+) -> io::Result<String> {
+    let unsupported = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unsupported proxy Digest authentication: {what}"),
+        )
+    };
+
+    let realm = params.get("realm").map(String::as_str).unwrap_or_default();
+    let nonce = params
+        .get("nonce")
+        .ok_or_else(|| unsupported("challenge without nonce"))?;
+    let algorithm = params.get("algorithm").map_or("MD5", String::as_str);
+
+    let upper = algorithm.to_ascii_uppercase();
+    let (base_algorithm, session) = match upper.strip_suffix("-SESS") {
+        Some(base_algorithm) => (base_algorithm, true),
+        None => (upper.as_str(), false),
+    };
+    let hash: fn(&str) -> String = match base_algorithm {
+        "MD5" => |data| hex::encode(Md5::digest(data)),
+        "SHA-256" => |data| hex::encode(Sha256::digest(data)),
+        _ => return Err(unsupported(algorithm)),
+    };
+
+    let qop = match params.get("qop") {
+        Some(qop) => Some(
+            qop.split(',')
+                .map(str::trim)
+                .find(|qop| *qop == "auth")
+                .ok_or_else(|| unsupported(qop))?,
+        ),
+        None => None,
+    };
+
+    let mut ha1 = hash(&format!("{username}:{realm}:{password}"));
+    if session {
+        ha1 = hash(&format!("{ha1}:{nonce}:{cnonce}"));
+    }
+    let ha2 = hash(&format!("CONNECT:{uri}"));
+
+    // Every connection is authenticated anew, so the nonce is only ever used once.
+    let nc = "00000001";
+    let response = match qop {
+        Some(qop) => hash(&format!("{ha1}:{nonce}:{nc}:{cnonce}:{qop}:{ha2}")),
+        None => hash(&format!("{ha1}:{nonce}:{ha2}")),
+    };
+
+    let mut authorization = format!(
+        "Digest username=\"{username}\", realm=\"{realm}\", nonce=\"{nonce}\", uri=\"{uri}\", algorithm={algorithm}, response=\"{response}\""
+    );
+    if let Some(qop) = qop {
+        let _ = write!(authorization, ", qop={qop}, nc={nc}, cnonce=\"{cnonce}\"");
+    }
+    if let Some(opaque) = params.get("opaque") {
+        let _ = write!(authorization, ", opaque=\"{opaque}\"");
+    }
+    Ok(authorization)
+}
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS5_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+/// The address to ask a SOCKS5 proxy to connect to: either an address resolved locally, or a
+/// host name for the proxy to resolve (as with `socks5h://` proxies).
+pub enum Socks5Target<'a> {
+    Addr(SocketAddr),
+    Domain(&'a str, u16),
+}
+
+fn socks5_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg.into())
+}
+
+pub async fn socks5_connect<T: AsyncRead + AsyncWrite + Unpin>(
+    mut proxy_connection: T,
+    target: Socks5Target<'_>,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<T> {
+    let method = if credentials.is_some() {
+        SOCKS5_AUTH_PASSWORD
+    } else {
+        SOCKS5_AUTH_NONE
+    };
+    proxy_connection
+        .write_all(&[SOCKS5_VERSION, 1, method])
+        .await?;
+
+    let mut reply = [0u8; 2];
+    proxy_connection.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(socks5_error("Malformed response from SOCKS5 proxy"));
+    }
+    match (reply[1], credentials) {
+        (SOCKS5_AUTH_NONE, _) => (),
+        (SOCKS5_AUTH_PASSWORD, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(socks5_error("SOCKS5 username or password too long"));
+            }
+
+            let mut buffer = vec![0x01, username.len() as u8];
+            buffer.extend_from_slice(username.as_bytes());
+            buffer.push(password.len() as u8);
+            buffer.extend_from_slice(password.as_bytes());
+            proxy_connection.write_all(&buffer).await?;
+
+            proxy_connection.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(socks5_error("SOCKS5 proxy rejected the credentials"));
+            }
+        }
+        (SOCKS5_AUTH_UNACCEPTABLE, _) => {
+            return Err(socks5_error(
+                "SOCKS5 proxy does not accept any of the offered authentication methods",
+            ))
+        }
+        (method, _) => {
+            return Err(socks5_error(format!(
+                "SOCKS5 proxy selected unsupported authentication method {method}"
+            )))
+        }
+    }
+
+    let mut buffer = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00];
+    let port = match target {
+        Socks5Target::Addr(SocketAddr::V4(addr)) => {
+            buffer.push(SOCKS5_ATYP_IPV4);
+            buffer.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Socks5Target::Addr(SocketAddr::V6(addr)) => {
+            buffer.push(SOCKS5_ATYP_IPV6);
+            buffer.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Socks5Target::Domain(host, port) => {
+            if host.len() > 255 {
+                return Err(socks5_error("Host name too long for SOCKS5"));
+            }
+            buffer.push(SOCKS5_ATYP_DOMAIN);
+            buffer.push(host.len() as u8);
+            buffer.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    buffer.extend_from_slice(&port.to_be_bytes());
+    proxy_connection.write_all(&buffer).await?;
+
+    let mut header = [0u8; 4];
+    proxy_connection.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(socks5_error("Malformed response from SOCKS5 proxy"));
+    }
+    if header[1] != 0x00 {
+        let reason = match header[1] {
+            0x01 => "general failure",
+            0x02 => "connection not allowed by ruleset",
+            0x03 => "network unreachable",
+            0x04 => "host unreachable",
+            0x05 => "connection refused",
+            0x06 => "TTL expired",
+            0x07 => "command not supported",
+            0x08 => "address type not supported",
+            _ => "unknown error",
+        };
+        return Err(socks5_error(format!(
+            "SOCKS5 proxy responded with {}: {reason}",
+            header[1]
+        )));
+    }
+
+    // Skip the address the proxy bound to, followed by its port.
+    let bound_len = match header[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            proxy_connection.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(socks5_error("Malformed response from SOCKS5 proxy")),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    proxy_connection.read_exact(&mut bound).await?;
+
+    Ok(proxy_connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest() {
+        let params = parse_auth_params(
+            r#"realm="proxy", qop="auth,auth-int", nonce="dcd98b7102dd2f0e", opaque="5ccc""#,
+        );
+        let authorization =
+            digest_authorization(&params, "user", "pass", "ap.spotify.com:443", "0a4f113b")
+                .unwrap();
+        assert_eq!(
+            authorization,
+            "Digest username=\"user\", realm=\"proxy\", nonce=\"dcd98b7102dd2f0e\", \
+             uri=\"ap.spotify.com:443\", algorithm=MD5, response=\"5651f54e15cba28be8aefbb73613715c\", \
+             qop=auth, nc=00000001, cnonce=\"0a4f113b\", opaque=\"5ccc\""
+        );
+
+        let basic = proxy_authorization(
+            &[r#"Basic realm="proxy""#.to_owned()],
+            "user",
+            "pass",
+            "ap.spotify.com:443",
+        )
+        .unwrap();
+        assert_eq!(basic, "Basic dXNlcjpwYXNz");
+    }
+
+    #[tokio::test]
+    async fn socks5_with_password() {
+        let (client, mut proxy) = tokio::io::duplex(256);
+
+        let server = tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            proxy.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0u8; 11];
+            proxy.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            proxy.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0u8; 21];
+            proxy.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x0eap.spotify.com\x01\xbb");
+            proxy
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x04, 0x38])
+                .await
+                .unwrap();
+        });
+
+        socks5_connect(
+            client,
+            Socks5Target::Domain("ap.spotify.com", 443),
+            Some(("user", "pass")),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_refused() {
+        let (client, mut proxy) = tokio::io::duplex(256);
+
+        let server = tokio::spawn(async move {
+            let mut buffer = [0u8; 3];
+            proxy.read_exact(&mut buffer).await.unwrap();
+            proxy.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            proxy.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[3], SOCKS5_ATYP_IPV4);
+            proxy.write_all(&[5, 5, 0, 1]).await.unwrap();
+        });
+
+        let target = Socks5Target::Addr(([127, 0, 0, 1], 4070).into());
+        let err = socks5_connect(client, target, None).await.unwrap_err();
+        assert!(err.to_string().contains("connection refused"));
+        server.await.unwrap();
+    }
+}
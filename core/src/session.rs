@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    fmt,
     future::Future,
     io,
     pin::Pin,
@@ -12,32 +13,59 @@ use std::{
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
 use futures_core::TryStream;
-use futures_util::{future, ready, StreamExt, TryStreamExt};
+use futures_util::{ready, FutureExt, StreamExt, TryStreamExt};
 use num_traits::FromPrimitive;
 use once_cell::sync::OnceCell;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use quick_xml::events::Event;
+use rand::Rng;
 use thiserror::Error;
-use tokio::{sync::mpsc, time::Instant};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{info_span, Instrument};
 
 use crate::{
-    apresolve::ApResolver,
+    apresolve::{ApResolver, SocketAddress},
     audio_key::AudioKeyManager,
-    authentication::Credentials,
+    authentication::{self, Credentials},
     cache::Cache,
     channel::ChannelManager,
+    client_token::ClientTokenProvider,
     config::SessionConfig,
-    connection::{self, AuthenticationError},
+    connection::{self, AuthenticationError, Transport},
+    date::Date,
+    dealer::Dealer,
+    error::{self, ErrorKind, Recovery},
     http_client::HttpClient,
     mercury::MercuryManager,
+    metrics::{Counter, Metrics, SessionStats},
     packet::PacketType,
     protocol::keyexchange::ErrorCode,
     spclient::SpClient,
-    token::TokenProvider,
+    state::{StateEvent, StateStore},
+    supervisor::Backoff,
+    token::{Token, TokenProvider},
     Error,
 };
 
+// The delay before reconnecting doubles with every failed attempt up to the maximum.
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+// How far the system clock may be off before the user is told about it.
+const CLOCK_SKEW_WARNING_SECS: i64 = 60;
+
+// Packets to send to the access point, by command.
+type PacketSender = mpsc::UnboundedSender<(u8, Vec<u8>)>;
+
+/// How long [`Session::shutdown_gracefully`] waits for the connection to the access point to be
+/// closed.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Debug, Error)]
 pub enum SessionError {
     #[error(transparent)]
@@ -53,16 +81,89 @@ pub enum SessionError {
 impl From<SessionError> for Error {
     fn from(err: SessionError) -> Self {
         match err {
-            SessionError::AuthenticationError(_) => Error::unauthenticated(err),
-            SessionError::IoError(_) => Error::unavailable(err),
-            SessionError::NotConnected => Error::unavailable(err),
-            SessionError::Packet(_) => Error::unimplemented(err),
+            SessionError::AuthenticationError(_) => {
+                Error::with_code(ErrorKind::Unauthenticated, err)
+            }
+            SessionError::IoError(_) => Error::with_code(ErrorKind::Unavailable, err),
+            SessionError::NotConnected => Error::with_code(ErrorKind::Unavailable, err),
+            SessionError::Packet(_) => Error::with_code(ErrorKind::Unimplemented, err),
+        }
+    }
+}
+
+impl error::ErrorCode for SessionError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::AuthenticationError(err) => err.code(),
+            Self::IoError(_) => "session.io",
+            Self::NotConnected => "session.not_connected",
+            Self::Packet(_) => "session.packet",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            (
+                Self::AuthenticationError(AuthenticationError::LoginFailed(code)),
+                ErrorKind::Unauthenticated,
+            ) => Some(connection::login_recovery(code)),
+            _ => None,
         }
     }
 }
 
 pub type UserAttributes = HashMap<String, String>;
 
+/// The lifecycle of a session, as received from [`Session::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The session is connected to the access point `host:port` and authenticated.
+    Connected {
+        username: String,
+        access_point: String,
+    },
+    /// The session is connecting to the access point `host:port` again, because the
+    /// previous one asked to try another one or the connection was lost.
+    Reconnecting { access_point: String },
+    /// The spclient access point changed, e.g. after the previous one became unreachable or
+    /// the country of the user changed.
+    AccessPointSwitched { access_point: String },
+    /// A new access token was fetched, because there was none in these scopes or it expired.
+    TokenRefreshed { scopes: Vec<String> },
+    /// The connection to the access point `host:port` is considered dead, because a ping or
+    /// the acknowledgement of a pong didn't come in time, see [`SessionConfig::keep_alive`].
+    /// `Disconnected` follows.
+    ConnectionDead {
+        access_point: String,
+        timeout: KeepAliveTimeout,
+    },
+    /// The connection was closed or lost. Unless the session reconnects, see
+    /// [`SessionConfig::auto_reconnect`], it is invalid now.
+    Disconnected,
+    /// The user logged out of this device remotely. The cached credentials have been
+    /// removed and must not be used to reconnect.
+    LoggedOut,
+    /// The country of the user changed during the session, e.g. after reconnecting
+    /// through a VPN. What was available before may not be anymore, and vice versa.
+    CountryChanged { country: String },
+}
+
+/// What didn't come in time from the access point, see [`SessionEvent::ConnectionDead`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAliveTimeout {
+    Ping,
+    PongAck,
+}
+
+impl fmt::Display for KeepAliveTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ping => write!(f, "no ping received"),
+            Self::PongAck => write!(f, "pong not acknowledged"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct UserData {
     pub country: String,
@@ -77,10 +178,28 @@ struct SessionData {
     client_brand_name: String,
     client_model_name: String,
     connection_id: String,
+    // The server time minus the system time in seconds.
     time_delta: i64,
     invalid: bool,
+    logged_out: bool,
     user_data: UserData,
     last_ping: Option<Instant>,
+    // When the oldest pong that wasn't acknowledged yet was sent.
+    pong_sent: Option<Instant>,
+    event_senders: Vec<mpsc::UnboundedSender<SessionEvent>>,
+    state: StateStore,
+    // The reusable credentials to reconnect with.
+    credentials: Option<Credentials>,
+    // Distinguishes the current connection from those that were lost before.
+    connection_generation: u64,
+}
+
+impl SessionData {
+    fn send_event(&mut self, event: SessionEvent) {
+        self.state.record(StateEvent::Session(event.clone()));
+        self.event_senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
 }
 
 struct SessionInternal {
@@ -88,17 +207,28 @@ struct SessionInternal {
     data: RwLock<SessionData>,
 
     http_client: HttpClient,
-    tx_connection: OnceCell<mpsc::UnboundedSender<(u8, Vec<u8>)>>,
+    tx_connection: RwLock<Option<PacketSender>>,
+    // Wakes the keep-alive watchdog when a pong was sent.
+    keep_alive_changed: Arc<Notify>,
 
     apresolver: OnceCell<ApResolver>,
     audio_key: OnceCell<AudioKeyManager>,
     channel: OnceCell<ChannelManager>,
     mercury: OnceCell<MercuryManager>,
     spclient: OnceCell<SpClient>,
+    client_token_provider: OnceCell<ClientTokenProvider>,
     token_provider: OnceCell<TokenProvider>,
     cache: Option<Arc<Cache>>,
+    metrics: Metrics,
 
     handle: tokio::runtime::Handle,
+    // The connection to the access point, and the tasks spawned with `Session::spawn`, which
+    // are cancelled when the session is shut down gracefully or dropped.
+    connection_task: Mutex<Option<JoinHandle<()>>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    // The connection to the dealer of this session, which says goodbye when the session is
+    // shut down gracefully.
+    dealer: Mutex<Option<Dealer>>,
 }
 
 /// A shared reference to a Spotify session.
@@ -108,19 +238,31 @@ struct SessionInternal {
 /// this structs interface directly or hand it to a
 /// `Player`.
 ///
-/// *Note*: [Session] instances cannot yet be reused once invalidated. After
-/// an unexpectedly closed connection, you'll need to create a new [Session].
+/// *Note*: [Session] instances cannot be reused once invalidated. Unless
+/// [`SessionConfig::auto_reconnect`] is set, an unexpectedly closed connection
+/// invalidates the session, and you'll need to create a new [Session].
 #[derive(Clone)]
 pub struct Session(Arc<SessionInternal>);
 
 impl Session {
     pub fn new(config: SessionConfig, cache: Option<Cache>) -> Self {
-        let http_client = HttpClient::new(config.proxy.as_ref());
+        Self::new_with_cache(config, cache.map(Arc::new), Metrics::default())
+    }
+
+    fn new_with_cache(config: SessionConfig, cache: Option<Arc<Cache>>, metrics: Metrics) -> Self {
+        let http_client = HttpClient::new(
+            config.proxy.as_ref(),
+            config.proxy_credentials.as_ref(),
+            &config.tls,
+            &config.happy_eyeballs,
+            config.resolver.clone(),
+        );
 
         debug!("new Session");
 
         let session_data = SessionData {
             client_id: config.client_id.clone(),
+            state: config.state.clone(),
             ..SessionData::default()
         };
 
@@ -128,15 +270,21 @@ impl Session {
             config,
             data: RwLock::new(session_data),
             http_client,
-            tx_connection: OnceCell::new(),
-            cache: cache.map(Arc::new),
+            tx_connection: RwLock::new(None),
+            keep_alive_changed: Arc::new(Notify::new()),
+            cache,
+            metrics,
             apresolver: OnceCell::new(),
             audio_key: OnceCell::new(),
             channel: OnceCell::new(),
             mercury: OnceCell::new(),
             spclient: OnceCell::new(),
+            client_token_provider: OnceCell::new(),
             token_provider: OnceCell::new(),
             handle: tokio::runtime::Handle::current(),
+            connection_task: Mutex::new(None),
+            tasks: Mutex::new(Vec::new()),
+            dealer: Mutex::new(None),
         }))
     }
 
@@ -145,69 +293,250 @@ impl Session {
         credentials: Credentials,
         store_credentials: bool,
     ) -> Result<(), Error> {
-        let (reusable_credentials, transport) = loop {
+        if self.0.tx_connection.read().is_some() {
+            return Err(SessionError::NotConnected.into());
+        }
+
+        // The access point is phasing out logging in with anything but stored credentials and
+        // access tokens, so exchange the credentials for fresh stored credentials with login5
+        // first where it supports them.
+        let credentials = if authentication::supports_login5(&credentials) {
+            match authentication::login5(self, &credentials)
+                .instrument(info_span!("login5"))
+                .await
+            {
+                Ok(stored_credentials) => stored_credentials,
+                Err(e) => {
+                    warn!(
+                        "Unable to log in with login5, trying the access point: {}",
+                        e
+                    );
+                    credentials
+                }
+            }
+        } else {
+            credentials
+        };
+
+        let (reusable_credentials, transport, ap) = self.authenticate(credentials, false).await?;
+
+        info!("Authenticated as \"{}\" !", reusable_credentials.username);
+        self.set_username(&reusable_credentials.username);
+        if let Some(cache) = self.cache() {
+            if store_credentials {
+                let cred_changed = cache
+                    .credentials()
+                    .map(|c| c != reusable_credentials)
+                    .unwrap_or(true);
+                if cred_changed {
+                    cache.save_credentials(&reusable_credentials);
+                }
+            }
+        }
+
+        self.0.data.write().credentials = Some(reusable_credentials);
+        self.start_connection(transport, &ap);
+
+        Ok(())
+    }
+
+    /// Connects to an access point and authenticates, moving on to another access point when
+    /// instructed to.
+    async fn authenticate(
+        &self,
+        credentials: Credentials,
+        mut retrying: bool,
+    ) -> Result<(Credentials, Transport, SocketAddress), Error> {
+        loop {
             let ap = self.apresolver().resolve("accesspoint").await?;
             info!("Connecting to AP \"{}:{}\"", ap.0, ap.1);
-            let mut transport =
-                connection::connect(&ap.0, ap.1, self.config().proxy.as_ref()).await?;
+            if retrying {
+                self.0.metrics.increment(Counter::Reconnects, 1);
+                self.send_event(SessionEvent::Reconnecting {
+                    access_point: format!("{}:{}", ap.0, ap.1),
+                });
+            }
+            let config = self.config();
+            let mut transport = connection::connect(
+                &ap.0,
+                ap.1,
+                config.proxy.as_ref(),
+                config.proxy_credentials.as_ref(),
+                &config.happy_eyeballs,
+                &*config.resolver,
+                config.keep_alive.interval,
+            )
+            .instrument(info_span!("ap_connect", host = %ap.0, port = ap.1))
+            .await?;
 
             match connection::authenticate(
                 &mut transport,
                 credentials.clone(),
                 &self.config().device_id,
             )
+            .instrument(info_span!("ap_authenticate", host = %ap.0))
             .await
             {
-                Ok(creds) => break (creds, transport),
+                Ok(creds) => return Ok((creds, transport, ap)),
                 Err(e) => {
                     if let Some(AuthenticationError::LoginFailed(ErrorCode::TryAnotherAP)) =
                         e.error.downcast_ref::<AuthenticationError>()
                     {
                         warn!("Instructed to try another access point...");
+                        retrying = true;
                         continue;
                     } else {
                         return Err(e);
                     }
                 }
             }
-        };
-
-        info!("Authenticated as \"{}\" !", reusable_credentials.username);
-        self.set_username(&reusable_credentials.username);
-        if let Some(cache) = self.cache() {
-            if store_credentials {
-                let cred_changed = cache
-                    .credentials()
-                    .map(|c| c != reusable_credentials)
-                    .unwrap_or(true);
-                if cred_changed {
-                    cache.save_credentials(&reusable_credentials);
-                }
-            }
         }
+    }
 
+    /// Sends and receives the packets of `transport` in a background task, until the
+    /// connection is lost or the session is shut down.
+    fn start_connection(&self, transport: Transport, ap: &SocketAddress) {
         let (tx_connection, rx_connection) = mpsc::unbounded_channel();
-        self.0
-            .tx_connection
-            .set(tx_connection)
-            .map_err(|_| SessionError::NotConnected)?;
+        let generation = {
+            let mut data = self.0.data.write();
+            data.connection_generation += 1;
+            data.last_ping = None;
+            data.pong_sent = None;
+            data.connection_generation
+        };
+        *self.0.tx_connection.write() = Some(tx_connection);
 
         let (sink, stream) = transport.split();
         let sender_task = UnboundedReceiverStream::new(rx_connection)
             .map(Ok)
             .forward(sink);
         let receiver_task = DispatchTask(stream, self.weak());
-        let timeout_task = Session::session_timeout(self.weak());
+        let access_point = format!("{}:{}", ap.0, ap.1);
+        let timeout_task = Session::keep_alive_watchdog(
+            self.weak(),
+            generation,
+            access_point.clone(),
+            self.0.keep_alive_changed.clone(),
+        );
 
-        tokio::spawn(async move {
-            let result = future::try_join3(sender_task, receiver_task, timeout_task).await;
+        let session = self.weak();
+        let connection_task = tokio::spawn(async move {
+            // The connection is gone as soon as one of these finishes.
+            let result = tokio::select! {
+                result = sender_task => result,
+                result = receiver_task => result,
+                result = timeout_task => result,
+            };
 
             if let Err(e) = result {
                 error!("{}", e);
             }
+
+            if let Some(session) = session.try_upgrade() {
+                session.connection_lost(generation);
+            }
         });
+        *self.0.connection_task.lock() = Some(connection_task);
 
-        Ok(())
+        self.send_event(SessionEvent::Connected {
+            username: self.username(),
+            access_point,
+        });
+    }
+
+    fn connection_lost(&self, generation: u64) {
+        {
+            let data = self.0.data.read();
+            if data.invalid || data.connection_generation != generation {
+                return;
+            }
+        }
+
+        warn!("Lost connection to the access point");
+        self.0.tx_connection.write().take();
+        self.send_event(SessionEvent::Disconnected);
+
+        // Requests in flight won't get a response anymore.
+        self.mercury().fail_pending();
+        self.channel().fail_pending();
+        self.audio_key().fail_pending();
+
+        let credentials = self.0.data.read().credentials.clone();
+        match credentials {
+            Some(credentials) if self.config().auto_reconnect => {
+                self.spawn(Self::reconnect(self.weak(), credentials));
+            }
+            _ => self.shutdown(),
+        }
+    }
+
+    /// Reconnects with exponential backoff until it succeeds, the credentials are rejected
+    /// or the session is shut down. The Mercury subscriptions are renewed once connected.
+    async fn reconnect(session: SessionWeak, credentials: Credentials) {
+        let mut backoff = Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY);
+
+        loop {
+            let delay = Self::reconnect_delay(&mut backoff);
+            info!("Reconnecting in {:?}", delay);
+            tokio::time::sleep(delay).await;
+
+            let session = match session.try_upgrade() {
+                Some(session) if !session.is_invalid() => session,
+                _ => return,
+            };
+
+            match session.authenticate(credentials.clone(), true).await {
+                Ok((_, transport, ap)) => {
+                    info!("Reconnected as \"{}\"", session.username());
+                    session.start_connection(transport, &ap);
+                    session.mercury().resubscribe();
+                    return;
+                }
+                Err(e) if e.kind == ErrorKind::Unauthenticated => {
+                    error!("Cannot reconnect: {}", e);
+                    session.shutdown();
+                    return;
+                }
+                Err(e) => warn!("Reconnecting failed: {}", e),
+            }
+        }
+    }
+
+    // The jitter spreads out the devices reconnecting after an outage.
+    fn reconnect_delay(backoff: &mut Backoff) -> Duration {
+        backoff
+            .next_delay()
+            .mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+    }
+
+    /// Connects as the user of `credentials` and returns the new session, which shares the
+    /// configuration, cache and metrics of this one. Only then this session is invalidated, together
+    /// with its user-scoped state like tokens, Mercury subscriptions and the Connect state.
+    /// If connecting fails, this session is left untouched.
+    ///
+    /// Components that hold on to this session must be given the new one, e.g. with
+    /// `Player::set_session`, and a new `Spirc` must be started for it. This way the
+    /// audio sink and the audio cache can be kept when switching accounts.
+    pub async fn reauthenticate(
+        &self,
+        credentials: Credentials,
+        store_credentials: bool,
+    ) -> Result<Session, Error> {
+        let session = Self::new_with_cache(
+            self.config().clone(),
+            self.0.cache.clone(),
+            self.0.metrics.clone(),
+        );
+        session.connect(credentials, store_credentials).await?;
+
+        info!(
+            "Switching account from \"{}\" to \"{}\"",
+            self.username(),
+            session.username()
+        );
+        self.shutdown();
+
+        Ok(session)
     }
 
     pub fn apresolver(&self) -> &ApResolver {
@@ -242,49 +571,123 @@ impl Session {
         self.0.spclient.get_or_init(|| SpClient::new(self.weak()))
     }
 
+    pub fn client_token_provider(&self) -> &ClientTokenProvider {
+        self.0
+            .client_token_provider
+            .get_or_init(|| ClientTokenProvider::new(self.weak()))
+    }
+
+    /// Returns an access token for the Web API with all `scopes`, e.g. `["user-read-private",
+    /// "playlist-read-private"]`. Tokens are cached until they expire, and requested anew
+    /// after.
+    pub async fn token(&self, scopes: &[&str]) -> Result<Token, Error> {
+        self.token_provider().get_token(&scopes.join(",")).await
+    }
+
     pub fn token_provider(&self) -> &TokenProvider {
         self.0
             .token_provider
             .get_or_init(|| TokenProvider::new(self.weak()))
     }
 
-    /// Returns an error, when we haven't received a ping for too long (2 minutes),
-    /// which means that we silently lost connection to Spotify servers.
-    async fn session_timeout(session: SessionWeak) -> io::Result<()> {
-        // pings are sent every 2 minutes and a 5 second margin should be fine
-        const SESSION_TIMEOUT: Duration = Duration::from_secs(125);
+    /// Returns an error when we haven't received a ping or the acknowledgement of a pong
+    /// for too long, see [`SessionConfig::keep_alive`], which means that we silently lost
+    /// connection to Spotify servers.
+    async fn keep_alive_watchdog(
+        session: SessionWeak,
+        generation: u64,
+        access_point: String,
+        keep_alive_changed: Arc<Notify>,
+    ) -> io::Result<()> {
+        let connected_at = Instant::now();
+
+        loop {
+            // created before reading the state, so that a pong sent in between wakes us
+            let changed = keep_alive_changed.notified();
 
-        while let Some(session) = session.try_upgrade() {
-            if session.is_invalid() {
+            let session = match session.try_upgrade() {
+                Some(session) => session,
+                None => break,
+            };
+            let (invalid, current_generation, last_ping, pong_sent) = {
+                let data = session.0.data.read();
+                (
+                    data.invalid,
+                    data.connection_generation,
+                    data.last_ping,
+                    data.pong_sent,
+                )
+            };
+            if invalid || current_generation != generation {
                 break;
             }
-            let last_ping = session.0.data.read().last_ping.unwrap_or_else(Instant::now);
-            if last_ping.elapsed() >= SESSION_TIMEOUT {
-                session.shutdown();
-                // TODO: Optionally reconnect (with cached/last credentials?)
+
+            let config = session.config().keep_alive;
+            let ping_deadline = deadline(last_ping.unwrap_or(connected_at), config.ping_timeout);
+            let pong_ack_deadline = config
+                .pong_ack_timeout
+                .zip(pong_sent)
+                .map(|(timeout, pong_sent)| deadline(pong_sent, timeout));
+
+            let now = Instant::now();
+            let timeout = if now >= ping_deadline {
+                Some(KeepAliveTimeout::Ping)
+            } else if pong_ack_deadline.map_or(false, |deadline| now >= deadline) {
+                Some(KeepAliveTimeout::PongAck)
+            } else {
+                None
+            };
+            if let Some(timeout) = timeout {
+                warn!("Connection to {} is dead: {}", access_point, timeout);
+                session.send_event(SessionEvent::ConnectionDead {
+                    access_point,
+                    timeout,
+                });
                 return Err(io::Error::new(
                     io::ErrorKind::TimedOut,
-                    "session lost connection to server",
+                    format!("session lost connection to server: {timeout}"),
                 ));
             }
+
             // drop the strong reference before sleeping
             drop(session);
-            // a potential timeout cannot occur at least until SESSION_TIMEOUT after the last_ping
-            tokio::time::sleep_until(last_ping + SESSION_TIMEOUT).await;
+            let deadline = pong_ack_deadline.map_or(ping_deadline, |d| d.min(ping_deadline));
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => (),
+                _ = changed => (),
+            }
         }
         Ok(())
     }
 
+    /// How many seconds the clock of the access point is ahead of the system clock, as
+    /// measured with the ping that follows the login and every ping after. Timestamps sent
+    /// by Spotify are compared against [`Session::server_time`] instead of the system clock.
     pub fn time_delta(&self) -> i64 {
         self.0.data.read().time_delta
     }
 
+    /// The time now by the clock of the access point, which is correct on devices with a
+    /// wrong system clock.
+    pub fn server_time(&self) -> Date {
+        Date(Date::now_utc().saturating_add(time::Duration::seconds(self.time_delta())))
+    }
+
     pub fn spawn<T>(&self, task: T)
     where
         T: Future + Send + 'static,
         T::Output: Send + 'static,
     {
-        self.0.handle.spawn(task);
+        let task = self.0.handle.spawn(task.map(drop));
+        let mut tasks = self.0.tasks.lock();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    /// Hands the connection to the dealer over to this session, to be closed with it.
+    #[allow(dead_code)] // like the dealer, not used yet
+    pub(crate) fn set_dealer(&self, dealer: Dealer) {
+        *self.0.dealer.lock() = Some(dealer);
     }
 
     fn debug_info(&self) {
@@ -329,17 +732,45 @@ impl Session {
 
                 {
                     let mut data = self.0.data.write();
-                    data.time_delta = server_timestamp.saturating_sub(timestamp);
+                    let time_delta = server_timestamp.saturating_sub(timestamp);
+                    if time_delta.abs() > CLOCK_SKEW_WARNING_SECS
+                        && data.time_delta.abs() <= CLOCK_SKEW_WARNING_SECS
+                    {
+                        warn!(
+                            "The system clock is off by {} seconds, correcting for it",
+                            -time_delta
+                        );
+                    }
+                    data.time_delta = time_delta;
                     data.last_ping = Some(Instant::now());
+                    data.pong_sent.get_or_insert_with(Instant::now);
                 }
 
                 self.debug_info();
-                self.send_packet(Pong, vec![0, 0, 0, 0])
+                let result = self.send_packet(Pong, vec![0, 0, 0, 0]);
+                self.0.keep_alive_changed.notify_waiters();
+                result
+            }
+            Some(PongAck) => {
+                self.0.data.write().pong_sent = None;
+                Ok(())
             }
             Some(CountryCode) => {
                 let country = String::from_utf8(data.as_ref().to_owned())?;
                 info!("Country: {:?}", country);
-                self.0.data.write().user_data.country = country;
+
+                let mut session_data = self.0.data.write();
+                let previous =
+                    std::mem::replace(&mut session_data.user_data.country, country.clone());
+
+                if !previous.is_empty() && previous != country {
+                    info!("Country changed from {:?} to {:?}", previous, country);
+
+                    // The spclient access point is chosen by location.
+                    self.spclient().flush_accesspoint().now_or_never();
+
+                    session_data.send_event(SessionEvent::CountryChanged { country });
+                }
                 Ok(())
             }
             Some(StreamChunkRes) | Some(ChannelError) => self.channel().dispatch(cmd, data),
@@ -385,8 +816,7 @@ impl Session {
                 self.0.data.write().user_data.attributes = user_attributes;
                 Ok(())
             }
-            Some(PongAck)
-            | Some(SecretBlock)
+            Some(SecretBlock)
             | Some(LegacyWelcome)
             | Some(UnknownDataAllZeros)
             | Some(LicenseVersion) => Ok(()),
@@ -398,16 +828,38 @@ impl Session {
     }
 
     pub fn send_packet(&self, cmd: PacketType, data: Vec<u8>) -> Result<(), Error> {
-        match self.0.tx_connection.get() {
+        match self.0.tx_connection.read().as_ref() {
             Some(tx) => Ok(tx.send((cmd as u8, data))?),
             None => Err(SessionError::NotConnected.into()),
         }
     }
 
+    /// Lets packets be sent without connecting, and returns what is sent.
+    #[cfg(test)]
+    pub(crate) fn connect_for_testing(&self) -> mpsc::UnboundedReceiver<(u8, Vec<u8>)> {
+        let (tx_connection, rx_connection) = mpsc::unbounded_channel();
+        *self.0.tx_connection.write() = Some(tx_connection);
+        rx_connection
+    }
+
     pub fn cache(&self) -> Option<&Arc<Cache>> {
         self.0.cache.as_ref()
     }
 
+    /// The metrics of this session and the players using it. Set a [`MetricsSink`] to
+    /// receive them.
+    ///
+    /// [`MetricsSink`]: crate::metrics::MetricsSink
+    pub fn metrics(&self) -> &Metrics {
+        &self.0.metrics
+    }
+
+    /// The bytes downloaded by this session so far, the current throughput and the CDN hosts
+    /// that audio files are being downloaded from.
+    pub fn stats(&self) -> SessionStats {
+        self.0.metrics.stats()
+    }
+
     pub fn config(&self) -> &SessionConfig {
         &self.0.config
     }
@@ -528,14 +980,109 @@ impl Session {
 
     pub fn shutdown(&self) {
         debug!("Invalidating session");
-        self.0.data.write().invalid = true;
+        {
+            let mut data = self.0.data.write();
+            // Dropping the sender closes the connection.
+            if self.0.tx_connection.write().take().is_some() && !data.invalid {
+                data.send_event(SessionEvent::Disconnected);
+            }
+            data.invalid = true;
+        }
         self.mercury().shutdown();
         self.channel().shutdown();
     }
 
+    /// Shuts the session down like [`Session::shutdown`], and resolves once it's done: the
+    /// connection to the dealer is closed with a goodbye, the packets that were queued are
+    /// sent and the connection to the access point is closed, waiting at most
+    /// [`SHUTDOWN_TIMEOUT`] for each, and the tasks started with [`Session::spawn`], like
+    /// downloads, are cancelled. Nothing is written to the cache after that, so that it
+    /// is safe to exit then.
+    ///
+    /// It must not be awaited in a task started with [`Session::spawn`], which would be
+    /// cancelled as well.
+    pub async fn shutdown_gracefully(&self) {
+        self.shutdown();
+
+        let dealer = self.0.dealer.lock().take();
+        if let Some(dealer) = dealer {
+            // Aborts the dealer when dropped.
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, dealer.close())
+                .await
+                .is_err()
+            {
+                warn!("Closing the connection to the dealer timed out");
+            }
+        }
+
+        // Dropping the sender in `shutdown` lets the connection flush and close its socket.
+        let connection_task = self.0.connection_task.lock().take();
+        if let Some(mut connection_task) = connection_task {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut connection_task)
+                .await
+                .is_err()
+            {
+                warn!("Closing the connection to the access point timed out");
+                connection_task.abort();
+            }
+        }
+
+        let tasks = std::mem::take(&mut *self.0.tasks.lock());
+        for task in &tasks {
+            task.abort();
+        }
+        for task in tasks {
+            // cancelled, or panicked which was reported already
+            let _ = task.await;
+        }
+        debug!("Session shut down");
+    }
+
     pub fn is_invalid(&self) -> bool {
         self.0.data.read().invalid
     }
+
+    /// Logs out of this device: removes the cached credentials, emits
+    /// [`SessionEvent::LoggedOut`] and invalidates the session.
+    pub fn logout(&self) {
+        info!("Logging out");
+        if let Some(cache) = self.cache() {
+            cache.remove_credentials();
+        }
+
+        {
+            let mut data = self.0.data.write();
+            data.logged_out = true;
+            data.send_event(SessionEvent::LoggedOut);
+        }
+
+        self.shutdown();
+    }
+
+    pub fn is_logged_out(&self) -> bool {
+        self.0.data.read().logged_out
+    }
+
+    pub fn get_session_event_channel(&self) -> mpsc::UnboundedReceiver<SessionEvent> {
+        let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        self.0.data.write().event_senders.push(event_sender);
+        event_receiver
+    }
+
+    /// Returns a stream of the events of this session from now on, e.g. to show the
+    /// connection state. Every call returns a stream that receives all events.
+    pub fn events(&self) -> UnboundedReceiverStream<SessionEvent> {
+        UnboundedReceiverStream::new(self.get_session_event_channel())
+    }
+
+    pub(crate) fn send_event(&self, event: SessionEvent) {
+        self.0.data.write().send_event(event)
+    }
+
+    /// The state of the device, see [`SessionConfig::state`].
+    pub fn state(&self) -> &StateStore {
+        &self.0.config.state
+    }
 }
 
 #[derive(Clone)]
@@ -555,6 +1102,9 @@ impl SessionWeak {
 impl Drop for SessionInternal {
     fn drop(&mut self) {
         debug!("drop Session");
+        for task in self.tasks.get_mut().drain(..) {
+            task.abort();
+        }
     }
 }
 
@@ -580,13 +1130,9 @@ where
                 Some(Ok(t)) => t,
                 None => {
                     warn!("Connection to server closed.");
-                    session.shutdown();
                     return Poll::Ready(Ok(()));
                 }
-                Some(Err(e)) => {
-                    session.shutdown();
-                    return Poll::Ready(Err(e));
-                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
             };
 
             if let Err(e) = session.dispatch(cmd, data) {
@@ -596,6 +1142,16 @@ where
     }
 }
 
+// The end of a timeout that starts at `start`. Timeouts too long to be represented, like
+// `Duration::MAX`, end far in the future instead of overflowing.
+fn deadline(start: Instant, timeout: Duration) -> Instant {
+    // about 30 years, like Tokio uses for sleeps that never end
+    const FAR_FUTURE: Duration = Duration::from_secs(86400 * 365 * 30);
+    start
+        .checked_add(timeout)
+        .unwrap_or_else(|| start + FAR_FUTURE)
+}
+
 impl<S> Drop for DispatchTask<S>
 where
     S: TryStream<Ok = (u8, Bytes)> + Unpin,
@@ -604,3 +1160,369 @@ where
         debug!("drop Dispatch");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::{config::KeepAliveConfig, dealer};
+
+    fn session(keep_alive: KeepAliveConfig) -> (Session, u64) {
+        let session = Session::new(
+            SessionConfig {
+                keep_alive,
+                ..Default::default()
+            },
+            None,
+        );
+        let generation = session.0.data.read().connection_generation;
+        (session, generation)
+    }
+
+    fn watchdog(session: &Session, generation: u64) -> impl Future<Output = io::Result<()>> {
+        Session::keep_alive_watchdog(
+            session.weak(),
+            generation,
+            "ap.example:4070".to_owned(),
+            session.0.keep_alive_changed.clone(),
+        )
+    }
+
+    #[tokio::test]
+    async fn missing_pings_kill_the_connection() {
+        let (session, generation) = session(KeepAliveConfig {
+            ping_timeout: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let mut events = session.events();
+
+        let result = watchdog(&session, generation).await;
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(
+            events.next().await,
+            Some(SessionEvent::ConnectionDead {
+                timeout: KeepAliveTimeout::Ping,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_pongs_kill_the_connection() {
+        let (session, generation) = session(KeepAliveConfig {
+            pong_ack_timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        });
+        session.0.data.write().pong_sent = Some(Instant::now());
+        let mut events = session.events();
+
+        let result = watchdog(&session, generation).await;
+
+        assert!(result.is_err());
+        assert!(matches!(
+            events.next().await,
+            Some(SessionEvent::ConnectionDead {
+                timeout: KeepAliveTimeout::PongAck,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn huge_timeouts_never_expire() {
+        let (session, generation) = session(KeepAliveConfig {
+            ping_timeout: Duration::MAX,
+            pong_ack_timeout: Some(Duration::MAX),
+            ..Default::default()
+        });
+        session.0.data.write().pong_sent = Some(Instant::now());
+
+        let result =
+            tokio::time::timeout(Duration::from_millis(20), watchdog(&session, generation)).await;
+
+        assert!(result.is_err());
+    }
+
+    // A task that never finishes, and tells when it is cancelled.
+    fn endless_task() -> (impl Future<Output = ()>, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        let task = async move {
+            let _tx = tx;
+            futures_util::future::pending::<()>().await
+        };
+        (task, rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_waits_for_the_connection_to_close() {
+        let (session, _) = session(KeepAliveConfig::default());
+        let (closed_tx, mut closed_rx) = oneshot::channel();
+        *session.0.connection_task.lock() = Some(tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let _ = closed_tx.send(());
+        }));
+
+        let start = Instant::now();
+        session.shutdown_gracefully().await;
+
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert!(closed_rx.try_recv().is_ok());
+        assert!(session.is_invalid());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_aborts_a_connection_that_does_not_close() {
+        let (session, _) = session(KeepAliveConfig::default());
+        let (task, cancelled) = endless_task();
+        *session.0.connection_task.lock() = Some(tokio::spawn(task));
+
+        let start = Instant::now();
+        session.shutdown_gracefully().await;
+
+        assert_eq!(start.elapsed(), SHUTDOWN_TIMEOUT);
+        assert!(cancelled.await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_the_spawned_tasks() {
+        let (session, _) = session(KeepAliveConfig::default());
+        let (task, cancelled) = endless_task();
+        session.spawn(task);
+
+        let start = Instant::now();
+        session.shutdown_gracefully().await;
+
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(session.0.tasks.lock().is_empty());
+        assert!(cancelled.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_session_aborts_its_tasks() {
+        let (session, _) = session(KeepAliveConfig::default());
+        let (task, cancelled) = endless_task();
+        session.spawn(task);
+
+        drop(session);
+
+        assert!(cancelled.await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_closes_the_dealer() {
+        let (session, _) = session(KeepAliveConfig::default());
+        let mut builder = dealer::Builder::new();
+        let mut subscription = builder.subscribe(&["hm://pusher/v1/connections/"]).unwrap();
+        // never gets to connect
+        let dealer = builder.launch_in_background(
+            futures_util::future::pending::<url::Url>,
+            &SessionConfig::default(),
+        );
+        session.set_dealer(dealer);
+
+        let start = Instant::now();
+        session.shutdown_gracefully().await;
+
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+        assert!(session.0.dealer.lock().is_none());
+        // its handlers are gone with it
+        assert!(subscription.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn watchdog_stops_after_reconnecting() {
+        let (session, generation) = session(KeepAliveConfig {
+            ping_timeout: Duration::from_millis(20),
+            ..Default::default()
+        });
+        session.0.data.write().connection_generation += 1;
+
+        assert!(watchdog(&session, generation).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reports_changes_of_the_country() {
+        let (session, _) = session(KeepAliveConfig::default());
+        let mut events = session.events();
+        let country = |country: &'static str| {
+            session.dispatch(PacketType::CountryCode as u8, Bytes::from(country))
+        };
+
+        // the first country after logging in is no change, and neither is the same again
+        country("DE").unwrap();
+        country("DE").unwrap();
+        assert!(events.next().now_or_never().is_none());
+
+        country("NL").unwrap();
+        assert_eq!(session.country(), "NL");
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(SessionEvent::CountryChanged {
+                country: "NL".to_owned()
+            }))
+        );
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn logging_out_removes_the_credentials() {
+        let dir = std::env::temp_dir().join(format!("librespot-logout-{}", std::process::id()));
+        let cache = Cache::new(Some(&dir), None, None, None).unwrap();
+        cache.save_credentials(&Credentials::with_password("user", "password"));
+        let session = Session::new(SessionConfig::default(), Some(cache));
+        let mut events = session.events();
+
+        session.logout();
+        let credentials = session.cache().unwrap().credentials();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(credentials.is_none());
+        assert!(session.is_logged_out());
+        assert!(session.is_invalid());
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(SessionEvent::LoggedOut))
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_disconnecting_once() {
+        let (unconnected, _) = session(KeepAliveConfig::default());
+        let (session, _) = session(KeepAliveConfig::default());
+        let mut events = session.events();
+        let _packets = session.connect_for_testing();
+
+        session.shutdown();
+        session.shutdown();
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(SessionEvent::Disconnected))
+        );
+        assert!(events.next().now_or_never().is_none());
+
+        // nor when it never connected
+        let mut events = unconnected.events();
+        unconnected.shutdown();
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    fn reconnecting_session(auto_reconnect: bool) -> (Session, u64) {
+        let session = Session::new(
+            SessionConfig {
+                auto_reconnect,
+                ..Default::default()
+            },
+            None,
+        );
+        session.0.data.write().credentials = Some(Credentials::with_password("user", "pass"));
+        let generation = session.0.data.read().connection_generation;
+        (session, generation)
+    }
+
+    #[tokio::test]
+    async fn losing_the_connection_invalidates_the_session() {
+        let (session, generation) = reconnecting_session(false);
+        let mut events = session.events();
+        let _packets = session.connect_for_testing();
+        let request = session.mercury().get("hm://test").unwrap();
+
+        session.connection_lost(generation);
+
+        assert!(request.await.is_err());
+        assert!(session.is_invalid());
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(SessionEvent::Disconnected))
+        );
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn losing_the_connection_reconnects_when_enabled() {
+        let (session, generation) = reconnecting_session(true);
+        let mut events = session.events();
+        let _packets = session.connect_for_testing();
+        let request = session.mercury().get("hm://test").unwrap();
+
+        session.connection_lost(generation);
+
+        // the requests in flight fail, but the session waits to reconnect
+        assert!(request.await.is_err());
+        assert!(!session.is_invalid());
+        assert!(session.0.tx_connection.read().is_none());
+        assert!(session.0.data.read().credentials.is_some());
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(SessionEvent::Disconnected))
+        );
+        session.shutdown();
+    }
+
+    #[tokio::test]
+    async fn losing_an_earlier_connection_is_ignored() {
+        let (session, generation) = reconnecting_session(false);
+        let mut events = session.events();
+        let _packets = session.connect_for_testing();
+        session.0.data.write().connection_generation += 1;
+
+        session.connection_lost(generation);
+
+        assert!(!session.is_invalid());
+        assert!(session.0.tx_connection.read().is_some());
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnecting_stops_once_the_session_is_shut_down() {
+        let (session, _) = reconnecting_session(true);
+        let mut events = session.events();
+        session.shutdown();
+
+        let credentials = Credentials::with_password("user", "pass");
+        Session::reconnect(session.weak(), credentials).await;
+
+        assert!(session.0.tx_connection.read().is_none());
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn reconnect_delays_double_with_jitter_up_to_the_maximum() {
+        let mut backoff = Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY);
+        let mut expected = RECONNECT_INITIAL_DELAY;
+        for _ in 0..10 {
+            let delay = Session::reconnect_delay(&mut backoff);
+            assert!(delay >= expected / 2 && delay <= expected * 3 / 2);
+            expected = (expected * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+
+    #[test]
+    fn classifies_login_failures() {
+        use crate::protocol::keyexchange::ErrorCode;
+
+        for (code, recovery) in [
+            (ErrorCode::BadCredentials, Recovery::Reauthenticate),
+            (ErrorCode::TryAnotherAP, Recovery::Retry),
+            (ErrorCode::PremiumAccountRequired, Recovery::GiveUp),
+        ] {
+            let err = Error::from(AuthenticationError::LoginFailed(code));
+            assert_eq!(err.kind, ErrorKind::PermissionDenied);
+            assert_eq!(err.code(), "ap.login_failed");
+            assert_eq!(err.recovery(), recovery, "{:?}", code);
+
+            // the same when the session fails to connect with it
+            let err = Error::from(SessionError::AuthenticationError(
+                AuthenticationError::LoginFailed(code),
+            ));
+            assert_eq!(err.kind, ErrorKind::Unauthenticated);
+            assert_eq!(err.code(), "ap.login_failed");
+            assert_eq!(err.recovery(), recovery, "{:?}", code);
+        }
+
+        let err = Error::from(SessionError::NotConnected);
+        assert_eq!(err.code(), "session.not_connected");
+        assert!(err.is_retryable());
+    }
+}
@@ -0,0 +1,290 @@
+//! Multiple authenticated sessions, e.g. of the members of a household sharing a device, of
+//! which one is active at a time.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{
+    authentication::Credentials,
+    cache::Cache,
+    error::{ErrorCode, ErrorKind},
+    Error, Session, SessionConfig,
+};
+
+#[derive(Debug, Error)]
+pub enum SessionManagerError {
+    #[error("no session for account {0}")]
+    UnknownAccount(String),
+    #[error("session is not connected")]
+    NotConnected,
+}
+
+impl From<SessionManagerError> for Error {
+    fn from(err: SessionManagerError) -> Self {
+        match err {
+            SessionManagerError::UnknownAccount(_) => Error::with_code(ErrorKind::NotFound, err),
+            SessionManagerError::NotConnected => {
+                Error::with_code(ErrorKind::FailedPrecondition, err)
+            }
+        }
+    }
+}
+
+impl ErrorCode for SessionManagerError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownAccount(_) => "session_manager.unknown_account",
+            Self::NotConnected => "session_manager.not_connected",
+        }
+    }
+}
+
+/// The changes of the active session, as received from [`SessionManager::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionManagerEvent {
+    /// The active session switched from the account `from` to `to`. Whatever runs on the
+    /// previous session, like the Connect device and the player, should move over to the
+    /// session of `to`; see `Spirc::handover` and `Player::set_session`.
+    ///
+    /// `from` and `to` are the same account when the active session was replaced with a
+    /// new one of that account.
+    Switched { from: Option<String>, to: String },
+    /// The active session was removed, and none is active until switching to another one.
+    Deactivated { username: String },
+}
+
+#[derive(Default)]
+struct SessionManagerInner {
+    // by canonical username
+    sessions: HashMap<String, Session>,
+    active: Option<String>,
+    event_senders: Vec<mpsc::UnboundedSender<SessionManagerEvent>>,
+}
+
+impl SessionManagerInner {
+    fn send_event(&mut self, event: SessionManagerEvent) {
+        self.event_senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+/// Holds a session per account, and which of them is active.
+///
+/// The sessions stay connected while they aren't active, so switching between accounts
+/// doesn't have to log in again.
+#[derive(Clone, Default)]
+pub struct SessionManager(Arc<RwLock<SessionManagerInner>>);
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a session, connects it with `credentials` and adds it. It becomes active if
+    /// no other session is.
+    pub async fn connect(
+        &self,
+        config: SessionConfig,
+        cache: Option<Cache>,
+        credentials: Credentials,
+        store_credentials: bool,
+    ) -> Result<Session, Error> {
+        let session = Session::new(config, cache);
+        session.connect(credentials, store_credentials).await?;
+        self.add(session.clone())?;
+        Ok(session)
+    }
+
+    /// Adds a connected session, replacing and shutting down a previous one of the same
+    /// account. It becomes active if no other session is, or if it replaces the active one.
+    pub fn add(&self, session: Session) -> Result<(), Error> {
+        let username = session.username();
+        if username.is_empty() || session.is_invalid() {
+            return Err(SessionManagerError::NotConnected.into());
+        }
+
+        let mut inner = self.0.write();
+        let previous = inner.sessions.insert(username.clone(), session);
+        if inner.active.is_none() {
+            inner.active = Some(username.clone());
+            inner.send_event(SessionManagerEvent::Switched {
+                from: None,
+                to: username,
+            });
+        } else if previous.is_some() && inner.active.as_deref() == Some(&username) {
+            info!("Replacing the active session of \"{}\"", username);
+            inner.send_event(SessionManagerEvent::Switched {
+                from: Some(username.clone()),
+                to: username,
+            });
+        }
+        drop(inner);
+
+        if let Some(previous) = previous {
+            previous.shutdown();
+        }
+        Ok(())
+    }
+
+    /// Removes the session of an account and shuts it down. If it was active, no session is
+    /// active anymore.
+    pub fn remove(&self, username: &str) -> Option<Session> {
+        let mut inner = self.0.write();
+        let session = inner.sessions.remove(username)?;
+        if inner.active.as_deref() == Some(username) {
+            inner.active = None;
+            inner.send_event(SessionManagerEvent::Deactivated {
+                username: username.to_owned(),
+            });
+        }
+        drop(inner);
+
+        session.shutdown();
+        Some(session)
+    }
+
+    /// Makes the session of an account the active one, and returns it.
+    pub fn switch_to(&self, username: &str) -> Result<Session, Error> {
+        let mut inner = self.0.write();
+        let session = inner
+            .sessions
+            .get(username)
+            .filter(|session| !session.is_invalid())
+            .cloned()
+            .ok_or_else(|| SessionManagerError::UnknownAccount(username.to_owned()))?;
+
+        if inner.active.as_deref() != Some(username) {
+            info!("Switching to the session of \"{}\"", username);
+            let from = inner.active.replace(username.to_owned());
+            inner.send_event(SessionManagerEvent::Switched {
+                from,
+                to: username.to_owned(),
+            });
+        }
+        Ok(session)
+    }
+
+    pub fn active(&self) -> Option<Session> {
+        let inner = self.0.read();
+        inner
+            .active
+            .as_ref()
+            .and_then(|username| inner.sessions.get(username))
+            .cloned()
+    }
+
+    pub fn active_username(&self) -> Option<String> {
+        self.0.read().active.clone()
+    }
+
+    pub fn get(&self, username: &str) -> Option<Session> {
+        self.0.read().sessions.get(username).cloned()
+    }
+
+    /// The accounts that have a session, in no particular order.
+    pub fn usernames(&self) -> Vec<String> {
+        self.0.read().sessions.keys().cloned().collect()
+    }
+
+    /// Shuts down all sessions.
+    pub fn shutdown(&self) {
+        let sessions: Vec<_> = {
+            let mut inner = self.0.write();
+            inner.active = None;
+            inner.sessions.drain().map(|(_, session)| session).collect()
+        };
+        for session in sessions {
+            session.shutdown();
+        }
+    }
+
+    /// Returns a stream of the changes of the active session from now on. Every call
+    /// returns a stream that receives all events.
+    pub fn events(&self) -> UnboundedReceiverStream<SessionManagerEvent> {
+        let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        self.0.write().event_senders.push(event_sender);
+        UnboundedReceiverStream::new(event_receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    fn session(username: &str) -> Session {
+        let session = Session::new(SessionConfig::default(), None);
+        session.set_username(username);
+        session
+    }
+
+    #[tokio::test]
+    async fn switches_between_accounts() {
+        let manager = SessionManager::new();
+        let mut events = manager.events();
+
+        manager.add(session("alice")).unwrap();
+        manager.add(session("bob")).unwrap();
+        assert_eq!(manager.active_username().as_deref(), Some("alice"));
+
+        manager.switch_to("bob").unwrap();
+        assert_eq!(manager.active().unwrap().username(), "bob");
+        assert!(manager.switch_to("carol").is_err());
+
+        manager.remove("bob");
+        assert!(manager.active().is_none());
+
+        let expected = [
+            SessionManagerEvent::Switched {
+                from: None,
+                to: "alice".to_owned(),
+            },
+            SessionManagerEvent::Switched {
+                from: Some("alice".to_owned()),
+                to: "bob".to_owned(),
+            },
+            SessionManagerEvent::Deactivated {
+                username: "bob".to_owned(),
+            },
+        ];
+        for event in expected {
+            assert_eq!(events.next().await, Some(event));
+        }
+    }
+
+    #[tokio::test]
+    async fn switches_to_the_replaced_active_session() {
+        let manager = SessionManager::new();
+        manager.add(session("alice")).unwrap();
+        manager.add(session("bob")).unwrap();
+        let mut events = manager.events();
+
+        let previous = manager.active().unwrap();
+        manager.add(session("alice")).unwrap();
+        assert!(previous.is_invalid());
+        assert!(!manager.active().unwrap().is_invalid());
+
+        // Replacing an inactive session doesn't switch.
+        manager.add(session("bob")).unwrap();
+        assert_eq!(manager.active_username().as_deref(), Some("alice"));
+
+        manager.remove("alice");
+        let expected = [
+            SessionManagerEvent::Switched {
+                from: Some("alice".to_owned()),
+                to: "alice".to_owned(),
+            },
+            SessionManagerEvent::Deactivated {
+                username: "alice".to_owned(),
+            },
+        ];
+        for event in expected {
+            assert_eq!(events.next().await, Some(event));
+        }
+    }
+}
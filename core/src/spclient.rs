@@ -1,68 +1,114 @@
-use std::{
-    env::consts::OS,
-    fmt::Write,
-    time::{Duration, Instant},
-};
+use std::fmt::Write;
 
-use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
 use futures_util::future::IntoStream;
-use http::header::HeaderValue;
+use http::header::{HeaderName, HeaderValue};
 use hyper::{
     client::ResponseFuture,
-    header::{HeaderName, ACCEPT, AUTHORIZATION, CONTENT_TYPE, RANGE},
-    Body, HeaderMap, Method, Request,
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, RANGE},
+    Body, HeaderMap, Method, Request, StatusCode,
 };
-use protobuf::{Enum, Message, MessageFull};
+use protobuf::Message;
 use rand::RngCore;
-use sha1::{Digest, Sha1};
-use sysinfo::{System, SystemExt};
 use thiserror::Error;
+use url::Url;
 
 use crate::{
     apresolve::SocketAddress,
+    cache::CacheError,
     cdn_url::CdnUrl,
-    config::SessionConfig,
-    error::ErrorKind,
+    client_token::CLIENT_TOKEN,
+    coalesce::InFlight,
+    error::{ErrorCode, ErrorKind, Recovery},
+    http_client::HttpClientError,
+    metrics::Counter,
     protocol::{
         canvaz::EntityCanvazRequest,
-        clienttoken_http::{
-            ChallengeAnswer, ChallengeType, ClientTokenRequest, ClientTokenRequestType,
-            ClientTokenResponse, ClientTokenResponseType,
-        },
+        collection2v2::{DeltaRequest, PageRequest},
         connect::PutStateRequest,
         extended_metadata::BatchedEntityRequest,
     },
-    token::Token,
-    version::spotify_version,
+    session::SessionEvent,
     Error, FileId, SpotifyId,
 };
 
 component! {
     SpClient : SpClientInner {
         accesspoint: Option<SocketAddress> = None,
+        flushed_accesspoint: Option<SocketAddress> = None,
         strategy: RequestStrategy = RequestStrategy::default(),
-        client_token: Option<Token> = None,
+        image_host: Option<String> = None,
+        // The `GET` requests in flight, which are shared by everyone making the same one.
+        in_flight: InFlight<RequestKey, Bytes> = InFlight::default(),
     }
 }
 
 pub type SpClientResult = Result<Bytes, Error>;
 
-#[allow(clippy::declare_interior_mutable_const)]
-const CLIENT_TOKEN: HeaderName = HeaderName::from_static("client-token");
+// The endpoint and headers of a `GET` request.
+type RequestKey = (String, Vec<(HeaderName, HeaderValue)>);
 
 #[derive(Debug, Error)]
 pub enum SpClientError {
     #[error("missing attribute {0}")]
     Attribute(String),
+    #[error("the access token has expired")]
+    TokenExpired,
+    #[error("the access token lacks the scope \"{scope}\"")]
+    MissingScope { scope: String },
+    #[error("the client token is invalid")]
+    ClientTokenInvalid,
 }
 
 impl From<SpClientError> for Error {
     fn from(err: SpClientError) -> Self {
-        Self::failed_precondition(err)
+        match err {
+            SpClientError::Attribute(_) => Self::with_code(ErrorKind::FailedPrecondition, err),
+            SpClientError::TokenExpired | SpClientError::ClientTokenInvalid => {
+                Self::with_code(ErrorKind::Unauthenticated, err)
+            }
+            SpClientError::MissingScope { .. } => Self::with_code(ErrorKind::PermissionDenied, err),
+        }
     }
 }
 
+impl ErrorCode for SpClientError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Attribute(_) => "spclient.attribute",
+            Self::TokenExpired => "spclient.token_expired",
+            Self::MissingScope { .. } => "spclient.missing_scope",
+            Self::ClientTokenInvalid => "spclient.client_token_invalid",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            // a token with the scope has to be requested
+            (Self::MissingScope { .. }, ErrorKind::PermissionDenied) => {
+                Some(Recovery::Reauthenticate)
+            }
+            _ => None,
+        }
+    }
+}
+
+// The scopes of the access token for spclient requests, which more are added to when a
+// request is rejected for lacking them.
+const DEFAULT_SCOPES: &str = "playlist-read";
+
+// Used when the session has no `image-url` attribute.
+const DEFAULT_IMAGE_URL: &str = "https://i.scdn.co/image/{file_id}";
+
+// Hosts that serve the same images, in order of preference. The host of the `image-url`
+// attribute is tried before these.
+const IMAGE_HOSTS: [&str; 3] = [
+    "i.scdn.co",
+    "image-cdn-ak.spotifycdn.com",
+    "image-cdn-fa.spotifycdn.com",
+];
+const IMAGE_TRIES_PER_HOST: usize = 2;
+
 #[derive(Copy, Clone, Debug)]
 pub enum RequestStrategy {
     TryTimes(usize),
@@ -81,7 +127,11 @@ impl SpClient {
     }
 
     pub async fn flush_accesspoint(&self) {
-        self.lock(|inner| inner.accesspoint = None)
+        self.lock(|inner| {
+            if let Some(ap) = inner.accesspoint.take() {
+                inner.flushed_accesspoint = Some(ap);
+            }
+        })
     }
 
     pub async fn get_accesspoint(&self) -> Result<SocketAddress, Error> {
@@ -91,11 +141,20 @@ impl SpClient {
             Some(tuple) => tuple,
             None => {
                 let tuple = self.session().apresolver().resolve("spclient").await?;
-                self.lock(|inner| inner.accesspoint = Some(tuple.clone()));
+                let flushed = self.lock(|inner| {
+                    inner.accesspoint = Some(tuple.clone());
+                    inner.flushed_accesspoint.take()
+                });
                 info!(
                     "Resolved \"{}:{}\" as spclient access point",
                     tuple.0, tuple.1
                 );
+                if flushed.map_or(false, |ap| ap != tuple) {
+                    self.session()
+                        .send_event(SessionEvent::AccessPointSwitched {
+                            access_point: format!("{}:{}", tuple.0, tuple.1),
+                        });
+                }
                 tuple
             }
         };
@@ -107,290 +166,19 @@ impl SpClient {
         Ok(format!("https://{}:{}", ap.0, ap.1))
     }
 
-    fn solve_hash_cash(
-        ctx: &[u8],
-        prefix: &[u8],
-        length: i32,
-        dst: &mut [u8],
-    ) -> Result<(), Error> {
-        // after a certain number of seconds, the challenge expires
-        const TIMEOUT: u64 = 5; // seconds
-        let now = Instant::now();
-
-        let md = Sha1::digest(ctx);
-
-        let mut counter: i64 = 0;
-        let target: i64 = BigEndian::read_i64(&md[12..20]);
-
-        let suffix = loop {
-            if now.elapsed().as_secs() >= TIMEOUT {
-                return Err(Error::deadline_exceeded(format!(
-                    "{TIMEOUT} seconds expired"
-                )));
-            }
-
-            let suffix = [(target + counter).to_be_bytes(), counter.to_be_bytes()].concat();
-
-            let mut hasher = Sha1::new();
-            hasher.update(prefix);
-            hasher.update(&suffix);
-            let md = hasher.finalize();
-
-            if BigEndian::read_i64(&md[12..20]).trailing_zeros() >= (length as u32) {
-                break suffix;
-            }
-
-            counter += 1;
-        };
-
-        dst.copy_from_slice(&suffix);
-
-        Ok(())
-    }
-
-    async fn client_token_request<M: Message>(&self, message: &M) -> Result<Bytes, Error> {
-        let body = message.write_to_bytes()?;
-
-        let request = Request::builder()
-            .method(&Method::POST)
-            .uri("https://clienttoken.spotify.com/v1/clienttoken")
-            .header(ACCEPT, HeaderValue::from_static("application/x-protobuf"))
-            .body(Body::from(body))?;
-
-        self.session().http_client().request_body(request).await
-    }
-
+    /// Returns the client token that is sent along with requests.
     pub async fn client_token(&self) -> Result<String, Error> {
-        let client_token = self.lock(|inner| {
-            if let Some(token) = &inner.client_token {
-                if token.is_expired() {
-                    inner.client_token = None;
-                }
-            }
-            inner.client_token.clone()
-        });
-
-        if let Some(client_token) = client_token {
-            return Ok(client_token.access_token);
-        }
-
-        debug!("Client token unavailable or expired, requesting new token.");
-
-        let mut request = ClientTokenRequest::new();
-        request.request_type = ClientTokenRequestType::REQUEST_CLIENT_DATA_REQUEST.into();
-
-        let client_data = request.mut_client_data();
-
-        client_data.client_version = spotify_version();
-
-        // Current state of affairs: keymaster ID works on all tested platforms, but may be phased out,
-        // so it seems a good idea to mimick the real clients. `self.session().client_id()` returns the
-        // ID of the client that last connected, but requesting a client token with this ID only works
-        // on macOS and Windows. On Android and iOS we can send a platform-specific client ID and are
-        // then presented with a hash cash challenge. On Linux, we have to pass the old keymaster ID.
-        // We delegate most of this logic to `SessionConfig`.
-        let client_id = match OS {
-            "macos" | "windows" => self.session().client_id(),
-            _ => SessionConfig::default().client_id,
-        };
-        client_data.client_id = client_id;
-
-        let connectivity_data = client_data.mut_connectivity_sdk_data();
-        connectivity_data.device_id = self.session().device_id().to_string();
-
-        let platform_data = connectivity_data
-            .platform_specific_data
-            .mut_or_insert_default();
-
-        let sys = System::new();
-        let os_version = sys.os_version().unwrap_or_else(|| String::from("0"));
-        let kernel_version = sys.kernel_version().unwrap_or_else(|| String::from("0"));
-
-        match OS {
-            "windows" => {
-                let os_version = os_version.parse::<f32>().unwrap_or(10.) as i32;
-                let kernel_version = kernel_version.parse::<i32>().unwrap_or(21370);
-
-                let (pe, image_file) = match std::env::consts::ARCH {
-                    "arm" => (448, 452),
-                    "aarch64" => (43620, 452),
-                    "x86_64" => (34404, 34404),
-                    _ => (332, 332), // x86
-                };
-
-                let windows_data = platform_data.mut_desktop_windows();
-                windows_data.os_version = os_version;
-                windows_data.os_build = kernel_version;
-                windows_data.platform_id = 2;
-                windows_data.unknown_value_6 = 9;
-                windows_data.image_file_machine = image_file;
-                windows_data.pe_machine = pe;
-                windows_data.unknown_value_10 = true;
-            }
-            "ios" => {
-                let ios_data = platform_data.mut_ios();
-                ios_data.user_interface_idiom = 0;
-                ios_data.target_iphone_simulator = false;
-                ios_data.hw_machine = "iPhone14,5".to_string();
-                ios_data.system_version = os_version;
-            }
-            "android" => {
-                let android_data = platform_data.mut_android();
-                android_data.android_version = os_version;
-                android_data.api_version = 31;
-                android_data.device_name = "Pixel".to_owned();
-                android_data.model_str = "GF5KQ".to_owned();
-                android_data.vendor = "Google".to_owned();
-            }
-            "macos" => {
-                let macos_data = platform_data.mut_desktop_macos();
-                macos_data.system_version = os_version;
-                macos_data.hw_model = "iMac21,1".to_string();
-                macos_data.compiled_cpu_type = std::env::consts::ARCH.to_string();
-            }
-            _ => {
-                let linux_data = platform_data.mut_desktop_linux();
-                linux_data.system_name = "Linux".to_string();
-                linux_data.system_release = kernel_version;
-                linux_data.system_version = os_version;
-                linux_data.hardware = std::env::consts::ARCH.to_string();
-            }
-        }
-
-        let mut response = self.client_token_request(&request).await?;
-        let mut count = 0;
-        const MAX_TRIES: u8 = 3;
-
-        let token_response = loop {
-            count += 1;
-
-            let message = ClientTokenResponse::parse_from_bytes(&response)?;
-
-            match ClientTokenResponseType::from_i32(message.response_type.value()) {
-                // depending on the platform, you're either given a token immediately
-                // or are presented a hash cash challenge to solve first
-                Some(ClientTokenResponseType::RESPONSE_GRANTED_TOKEN_RESPONSE) => break message,
-                Some(ClientTokenResponseType::RESPONSE_CHALLENGES_RESPONSE) => {
-                    debug!("Received a hash cash challenge, solving...");
-
-                    let challenges = message.challenges().clone();
-                    let state = challenges.state;
-                    if let Some(challenge) = challenges.challenges.first() {
-                        let hash_cash_challenge = challenge.evaluate_hashcash_parameters();
-
-                        let ctx = vec![];
-                        let prefix = hex::decode(&hash_cash_challenge.prefix).map_err(|e| {
-                            Error::failed_precondition(format!(
-                                "Unable to decode hash cash challenge: {e}"
-                            ))
-                        })?;
-                        let length = hash_cash_challenge.length;
-
-                        let mut suffix = vec![0; 0x10];
-                        let answer = Self::solve_hash_cash(&ctx, &prefix, length, &mut suffix);
-
-                        match answer {
-                            Ok(_) => {
-                                // the suffix must be in uppercase
-                                let suffix = hex::encode(suffix).to_uppercase();
-
-                                let mut answer_message = ClientTokenRequest::new();
-                                answer_message.request_type =
-                                    ClientTokenRequestType::REQUEST_CHALLENGE_ANSWERS_REQUEST
-                                        .into();
-
-                                let challenge_answers = answer_message.mut_challenge_answers();
-
-                                let mut challenge_answer = ChallengeAnswer::new();
-                                challenge_answer.mut_hash_cash().suffix = suffix.to_string();
-                                challenge_answer.ChallengeType =
-                                    ChallengeType::CHALLENGE_HASH_CASH.into();
-
-                                challenge_answers.state = state.to_string();
-                                challenge_answers.answers.push(challenge_answer);
-
-                                trace!("Answering hash cash challenge");
-                                match self.client_token_request(&answer_message).await {
-                                    Ok(token) => {
-                                        response = token;
-                                        continue;
-                                    }
-                                    Err(e) => {
-                                        trace!(
-                                            "Answer not accepted {}/{}: {}",
-                                            count,
-                                            MAX_TRIES,
-                                            e
-                                        );
-                                    }
-                                }
-                            }
-                            Err(e) => trace!(
-                                "Unable to solve hash cash challenge {}/{}: {}",
-                                count,
-                                MAX_TRIES,
-                                e
-                            ),
-                        }
-
-                        if count < MAX_TRIES {
-                            response = self.client_token_request(&request).await?;
-                        } else {
-                            return Err(Error::failed_precondition(format!(
-                                "Unable to solve any of {MAX_TRIES} hash cash challenges"
-                            )));
-                        }
-                    } else {
-                        return Err(Error::failed_precondition("No challenges found"));
-                    }
-                }
-
-                Some(unknown) => {
-                    return Err(Error::unimplemented(format!(
-                        "Unknown client token response type: {unknown:?}"
-                    )))
-                }
-                None => return Err(Error::failed_precondition("No client token response type")),
-            }
-        };
-
-        let granted_token = token_response.granted_token();
-        let access_token = granted_token.token.to_owned();
-
-        self.lock(|inner| {
-            let client_token = Token {
-                access_token: access_token.clone(),
-                expires_in: Duration::from_secs(
-                    granted_token
-                        .refresh_after_seconds
-                        .try_into()
-                        .unwrap_or(7200),
-                ),
-                token_type: "client-token".to_string(),
-                scopes: granted_token
-                    .domains
-                    .iter()
-                    .map(|d| d.domain.clone())
-                    .collect(),
-                timestamp: Instant::now(),
-            };
-
-            inner.client_token = Some(client_token);
-        });
-
-        trace!("Got client token: {:?}", granted_token);
-
-        Ok(access_token)
+        self.session().client_token_provider().get_token().await
     }
 
-    pub async fn request_with_protobuf<M: Message + MessageFull>(
+    pub async fn request_with_protobuf<M: Message>(
         &self,
         method: &Method,
         endpoint: &str,
         headers: Option<HeaderMap>,
         message: &M,
     ) -> SpClientResult {
-        let body = protobuf::text_format::print_to_string(message);
+        let body = message.write_to_bytes()?;
 
         let mut headers = headers.unwrap_or_default();
         headers.insert(
@@ -398,10 +186,61 @@ impl SpClient {
             HeaderValue::from_static("application/x-protobuf"),
         );
 
-        self.request(method, endpoint, Some(headers), Some(&body))
+        self.request_body(method, endpoint, Some(headers), body.into())
             .await
     }
 
+    /// Sends `message` to `endpoint` and parses the response as `Resp`, to call endpoints
+    /// that have no method here. The request is authorized, carries the client token and is
+    /// retried like all others.
+    ///
+    /// ```no_run
+    /// # use hyper::Method;
+    /// # use librespot_core::{Error, Session};
+    /// # use librespot_protocol::extended_metadata::{BatchedEntityRequest, BatchedExtensionResponse};
+    /// # async fn example(session: Session, request: BatchedEntityRequest) -> Result<(), Error> {
+    /// let response: BatchedExtensionResponse = session
+    ///     .spclient()
+    ///     .request_message(
+    ///         &Method::POST,
+    ///         "/extended-metadata/v0/extended-metadata",
+    ///         &request,
+    ///     )
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn request_message<Req, Resp>(
+        &self,
+        method: &Method,
+        endpoint: &str,
+        message: &Req,
+    ) -> Result<Resp, Error>
+    where
+        Req: Message,
+        Resp: Message,
+    {
+        let response = self
+            .request_with_protobuf(method, endpoint, Some(Self::accept_protobuf()), message)
+            .await?;
+        Ok(Resp::parse_from_bytes(&response)?)
+    }
+
+    /// Gets `endpoint` and parses the response as `Resp`, like
+    /// [`request_message`](Self::request_message) for requests without a body.
+    pub async fn get_message<Resp: Message>(&self, endpoint: &str) -> Result<Resp, Error> {
+        let response = self
+            .request(&Method::GET, endpoint, Some(Self::accept_protobuf()), None)
+            .await?;
+        Ok(Resp::parse_from_bytes(&response)?)
+    }
+
+    fn accept_protobuf() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/x-protobuf"));
+        headers
+    }
+
     pub async fn request_as_json(
         &self,
         method: &Method,
@@ -415,18 +254,66 @@ impl SpClient {
         self.request(method, endpoint, Some(headers), body).await
     }
 
+    /// Sends a request to `endpoint`. A `GET` request without a body joins an identical one
+    /// that is in flight, so that both callers get the same response.
     pub async fn request(
         &self,
         method: &Method,
         endpoint: &str,
         headers: Option<HeaderMap>,
         body: Option<&str>,
+    ) -> SpClientResult {
+        let body = body.map(|body| Bytes::copy_from_slice(body.as_bytes()));
+        self.request_body(method, endpoint, headers, body.unwrap_or_default())
+            .await
+    }
+
+    /// Like [`request`](Self::request), with a binary body.
+    pub async fn request_body(
+        &self,
+        method: &Method,
+        endpoint: &str,
+        headers: Option<HeaderMap>,
+        body: Bytes,
+    ) -> SpClientResult {
+        if *method != Method::GET || !body.is_empty() {
+            return self.send_request(method, endpoint, headers, body).await;
+        }
+
+        let key = (
+            endpoint.to_owned(),
+            headers
+                .iter()
+                .flatten()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        );
+        let in_flight = self.lock(|inner| inner.in_flight.clone());
+        let client = self.clone();
+        let endpoint = endpoint.to_owned();
+        in_flight
+            .join_or_start(key, || {
+                Ok(async move {
+                    client
+                        .send_request(&Method::GET, &endpoint, headers, Bytes::new())
+                        .await
+                })
+            })?
+            .await
+    }
+
+    async fn send_request(
+        &self,
+        method: &Method,
+        endpoint: &str,
+        headers: Option<HeaderMap>,
+        body: Bytes,
     ) -> SpClientResult {
         let mut tries: usize = 0;
+        let mut scopes = DEFAULT_SCOPES.to_owned();
+        let mut refreshed = false;
         let mut last_response;
 
-        let body = body.unwrap_or_default();
-
         loop {
             tries += 1;
 
@@ -459,14 +346,10 @@ impl SpClient {
             let mut request = Request::builder()
                 .method(method)
                 .uri(url)
-                .body(Body::from(body.to_owned()))?;
+                .body(Body::from(body.clone()))?;
 
             // Reconnection logic: keep getting (cached) tokens because they might have expired.
-            let token = self
-                .session()
-                .token_provider()
-                .get_token("playlist-read")
-                .await?;
+            let token = self.session().token_provider().get_token(&scopes).await?;
 
             let headers_mut = request.headers_mut();
             if let Some(ref hdrs) = headers {
@@ -486,10 +369,46 @@ impl SpClient {
 
             last_response = self.session().http_client().request_body(request).await;
 
-            if last_response.is_ok() {
+            if let Ok(body) = &last_response {
+                self.session()
+                    .metrics()
+                    .increment(Counter::MetadataBytesDownloaded, body.len() as u64);
                 return last_response;
             }
 
+            // Refresh what the request was rejected for once, before giving up on it.
+            if let Some(rejection) = last_response.as_ref().err().and_then(Self::rejection) {
+                let refresh = match &rejection {
+                    _ if refreshed => false,
+                    SpClientError::TokenExpired => {
+                        self.session().token_provider().invalidate(&token);
+                        true
+                    }
+                    SpClientError::MissingScope { scope }
+                        if !scope.is_empty() && !token.in_scope(scope) =>
+                    {
+                        scopes.push(',');
+                        scopes.push_str(scope);
+                        true
+                    }
+                    SpClientError::ClientTokenInvalid => {
+                        self.session().client_token_provider().invalidate();
+                        true
+                    }
+                    _ => false,
+                };
+                if !refresh {
+                    return Err(rejection.into());
+                }
+
+                debug!(
+                    "Request was rejected: {}, refreshing and retrying",
+                    rejection
+                );
+                refreshed = true;
+                continue;
+            }
+
             // Break before the reconnection logic below, so that the current access point
             // is retained when max_tries == 1. Leave it up to the caller when to flush.
             if let RequestStrategy::TryTimes(max_tries) = self.lock(|inner| inner.strategy) {
@@ -518,6 +437,40 @@ impl SpClient {
         last_response
     }
 
+    // Tells why a request was rejected with `401 Unauthorized` or `403 Forbidden`, if that
+    // is something a refreshed token may fix.
+    fn rejection(error: &Error) -> Option<SpClientError> {
+        let (code, challenge, message) = match error.error.downcast_ref::<HttpClientError>()? {
+            HttpClientError::Unauthorized {
+                code,
+                challenge,
+                message,
+            } => (*code, challenge.as_deref().unwrap_or_default(), message),
+            _ => return None,
+        };
+        let message = message.to_lowercase();
+
+        // e.g. `Bearer error="insufficient_scope", scope="user-read-private"`
+        if challenge.contains("insufficient_scope") || message.contains("scope") {
+            let scope = challenge
+                .split_once("scope=\"")
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .map(|(scope, _)| scope.to_owned())
+                .unwrap_or_default();
+            return Some(SpClientError::MissingScope { scope });
+        }
+        if message.contains("client token") || message.contains("client-token") {
+            return Some(SpClientError::ClientTokenInvalid);
+        }
+        if code == StatusCode::UNAUTHORIZED
+            || challenge.contains("invalid_token")
+            || message.contains("expired")
+        {
+            return Some(SpClientError::TokenExpired);
+        }
+        None
+    }
+
     pub async fn put_connect_state(
         &self,
         connection_id: &str,
@@ -532,9 +485,42 @@ impl SpClient {
             .await
     }
 
+    /// Fetches the metadata of an item, or returns the copy that was kept for offline
+    /// playback with [`save_offline_metadata`](Self::save_offline_metadata) if that fails.
     pub async fn get_metadata(&self, scope: &str, id: &SpotifyId) -> SpClientResult {
         let endpoint = format!("/metadata/4/{}/{}", scope, id.to_base16()?);
-        self.request(&Method::GET, &endpoint, None, None).await
+        match self.request(&Method::GET, &endpoint, None, None).await {
+            Err(e) => {
+                let offline = self.session().cache().and_then(|cache| {
+                    let data = cache.metadata(&Self::offline_metadata_key(scope, id).ok()?)?;
+                    debug!("Using offline metadata of {}: {}", id.to_uri().ok()?, e);
+                    Some(Bytes::from(data))
+                });
+                offline.ok_or(e)
+            }
+            result => result,
+        }
+    }
+
+    fn offline_metadata_key(scope: &str, id: &SpotifyId) -> Result<String, Error> {
+        Ok(format!("offline-{}-{}", scope, id.to_base16()?))
+    }
+
+    /// Fetches the metadata of an item and keeps it in the cache, so that
+    /// [`get_metadata`](Self::get_metadata) returns it without a connection.
+    pub async fn save_offline_metadata(&self, scope: &str, id: &SpotifyId) -> Result<(), Error> {
+        let session = self.session();
+        let cache = session.cache().ok_or(CacheError::Path)?;
+        let data = self.get_metadata(scope, id).await?;
+        cache.save_metadata(&Self::offline_metadata_key(scope, id)?, &data);
+        Ok(())
+    }
+
+    pub fn remove_offline_metadata(&self, scope: &str, id: &SpotifyId) -> Result<(), Error> {
+        if let Some(cache) = self.session().cache() {
+            cache.remove_metadata(&Self::offline_metadata_key(scope, id)?);
+        }
+        Ok(())
     }
 
     pub async fn get_track_metadata(&self, track_id: &SpotifyId) -> SpClientResult {
@@ -585,6 +571,107 @@ impl SpClient {
         self.request(&Method::GET, &endpoint, None, None).await
     }
 
+    /// Requests the items of a playlist from index `from` on, to continue reading a playlist
+    /// whose contents were truncated.
+    pub async fn get_playlist_page(
+        &self,
+        playlist_id: &SpotifyId,
+        from: usize,
+        length: Option<usize>,
+    ) -> SpClientResult {
+        let mut endpoint = format!(
+            "/playlist/v2/playlist/{}?from={}",
+            playlist_id.to_base62()?,
+            from
+        );
+        if let Some(length) = length {
+            endpoint.push_str(&format!("&length={}", length));
+        }
+
+        self.request(&Method::GET, &endpoint, None, None).await
+    }
+
+    /// Requests the changes to a playlist since `revision`, as returned in a previous response.
+    pub async fn get_playlist_diff(
+        &self,
+        playlist_id: &SpotifyId,
+        revision: &[u8],
+    ) -> SpClientResult {
+        let endpoint = format!(
+            "/playlist/v2/playlist/{}/diff?revision={}&handlesContent=",
+            playlist_id.to_base62()?,
+            Self::format_revision(revision)
+        );
+
+        self.request(&Method::GET, &endpoint, None, None).await
+    }
+
+    /// Requests the playlists and folders in the user's library, with their names.
+    pub async fn get_rootlist(&self, from: usize, length: Option<usize>) -> SpClientResult {
+        let length = length.unwrap_or(120);
+        let user = self.session().username();
+        let endpoint = format!(
+            "/playlist/v2/user/{user}/rootlist?decorate=revision,attributes,length,owner&from={from}&length={length}"
+        );
+
+        self.request(&Method::GET, &endpoint, None, None).await
+    }
+
+    /// Requests the changes to the rootlist since `revision`, as returned in a previous
+    /// response. Unlike [`get_rootlist`](Self::get_rootlist), the added playlists are not
+    /// decorated with their names.
+    pub async fn get_rootlist_diff(&self, revision: &[u8]) -> SpClientResult {
+        let user = self.session().username();
+        let endpoint = format!(
+            "/playlist/v2/user/{user}/rootlist/diff?revision={}&handlesContent=",
+            Self::format_revision(revision)
+        );
+
+        self.request(&Method::GET, &endpoint, None, None).await
+    }
+
+    // Revisions are a big endian counter followed by a hash.
+    fn format_revision(revision: &[u8]) -> String {
+        match revision.split_at(revision.len().min(4)) {
+            (counter, hash) if counter.len() == 4 => format!(
+                "{},{}",
+                u32::from_be_bytes([counter[0], counter[1], counter[2], counter[3]]),
+                hex::encode(hash)
+            ),
+            _ => hex::encode(revision),
+        }
+    }
+
+    /// Requests a page of a set in the user's collection, e.g. `collection` for the liked
+    /// songs and albums or `artist` for the followed artists.
+    pub async fn get_collection_page(
+        &self,
+        set: &str,
+        pagination_token: Option<&str>,
+        limit: Option<i32>,
+    ) -> SpClientResult {
+        let mut request = PageRequest::new();
+        request.username = self.session().username();
+        request.set = set.to_owned();
+        request.pagination_token = pagination_token.unwrap_or_default().to_owned();
+        request.limit = limit.unwrap_or(300);
+
+        self.request_with_protobuf(&Method::POST, "/collection/v2/paging", None, &request)
+            .await
+    }
+
+    /// Requests the changes to a set in the user's collection since `sync_token`, as
+    /// returned in a previous response.
+    pub async fn get_collection_delta(&self, set: &str, sync_token: &str) -> SpClientResult {
+        let mut request = DeltaRequest::new();
+        request.username = self.session().username();
+        request.set = set.to_owned();
+        request.last_sync_token = sync_token.to_owned();
+
+        self.request_with_protobuf(&Method::POST, "/collection/v2/delta", None, &request)
+            .await
+    }
+
     pub async fn get_user_profile(
         &self,
         username: &str,
@@ -765,14 +852,136 @@ impl SpClient {
         self.request_url(&url).await
     }
 
-    pub async fn get_image(&self, image_id: &FileId) -> SpClientResult {
-        let attribute = "image-url";
+    /// The CDN URL of an image, e.g. of a `spotify:image:{base16}` URI parsed with
+    /// [`FileId::from_uri`].
+    pub fn get_image_url(&self, image_id: &FileId) -> Result<String, Error> {
         let template = self
             .session()
-            .get_user_attribute(attribute)
-            .ok_or_else(|| SpClientError::Attribute(attribute.to_string()))?;
-        let url = template.replace("{file_id}", &image_id.to_base16()?);
+            .get_user_attribute("image-url")
+            .unwrap_or_else(|| DEFAULT_IMAGE_URL.to_owned());
+        Ok(template.replace("{file_id}", &image_id.to_base16()?))
+    }
 
-        self.request_url(&url).await
+    pub async fn get_image(&self, image_id: &FileId) -> SpClientResult {
+        let mut url = Url::parse(&self.get_image_url(image_id)?)?;
+
+        // Start with the host that worked last, so that an unreachable host is only
+        // tried again when the others fail as well.
+        let mut hosts: Vec<String> = Vec::with_capacity(IMAGE_HOSTS.len() + 2);
+        let candidates = self
+            .lock(|inner| inner.image_host.clone())
+            .into_iter()
+            .chain(url.host_str().map(str::to_owned))
+            .chain(IMAGE_HOSTS.iter().map(|host| host.to_string()));
+        for host in candidates {
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+
+        let mut last_response = Err(Error::unavailable("no image hosts"));
+        for host in hosts {
+            url.set_host(Some(&host))?;
+
+            for _ in 0..IMAGE_TRIES_PER_HOST {
+                last_response = self.request_url(url.as_str()).await;
+
+                match last_response {
+                    Ok(_) => {
+                        self.lock(|inner| inner.image_host = Some(host));
+                        return last_response;
+                    }
+                    Err(ref e) => match e.kind {
+                        ErrorKind::Unavailable | ErrorKind::DeadlineExceeded => {
+                            debug!("Failed to fetch image from {}: {}", host, e);
+                        }
+                        _ => return last_response, // other hosts won't do any better
+                    },
+                }
+            }
+        }
+
+        last_response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{FutureExt, StreamExt};
+
+    use crate::{
+        config::{AccessPointConfig, AccessPointResolvePolicy, SessionConfig},
+        Session,
+    };
+
+    #[tokio::test]
+    async fn reports_switching_the_access_point() {
+        // resolves to the fallback spclient.wg.spotify.com:443
+        let session = Session::new(
+            SessionConfig {
+                access_points: AccessPointConfig {
+                    resolve: AccessPointResolvePolicy::Never,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        let mut events = session.events();
+        let spclient = session.spclient();
+        spclient.lock(|inner| inner.accesspoint = Some(("spclient.example".into(), 443)));
+
+        // the first one, and the one that is memoized, are no switch
+        spclient.get_accesspoint().await.unwrap();
+        assert!(events.next().now_or_never().is_none());
+
+        spclient.flush_accesspoint().await;
+        spclient.get_accesspoint().await.unwrap();
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(SessionEvent::AccessPointSwitched {
+                access_point: "spclient.wg.spotify.com:443".to_owned()
+            }))
+        );
+
+        // the same one again
+        spclient.flush_accesspoint().await;
+        spclient.get_accesspoint().await.unwrap();
+        assert!(events.next().now_or_never().is_none());
+    }
+
+    fn rejection(code: StatusCode, challenge: Option<&str>, message: &str) -> Option<String> {
+        let error = Error::from(HttpClientError::Unauthorized {
+            code,
+            challenge: challenge.map(str::to_owned),
+            message: message.to_owned(),
+        });
+        SpClient::rejection(&error).map(|rejection| format!("{rejection:?}"))
+    }
+
+    #[test]
+    fn classifies_rejections() {
+        assert_eq!(
+            rejection(
+                StatusCode::FORBIDDEN,
+                Some(r#"Bearer error="insufficient_scope", scope="user-read-private""#),
+                "",
+            ),
+            Some(r#"MissingScope { scope: "user-read-private" }"#.to_owned())
+        );
+        assert_eq!(
+            rejection(StatusCode::FORBIDDEN, None, "Insufficient client scope"),
+            Some(r#"MissingScope { scope: "" }"#.to_owned())
+        );
+        assert_eq!(
+            rejection(StatusCode::FORBIDDEN, None, "Invalid client token"),
+            Some("ClientTokenInvalid".to_owned())
+        );
+        assert_eq!(
+            rejection(StatusCode::UNAUTHORIZED, None, "The access token expired"),
+            Some("TokenExpired".to_owned())
+        );
+        assert_eq!(rejection(StatusCode::FORBIDDEN, None, "Forbidden"), None);
     }
 }
@@ -2,11 +2,17 @@ use std::{
     convert::{TryFrom, TryInto},
     fmt,
     ops::Deref,
+    time::Duration,
 };
 
+use percent_encoding::percent_decode_str;
 use thiserror::Error;
+use url::{form_urlencoded, Url};
 
-use crate::Error;
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    Error,
+};
 
 use librespot_protocol as protocol;
 
@@ -17,8 +23,10 @@ pub use crate::FileId;
 pub enum SpotifyItemType {
     Album,
     Artist,
+    Concert,
     Episode,
     Playlist,
+    Prerelease,
     Show,
     Track,
     Local,
@@ -30,8 +38,10 @@ impl From<&str> for SpotifyItemType {
         match v {
             "album" => Self::Album,
             "artist" => Self::Artist,
+            "concert" => Self::Concert,
             "episode" => Self::Episode,
             "playlist" => Self::Playlist,
+            "prerelease" => Self::Prerelease,
             "show" => Self::Show,
             "track" => Self::Track,
             "local" => Self::Local,
@@ -45,8 +55,10 @@ impl From<SpotifyItemType> for &str {
         match item_type {
             SpotifyItemType::Album => "album",
             SpotifyItemType::Artist => "artist",
+            SpotifyItemType::Concert => "concert",
             SpotifyItemType::Episode => "episode",
             SpotifyItemType::Playlist => "playlist",
+            SpotifyItemType::Prerelease => "prerelease",
             SpotifyItemType::Show => "show",
             SpotifyItemType::Track => "track",
             SpotifyItemType::Local => "local",
@@ -69,24 +81,74 @@ pub enum SpotifyIdError {
     InvalidFormat,
     #[error("URI does not belong to Spotify")]
     InvalidRoot,
+    #[error("ID is not of the expected item type")]
+    InvalidItemType,
 }
 
 impl From<SpotifyIdError> for Error {
     fn from(err: SpotifyIdError) -> Self {
-        Error::invalid_argument(err)
+        Error::with_code(ErrorKind::InvalidArgument, err)
+    }
+}
+
+impl ErrorCode for SpotifyIdError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidId => "spotify_id.invalid_id",
+            Self::InvalidFormat => "spotify_id.invalid_format",
+            Self::InvalidRoot => "spotify_id.invalid_root",
+            Self::InvalidItemType => "spotify_id.invalid_item_type",
+        }
     }
 }
 
 pub type SpotifyIdResult = Result<SpotifyId, Error>;
 pub type NamedSpotifyIdResult = Result<NamedSpotifyId, Error>;
 
-const BASE62_DIGITS: &[u8; 62] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+pub(crate) const BASE62_DIGITS: &[u8; 62] =
+    b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
 const BASE16_DIGITS: &[u8; 16] = b"0123456789abcdef";
 
+const SHARE_URL_HOST: &str = "open.spotify.com";
+
+fn is_share_url(src: &str) -> bool {
+    src.starts_with("https://") || src.starts_with("http://")
+}
+
+// Converts a share URL like `https://open.spotify.com/intl-de/track/{id}?si=...` into the
+// URI it links to.
+fn uri_from_share_url(src: &str) -> Result<String, Error> {
+    let url = Url::parse(src).map_err(|_| SpotifyIdError::InvalidFormat)?;
+    if url.host_str() != Some(SHARE_URL_HOST) {
+        return Err(SpotifyIdError::InvalidRoot.into());
+    }
+
+    let mut segments: Vec<&str> = url
+        .path_segments()
+        .ok_or(SpotifyIdError::InvalidFormat)?
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    // Localised links start with e.g. `intl-de`.
+    if segments
+        .first()
+        .map_or(false, |segment| segment.starts_with("intl-"))
+    {
+        segments.remove(0);
+    }
+
+    if segments.len() < 2 {
+        return Err(SpotifyIdError::InvalidFormat.into());
+    }
+
+    Ok(format!("spotify:{}", segments.join(":")))
+}
+
 impl SpotifyId {
     const SIZE: usize = 16;
     const SIZE_BASE16: usize = 32;
-    const SIZE_BASE62: usize = 22;
+    /// The length of a base62 encoded `SpotifyId`, see [`write_base62`](Self::write_base62).
+    pub const SIZE_BASE62: usize = 22;
 
     /// Returns whether this `SpotifyId` is for a playable audio item, if known.
     pub fn is_playable(&self) -> bool {
@@ -168,8 +230,14 @@ impl SpotifyId {
     /// Note that this should not be used for playlists, which have the form of
     /// `spotify:playlist:{id}`.
     ///
+    /// Share URLs like `https://open.spotify.com/track/{id}?si=...` are accepted as well.
+    ///
     /// [Spotify URI]: https://developer.spotify.com/documentation/web-api/concepts/spotify-uris-ids
     pub fn from_uri(src: &str) -> SpotifyIdResult {
+        if is_share_url(src) {
+            return Self::from_uri(&uri_from_share_url(src)?);
+        }
+
         // Basic: `spotify:{type}:{id}`
         // Named: `spotify:user:{user}:{type}:{id}`
         // Local: `spotify:local:{artist}:{album_title}:{track_title}:{duration_in_seconds}`
@@ -226,7 +294,16 @@ impl SpotifyId {
     /// [canonically]: https://developer.spotify.com/documentation/web-api/concepts/spotify-uris-ids
     #[allow(clippy::wrong_self_convention)]
     pub fn to_base62(&self) -> Result<String, Error> {
-        let mut dst = [0u8; 22];
+        let mut dst = [0u8; Self::SIZE_BASE62];
+        Ok(self.write_base62(&mut dst).to_owned())
+    }
+
+    /// Writes the `SpotifyId` [canonically] base62 encoded into `dst` and returns it as a
+    /// `&str`, without allocating.
+    ///
+    /// [canonically]: https://developer.spotify.com/documentation/web-api/concepts/spotify-uris-ids
+    pub fn write_base62<'a>(&self, dst: &'a mut [u8; Self::SIZE_BASE62]) -> &'a str {
+        dst.fill(0);
         let mut i = 0;
         let n = self.id;
 
@@ -257,13 +334,26 @@ impl SpotifyId {
             }
         }
 
-        for b in &mut dst {
+        for b in dst.iter_mut() {
             *b = BASE62_DIGITS[*b as usize];
         }
 
         dst.reverse();
 
-        String::from_utf8(dst.to_vec()).map_err(|_| SpotifyIdError::InvalidId.into())
+        // only base62 digits were written, which are ASCII
+        std::str::from_utf8(dst).unwrap_or_default()
+    }
+
+    /// Writes the `SpotifyId` as a [Spotify URI], like [`to_uri`](Self::to_uri), but without
+    /// allocating.
+    ///
+    /// [Spotify URI]: https://developer.spotify.com/documentation/web-api/concepts/spotify-uris-ids
+    pub fn write_uri<W: fmt::Write>(&self, dst: &mut W) -> fmt::Result {
+        let item_type: &str = self.item_type.into();
+        dst.write_str("spotify:")?;
+        dst.write_str(item_type)?;
+        dst.write_char(':')?;
+        dst.write_str(self.write_base62(&mut [0u8; Self::SIZE_BASE62]))
     }
 
     /// Returns a copy of the `SpotifyId` as an array of `SpotifyId::SIZE` (16) bytes in
@@ -290,24 +380,143 @@ impl SpotifyId {
         dst.push_str("spotify:");
         dst.push_str(item_type);
         dst.push(':');
-        let base_62 = self.to_base62()?;
-        dst.push_str(&base_62);
+        dst.push_str(self.write_base62(&mut [0u8; Self::SIZE_BASE62]));
 
         Ok(dst)
     }
+
+    /// Parses a URI of the form `spotify:{type}:{id}` in a const context, see
+    /// [`spotify_id!`](crate::spotify_id!).
+    #[doc(hidden)]
+    pub const fn from_uri_const(src: &str) -> Result<Self, SpotifyIdError> {
+        const SCHEME: &[u8] = b"spotify:";
+
+        let src = src.as_bytes();
+        if !bytes_eq(src, 0, SCHEME.len(), SCHEME) {
+            return Err(SpotifyIdError::InvalidRoot);
+        }
+
+        let type_start = SCHEME.len();
+        let mut type_end = type_start;
+        while type_end < src.len() && src[type_end] != b':' {
+            type_end += 1;
+        }
+
+        let item_type = match item_type_from_bytes(src, type_start, type_end) {
+            SpotifyItemType::Local | SpotifyItemType::Unknown => {
+                return Err(SpotifyIdError::InvalidFormat)
+            }
+            item_type => item_type,
+        };
+
+        let id_start = type_end + 1;
+        if src.len() != id_start + Self::SIZE_BASE62 {
+            return Err(SpotifyIdError::InvalidId);
+        }
+
+        let mut id: u128 = 0;
+        let mut i = id_start;
+        while i < src.len() {
+            let c = src[i];
+            let p = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'z' => c - b'a' + 10,
+                b'A'..=b'Z' => c - b'A' + 36,
+                _ => return Err(SpotifyIdError::InvalidId),
+            } as u128;
+
+            id = match id.checked_mul(62) {
+                Some(id) => id,
+                None => return Err(SpotifyIdError::InvalidId),
+            };
+            id = match id.checked_add(p) {
+                Some(id) => id,
+                None => return Err(SpotifyIdError::InvalidId),
+            };
+            i += 1;
+        }
+
+        Ok(Self { id, item_type })
+    }
+}
+
+// Whether `src[start..end]` is `expected`, as slicing isn't possible in const functions.
+const fn bytes_eq(src: &[u8], start: usize, end: usize, expected: &[u8]) -> bool {
+    if end > src.len() || end - start != expected.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < expected.len() {
+        if src[start + i] != expected[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn item_type_from_bytes(src: &[u8], start: usize, end: usize) -> SpotifyItemType {
+    if bytes_eq(src, start, end, b"album") {
+        SpotifyItemType::Album
+    } else if bytes_eq(src, start, end, b"artist") {
+        SpotifyItemType::Artist
+    } else if bytes_eq(src, start, end, b"concert") {
+        SpotifyItemType::Concert
+    } else if bytes_eq(src, start, end, b"episode") {
+        SpotifyItemType::Episode
+    } else if bytes_eq(src, start, end, b"playlist") {
+        SpotifyItemType::Playlist
+    } else if bytes_eq(src, start, end, b"prerelease") {
+        SpotifyItemType::Prerelease
+    } else if bytes_eq(src, start, end, b"show") {
+        SpotifyItemType::Show
+    } else if bytes_eq(src, start, end, b"track") {
+        SpotifyItemType::Track
+    } else {
+        SpotifyItemType::Unknown
+    }
+}
+
+/// Creates a [`SpotifyId`](crate::SpotifyId) from a URI of the form `spotify:{type}:{id}`,
+/// which is validated at compile time, e.g. for built-in fallback contexts:
+///
+/// ```
+/// use librespot_core::{spotify_id, SpotifyId};
+///
+/// const FALLBACK_PLAYLIST: SpotifyId = spotify_id!("spotify:playlist:37i9dQZF1DXec50AjHrNTq");
+/// ```
+#[macro_export]
+macro_rules! spotify_id {
+    ($uri:literal) => {{
+        const ID: $crate::SpotifyId = match $crate::SpotifyId::from_uri_const($uri) {
+            Ok(id) => id,
+            Err(_) => panic!(concat!("invalid Spotify URI: ", $uri)),
+        };
+        ID
+    }};
+}
+
+// Formats like the `Debug` of a `String` holding the URI, without allocating it.
+struct DebugUri<'a>(&'a SpotifyId);
+
+impl fmt::Debug for DebugUri<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        self.0.write_uri(f)?;
+        f.write_str("\"")
+    }
 }
 
 impl fmt::Debug for SpotifyId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_tuple("SpotifyId")
-            .field(&self.to_uri().unwrap_or_else(|_| "invalid uri".into()))
-            .finish()
+        f.debug_tuple("SpotifyId").field(&DebugUri(self)).finish()
     }
 }
 
 impl fmt::Display for SpotifyId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&self.to_uri().unwrap_or_else(|_| "invalid uri".into()))
+        self.write_uri(f)
     }
 }
 
@@ -319,6 +528,10 @@ pub struct NamedSpotifyId {
 
 impl NamedSpotifyId {
     pub fn from_uri(src: &str) -> NamedSpotifyIdResult {
+        if is_share_url(src) {
+            return Self::from_uri(&uri_from_share_url(src)?);
+        }
+
         let uri_parts: Vec<&str> = src.split(':').collect();
 
         // At minimum, should be `spotify:user:{username}:{type}:{id}`
@@ -372,27 +585,599 @@ impl Deref for NamedSpotifyId {
 impl fmt::Debug for NamedSpotifyId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("NamedSpotifyId")
-            .field(
-                &self
-                    .inner_id
-                    .to_uri()
-                    .unwrap_or_else(|_| "invalid id".into()),
-            )
+            .field(&DebugUri(&self.inner_id))
             .finish()
     }
 }
 
 impl fmt::Display for NamedSpotifyId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(
-            &self
-                .inner_id
-                .to_uri()
-                .unwrap_or_else(|_| "invalid id".into()),
+        self.inner_id.write_uri(f)
+    }
+}
+
+const SEARCH_URI_PREFIX: &str = "spotify:search:";
+
+// Free text in URIs is form-urlencoded, with spaces as `+`.
+fn decode_uri_component(src: &str) -> Result<String, Error> {
+    let src = src.replace('+', " ");
+    percent_decode_str(&src)
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .map_err(|_| SpotifyIdError::InvalidFormat.into())
+}
+
+fn encode_uri_component(src: &str) -> String {
+    form_urlencoded::byte_serialize(src.as_bytes()).collect()
+}
+
+/// A `spotify:search:{query}` URI, as shared by the desktop clients. The query is
+/// form-urlencoded in the URI, e.g. `spotify:search:daft+punk`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpotifySearch {
+    pub query: String,
+}
+
+impl SpotifySearch {
+    pub fn new(query: &str) -> Self {
+        Self {
+            query: query.to_owned(),
+        }
+    }
+
+    pub fn from_uri(src: &str) -> Result<Self, Error> {
+        if !src.starts_with("spotify:") {
+            return Err(SpotifyIdError::InvalidRoot.into());
+        }
+
+        let query = src
+            .strip_prefix(SEARCH_URI_PREFIX)
+            .ok_or(SpotifyIdError::InvalidFormat)?;
+
+        Ok(Self {
+            query: decode_uri_component(query)?,
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        format!("{}{}", SEARCH_URI_PREFIX, encode_uri_component(&self.query))
+    }
+}
+
+impl fmt::Display for SpotifySearch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_uri())
+    }
+}
+
+const USER_URI_PREFIX: &str = "spotify:user:";
+
+/// A `spotify:user:{username}` URI of the profile of a user. The username is
+/// form-urlencoded in the URI.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpotifyUser {
+    pub username: String,
+}
+
+impl SpotifyUser {
+    pub fn new(username: &str) -> Self {
+        Self {
+            username: username.to_owned(),
+        }
+    }
+
+    pub fn from_uri(src: &str) -> Result<Self, Error> {
+        if !src.starts_with("spotify:") {
+            return Err(SpotifyIdError::InvalidRoot.into());
+        }
+
+        // leaves out items in the namespace of the user, like `spotify:user:{username}:collection`
+        let username = src
+            .strip_prefix(USER_URI_PREFIX)
+            .filter(|username| !username.is_empty() && !username.contains(':'))
+            .ok_or(SpotifyIdError::InvalidFormat)?;
+
+        Ok(Self {
+            username: decode_uri_component(username)?,
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        format!(
+            "{}{}",
+            USER_URI_PREFIX,
+            encode_uri_component(&self.username)
         )
     }
 }
 
+impl fmt::Display for SpotifyUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_uri())
+    }
+}
+
+/// The collection of the user, as played from Connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpotifyCollection {
+    /// The liked songs: `spotify:collection:tracks`, or `spotify:user:{username}:collection`
+    /// as sent by some clients.
+    Tracks,
+    /// The saved albums: `spotify:collection:albums`.
+    Albums,
+}
+
+impl SpotifyCollection {
+    pub fn from_uri(src: &str) -> Result<Self, Error> {
+        let uri_parts: Vec<&str> = src.split(':').collect();
+
+        if uri_parts[0] != "spotify" {
+            return Err(SpotifyIdError::InvalidRoot.into());
+        }
+
+        match &uri_parts[1..] {
+            ["collection", "tracks"] | ["user", _, "collection"] => Ok(Self::Tracks),
+            ["collection", "albums"] => Ok(Self::Albums),
+            _ => Err(SpotifyIdError::InvalidFormat.into()),
+        }
+    }
+
+    pub fn to_uri(&self) -> String {
+        match self {
+            Self::Tracks => "spotify:collection:tracks",
+            Self::Albums => "spotify:collection:albums",
+        }
+        .to_owned()
+    }
+}
+
+impl fmt::Display for SpotifyCollection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_uri())
+    }
+}
+
+/// A folder marker in the rootlist of the user. The playlists between a `Start` and the
+/// `End` with the same `id` are in that folder; folders may be nested.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SpotifyPlaylistGroup {
+    /// `spotify:start-group:{id}:{name}`, with the name form-urlencoded.
+    Start { id: String, name: String },
+    /// `spotify:end-group:{id}`
+    End { id: String },
+}
+
+impl SpotifyPlaylistGroup {
+    pub fn from_uri(src: &str) -> Result<Self, Error> {
+        // The name is last, and its `:`s are encoded, but don't rely on it.
+        let uri_parts: Vec<&str> = src.splitn(4, ':').collect();
+
+        if uri_parts[0] != "spotify" {
+            return Err(SpotifyIdError::InvalidRoot.into());
+        }
+
+        match &uri_parts[1..] {
+            ["start-group", id, name] if !id.is_empty() => Ok(Self::Start {
+                id: (*id).to_owned(),
+                name: decode_uri_component(name)?,
+            }),
+            ["end-group", id] if !id.is_empty() => Ok(Self::End {
+                id: (*id).to_owned(),
+            }),
+            _ => Err(SpotifyIdError::InvalidFormat.into()),
+        }
+    }
+
+    pub fn to_uri(&self) -> String {
+        match self {
+            Self::Start { id, name } => {
+                format!("spotify:start-group:{}:{}", id, encode_uri_component(name))
+            }
+            Self::End { id } => format!("spotify:end-group:{}", id),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Start { id, .. } | Self::End { id } => id,
+        }
+    }
+}
+
+impl fmt::Display for SpotifyPlaylistGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_uri())
+    }
+}
+
+/// Builds the URIs that a [`SpotifyId`] can't represent on its own: those in the namespace
+/// of a user, stations and local files. The built URIs are canonical, see [`canonicalize_uri`].
+///
+/// ```
+/// # use librespot_core::spotify_id::{PlaylistId, SpotifyUriBuilder};
+/// let id = PlaylistId::from_base62("37i9dQZF1DWSw8liJZcPOI")?;
+/// let uri = SpotifyUriBuilder::new().user("spotify").id(id)?;
+/// assert_eq!(uri, "spotify:user:spotify:playlist:37i9dQZF1DWSw8liJZcPOI");
+/// # Ok::<(), librespot_core::Error>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpotifyUriBuilder {
+    username: Option<String>,
+    station: bool,
+}
+
+impl SpotifyUriBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts the URI in the namespace of `username`: `spotify:user:{username}:...`.
+    pub fn user(mut self, username: &str) -> Self {
+        self.username = Some(username.to_owned());
+        self
+    }
+
+    /// Builds the URI of the station seeded by the item: `spotify:station:{type}:{id}`.
+    pub fn station(mut self) -> Self {
+        self.station = true;
+        self
+    }
+
+    pub fn id(self, id: impl Into<SpotifyId>) -> Result<String, Error> {
+        let id = id.into();
+        if id.item_type == SpotifyItemType::Local {
+            return Err(SpotifyIdError::InvalidItemType.into());
+        }
+
+        let item_type: &str = id.item_type.into();
+        let mut dst = self.prefix();
+        if self.station {
+            dst.push_str("station:");
+        }
+        dst.push_str(item_type);
+        dst.push(':');
+        dst.push_str(&id.to_base62()?);
+
+        Ok(dst)
+    }
+
+    /// Builds `spotify:local:{artist}:{album_title}:{track_title}:{duration_in_seconds}`.
+    /// Local files can't be in the namespace of a user, or seed a station.
+    pub fn local(
+        self,
+        artist: &str,
+        album_title: &str,
+        track_title: &str,
+        duration: Duration,
+    ) -> Result<String, Error> {
+        if self.username.is_some() || self.station {
+            return Err(SpotifyIdError::InvalidFormat.into());
+        }
+
+        Ok(format!(
+            "spotify:local:{}:{}:{}:{}",
+            encode_uri_component(artist),
+            encode_uri_component(album_title),
+            encode_uri_component(track_title),
+            duration.as_secs()
+        ))
+    }
+
+    fn prefix(&self) -> String {
+        match &self.username {
+            Some(username) => format!("spotify:user:{}:", encode_uri_component(username)),
+            None => "spotify:".to_owned(),
+        }
+    }
+}
+
+// The segments of a URI that are matched case insensitively. IDs are case sensitive.
+const URI_KEYWORDS: &[&str] = &[
+    "album",
+    "albums",
+    "artist",
+    "collection",
+    "concert",
+    "end-group",
+    "episode",
+    "playlist",
+    "prerelease",
+    "show",
+    "station",
+    "track",
+    "tracks",
+];
+
+/// Returns the canonical form of a Spotify URI, or of the URI a share URL links to: the
+/// keywords are in lowercase, and the free text (usernames, search queries, folder names
+/// and the fields of local files) is form-urlencoded the way the Spotify clients do.
+///
+/// Canonicalizing a canonical URI returns it unchanged, and the types in this module parse
+/// a URI and its canonical form to the same value.
+pub fn canonicalize_uri(src: &str) -> Result<String, Error> {
+    let src = if is_share_url(src) {
+        uri_from_share_url(src)?
+    } else {
+        src.to_owned()
+    };
+
+    let mut parts = src.split(':');
+    if !parts
+        .next()
+        .map_or(false, |scheme| scheme.eq_ignore_ascii_case("spotify"))
+    {
+        return Err(SpotifyIdError::InvalidRoot.into());
+    }
+
+    let mut dst = vec!["spotify".to_owned()];
+    while let Some(part) = parts.next() {
+        let keyword = part.to_ascii_lowercase();
+        match keyword.as_str() {
+            "user" => {
+                let username = parts.next().ok_or(SpotifyIdError::InvalidFormat)?;
+                dst.push(keyword);
+                dst.push(encode_uri_component(&decode_uri_component(username)?));
+            }
+            "local" => {
+                dst.push(keyword);
+                for field in parts.by_ref() {
+                    dst.push(encode_uri_component(&decode_uri_component(field)?));
+                }
+            }
+            // Unlike the other free text, these run until the end of the URI, and may
+            // contain unescaped colons.
+            "search" | "start-group" => {
+                dst.push(keyword.clone());
+                if keyword == "start-group" {
+                    let id = parts.next().ok_or(SpotifyIdError::InvalidFormat)?;
+                    dst.push(id.to_owned());
+                }
+                let text = parts.by_ref().collect::<Vec<_>>().join(":");
+                dst.push(encode_uri_component(&decode_uri_component(&text)?));
+            }
+            _ if URI_KEYWORDS.contains(&keyword.as_str()) => dst.push(keyword),
+            _ => dst.push(part.to_owned()),
+        }
+    }
+
+    Ok(dst.join(":"))
+}
+
+macro_rules! typed_spotify_id {
+    ($(#[$doc:meta])* $name:ident, $item_type:ident) => {
+        $(#[$doc])*
+        ///
+        /// Converts into a [`SpotifyId`] infallibly, and from one if the item type matches.
+        #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct $name(SpotifyId);
+
+        impl $name {
+            pub const ITEM_TYPE: SpotifyItemType = SpotifyItemType::$item_type;
+
+            fn with_item_type(id: SpotifyId) -> Self {
+                Self(SpotifyId {
+                    item_type: Self::ITEM_TYPE,
+                    ..id
+                })
+            }
+
+            /// Parses a base16 (hex) encoded Spotify ID, which doesn't contain the item type.
+            pub fn from_base16(src: &str) -> Result<Self, Error> {
+                SpotifyId::from_base16(src).map(Self::with_item_type)
+            }
+
+            /// Parses a base62 encoded Spotify ID, which doesn't contain the item type.
+            pub fn from_base62(src: &str) -> Result<Self, Error> {
+                SpotifyId::from_base62(src).map(Self::with_item_type)
+            }
+
+            /// Creates an ID from a raw 16 byte Spotify ID, which doesn't contain the item
+            /// type.
+            pub fn from_raw(src: &[u8]) -> Result<Self, Error> {
+                SpotifyId::from_raw(src).map(Self::with_item_type)
+            }
+
+            /// Parses a Spotify URI or share URL, which has to be of the item type of this ID.
+            pub fn from_uri(src: &str) -> Result<Self, Error> {
+                SpotifyId::from_uri(src)?.try_into()
+            }
+        }
+
+        impl Deref for $name {
+            type Target = SpotifyId;
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl From<$name> for SpotifyId {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl TryFrom<SpotifyId> for $name {
+            type Error = crate::Error;
+            fn try_from(id: SpotifyId) -> Result<Self, Self::Error> {
+                if id.item_type == Self::ITEM_TYPE {
+                    Ok(Self(id))
+                } else {
+                    Err(SpotifyIdError::InvalidItemType.into())
+                }
+            }
+        }
+
+        impl TryFrom<&SpotifyId> for $name {
+            type Error = crate::Error;
+            fn try_from(id: &SpotifyId) -> Result<Self, Self::Error> {
+                Self::try_from(*id)
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_tuple(stringify!($name))
+                    .field(&DebugUri(&self.0))
+                    .finish()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+typed_spotify_id!(
+    /// The ID of an album.
+    AlbumId,
+    Album
+);
+typed_spotify_id!(
+    /// The ID of an artist.
+    ArtistId,
+    Artist
+);
+typed_spotify_id!(
+    /// The ID of a podcast episode.
+    EpisodeId,
+    Episode
+);
+typed_spotify_id!(
+    /// The ID of a playlist.
+    PlaylistId,
+    Playlist
+);
+typed_spotify_id!(
+    /// The ID of a podcast.
+    ShowId,
+    Show
+);
+typed_spotify_id!(
+    /// The ID of a track.
+    TrackId,
+    Track
+);
+
+#[cfg(feature = "with-serde")]
+mod serialization {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{
+        AlbumId, ArtistId, EpisodeId, NamedSpotifyId, PlaylistId, ShowId, SpotifyCollection,
+        SpotifyId, SpotifyItemType, SpotifyPlaylistGroup, SpotifySearch, TrackId,
+    };
+
+    impl Serialize for SpotifyItemType {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str((*self).into())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for SpotifyItemType {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let item_type = String::deserialize(deserializer)?;
+            Ok(item_type.as_str().into())
+        }
+    }
+
+    /// Serialized as a Spotify URI.
+    impl Serialize for SpotifyId {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let uri = self.to_uri().map_err(serde::ser::Error::custom)?;
+            serializer.serialize_str(&uri)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for SpotifyId {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let uri = String::deserialize(deserializer)?;
+            Self::from_uri(&uri).map_err(de::Error::custom)
+        }
+    }
+
+    /// Serialized as a Spotify URI.
+    impl Serialize for NamedSpotifyId {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let uri = self.to_uri().map_err(serde::ser::Error::custom)?;
+            serializer.serialize_str(&uri)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for NamedSpotifyId {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let uri = String::deserialize(deserializer)?;
+            Self::from_uri(&uri).map_err(de::Error::custom)
+        }
+    }
+
+    /// Serialized as a Spotify URI.
+    impl Serialize for SpotifySearch {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&self.to_uri())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for SpotifySearch {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let uri = String::deserialize(deserializer)?;
+            Self::from_uri(&uri).map_err(de::Error::custom)
+        }
+    }
+
+    /// Serialized as a Spotify URI.
+    impl Serialize for SpotifyCollection {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&self.to_uri())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for SpotifyCollection {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let uri = String::deserialize(deserializer)?;
+            Self::from_uri(&uri).map_err(de::Error::custom)
+        }
+    }
+
+    /// Serialized as a Spotify URI.
+    impl Serialize for SpotifyPlaylistGroup {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&self.to_uri())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for SpotifyPlaylistGroup {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let uri = String::deserialize(deserializer)?;
+            Self::from_uri(&uri).map_err(de::Error::custom)
+        }
+    }
+
+    // The typed IDs are serialized like the `SpotifyId` they wrap.
+    macro_rules! typed_serialization {
+        ($($name:ident),*) => {
+            $(
+                impl Serialize for $name {
+                    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                        SpotifyId::from(*self).serialize(serializer)
+                    }
+                }
+
+                impl<'de> Deserialize<'de> for $name {
+                    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                        let id = SpotifyId::deserialize(deserializer)?;
+                        Self::try_from(id).map_err(de::Error::custom)
+                    }
+                }
+            )*
+        };
+    }
+
+    typed_serialization!(AlbumId, ArtistId, EpisodeId, PlaylistId, ShowId, TrackId);
+}
+
 impl TryFrom<&[u8]> for SpotifyId {
     type Error = crate::Error;
     fn try_from(src: &[u8]) -> Result<Self, Self::Error> {
@@ -523,18 +1308,6 @@ impl TryFrom<&protocol::playlist4_external::SelectedListContent> for SpotifyId {
     }
 }
 
-// TODO: check meaning and format of this field in the wild. This might be a FileId,
-// which is why we now don't create a separate `Playlist` enum value yet and choose
-// to discard any item type.
-impl TryFrom<&protocol::playlist_annotate3::TranscodedPicture> for SpotifyId {
-    type Error = crate::Error;
-    fn try_from(
-        picture: &protocol::playlist_annotate3::TranscodedPicture,
-    ) -> Result<Self, Self::Error> {
-        Self::from_base62(picture.uri())
-    }
-}
-
 pub fn to_base16(src: &[u8], buf: &mut [u8]) -> Result<String, Error> {
     let mut i = 0;
     for v in src {
@@ -559,7 +1332,7 @@ mod tests {
         raw: &'static [u8],
     }
 
-    static CONV_VALID: [ConversionCase; 5] = [
+    static CONV_VALID: [ConversionCase; 7] = [
         ConversionCase {
             id: 238762092608182713602505436543891614649,
             kind: SpotifyItemType::Track,
@@ -600,6 +1373,26 @@ mod tests {
                 154, 27, 28, 251, 198, 242, 68, 86, 154, 224, 53, 108, 119, 187, 233, 216,
             ],
         },
+        ConversionCase {
+            id: 204841891221366092811751085145916697048,
+            kind: SpotifyItemType::Concert,
+            uri: "spotify:concert:4GNcXTGWmnZ3ySrqvol3o4",
+            base16: "9a1b1cfbc6f244569ae0356c77bbe9d8",
+            base62: "4GNcXTGWmnZ3ySrqvol3o4",
+            raw: &[
+                154, 27, 28, 251, 198, 242, 68, 86, 154, 224, 53, 108, 119, 187, 233, 216,
+            ],
+        },
+        ConversionCase {
+            id: 204841891221366092811751085145916697048,
+            kind: SpotifyItemType::Prerelease,
+            uri: "spotify:prerelease:4GNcXTGWmnZ3ySrqvol3o4",
+            base16: "9a1b1cfbc6f244569ae0356c77bbe9d8",
+            base62: "4GNcXTGWmnZ3ySrqvol3o4",
+            raw: &[
+                154, 27, 28, 251, 198, 242, 68, 86, 154, 224, 53, 108, 119, 187, 233, 216,
+            ],
+        },
         ConversionCase {
             id: 0,
             kind: SpotifyItemType::Local,
@@ -669,6 +1462,10 @@ mod tests {
             };
 
             assert_eq!(id.to_base62().unwrap(), c.base62);
+            assert_eq!(
+                id.write_base62(&mut [b'x'; SpotifyId::SIZE_BASE62]),
+                c.base62
+            );
         }
     }
 
@@ -728,6 +1525,229 @@ mod tests {
         assert_eq!(actual.username, "spotify");
     }
 
+    #[test]
+    fn from_share_url() {
+        for url in [
+            "https://open.spotify.com/track/5sWHDYs0csV6RS48xBl0tH",
+            "https://open.spotify.com/track/5sWHDYs0csV6RS48xBl0tH?si=a1b2c3d4e5f6",
+            "https://open.spotify.com/intl-de/track/5sWHDYs0csV6RS48xBl0tH?si=a1b2c3d4e5f6",
+            "http://open.spotify.com/track/5sWHDYs0csV6RS48xBl0tH/",
+        ] {
+            let actual = SpotifyId::from_uri(url).unwrap();
+
+            assert_eq!(actual.id, CONV_VALID[0].id);
+            assert_eq!(actual.item_type, SpotifyItemType::Track);
+        }
+
+        let actual = NamedSpotifyId::from_uri(
+            "https://open.spotify.com/user/spotify/playlist/37i9dQZF1DWSw8liJZcPOI?si=x",
+        )
+        .unwrap();
+        assert_eq!(actual.id, 136159921382084734723401526672209703396);
+        assert_eq!(actual.item_type, SpotifyItemType::Playlist);
+        assert_eq!(actual.username, "spotify");
+
+        assert!(SpotifyId::from_uri("https://example.com/track/5sWHDYs0csV6RS48xBl0tH").is_err());
+        assert!(SpotifyId::from_uri("https://open.spotify.com/track").is_err());
+    }
+
+    #[test]
+    fn search_uri() {
+        let search = SpotifySearch::from_uri("spotify:search:daft+punk%20%26+friends").unwrap();
+        assert_eq!(search.query, "daft punk & friends");
+        assert_eq!(search.to_uri(), "spotify:search:daft+punk+%26+friends");
+
+        let search = SpotifySearch::new("motörhead");
+        assert_eq!(SpotifySearch::from_uri(&search.to_uri()).unwrap(), search);
+
+        assert!(SpotifySearch::from_uri("spotify:track:5sWHDYs0csV6RS48xBl0tH").is_err());
+        assert!(SpotifySearch::from_uri("spotify:search:%FF").is_err());
+    }
+
+    #[test]
+    fn user_uri() {
+        let user = SpotifyUser::from_uri("spotify:user:john+doe").unwrap();
+        assert_eq!(user.username, "john doe");
+        assert_eq!(SpotifyUser::from_uri(&user.to_uri()).unwrap(), user);
+
+        assert!(SpotifyUser::from_uri("spotify:user:").is_err());
+        assert!(SpotifyUser::from_uri("spotify:user:spotify:collection").is_err());
+        assert!(SpotifyUser::from_uri("spotify:track:5sWHDYs0csV6RS48xBl0tH").is_err());
+    }
+
+    #[test]
+    fn collection_uri() {
+        for (uri, expected) in [
+            ("spotify:collection:tracks", SpotifyCollection::Tracks),
+            ("spotify:user:spotify:collection", SpotifyCollection::Tracks),
+            ("spotify:collection:albums", SpotifyCollection::Albums),
+        ] {
+            assert_eq!(SpotifyCollection::from_uri(uri).unwrap(), expected);
+        }
+
+        assert!(SpotifyCollection::from_uri("spotify:collection").is_err());
+        assert!(SpotifyCollection::from_uri("spotify:user:spotify:collection:tracks").is_err());
+        assert!(SpotifyCollection::from_uri("spotify:track:5sWHDYs0csV6RS48xBl0tH").is_err());
+    }
+
+    #[test]
+    fn playlist_group_uri() {
+        let start = SpotifyPlaylistGroup::from_uri(
+            "spotify:start-group:8212237ac7347bfe:Road+trip%3A+2022",
+        )
+        .unwrap();
+        assert_eq!(
+            start,
+            SpotifyPlaylistGroup::Start {
+                id: "8212237ac7347bfe".to_owned(),
+                name: "Road trip: 2022".to_owned(),
+            }
+        );
+        assert_eq!(
+            start.to_uri(),
+            "spotify:start-group:8212237ac7347bfe:Road+trip%3A+2022"
+        );
+
+        let end = SpotifyPlaylistGroup::from_uri("spotify:end-group:8212237ac7347bfe").unwrap();
+        assert_eq!(end.id(), start.id());
+        assert_eq!(end.to_uri(), "spotify:end-group:8212237ac7347bfe");
+
+        assert!(SpotifyPlaylistGroup::from_uri("spotify:start-group:8212237ac7347bfe").is_err());
+        assert!(SpotifyPlaylistGroup::from_uri("spotify:end-group:").is_err());
+        assert!(SpotifyPlaylistGroup::from_uri("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M").is_err());
+    }
+
+    #[test]
+    fn uri_builder() {
+        let playlist = PlaylistId::from_base62("37i9dQZF1DWSw8liJZcPOI").unwrap();
+        let track = TrackId::from_base62("5sWHDYs0csV6RS48xBl0tH").unwrap();
+
+        let uri = SpotifyUriBuilder::new()
+            .user("some user")
+            .id(playlist)
+            .unwrap();
+        assert_eq!(
+            uri,
+            "spotify:user:some+user:playlist:37i9dQZF1DWSw8liJZcPOI"
+        );
+        assert_eq!(NamedSpotifyId::from_uri(&uri).unwrap().inner_id, *playlist);
+
+        let uri = SpotifyUriBuilder::new().station().id(track).unwrap();
+        assert_eq!(uri, "spotify:station:track:5sWHDYs0csV6RS48xBl0tH");
+
+        let uri = SpotifyUriBuilder::new()
+            .local(
+                "Daft Punk",
+                "Discovery",
+                "One More Time",
+                Duration::from_secs(320),
+            )
+            .unwrap();
+        assert_eq!(uri, "spotify:local:Daft+Punk:Discovery:One+More+Time:320");
+        assert_eq!(
+            SpotifyId::from_uri(&uri).unwrap().item_type,
+            SpotifyItemType::Local
+        );
+
+        assert!(SpotifyUriBuilder::new()
+            .station()
+            .local("a", "b", "c", Duration::ZERO)
+            .is_err());
+    }
+
+    #[test]
+    fn canonicalize() {
+        for (uri, expected) in [
+            (
+                "Spotify:Track:5sWHDYs0csV6RS48xBl0tH",
+                "spotify:track:5sWHDYs0csV6RS48xBl0tH",
+            ),
+            (
+                "spotify:USER:some%20user:playlist:37i9dQZF1DWSw8liJZcPOI",
+                "spotify:user:some+user:playlist:37i9dQZF1DWSw8liJZcPOI",
+            ),
+            (
+                "https://open.spotify.com/intl-de/album/0tdKRxDdW9g5bo1yPTXTfA?si=abc",
+                "spotify:album:0tdKRxDdW9g5bo1yPTXTfA",
+            ),
+            (
+                "spotify:search:daft%20punk:live",
+                "spotify:search:daft+punk%3Alive",
+            ),
+            (
+                "spotify:local:Daft%20Punk::One+More+Time:320",
+                "spotify:local:Daft+Punk::One+More+Time:320",
+            ),
+            (
+                "spotify:start-group:8212237ac7347bfe:Road trip",
+                "spotify:start-group:8212237ac7347bfe:Road+trip",
+            ),
+        ] {
+            let canonical = canonicalize_uri(uri).unwrap();
+            assert_eq!(canonical, expected);
+            assert_eq!(canonicalize_uri(&canonical).unwrap(), canonical);
+        }
+
+        assert_eq!(
+            SpotifySearch::from_uri("spotify:search:daft%20punk:live").unwrap(),
+            SpotifySearch::from_uri(&canonicalize_uri("spotify:search:daft%20punk:live").unwrap())
+                .unwrap()
+        );
+
+        assert!(canonicalize_uri("spotfy:track:5sWHDYs0csV6RS48xBl0tH").is_err());
+        assert!(canonicalize_uri("spotify:search:%FF").is_err());
+    }
+
+    #[test]
+    fn from_uri_const() {
+        const TRACK: SpotifyId = spotify_id!("spotify:track:5sWHDYs0csV6RS48xBl0tH");
+        assert_eq!(TRACK, SpotifyId::from_uri(CONV_VALID[0].uri).unwrap());
+
+        for c in &CONV_VALID[..6] {
+            let actual = SpotifyId::from_uri_const(c.uri).unwrap();
+            assert_eq!(actual.id, c.id);
+            assert_eq!(actual.item_type, c.kind);
+        }
+
+        for c in &CONV_INVALID {
+            assert!(SpotifyId::from_uri_const(c.uri).is_err());
+        }
+        assert!(SpotifyId::from_uri_const("spotify:local:0000000000000000000000").is_err());
+    }
+
+    #[test]
+    fn typed_ids() {
+        let track_id = TrackId::from_uri("spotify:track:5sWHDYs0csV6RS48xBl0tH").unwrap();
+        assert_eq!(track_id.id, CONV_VALID[0].id);
+        assert_eq!(
+            SpotifyId::from(track_id),
+            SpotifyId::from_uri(CONV_VALID[0].uri).unwrap()
+        );
+        assert_eq!(
+            TrackId::from_base62(CONV_VALID[0].base62).unwrap(),
+            track_id
+        );
+
+        let album_id = SpotifyId::from_uri("spotify:album:5sWHDYs0csV6RS48xBl0tH").unwrap();
+        assert!(TrackId::try_from(album_id).is_err());
+        assert!(AlbumId::try_from(album_id).is_ok());
+        assert!(AlbumId::from_uri("spotify:track:5sWHDYs0csV6RS48xBl0tH").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "with-serde")]
+    fn serde_round_trip() {
+        for c in &CONV_VALID[..6] {
+            let id = SpotifyId::from_uri(c.uri).unwrap();
+            let json = serde_json::to_string(&id).unwrap();
+
+            assert_eq!(json, format!("\"{}\"", c.uri));
+            assert_eq!(serde_json::from_str::<SpotifyId>(&json).unwrap(), id);
+        }
+
+        assert!(serde_json::from_str::<SpotifyId>("\"spotify:track:invalid\"").is_err());
+    }
+
     #[test]
     fn to_uri() {
         for c in &CONV_VALID {
@@ -737,6 +1757,8 @@ mod tests {
             };
 
             assert_eq!(id.to_uri().unwrap(), c.uri);
+            assert_eq!(id.to_string(), c.uri);
+            assert_eq!(format!("{:?}", id), format!("SpotifyId({:?})", c.uri));
         }
     }
 
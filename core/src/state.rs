@@ -0,0 +1,375 @@
+//! The state of the whole device in one place. The session, discovery, Spotify Connect and
+//! the player record what happens to them as [`StateEvent`]s into the [`StateStore`] of
+//! [`SessionConfig::state`], which folds them into a [`DeviceState`].
+//!
+//! Applications that show or persist the state, like an HTTP API or an MPRIS interface, take
+//! a snapshot of it and follow its changes, instead of combining the events of the session
+//! and the player themselves.
+//!
+//! [`SessionConfig::state`]: crate::config::SessionConfig::state
+
+use std::{fmt, sync::Arc, time::Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{session::SessionEvent, SpotifyId};
+
+/// Something that happened to the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateEvent {
+    Session(SessionEvent),
+    /// Credentials of `username` were received through zeroconf discovery.
+    CredentialsReceived {
+        username: String,
+    },
+    /// The device became the active Spotify Connect device of `username`.
+    Activated {
+        username: String,
+    },
+    /// Another device became the active one, or the session was closed.
+    Deactivated,
+    /// Another client controls the device now.
+    ClientChanged {
+        client_id: String,
+        client_name: String,
+    },
+    VolumeChanged {
+        volume: u16,
+    },
+    ShuffleChanged {
+        shuffle: bool,
+    },
+    RepeatChanged {
+        repeat: bool,
+    },
+    /// The metadata of the track or episode that is loaded.
+    TrackChanged {
+        track_id: SpotifyId,
+        name: String,
+        duration_ms: u32,
+    },
+    Loading {
+        track_id: SpotifyId,
+        position_ms: u32,
+    },
+    /// The track is playing from `position_ms` on, after it started, was unpaused or seeked.
+    Playing {
+        track_id: SpotifyId,
+        position_ms: u32,
+    },
+    Paused {
+        track_id: SpotifyId,
+        position_ms: u32,
+    },
+    /// The position moved to `position_ms`, while playing or paused.
+    Seeked {
+        track_id: SpotifyId,
+        position_ms: u32,
+    },
+    Stopped,
+}
+
+/// A change of the state, as received from [`StateStore::changes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    /// The version of the state after `event` was applied.
+    pub version: u64,
+    pub event: StateEvent,
+}
+
+/// What is known about the device, as made by applying every [`StateEvent`] in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceState {
+    /// The number of events applied, which tells whether a snapshot is older than a change.
+    pub version: u64,
+    pub session: SessionState,
+    pub connect: ConnectState,
+    pub playback: PlaybackState,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionState {
+    pub connected: bool,
+    pub username: Option<String>,
+    /// The access point `host:port` the session is connected or connecting to.
+    pub access_point: Option<String>,
+    pub country: Option<String>,
+    /// The user logged out of this device remotely, and new credentials are needed.
+    pub logged_out: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectState {
+    pub active: bool,
+    /// The user whose credentials were last received through discovery.
+    pub discovered_username: Option<String>,
+    pub client_id: Option<String>,
+    pub client_name: Option<String>,
+    pub volume: u16,
+    pub shuffle: bool,
+    pub repeat: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlaybackState {
+    pub status: PlaybackStatus,
+    pub track: Option<Track>,
+    pub position_ms: u32,
+    /// When `position_ms` was recorded, to tell the current position while playing.
+    pub position_updated: Option<Instant>,
+}
+
+impl PlaybackState {
+    /// The position now, which moves on from `position_ms` while playing.
+    pub fn position_ms(&self) -> u32 {
+        match (self.status, self.position_updated) {
+            (PlaybackStatus::Playing, Some(updated)) => {
+                let elapsed = updated.elapsed().as_millis().min(u32::MAX as u128) as u32;
+                let position = self.position_ms.saturating_add(elapsed);
+                match &self.track {
+                    Some(track) if track.duration_ms > 0 => position.min(track.duration_ms),
+                    _ => position,
+                }
+            }
+            _ => self.position_ms,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Stopped,
+    Loading,
+    Playing,
+    Paused,
+}
+
+impl Default for PlaybackStatus {
+    fn default() -> Self {
+        Self::Stopped
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub track_id: SpotifyId,
+    /// Empty until the metadata of the track was loaded.
+    pub name: String,
+    pub duration_ms: u32,
+}
+
+impl DeviceState {
+    pub fn apply(&mut self, event: &StateEvent) {
+        self.version += 1;
+
+        match event {
+            StateEvent::Session(event) => self.apply_session(event),
+            StateEvent::CredentialsReceived { username } => {
+                self.connect.discovered_username = Some(username.clone());
+            }
+            StateEvent::Activated { username } => {
+                self.connect.active = true;
+                self.session.username = Some(username.clone());
+            }
+            StateEvent::Deactivated => {
+                self.connect.active = false;
+                self.connect.client_id = None;
+                self.connect.client_name = None;
+            }
+            StateEvent::ClientChanged {
+                client_id,
+                client_name,
+            } => {
+                self.connect.client_id = Some(client_id.clone());
+                self.connect.client_name = Some(client_name.clone());
+            }
+            StateEvent::VolumeChanged { volume } => self.connect.volume = *volume,
+            StateEvent::ShuffleChanged { shuffle } => self.connect.shuffle = *shuffle,
+            StateEvent::RepeatChanged { repeat } => self.connect.repeat = *repeat,
+            StateEvent::TrackChanged {
+                track_id,
+                name,
+                duration_ms,
+            } => {
+                self.playback.track = Some(Track {
+                    track_id: *track_id,
+                    name: name.clone(),
+                    duration_ms: *duration_ms,
+                });
+            }
+            StateEvent::Loading {
+                track_id,
+                position_ms,
+            } => self.set_playback(PlaybackStatus::Loading, *track_id, *position_ms),
+            StateEvent::Playing {
+                track_id,
+                position_ms,
+            } => self.set_playback(PlaybackStatus::Playing, *track_id, *position_ms),
+            StateEvent::Paused {
+                track_id,
+                position_ms,
+            } => self.set_playback(PlaybackStatus::Paused, *track_id, *position_ms),
+            StateEvent::Seeked {
+                track_id,
+                position_ms,
+            } => self.set_playback(self.playback.status, *track_id, *position_ms),
+            StateEvent::Stopped => {
+                self.playback = PlaybackState::default();
+            }
+        }
+    }
+
+    fn apply_session(&mut self, event: &SessionEvent) {
+        match event {
+            SessionEvent::Connected {
+                username,
+                access_point,
+            } => {
+                self.session.connected = true;
+                self.session.logged_out = false;
+                self.session.username = Some(username.clone());
+                self.session.access_point = Some(access_point.clone());
+            }
+            SessionEvent::Reconnecting { access_point } => {
+                self.session.connected = false;
+                self.session.access_point = Some(access_point.clone());
+            }
+            SessionEvent::Disconnected => self.session.connected = false,
+            SessionEvent::LoggedOut => {
+                self.session.connected = false;
+                self.session.logged_out = true;
+            }
+            SessionEvent::CountryChanged { country } => {
+                self.session.country = Some(country.clone());
+            }
+            SessionEvent::AccessPointSwitched { .. }
+            | SessionEvent::TokenRefreshed { .. }
+            | SessionEvent::ConnectionDead { .. } => (),
+        }
+    }
+
+    fn set_playback(&mut self, status: PlaybackStatus, track_id: SpotifyId, position_ms: u32) {
+        // the metadata of another track doesn't apply anymore
+        if self.playback.track.as_ref().map(|track| track.track_id) != Some(track_id) {
+            self.playback.track = Some(Track {
+                track_id,
+                name: String::new(),
+                duration_ms: 0,
+            });
+        }
+        self.playback.status = status;
+        self.playback.position_ms = position_ms;
+        self.playback.position_updated = Some(Instant::now());
+    }
+}
+
+#[derive(Default)]
+struct StateStoreInner {
+    state: DeviceState,
+    subscribers: Vec<mpsc::UnboundedSender<StateChange>>,
+}
+
+/// Where the subsystems record what happens, see the [module documentation](self). Clones
+/// share the same state.
+#[derive(Clone, Default)]
+pub struct StateStore(Arc<Mutex<StateStoreInner>>);
+
+impl StateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `event` to the state and passes it on to those following the changes.
+    pub fn record(&self, event: StateEvent) {
+        let mut inner = self.0.lock();
+        inner.state.apply(&event);
+
+        let change = StateChange {
+            version: inner.state.version,
+            event,
+        };
+        inner
+            .subscribers
+            .retain(|subscriber| subscriber.send(change.clone()).is_ok());
+    }
+
+    pub fn snapshot(&self) -> DeviceState {
+        self.0.lock().state.clone()
+    }
+
+    /// Returns the state now, and a stream of the changes from then on. Applying the changes
+    /// to the snapshot keeps it up to date without missing one.
+    pub fn changes(&self) -> (DeviceState, UnboundedReceiverStream<StateChange>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut inner = self.0.lock();
+        inner.subscribers.push(tx);
+        (inner.state.clone(), UnboundedReceiverStream::new(rx))
+    }
+}
+
+impl fmt::Debug for StateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StateStore")
+            .field(&self.0.lock().state.version)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    fn track_id() -> SpotifyId {
+        SpotifyId::from_base62("4GNcXTGWmnZ3ySrqvol3o4").expect("valid base62")
+    }
+
+    #[test]
+    fn applies_events() {
+        let store = StateStore::new();
+        store.record(StateEvent::Session(SessionEvent::Connected {
+            username: "user".into(),
+            access_point: "ap.spotify.com:4070".into(),
+        }));
+        store.record(StateEvent::TrackChanged {
+            track_id: track_id(),
+            name: "Track".into(),
+            duration_ms: 180_000,
+        });
+        store.record(StateEvent::Paused {
+            track_id: track_id(),
+            position_ms: 1_000,
+        });
+
+        let state = store.snapshot();
+        assert_eq!(state.version, 3);
+        assert!(state.session.connected);
+        assert_eq!(state.session.username.as_deref(), Some("user"));
+        assert_eq!(state.playback.status, PlaybackStatus::Paused);
+        assert_eq!(state.playback.position_ms(), 1_000);
+        assert_eq!(
+            state.playback.track.map(|track| track.name),
+            Some("Track".into())
+        );
+
+        store.record(StateEvent::Stopped);
+        assert_eq!(store.snapshot().playback, PlaybackState::default());
+    }
+
+    #[tokio::test]
+    async fn changes_follow_snapshot() {
+        let store = StateStore::new();
+        store.record(StateEvent::VolumeChanged { volume: 100 });
+
+        let (mut state, mut changes) = store.changes();
+        store.record(StateEvent::ShuffleChanged { shuffle: true });
+
+        let change = changes.next().await.expect("a change");
+        assert_eq!(change.version, state.version + 1);
+        state.apply(&change.event);
+        assert_eq!(state, store.snapshot());
+    }
+}
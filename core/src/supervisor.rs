@@ -0,0 +1,172 @@
+//! Containment of panics in background tasks, so a panicking task is logged and can be
+//! restarted instead of silently leaving the session half-dead.
+
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+
+use futures_util::FutureExt;
+use thiserror::Error;
+
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    Error,
+};
+
+#[derive(Debug, Error)]
+#[error("task {task} panicked: {message}")]
+pub struct TaskPanicked {
+    pub task: String,
+    pub message: String,
+}
+
+impl From<TaskPanicked> for Error {
+    fn from(err: TaskPanicked) -> Self {
+        Self::with_code(ErrorKind::Internal, err)
+    }
+}
+
+impl ErrorCode for TaskPanicked {
+    fn code(&self) -> &'static str {
+        "supervisor.task_panicked"
+    }
+}
+
+/// Returns the message a panic was raised with, as far as it is a string.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+/// The delay before restarting a failed task, which doubles with every consecutive failure
+/// up to a maximum. Once a task has kept running for longer than the maximum delay, the
+/// next failure starts over at the initial delay.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+    restarted_at: Option<Instant>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+            restarted_at: None,
+        }
+    }
+
+    /// Returns the delay before the next restart.
+    pub fn next_delay(&mut self) -> Duration {
+        let now = Instant::now();
+        if matches!(self.restarted_at, Some(t) if now > t && now - t > self.max) {
+            self.reset();
+        }
+
+        let delay = self.next;
+        self.next = (delay * 2).min(self.max);
+        self.restarted_at = Some(now + delay);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+        self.restarted_at = None;
+    }
+}
+
+/// Runs `task` to completion and catches a panic in it, which is logged together with the
+/// name of the task and how long it ran.
+pub async fn contain<Fut: Future>(
+    name: impl Into<String>,
+    task: Fut,
+) -> Result<Fut::Output, TaskPanicked> {
+    let name = name.into();
+    let started = Instant::now();
+    AssertUnwindSafe(task)
+        .catch_unwind()
+        .await
+        .map_err(|payload| {
+            let message = panic_message(&*payload).to_owned();
+            error!(
+                "Task {} panicked after running for {:?}: {}",
+                name,
+                started.elapsed(),
+                message
+            );
+            TaskPanicked {
+                task: name,
+                message,
+            }
+        })
+}
+
+/// Runs the task created by `make_task` until it completes without panicking, restarting it
+/// after every panic with the delays given by `backoff`.
+pub async fn supervise<F, Fut>(name: &str, mut backoff: Backoff, mut make_task: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut restarts = 0;
+    while contain(name, make_task()).await.is_err() {
+        restarts += 1;
+        let delay = backoff.next_delay();
+        warn!(
+            "Restarting task {} in {:?} (restart #{})",
+            name, delay, restarts
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn restart_after_panic() {
+        let mut runs = 0;
+        supervise("test", Backoff::new(Duration::ZERO, Duration::ZERO), || {
+            runs += 1;
+            let run = runs;
+            async move {
+                if run < 3 {
+                    panic!("run {}", run);
+                }
+            }
+        })
+        .await;
+        assert_eq!(runs, 3);
+
+        let err = contain("test", async { panic!("oops") }).await.unwrap_err();
+        assert_eq!(err.to_string(), "task test panicked: oops");
+    }
+}
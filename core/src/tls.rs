@@ -0,0 +1,255 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::PathBuf,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, RootCertStore, ServerName,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    config::TlsConfig,
+    error::{ErrorCode, ErrorKind},
+    Error,
+};
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("cannot read certificates from {0:?}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("no certificates found in {0:?}")]
+    NoCertificates(PathBuf),
+}
+
+impl From<TlsError> for Error {
+    fn from(err: TlsError) -> Self {
+        Error::with_code(ErrorKind::InvalidArgument, err)
+    }
+}
+
+impl ErrorCode for TlsError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Read(..) => "tls.read",
+            Self::NoCertificates(_) => "tls.no_certificates",
+        }
+    }
+}
+
+/// Builds the client configuration for TLS connections, trusting the system's root
+/// certificates plus those configured in `config`, unless it has a client configuration of
+/// its own.
+pub(crate) fn client_config(config: &TlsConfig) -> Result<ClientConfig, Error> {
+    if let Some(client_config) = &config.client_config {
+        return Ok(ClientConfig::clone(client_config));
+    }
+
+    let mut certs = Vec::new();
+
+    match rustls_native_certs::load_native_certs() {
+        Ok(native_certs) => certs.extend(native_certs.into_iter().map(|cert| cert.0)),
+        Err(e) => warn!("Cannot load native root certificates: {}", e),
+    }
+
+    for path in config.extra_root_certificates.iter() {
+        let extra_certs = File::open(path)
+            .and_then(|file| rustls_pemfile::certs(&mut BufReader::new(file)))
+            .map_err(|e| TlsError::Read(path.clone(), e))?;
+
+        let (added, _) = RootCertStore::empty().add_parsable_certificates(&extra_certs);
+        if added == 0 {
+            return Err(TlsError::NoCertificates(path.clone()).into());
+        }
+        debug!("Trusting {} extra root certificates from {:?}", added, path);
+        certs.extend(extra_certs);
+    }
+
+    let mut roots = RootCertStore::empty();
+    let (_, ignored) = roots.add_parsable_certificates(&certs);
+    if ignored > 0 {
+        debug!("Ignored {} invalid root certificates", ignored);
+    }
+
+    let mut client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone())
+        .with_no_client_auth();
+
+    if !config.certificate_pins.is_empty() {
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinningVerifier::new(
+                roots,
+                &certs,
+                config.certificate_pins.clone(),
+            )));
+    }
+
+    Ok(client_config)
+}
+
+fn is_pinned(pins: &[[u8; 32]], cert: &[u8]) -> bool {
+    let fingerprint = Sha256::digest(cert);
+    pins.iter().any(|pin| fingerprint.as_slice() == pin)
+}
+
+// Verifies the certificate chain of a server against the root certificates as usual, and then
+// requires a pinned certificate in it: the server's own certificate, or one that the chain is
+// valid up to on its own. Merely presenting a pinned certificate isn't enough, as anyone can
+// append a public certificate to their chain.
+struct PinningVerifier {
+    pins: Vec<[u8; 32]>,
+    inner: WebPkiVerifier,
+    // the root certificates that are pinned themselves
+    pinned_roots: RootCertStore,
+}
+
+impl PinningVerifier {
+    fn new(roots: RootCertStore, root_certs: &[Vec<u8>], pins: Vec<[u8; 32]>) -> Self {
+        let pinned_certs: Vec<Vec<u8>> = root_certs
+            .iter()
+            .filter(|cert| is_pinned(&pins, cert))
+            .cloned()
+            .collect();
+        let mut pinned_roots = RootCertStore::empty();
+        pinned_roots.add_parsable_certificates(&pinned_certs);
+
+        Self {
+            pins,
+            inner: WebPkiVerifier::new(roots, None),
+            pinned_roots,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        if is_pinned(&self.pins, &end_entity.0) {
+            return Ok(verified);
+        }
+
+        // The chain has to be valid when only the pinned certificates are trusted.
+        let mut anchors = self.pinned_roots.clone();
+        for cert in intermediates
+            .iter()
+            .filter(|cert| is_pinned(&self.pins, &cert.0))
+        {
+            let _ = anchors.add(cert);
+        }
+
+        if !anchors.is_empty()
+            && WebPkiVerifier::new(anchors, None)
+                .verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    &mut std::iter::empty(),
+                    ocsp_response,
+                    now,
+                )
+                .is_ok()
+        {
+            return Ok(verified);
+        }
+
+        Err(rustls::Error::General(
+            "no pinned certificate in the certificate chain".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Two CAs with a certificate each for spclient.example, valid from 2020 to 2100, made
+    // with openssl.
+    const PINNED_CA: &[u8] = include_bytes!("../tests/data/tls/pinned-ca.pem");
+    const OTHER_CA: &[u8] = include_bytes!("../tests/data/tls/other-ca.pem");
+    const PINNED_LEAF: &[u8] = include_bytes!("../tests/data/tls/pinned-leaf.pem");
+    const OTHER_LEAF: &[u8] = include_bytes!("../tests/data/tls/other-leaf.pem");
+    const HOST: &str = "spclient.example";
+
+    fn der(pem: &[u8]) -> Vec<u8> {
+        rustls_pemfile::certs(&mut &*pem).unwrap().remove(0)
+    }
+
+    fn fingerprint(pem: &[u8]) -> [u8; 32] {
+        Sha256::digest(der(pem)).into()
+    }
+
+    fn pinning_verifier(pins: &[&[u8]]) -> PinningVerifier {
+        let certs = vec![der(PINNED_CA), der(OTHER_CA)];
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(&certs);
+        let pins = pins.iter().map(|pem| fingerprint(pem)).collect();
+        PinningVerifier::new(roots, &certs, pins)
+    }
+
+    fn verify(verifier: &PinningVerifier, chain: &[&[u8]], host: &str) -> bool {
+        let chain: Vec<Certificate> = chain.iter().map(|pem| Certificate(der(pem))).collect();
+        verifier
+            .verify_server_cert(
+                &chain[0],
+                &chain[1..],
+                &ServerName::try_from(host).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .is_ok()
+    }
+
+    #[test]
+    fn accepts_chains_with_a_pinned_root() {
+        let verifier = pinning_verifier(&[PINNED_CA]);
+        assert!(verify(&verifier, &[PINNED_LEAF], HOST));
+        assert!(verify(&verifier, &[PINNED_LEAF, PINNED_CA], HOST));
+        assert!(!verify(&verifier, &[OTHER_LEAF], HOST));
+    }
+
+    #[test]
+    fn accepts_pinned_server_certificates() {
+        let verifier = pinning_verifier(&[OTHER_LEAF]);
+        assert!(verify(&verifier, &[OTHER_LEAF], HOST));
+        assert!(!verify(&verifier, &[PINNED_LEAF], HOST));
+    }
+
+    #[test]
+    fn rejects_appended_pinned_certificates() {
+        let verifier = pinning_verifier(&[PINNED_CA]);
+        assert!(!verify(&verifier, &[OTHER_LEAF, PINNED_CA], HOST));
+    }
+
+    #[test]
+    fn checks_the_host_name_of_pinned_chains() {
+        let verifier = pinning_verifier(&[PINNED_CA]);
+        assert!(!verify(&verifier, &[PINNED_LEAF], "ap.example"));
+
+        let verifier = pinning_verifier(&[PINNED_LEAF]);
+        assert!(!verify(&verifier, &[PINNED_LEAF], "ap.example"));
+    }
+}
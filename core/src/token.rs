@@ -8,16 +8,26 @@
 //   user-library-modify, user-library-read, user-follow-modify, user-follow-read, streaming,
 //   app-remote-control
 
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use serde::Deserialize;
 use thiserror::Error;
+use tokio::sync::Mutex;
 
-use crate::Error;
+use crate::{
+    error::{ErrorCode, ErrorKind},
+    session::SessionEvent,
+    Error,
+};
 
 component! {
     TokenProvider : TokenProviderInner {
         tokens: Vec<Token> = vec![],
+        // held while requesting a token, so that concurrent requests share one
+        requesting: Arc<Mutex<()>> = Arc::new(Mutex::new(())),
     }
 }
 
@@ -25,11 +35,25 @@ component! {
 pub enum TokenError {
     #[error("no tokens available")]
     Empty,
+    #[error("no scopes requested")]
+    NoScopes,
 }
 
 impl From<TokenError> for Error {
     fn from(err: TokenError) -> Self {
-        Error::unavailable(err)
+        match err {
+            TokenError::Empty => Error::with_code(ErrorKind::Unavailable, err),
+            TokenError::NoScopes => Error::with_code(ErrorKind::InvalidArgument, err),
+        }
+    }
+}
+
+impl ErrorCode for TokenError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Empty => "token.empty",
+            Self::NoScopes => "token.no_scopes",
+        }
     }
 }
 
@@ -52,12 +76,28 @@ struct TokenData {
 }
 
 impl TokenProvider {
-    fn find_token(&self, scopes: Vec<&str>) -> Option<usize> {
+    // Returns a cached token that has all `scopes` and isn't expired, dropping those that are.
+    fn cached_token(&self, scopes: &[&str]) -> Option<Token> {
         self.lock(|inner| {
-            (0..inner.tokens.len()).find(|&i| inner.tokens[i].in_scopes(scopes.clone()))
+            inner.tokens.retain(|token| !token.is_expired());
+            inner
+                .tokens
+                .iter()
+                .find(|token| token.in_scopes(scopes.to_vec()))
+                .cloned()
         })
     }
 
+    /// Drops `token` e.g. because it was rejected before it was due to expire, so that it is
+    /// requested anew.
+    pub fn invalidate(&self, token: &Token) {
+        self.lock(|inner| {
+            inner
+                .tokens
+                .retain(|cached| cached.access_token != token.access_token)
+        });
+    }
+
     // scopes must be comma-separated
     pub async fn get_token(&self, scopes: &str) -> Result<Token, Error> {
         let client_id = self.session().client_id();
@@ -65,13 +105,27 @@ impl TokenProvider {
             return Err(Error::invalid_argument("Client ID cannot be empty"));
         }
 
-        if let Some(index) = self.find_token(scopes.split(',').collect()) {
-            let cached_token = self.lock(|inner| inner.tokens[index].clone());
-            if cached_token.is_expired() {
-                self.lock(|inner| inner.tokens.remove(index));
-            } else {
-                return Ok(cached_token);
-            }
+        let mut scopes: Vec<&str> = scopes
+            .split(',')
+            .map(str::trim)
+            .filter(|scope| !scope.is_empty())
+            .collect();
+        scopes.sort_unstable();
+        scopes.dedup();
+        if scopes.is_empty() {
+            return Err(TokenError::NoScopes.into());
+        }
+
+        if let Some(token) = self.cached_token(&scopes) {
+            return Ok(token);
+        }
+
+        let requesting = self.lock(|inner| inner.requesting.clone());
+        let _requesting = requesting.lock().await;
+
+        // Another caller may have requested it in the meantime.
+        if let Some(token) = self.cached_token(&scopes) {
+            return Ok(token);
         }
 
         trace!(
@@ -81,7 +135,7 @@ impl TokenProvider {
 
         let query_uri = format!(
             "hm://keymaster/token/authenticated?scope={}&client_id={}&device_id={}",
-            scopes,
+            scopes.join(","),
             client_id,
             self.session().device_id(),
         );
@@ -89,8 +143,15 @@ impl TokenProvider {
         let response = request.await?;
         let data = response.payload.first().ok_or(TokenError::Empty)?.to_vec();
         let token = Token::from_json(String::from_utf8(data)?)?;
-        trace!("Got token: {:#?}", token);
+        trace!(
+            "Got token in scopes {:?}, expires in {:?}",
+            token.scopes,
+            token.expires_in
+        );
         self.lock(|inner| inner.tokens.push(token.clone()));
+        self.session().send_event(SessionEvent::TokenRefreshed {
+            scopes: token.scopes.clone(),
+        });
         Ok(token)
     }
 }
@@ -131,3 +192,73 @@ impl Token {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures_util::{FutureExt, StreamExt};
+    use protobuf::Message;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{config::SessionConfig, packet::PacketType, protocol, Session};
+
+    // Answers the next Mercury request with `payload`.
+    async fn respond(
+        session: &Session,
+        packets: &mut mpsc::UnboundedReceiver<(u8, Vec<u8>)>,
+        payload: &str,
+    ) {
+        let (_, request) = packets.recv().await.expect("request");
+        let seq_len = u16::from_be_bytes([request[0], request[1]]) as usize;
+        let seq = &request[2..2 + seq_len];
+
+        let mut header = protocol::mercury::Header::new();
+        header.set_uri("hm://keymaster/token/authenticated".to_owned());
+        header.set_status_code(200);
+        let header = header.write_to_bytes().expect("header");
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&(seq_len as u16).to_be_bytes());
+        response.extend_from_slice(seq);
+        response.push(1); // final
+        response.extend_from_slice(&2u16.to_be_bytes());
+        for part in [&header[..], payload.as_bytes()] {
+            response.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            response.extend_from_slice(part);
+        }
+
+        session
+            .mercury()
+            .dispatch(PacketType::MercuryReq, Bytes::from(response))
+            .expect("dispatched");
+    }
+
+    #[tokio::test]
+    async fn reports_refreshed_tokens() {
+        let session = Session::new(SessionConfig::default(), None);
+        let mut packets = session.connect_for_testing();
+        let mut events = session.events();
+        let provider = session.token_provider();
+
+        let (token, ()) = tokio::join!(
+            provider.get_token("streaming,playlist-read"),
+            respond(
+                &session,
+                &mut packets,
+                r#"{"accessToken":"token","expiresIn":3600,"tokenType":"Bearer","scope":["playlist-read","streaming"]}"#,
+            )
+        );
+        assert_eq!(token.unwrap().access_token, "token");
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(SessionEvent::TokenRefreshed {
+                scopes: vec!["playlist-read".to_owned(), "streaming".to_owned()]
+            }))
+        );
+
+        // until it expires, it is taken from the cache
+        provider.get_token("streaming").await.unwrap();
+        assert!(events.next().now_or_never().is_none());
+    }
+}
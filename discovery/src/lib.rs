@@ -24,6 +24,8 @@ use self::server::DiscoveryServer;
 pub use crate::core::Error;
 use librespot_core as core;
 
+use crate::core::state::StateStore;
+
 /// Credentials to be used in [`librespot`](`librespot_core`).
 pub use crate::core::authentication::Credentials;
 
@@ -63,6 +65,8 @@ pub enum DiscoveryError {
     HttpServerError(#[from] hyper::Error),
     #[error("Missing params for key {0}")]
     ParamsError(&'static str),
+    #[error("Unsupported token type {0}")]
+    TokenTypeError(String),
 }
 
 impl From<DiscoveryError> for Error {
@@ -73,6 +77,7 @@ impl From<DiscoveryError> for Error {
             DiscoveryError::HmacError(_) => Error::invalid_argument(err),
             DiscoveryError::HttpServerError(_) => Error::unavailable(err),
             DiscoveryError::ParamsError(_) => Error::invalid_argument(err),
+            DiscoveryError::TokenTypeError(_) => Error::invalid_argument(err),
         }
     }
 }
@@ -86,6 +91,7 @@ impl Builder {
                 device_type: DeviceType::default(),
                 device_id: device_id.into(),
                 client_id: client_id.into(),
+                state: StateStore::default(),
             },
             port: 0,
             zeroconf_ip: vec![],
@@ -110,6 +116,13 @@ impl Builder {
         self
     }
 
+    /// Records the credentials that are received into `state`, usually the one of
+    /// [`SessionConfig::state`](crate::core::SessionConfig::state).
+    pub fn state(mut self, state: StateStore) -> Self {
+        self.server_config.state = state;
+        self
+    }
+
     /// Sets the port on which it should listen to incoming connections.
     /// The default value `0` means any port.
     pub fn port(mut self, port: u16) -> Self {
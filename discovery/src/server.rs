@@ -28,18 +28,38 @@ use super::DiscoveryError;
 
 use crate::{
     core::config::DeviceType,
-    core::{authentication::Credentials, diffie_hellman::DhLocalKeys, Error},
+    core::{
+        authentication::Credentials,
+        diffie_hellman::DhLocalKeys,
+        state::{StateEvent, StateStore},
+        Error,
+    },
 };
 
 type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
 
 type Params<'a> = BTreeMap<Cow<'a, str>, Cow<'a, str>>;
 
+// The version of the discovery protocol announced in getInfo. Departing from the Spotify
+// documentation, Google Cast announces "5.0.0".
+const PROTOCOL_VERSION: &str = "2.9.0";
+
+// Whether the key exchange of the protocol `version` that a client announces in addUser is
+// the one implemented here. That holds up to the major version announced in getInfo.
+fn speaks_version(version: &str) -> bool {
+    let major = |version: &str| version.split('.').next()?.parse::<u32>().ok();
+    matches!(
+        (major(version), major(PROTOCOL_VERSION)),
+        (Some(client), Some(server)) if (1..=server).contains(&client)
+    )
+}
+
 pub struct Config {
     pub name: Cow<'static, str>,
     pub device_type: DeviceType,
     pub device_id: String,
     pub client_id: String,
+    pub state: StateStore,
 }
 
 struct RequestHandler {
@@ -76,8 +96,7 @@ impl RequestHandler {
             "status": 101,
             "statusString": "OK",
             "spotifyError": 0,
-            // departing from the Spotify documentation, Google Cast uses "5.0.0"
-            "version": "2.9.0",
+            "version": PROTOCOL_VERSION,
             "deviceID": (self.config.device_id),
             "deviceType": (device_type),
             "remoteName": (self.config.name),
@@ -128,13 +147,21 @@ impl RequestHandler {
             .get(clientkey_key)
             .ok_or(DiscoveryError::ParamsError(clientkey_key))?;
 
+        // Clients announce the version of the protocol they speak, which decides how the
+        // credentials are encrypted. Those that don't announce one speak the original.
+        let version = params.get("version").map(Cow::as_ref);
+        if let Some(version) = version {
+            debug!("Client {:?} uses discovery version {}", username, version);
+        }
+
         let encrypted_blob = BASE64.decode(encrypted_blob.as_bytes())?;
 
         let client_key = BASE64.decode(client_key.as_bytes())?;
         let shared_key = self.keys.shared_secret(&client_key);
 
+        // the blob consists of a 16 byte IV, the encrypted data and a 20 byte checksum
         let encrypted_blob_len = encrypted_blob.len();
-        if encrypted_blob_len < 16 {
+        if encrypted_blob_len < 16 + 20 {
             return Err(DiscoveryError::HmacError(encrypted_blob.to_vec()).into());
         }
 
@@ -163,12 +190,29 @@ impl RequestHandler {
             .map_err(|_| DiscoveryError::HmacError(base_key.to_vec()))?;
         h.update(encrypted);
         if h.verify_slice(cksum).is_err() {
-            warn!("Login error for user {:?}: MAC mismatch", username);
-            let result = json!({
-                "status": 102,
-                "spotifyError": 1,
-                "statusString": "ERROR-MAC"
-            });
+            // A client with a newer key exchange may still pair after updating this device,
+            // unlike one that failed to encrypt with ours.
+            let result = match version {
+                Some(version) if !speaks_version(version) => {
+                    warn!(
+                        "Login error for user {:?}: unsupported discovery version {}",
+                        username, version
+                    );
+                    json!({
+                        "status": 104,
+                        "spotifyError": 1,
+                        "statusString": "ERROR-NOT-IMPLEMENTED"
+                    })
+                }
+                _ => {
+                    warn!("Login error for user {:?}: MAC mismatch", username);
+                    json!({
+                        "status": 102,
+                        "spotifyError": 1,
+                        "statusString": "ERROR-MAC"
+                    })
+                }
+            };
 
             let body = result.to_string();
             return Ok(Response::new(Body::from(body)));
@@ -182,8 +226,24 @@ impl RequestHandler {
             data
         };
 
-        let credentials = Credentials::with_blob(username, decrypted, &self.config.device_id)?;
+        // Newer clients may send an access token instead of an encrypted credentials blob,
+        // even though "default" is announced as the token type.
+        let credentials = match params.get("tokenType").map(|t| t.as_ref()) {
+            None | Some("default") => {
+                Credentials::with_blob(username, decrypted, &self.config.device_id)?
+            }
+            Some("accesstoken") => {
+                debug!("Client {:?} sent an access token", username);
+                Credentials::with_access_token(String::from_utf8(decrypted)?)
+            }
+            Some(token_type) => {
+                return Err(DiscoveryError::TokenTypeError(token_type.to_owned()).into());
+            }
+        };
 
+        self.config.state.record(StateEvent::CredentialsReceived {
+            username: username.to_owned(),
+        });
         self.tx.send(credentials)?;
 
         let result = json!({
@@ -295,3 +355,193 @@ impl Stream for DiscoveryServer {
         self.cred_rx.poll_recv(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use aes::{
+        cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+        Aes192,
+    };
+    use pbkdf2::pbkdf2_hmac;
+
+    use super::*;
+
+    const DEVICE_ID: &str = "device";
+    const USERNAME: &str = "user";
+    // `AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS`
+    const STORED_CREDENTIALS: u8 = 1;
+
+    fn handler() -> (RequestHandler, mpsc::UnboundedReceiver<Credentials>) {
+        RequestHandler::new(Config {
+            name: "librespot".into(),
+            device_type: DeviceType::Speaker,
+            device_id: DEVICE_ID.into(),
+            client_id: String::new(),
+            state: StateStore::new(),
+        })
+    }
+
+    // A credentials blob the way Spotify clients encrypt it for `DEVICE_ID`, the inverse of
+    // `Credentials::with_blob`.
+    fn credentials_blob(auth_data: &[u8]) -> Vec<u8> {
+        let mut blob = vec![0, 0, 0];
+        blob.push(STORED_CREDENTIALS);
+        blob.push(0);
+        blob.push(auth_data.len() as u8);
+        blob.extend_from_slice(auth_data);
+        blob.resize((blob.len() + 15) / 16 * 16, 0);
+
+        let mut key = [0u8; 24];
+        pbkdf2_hmac::<Sha1>(
+            &Sha1::digest(DEVICE_ID),
+            USERNAME.as_bytes(),
+            0x100,
+            &mut key[..20],
+        );
+        let hash = Sha1::digest(&key[..20]);
+        key[..20].copy_from_slice(&hash);
+        key[20..].copy_from_slice(&20_u32.to_be_bytes());
+
+        for i in 16..blob.len() {
+            blob[i] ^= blob[i - 16];
+        }
+        let cipher = Aes192::new(GenericArray::from_slice(&key));
+        for block in blob.chunks_exact_mut(16) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        BASE64.encode(blob).into_bytes()
+    }
+
+    // Encrypts `data` for `handler` the way clients do when they send credentials.
+    fn add_user(
+        handler: &RequestHandler,
+        token_type: Option<&str>,
+        data: &[u8],
+    ) -> Result<Response<Body>, Error> {
+        handler.handle_add_user(&add_user_params(handler, token_type, None, data))
+    }
+
+    fn add_user_params(
+        handler: &RequestHandler,
+        token_type: Option<&str>,
+        version: Option<&str>,
+        data: &[u8],
+    ) -> Params<'static> {
+        let keys = DhLocalKeys::random(&mut rand::thread_rng());
+        let base_key = Sha1::digest(keys.shared_secret(&handler.keys.public_key()));
+        let base_key = &base_key[..16];
+        let hmac = |key: &[u8], data: &[u8]| {
+            let mut h = <Hmac<Sha1> as Mac>::new_from_slice(key).unwrap();
+            h.update(data);
+            h.finalize().into_bytes()
+        };
+
+        let iv = [7; 16];
+        let mut encrypted = data.to_vec();
+        Aes128Ctr::new_from_slices(&hmac(base_key, b"encryption")[..16], &iv)
+            .unwrap()
+            .apply_keystream(&mut encrypted);
+        let checksum = hmac(&hmac(base_key, b"checksum"), &encrypted);
+
+        let blob = [&iv[..], &encrypted, &checksum].concat();
+        let mut params = Params::new();
+        params.insert("userName".into(), USERNAME.into());
+        params.insert("blob".into(), BASE64.encode(blob).into());
+        params.insert("clientKey".into(), BASE64.encode(keys.public_key()).into());
+        if let Some(token_type) = token_type {
+            params.insert("tokenType".into(), token_type.to_owned().into());
+        }
+        if let Some(version) = version {
+            params.insert("version".into(), version.to_owned().into());
+        }
+        params
+    }
+
+    fn json(response: Response<Body>) -> serde_json::Value {
+        let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body()));
+        serde_json::from_slice(&body.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn decrypts_credentials_blobs() {
+        let (handler, mut credentials) = handler();
+        let blob = credentials_blob(b"stored credentials");
+
+        for token_type in [None, Some("default")] {
+            add_user(&handler, token_type, &blob).unwrap();
+            let received = credentials.try_recv().unwrap();
+            assert_eq!(received.username, USERNAME);
+            assert_eq!(received.auth_type as u8, STORED_CREDENTIALS);
+            assert_eq!(received.auth_data, b"stored credentials");
+        }
+    }
+
+    #[test]
+    fn accepts_access_tokens() {
+        let (handler, mut credentials) = handler();
+        add_user(&handler, Some("accesstoken"), b"token").unwrap();
+        assert_eq!(
+            credentials.try_recv().unwrap(),
+            Credentials::with_access_token("token")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_token_types() {
+        let (handler, mut credentials) = handler();
+        let err = add_user(&handler, Some("authorizationcode"), b"code").unwrap_err();
+        assert_eq!(
+            err.error.to_string(),
+            "Unsupported token type authorizationcode"
+        );
+        assert!(credentials.try_recv().is_err());
+    }
+
+    #[test]
+    fn negotiates_the_protocol_version() {
+        assert!(speaks_version("1.0.0"));
+        assert!(speaks_version("2.7.1"));
+        assert!(speaks_version(PROTOCOL_VERSION));
+        assert!(!speaks_version("3.0.0"));
+        assert!(!speaks_version("5.0.0"));
+        assert!(!speaks_version("0.1"));
+        assert!(!speaks_version("unknown"));
+
+        let (handler, _) = handler();
+        let info = json(handler.handle_get_info());
+        assert_eq!(info["version"], PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn accepts_credentials_of_all_versions_with_the_same_key_exchange() {
+        let (handler, mut credentials) = handler();
+        let blob = credentials_blob(b"stored credentials");
+
+        for version in ["2.7.1", PROTOCOL_VERSION, "5.0.0"] {
+            let params = add_user_params(&handler, None, Some(version), &blob);
+            assert_eq!(
+                json(handler.handle_add_user(&params).unwrap())["status"],
+                101
+            );
+            assert_eq!(
+                credentials.try_recv().unwrap().auth_data,
+                b"stored credentials"
+            );
+        }
+    }
+
+    #[test]
+    fn tells_clients_of_unsupported_versions_apart_from_bad_checksums() {
+        let (handler, mut credentials) = handler();
+        // encrypted for another device, so that the checksum doesn't match
+        let (other, _) = self::handler();
+        let blob = credentials_blob(b"stored credentials");
+
+        for (version, expected) in [(None, 102), (Some("2.7.1"), 102), (Some("3.0.0"), 104)] {
+            let params = add_user_params(&other, None, version, &blob);
+            let response = json(handler.handle_add_user(&params).unwrap());
+            assert_eq!(response["status"], expected);
+        }
+        assert!(credentials.try_recv().is_err());
+    }
+}
@@ -2,8 +2,12 @@ use std::env;
 
 use librespot::core::{authentication::Credentials, config::SessionConfig, session::Session};
 
-const SCOPES: &str =
-    "streaming,user-read-playback-state,user-modify-playback-state,user-read-currently-playing";
+const SCOPES: &[&str] = &[
+    "streaming",
+    "user-read-playback-state",
+    "user-modify-playback-state",
+    "user-read-currently-playing",
+];
 
 #[tokio::main]
 async fn main() {
@@ -20,10 +24,7 @@ async fn main() {
     let session = Session::new(session_config, None);
 
     match session.connect(credentials, false).await {
-        Ok(()) => println!(
-            "Token: {:#?}",
-            session.token_provider().get_token(SCOPES).await.unwrap()
-        ),
+        Ok(()) => println!("Token: {:#?}", session.token(SCOPES).await.unwrap()),
         Err(e) => println!("Error connecting: {}", e),
     }
 }
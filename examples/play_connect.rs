@@ -1,6 +1,6 @@
 use librespot::{
     core::{
-        authentication::Credentials, config::SessionConfig, session::Session, spotify_id::SpotifyId,
+        authentication::Credentials, config::SessionConfig, session::Session, spotify_id::AlbumId,
     },
     playback::{
         audio_backend,
@@ -61,7 +61,7 @@ async fn main() {
     .unwrap();
 
     join!(spirc_task, async {
-        let album = Album::get(&session, &SpotifyId::from_uri(&context_uri).unwrap())
+        let album = Album::get(&session, &AlbumId::from_uri(&context_uri).unwrap())
             .await
             .unwrap();
         let tracks = album
@@ -88,8 +88,10 @@ async fn main() {
                 context_uri,
                 start_playing: true,
                 shuffle: false,
+                shuffle_seed: None,
                 repeat: false,
                 playing_track_index: 0, // the index specifies which track in the context starts playing, in this case the first in the album
+                position_ms: 0,
                 tracks,
             })
             .unwrap();
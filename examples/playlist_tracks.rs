@@ -2,7 +2,10 @@ use std::{env, process::exit};
 
 use librespot::{
     core::{
-        authentication::Credentials, config::SessionConfig, session::Session, spotify_id::SpotifyId,
+        authentication::Credentials,
+        config::SessionConfig,
+        session::Session,
+        spotify_id::{PlaylistId, TrackId},
     },
     metadata::{Metadata, Playlist, Track},
 };
@@ -19,7 +22,7 @@ async fn main() {
     }
     let credentials = Credentials::with_password(&args[1], &args[2]);
 
-    let plist_uri = SpotifyId::from_uri(&args[3]).unwrap_or_else(|_| {
+    let plist_uri = PlaylistId::from_uri(&args[3]).unwrap_or_else(|_| {
         eprintln!(
             "PLAYLIST should be a playlist URI such as: \
                 \"spotify:playlist:37i9dQZF1DXec50AjHrNTq\""
@@ -35,8 +38,9 @@ async fn main() {
 
     let plist = Playlist::get(&session, &plist_uri).await.unwrap();
     println!("{:?}", plist);
-    for track_id in plist.tracks() {
-        let plist_track = Track::get(&session, track_id).await.unwrap();
+    // Playlists can contain podcast episodes as well.
+    for track_id in plist.tracks().filter_map(|id| TrackId::try_from(id).ok()) {
+        let plist_track = Track::get(&session, &track_id).await.unwrap();
         println!("track: {} ", plist_track.name);
     }
 }
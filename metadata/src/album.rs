@@ -18,7 +18,7 @@ use crate::{
     Metadata,
 };
 
-use librespot_core::{date::Date, Error, Session, SpotifyId};
+use librespot_core::{date::Date, spotify_id::AlbumId, Error, Session, SpotifyId};
 
 use librespot_protocol as protocol;
 pub use protocol::metadata::album::Type as AlbumType;
@@ -75,6 +75,7 @@ impl Album {
 #[async_trait]
 impl Metadata for Album {
     type Message = protocol::metadata::Album;
+    type Id = AlbumId;
 
     async fn request(session: &Session, album_id: &SpotifyId) -> RequestResult {
         session.spclient().get_album_metadata(album_id).await
@@ -97,7 +98,7 @@ impl TryFrom<&<Self as Metadata>::Message> for Album {
             date: album.date.get_or_default().try_into()?,
             popularity: album.popularity(),
             genres: album.genre.to_vec(),
-            covers: album.cover_group.get_or_default().into(),
+            covers: album.cover_group.get_or_default().try_into()?,
             external_ids: album.external_id.as_slice().into(),
             discs: album.disc.as_slice().try_into()?,
             reviews: album.review.to_vec(),
@@ -105,7 +106,7 @@ impl TryFrom<&<Self as Metadata>::Message> for Album {
             restrictions: album.restriction.as_slice().into(),
             related: album.related.as_slice().try_into()?,
             sale_periods: album.sale_period.as_slice().try_into()?,
-            cover_group: album.cover_group.image.as_slice().into(),
+            cover_group: album.cover_group.image.as_slice().try_into()?,
             original_title: album.original_title().to_owned(),
             version_title: album.version_title().to_owned(),
             type_str: album.type_str().to_owned(),
@@ -13,11 +13,11 @@ use crate::{
     restriction::Restrictions,
     sale_period::SalePeriods,
     track::Tracks,
-    util::{impl_deref_wrapped, impl_from_repeated, impl_try_from_repeated},
+    util::{impl_deref_wrapped, impl_try_from_repeated},
     Metadata,
 };
 
-use librespot_core::{Error, Session, SpotifyId};
+use librespot_core::{spotify_id::ArtistId, Error, Session, SpotifyId};
 
 use librespot_protocol as protocol;
 pub use protocol::metadata::artist_with_role::ArtistRole;
@@ -172,6 +172,7 @@ impl Artist {
 #[async_trait]
 impl Metadata for Artist {
     type Message = protocol::metadata::Artist;
+    type Id = ArtistId;
 
     async fn request(session: &Session, artist_id: &SpotifyId) -> RequestResult {
         session.spclient().get_artist_metadata(artist_id).await
@@ -196,8 +197,8 @@ impl TryFrom<&<Self as Metadata>::Message> for Artist {
             appears_on_albums: artist.appears_on_group.as_slice().try_into()?,
             genre: artist.genre.to_vec(),
             external_ids: artist.external_id.as_slice().into(),
-            portraits: artist.portrait.as_slice().into(),
-            biographies: artist.biography.as_slice().into(),
+            portraits: artist.portrait.as_slice().try_into()?,
+            biographies: artist.biography.as_slice().try_into()?,
             activity_periods: artist.activity_period.as_slice().try_into()?,
             restrictions: artist.restriction.as_slice().into(),
             related: artist.related.as_slice().try_into()?,
@@ -207,7 +208,7 @@ impl TryFrom<&<Self as Metadata>::Message> for Artist {
                 .get_or_default()
                 .image
                 .as_slice()
-                .into(),
+                .try_into()?,
             sales_periods: artist.sale_period.as_slice().try_into()?,
             availabilities: artist.availability.as_slice().try_into()?,
         })
@@ -259,23 +260,24 @@ impl AlbumGroups {
 
 impl_try_from_repeated!(AlbumGroupMessage, AlbumGroups);
 
-impl From<&BiographyMessage> for Biography {
-    fn from(biography: &BiographyMessage) -> Self {
+impl TryFrom<&BiographyMessage> for Biography {
+    type Error = librespot_core::Error;
+    fn try_from(biography: &BiographyMessage) -> Result<Self, Self::Error> {
         let portrait_group = biography
             .portrait_group
             .iter()
-            .map(|it| it.image.as_slice().into())
-            .collect();
+            .map(|it| it.image.as_slice().try_into())
+            .collect::<Result<_, _>>()?;
 
-        Self {
+        Ok(Self {
             text: biography.text().to_owned(),
-            portraits: biography.portrait.as_slice().into(),
+            portraits: biography.portrait.as_slice().try_into()?,
             portrait_group,
-        }
+        })
     }
 }
 
-impl_from_repeated!(BiographyMessage, Biographies);
+impl_try_from_repeated!(BiographyMessage, Biographies);
 
 impl TryFrom<&ActivityPeriodMessage> for ActivityPeriod {
     type Error = librespot_core::Error;
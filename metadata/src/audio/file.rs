@@ -1,10 +1,11 @@
 use std::{
     collections::HashMap,
+    convert::TryFrom,
     fmt::Debug,
     ops::{Deref, DerefMut},
 };
 
-use librespot_core::FileId;
+use librespot_core::{Error, FileId};
 
 use librespot_protocol as protocol;
 pub use protocol::metadata::audio_file::Format as AudioFileFormat;
@@ -43,21 +44,19 @@ impl AudioFiles {
     }
 }
 
-impl From<&[AudioFileMessage]> for AudioFiles {
-    fn from(files: &[AudioFileMessage]) -> Self {
-        let audio_files: HashMap<AudioFileFormat, FileId> = files
-            .iter()
-            .filter_map(|file| {
-                let file_id = FileId::from(file.file_id());
-                if file.has_format() {
-                    Some((file.format(), file_id))
-                } else {
-                    trace!("Ignoring file <{}> with unspecified format", file_id);
-                    None
-                }
-            })
-            .collect();
-
-        AudioFiles(audio_files)
+impl TryFrom<&[AudioFileMessage]> for AudioFiles {
+    type Error = Error;
+    fn try_from(files: &[AudioFileMessage]) -> Result<Self, Self::Error> {
+        let mut audio_files = HashMap::with_capacity(files.len());
+        for file in files {
+            let file_id = FileId::try_from(file)?;
+            if file.has_format() {
+                audio_files.insert(file.format(), file_id);
+            } else {
+                trace!("Ignoring file <{}> with unspecified format", file_id);
+            }
+        }
+
+        Ok(AudioFiles(audio_files))
     }
 }
@@ -14,7 +14,10 @@ use crate::{
 use super::file::AudioFiles;
 
 use librespot_core::{
-    date::Date, session::UserData, spotify_id::SpotifyItemType, Error, Session, SpotifyId,
+    date::Date,
+    session::UserData,
+    spotify_id::{EpisodeId, SpotifyItemType, TrackId},
+    Error, Session, SpotifyId,
 };
 
 pub type AudioItemResult = Result<AudioItem, Error>;
@@ -67,7 +70,7 @@ impl AudioItem {
 
         match id.item_type {
             SpotifyItemType::Track => {
-                let track = Track::get(session, &id).await?;
+                let track = Track::get(session, &TrackId::try_from(id)?).await?;
 
                 if track.duration <= 0 {
                     return Err(Error::unavailable(MetadataError::InvalidDuration(
@@ -99,11 +102,13 @@ impl AudioItem {
                     Some(track.alternatives)
                 };
 
-                let availability = if Date::now_utc() < track.earliest_live_timestamp {
+                let now = session.server_time();
+                let availability = if now < track.earliest_live_timestamp {
                     Err(UnavailabilityReason::Embargo)
                 } else {
                     available_for_user(
                         &session.user_data(),
+                        now,
                         &track.availability,
                         &track.restrictions,
                     )
@@ -137,7 +142,7 @@ impl AudioItem {
                 })
             }
             SpotifyItemType::Episode => {
-                let episode = Episode::get(session, &id).await?;
+                let episode = Episode::get(session, &EpisodeId::try_from(id)?).await?;
 
                 if episode.duration <= 0 {
                     return Err(Error::unavailable(MetadataError::InvalidDuration(
@@ -156,6 +161,7 @@ impl AudioItem {
 
                 let availability = available_for_user(
                     &session.user_data(),
+                    session.server_time(),
                     &episode.availability,
                     &episode.restrictions,
                 );
@@ -250,7 +256,7 @@ fn allowed_for_user(user_data: &UserData, restrictions: &Restrictions) -> AudioI
     Ok(()) // no restrictions in place
 }
 
-fn available(availability: &Availabilities) -> AudioItemAvailability {
+fn available(availability: &Availabilities, now: Date) -> AudioItemAvailability {
     if availability.is_empty() {
         // not all items have availability specified
         return Ok(());
@@ -258,7 +264,7 @@ fn available(availability: &Availabilities) -> AudioItemAvailability {
 
     if !(availability
         .iter()
-        .any(|availability| Date::now_utc() >= availability.start))
+        .any(|availability| now >= availability.start))
     {
         return Err(UnavailabilityReason::Embargo);
     }
@@ -268,10 +274,11 @@ fn available(availability: &Availabilities) -> AudioItemAvailability {
 
 fn available_for_user(
     user_data: &UserData,
+    now: Date,
     availability: &Availabilities,
     restrictions: &Restrictions,
 ) -> AudioItemAvailability {
-    available(availability)?;
+    available(availability, now)?;
     allowed_for_user(user_data, restrictions)?;
     Ok(())
 }
@@ -16,7 +16,7 @@ use crate::{
     Metadata,
 };
 
-use librespot_core::{date::Date, Error, Session, SpotifyId};
+use librespot_core::{date::Date, spotify_id::EpisodeId, Error, Session, SpotifyId};
 
 use librespot_protocol as protocol;
 pub use protocol::metadata::episode::EpisodeType;
@@ -57,6 +57,7 @@ impl_deref_wrapped!(Episodes, Vec<SpotifyId>);
 #[async_trait]
 impl Metadata for Episode {
     type Message = protocol::metadata::Episode;
+    type Id = EpisodeId;
 
     async fn request(session: &Session, episode_id: &SpotifyId) -> RequestResult {
         session.spclient().get_episode_metadata(episode_id).await
@@ -74,19 +75,19 @@ impl TryFrom<&<Self as Metadata>::Message> for Episode {
             id: episode.try_into()?,
             name: episode.name().to_owned(),
             duration: episode.duration().to_owned(),
-            audio: episode.audio.as_slice().into(),
+            audio: episode.audio.as_slice().try_into()?,
             description: episode.description().to_owned(),
             number: episode.number(),
             publish_time: episode.publish_time.get_or_default().try_into()?,
-            covers: episode.cover_image.image.as_slice().into(),
+            covers: episode.cover_image.image.as_slice().try_into()?,
             language: episode.language().to_owned(),
             is_explicit: episode.explicit().to_owned(),
             show_name: episode.show.name().to_owned(),
-            videos: episode.video.as_slice().into(),
-            video_previews: episode.video_preview.as_slice().into(),
-            audio_previews: episode.audio_preview.as_slice().into(),
+            videos: episode.video.as_slice().try_into()?,
+            video_previews: episode.video_preview.as_slice().try_into()?,
+            audio_previews: episode.audio_preview.as_slice().try_into()?,
             restrictions: episode.restriction.as_slice().into(),
-            freeze_frames: episode.freeze_frame.image.as_slice().into(),
+            freeze_frames: episode.freeze_frame.image.as_slice().try_into()?,
             keywords: episode.keyword.to_vec(),
             allow_background_playback: episode.allow_background_playback(),
             availability: episode.availability.as_slice().try_into()?,
@@ -11,4 +11,30 @@ pub enum MetadataError {
     InvalidDuration(i32),
     #[error("track is marked as explicit, which client setting forbids")]
     ExplicitContentFiltered,
+    #[error("playlist diff can not be applied: {0}")]
+    InvalidPlaylistDiff(String),
+    #[error(
+        "playlist revision {} conflicts with {}",
+        format_revision(.actual),
+        format_revision(.expected)
+    )]
+    RevisionConflict { expected: Vec<u8>, actual: Vec<u8> },
+}
+
+// Revisions are a big endian counter followed by a hash, shown as `{counter},{hash}`.
+fn format_revision(revision: &[u8]) -> String {
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+    match revision.split_at(revision.len().min(4)) {
+        (counter, hash) if counter.len() == 4 => format!(
+            "{},{}",
+            u32::from_be_bytes([counter[0], counter[1], counter[2], counter[3]]),
+            hex(hash)
+        ),
+        _ => hex(revision),
+    }
 }
@@ -6,7 +6,7 @@ use std::{
 
 use crate::util::{impl_deref_wrapped, impl_from_repeated, impl_try_from_repeated};
 
-use librespot_core::{FileId, SpotifyId};
+use librespot_core::FileId;
 
 use librespot_protocol as protocol;
 pub use protocol::metadata::image::Size as ImageSize;
@@ -26,9 +26,10 @@ pub struct Image {
 #[derive(Debug, Clone, Default)]
 pub struct Images(pub Vec<Image>);
 
-impl From<&ImageGroup> for Images {
-    fn from(image_group: &ImageGroup) -> Self {
-        Self(image_group.image.iter().map(|i| i.into()).collect())
+impl TryFrom<&ImageGroup> for Images {
+    type Error = librespot_core::Error;
+    fn try_from(image_group: &ImageGroup) -> Result<Self, Self::Error> {
+        image_group.image.as_slice().try_into()
     }
 }
 
@@ -48,7 +49,7 @@ impl_deref_wrapped!(PictureSizes, Vec<PictureSize>);
 #[derive(Debug, Clone)]
 pub struct TranscodedPicture {
     pub target_name: String,
-    pub uri: SpotifyId,
+    pub id: FileId,
 }
 
 #[derive(Debug, Clone)]
@@ -56,18 +57,19 @@ pub struct TranscodedPictures(pub Vec<TranscodedPicture>);
 
 impl_deref_wrapped!(TranscodedPictures, Vec<TranscodedPicture>);
 
-impl From<&ImageMessage> for Image {
-    fn from(image: &ImageMessage) -> Self {
-        Self {
-            id: image.into(),
+impl TryFrom<&ImageMessage> for Image {
+    type Error = librespot_core::Error;
+    fn try_from(image: &ImageMessage) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: image.try_into()?,
             size: image.size(),
             width: image.width(),
             height: image.height(),
-        }
+        })
     }
 }
 
-impl_from_repeated!(ImageMessage, Images);
+impl_try_from_repeated!(ImageMessage, Images);
 
 impl From<&PictureSizeMessage> for PictureSize {
     fn from(size: &PictureSizeMessage) -> Self {
@@ -85,7 +87,7 @@ impl TryFrom<&TranscodedPictureMessage> for TranscodedPicture {
     fn try_from(picture: &TranscodedPictureMessage) -> Result<Self, Self::Error> {
         Ok(Self {
             target_name: picture.target_name().to_owned(),
-            uri: picture.try_into()?,
+            id: picture.try_into()?,
         })
     }
 }
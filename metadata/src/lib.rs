@@ -4,9 +4,7 @@ extern crate log;
 #[macro_use]
 extern crate async_trait;
 
-use protobuf::Message;
-
-use librespot_core::{Error, Session, SpotifyId};
+use librespot_core::{anomaly, Error, Session, SpotifyId};
 
 pub mod album;
 pub mod artist;
@@ -18,6 +16,7 @@ pub mod episode;
 pub mod error;
 pub mod external_id;
 pub mod image;
+pub mod library;
 pub mod lyrics;
 pub mod playlist;
 mod request;
@@ -25,6 +24,7 @@ pub mod restriction;
 pub mod sale_period;
 pub mod show;
 pub mod track;
+pub mod user;
 mod util;
 pub mod video;
 
@@ -38,21 +38,69 @@ pub use lyrics::Lyrics;
 pub use playlist::Playlist;
 pub use show::Show;
 pub use track::Track;
+pub use user::UserProfile;
 
 #[async_trait]
 pub trait Metadata: Send + Sized + 'static {
-    type Message: protobuf::Message + std::fmt::Debug;
+    type Message: protobuf::MessageFull + std::fmt::Debug;
+    // The typed ID of the item, e.g. `TrackId` for a `Track`
+    type Id: Into<SpotifyId> + Copy + Send + Sync;
 
     // Request a protobuf
     async fn request(session: &Session, id: &SpotifyId) -> RequestResult;
 
     // Request a metadata struct
-    async fn get(session: &Session, id: &SpotifyId) -> Result<Self, Error> {
-        let response = Self::request(session, id).await?;
-        let msg = Self::Message::parse_from_bytes(&response)?;
+    async fn get(session: &Session, id: &Self::Id) -> Result<Self, Error> {
+        let id: SpotifyId = (*id).into();
+        let response = Self::request(session, &id).await?;
+        let msg = parse_message::<Self::Message>(session, &response)?;
         trace!("Received metadata: {:#?}", msg);
-        Self::parse(&msg, id)
+        Self::parse(&msg, &id)
     }
 
     fn parse(msg: &Self::Message, _: &SpotifyId) -> Result<Self, Error>;
 }
+
+// Parses a metadata message. Fields that are unknown to this version of the protocol are
+// reported as anomalies in strict mode, see `SessionConfig::strict_protocol`.
+pub(crate) fn parse_message<M: protobuf::MessageFull>(
+    session: &Session,
+    response: &[u8],
+) -> Result<M, Error> {
+    parse_strict(session.config().strict_protocol, response)
+}
+
+fn parse_strict<M: protobuf::MessageFull>(strict: bool, response: &[u8]) -> Result<M, Error> {
+    let msg = M::parse_from_bytes(response)?;
+    anomaly::check_unknown_fields(strict, &msg)?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::{Message, UnknownValue};
+
+    use super::*;
+    use librespot_protocol::metadata::{Album as AlbumMessage, Track as TrackMessage};
+
+    #[test]
+    fn reports_unknown_fields_in_strict_mode() {
+        let mut album = AlbumMessage::new();
+        album
+            .mut_unknown_fields()
+            .add_value(99, UnknownValue::Varint(1));
+        let mut track = TrackMessage::new();
+        track.set_name("Track".to_owned());
+        track.album = Some(album).into();
+        let bytes = track.write_to_bytes().expect("encoded track");
+
+        let tolerated = parse_strict::<TrackMessage>(false, &bytes).expect("parsed track");
+        assert_eq!(tolerated.name(), "Track");
+
+        let err = parse_strict::<TrackMessage>(true, &bytes).unwrap_err();
+        assert!(err.to_string().contains("album.99"), "{err}");
+
+        let known = TrackMessage::new().write_to_bytes().expect("encoded track");
+        assert!(parse_strict::<TrackMessage>(true, &known).is_ok());
+    }
+}
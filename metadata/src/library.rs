@@ -0,0 +1,642 @@
+//! A local index of the user's library, so that it can be searched without a round trip to
+//! Spotify, e.g. on devices with a slow or intermittent connection.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+use futures_util::future::join_all;
+
+use crate::{
+    playlist::sync::{apply_diff, read_snapshot},
+    Album, Artist, Metadata, Playlist, Track,
+};
+
+use librespot_core::{
+    spotify_id::{AlbumId, ArtistId, PlaylistId, SpotifyCollection, SpotifyItemType, TrackId},
+    Error, Session, SpotifyId,
+};
+
+use librespot_protocol as protocol;
+use protocol::collection2v2::{CollectionItem, DeltaResponse, PageResponse};
+use protocol::playlist4_external::Item as PlaylistItemMessage;
+use protocol::playlist4_external::SelectedListContent as PlaylistMessage;
+
+// The liked songs and albums, and the followed artists.
+const LIKED_SET: &str = "collection";
+const ARTISTS_SET: &str = "artist";
+
+// How many items are requested at once.
+const ROOTLIST_PAGE_SIZE: usize = 120;
+// How often the rootlist is read again when it changes while reading it.
+const ROOTLIST_MAX_ATTEMPTS: usize = 3;
+const METADATA_BATCH_SIZE: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryItem {
+    pub id: SpotifyId,
+    pub name: String,
+    /// The artists of a track, or the owner of a playlist.
+    pub subtitle: String,
+}
+
+#[derive(Debug, Default)]
+struct CollectionSet {
+    sync_token: Option<String>,
+    ids: HashSet<SpotifyId>,
+}
+
+impl CollectionSet {
+    fn with_delta(&self, delta: &DeltaResponse) -> Self {
+        let mut ids = self.ids.clone();
+        for (id, item) in LibraryIndex::indexable(&delta.items) {
+            if item.is_removed {
+                ids.remove(&id);
+            } else {
+                ids.insert(id);
+            }
+        }
+
+        let sync_token = Some(delta.sync_token.clone())
+            .filter(|sync_token| !sync_token.is_empty())
+            .or_else(|| self.sync_token.clone());
+        Self { sync_token, ids }
+    }
+}
+
+/// An in-memory index of the liked songs, playlists and followed artists of the user, for
+/// fuzzy searching.
+///
+/// The first [`sync`](Self::sync) requests the complete library, later ones only the changes
+/// to the liked songs, followed artists and playlists.
+#[derive(Debug, Default)]
+pub struct LibraryIndex {
+    items: HashMap<SpotifyId, LibraryItem>,
+    sets: HashMap<&'static str, CollectionSet>,
+    playlists: HashSet<SpotifyId>,
+    // the items of the rootlist at its last known revision
+    rootlist: Option<PlaylistMessage>,
+}
+
+impl LibraryIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Brings the index up to date with the library of the user.
+    pub async fn sync(&mut self, session: &Session) -> Result<(), Error> {
+        for set in [LIKED_SET, ARTISTS_SET] {
+            self.sync_set(session, set).await?;
+        }
+        self.sync_playlists(session).await?;
+
+        debug!("Indexed {} items of the library", self.items.len());
+        Ok(())
+    }
+
+    // The set is replaced once it has been brought up to date, so that a failure leaves
+    // the previous state to continue from with the next sync.
+    async fn sync_set(&mut self, session: &Session, set: &'static str) -> Result<(), Error> {
+        let updated = match self.sets.get(set) {
+            Some(collection) => Self::fetch_delta(session, set, collection).await?,
+            None => None,
+        };
+        let updated = match updated {
+            Some(updated) => updated,
+            None => Self::fetch_set(session, set).await?,
+        };
+
+        let (added, removed): (Vec<SpotifyId>, Vec<SpotifyId>) = match self.sets.get(set) {
+            Some(collection) => (
+                updated.ids.difference(&collection.ids).copied().collect(),
+                collection.ids.difference(&updated.ids).copied().collect(),
+            ),
+            None => (updated.ids.iter().copied().collect(), Vec::new()),
+        };
+
+        for id in removed {
+            self.items.remove(&id);
+        }
+        self.add_items(session, &added).await;
+
+        self.sets.insert(set, updated);
+        Ok(())
+    }
+
+    // The set with the changes since the last sync, or `None` if the complete set has to be
+    // requested.
+    async fn fetch_delta(
+        session: &Session,
+        set: &str,
+        collection: &CollectionSet,
+    ) -> Result<Option<CollectionSet>, Error> {
+        let sync_token = match collection.sync_token.as_deref() {
+            Some(sync_token) => sync_token,
+            None => return Ok(None),
+        };
+
+        let response = session
+            .spclient()
+            .get_collection_delta(set, sync_token)
+            .await?;
+        let delta: DeltaResponse = crate::parse_message(session, &response)?;
+        if !delta.delta_update_possible {
+            return Ok(None);
+        }
+
+        Ok(Some(collection.with_delta(&delta)))
+    }
+
+    async fn fetch_set(session: &Session, set: &str) -> Result<CollectionSet, Error> {
+        let (items, sync_token) = fetch_collection(session, set).await?;
+        Ok(CollectionSet {
+            sync_token: Some(sync_token),
+            ids: Self::indexable(&items).map(|(id, _)| id).collect(),
+        })
+    }
+
+    // Liked albums are in the same set as the liked songs, but aren't indexed.
+    fn indexable(
+        items: &[CollectionItem],
+    ) -> impl Iterator<Item = (SpotifyId, &CollectionItem)> + '_ {
+        items.iter().filter_map(|item| {
+            SpotifyId::from_uri(&item.uri)
+                .ok()
+                .filter(|id| {
+                    matches!(
+                        id.item_type,
+                        SpotifyItemType::Track | SpotifyItemType::Artist
+                    )
+                })
+                .map(|id| (id, item))
+        })
+    }
+
+    async fn add_items(&mut self, session: &Session, ids: &[SpotifyId]) {
+        for batch in ids.chunks(METADATA_BATCH_SIZE) {
+            let items = join_all(batch.iter().map(|id| Self::fetch_item(session, id))).await;
+            for (id, item) in batch.iter().zip(items) {
+                match item {
+                    Ok(item) => {
+                        self.items.insert(*id, item);
+                    }
+                    Err(e) => warn!("Unable to index {}: {}", id, e),
+                }
+            }
+        }
+    }
+
+    async fn fetch_item(session: &Session, id: &SpotifyId) -> Result<LibraryItem, Error> {
+        match id.item_type {
+            SpotifyItemType::Playlist => {
+                let playlist = Playlist::get(session, &PlaylistId::try_from(id)?).await?;
+                Ok(LibraryItem {
+                    id: *id,
+                    name: playlist.name().to_owned(),
+                    subtitle: playlist.id.username,
+                })
+            }
+            SpotifyItemType::Artist => {
+                let artist = Artist::get(session, &ArtistId::try_from(id)?).await?;
+                Ok(LibraryItem {
+                    id: *id,
+                    name: artist.name,
+                    subtitle: String::new(),
+                })
+            }
+            _ => {
+                let track = Track::get(session, &TrackId::try_from(id)?).await?;
+                let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+                Ok(LibraryItem {
+                    id: *id,
+                    name: track.name,
+                    subtitle: artists.join(", "),
+                })
+            }
+        }
+    }
+
+    // The rootlist is requested completely the first time, as it includes the names of the
+    // playlists already. Later only the changes are requested, and the names of the added
+    // playlists. If the changes can't be applied, it is requested completely again.
+    async fn sync_playlists(&mut self, session: &Session) -> Result<(), Error> {
+        let updated = match self.rootlist.as_ref() {
+            Some(rootlist) => match Self::fetch_rootlist_diff(session, rootlist).await {
+                Ok(None) => {
+                    trace!("The rootlist is up to date");
+                    return Ok(());
+                }
+                Ok(updated) => updated,
+                Err(e) => {
+                    debug!("Reading the rootlist again: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let (rootlist, named) = match updated {
+            Some(rootlist) => (rootlist, HashMap::new()),
+            None => {
+                let mut rootlist = read_snapshot(ROOTLIST_MAX_ATTEMPTS, |from| {
+                    session
+                        .spclient()
+                        .get_rootlist(from, Some(ROOTLIST_PAGE_SIZE))
+                })
+                .await?;
+                let named = named_playlists(&rootlist);
+                // The changes don't keep the names in line with the items.
+                rootlist.contents.mut_or_insert_default().meta_items.clear();
+                (rootlist, named)
+            }
+        };
+
+        let ids: HashSet<SpotifyId> = playlist_ids(&rootlist).collect();
+        for id in self.playlists.difference(&ids) {
+            self.items.remove(id);
+        }
+        let unnamed: Vec<SpotifyId> = ids
+            .iter()
+            .filter(|id| !named.contains_key(id) && !self.playlists.contains(id))
+            .copied()
+            .collect();
+        self.items.extend(named);
+        self.add_items(session, &unnamed).await;
+
+        // Playlists that couldn't be indexed are added again with the next changes.
+        self.playlists = ids
+            .into_iter()
+            .filter(|id| self.items.contains_key(id))
+            .collect();
+        self.rootlist = Some(rootlist);
+
+        Ok(())
+    }
+
+    // The rootlist with the changes since the last sync, or `None` if it didn't change.
+    async fn fetch_rootlist_diff(
+        session: &Session,
+        rootlist: &PlaylistMessage,
+    ) -> Result<Option<PlaylistMessage>, Error> {
+        let response = session
+            .spclient()
+            .get_rootlist_diff(rootlist.revision())
+            .await?;
+        let msg: PlaylistMessage = crate::parse_message(session, &response)?;
+        apply_diff(rootlist, &msg)
+    }
+
+    /// Returns the items whose name or subtitle match `query`, best matches first. Every
+    /// word of `query` has to match a word of the item, either as a prefix, anywhere in
+    /// it, or with the letters in order.
+    pub fn search_library(&self, query: &str) -> Vec<&LibraryItem> {
+        let query: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches: Vec<(u32, &LibraryItem)> = self
+            .items
+            .values()
+            .filter_map(|item| {
+                let text = format!("{} {}", item.name, item.subtitle).to_lowercase();
+                let words: Vec<&str> = text.split_whitespace().collect();
+
+                query
+                    .iter()
+                    .map(|term| words.iter().map(|word| match_score(term, word)).max())
+                    .try_fold(0, |total, score| match score {
+                        Some(score) if score > 0 => Some(total + score),
+                        _ => None,
+                    })
+                    .map(|score| (score, item))
+            })
+            .collect();
+
+        matches.sort_by(|(a_score, a), (b_score, b)| match b_score.cmp(a_score) {
+            Ordering::Equal => a.name.cmp(&b.name),
+            ordering => ordering,
+        });
+        matches.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+// The items of a set in their order, i.e. the most recently added first, and the token to
+// request the changes since.
+async fn fetch_collection(
+    session: &Session,
+    set: &str,
+) -> Result<(Vec<CollectionItem>, String), Error> {
+    let mut items = Vec::new();
+    let mut pagination_token: Option<String> = None;
+
+    loop {
+        let mut page = fetch_collection_page(session, set, pagination_token.as_deref()).await?;
+        items.append(&mut page.items);
+
+        if page.next_page_token.is_empty() {
+            return Ok((items, page.sync_token));
+        }
+        pagination_token = Some(page.next_page_token);
+    }
+}
+
+// A page of the items of a set, without those that were removed.
+async fn fetch_collection_page(
+    session: &Session,
+    set: &str,
+    pagination_token: Option<&str>,
+) -> Result<PageResponse, Error> {
+    let response = session
+        .spclient()
+        .get_collection_page(set, pagination_token, None)
+        .await?;
+    let mut page: PageResponse = crate::parse_message(session, &response)?;
+    page.items.retain(|item| !item.is_removed);
+    Ok(page)
+}
+
+/// Returns the tracks of a collection of the user in the order of the collection, i.e. the
+/// most recently added first. The tracks of saved albums are in album order.
+pub async fn get_collection_tracks(
+    session: &Session,
+    collection: SpotifyCollection,
+) -> Result<Vec<SpotifyId>, Error> {
+    let (items, _) = fetch_collection(session, LIKED_SET).await?;
+    collection_tracks(session, collection, &items).await
+}
+
+/// Returns a page of the tracks of a collection of the user, like [`get_collection_tracks`],
+/// and the token to request the next page with, if there is one. This way large collections
+/// can be played without requesting them completely.
+pub async fn get_collection_tracks_page(
+    session: &Session,
+    collection: SpotifyCollection,
+    pagination_token: Option<&str>,
+) -> Result<(Vec<SpotifyId>, Option<String>), Error> {
+    let mut pagination_token = pagination_token.map(str::to_owned);
+
+    // A page may not contain any item of the collection, e.g. only liked songs for the
+    // saved albums.
+    loop {
+        let page = fetch_collection_page(session, LIKED_SET, pagination_token.as_deref()).await?;
+        let tracks = collection_tracks(session, collection, &page.items).await?;
+
+        pagination_token = Some(page.next_page_token).filter(|token| !token.is_empty());
+        if !tracks.is_empty() || pagination_token.is_none() {
+            return Ok((tracks, pagination_token));
+        }
+    }
+}
+
+async fn collection_tracks(
+    session: &Session,
+    collection: SpotifyCollection,
+    items: &[CollectionItem],
+) -> Result<Vec<SpotifyId>, Error> {
+    let item_type = match collection {
+        SpotifyCollection::Tracks => SpotifyItemType::Track,
+        SpotifyCollection::Albums => SpotifyItemType::Album,
+    };
+
+    let ids: Vec<SpotifyId> = items
+        .iter()
+        .filter_map(|item| SpotifyId::from_uri(&item.uri).ok())
+        .filter(|id| id.item_type == item_type)
+        .collect();
+
+    if collection == SpotifyCollection::Tracks {
+        return Ok(ids);
+    }
+
+    let mut tracks = Vec::new();
+    for batch in ids.chunks(METADATA_BATCH_SIZE) {
+        let albums = join_all(
+            batch
+                .iter()
+                .map(|id| async move { Album::get(session, &AlbumId::try_from(id)?).await }),
+        )
+        .await;
+
+        for (id, album) in batch.iter().zip(albums) {
+            match album {
+                Ok(album) => tracks.extend(album.tracks().copied()),
+                Err(e) => warn!("Unable to get the tracks of {}: {}", id, e),
+            }
+        }
+    }
+
+    Ok(tracks)
+}
+
+// Folders are delimited by `SpotifyPlaylistGroup` items, which aren't playlists.
+fn playlist_id(item: &PlaylistItemMessage) -> Option<SpotifyId> {
+    SpotifyId::from_uri(item.uri())
+        .ok()
+        .filter(|id| id.item_type == SpotifyItemType::Playlist)
+}
+
+fn playlist_ids(rootlist: &PlaylistMessage) -> impl Iterator<Item = SpotifyId> + '_ {
+    rootlist.contents.items.iter().filter_map(playlist_id)
+}
+
+// The playlists of a rootlist that was requested with their names.
+fn named_playlists(rootlist: &PlaylistMessage) -> HashMap<SpotifyId, LibraryItem> {
+    let contents = rootlist.contents.get_or_default();
+    contents
+        .items
+        .iter()
+        .zip(contents.meta_items.iter())
+        .filter_map(|(item, meta_item)| {
+            let id = playlist_id(item)?;
+            let item = LibraryItem {
+                id,
+                name: meta_item.attributes.name().to_owned(),
+                subtitle: meta_item.owner_username().to_owned(),
+            };
+            Some((id, item))
+        })
+        .collect()
+}
+
+fn match_score(term: &str, word: &str) -> u32 {
+    if word == term {
+        4
+    } else if word.starts_with(term) {
+        3
+    } else if word.contains(term) {
+        2
+    } else if is_subsequence(term, word) {
+        1
+    } else {
+        0
+    }
+}
+
+fn is_subsequence(term: &str, word: &str) -> bool {
+    let mut chars = word.chars();
+    term.chars().all(|c| chars.any(|w| w == c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: u128, item_type: SpotifyItemType) -> SpotifyId {
+        SpotifyId { id, item_type }
+    }
+
+    fn collection_item(id: SpotifyId, is_removed: bool) -> CollectionItem {
+        let mut item = CollectionItem::new();
+        item.uri = id.to_uri().unwrap();
+        item.is_removed = is_removed;
+        item
+    }
+
+    fn rootlist_item(uri: &str) -> PlaylistItemMessage {
+        let mut item = PlaylistItemMessage::new();
+        item.set_uri(uri.to_owned());
+        item
+    }
+
+    fn item(id: u128, name: &str, subtitle: &str) -> LibraryItem {
+        LibraryItem {
+            id: SpotifyId {
+                id,
+                item_type: SpotifyItemType::Track,
+            },
+            name: name.to_owned(),
+            subtitle: subtitle.to_owned(),
+        }
+    }
+
+    #[test]
+    fn search_ranks_and_fuzzy_matches() {
+        let mut index = LibraryIndex::new();
+        for item in [
+            item(1, "Bohemian Rhapsody", "Queen"),
+            item(2, "Rhapsody in Blue", "George Gershwin"),
+            item(3, "Killer Queen", "Queen"),
+        ] {
+            index.items.insert(item.id, item);
+        }
+
+        let names = |query| -> Vec<String> {
+            index
+                .search_library(query)
+                .into_iter()
+                .map(|item| item.name.clone())
+                .collect()
+        };
+
+        assert_eq!(names("rhapsody"), ["Bohemian Rhapsody", "Rhapsody in Blue"]);
+        assert_eq!(names("queen"), ["Bohemian Rhapsody", "Killer Queen"]);
+        assert_eq!(names("bhm rhap"), ["Bohemian Rhapsody"]);
+        assert!(names("zeppelin").is_empty());
+        assert!(names("").is_empty());
+    }
+
+    #[test]
+    fn applies_collection_deltas() {
+        let collection = CollectionSet {
+            sync_token: Some("1".to_owned()),
+            ids: [id(1, SpotifyItemType::Track), id(2, SpotifyItemType::Track)]
+                .into_iter()
+                .collect(),
+        };
+
+        let mut delta = DeltaResponse::new();
+        delta.delta_update_possible = true;
+        delta.items = vec![
+            collection_item(id(1, SpotifyItemType::Track), true),
+            collection_item(id(3, SpotifyItemType::Track), false),
+            collection_item(id(4, SpotifyItemType::Album), false),
+        ];
+        delta.sync_token = "2".to_owned();
+
+        let updated = collection.with_delta(&delta);
+        let expected: HashSet<SpotifyId> =
+            [id(2, SpotifyItemType::Track), id(3, SpotifyItemType::Track)]
+                .into_iter()
+                .collect();
+        assert_eq!(updated.ids, expected);
+        assert_eq!(updated.sync_token.as_deref(), Some("2"));
+        // the previous set is left as it was
+        assert!(collection.ids.contains(&id(1, SpotifyItemType::Track)));
+
+        delta.sync_token.clear();
+        assert_eq!(
+            collection.with_delta(&delta).sync_token.as_deref(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn follows_the_rootlist_changes() {
+        let playlist = |n| id(n, SpotifyItemType::Playlist).to_uri().unwrap();
+
+        let mut rootlist = PlaylistMessage::new();
+        rootlist.set_revision(1u32.to_be_bytes().to_vec());
+        let contents = rootlist.contents.mut_or_insert_default();
+        for (uri, name) in [
+            (playlist(1), "Mix"),
+            ("spotify:start-group:1:Folder".to_owned(), "Folder"),
+            (playlist(2), "Chill"),
+            ("spotify:end-group:1".to_owned(), ""),
+        ] {
+            contents.items.push(rootlist_item(&uri));
+            let mut meta_item = protocol::playlist4_external::MetaItem::new();
+            meta_item
+                .attributes
+                .mut_or_insert_default()
+                .set_name(name.to_owned());
+            meta_item.set_owner_username("owner".to_owned());
+            contents.meta_items.push(meta_item);
+        }
+
+        let named = named_playlists(&rootlist);
+        assert_eq!(named.len(), 2);
+        assert_eq!(named[&id(2, SpotifyItemType::Playlist)].name, "Chill");
+        assert_eq!(named[&id(2, SpotifyItemType::Playlist)].subtitle, "owner");
+
+        // One playlist is added at the start, the one in the folder is removed.
+        let mut msg = PlaylistMessage::new();
+        let diff = msg.diff.mut_or_insert_default();
+        diff.set_from_revision(1u32.to_be_bytes().to_vec());
+        diff.set_to_revision(2u32.to_be_bytes().to_vec());
+        let mut add = protocol::playlist4_external::Op::new();
+        add.set_kind(protocol::playlist4_external::op::Kind::ADD);
+        let add_op = add.add.mut_or_insert_default();
+        add_op.set_add_first(true);
+        add_op.items.push(rootlist_item(&playlist(3)));
+        let mut rem = protocol::playlist4_external::Op::new();
+        rem.set_kind(protocol::playlist4_external::op::Kind::REM);
+        let rem_op = rem.rem.mut_or_insert_default();
+        rem_op.set_from_index(3);
+        rem_op.set_length(1);
+        diff.ops = vec![add, rem];
+
+        let updated = apply_diff(&rootlist, &msg).unwrap().unwrap();
+        assert_eq!(updated.revision(), 2u32.to_be_bytes());
+        let ids: Vec<SpotifyId> = playlist_ids(&updated).collect();
+        assert_eq!(
+            ids,
+            [
+                id(3, SpotifyItemType::Playlist),
+                id(1, SpotifyItemType::Playlist)
+            ]
+        );
+
+        msg.set_up_to_date(true);
+        assert!(apply_diff(&updated, &msg).unwrap().is_none());
+    }
+}
@@ -1,15 +1,13 @@
 use std::convert::TryFrom;
 use std::fmt::Debug;
 
-use protobuf::Message;
-
 use crate::{
     image::TranscodedPictures,
     request::{MercuryRequest, RequestResult},
     Metadata,
 };
 
-use librespot_core::{Error, Session, SpotifyId};
+use librespot_core::{spotify_id::PlaylistId, Error, Session, SpotifyId};
 
 use librespot_protocol as protocol;
 pub use protocol::playlist_annotate3::AbuseReportState;
@@ -26,6 +24,7 @@ pub struct PlaylistAnnotation {
 #[async_trait]
 impl Metadata for PlaylistAnnotation {
     type Message = protocol::playlist_annotate3::PlaylistAnnotation;
+    type Id = PlaylistId;
 
     async fn request(session: &Session, playlist_id: &SpotifyId) -> RequestResult {
         let current_user = session.username();
@@ -64,7 +63,7 @@ impl PlaylistAnnotation {
         playlist_id: &SpotifyId,
     ) -> Result<Self, Error> {
         let response = Self::request_for_user(session, username, playlist_id).await?;
-        let msg = <Self as Metadata>::Message::parse_from_bytes(&response)?;
+        let msg = crate::parse_message::<<Self as Metadata>::Message>(session, &response)?;
         Self::parse(&msg, playlist_id)
     }
 }
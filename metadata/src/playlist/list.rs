@@ -17,7 +17,7 @@ use super::{
 
 use librespot_core::{
     date::Date,
-    spotify_id::{NamedSpotifyId, SpotifyId},
+    spotify_id::{NamedSpotifyId, PlaylistId, SpotifyId},
     Error, Session,
 };
 
@@ -96,6 +96,7 @@ impl Playlist {
 #[async_trait]
 impl Metadata for Playlist {
     type Message = protocol::playlist4_external::SelectedListContent;
+    type Id = PlaylistId;
 
     async fn request(session: &Session, playlist_id: &SpotifyId) -> RequestResult {
         session.spclient().get_playlist(playlist_id).await
@@ -5,6 +5,8 @@ pub mod item;
 pub mod list;
 pub mod operation;
 pub mod permission;
+pub mod sync;
 
 pub use annotation::PlaylistAnnotation;
 pub use list::Playlist;
+pub use sync::PlaylistSync;
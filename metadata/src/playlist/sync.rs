@@ -0,0 +1,614 @@
+use std::{future::Future, ops::Range};
+
+use bytes::Bytes;
+use protobuf::Message;
+
+use crate::{error::MetadataError, Metadata};
+
+use super::list::Playlist;
+
+use librespot_core::{Error, Session, SpotifyId};
+
+use librespot_protocol as protocol;
+use protocol::playlist4_external::op::Kind as PlaylistOperationKind;
+use protocol::playlist4_external::Item as PlaylistItemMessage;
+use protocol::playlist4_external::Op as PlaylistOperationMessage;
+use protocol::playlist4_external::SelectedListContent as PlaylistMessage;
+
+// How often a playlist is read again when its pages are of different revisions.
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+/// Keeps a playlist up to date by applying the changes since the last known revision,
+/// instead of requesting the complete playlist every time. If a cache is configured, the
+/// last known revision is persisted, so that it survives restarts.
+///
+/// The revisions are checked to follow each other, so that a playlist that is changed while
+/// it is read isn't assembled from different revisions. It is read again instead, up to
+/// [`with_max_attempts`](Self::with_max_attempts) times, before failing with
+/// [`MetadataError::RevisionConflict`].
+pub struct PlaylistSync {
+    id: SpotifyId,
+    content: Option<PlaylistMessage>,
+    max_attempts: usize,
+}
+
+impl PlaylistSync {
+    pub fn new(session: &Session, id: SpotifyId) -> Self {
+        let content = session
+            .cache()
+            .and_then(|cache| cache.metadata(&Self::cache_key(&id)))
+            .and_then(|data| match PlaylistMessage::parse_from_bytes(&data) {
+                Ok(content) => Some(content),
+                Err(e) => {
+                    warn!("Ignoring cached playlist {}: {}", id, e);
+                    None
+                }
+            });
+
+        Self {
+            id,
+            content,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The last known revision, if any.
+    pub fn revision(&self) -> Option<&[u8]> {
+        self.content.as_ref().map(|content| content.revision())
+    }
+
+    /// Brings the playlist up to date and returns it. Falls back to requesting the complete
+    /// playlist if there is no known revision, or if the changes can not be applied to it.
+    pub async fn sync(&mut self, session: &Session) -> Result<Playlist, Error> {
+        if let Some(content) = self.content.as_mut() {
+            match Self::update(session, &self.id, content).await {
+                Ok(true) => {
+                    debug!("Applied changes to playlist {}", self.id);
+                    self.persist(session);
+                }
+                Ok(false) => trace!("Playlist {} is up to date", self.id),
+                Err(e) => {
+                    debug!("Reloading playlist {}: {}", self.id, e);
+                    self.content = None;
+                }
+            }
+        }
+
+        if self.content.is_none() {
+            let id = self.id;
+            let content = read_snapshot(self.max_attempts, |from| async move {
+                if from == 0 {
+                    Playlist::request(session, &id).await
+                } else {
+                    session.spclient().get_playlist_page(&id, from, None).await
+                }
+            })
+            .await?;
+
+            self.content = Some(content);
+            self.persist(session);
+        }
+
+        match self.content.as_ref() {
+            Some(content) => Playlist::parse(content, &self.id),
+            None => Err(Error::unavailable(MetadataError::Empty)),
+        }
+    }
+
+    fn cache_key(id: &SpotifyId) -> String {
+        format!("playlist-{}", id.to_base62().unwrap_or_default())
+    }
+
+    fn persist(&self, session: &Session) {
+        if let (Some(cache), Some(content)) = (session.cache(), self.content.as_ref()) {
+            match content.write_to_bytes() {
+                Ok(data) => cache.save_metadata(&Self::cache_key(&self.id), &data),
+                Err(e) => warn!("Cannot save playlist {}: {}", self.id, e),
+            }
+        }
+    }
+
+    // Returns whether the playlist changed.
+    async fn update(
+        session: &Session,
+        id: &SpotifyId,
+        content: &mut PlaylistMessage,
+    ) -> Result<bool, Error> {
+        if content.contents.truncated() {
+            return Err(invalid_diff("the known revision is incomplete"));
+        }
+
+        let response = session
+            .spclient()
+            .get_playlist_diff(id, content.revision())
+            .await?;
+        let msg: PlaylistMessage = crate::parse_message(session, &response)?;
+
+        match apply_diff(content, &msg)? {
+            Some(updated) => {
+                *content = updated;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Applies the changes in `msg`, the response to a diff request for the revision of
+/// `content`, to a copy of `content`, so that a failure leaves the known revision intact.
+/// Returns `None` if there are no changes.
+pub(crate) fn apply_diff(
+    content: &PlaylistMessage,
+    msg: &PlaylistMessage,
+) -> Result<Option<PlaylistMessage>, Error> {
+    if msg.up_to_date() {
+        return Ok(None);
+    }
+
+    let diff = msg
+        .diff
+        .as_ref()
+        .ok_or_else(|| invalid_diff("the response contains no diff"))?;
+    if diff.from_revision() != content.revision() {
+        return Err(revision_conflict(content.revision(), diff.from_revision()));
+    }
+    if diff.to_revision() == content.revision() {
+        return Ok(None);
+    }
+    // the counter of the revisions only grows
+    if let (Some(from), Some(to)) = (
+        revision_counter(diff.from_revision()),
+        revision_counter(diff.to_revision()),
+    ) {
+        if to <= from {
+            return Err(revision_conflict(diff.from_revision(), diff.to_revision()));
+        }
+    }
+    if msg.has_revision() && msg.revision() != diff.to_revision() {
+        return Err(revision_conflict(diff.to_revision(), msg.revision()));
+    }
+
+    let mut updated = content.clone();
+    for op in diff.ops.iter() {
+        apply_operation(&mut updated, op)?;
+    }
+
+    updated.set_revision(diff.to_revision().to_owned());
+    updated.set_length(updated.contents.items.len() as i32);
+    if msg.has_timestamp() {
+        updated.set_timestamp(msg.timestamp());
+    }
+
+    Ok(Some(updated))
+}
+
+fn invalid_diff(reason: &str) -> Error {
+    Error::failed_precondition(MetadataError::InvalidPlaylistDiff(reason.to_owned()))
+}
+
+fn revision_conflict(expected: &[u8], actual: &[u8]) -> Error {
+    Error::aborted(MetadataError::RevisionConflict {
+        expected: expected.to_vec(),
+        actual: actual.to_vec(),
+    })
+}
+
+fn is_revision_conflict(e: &Error) -> bool {
+    matches!(
+        e.error.downcast_ref::<MetadataError>(),
+        Some(MetadataError::RevisionConflict { .. })
+    )
+}
+
+fn revision_counter(revision: &[u8]) -> Option<u32> {
+    match revision {
+        [a, b, c, d, ..] => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+        _ => None,
+    }
+}
+
+/// Reads a playlist or the rootlist page by page, with `read_page` requesting the items from
+/// the given index on, and returns it as one snapshot. All pages have to be of the same
+/// revision; if the list changed in between, it is read again, up to `max_attempts` times.
+pub(crate) async fn read_snapshot<F, Fut>(
+    max_attempts: usize,
+    mut read_page: F,
+) -> Result<PlaylistMessage, Error>
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<Bytes, Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match read_pages(&mut read_page).await {
+            Err(e) if attempt < max_attempts && is_revision_conflict(&e) => {
+                debug!("Reading again: {}", e);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn read_pages<F, Fut>(read_page: &mut F) -> Result<PlaylistMessage, Error>
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<Bytes, Error>>,
+{
+    let mut snapshot = PlaylistMessage::parse_from_bytes(&read_page(0).await?)?;
+
+    while snapshot.contents.truncated() {
+        let from = snapshot.contents.items.len();
+        let page = PlaylistMessage::parse_from_bytes(&read_page(from).await?)?;
+        if page.revision() != snapshot.revision() {
+            return Err(revision_conflict(snapshot.revision(), page.revision()));
+        }
+
+        let page = page.contents.get_or_default();
+        let contents = snapshot.contents.mut_or_insert_default();
+        contents.items.extend(page.items.iter().cloned());
+        contents.meta_items.extend(page.meta_items.iter().cloned());
+        contents.set_truncated(page.truncated() && !page.items.is_empty());
+    }
+
+    Ok(snapshot)
+}
+
+fn checked_range(start: i32, length: i32, len: usize) -> Result<Range<usize>, Error> {
+    let start = usize::try_from(start).map_err(|_| invalid_diff("negative index"))?;
+    let length = usize::try_from(length).map_err(|_| invalid_diff("negative length"))?;
+    let end = start
+        .checked_add(length)
+        .filter(|&end| end <= len)
+        .ok_or_else(|| invalid_diff("range out of bounds"))?;
+    Ok(start..end)
+}
+
+fn apply_operation(
+    content: &mut PlaylistMessage,
+    op: &PlaylistOperationMessage,
+) -> Result<(), Error> {
+    let items = &mut content.contents.mut_or_insert_default().items;
+
+    match op.kind() {
+        PlaylistOperationKind::ADD => {
+            let add = op.add.get_or_default();
+            let index = if add.add_first() {
+                0
+            } else if add.add_last() {
+                items.len()
+            } else {
+                checked_range(add.from_index(), 0, items.len())?.start
+            };
+            items.splice(index..index, add.items.iter().cloned());
+        }
+        PlaylistOperationKind::REM => {
+            let rem = op.rem.get_or_default();
+            if rem.items_as_key() {
+                items.retain(|item| !rem.items.iter().any(|key| key.uri() == item.uri()));
+            } else {
+                let range = checked_range(rem.from_index(), rem.length(), items.len())?;
+                items.drain(range);
+            }
+        }
+        PlaylistOperationKind::MOV => {
+            let mov = op.mov.get_or_default();
+            let range = checked_range(mov.from_index(), mov.length(), items.len())?;
+            // the destination is an index into the list before the items were taken out
+            let to_index = checked_range(mov.to_index(), 0, items.len())?.start;
+            let to_index = if to_index > range.start {
+                to_index.saturating_sub(range.len()).max(range.start)
+            } else {
+                to_index
+            };
+            let moved: Vec<PlaylistItemMessage> = items.drain(range).collect();
+            items.splice(to_index..to_index, moved);
+        }
+        PlaylistOperationKind::UPDATE_ITEM_ATTRIBUTES => {
+            let update = op.update_item_attributes.get_or_default();
+            let new_attributes = update.new_attributes.get_or_default();
+            if !new_attributes.no_value.is_empty() {
+                return Err(invalid_diff("removing item attributes is not supported"));
+            }
+
+            let index = checked_range(update.index(), 1, items.len())?.start;
+            let values = new_attributes.values.get_or_default();
+            let attributes = items[index].attributes.mut_or_insert_default();
+            if !values.format_attributes.is_empty() {
+                attributes.format_attributes.clear();
+            }
+            attributes.merge_from_bytes(&values.write_to_bytes()?)?;
+        }
+        PlaylistOperationKind::UPDATE_LIST_ATTRIBUTES => {
+            let update = op.update_list_attributes.get_or_default();
+            let new_attributes = update.new_attributes.get_or_default();
+            if !new_attributes.no_value.is_empty() {
+                return Err(invalid_diff("removing list attributes is not supported"));
+            }
+
+            let values = new_attributes.values.get_or_default();
+            let attributes = content.attributes.mut_or_insert_default();
+            if !values.format_attributes.is_empty() {
+                attributes.format_attributes.clear();
+            }
+            if !values.picture_size.is_empty() {
+                attributes.picture_size.clear();
+            }
+            attributes.merge_from_bytes(&values.write_to_bytes()?)?;
+        }
+        PlaylistOperationKind::KIND_UNKNOWN => {
+            return Err(invalid_diff("unknown operation"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn page(revision: u32, from: usize, uris: &[&str], truncated: bool) -> Bytes {
+        let mut msg = PlaylistMessage::new();
+        msg.set_revision(revision.to_be_bytes().to_vec());
+        let contents = msg.contents.mut_or_insert_default();
+        contents.set_pos(from as i32);
+        contents.set_truncated(truncated);
+        for uri in uris {
+            let mut item = PlaylistItemMessage::new();
+            item.set_uri(uri.to_string());
+            contents.items.push(item);
+        }
+        msg.write_to_bytes().unwrap().into()
+    }
+
+    #[test]
+    fn snapshot_of_one_revision() {
+        // The playlist changes after the first page was read the first time.
+        let mut reads = 0;
+        let snapshot = read_snapshot(2, |from| {
+            reads += 1;
+            let response = match (reads, from) {
+                (1, 0) => page(1, from, &["a", "b"], true),
+                (2, 2) => page(2, from, &["c"], false),
+                (_, 0) => page(2, from, &["b", "a"], true),
+                _ => page(2, from, &["c"], false),
+            };
+            async move { Ok(response) }
+        })
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+
+        let uris: Vec<&str> = snapshot.contents.items.iter().map(|i| i.uri()).collect();
+        assert_eq!(uris, ["b", "a", "c"]);
+        assert_eq!(snapshot.revision(), 2u32.to_be_bytes());
+        assert!(!snapshot.contents.truncated());
+
+        let mut reads = 0;
+        let e = read_snapshot(2, |from| {
+            reads += 1;
+            let response = page(reads, from, &["a"], true);
+            async move { Ok(response) }
+        })
+        .now_or_never()
+        .unwrap()
+        .unwrap_err();
+        assert!(is_revision_conflict(&e));
+        assert_eq!(reads, 4);
+    }
+
+    fn item(uri: &str) -> PlaylistItemMessage {
+        let mut item = PlaylistItemMessage::new();
+        item.set_uri(uri.to_owned());
+        item
+    }
+
+    fn content(uris: &[&str]) -> PlaylistMessage {
+        let mut content = PlaylistMessage::new();
+        content.set_revision(1u32.to_be_bytes().to_vec());
+        content.contents.mut_or_insert_default().items = uris.iter().map(|uri| item(uri)).collect();
+        content
+    }
+
+    fn uris(content: &PlaylistMessage) -> Vec<&str> {
+        content.contents.items.iter().map(|i| i.uri()).collect()
+    }
+
+    fn add(
+        from_index: Option<i32>,
+        first: bool,
+        last: bool,
+        uris: &[&str],
+    ) -> PlaylistOperationMessage {
+        let mut op = PlaylistOperationMessage::new();
+        op.set_kind(PlaylistOperationKind::ADD);
+        let add = op.add.mut_or_insert_default();
+        if let Some(from_index) = from_index {
+            add.set_from_index(from_index);
+        }
+        add.set_add_first(first);
+        add.set_add_last(last);
+        add.items = uris.iter().map(|uri| item(uri)).collect();
+        op
+    }
+
+    fn rem(from_index: i32, length: i32) -> PlaylistOperationMessage {
+        let mut op = PlaylistOperationMessage::new();
+        op.set_kind(PlaylistOperationKind::REM);
+        let rem = op.rem.mut_or_insert_default();
+        rem.set_from_index(from_index);
+        rem.set_length(length);
+        op
+    }
+
+    fn mov(from_index: i32, length: i32, to_index: i32) -> PlaylistOperationMessage {
+        let mut op = PlaylistOperationMessage::new();
+        op.set_kind(PlaylistOperationKind::MOV);
+        let mov = op.mov.mut_or_insert_default();
+        mov.set_from_index(from_index);
+        mov.set_length(length);
+        mov.set_to_index(to_index);
+        op
+    }
+
+    fn applied(uris_before: &[&str], op: &PlaylistOperationMessage) -> Result<Vec<String>, Error> {
+        let mut content = content(uris_before);
+        apply_operation(&mut content, op)?;
+        Ok(uris(&content).into_iter().map(str::to_owned).collect())
+    }
+
+    #[test]
+    fn adds_items() {
+        let before = ["a", "b", "c"];
+        assert_eq!(
+            applied(&before, &add(None, true, false, &["x", "y"])).unwrap(),
+            ["x", "y", "a", "b", "c"]
+        );
+        assert_eq!(
+            applied(&before, &add(None, false, true, &["x"])).unwrap(),
+            ["a", "b", "c", "x"]
+        );
+        assert_eq!(
+            applied(&before, &add(Some(1), false, false, &["x"])).unwrap(),
+            ["a", "x", "b", "c"]
+        );
+        assert_eq!(
+            applied(&before, &add(Some(3), false, false, &["x"])).unwrap(),
+            ["a", "b", "c", "x"]
+        );
+        assert!(applied(&before, &add(Some(4), false, false, &["x"])).is_err());
+        assert!(applied(&before, &add(Some(-1), false, false, &["x"])).is_err());
+    }
+
+    #[test]
+    fn removes_items() {
+        let before = ["a", "b", "c", "d"];
+        assert_eq!(applied(&before, &rem(1, 2)).unwrap(), ["a", "d"]);
+        assert_eq!(applied(&before, &rem(0, 4)).unwrap(), Vec::<String>::new());
+        assert!(applied(&before, &rem(3, 2)).is_err());
+        assert!(applied(&before, &rem(0, -1)).is_err());
+
+        let mut by_key = rem(0, 0);
+        let rem = by_key.rem.mut_or_insert_default();
+        rem.set_items_as_key(true);
+        rem.items = vec![item("b"), item("d"), item("x")];
+        assert_eq!(applied(&before, &by_key).unwrap(), ["a", "c"]);
+    }
+
+    #[test]
+    fn moves_items() {
+        let before = ["a", "b", "c", "d", "e"];
+        // the destination is counted before the items are taken out
+        assert_eq!(
+            applied(&before, &mov(0, 2, 4)).unwrap(),
+            ["c", "d", "a", "b", "e"]
+        );
+        assert_eq!(
+            applied(&before, &mov(0, 2, 5)).unwrap(),
+            ["c", "d", "e", "a", "b"]
+        );
+        assert_eq!(
+            applied(&before, &mov(3, 1, 0)).unwrap(),
+            ["d", "a", "b", "c", "e"]
+        );
+        assert_eq!(applied(&before, &mov(1, 2, 2)).unwrap(), before);
+        assert!(applied(&before, &mov(4, 2, 0)).is_err());
+        assert!(applied(&before, &mov(0, 1, 6)).is_err());
+    }
+
+    #[test]
+    fn updates_item_attributes() {
+        let mut op = PlaylistOperationMessage::new();
+        op.set_kind(PlaylistOperationKind::UPDATE_ITEM_ATTRIBUTES);
+        let update = op.update_item_attributes.mut_or_insert_default();
+        update.set_index(1);
+        let values = update
+            .new_attributes
+            .mut_or_insert_default()
+            .values
+            .mut_or_insert_default();
+        values.set_added_by("user".to_owned());
+
+        let mut updated = content(&["a", "b"]);
+        updated.contents.mut_or_insert_default().items[1]
+            .attributes
+            .mut_or_insert_default()
+            .set_timestamp(42);
+        apply_operation(&mut updated, &op).unwrap();
+        let attributes = updated.contents.items[1].attributes.get_or_default();
+        assert_eq!(
+            (attributes.added_by(), attributes.timestamp()),
+            ("user", 42)
+        );
+        assert!(!updated.contents.items[0].attributes.has_added_by());
+
+        op.update_item_attributes
+            .mut_or_insert_default()
+            .set_index(2);
+        assert!(apply_operation(&mut updated, &op).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_operations() {
+        assert!(applied(&["a"], &PlaylistOperationMessage::new()).is_err());
+    }
+
+    fn diff(from: u32, to: u32, ops: Vec<PlaylistOperationMessage>) -> PlaylistMessage {
+        let mut msg = PlaylistMessage::new();
+        let diff = msg.diff.mut_or_insert_default();
+        diff.set_from_revision(from.to_be_bytes().to_vec());
+        diff.set_to_revision(to.to_be_bytes().to_vec());
+        diff.ops = ops;
+        msg
+    }
+
+    #[test]
+    fn applies_a_diff_to_a_copy() {
+        let content = content(&["a", "b", "c"]);
+        let updated = apply_diff(
+            &content,
+            &diff(1, 2, vec![rem(0, 1), add(None, false, true, &["d"])]),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(uris(&updated), ["b", "c", "d"]);
+        assert_eq!(updated.revision(), 2u32.to_be_bytes());
+        assert_eq!(updated.length(), 3);
+
+        // an operation that fails leaves the playlist as it was
+        let e = apply_diff(&content, &diff(1, 2, vec![rem(0, 1), rem(5, 1)])).unwrap_err();
+        assert!(!is_revision_conflict(&e));
+        assert_eq!(uris(&content), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn checks_the_revisions_of_a_diff() {
+        let content = content(&["a"]);
+
+        let mut up_to_date = PlaylistMessage::new();
+        up_to_date.set_up_to_date(true);
+        assert!(apply_diff(&content, &up_to_date).unwrap().is_none());
+        assert!(apply_diff(&content, &diff(1, 1, vec![rem(0, 1)]))
+            .unwrap()
+            .is_none());
+
+        assert!(is_revision_conflict(
+            &apply_diff(&content, &diff(2, 3, Vec::new())).unwrap_err()
+        ));
+        // the counter of the revisions went back
+        let mut content = content;
+        content.set_revision(5u32.to_be_bytes().to_vec());
+        assert!(is_revision_conflict(
+            &apply_diff(&content, &diff(5, 4, Vec::new())).unwrap_err()
+        ));
+        assert!(apply_diff(&content, &PlaylistMessage::new()).is_err());
+    }
+}
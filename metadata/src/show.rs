@@ -8,7 +8,7 @@ use crate::{
     restriction::Restrictions, Metadata, RequestResult,
 };
 
-use librespot_core::{Error, Session, SpotifyId};
+use librespot_core::{spotify_id::ShowId, Error, Session, SpotifyId};
 
 use librespot_protocol as protocol;
 pub use protocol::metadata::show::ConsumptionOrder as ShowConsumptionOrder;
@@ -38,6 +38,7 @@ pub struct Show {
 #[async_trait]
 impl Metadata for Show {
     type Message = protocol::metadata::Show;
+    type Id = ShowId;
 
     async fn request(session: &Session, show_id: &SpotifyId) -> RequestResult {
         session.spclient().get_show_metadata(show_id).await
@@ -58,7 +59,7 @@ impl TryFrom<&<Self as Metadata>::Message> for Show {
             publisher: show.publisher().to_owned(),
             language: show.language().to_owned(),
             is_explicit: show.explicit(),
-            covers: show.cover_image.image.as_slice().into(),
+            covers: show.cover_image.image.as_slice().try_into()?,
             episodes: show.episode.as_slice().try_into()?,
             copyrights: show.copyright.as_slice().into(),
             restrictions: show.restriction.as_slice().into(),
@@ -18,7 +18,7 @@ use crate::{
     Album, Metadata, RequestResult,
 };
 
-use librespot_core::{date::Date, Error, Session, SpotifyId};
+use librespot_core::{date::Date, spotify_id::TrackId, Error, Session, SpotifyId};
 use librespot_protocol as protocol;
 
 #[derive(Debug, Clone)]
@@ -58,6 +58,7 @@ impl_deref_wrapped!(Tracks, Vec<SpotifyId>);
 #[async_trait]
 impl Metadata for Track {
     type Message = protocol::metadata::Track;
+    type Id = TrackId;
 
     async fn request(session: &Session, track_id: &SpotifyId) -> RequestResult {
         session.spclient().get_track_metadata(track_id).await
@@ -83,10 +84,10 @@ impl TryFrom<&<Self as Metadata>::Message> for Track {
             is_explicit: track.explicit(),
             external_ids: track.external_id.as_slice().into(),
             restrictions: track.restriction.as_slice().into(),
-            files: track.file.as_slice().into(),
+            files: track.file.as_slice().try_into()?,
             alternatives: track.alternative.as_slice().try_into()?,
             sale_periods: track.sale_period.as_slice().try_into()?,
-            previews: track.preview.as_slice().into(),
+            previews: track.preview.as_slice().try_into()?,
             tags: track.tags.to_vec(),
             earliest_live_timestamp: Date::from_timestamp_ms(track.earliest_live_timestamp())?,
             has_lyrics: track.has_lyrics(),
@@ -0,0 +1,162 @@
+use bytes::Bytes;
+
+use librespot_core::{spotify_id::SpotifyUser, Error, Session, SpotifyId};
+
+// As many public playlists as the profile page of the desktop client shows.
+const DEFAULT_PLAYLIST_LIMIT: u32 = 50;
+
+impl UserProfile {
+    /// Fetches the public profile of any user, with the first 50 public playlists.
+    pub async fn get(session: &Session, user: &SpotifyUser) -> Result<Self, Error> {
+        Self::get_with_playlist_limit(session, user, DEFAULT_PLAYLIST_LIMIT).await
+    }
+
+    /// Like [`get`](Self::get), with at most `playlist_limit` public playlists. See
+    /// [`total_public_playlists_count`](Self::total_public_playlists_count) for how many
+    /// there are.
+    pub async fn get_with_playlist_limit(
+        session: &Session,
+        user: &SpotifyUser,
+        playlist_limit: u32,
+    ) -> Result<Self, Error> {
+        let spclient = session.spclient();
+        let profile = spclient
+            .get_user_profile(&user.username, Some(playlist_limit), Some(0))
+            .await?;
+        Self::try_from(&profile)
+    }
+
+    /// The users that follow `user`.
+    pub async fn followers(
+        session: &Session,
+        user: &SpotifyUser,
+    ) -> Result<Vec<UserSummary>, Error> {
+        let spclient = session.spclient();
+        let followers = spclient.get_user_followers(&user.username).await?;
+        Ok(UserList::try_from(&followers)?.profiles)
+    }
+
+    /// The users that `user` follows.
+    pub async fn following(
+        session: &Session,
+        user: &SpotifyUser,
+    ) -> Result<Vec<UserSummary>, Error> {
+        let spclient = session.spclient();
+        let following = spclient.get_user_following(&user.username).await?;
+        Ok(UserList::try_from(&following)?.profiles)
+    }
+}
+
+impl TryFrom<&Bytes> for UserProfile {
+    type Error = Error;
+
+    fn try_from(profile: &Bytes) -> Result<Self, Self::Error> {
+        serde_json::from_slice(profile).map_err(|err| err.into())
+    }
+}
+
+impl TryFrom<&Bytes> for UserList {
+    type Error = Error;
+
+    fn try_from(list: &Bytes) -> Result<Self, Self::Error> {
+        serde_json::from_slice(list).map_err(|err| err.into())
+    }
+}
+
+impl PublicPlaylist {
+    pub fn id(&self) -> Result<SpotifyId, Error> {
+        SpotifyId::from_uri(&self.uri)
+    }
+}
+
+/// The public profile of a user, as shown on their profile page.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct UserProfile {
+    pub uri: String,
+    pub name: String,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub followers_count: u64,
+    #[serde(default)]
+    pub following_count: u64,
+    #[serde(default)]
+    pub public_playlists: Vec<PublicPlaylist>,
+    #[serde(default)]
+    pub total_public_playlists_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct PublicPlaylist {
+    pub uri: String,
+    pub name: String,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub followers_count: u64,
+    pub owner_name: Option<String>,
+    pub owner_uri: Option<String>,
+}
+
+/// A user in the followers or following of another user.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct UserSummary {
+    pub uri: String,
+    pub name: String,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub followers_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+struct UserList {
+    #[serde(default)]
+    profiles: Vec<UserSummary>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profile() {
+        let profile = Bytes::from_static(
+            br#"{
+                "uri": "spotify:user:someone",
+                "name": "Someone",
+                "image_url": "https://i.scdn.co/image/ab67757000003b82",
+                "followers_count": 12,
+                "following_count": 3,
+                "public_playlists": [{
+                    "uri": "spotify:playlist:37i9dQZF1DWSw8liJZcPOI",
+                    "name": "Mix",
+                    "image_url": "https://mosaic.scdn.co/640/ab67616d0000b273",
+                    "followers_count": 1,
+                    "owner_name": "Someone",
+                    "owner_uri": "spotify:user:someone"
+                }],
+                "total_public_playlists_count": 7,
+                "is_following": false,
+                "color": 1234
+            }"#,
+        );
+
+        let profile = UserProfile::try_from(&profile).unwrap();
+        assert_eq!(profile.name, "Someone");
+        assert_eq!(profile.followers_count, 12);
+        assert_eq!(profile.total_public_playlists_count, 7);
+        assert_eq!(
+            profile.public_playlists[0]
+                .id()
+                .unwrap()
+                .to_base62()
+                .unwrap(),
+            "37i9dQZF1DWSw8liJZcPOI"
+        );
+
+        let followers = Bytes::from_static(
+            br#"{"profiles": [{"uri": "spotify:user:other", "name": "Other"}]}"#,
+        );
+        let followers = UserList::try_from(&followers).unwrap().profiles;
+        assert_eq!(followers[0].name, "Other");
+        assert_eq!(followers[0].image_url, None);
+    }
+}
@@ -1,9 +1,10 @@
 use std::{
+    convert::TryFrom,
     fmt::Debug,
     ops::{Deref, DerefMut},
 };
 
-use crate::util::{impl_deref_wrapped, impl_from_repeated};
+use crate::util::{impl_deref_wrapped, impl_try_from_repeated};
 
 use librespot_core::FileId;
 
@@ -15,4 +16,4 @@ pub struct VideoFiles(pub Vec<FileId>);
 
 impl_deref_wrapped!(VideoFiles, Vec<FileId>);
 
-impl_from_repeated!(VideoFileMessage, VideoFiles);
+impl_try_from_repeated!(VideoFileMessage, VideoFiles);
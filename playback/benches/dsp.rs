@@ -0,0 +1,103 @@
+//! Benchmarks of the per-sample work done for every packet: normalisation and conversion to
+//! the sample format of the sink. On slow devices these decide whether playback keeps up.
+//!
+//! Run with `cargo bench -p librespot-playback --features bench`. To compare a change
+//! against the code before it, save a baseline first with `-- --save-baseline before`, then
+//! run again with `-- --baseline before`.
+
+use std::f64::consts::PI;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use librespot_playback::{
+    config::{NormalisationMethod, PlayerConfig},
+    convert::Converter,
+    dither::{Ditherer, DithererBuilder, TriangularDitherer},
+    normaliser::Normaliser,
+    SAMPLE_RATE,
+};
+
+// the size of a decoded packet of interleaved stereo samples
+const PACKET_SIZE: usize = 4096;
+
+fn packet() -> Vec<f64> {
+    (0..PACKET_SIZE)
+        .map(|i| {
+            let frame = (i / 2) as f64;
+            0.9 * (2.0 * PI * 440.0 * frame / SAMPLE_RATE as f64).sin()
+        })
+        .collect()
+}
+
+fn tpdf() -> Box<dyn Ditherer> {
+    Box::new(TriangularDitherer::with_seed(0x5eed))
+}
+
+fn normalisation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("normalisation");
+    group.throughput(Throughput::Elements(PACKET_SIZE as u64));
+
+    let configs = [
+        ("volume", PlayerConfig::default()),
+        (
+            "basic",
+            PlayerConfig {
+                normalisation: true,
+                normalisation_method: NormalisationMethod::Basic,
+                ..PlayerConfig::default()
+            },
+        ),
+        (
+            "dynamic",
+            PlayerConfig {
+                normalisation: true,
+                normalisation_method: NormalisationMethod::Dynamic,
+                ..PlayerConfig::default()
+            },
+        ),
+    ];
+
+    let packet = packet();
+    for (name, config) in configs {
+        let mut normaliser = Normaliser::new();
+        group.bench_function(name, |b| {
+            b.iter_batched_ref(
+                || packet.clone(),
+                |data| normaliser.process(&config, data, black_box(0.8), black_box(0.5)),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+fn conversion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("conversion");
+    group.throughput(Throughput::Elements(PACKET_SIZE as u64));
+
+    let packet = packet();
+    for (dither, ditherer) in [("", None), ("/tpdf", Some(tpdf as DithererBuilder))] {
+        let mut converter = Converter::new(ditherer);
+        group.bench_function(format!("f32{dither}"), |b| {
+            b.iter(|| converter.f64_to_f32(black_box(&packet)))
+        });
+        group.bench_function(format!("s32{dither}"), |b| {
+            b.iter(|| converter.f64_to_s32(black_box(&packet)))
+        });
+        group.bench_function(format!("s24{dither}"), |b| {
+            b.iter(|| converter.f64_to_s24(black_box(&packet)))
+        });
+        group.bench_function(format!("s24_3{dither}"), |b| {
+            b.iter(|| converter.f64_to_s24_3(black_box(&packet)))
+        });
+        group.bench_function(format!("s16{dither}"), |b| {
+            b.iter(|| converter.f64_to_s16(black_box(&packet)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, normalisation, conversion);
+criterion_main!(benches);
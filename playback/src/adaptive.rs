@@ -0,0 +1,181 @@
+//! Lowers the bitrate of the tracks that are loaded next when the network can't keep up with
+//! it, and raises it again up to the configured bitrate when it can, see
+//! [`PlayerConfig::adaptive_bitrate`].
+//!
+//! [`PlayerConfig::adaptive_bitrate`]: crate::config::PlayerConfig::adaptive_bitrate
+
+use crate::{config::Bitrate, metadata::audio::AudioFileFormat, resolve::stream_data_rate};
+
+// how much faster than the data rate the throughput must be to keep a bitrate, and to switch
+// to a higher one
+const KEEP_HEADROOM: f64 = 1.5;
+const RAISE_HEADROOM: f64 = 3.0;
+
+// how many tracks in a row must have been downloaded fast enough to raise the bitrate
+const RAISE_AFTER_TRACKS: u32 = 3;
+
+// the weight of the throughput of the last track in the estimate
+const THROUGHPUT_WEIGHT: f64 = 0.5;
+
+fn data_rate(bitrate: Bitrate) -> f64 {
+    let format = match bitrate {
+        Bitrate::Bitrate96 => AudioFileFormat::OGG_VORBIS_96,
+        Bitrate::Bitrate160 => AudioFileFormat::OGG_VORBIS_160,
+        Bitrate::Bitrate320 => AudioFileFormat::OGG_VORBIS_320,
+    };
+    stream_data_rate(format) as f64
+}
+
+fn lower(bitrate: Bitrate) -> Option<Bitrate> {
+    match bitrate {
+        Bitrate::Bitrate96 => None,
+        Bitrate::Bitrate160 => Some(Bitrate::Bitrate96),
+        Bitrate::Bitrate320 => Some(Bitrate::Bitrate160),
+    }
+}
+
+fn higher(bitrate: Bitrate) -> Option<Bitrate> {
+    match bitrate {
+        Bitrate::Bitrate96 => Some(Bitrate::Bitrate160),
+        Bitrate::Bitrate160 => Some(Bitrate::Bitrate320),
+        Bitrate::Bitrate320 => None,
+    }
+}
+
+/// Picks the bitrate from the download throughput of the tracks that were played.
+#[derive(Debug, Clone)]
+pub struct BitrateAdapter {
+    max: Bitrate,
+    bitrate: Bitrate,
+    // in bytes per second, weighted towards the last tracks
+    throughput: Option<f64>,
+    fast_tracks: u32,
+}
+
+impl BitrateAdapter {
+    /// Starts at `max`, the highest bitrate that is played.
+    pub fn new(max: Bitrate) -> Self {
+        Self {
+            max,
+            bitrate: max,
+            throughput: None,
+            fast_tracks: 0,
+        }
+    }
+
+    pub fn bitrate(&self) -> Bitrate {
+        self.bitrate
+    }
+
+    /// The estimated download throughput in bytes per second.
+    pub fn throughput(&self) -> Option<usize> {
+        self.throughput.map(|throughput| throughput as usize)
+    }
+
+    /// Takes a played track into account, which was downloaded at `throughput` in bytes per
+    /// second, or cached if it is `None`, and had to wait for data `underruns` times. Returns
+    /// the new bitrate if it changed.
+    pub fn update(&mut self, throughput: Option<usize>, underruns: usize) -> Option<Bitrate> {
+        // cached tracks tell nothing about the network
+        let throughput = throughput? as f64;
+        let estimate = match self.throughput {
+            Some(estimate) => estimate * (1.0 - THROUGHPUT_WEIGHT) + throughput * THROUGHPUT_WEIGHT,
+            None => throughput,
+        };
+        self.throughput = Some(estimate);
+
+        // A single slow track could be a hiccup, unless playback had to wait for it.
+        let too_slow = estimate < data_rate(self.bitrate) * KEEP_HEADROOM
+            || (underruns > 0 && throughput < data_rate(self.bitrate) * KEEP_HEADROOM);
+        if too_slow {
+            self.fast_tracks = 0;
+            let lower = lower(self.bitrate)?;
+            return Some(self.switch_to(lower));
+        }
+
+        match higher(self.bitrate).filter(|&higher| higher <= self.max) {
+            Some(higher) if underruns == 0 && estimate >= data_rate(higher) * RAISE_HEADROOM => {
+                self.fast_tracks += 1;
+                if self.fast_tracks >= RAISE_AFTER_TRACKS {
+                    return Some(self.switch_to(higher));
+                }
+            }
+            _ => self.fast_tracks = 0,
+        }
+
+        None
+    }
+
+    fn switch_to(&mut self, bitrate: Bitrate) -> Bitrate {
+        self.bitrate = bitrate;
+        self.fast_tracks = 0;
+        bitrate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // enough to raise the bitrate to 320 kbit/s
+    const FAST: usize = 1_000_000;
+
+    #[test]
+    fn ignores_cached_tracks() {
+        let mut adapter = BitrateAdapter::new(Bitrate::Bitrate320);
+        assert_eq!(adapter.update(None, 5), None);
+        assert_eq!(adapter.throughput(), None);
+        assert_eq!(adapter.bitrate(), Bitrate::Bitrate320);
+    }
+
+    #[test]
+    fn lowers_the_bitrate_down_to_the_lowest() {
+        let mut adapter = BitrateAdapter::new(Bitrate::Bitrate320);
+        assert_eq!(adapter.update(Some(20_000), 0), Some(Bitrate::Bitrate160));
+        assert_eq!(adapter.update(Some(20_000), 0), Some(Bitrate::Bitrate96));
+        assert_eq!(adapter.update(Some(1_000), 3), None);
+        assert_eq!(adapter.bitrate(), Bitrate::Bitrate96);
+    }
+
+    #[test]
+    fn weighs_the_throughput_of_the_last_tracks() {
+        let mut adapter = BitrateAdapter::new(Bitrate::Bitrate320);
+        adapter.update(Some(200_000), 0);
+        assert_eq!(adapter.throughput(), Some(200_000));
+
+        // a single slow track is a hiccup ...
+        assert_eq!(adapter.update(Some(30_000), 0), None);
+        assert_eq!(adapter.throughput(), Some(115_000));
+        // ... unless playback had to wait for it
+        assert_eq!(adapter.update(Some(30_000), 1), Some(Bitrate::Bitrate160));
+        assert_eq!(adapter.throughput(), Some(72_500));
+    }
+
+    #[test]
+    fn raises_the_bitrate_after_a_few_fast_tracks() {
+        let mut adapter = BitrateAdapter::new(Bitrate::Bitrate320);
+        adapter.update(Some(10_000), 0);
+        adapter.update(Some(10_000), 0);
+        assert_eq!(adapter.bitrate(), Bitrate::Bitrate96);
+
+        assert_eq!(adapter.update(Some(FAST), 0), None);
+        assert_eq!(adapter.update(Some(FAST), 0), None);
+        assert_eq!(adapter.update(Some(FAST), 0), Some(Bitrate::Bitrate160));
+
+        // an underrun starts over
+        assert_eq!(adapter.update(Some(FAST), 0), None);
+        assert_eq!(adapter.update(Some(FAST), 1), None);
+        assert_eq!(adapter.update(Some(FAST), 0), None);
+        assert_eq!(adapter.update(Some(FAST), 0), None);
+        assert_eq!(adapter.update(Some(FAST), 0), Some(Bitrate::Bitrate320));
+    }
+
+    #[test]
+    fn stays_at_the_configured_bitrate() {
+        let mut adapter = BitrateAdapter::new(Bitrate::Bitrate160);
+        for _ in 0..10 {
+            assert_eq!(adapter.update(Some(FAST), 0), None);
+        }
+        assert_eq!(adapter.bitrate(), Bitrate::Bitrate160);
+    }
+}
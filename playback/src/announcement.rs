@@ -0,0 +1,323 @@
+//! Announcements, like a doorbell or the response of a voice assistant, played over or
+//! instead of the music, see [`Player::announce`](crate::player::Player::announce).
+
+use std::time::Duration;
+
+use crate::{resampler::Resampler, NUM_CHANNELS};
+
+const CHANNELS: usize = NUM_CHANNELS as usize;
+
+/// What happens to the music while an announcement plays.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ducking {
+    /// Plays the announcement over the music, which is lowered to this attenuation factor,
+    /// from `0.0` to `1.0`.
+    Duck(f64),
+    /// Holds the music during the announcement, and continues it where it was afterwards.
+    Pause,
+}
+
+/// Audio to play over or instead of the music.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    /// Interleaved stereo samples, from `-1.0` to `1.0`.
+    pub samples: Vec<f64>,
+    pub sample_rate: u32,
+    pub ducking: Ducking,
+    /// How long the music takes to fade out before the announcement, and back in after it.
+    pub crossfade: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    FadeOut,
+    Announce,
+    FadeIn,
+    Finished,
+}
+
+/// Mixes an announcement into the music, fading the music out before and back in after it.
+pub struct AnnouncementMixer {
+    // at the sample rate of the sink
+    samples: Vec<f64>,
+    position: usize,
+    // the loudest sample, which the music leaves room for so that the mix doesn't clip
+    peak: f64,
+    pauses_music: bool,
+    // the gain of the music when the current fade started, and during the announcement
+    start_gain: f64,
+    ducked_gain: f64,
+    gain: f64,
+    fade_frames: usize,
+    phase: Phase,
+    phase_frames: usize,
+}
+
+impl AnnouncementMixer {
+    /// Prepares `announcement` for a sink playing at `sample_rate`. The music fades out from
+    /// `gain`, which is below `1.0` when the announcement replaces one that was still playing.
+    pub fn new(announcement: Announcement, sample_rate: u32, gain: f64) -> Self {
+        let mut samples = if announcement.sample_rate == sample_rate {
+            announcement.samples
+        } else {
+            Resampler::new(announcement.sample_rate, sample_rate).resample(&announcement.samples)
+        };
+        samples.truncate(samples.len() - samples.len() % CHANNELS);
+        let peak = samples
+            .iter()
+            .fold(0.0_f64, |peak, sample| peak.max(sample.abs()));
+
+        let (ducked_gain, pauses_music) = match announcement.ducking {
+            Ducking::Duck(level) => (level.clamp(0.0, 1.0), false),
+            Ducking::Pause => (0.0, true),
+        };
+
+        Self {
+            samples,
+            position: 0,
+            peak,
+            pauses_music,
+            start_gain: gain,
+            ducked_gain,
+            gain,
+            fade_frames: (announcement.crossfade.as_secs_f64() * sample_rate as f64) as usize,
+            phase: Phase::FadeOut,
+            phase_frames: 0,
+        }
+    }
+
+    /// The gain of the music now.
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    /// Whether the music is held now, so that the announcement is played on its own with
+    /// [`AnnouncementMixer::render`].
+    pub fn pauses_music(&self) -> bool {
+        self.pauses_music && self.phase == Phase::Announce
+    }
+
+    /// Whether the announcement was played, so that only the music may be fading back in.
+    pub fn is_announced(&self) -> bool {
+        matches!(self.phase, Phase::FadeIn | Phase::Finished)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.phase == Phase::Finished
+    }
+
+    // The gain of the music during the announcement at `volume`, which is lowered further
+    // than asked if needed so that the music and the announcement add up to `1.0` at most.
+    fn ducked_gain(&self, volume: f64) -> f64 {
+        self.ducked_gain.min((1.0 - self.peak * volume).max(0.0))
+    }
+
+    // Moves on by a frame. Returns whether the announcement is playing at this frame.
+    fn advance(&mut self, volume: f64) -> bool {
+        if self.phase == Phase::FadeOut && self.phase_frames >= self.fade_frames {
+            self.enter(Phase::Announce);
+        }
+        if self.phase == Phase::Announce && self.position >= self.samples.len() {
+            self.enter(Phase::FadeIn);
+        }
+        if self.phase == Phase::FadeIn && self.phase_frames >= self.fade_frames {
+            self.enter(Phase::Finished);
+        }
+
+        let progress = if self.fade_frames > 0 {
+            self.phase_frames as f64 / self.fade_frames as f64
+        } else {
+            1.0
+        };
+        let ducked_gain = self.ducked_gain(volume);
+        self.gain = match self.phase {
+            Phase::FadeOut => self.start_gain + (ducked_gain - self.start_gain) * progress,
+            Phase::Announce => ducked_gain,
+            Phase::FadeIn => self.start_gain + (1.0 - self.start_gain) * progress,
+            Phase::Finished => 1.0,
+        };
+        self.phase_frames += 1;
+
+        self.phase == Phase::Announce
+    }
+
+    fn enter(&mut self, phase: Phase) {
+        if phase == Phase::FadeIn {
+            self.start_gain = self.gain;
+        }
+        self.phase = phase;
+        self.phase_frames = 0;
+    }
+
+    fn next_frame(&mut self) -> &[f64] {
+        let end = (self.position + CHANNELS).min(self.samples.len());
+        let frame = &self.samples[self.position..end];
+        self.position = end;
+        frame
+    }
+
+    /// Attenuates `music` as it fades, and adds the announcement to it at `volume`. The music
+    /// is ducked enough for the sum not to clip.
+    pub fn mix(&mut self, music: &mut [f64], volume: f64) {
+        for frame in music.chunks_exact_mut(CHANNELS) {
+            let announcing = self.advance(volume);
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+            if announcing {
+                for (sample, announced) in frame.iter_mut().zip(self.next_frame()) {
+                    *sample += announced * volume;
+                }
+            }
+        }
+    }
+
+    /// Returns up to `frames` frames of the announcement at `volume`, to be played while
+    /// there is no music to mix it into. That skips the fade out, and leaves the music to
+    /// fade back in when the announcement is over.
+    pub fn render(&mut self, frames: usize, volume: f64) -> Vec<f64> {
+        if self.phase == Phase::FadeOut {
+            self.enter(Phase::Announce);
+        }
+
+        let mut rendered = Vec::with_capacity(frames * CHANNELS);
+        while rendered.len() < frames * CHANNELS && self.phase == Phase::Announce {
+            if self.position >= self.samples.len() {
+                self.enter(Phase::FadeIn);
+                break;
+            }
+            self.gain = self.ducked_gain(volume);
+            rendered.extend(self.next_frame().iter().map(|sample| sample * volume));
+        }
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 10;
+
+    // An announcement of `frames` frames at `level`, with a crossfade of 10 frames.
+    fn announce(frames: usize, level: f64, ducking: Ducking) -> AnnouncementMixer {
+        let announcement = Announcement {
+            samples: vec![level; frames * CHANNELS],
+            sample_rate: RATE,
+            ducking,
+            crossfade: Duration::from_secs(1),
+        };
+        AnnouncementMixer::new(announcement, RATE, 1.0)
+    }
+
+    fn left(samples: &[f64]) -> Vec<f64> {
+        samples.iter().step_by(CHANNELS).copied().collect()
+    }
+
+    fn round(samples: &[f64]) -> Vec<f64> {
+        samples
+            .iter()
+            .map(|sample| (sample * 1000.0).round() / 1000.0)
+            .collect()
+    }
+
+    #[test]
+    fn ducks_the_music_during_the_announcement() {
+        let mut mixer = announce(3, 0.2, Ducking::Duck(0.5));
+        let mut music = vec![0.4; 30 * CHANNELS];
+        mixer.mix(&mut music, 1.0);
+
+        let music = round(&left(&music));
+        // fades out from 1.0 to 0.5 ...
+        assert_eq!(music[..3], [0.4, 0.38, 0.36]);
+        assert_eq!(music[9], 0.22);
+        // ... plays the announcement over it ...
+        assert_eq!(music[10..13], [0.4, 0.4, 0.4]);
+        // ... and fades back in
+        assert_eq!(music[13..15], [0.2, 0.22]);
+        assert_eq!(music[23..], [0.4; 7]);
+        assert!(mixer.is_announced());
+        assert!(mixer.is_finished());
+        assert_eq!(mixer.gain(), 1.0);
+    }
+
+    #[test]
+    fn leaves_room_for_the_announcement() {
+        let mut mixer = announce(5, 0.8, Ducking::Duck(0.9));
+        let mut music = vec![1.0; 30 * CHANNELS];
+        mixer.mix(&mut music, 1.0);
+
+        assert!(music.iter().all(|sample| *sample <= 1.0 + 1e-9));
+        // the music is lowered to 0.2 rather than 0.9 while the announcement plays
+        assert_eq!(round(&left(&music))[10..15], [1.0; 5]);
+        assert_eq!(round(&[mixer.gain()]), [1.0]);
+
+        // a quieter announcement needs less room
+        let mut mixer = announce(5, 0.8, Ducking::Duck(0.9));
+        let mut music = vec![1.0; 15 * CHANNELS];
+        mixer.mix(&mut music, 0.1);
+        assert_eq!(round(&left(&music))[10..15], [0.9 + 0.08; 5]);
+    }
+
+    #[test]
+    fn fades_in_from_where_it_was_ducked_to() {
+        let mut mixer = announce(2, 1.0, Ducking::Duck(0.8));
+        let mut music = vec![0.5; 13 * CHANNELS];
+        mixer.mix(&mut music, 0.5);
+
+        // the announcement only leaves room for half of the music
+        assert_eq!(round(&left(&music))[10..12], [0.75, 0.75]);
+        assert_eq!(round(&[music[24], mixer.gain()]), [0.25, 0.5]);
+    }
+
+    #[test]
+    fn holds_the_music_for_the_announcement() {
+        let mut mixer = announce(5, 0.5, Ducking::Pause);
+        assert!(!mixer.pauses_music());
+
+        let mut music = vec![1.0; 10 * CHANNELS];
+        mixer.mix(&mut music, 1.0);
+        assert_eq!(round(&left(&music))[9], 0.1);
+
+        let mut music = vec![1.0; CHANNELS];
+        mixer.mix(&mut music, 1.0);
+        assert!(mixer.pauses_music());
+        assert_eq!(music, [0.5; CHANNELS]);
+
+        assert_eq!(mixer.render(3, 0.5), [0.25; 3 * CHANNELS]);
+        assert_eq!(mixer.render(3, 0.5), [0.25; CHANNELS]);
+        assert!(!mixer.pauses_music());
+        assert!(mixer.is_announced());
+        assert!(!mixer.is_finished());
+        assert!(mixer.render(3, 0.5).is_empty());
+    }
+
+    #[test]
+    fn renders_without_a_fade_out() {
+        let mut mixer = announce(4, 0.5, Ducking::Duck(0.3));
+        assert_eq!(mixer.render(10, 1.0), [0.5; 4 * CHANNELS]);
+        assert!(mixer.render(10, 1.0).is_empty());
+        assert!(mixer.is_announced());
+
+        let mut music = vec![1.0; 11 * CHANNELS];
+        mixer.mix(&mut music, 1.0);
+        assert_eq!(round(&left(&music))[..2], [0.3, 0.37]);
+        assert!(mixer.is_finished());
+    }
+
+    #[test]
+    fn resamples_the_announcement() {
+        let announcement = Announcement {
+            samples: vec![0.5; 100 * CHANNELS + 1],
+            sample_rate: RATE,
+            ducking: Ducking::Pause,
+            crossfade: Duration::ZERO,
+        };
+        let mut mixer = AnnouncementMixer::new(announcement, 2 * RATE, 1.0);
+
+        let rendered = mixer.render(1000, 1.0);
+        assert_eq!(rendered.len() % CHANNELS, 0);
+        assert!((196..=200).contains(&(rendered.len() / CHANNELS)));
+        assert!(rendered.iter().all(|sample| (sample - 0.5).abs() < 1e-9));
+    }
+}
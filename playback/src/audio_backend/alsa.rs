@@ -98,6 +98,7 @@ impl From<AudioFormat> for Format {
 pub struct AlsaSink {
     pcm: Option<PCM>,
     format: AudioFormat,
+    sample_rate: u32,
     device: String,
     period_buffer: Vec<u8>,
 }
@@ -162,7 +163,7 @@ fn list_compatible_devices() -> SinkResult<()> {
     Ok(())
 }
 
-fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)> {
+fn open_device(dev_name: &str, format: AudioFormat, sample_rate: u32) -> SinkResult<(PCM, usize)> {
     let pcm = PCM::new(dev_name, Direction::Playback, false).map_err(|e| AlsaError::PcmSetUp {
         device: dev_name.to_string(),
         e,
@@ -187,10 +188,10 @@ fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)>
                 e,
             })?;
 
-        hwp.set_rate(SAMPLE_RATE, ValueOr::Nearest).map_err(|e| {
+        hwp.set_rate(sample_rate, ValueOr::Nearest).map_err(|e| {
             AlsaError::UnsupportedSampleRate {
                 device: dev_name.to_string(),
-                samplerate: SAMPLE_RATE,
+                samplerate: sample_rate,
                 e,
             }
         })?;
@@ -415,6 +416,7 @@ impl Open for AlsaSink {
         Self {
             pcm: None,
             format,
+            sample_rate: SAMPLE_RATE,
             device: name,
             period_buffer: vec![],
         }
@@ -424,7 +426,7 @@ impl Open for AlsaSink {
 impl Sink for AlsaSink {
     fn start(&mut self) -> SinkResult<()> {
         if self.pcm.is_none() {
-            let (pcm, bytes_per_period) = open_device(&self.device, self.format)?;
+            let (pcm, bytes_per_period) = open_device(&self.device, self.format, self.sample_rate)?;
             self.pcm = Some(pcm);
 
             if self.period_buffer.capacity() != bytes_per_period {
@@ -456,6 +458,44 @@ impl Sink for AlsaSink {
         Ok(())
     }
 
+    fn set_sample_rate(&mut self, sample_rate: u32) -> SinkResult<bool> {
+        if sample_rate == self.sample_rate {
+            return Ok(true);
+        }
+
+        // The rate can only be changed by reopening the device, which also tells whether
+        // the device supports it.
+        let was_open = self.pcm.is_some();
+        self.stop()?;
+
+        let previous_rate = std::mem::replace(&mut self.sample_rate, sample_rate);
+        let switched = match self.start() {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    "Unable to switch <AlsaSink> to a sample rate of {}: {}",
+                    sample_rate, e
+                );
+                self.sample_rate = previous_rate;
+                false
+            }
+        };
+
+        if was_open {
+            self.start()?;
+        } else {
+            self.stop()?;
+        }
+        Ok(switched)
+    }
+
+    fn buffered_frames(&self) -> Option<usize> {
+        let pcm = self.pcm.as_ref()?;
+        let delay = pcm.delay().ok()?.max(0) as usize;
+        let bytes_per_frame = self.format.size() * NUM_CHANNELS as usize;
+        Some(delay + self.period_buffer.len() / bytes_per_frame)
+    }
+
     sink_as_bytes!();
 }
 
@@ -0,0 +1,613 @@
+use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
+use crate::config::AudioFormat;
+use crate::convert::Converter;
+use crate::decoder::AudioPacket;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+use std::process::exit;
+use thiserror::Error;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::{fs::FileExt, net::UnixStream};
+
+// The sink writes frames of a one byte type and a little-endian u32 payload length,
+// followed by the payload:
+//
+// FORMAT  u32 sample rate, u8 channels, u8 encoding (see `Encoding`). Sent before the
+//         first data and whenever the encoding changes, e.g. for passthrough.
+// DATA    The audio data. In memfd mode: u64 offset and u32 length of the data in the
+//         memfd, which is a ring buffer that is written to from the start again at its end.
+// STOP    Playback stopped, no data follows until the next FORMAT.
+// MEMFD   Only in memfd mode, sent once with the memfd attached as SCM_RIGHTS: u64 size
+//         of the ring buffer.
+//
+// In memfd mode the consumer writes the number of bytes it has read from the ring so far to
+// the socket, as a little-endian u64, whenever it is done with some data. The sink doesn't
+// write over data that wasn't read, and waits for the consumer when the ring is full.
+const FRAME_FORMAT: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_STOP: u8 = 3;
+#[cfg(target_os = "linux")]
+const FRAME_MEMFD: u8 = 4;
+
+// About 12 seconds of F64 audio at 44.1 kHz.
+#[cfg(target_os = "linux")]
+const MEMFD_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+enum FdError {
+    #[error("<FdSink> {0}")]
+    OnWrite(io::Error),
+
+    #[error("<FdSink> Invalid Device {0}, Expected fd:{{number}} or memfd:{{socket path}}")]
+    InvalidDevice(String),
+
+    #[error("<FdSink> Missing Required Device")]
+    MissingDevice,
+
+    #[error("<FdSink> Failed to Connect to {path}, {e}")]
+    ConnectFailure { path: String, e: io::Error },
+
+    #[error("<FdSink> Failed to Create the memfd, {0}")]
+    MemfdFailure(io::Error),
+
+    #[error("<FdSink> Failed to Hand Off the memfd, {0}")]
+    HandoffFailure(io::Error),
+
+    #[error("<FdSink> Failed to Flush the Output, {0}")]
+    FlushFailure(io::Error),
+
+    #[error("<FdSink> The Output is None")]
+    NoOutput,
+}
+
+impl From<FdError> for SinkError {
+    fn from(e: FdError) -> SinkError {
+        use FdError::*;
+        let es = e.to_string();
+        match e {
+            FlushFailure(_) | OnWrite(_) => SinkError::OnWrite(es),
+            InvalidDevice(_) | MissingDevice => SinkError::InvalidParams(es),
+            ConnectFailure { .. } | MemfdFailure(_) | HandoffFailure(_) => {
+                SinkError::ConnectionRefused(es)
+            }
+            NoOutput => SinkError::NotConnected(es),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Pcm(AudioFormat),
+    // Raw packets of the passthrough decoder.
+    Ogg,
+}
+
+impl Encoding {
+    fn id(self) -> u8 {
+        match self {
+            Self::Pcm(AudioFormat::F64) => 0,
+            Self::Pcm(AudioFormat::F32) => 1,
+            Self::Pcm(AudioFormat::S32) => 2,
+            Self::Pcm(AudioFormat::S24) => 3,
+            Self::Pcm(AudioFormat::S24_3) => 4,
+            Self::Pcm(AudioFormat::S16) => 5,
+            Self::Ogg => 0xff,
+        }
+    }
+}
+
+enum Device {
+    Fd(i32),
+    #[cfg(target_os = "linux")]
+    Memfd(String),
+}
+
+impl Device {
+    fn parse(device: &str) -> Option<Self> {
+        if let Some(fd) = device.strip_prefix("fd:") {
+            return fd.parse().ok().filter(|fd| *fd >= 0).map(Self::Fd);
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(path) = device.strip_prefix("memfd:") {
+            return (!path.is_empty()).then(|| Self::Memfd(path.to_string()));
+        }
+
+        None
+    }
+}
+
+enum Output {
+    Fd(File),
+    #[cfg(target_os = "linux")]
+    Memfd(Ring),
+}
+
+// The memfd, and how far it was written and read, in bytes since the handoff.
+#[cfg(target_os = "linux")]
+struct Ring {
+    socket: UnixStream,
+    memfd: File,
+    size: u64,
+    written: u64,
+    read: u64,
+    // the start of a read position from the consumer that was only partly received
+    partial: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl Ring {
+    fn new(socket: UnixStream, memfd: File, size: u64) -> Self {
+        Self {
+            socket,
+            memfd,
+            size,
+            written: 0,
+            read: 0,
+            partial: Vec::with_capacity(8),
+        }
+    }
+
+    fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            self.receive_read_positions(false)?;
+            while self.written - self.read == self.size {
+                self.receive_read_positions(true)?;
+            }
+
+            let offset = self.written % self.size;
+            let free = self.size - (self.written - self.read);
+            let len = (data.len() as u64).min(free).min(self.size - offset) as usize;
+            self.memfd.write_all_at(&data[..len], offset)?;
+
+            let mut frame = frame_header(FRAME_DATA, 12);
+            frame.extend_from_slice(&offset.to_le_bytes());
+            frame.extend_from_slice(&(len as u32).to_le_bytes());
+            self.socket.write_all(&frame)?;
+
+            self.written += len as u64;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    // Takes in the read positions the consumer sent, and waits for one if `wait`.
+    fn receive_read_positions(&mut self, wait: bool) -> io::Result<()> {
+        let mut buf = [0; 64];
+        let mut wait = wait;
+        loop {
+            let received = match memfd::recv(&self.socket, &mut buf, wait)? {
+                Some(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Some(received) => received,
+                None => return Ok(()),
+            };
+
+            self.partial.extend_from_slice(&buf[..received]);
+            let complete = self.partial.len() / 8 * 8;
+            for position in self.partial[..complete].chunks_exact(8) {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(position);
+                let position = u64::from_le_bytes(bytes);
+                if position < self.read || position > self.written {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid read position {position}"),
+                    ));
+                }
+                self.read = position;
+                wait = false;
+            }
+            self.partial.drain(..complete);
+        }
+    }
+}
+
+impl Output {
+    fn open(device: &Device) -> Result<Self, FdError> {
+        match device {
+            // SAFETY: the fd was passed to us to write to, and is only taken once.
+            Device::Fd(fd) => Ok(Self::Fd(unsafe { File::from_raw_fd(*fd) })),
+            #[cfg(target_os = "linux")]
+            Device::Memfd(path) => {
+                let socket = UnixStream::connect(path).map_err(|e| FdError::ConnectFailure {
+                    path: path.clone(),
+                    e,
+                })?;
+                let memfd = memfd::create("librespot").map_err(FdError::MemfdFailure)?;
+                memfd.set_len(MEMFD_SIZE).map_err(FdError::MemfdFailure)?;
+
+                let mut frame = frame_header(FRAME_MEMFD, 8);
+                frame.extend_from_slice(&MEMFD_SIZE.to_le_bytes());
+                memfd::send_with_fd(&socket, &frame, &memfd).map_err(FdError::HandoffFailure)?;
+
+                Ok(Self::Memfd(Ring::new(socket, memfd, MEMFD_SIZE)))
+            }
+        }
+    }
+
+    fn control(&mut self) -> &mut dyn Write {
+        match self {
+            Self::Fd(file) => file,
+            #[cfg(target_os = "linux")]
+            Self::Memfd(ring) => &mut ring.socket,
+        }
+    }
+
+    fn write_frame(&mut self, frame_type: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = frame_header(frame_type, payload.len());
+        frame.extend_from_slice(payload);
+        self.control().write_all(&frame)
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Fd(_) => self.write_frame(FRAME_DATA, data),
+            #[cfg(target_os = "linux")]
+            Self::Memfd(ring) => ring.write(data),
+        }
+    }
+}
+
+fn frame_header(frame_type: u8, len: usize) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + len);
+    frame.push(frame_type);
+    frame.extend_from_slice(&(len as u32).to_le_bytes());
+    frame
+}
+
+pub struct FdSink {
+    device: Device,
+    output: Option<Output>,
+    encoding: Option<Encoding>,
+    format: AudioFormat,
+    sample_rate: u32,
+}
+
+impl Open for FdSink {
+    fn open(device: Option<String>, format: AudioFormat) -> Self {
+        let device = match device.as_deref() {
+            Some("?") => {
+                println!("\nUsage:\n\nOutput to an inherited file descriptor:\n\n\t--backend fd --device fd:{{number}}\n");
+                #[cfg(target_os = "linux")]
+                println!("Hand off a memfd over a Unix socket:\n\n\t--backend fd --device memfd:{{socket path}}\n");
+                exit(0);
+            }
+            None => {
+                error!("{}", FdError::MissingDevice);
+                exit(1);
+            }
+            Some(device) => Device::parse(device).unwrap_or_else(|| {
+                error!("{}", FdError::InvalidDevice(device.to_string()));
+                exit(1);
+            }),
+        };
+
+        info!("Using FdSink with format: {:?}", format);
+
+        Self {
+            device,
+            output: None,
+            encoding: None,
+            format,
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+}
+
+impl Sink for FdSink {
+    fn start(&mut self) -> SinkResult<()> {
+        // The output stays open while stopped, an inherited fd can't be opened again.
+        if self.output.is_none() {
+            self.output = Some(Output::open(&self.device)?);
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        let output = self.output.as_mut().ok_or(FdError::NoOutput)?;
+        output
+            .write_frame(FRAME_STOP, &[])
+            .map_err(FdError::OnWrite)?;
+        output.control().flush().map_err(FdError::FlushFailure)?;
+        self.encoding = None;
+
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let encoding = match packet {
+            AudioPacket::Samples(_) => Encoding::Pcm(self.format),
+            AudioPacket::Raw(_) => Encoding::Ogg,
+        };
+
+        if self.encoding != Some(encoding) {
+            let mut payload = self.sample_rate.to_le_bytes().to_vec();
+            payload.push(NUM_CHANNELS);
+            payload.push(encoding.id());
+
+            self.output
+                .as_mut()
+                .ok_or(FdError::NoOutput)?
+                .write_frame(FRAME_FORMAT, &payload)
+                .map_err(FdError::OnWrite)?;
+            self.encoding = Some(encoding);
+        }
+
+        self.write_samples(packet, converter)
+    }
+
+    // The consumer is told the rate of the data that follows.
+    fn set_sample_rate(&mut self, sample_rate: u32) -> SinkResult<bool> {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.encoding = None;
+        }
+        Ok(true)
+    }
+}
+
+impl FdSink {
+    pub const NAME: &'static str = "fd";
+
+    fn write_samples(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        use crate::convert::i24;
+        use zerocopy::AsBytes;
+        match packet {
+            AudioPacket::Samples(samples) => match self.format {
+                AudioFormat::F64 => self.write_bytes(samples.as_bytes()),
+                AudioFormat::F32 => {
+                    let samples_f32: &[f32] = &converter.f64_to_f32(&samples);
+                    self.write_bytes(samples_f32.as_bytes())
+                }
+                AudioFormat::S32 => {
+                    let samples_s32: &[i32] = &converter.f64_to_s32(&samples);
+                    self.write_bytes(samples_s32.as_bytes())
+                }
+                AudioFormat::S24 => {
+                    let samples_s24: &[i32] = &converter.f64_to_s24(&samples);
+                    self.write_bytes(samples_s24.as_bytes())
+                }
+                AudioFormat::S24_3 => {
+                    let samples_s24_3: &[i24] = &converter.f64_to_s24_3(&samples);
+                    self.write_bytes(samples_s24_3.as_bytes())
+                }
+                AudioFormat::S16 => {
+                    let samples_s16: &[i16] = &converter.f64_to_s16(&samples);
+                    self.write_bytes(samples_s16.as_bytes())
+                }
+            },
+            AudioPacket::Raw(samples) => self.write_bytes(&samples),
+        }
+    }
+}
+
+impl SinkAsBytes for FdSink {
+    fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        self.output
+            .as_mut()
+            .ok_or(FdError::NoOutput)?
+            .write_data(data)
+            .map_err(FdError::OnWrite)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod memfd {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use std::os::unix::net::UnixStream;
+    use std::ptr;
+
+    pub fn create(name: &str) -> io::Result<File> {
+        let name = CString::new(name)?;
+        // SAFETY: `name` is a valid C string, and the returned fd is owned by the `File`.
+        unsafe {
+            let fd = libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(File::from_raw_fd(fd))
+        }
+    }
+
+    // Receives what is there, or waits for something if `wait`. Returns `None` if there was
+    // nothing to receive.
+    pub fn recv(socket: &UnixStream, buf: &mut [u8], wait: bool) -> io::Result<Option<usize>> {
+        let flags = if wait { 0 } else { libc::MSG_DONTWAIT };
+        loop {
+            // SAFETY: `buf` is valid for writes of its length.
+            let received = unsafe {
+                libc::recv(
+                    socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    flags,
+                )
+            };
+            if received >= 0 {
+                return Ok(Some(received as usize));
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock => return Ok(None),
+                _ => return Err(e),
+            }
+        }
+    }
+
+    // Sends `data` with `file` attached, so the receiving process can map or read it.
+    pub fn send_with_fd(socket: &UnixStream, data: &[u8], file: &File) -> io::Result<()> {
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        // u64 for the alignment of `cmsghdr`.
+        let mut control = [0u64; 4];
+
+        // SAFETY: the buffers outlive the call, and `control` has room for one fd.
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, file.as_raw_fd());
+
+            match libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) {
+                sent if sent < 0 => Err(io::Error::last_os_error()),
+                sent if (sent as usize) < data.len() => Err(io::ErrorKind::WriteZero.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::{
+        io::{FromRawFd, IntoRawFd},
+        net::UnixStream,
+    };
+
+    use super::*;
+
+    fn read_frame(socket: &mut UnixStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 5];
+        socket.read_exact(&mut header).unwrap();
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[1..]);
+        let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+        socket.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    fn format_payload(sample_rate: u32, encoding: Encoding) -> Vec<u8> {
+        let mut payload = sample_rate.to_le_bytes().to_vec();
+        payload.extend_from_slice(&[NUM_CHANNELS, encoding.id()]);
+        payload
+    }
+
+    #[test]
+    fn parses_devices() {
+        assert!(matches!(Device::parse("fd:3"), Some(Device::Fd(3))));
+        assert!(Device::parse("fd:-1").is_none());
+        assert!(Device::parse("fd:").is_none());
+        assert!(Device::parse("3").is_none());
+        #[cfg(target_os = "linux")]
+        {
+            assert!(matches!(
+                Device::parse("memfd:/run/audio.sock"),
+                Some(Device::Memfd(path)) if path == "/run/audio.sock"
+            ));
+            assert!(Device::parse("memfd:").is_none());
+        }
+    }
+
+    #[test]
+    fn writes_frames_at_the_sample_rate() {
+        let (output, mut consumer) = UnixStream::pair().unwrap();
+        // SAFETY: the fd is owned by the stream, which gives it up.
+        let output = unsafe { File::from_raw_fd(output.into_raw_fd()) };
+        let mut sink = FdSink {
+            device: Device::Fd(-1),
+            output: Some(Output::Fd(output)),
+            encoding: None,
+            format: AudioFormat::S16,
+            sample_rate: SAMPLE_RATE,
+        };
+        let mut converter = Converter::new(None);
+        let pcm = Encoding::Pcm(AudioFormat::S16);
+
+        sink.write(AudioPacket::Samples(vec![0.0, 0.5]), &mut converter)
+            .unwrap();
+        sink.write(AudioPacket::Samples(vec![-0.5, 0.0]), &mut converter)
+            .unwrap();
+        assert!(sink.set_sample_rate(48000).unwrap());
+        sink.write(AudioPacket::Raw(vec![1, 2, 3]), &mut converter)
+            .unwrap();
+        sink.stop().unwrap();
+
+        let frames: Vec<_> = (0..6).map(|_| read_frame(&mut consumer)).collect();
+        assert_eq!(frames[0], (FRAME_FORMAT, format_payload(SAMPLE_RATE, pcm)));
+        assert_eq!(frames[1].0, FRAME_DATA);
+        assert_eq!(frames[1].1.len(), 4);
+        assert_eq!(frames[2].0, FRAME_DATA);
+        assert_eq!(
+            frames[3],
+            (FRAME_FORMAT, format_payload(48000, Encoding::Ogg))
+        );
+        assert_eq!(frames[4], (FRAME_DATA, vec![1, 2, 3]));
+        assert_eq!(frames[5], (FRAME_STOP, Vec::new()));
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_data_frame(socket: &mut UnixStream) -> (u64, u32) {
+        let (frame_type, payload) = read_frame(socket);
+        assert_eq!(frame_type, FRAME_DATA);
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&payload[..8]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&payload[8..]);
+        (u64::from_le_bytes(offset), u32::from_le_bytes(len))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn waits_for_the_consumer_when_the_ring_is_full() {
+        use std::{sync::mpsc, thread, time::Duration};
+
+        let (socket, mut consumer) = UnixStream::pair().unwrap();
+        let memfd = memfd::create("librespot-test").unwrap();
+        memfd.set_len(16).unwrap();
+        let mut ring = Ring::new(socket, memfd.try_clone().unwrap(), 16);
+
+        ring.write(&[1; 12]).unwrap();
+        assert_eq!(read_data_frame(&mut consumer), (0, 12));
+
+        // 4 bytes fit before the end, and then the ring is full
+        let (done_tx, done_rx) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            let result = ring.write(&[2; 8]);
+            done_tx.send(()).unwrap();
+            result.map(|()| ring)
+        });
+        assert_eq!(read_data_frame(&mut consumer), (12, 4));
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // half a position first, which isn't enough
+        consumer.write_all(&10u64.to_le_bytes()[..4]).unwrap();
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        consumer.write_all(&10u64.to_le_bytes()[4..]).unwrap();
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(read_data_frame(&mut consumer), (0, 4));
+
+        let mut ring = writer.join().unwrap().unwrap();
+        let mut contents = [0; 16];
+        memfd.read_exact_at(&mut contents, 0).unwrap();
+        assert_eq!(contents[..4], [2; 4]);
+        assert_eq!(contents[4..12], [1; 8]);
+        assert_eq!(contents[12..], [2; 4]);
+
+        // the consumer can't have read more than was written
+        consumer.write_all(&100u64.to_le_bytes()).unwrap();
+        let e = ring.write(&[3]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -22,7 +22,12 @@ pub struct GstreamerSink {
     bufferpool: gst::BufferPool,
     pipeline: gst::Pipeline,
     format: AudioFormat,
+    sample_rate: u32,
     async_error: Arc<Mutex<Option<String>>>,
+    // the time of the last sample rate switch since the sink was started, and the number of
+    // frames pushed since, to timestamp the buffers
+    rate_switched_at: gst::ClockTime,
+    frames_written: u64,
 }
 
 impl Open for GstreamerSink {
@@ -30,19 +35,7 @@ impl Open for GstreamerSink {
         info!("Using GStreamer sink with format: {format:?}");
         gst::init().expect("failed to init GStreamer!");
 
-        let gst_format = match format {
-            AudioFormat::F64 => gst_audio::AUDIO_FORMAT_F64,
-            AudioFormat::F32 => gst_audio::AUDIO_FORMAT_F32,
-            AudioFormat::S32 => gst_audio::AUDIO_FORMAT_S32,
-            AudioFormat::S24 => gst_audio::AUDIO_FORMAT_S2432,
-            AudioFormat::S24_3 => gst_audio::AUDIO_FORMAT_S24,
-            AudioFormat::S16 => gst_audio::AUDIO_FORMAT_S16,
-        };
-
-        let gst_info = gst_audio::AudioInfo::builder(gst_format, SAMPLE_RATE, NUM_CHANNELS as u32)
-            .build()
-            .expect("Failed to create GStreamer audio format");
-        let gst_caps = gst_info.to_caps().expect("Failed to create GStreamer caps");
+        let gst_caps = Self::caps(format, SAMPLE_RATE).expect("Failed to create GStreamer caps");
 
         let sample_size = format.size();
         let gst_bytes = NUM_CHANNELS as usize * 2048 * sample_size;
@@ -54,6 +47,7 @@ impl Open for GstreamerSink {
             .downcast::<gst_app::AppSrc>()
             .expect("couldn't cast AppSrc element at runtime!");
         appsrc.set_caps(Some(&gst_caps));
+        appsrc.set_format(gst::Format::Time);
         appsrc.set_max_bytes(gst_bytes as u64);
         appsrc.set_block(true);
 
@@ -131,7 +125,10 @@ impl Open for GstreamerSink {
             bufferpool,
             pipeline,
             format,
+            sample_rate: SAMPLE_RATE,
             async_error,
+            rate_switched_at: gst::ClockTime::ZERO,
+            frames_written: 0,
         }
     }
 }
@@ -139,6 +136,9 @@ impl Open for GstreamerSink {
 impl Sink for GstreamerSink {
     fn start(&mut self) -> SinkResult<()> {
         *self.async_error.lock() = None;
+        // flushing resets the running time, so the timestamps start over as well
+        self.rate_switched_at = gst::ClockTime::ZERO;
+        self.frames_written = 0;
         self.appsrc.send_event(FlushStop::new(true));
         self.bufferpool
             .set_active(true)
@@ -161,6 +161,17 @@ impl Sink for GstreamerSink {
         Ok(())
     }
 
+    fn set_sample_rate(&mut self, sample_rate: u32) -> SinkResult<bool> {
+        let caps = Self::caps(self.format, sample_rate)
+            .map_err(|e| SinkError::InvalidParams(e.to_string()))?;
+        self.appsrc.set_caps(Some(&caps));
+
+        self.rate_switched_at = self.written_time();
+        self.frames_written = 0;
+        self.sample_rate = sample_rate;
+        Ok(true)
+    }
+
     sink_as_bytes!();
 }
 
@@ -181,8 +192,15 @@ impl SinkAsBytes for GstreamerSink {
             .acquire_buffer(None)
             .map_err(|e| SinkError::OnWrite(e.to_string()))?;
 
+        let frames = (data.len() / (self.format.size() * NUM_CHANNELS as usize)) as u64;
+        let pts = self.written_time();
+        self.frames_written += frames;
+        let duration = self.written_time() - pts;
+
         let mutbuf = buffer.make_mut();
         mutbuf.set_size(data.len());
+        mutbuf.set_pts(pts);
+        mutbuf.set_duration(duration);
         mutbuf
             .copy_from_slice(0, data)
             .map_err(|e| SinkError::OnWrite(e.to_string()))?;
@@ -197,4 +215,27 @@ impl SinkAsBytes for GstreamerSink {
 
 impl GstreamerSink {
     pub const NAME: &'static str = "gstreamer";
+
+    fn caps(format: AudioFormat, sample_rate: u32) -> Result<gst::Caps, gst::glib::BoolError> {
+        let gst_format = match format {
+            AudioFormat::F64 => gst_audio::AUDIO_FORMAT_F64,
+            AudioFormat::F32 => gst_audio::AUDIO_FORMAT_F32,
+            AudioFormat::S32 => gst_audio::AUDIO_FORMAT_S32,
+            AudioFormat::S24 => gst_audio::AUDIO_FORMAT_S2432,
+            AudioFormat::S24_3 => gst_audio::AUDIO_FORMAT_S24,
+            AudioFormat::S16 => gst_audio::AUDIO_FORMAT_S16,
+        };
+
+        gst_audio::AudioInfo::builder(gst_format, sample_rate, NUM_CHANNELS as u32)
+            .build()?
+            .to_caps()
+    }
+
+    // The running time at the end of the frames pushed so far.
+    fn written_time(&self) -> gst::ClockTime {
+        let since_switch = gst::ClockTime::SECOND
+            .mul_div_floor(self.frames_written, self.sample_rate as u64)
+            .unwrap_or(gst::ClockTime::MAX);
+        self.rate_switched_at.saturating_add(since_switch)
+    }
 }
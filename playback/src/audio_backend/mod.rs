@@ -1,5 +1,6 @@
 use crate::config::AudioFormat;
 use crate::convert::Converter;
+use crate::core::error::{Error, ErrorCode, ErrorKind, Recovery};
 use crate::decoder::AudioPacket;
 use thiserror::Error;
 
@@ -19,6 +20,39 @@ pub enum SinkError {
 
 pub type SinkResult<T> = Result<T, SinkError>;
 
+impl From<SinkError> for Error {
+    fn from(err: SinkError) -> Self {
+        match err {
+            SinkError::NotConnected(_) | SinkError::ConnectionRefused(_) => {
+                Error::with_code(ErrorKind::Unavailable, err)
+            }
+            SinkError::OnWrite(_) => Error::with_code(ErrorKind::Aborted, err),
+            SinkError::InvalidParams(_) => Error::with_code(ErrorKind::InvalidArgument, err),
+            SinkError::StateChange(_) => Error::with_code(ErrorKind::FailedPrecondition, err),
+        }
+    }
+}
+
+impl ErrorCode for SinkError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::NotConnected(_) => "sink.not_connected",
+            Self::ConnectionRefused(_) => "sink.connection_refused",
+            Self::OnWrite(_) => "sink.on_write",
+            Self::InvalidParams(_) => "sink.invalid_params",
+            Self::StateChange(_) => "sink.state_change",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        match (self, kind) {
+            // the device may come back, e.g. after it was unplugged
+            (Self::StateChange(_), ErrorKind::FailedPrecondition) => Some(Recovery::Retry),
+            _ => None,
+        }
+    }
+}
+
 pub trait Open {
     fn open(_: Option<String>, format: AudioFormat) -> Self;
 }
@@ -31,6 +65,17 @@ pub trait Sink {
         Ok(())
     }
     fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()>;
+    /// Switches to `sample_rate` for the following packets, if the backend supports it,
+    /// and returns whether it did. Otherwise the player resamples to the current rate,
+    /// which starts out as [`SAMPLE_RATE`](crate::SAMPLE_RATE).
+    fn set_sample_rate(&mut self, _sample_rate: u32) -> SinkResult<bool> {
+        Ok(false)
+    }
+    /// The number of frames written but not yet played, if the backend can tell. The
+    /// player uses it to detect drift between the clock of the sink and its sample rate.
+    fn buffered_frames(&self) -> Option<usize> {
+        None
+    }
 }
 
 pub type SinkBuilder = fn(Option<String>, AudioFormat) -> Box<dyn Sink>;
@@ -120,6 +165,11 @@ use self::pipe::StdoutSink;
 mod subprocess;
 use self::subprocess::SubprocessSink;
 
+#[cfg(unix)]
+mod fd;
+#[cfg(unix)]
+use self::fd::FdSink;
+
 pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     #[cfg(feature = "rodio-backend")]
     (RodioSink::NAME, rodio::mk_rodio), // default goes first
@@ -139,6 +189,8 @@ pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     (SdlSink::NAME, mk_sink::<SdlSink>),
     (StdoutSink::NAME, mk_sink::<StdoutSink>),
     (SubprocessSink::NAME, mk_sink::<SubprocessSink>),
+    #[cfg(unix)]
+    (FdSink::NAME, mk_sink::<FdSink>),
 ];
 
 pub fn find(name: Option<String>) -> Option<SinkBuilder> {
@@ -151,3 +203,20 @@ pub fn find(name: Option<String>) -> Option<SinkBuilder> {
         BACKENDS.first().map(|backend| backend.1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        // the device may come back
+        let err = Error::from(SinkError::StateChange("unplugged".into()));
+        assert_eq!(err.code(), "sink.state_change");
+        assert_eq!(err.kind, ErrorKind::FailedPrecondition);
+        assert_eq!(err.recovery(), Recovery::Retry);
+
+        let err = Error::from(SinkError::InvalidParams("format".into()));
+        assert_eq!(err.recovery(), Recovery::GiveUp);
+    }
+}
@@ -1,6 +1,6 @@
 use std::process::exit;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use cpal::traits::{DeviceTrait, HostTrait};
 use thiserror::Error;
@@ -8,6 +8,7 @@ use thiserror::Error;
 use super::{Sink, SinkError, SinkResult};
 use crate::config::AudioFormat;
 use crate::convert::Converter;
+use crate::core::supervisor::Backoff;
 use crate::decoder::AudioPacket;
 use crate::{NUM_CHANNELS, SAMPLE_RATE};
 
@@ -17,6 +18,15 @@ use crate::{NUM_CHANNELS, SAMPLE_RATE};
 ))]
 compile_error!("Rodio JACK backend is currently only supported on linux.");
 
+// A device that isn't available, e.g. a Bluetooth speaker that connects after startup, is
+// looked for again with increasing delays when playback starts, for up to this long.
+const DEVICE_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEVICE_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(250);
+const DEVICE_RETRY_MAX_DELAY: Duration = Duration::from_secs(4);
+
+// When the device doesn't consume any samples for this long, it is assumed to be gone.
+const DEVICE_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[cfg(feature = "rodio-backend")]
 pub fn mk_rodio(device: Option<String>, format: AudioFormat) -> Box<dyn Sink> {
     Box::new(open(cpal::default_host(), device, format))
@@ -45,6 +55,8 @@ pub enum RodioError {
     DevicesError(#[from] cpal::DevicesError),
     #[error("<RodioSink> {0}")]
     Samples(String),
+    #[error("<RodioSink> device stopped playing")]
+    DeviceStalled,
 }
 
 impl From<RodioError> for SinkError {
@@ -54,17 +66,24 @@ impl From<RodioError> for SinkError {
         match e {
             StreamError(_) | PlayError(_) | Samples(_) => SinkError::OnWrite(es),
             NoDeviceAvailable | DeviceNotAvailable(_) => SinkError::ConnectionRefused(es),
+            DeviceStalled => SinkError::NotConnected(es),
             DevicesError(_) => SinkError::InvalidParams(es),
         }
     }
 }
 
-pub struct RodioSink {
+struct RodioOutput {
     rodio_sink: rodio::Sink,
-    format: AudioFormat,
     _stream: rodio::OutputStream,
 }
 
+pub struct RodioSink {
+    host: cpal::Host,
+    device: Option<String>,
+    output: Option<RodioOutput>,
+    format: AudioFormat,
+}
+
 fn list_formats(device: &rodio::Device) {
     match device.default_output_config() {
         Ok(cfg) => {
@@ -131,11 +150,8 @@ fn list_outputs(host: &cpal::Host) -> Result<(), cpal::DevicesError> {
     Ok(())
 }
 
-fn create_sink(
-    host: &cpal::Host,
-    device: Option<String>,
-) -> Result<(rodio::Sink, rodio::OutputStream), RodioError> {
-    let rodio_device = match device.as_deref() {
+fn create_sink(host: &cpal::Host, device: Option<&str>) -> Result<RodioOutput, RodioError> {
+    let rodio_device = match device {
         Some("?") => match list_outputs(host) {
             Ok(()) => exit(0),
             Err(e) => {
@@ -161,7 +177,10 @@ fn create_sink(
 
     let (stream, handle) = rodio::OutputStream::try_from_device(&rodio_device)?;
     let sink = rodio::Sink::try_new(&handle)?;
-    Ok((sink, stream))
+    Ok(RodioOutput {
+        rodio_sink: sink,
+        _stream: stream,
+    })
 }
 
 pub fn open(host: cpal::Host, device: Option<String>, format: AudioFormat) -> RodioSink {
@@ -174,25 +193,35 @@ pub fn open(host: cpal::Host, device: Option<String>, format: AudioFormat) -> Ro
         unimplemented!("Rodio currently only supports F32 and S16 formats");
     }
 
-    let (sink, stream) = create_sink(&host, device).unwrap();
+    let output = match create_sink(&host, device.as_deref()) {
+        Ok(output) => Some(output),
+        Err(e @ (RodioError::NoDeviceAvailable | RodioError::DeviceNotAvailable(_))) => {
+            warn!("{e}, looking for it again when playback starts");
+            None
+        }
+        Err(e) => panic!("{e}"),
+    };
 
     debug!("Rodio sink was created");
     RodioSink {
-        rodio_sink: sink,
+        host,
+        device,
+        output,
         format,
-        _stream: stream,
     }
 }
 
 impl Sink for RodioSink {
     fn start(&mut self) -> SinkResult<()> {
-        self.rodio_sink.play();
+        self.output()?.rodio_sink.play();
         Ok(())
     }
 
     fn stop(&mut self) -> SinkResult<()> {
-        self.rodio_sink.sleep_until_end();
-        self.rodio_sink.pause();
+        if let Some(output) = &self.output {
+            output.rodio_sink.sleep_until_end();
+            output.rodio_sink.pause();
+        }
         Ok(())
     }
 
@@ -200,6 +229,11 @@ impl Sink for RodioSink {
         let samples = packet
             .samples()
             .map_err(|e| RodioError::Samples(e.to_string()))?;
+        let rodio_sink = &self
+            .output
+            .as_ref()
+            .ok_or(RodioError::NoDeviceAvailable)?
+            .rodio_sink;
         match self.format {
             AudioFormat::F32 => {
                 let samples_f32: &[f32] = &converter.f64_to_f32(samples);
@@ -208,7 +242,7 @@ impl Sink for RodioSink {
                     SAMPLE_RATE,
                     samples_f32,
                 );
-                self.rodio_sink.append(source);
+                rodio_sink.append(source);
             }
             AudioFormat::S16 => {
                 let samples_s16: &[i16] = &converter.f64_to_s16(samples);
@@ -217,7 +251,7 @@ impl Sink for RodioSink {
                     SAMPLE_RATE,
                     samples_s16,
                 );
-                self.rodio_sink.append(source);
+                rodio_sink.append(source);
             }
             _ => unreachable!(),
         };
@@ -225,9 +259,21 @@ impl Sink for RodioSink {
         // Chunk sizes seem to be about 256 to 3000 ish items long.
         // Assuming they're on average 1628 then a half second buffer is:
         // 44100 elements --> about 27 chunks
-        while self.rodio_sink.len() > 26 {
+        let mut len = rodio_sink.len();
+        let mut draining_since = Instant::now();
+        while len > 26 {
             // sleep and wait for rodio to drain a bit
             thread::sleep(Duration::from_millis(10));
+
+            let current_len = rodio_sink.len();
+            if current_len < len {
+                len = current_len;
+                draining_since = Instant::now();
+            } else if draining_since.elapsed() > DEVICE_STALL_TIMEOUT {
+                // The device was probably disconnected, so look for it again on the next start.
+                self.output = None;
+                return Err(RodioError::DeviceStalled.into());
+            }
         }
         Ok(())
     }
@@ -236,4 +282,33 @@ impl Sink for RodioSink {
 impl RodioSink {
     #[allow(dead_code)]
     pub const NAME: &'static str = "rodio";
+
+    /// Returns the output, opening the device first if it wasn't available before. Devices
+    /// are enumerated again with increasing delays until it appears or the retries time out.
+    fn output(&mut self) -> Result<&RodioOutput, RodioError> {
+        if self.output.is_none() {
+            let started = Instant::now();
+            let mut backoff = Backoff::new(DEVICE_RETRY_INITIAL_DELAY, DEVICE_RETRY_MAX_DELAY);
+            let output = loop {
+                match create_sink(&self.host, self.device.as_deref()) {
+                    Ok(output) => break output,
+                    Err(
+                        e @ (RodioError::NoDeviceAvailable | RodioError::DeviceNotAvailable(_)),
+                    ) => {
+                        let delay = backoff.next_delay();
+                        if started.elapsed() + delay > DEVICE_RETRY_TIMEOUT {
+                            return Err(e);
+                        }
+                        debug!("{e}, enumerating devices again in {delay:?}");
+                        thread::sleep(delay);
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            self.output = Some(output);
+        }
+
+        // Just set above if it was missing.
+        self.output.as_ref().ok_or(RodioError::NoDeviceAvailable)
+    }
 }
@@ -1,7 +1,10 @@
 use std::{mem, str::FromStr, time::Duration};
 
 pub use crate::dither::{mk_ditherer, DithererBuilder, TriangularDitherer};
-use crate::{convert::i24, player::duration_to_coefficient};
+use crate::{
+    audio::READ_AHEAD_BEFORE_PLAYBACK, content_policy::ContentPolicy, convert::i24,
+    player::duration_to_coefficient,
+};
 
 #[derive(Clone, Copy, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub enum Bitrate {
@@ -121,11 +124,72 @@ impl Default for NormalisationMethod {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventOverflowPolicy {
+    /// Replace a queued event of the same kind if it only reports state (volume, position,
+    /// shuffle, ...), otherwise drop the oldest queued event that only reports state. When
+    /// all queued events report changes that must not be lost, like the end of a track,
+    /// block the player like [`Block`](Self::Block).
+    Coalesce,
+    /// Drop the oldest queued event.
+    DropOldest,
+    /// Block the player until the consumer has made room in its queue.
+    Block,
+}
+
+impl FromStr for EventOverflowPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "coalesce" => Ok(Self::Coalesce),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "block" => Ok(Self::Block),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for EventOverflowPolicy {
+    fn default() -> Self {
+        Self::Coalesce
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadFailurePolicy {
+    /// Skip to the next track once a track has failed to load after all retries.
+    Skip,
+    /// Stop playback once a track has failed to load after all retries.
+    Stop,
+}
+
+impl FromStr for LoadFailurePolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "skip" => Ok(Self::Skip),
+            "stop" => Ok(Self::Stop),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for LoadFailurePolicy {
+    fn default() -> Self {
+        Self::Skip
+    }
+}
+
 #[derive(Clone)]
 pub struct PlayerConfig {
     pub bitrate: Bitrate,
+    // lower the bitrate of the tracks loaded next when they can't be downloaded fast enough,
+    // and raise it back up to `bitrate` when they can
+    pub adaptive_bitrate: bool,
     pub gapless: bool,
     pub passthrough: bool,
+    // resample slightly to make up for the clock of the sink drifting from its sample rate
+    pub drift_correction: bool,
 
     pub normalisation: bool,
     pub normalisation_type: NormalisationType,
@@ -139,12 +203,39 @@ pub struct PlayerConfig {
     // pass function pointers so they can be lazily instantiated *after* spawning a thread
     // (thereby circumventing Send bounds that they might not satisfy)
     pub ditherer: Option<DithererBuilder>,
+
+    // bounds the number of events that are queued for each event channel
+    pub event_queue_capacity: usize,
+    pub event_overflow_policy: EventOverflowPolicy,
+    // bounds the number of commands that are queued for the player, further hints like
+    // preloading are dropped until it caught up while other commands are queued anyway
+    pub command_queue_capacity: usize,
+
+    // the number of times loading a track is retried, waiting twice as long each time
+    pub load_retries: u32,
+    pub load_retry_backoff: Duration,
+    pub load_failure_policy: LoadFailurePolicy,
+
+    // how many of the tracks that are up next have their metadata and audio keys fetched
+    // ahead of loading them
+    pub prefetch_count: usize,
+
+    // how much audio must be downloaded before a track starts playing, and before playback
+    // resumes after seeking to a position that isn't downloaded yet. They are converted to
+    // bytes with the nominal data rate of the quality that is played.
+    pub start_buffer: Duration,
+    pub seek_buffer: Duration,
+
+    // what the device may play, on top of the explicit content filter of the account. It can
+    // be changed with `Player::set_content_policy`, e.g. when another user takes over.
+    pub content_policy: ContentPolicy,
 }
 
 impl Default for PlayerConfig {
     fn default() -> Self {
         Self {
             bitrate: Bitrate::default(),
+            adaptive_bitrate: false,
             gapless: true,
             normalisation: false,
             normalisation_type: NormalisationType::default(),
@@ -155,7 +246,18 @@ impl Default for PlayerConfig {
             normalisation_release_cf: duration_to_coefficient(Duration::from_millis(100)),
             normalisation_knee_db: 5.0,
             passthrough: false,
+            drift_correction: false,
             ditherer: Some(mk_ditherer::<TriangularDitherer>),
+            event_queue_capacity: 256,
+            event_overflow_policy: EventOverflowPolicy::default(),
+            command_queue_capacity: 256,
+            load_retries: 2,
+            load_retry_backoff: Duration::from_secs(1),
+            load_failure_policy: LoadFailurePolicy::default(),
+            prefetch_count: 3,
+            start_buffer: READ_AHEAD_BEFORE_PLAYBACK,
+            seek_buffer: READ_AHEAD_BEFORE_PLAYBACK,
+            content_policy: ContentPolicy::default(),
         }
     }
 }
@@ -0,0 +1,297 @@
+//! Restrictions on what a device plays, for family devices where the explicit content filter
+//! of the account isn't granular enough, see [`PlayerConfig::content_policy`].
+//!
+//! [`PlayerConfig::content_policy`]: crate::config::PlayerConfig::content_policy
+
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use time::{OffsetDateTime, Time, UtcOffset};
+
+use crate::{
+    core::SpotifyId,
+    metadata::audio::{AudioItem, UniqueFields},
+};
+
+/// The hours of the day during which playback is allowed. They wrap around midnight when
+/// `end` is before `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedHours {
+    pub start: Time,
+    pub end: Time,
+    /// The time zone of `start` and `end`.
+    pub utc_offset: UtcOffset,
+}
+
+impl AllowedHours {
+    pub fn contains(&self, now: OffsetDateTime) -> bool {
+        let time = now.to_offset(self.utc_offset).time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+// Parses "07:00-20:00", optionally followed by the UTC offset like "07:00-20:00+02:00".
+// Without it the hours are in UTC.
+impl FromStr for AllowedHours {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn hours_minutes(s: &str) -> Result<(u8, u8), ()> {
+            let (hours, minutes) = s.split_once(':').ok_or(())?;
+            Ok((
+                hours.parse().map_err(|_| ())?,
+                minutes.parse().map_err(|_| ())?,
+            ))
+        }
+
+        let (start, rest) = s.split_once('-').ok_or(())?;
+        let (end, utc_offset) = match rest.find(['+', '-']) {
+            Some(index) => {
+                let (end, offset) = rest.split_at(index);
+                let (hours, minutes) = hours_minutes(&offset[1..])?;
+                let (hours, minutes) = (hours as i8, minutes as i8);
+                let utc_offset = if offset.starts_with('-') {
+                    UtcOffset::from_hms(-hours, -minutes, 0)
+                } else {
+                    UtcOffset::from_hms(hours, minutes, 0)
+                };
+                (end, utc_offset.map_err(|_| ())?)
+            }
+            None => (rest, UtcOffset::UTC),
+        };
+
+        let time = |s: &str| {
+            let (hours, minutes) = hours_minutes(s)?;
+            Time::from_hms(hours, minutes, 0).map_err(|_| ())
+        };
+
+        Ok(Self {
+            start: time(start)?,
+            end: time(end)?,
+            utc_offset,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContentPolicy {
+    /// Blocks explicit tracks and episodes, regardless of the setting of the account.
+    pub block_explicit: bool,
+    /// Tracks and episodes that are blocked, and artists whose tracks are blocked.
+    pub blocked: HashSet<SpotifyId>,
+    pub allowed_hours: Option<AllowedHours>,
+}
+
+/// Why a track was blocked by the [`ContentPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    Explicit,
+    Blocked,
+    /// The track is by this artist, who is blocked.
+    BlockedArtist(SpotifyId),
+    OutsideAllowedHours,
+}
+
+impl fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Explicit => write!(f, "explicit"),
+            Self::Blocked => write!(f, "blocked"),
+            Self::BlockedArtist(_) => write!(f, "blocked_artist"),
+            Self::OutsideAllowedHours => write!(f, "outside_allowed_hours"),
+        }
+    }
+}
+
+impl ContentPolicy {
+    pub fn is_empty(&self) -> bool {
+        !self.block_explicit && self.blocked.is_empty() && self.allowed_hours.is_none()
+    }
+
+    /// Checks whether `audio_item` may be played at `now`.
+    pub fn check(&self, audio_item: &AudioItem, now: OffsetDateTime) -> Result<(), BlockReason> {
+        if self.block_explicit && audio_item.is_explicit {
+            return Err(BlockReason::Explicit);
+        }
+
+        if self.blocked.contains(&audio_item.track_id) {
+            return Err(BlockReason::Blocked);
+        }
+
+        if let UniqueFields::Track { ref artists, .. } = audio_item.unique_fields {
+            if let Some(artist) = artists.0.iter().find(|a| self.blocked.contains(&a.id)) {
+                return Err(BlockReason::BlockedArtist(artist.id));
+            }
+        }
+
+        match self.allowed_hours {
+            Some(hours) if !hours.contains(now) => Err(BlockReason::OutsideAllowedHours),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use time::{Date, Month};
+
+    use super::*;
+    use crate::metadata::{
+        artist::{ArtistWithRole, ArtistsWithRole},
+        audio::AudioFiles,
+    };
+
+    fn time(hours: u8, minutes: u8) -> Time {
+        Time::from_hms(hours, minutes, 0).unwrap()
+    }
+
+    fn utc(hours: u8, minutes: u8) -> OffsetDateTime {
+        Date::from_calendar_date(2024, Month::March, 1)
+            .unwrap()
+            .with_time(time(hours, minutes))
+            .assume_utc()
+    }
+
+    fn id(base62: &str) -> SpotifyId {
+        SpotifyId::from_base62(base62).unwrap()
+    }
+
+    fn track(is_explicit: bool, artist: SpotifyId) -> AudioItem {
+        AudioItem {
+            track_id: id("4uLU6hMCjMI75M1A2tKUQC"),
+            uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".into(),
+            files: AudioFiles(HashMap::new()),
+            name: "Track".into(),
+            covers: Vec::new(),
+            language: Vec::new(),
+            duration_ms: 180_000,
+            is_explicit,
+            availability: Ok(()),
+            alternatives: None,
+            unique_fields: UniqueFields::Track {
+                artists: ArtistsWithRole(vec![ArtistWithRole {
+                    id: artist,
+                    name: "Artist".into(),
+                    role: Default::default(),
+                }]),
+                album: "Album".into(),
+                album_artists: Vec::new(),
+                popularity: 0,
+                number: 1,
+                disc_number: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn parses_allowed_hours() {
+        assert_eq!(
+            "07:00-20:30".parse(),
+            Ok(AllowedHours {
+                start: time(7, 0),
+                end: time(20, 30),
+                utc_offset: UtcOffset::UTC,
+            })
+        );
+        assert_eq!(
+            "22:00-06:00+02:00".parse(),
+            Ok(AllowedHours {
+                start: time(22, 0),
+                end: time(6, 0),
+                utc_offset: UtcOffset::from_hms(2, 0, 0).unwrap(),
+            })
+        );
+        assert_eq!(
+            "07:00-20:00-05:30"
+                .parse::<AllowedHours>()
+                .map(|hours| hours.utc_offset),
+            Ok(UtcOffset::from_hms(-5, -30, 0).unwrap())
+        );
+
+        for invalid in [
+            "",
+            "07:00",
+            "7-20",
+            "07:00-24:00",
+            "07:60-20:00",
+            "07:00-20:00+x",
+        ] {
+            assert_eq!(invalid.parse::<AllowedHours>(), Err(()), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn allowed_hours_wrap_around_midnight() {
+        let day: AllowedHours = "07:00-20:00".parse().unwrap();
+        assert!(!day.contains(utc(6, 59)));
+        assert!(day.contains(utc(7, 0)));
+        assert!(day.contains(utc(19, 59)));
+        assert!(!day.contains(utc(20, 0)));
+
+        let night: AllowedHours = "22:00-06:00".parse().unwrap();
+        assert!(night.contains(utc(23, 0)));
+        assert!(night.contains(utc(0, 0)));
+        assert!(night.contains(utc(5, 59)));
+        assert!(!night.contains(utc(6, 0)));
+        assert!(!night.contains(utc(12, 0)));
+
+        // 07:00-20:00 in UTC+02:00 is 05:00-18:00 in UTC
+        let offset: AllowedHours = "07:00-20:00+02:00".parse().unwrap();
+        assert!(offset.contains(utc(5, 0)));
+        assert!(!offset.contains(utc(18, 0)));
+    }
+
+    #[test]
+    fn checks_tracks() {
+        let artist = id("0gxyHStUsqpMadRV0Di1Qt");
+        let other_artist = id("6rqhFgbbKwnb9MLmUQDhG6");
+        let noon = utc(12, 0);
+
+        let policy = ContentPolicy::default();
+        assert!(policy.is_empty());
+        assert_eq!(policy.check(&track(true, artist), noon), Ok(()));
+
+        let policy = ContentPolicy {
+            block_explicit: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check(&track(true, artist), noon),
+            Err(BlockReason::Explicit)
+        );
+        assert_eq!(policy.check(&track(false, artist), noon), Ok(()));
+
+        let policy = ContentPolicy {
+            blocked: [artist].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check(&track(false, artist), noon),
+            Err(BlockReason::BlockedArtist(artist))
+        );
+        assert_eq!(policy.check(&track(false, other_artist), noon), Ok(()));
+
+        let policy = ContentPolicy {
+            blocked: [id("4uLU6hMCjMI75M1A2tKUQC")].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check(&track(false, other_artist), noon),
+            Err(BlockReason::Blocked)
+        );
+
+        let policy = ContentPolicy {
+            allowed_hours: Some("07:00-20:00".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(policy.check(&track(false, artist), noon), Ok(()));
+        assert_eq!(
+            policy.check(&track(false, artist), utc(21, 0)),
+            Err(BlockReason::OutsideAllowedHours)
+        );
+    }
+}
@@ -2,11 +2,19 @@ use std::ops::Deref;
 
 use thiserror::Error;
 
+use crate::{
+    core::error::{Error, ErrorCode, ErrorKind},
+    SAMPLE_RATE,
+};
+
 #[cfg(feature = "passthrough-decoder")]
 mod passthrough_decoder;
 #[cfg(feature = "passthrough-decoder")]
 pub use passthrough_decoder::PassthroughDecoder;
 
+mod seek_table;
+pub use seek_table::SeekTable;
+
 mod symphonia_decoder;
 pub use symphonia_decoder::SymphoniaDecoder;
 
@@ -16,6 +24,9 @@ pub enum DecoderError {
     PassthroughDecoder(String),
     #[error("Symphonia Decoder Error: {0}")]
     SymphoniaDecoder(String),
+    /// The file could not be read, e.g. because downloading it failed, rather than decoded.
+    #[error("Decoder I/O Error: {0}")]
+    Io(String),
 }
 
 pub type DecoderResult<T> = Result<T, DecoderError>;
@@ -30,6 +41,15 @@ pub enum AudioPacketError {
 
 pub type AudioPacketResult<T> = Result<T, AudioPacketError>;
 
+impl ErrorCode for AudioPacketError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Raw => "audio_packet.raw",
+            Self::Samples => "audio_packet.samples",
+        }
+    }
+}
+
 pub enum AudioPacket {
     Samples(Vec<f64>),
     Raw(Vec<u8>),
@@ -74,16 +94,77 @@ impl Deref for AudioPacketPosition {
 pub trait AudioDecoder {
     fn seek(&mut self, position_ms: u32) -> Result<u32, DecoderError>;
     fn next_packet(&mut self) -> DecoderResult<Option<(AudioPacketPosition, AudioPacket)>>;
+
+    // The sample rate of the decoded samples
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
 }
 
-impl From<DecoderError> for librespot_core::error::Error {
+impl From<DecoderError> for Error {
     fn from(err: DecoderError) -> Self {
-        librespot_core::error::Error::aborted(err)
+        match err {
+            // decoding the same data again fails the same way
+            DecoderError::PassthroughDecoder(_) | DecoderError::SymphoniaDecoder(_) => {
+                Error::with_code(ErrorKind::DataLoss, err)
+            }
+            DecoderError::Io(_) => Error::with_code(ErrorKind::Unavailable, err),
+        }
+    }
+}
+
+impl ErrorCode for DecoderError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::PassthroughDecoder(_) => "decoder.passthrough",
+            Self::SymphoniaDecoder(_) => "decoder.symphonia",
+            Self::Io(_) => "decoder.io",
+        }
     }
 }
 
 impl From<symphonia::core::errors::Error> for DecoderError {
     fn from(err: symphonia::core::errors::Error) -> Self {
-        Self::SymphoniaDecoder(err.to_string())
+        match err {
+            symphonia::core::errors::Error::IoError(err) => Self::Io(err.to_string()),
+            err => Self::SymphoniaDecoder(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use symphonia::core::errors::Error as SymphoniaError;
+
+    use super::*;
+    use crate::core::error::Recovery;
+
+    #[test]
+    fn gives_up_on_invalid_data() {
+        for err in [
+            DecoderError::from(SymphoniaError::DecodeError("invalid main_data offset")),
+            DecoderError::from(SymphoniaError::Unsupported("codec")),
+            DecoderError::PassthroughDecoder("Invalid Data".into()),
+        ] {
+            let err = Error::from(err);
+            assert_eq!(err.kind, ErrorKind::DataLoss);
+            assert_eq!(err.recovery(), Recovery::GiveUp);
+        }
+    }
+
+    #[test]
+    fn retries_failed_reads() {
+        let err = DecoderError::from(SymphoniaError::IoError(io::Error::new(
+            io::ErrorKind::Other,
+            "download failed",
+        )));
+        assert!(matches!(err, DecoderError::Io(_)));
+
+        let err = Error::from(err);
+        assert_eq!(err.code(), "decoder.io");
+        assert_eq!(err.kind, ErrorKind::Unavailable);
+        assert!(err.is_retryable());
     }
 }
@@ -14,13 +14,19 @@ use crate::{
     MS_PER_PAGE, PAGES_PER_MS,
 };
 
+// Tells failing to read the file apart from invalid data in it.
+fn read_error(e: OggReadError) -> DecoderError {
+    match e {
+        OggReadError::ReadError(e) => DecoderError::Io(e.to_string()),
+        e => DecoderError::PassthroughDecoder(e.to_string()),
+    }
+}
+
 fn get_header<T>(code: u8, rdr: &mut PacketReader<T>) -> DecoderResult<Vec<u8>>
 where
     T: Read + Seek,
 {
-    let pck: Packet = rdr
-        .read_packet_expected()
-        .map_err(|e| DecoderError::PassthroughDecoder(e.to_string()))?;
+    let pck: Packet = rdr.read_packet_expected().map_err(read_error)?;
 
     let pkt_type = pck.data[0];
     debug!("Vorbis header type {}", &pkt_type);
@@ -117,10 +123,7 @@ impl<R: Read + Seek> AudioDecoder for PassthroughDecoder<R> {
         match self.rdr.seek_absgp(None, absgp) {
             Ok(_) => {
                 // need to set some offset for next_page()
-                let pck = self
-                    .rdr
-                    .read_packet()
-                    .map_err(|e| DecoderError::PassthroughDecoder(e.to_string()))?;
+                let pck = self.rdr.read_packet().map_err(read_error)?;
                 match pck {
                     Some(pck) => {
                         let new_page = pck.absgp_page();
@@ -132,7 +135,7 @@ impl<R: Read + Seek> AudioDecoder for PassthroughDecoder<R> {
                     None => Err(DecoderError::PassthroughDecoder("Packet is None".into())),
                 }
             }
-            Err(e) => Err(DecoderError::PassthroughDecoder(e.to_string())),
+            Err(e) => Err(read_error(e)),
         }
     }
 
@@ -174,7 +177,7 @@ impl<R: Read + Seek> AudioDecoder for PassthroughDecoder<R> {
                     info!("end of streaming");
                     return Ok(None);
                 }
-                Err(e) => return Err(DecoderError::PassthroughDecoder(e.to_string())),
+                Err(e) => return Err(read_error(e)),
             };
 
             let pckgp_page = pck.absgp_page();
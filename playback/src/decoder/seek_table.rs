@@ -0,0 +1,336 @@
+//! Where the pages of a cached Ogg Vorbis file start, kept in the cache next to the file, see
+//! [`SymphoniaDecoder::with_seek_table`](super::SymphoniaDecoder::with_seek_table).
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use parking_lot::Mutex;
+use symphonia::core::io::MediaSource;
+
+use crate::SAMPLE_RATE;
+
+const VERSION: u8 = 1;
+
+// the fixed part of an Ogg page header, followed by up to 255 segment sizes
+const PAGE_HEADER_SIZE: usize = 27;
+
+/// The positions of the pages of an Ogg Vorbis file, about one per second of audio, and its
+/// length. Byte offsets are from the start of the Vorbis stream, after Spotify's header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekTable {
+    /// The size of the file the table was made for, to tell when it doesn't fit anymore.
+    pub byte_len: u64,
+    /// Where the first page with audio starts, after the Vorbis headers.
+    pub audio_start: u64,
+    /// The timestamp after the last sample, which is where the padding of the last packet
+    /// starts.
+    pub end_ts: u64,
+    /// The timestamp at the end of a page, and where that page starts, in ascending order.
+    pub pages: Vec<(u64, u64)>,
+}
+
+impl SeekTable {
+    /// Reads the page headers of the Vorbis stream `reader`, which is positioned at its
+    /// start, skipping over the pages themselves.
+    pub fn scan<R: Read + Seek>(reader: &mut R, byte_len: u64) -> io::Result<Self> {
+        let mut audio_start = None;
+        let mut end_ts = 0;
+        let mut pages: Vec<(u64, u64)> = Vec::new();
+
+        let mut offset = 0;
+        let mut header = [0; PAGE_HEADER_SIZE];
+        let mut segments = [0; 255];
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            if &header[..4] != b"OggS" {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no Ogg page at offset {offset}"),
+                ));
+            }
+
+            let segments = &mut segments[..header[26] as usize];
+            reader.read_exact(segments)?;
+            let body_len: u64 = segments.iter().map(|&size| size as u64).sum();
+
+            // The headers are on pages with a granule position of 0, and pages on which no
+            // packet ends have -1.
+            let granule_position = LittleEndian::read_u64(&header[6..14]);
+            if granule_position != 0 && granule_position != u64::MAX {
+                audio_start.get_or_insert(offset);
+                end_ts = granule_position;
+                let due = pages
+                    .last()
+                    .map_or(true, |&(ts, _)| granule_position >= ts + SAMPLE_RATE as u64);
+                if due {
+                    pages.push((granule_position, offset));
+                }
+            }
+
+            offset += (PAGE_HEADER_SIZE + segments.len()) as u64 + body_len;
+            reader.seek(SeekFrom::Current(body_len as i64))?;
+        }
+
+        let audio_start = audio_start
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no audio pages"))?;
+
+        Ok(Self {
+            byte_len,
+            audio_start,
+            end_ts,
+            pages,
+        })
+    }
+
+    /// Where the last page that ends at or before `ts` starts, so that reading from there
+    /// doesn't miss a sample from `ts` on.
+    pub fn page_before(&self, ts: u64) -> u64 {
+        let index = self.pages.partition_point(|&(end_ts, _)| end_ts <= ts);
+        match index.checked_sub(1) {
+            Some(index) => self.pages[index].1,
+            None => self.audio_start,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(29 + 16 * self.pages.len());
+        bytes.push(VERSION);
+        for value in [self.byte_len, self.audio_start, self.end_ts] {
+            let _ = bytes.write_u64::<LittleEndian>(value);
+        }
+        let _ = bytes.write_u32::<LittleEndian>(self.pages.len() as u32);
+        for &(ts, offset) in &self.pages {
+            let _ = bytes.write_u64::<LittleEndian>(ts);
+            let _ = bytes.write_u64::<LittleEndian>(offset);
+        }
+        bytes
+    }
+
+    /// Returns `None` if `bytes` are not a seek table of this version.
+    pub fn from_bytes(mut bytes: &[u8]) -> Option<Self> {
+        if bytes.read_u8().ok()? != VERSION {
+            return None;
+        }
+        let byte_len = bytes.read_u64::<LittleEndian>().ok()?;
+        let audio_start = bytes.read_u64::<LittleEndian>().ok()?;
+        let end_ts = bytes.read_u64::<LittleEndian>().ok()?;
+        let len = bytes.read_u32::<LittleEndian>().ok()? as usize;
+        if bytes.len() != 16 * len {
+            return None;
+        }
+        let pages = bytes
+            .chunks_exact(16)
+            .map(|page| {
+                (
+                    LittleEndian::read_u64(&page[..8]),
+                    LittleEndian::read_u64(&page[8..]),
+                )
+            })
+            .collect();
+
+        Some(Self {
+            byte_len,
+            audio_start,
+            end_ts,
+            pages,
+        })
+    }
+}
+
+/// Presents the headers of a Vorbis stream followed by its pages from `resume_at` on, as if
+/// they were one stream. It isn't seekable, so that the Ogg reader doesn't read the end of
+/// the file to find its duration, and moves forward to seek.
+pub(super) struct IndexedSource {
+    inner: Arc<Mutex<dyn MediaSource>>,
+    audio_start: u64,
+    resume_at: u64,
+    position: u64,
+    // where `inner` is, if we know
+    inner_position: Option<u64>,
+}
+
+impl IndexedSource {
+    pub fn new(inner: Arc<Mutex<dyn MediaSource>>, audio_start: u64, resume_at: u64) -> Self {
+        Self {
+            inner,
+            audio_start,
+            resume_at,
+            position: 0,
+            inner_position: None,
+        }
+    }
+}
+
+impl Read for IndexedSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (inner_position, len) = if self.position < self.audio_start {
+            let headers_left = (self.audio_start - self.position) as usize;
+            (self.position, buf.len().min(headers_left))
+        } else {
+            (self.resume_at + self.position - self.audio_start, buf.len())
+        };
+
+        let mut inner = self.inner.lock();
+        if self.inner_position != Some(inner_position) {
+            inner.seek(SeekFrom::Start(inner_position))?;
+        }
+        let read = inner.read(&mut buf[..len])?;
+
+        self.position += read as u64;
+        self.inner_position = Some(inner_position + read as u64);
+        Ok(read)
+    }
+}
+
+impl Seek for IndexedSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => {
+                let position = if offset >= 0 {
+                    self.position.checked_add(offset.unsigned_abs())
+                } else {
+                    self.position.checked_sub(offset.unsigned_abs())
+                };
+                position.ok_or(io::ErrorKind::InvalidInput)?
+            }
+            SeekFrom::End(_) => return Err(io::ErrorKind::Unsupported.into()),
+        };
+        Ok(self.position)
+    }
+}
+
+impl MediaSource for IndexedSource {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        decoder::{AudioDecoder, AudioPacket, SymphoniaDecoder},
+        metadata::audio::AudioFileFormat,
+    };
+
+    // 130 packets of 1024 samples on pages of 4 packets, see tests/data/make_tone.py
+    const TONE: &[u8] = include_bytes!("../../tests/data/tone.ogg");
+    const TONE_END_TS: u64 = 129 * 1024 - 96;
+
+    fn tone_seek_table() -> SeekTable {
+        SeekTable::scan(&mut Cursor::new(TONE), TONE.len() as u64).unwrap()
+    }
+
+    fn decode(decoder: &mut SymphoniaDecoder) -> Vec<(u32, Vec<f64>)> {
+        let mut packets = Vec::new();
+        while let Some((position, packet)) = decoder.next_packet().unwrap() {
+            // The first packet after a seek only primes the decoder.
+            match packet {
+                AudioPacket::Samples(samples) if !samples.is_empty() => {
+                    packets.push((position.position_ms, samples))
+                }
+                _ => (),
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn round_trip() {
+        let seek_table = SeekTable {
+            byte_len: 123_456,
+            audio_start: 4_321,
+            end_ts: 999_999,
+            pages: vec![(44_100, 5_000), (90_000, 20_000), (135_000, 41_000)],
+        };
+
+        let bytes = seek_table.to_bytes();
+        assert_eq!(SeekTable::from_bytes(&bytes), Some(seek_table));
+
+        let mut other_version = bytes.clone();
+        other_version[0] = VERSION + 1;
+        assert_eq!(SeekTable::from_bytes(&other_version), None);
+        assert_eq!(SeekTable::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(SeekTable::from_bytes(&[]), None);
+    }
+
+    #[test]
+    fn scans_the_pages() {
+        let seek_table = tone_seek_table();
+
+        assert_eq!(seek_table.byte_len, TONE.len() as u64);
+        assert_eq!(&TONE[seek_table.audio_start as usize..][..4], b"OggS");
+        assert_eq!(seek_table.end_ts, TONE_END_TS);
+
+        // the first audio page, and then one about every second
+        assert_eq!(seek_table.pages[0], (3 * 1024, seek_table.audio_start));
+        assert_eq!(seek_table.pages.len(), 3);
+        for pages in seek_table.pages.windows(2) {
+            assert!(pages[1].0 >= pages[0].0 + SAMPLE_RATE as u64);
+            assert!(pages[1].0 < pages[0].0 + SAMPLE_RATE as u64 + 4 * 1024);
+            assert!(pages[1].1 > pages[0].1);
+        }
+        for &(_, offset) in &seek_table.pages {
+            assert_eq!(&TONE[offset as usize..][..4], b"OggS");
+        }
+
+        assert_eq!(seek_table.page_before(0), seek_table.audio_start);
+        assert_eq!(seek_table.page_before(3 * 1024), seek_table.audio_start);
+        let (ts, offset) = seek_table.pages[1];
+        assert_eq!(seek_table.page_before(ts - 1), seek_table.audio_start);
+        assert_eq!(seek_table.page_before(ts), offset);
+        assert_eq!(seek_table.page_before(u64::MAX), seek_table.pages[2].1);
+    }
+
+    #[test]
+    fn rejects_what_is_not_ogg() {
+        let mut data = TONE.to_vec();
+        data[tone_seek_table().pages[1].1 as usize] = b'X';
+        assert!(SeekTable::scan(&mut Cursor::new(&data), data.len() as u64).is_err());
+    }
+
+    #[test]
+    fn decoders_seek_to_the_same_samples() {
+        let format = AudioFileFormat::OGG_VORBIS_320;
+        let reference = decode(&mut SymphoniaDecoder::new(Cursor::new(TONE), format).unwrap());
+        let frames: usize = reference.iter().map(|(_, samples)| samples.len() / 2).sum();
+        assert_eq!(frames as u64, TONE_END_TS);
+        assert!(reference
+            .iter()
+            .flat_map(|(_, samples)| samples)
+            .any(|&sample| sample.abs() > 0.1));
+
+        let mut bisecting = SymphoniaDecoder::new(Cursor::new(TONE), format).unwrap();
+        let mut indexed =
+            SymphoniaDecoder::with_seek_table(Cursor::new(TONE), format, tone_seek_table())
+                .unwrap();
+
+        for position_ms in [2500, 1200, 0] {
+            for decoder in [&mut bisecting, &mut indexed] {
+                let seeked_to = decoder.seek(position_ms).unwrap();
+                assert!(seeked_to <= position_ms && seeked_to + 25 > position_ms);
+
+                let packets = decode(decoder);
+                let first = reference
+                    .iter()
+                    .position(|(ms, _)| *ms == packets[0].0)
+                    .unwrap();
+                assert_eq!(&reference[first..], &packets[..]);
+            }
+        }
+    }
+}
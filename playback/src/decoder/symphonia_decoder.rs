@@ -1,5 +1,6 @@
-use std::io;
+use std::{io, sync::Arc};
 
+use parking_lot::Mutex;
 use symphonia::{
     core::{
         audio::SampleBuffer,
@@ -16,18 +17,45 @@ use symphonia::{
     },
 };
 
-use super::{AudioDecoder, AudioPacket, AudioPacketPosition, DecoderError, DecoderResult};
+use super::{
+    seek_table::{IndexedSource, SeekTable},
+    AudioDecoder, AudioPacket, AudioPacketPosition, DecoderError, DecoderResult,
+};
 
 use crate::{
     metadata::audio::{AudioFileFormat, AudioFiles},
     player::NormalisationData,
-    NUM_CHANNELS, PAGES_PER_MS, SAMPLE_RATE,
+    NUM_CHANNELS,
 };
 
+// A cached Ogg Vorbis file opened with its seek table.
+struct Indexed {
+    source: Arc<Mutex<dyn MediaSource>>,
+    seek_table: SeekTable,
+    // where the packet after the last one starts
+    next_ts: Option<u64>,
+}
+
 pub struct SymphoniaDecoder {
     format: Box<dyn FormatReader>,
     decoder: Box<dyn Decoder>,
     sample_buffer: Option<SampleBuffer<f64>>,
+    sample_rate: u32,
+    indexed: Option<Indexed>,
+}
+
+fn media_source_stream(input: Box<dyn MediaSource>) -> MediaSourceStream {
+    let mss_opts = MediaSourceStreamOptions {
+        buffer_len: librespot_audio::MINIMUM_DOWNLOAD_SIZE,
+    };
+    MediaSourceStream::new(input, mss_opts)
+}
+
+fn format_options() -> FormatOptions {
+    FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    }
 }
 
 impl SymphoniaDecoder {
@@ -35,15 +63,8 @@ impl SymphoniaDecoder {
     where
         R: MediaSource + 'static,
     {
-        let mss_opts = MediaSourceStreamOptions {
-            buffer_len: librespot_audio::MINIMUM_DOWNLOAD_SIZE,
-        };
-        let mss = MediaSourceStream::new(Box::new(input), mss_opts);
-
-        let format_opts = FormatOptions {
-            enable_gapless: true,
-            ..Default::default()
-        };
+        let mss = media_source_stream(Box::new(input));
+        let format_opts = format_options();
 
         let format: Box<dyn FormatReader> = if AudioFiles::is_ogg_vorbis(file_format) {
             Box::new(OggReader::try_new(mss, &format_opts)?)
@@ -55,6 +76,52 @@ impl SymphoniaDecoder {
             )));
         };
 
+        Self::with_format(format, file_format, None)
+    }
+
+    /// Opens an Ogg Vorbis file with the [`SeekTable`] that was made for it. Unlike
+    /// [`SymphoniaDecoder::new`], this doesn't read the end of the file to find its duration,
+    /// and seeks to the page in the table instead of bisecting the file.
+    pub fn with_seek_table<R>(
+        input: R,
+        file_format: AudioFileFormat,
+        seek_table: SeekTable,
+    ) -> DecoderResult<Self>
+    where
+        R: MediaSource + 'static,
+    {
+        if !AudioFiles::is_ogg_vorbis(file_format) {
+            return Err(DecoderError::SymphoniaDecoder(format!(
+                "Seek tables are not supported for format {file_format:?}"
+            )));
+        }
+
+        let source: Arc<Mutex<dyn MediaSource>> = Arc::new(Mutex::new(input));
+        let indexed = Indexed {
+            source,
+            seek_table,
+            next_ts: None,
+        };
+        let format = Self::open_indexed(&indexed, indexed.seek_table.audio_start)?;
+
+        Self::with_format(format, file_format, Some(indexed))
+    }
+
+    fn open_indexed(indexed: &Indexed, resume_at: u64) -> DecoderResult<Box<dyn FormatReader>> {
+        let source = IndexedSource::new(
+            indexed.source.clone(),
+            indexed.seek_table.audio_start,
+            resume_at,
+        );
+        let mss = media_source_stream(Box::new(source));
+        Ok(Box::new(OggReader::try_new(mss, &format_options())?))
+    }
+
+    fn with_format(
+        format: Box<dyn FormatReader>,
+        file_format: AudioFileFormat,
+        indexed: Option<Indexed>,
+    ) -> DecoderResult<Self> {
         let track = format.default_track().ok_or_else(|| {
             DecoderError::SymphoniaDecoder("Could not retrieve default track".into())
         })?;
@@ -70,14 +137,11 @@ impl SymphoniaDecoder {
             )));
         };
 
-        let rate = decoder.codec_params().sample_rate.ok_or_else(|| {
+        // Other sample rates than `SAMPLE_RATE` are played by the player at that rate if the
+        // sink supports it, or resampled otherwise.
+        let sample_rate = decoder.codec_params().sample_rate.ok_or_else(|| {
             DecoderError::SymphoniaDecoder("Could not retrieve sample rate".into())
         })?;
-        if rate != SAMPLE_RATE {
-            return Err(DecoderError::SymphoniaDecoder(format!(
-                "Unsupported sample rate: {rate}"
-            )));
-        }
 
         let channels = decoder.codec_params().channels.ok_or_else(|| {
             DecoderError::SymphoniaDecoder("Could not retrieve channel configuration".into())
@@ -95,6 +159,8 @@ impl SymphoniaDecoder {
             // We set the sample buffer when decoding the first full packet,
             // whose duration is also the ideal sample buffer size.
             sample_buffer: None,
+            sample_rate,
+            indexed,
         })
     }
 
@@ -140,7 +206,7 @@ impl SymphoniaDecoder {
                 (time.seconds as f64 + time.frac) * 1000.
             }
             // Fallback in the unexpected case that the format has no base time set.
-            None => ts as f64 * PAGES_PER_MS,
+            None => ts as f64 * 1000. / self.sample_rate as f64,
         };
         seeked_to_ms as u32
     }
@@ -152,6 +218,14 @@ impl AudioDecoder for SymphoniaDecoder {
         let frac = (position_ms as f64 % 1000.) / 1000.;
         let time = Time::new(seconds, frac);
 
+        // Without reading the end of the file, the Ogg reader can only move forward. Start
+        // reading again just before the position.
+        if let Some(indexed) = &mut self.indexed {
+            let ts = position_ms as u64 * self.sample_rate as u64 / 1000;
+            self.format = Self::open_indexed(indexed, indexed.seek_table.page_before(ts))?;
+            indexed.next_ts = None;
+        }
+
         // `track_id: None` implies the default track ID (of the container, not of Spotify).
         let seeked_to_ts = self.format.seek(
             SeekMode::Accurate,
@@ -168,6 +242,10 @@ impl AudioDecoder for SymphoniaDecoder {
         Ok(self.ts_to_ms(seeked_to_ts.actual_ts))
     }
 
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
     fn next_packet(&mut self) -> DecoderResult<Option<(AudioPacketPosition, AudioPacket)>> {
         let mut skipped = false;
 
@@ -178,7 +256,7 @@ impl AudioDecoder for SymphoniaDecoder {
                     if err.kind() == io::ErrorKind::UnexpectedEof {
                         return Ok(None);
                     } else {
-                        return Err(DecoderError::SymphoniaDecoder(err.to_string()));
+                        return Err(DecoderError::Io(err.to_string()));
                     }
                 }
                 Err(err) => {
@@ -186,7 +264,19 @@ impl AudioDecoder for SymphoniaDecoder {
                 }
             };
 
-            let position_ms = self.ts_to_ms(packet.ts());
+            let mut packet_ts = packet.ts();
+
+            // Without the end of the file, the Ogg reader counts the timestamps of the packets
+            // on the last page back from where it ends, which is too early when the last
+            // packet is cut short. They follow the packets before them.
+            if let Some(indexed) = &mut self.indexed {
+                if let Some(next_ts) = indexed.next_ts {
+                    packet_ts = packet_ts.max(next_ts);
+                }
+                indexed.next_ts = Some(packet_ts + packet.dur());
+            }
+
+            let position_ms = self.ts_to_ms(packet_ts);
             let packet_position = AudioPacketPosition {
                 position_ms,
                 skipped,
@@ -204,9 +294,19 @@ impl AudioDecoder for SymphoniaDecoder {
                     };
 
                     sample_buffer.copy_interleaved_ref(decoded);
-                    let samples = AudioPacket::Samples(sample_buffer.samples().to_vec());
+                    let mut samples = sample_buffer.samples().to_vec();
+
+                    // The Ogg reader only trims the padding of the last packet when it read
+                    // the end of the file.
+                    if let Some(indexed) = &self.indexed {
+                        let frames = indexed.seek_table.end_ts.saturating_sub(packet_ts);
+                        let len = frames.saturating_mul(NUM_CHANNELS as u64);
+                        if len < samples.len() as u64 {
+                            samples.truncate(len as usize);
+                        }
+                    }
 
-                    return Ok(Some((packet_position, samples)));
+                    return Ok(Some((packet_position, AudioPacket::Samples(samples))));
                 }
                 Err(Error::DecodeError(_)) => {
                     // The packet failed to decode due to corrupted or invalid data, get a new
@@ -53,11 +53,7 @@ pub struct TriangularDitherer {
 
 impl Ditherer for TriangularDitherer {
     fn new() -> Self {
-        Self {
-            cached_rng: create_rng(),
-            // 2 LSB peak-to-peak needed to linearize the response:
-            distribution: Triangular::new(-1.0, 1.0, 0.0).unwrap(),
-        }
+        Self::with_rng(create_rng())
     }
 
     fn name(&self) -> &'static str {
@@ -71,6 +67,19 @@ impl Ditherer for TriangularDitherer {
 
 impl TriangularDitherer {
     pub const NAME: &'static str = "tpdf";
+
+    /// Creates a ditherer that always generates the same noise, e.g. for reproducible tests.
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(SmallRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(cached_rng: SmallRng) -> Self {
+        Self {
+            cached_rng,
+            // 2 LSB peak-to-peak needed to linearize the response:
+            distribution: Triangular::new(-1.0, 1.0, 0.0).unwrap(),
+        }
+    }
 }
 
 pub struct GaussianDitherer {
@@ -80,11 +89,7 @@ pub struct GaussianDitherer {
 
 impl Ditherer for GaussianDitherer {
     fn new() -> Self {
-        Self {
-            cached_rng: create_rng(),
-            // 1/2 LSB RMS needed to linearize the response:
-            distribution: Normal::new(0.0, 0.5).unwrap(),
-        }
+        Self::with_rng(create_rng())
     }
 
     fn name(&self) -> &'static str {
@@ -98,6 +103,19 @@ impl Ditherer for GaussianDitherer {
 
 impl GaussianDitherer {
     pub const NAME: &'static str = "gpdf";
+
+    /// Creates a ditherer that always generates the same noise, e.g. for reproducible tests.
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(SmallRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(cached_rng: SmallRng) -> Self {
+        Self {
+            cached_rng,
+            // 1/2 LSB RMS needed to linearize the response:
+            distribution: Normal::new(0.0, 0.5).unwrap(),
+        }
+    }
 }
 
 pub struct HighPassDitherer {
@@ -109,12 +127,7 @@ pub struct HighPassDitherer {
 
 impl Ditherer for HighPassDitherer {
     fn new() -> Self {
-        Self {
-            active_channel: 0,
-            previous_noises: [0.0; NUM_CHANNELS as usize],
-            cached_rng: create_rng(),
-            distribution: Uniform::new_inclusive(-0.5, 0.5), // 1 LSB +/- 1 LSB (previous) = 2 LSB
-        }
+        Self::with_rng(create_rng())
     }
 
     fn name(&self) -> &'static str {
@@ -132,6 +145,20 @@ impl Ditherer for HighPassDitherer {
 
 impl HighPassDitherer {
     pub const NAME: &'static str = "tpdf_hp";
+
+    /// Creates a ditherer that always generates the same noise, e.g. for reproducible tests.
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(SmallRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(cached_rng: SmallRng) -> Self {
+        Self {
+            active_channel: 0,
+            previous_noises: [0.0; NUM_CHANNELS as usize],
+            cached_rng,
+            distribution: Uniform::new_inclusive(-0.5, 0.5), // 1 LSB +/- 1 LSB (previous) = 2 LSB
+        }
+    }
 }
 
 pub fn mk_ditherer<D: Ditherer + 'static>() -> Box<dyn Ditherer> {
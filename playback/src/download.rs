@@ -0,0 +1,440 @@
+//! Downloads of tracks and episodes for playback without a connection.
+//!
+//! A download keeps the complete encrypted audio file in the audio cache, pinned so that it
+//! is never evicted, together with its audio key and the metadata that loading it needs.
+//! The player then loads it like any other cached track, also when it is offline.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::{
+    audio::download_to_cache,
+    config::Bitrate,
+    core::{
+        cache::Cache,
+        error::{ErrorCode, ErrorKind},
+        Error, FileId, Session, SpotifyId,
+    },
+    metadata::audio::AudioItem,
+    resolve::{find_available_alternative, select_file, ResolveError},
+};
+
+// The metadata key of the downloads, with a line `{uri}\t{played uri}\t{file id}` per
+// download. The played URI differs when an alternative is played instead.
+const DOWNLOADS_KEY: &str = "offline-downloads";
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("downloads need a cache for audio files and metadata")]
+    NoCache,
+    #[error("no audio key for <{0}>")]
+    NoAudioKey(String),
+}
+
+impl From<DownloadError> for Error {
+    fn from(err: DownloadError) -> Self {
+        match err {
+            DownloadError::NoCache => Error::with_code(ErrorKind::FailedPrecondition, err),
+            DownloadError::NoAudioKey(_) => Error::with_code(ErrorKind::Unavailable, err),
+        }
+    }
+}
+
+impl ErrorCode for DownloadError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::NoCache => "download.no_cache",
+            Self::NoAudioKey(_) => "download.no_audio_key",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadState {
+    NotDownloaded,
+    Downloading,
+    Downloaded,
+    /// The last download failed. It is not retried until it is pinned again.
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Download {
+    played_id: SpotifyId,
+    file_id: FileId,
+}
+
+#[derive(Default)]
+struct DownloadsInner {
+    downloaded: HashMap<SpotifyId, Download>,
+    downloading: HashSet<SpotifyId>,
+    failed: HashSet<SpotifyId>,
+}
+
+/// Downloads tracks and episodes for offline playback, in the bitrate that the player plays.
+/// Which items are downloaded is kept in the cache, so it persists across restarts.
+///
+/// This needs a premium account, like streaming does.
+#[derive(Clone)]
+pub struct DownloadManager {
+    session: Session,
+    bitrate: Bitrate,
+    inner: Arc<Mutex<DownloadsInner>>,
+}
+
+impl DownloadManager {
+    pub fn new(session: Session, bitrate: Bitrate) -> Result<Self, Error> {
+        let cache = session.cache().ok_or(DownloadError::NoCache)?;
+        let downloaded = Self::read_downloads(cache);
+
+        Ok(Self {
+            session,
+            bitrate,
+            inner: Arc::new(Mutex::new(DownloadsInner {
+                downloaded,
+                ..Default::default()
+            })),
+        })
+    }
+
+    fn cache(&self) -> Result<&Cache, Error> {
+        let cache = self.session.cache().ok_or(DownloadError::NoCache)?;
+        Ok(cache.as_ref())
+    }
+
+    fn read_downloads(cache: &Cache) -> HashMap<SpotifyId, Download> {
+        let contents = cache.metadata(DOWNLOADS_KEY).unwrap_or_default();
+        String::from_utf8_lossy(&contents)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let id = SpotifyId::from_uri(fields.next()?).ok()?;
+                let played_id = SpotifyId::from_uri(fields.next()?).ok()?;
+                let file_id = FileId::from_base16(fields.next()?).ok()?;
+                Some((id, Download { played_id, file_id }))
+            })
+            .collect()
+    }
+
+    fn save_downloads(&self, downloaded: &HashMap<SpotifyId, Download>) -> Result<(), Error> {
+        let mut contents = String::new();
+        for (id, download) in downloaded {
+            contents += &format!(
+                "{}\t{}\t{}\n",
+                id.to_uri()?,
+                download.played_id.to_uri()?,
+                download.file_id.to_base16()?
+            );
+        }
+        self.cache()?
+            .save_metadata(DOWNLOADS_KEY, contents.as_bytes());
+        Ok(())
+    }
+
+    /// Downloads a track or episode and keeps it until it is unpinned. Returns when the
+    /// download is complete, or right away if it is downloaded or being downloaded already.
+    ///
+    /// This method is cancel safe: when the returned future is dropped, the download is
+    /// abandoned and nothing of it is kept, so it can be pinned again.
+    pub async fn pin(&self, uri: &str) -> Result<(), Error> {
+        let id = SpotifyId::from_uri(uri)?;
+        {
+            let mut inner = self.inner.lock();
+            if inner.downloaded.contains_key(&id) || !inner.downloading.insert(id) {
+                return Ok(());
+            }
+            inner.failed.remove(&id);
+        }
+        let _downloading = Downloading {
+            inner: &self.inner,
+            id,
+        };
+
+        let result = self.download(id).await;
+
+        let mut inner = self.inner.lock();
+        match result {
+            Ok(download) => {
+                info!("Downloaded <{}> for offline playback", uri);
+                inner.downloaded.insert(id, download);
+                self.save_downloads(&inner.downloaded)
+            }
+            Err(e) => {
+                warn!("Unable to download <{}>: {}", uri, e);
+                inner.failed.insert(id);
+                Err(e)
+            }
+        }
+    }
+
+    async fn download(&self, id: SpotifyId) -> Result<Download, Error> {
+        let cache = self.cache()?;
+        let uri = id.to_uri()?;
+
+        let audio_item = AudioItem::get_file(&self.session, id).await?;
+        let audio_item = find_available_alternative(&self.session, audio_item)
+            .await
+            .ok_or_else(|| ResolveError::Unavailable(uri.clone()))?;
+        let (_, file_id) = select_file(&audio_item, self.bitrate)
+            .ok_or_else(|| ResolveError::NoSupportedFormat(audio_item.name.clone()))?;
+
+        // the key is requested the same way as when loading, with the requested item
+        let key = self
+            .session
+            .audio_key()
+            .request(id, file_id)
+            .await
+            .map_err(|_| DownloadError::NoAudioKey(uri))?;
+
+        let download = Download {
+            played_id: audio_item.track_id,
+            file_id,
+        };
+        let mut rollback = Rollback {
+            manager: self,
+            id,
+            download,
+            done: false,
+        };
+
+        let spclient = self.session.spclient();
+        spclient
+            .save_offline_metadata(id.item_type.into(), &id)
+            .await?;
+        if download.played_id != id {
+            spclient
+                .save_offline_metadata(download.played_id.item_type.into(), &download.played_id)
+                .await?;
+        }
+
+        download_to_cache(&self.session, file_id).await?;
+        cache.save_audio_key(file_id, key);
+        rollback.done = true;
+
+        Ok(download)
+    }
+
+    // Removes what a download kept in the cache. Its audio file, key and the metadata of the
+    // alternative may be `shared` with another download, which keeps them.
+    fn remove_files(&self, id: SpotifyId, download: &Download, shared: bool) -> Result<(), Error> {
+        let cache = self.cache()?;
+        let spclient = self.session.spclient();
+        spclient.remove_offline_metadata(id.item_type.into(), &id)?;
+        if !shared {
+            if download.played_id != id {
+                spclient.remove_offline_metadata(
+                    download.played_id.item_type.into(),
+                    &download.played_id,
+                )?;
+            }
+            cache.remove_audio_key(download.file_id);
+            cache.unpin_file(download.file_id)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_shared(&self, download: &Download) -> bool {
+        self.inner
+            .lock()
+            .downloaded
+            .values()
+            .any(|other| other.file_id == download.file_id)
+    }
+
+    /// Removes a download, and lets its audio file be evicted from the cache again.
+    pub fn unpin(&self, uri: &str) -> Result<(), Error> {
+        let id = SpotifyId::from_uri(uri)?;
+        let mut inner = self.inner.lock();
+        inner.failed.remove(&id);
+        let download = match inner.downloaded.remove(&id) {
+            Some(download) => download,
+            None => return Ok(()),
+        };
+        self.save_downloads(&inner.downloaded)?;
+        drop(inner);
+
+        // another download may play the same alternative
+        self.remove_files(id, &download, self.is_shared(&download))
+    }
+
+    pub fn state(&self, uri: &str) -> Result<DownloadState, Error> {
+        let id = SpotifyId::from_uri(uri)?;
+        Ok(self.inner.lock().state(&id))
+    }
+
+    /// The items that are downloaded, being downloaded or failed to download, in no
+    /// particular order.
+    pub fn downloads(&self) -> Vec<(SpotifyId, DownloadState)> {
+        let inner = self.inner.lock();
+        inner
+            .downloaded
+            .keys()
+            .chain(&inner.downloading)
+            .chain(&inner.failed)
+            .map(|id| (*id, inner.state(id)))
+            .collect()
+    }
+}
+
+// Marks an item as being downloaded until it is dropped, also when the download is cancelled.
+struct Downloading<'a> {
+    inner: &'a Mutex<DownloadsInner>,
+    id: SpotifyId,
+}
+
+impl Drop for Downloading<'_> {
+    fn drop(&mut self) {
+        self.inner.lock().downloading.remove(&self.id);
+    }
+}
+
+// Removes what a download saved in the cache when it fails or is cancelled before it is done.
+struct Rollback<'a> {
+    manager: &'a DownloadManager,
+    id: SpotifyId,
+    download: Download,
+    done: bool,
+}
+
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let shared = self.manager.is_shared(&self.download);
+        if let Err(e) = self.manager.remove_files(self.id, &self.download, shared) {
+            warn!(
+                "Unable to remove the incomplete download of <{}>: {}",
+                self.id, e
+            );
+        }
+    }
+}
+
+impl DownloadsInner {
+    fn state(&self, id: &SpotifyId) -> DownloadState {
+        if self.downloaded.contains_key(id) {
+            DownloadState::Downloaded
+        } else if self.downloading.contains(id) {
+            DownloadState::Downloading
+        } else if self.failed.contains(id) {
+            DownloadState::Failed
+        } else {
+            DownloadState::NotDownloaded
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::core::{audio_key::AudioKey, config::SessionConfig};
+
+    fn manager(name: &str, runtime: &Runtime) -> (DownloadManager, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "librespot-download-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let cache = Cache::new(None, Some(&dir), Some(&dir), None).unwrap();
+        let _runtime = runtime.enter();
+        let session = Session::new(SessionConfig::default(), Some(cache));
+        (
+            DownloadManager::new(session, Bitrate::default()).unwrap(),
+            dir,
+        )
+    }
+
+    fn download(file_id: u8) -> (SpotifyId, Download) {
+        let id = SpotifyId::from_uri("spotify:track:4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let download = Download {
+            played_id: id,
+            file_id: FileId::try_from(&[file_id; 20][..]).unwrap(),
+        };
+        (id, download)
+    }
+
+    #[test]
+    fn cancelled_downloads_are_forgotten() {
+        let runtime = Runtime::new().unwrap();
+        let (manager, dir) = manager("cancelled", &runtime);
+        let (id, _) = download(1);
+
+        manager.inner.lock().downloading.insert(id);
+        let downloading = Downloading {
+            inner: &manager.inner,
+            id,
+        };
+        assert_eq!(manager.inner.lock().state(&id), DownloadState::Downloading);
+        drop(downloading);
+        let state = manager.inner.lock().state(&id);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(state, DownloadState::NotDownloaded);
+    }
+
+    #[test]
+    fn incomplete_downloads_are_rolled_back() {
+        let runtime = Runtime::new().unwrap();
+        let (manager, dir) = manager("rollback", &runtime);
+        let cache = manager.cache().unwrap();
+        let (id, download) = download(2);
+
+        cache.pin_file(download.file_id).unwrap();
+        cache.save_audio_key(download.file_id, AudioKey([0; 16]));
+        drop(Rollback {
+            manager: &manager,
+            id,
+            download,
+            done: false,
+        });
+        let pinned = cache.is_file_pinned(download.file_id);
+        let key = cache.audio_key(download.file_id);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(!pinned);
+        assert!(key.is_none());
+    }
+
+    #[test]
+    fn rollback_keeps_shared_and_complete_downloads() {
+        let runtime = Runtime::new().unwrap();
+        let (manager, dir) = manager("shared", &runtime);
+        let cache = manager.cache().unwrap();
+        let (id, download) = download(3);
+
+        cache.pin_file(download.file_id).unwrap();
+        drop(Rollback {
+            manager: &manager,
+            id,
+            download,
+            done: true,
+        });
+        let kept_when_done = cache.is_file_pinned(download.file_id);
+
+        let other = SpotifyId::from_uri("spotify:track:6rqhFgbbKwnb9MLmUQDhG6").unwrap();
+        manager.inner.lock().downloaded.insert(other, download);
+        drop(Rollback {
+            manager: &manager,
+            id,
+            download,
+            done: false,
+        });
+        let kept_when_shared = cache.is_file_pinned(download.file_id);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(kept_when_done);
+        assert!(kept_when_shared);
+    }
+}
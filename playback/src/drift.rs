@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant};
+
+/// The largest correction that is applied, in parts per million.
+pub const MAX_CORRECTION_PPM: f64 = 50.0;
+
+// How long the buffer level is averaged before it is taken as the level to keep.
+const SETTLE_TIME: Duration = Duration::from_secs(10);
+// The weight of a new measurement in the average buffer level.
+const SMOOTHING: f64 = 0.01;
+// The correction per frame that the buffer level is off, in parts per million.
+const PPM_PER_FRAME: f64 = 0.1;
+// How much the correction has to change before it is applied, so that the resampler
+// doesn't follow every bit of jitter.
+const HYSTERESIS_PPM: f64 = 1.0;
+
+/// Detects when the clock of the sink drifts from the sample rate of the audio written to
+/// it, by watching how many frames are buffered in the sink. Over a long session even a
+/// small drift drains or overflows the buffer, which is heard as a glitch every few hours.
+///
+/// The level the buffer settles at after starting is kept by resampling the audio slightly,
+/// up to [`MAX_CORRECTION_PPM`].
+#[derive(Debug, Default)]
+pub struct DriftWatchdog {
+    started_at: Option<Instant>,
+    // the sum and number of the levels while settling
+    settling_sum: f64,
+    settling_count: usize,
+    // the average number of buffered frames, once settled
+    level: Option<f64>,
+    // the level to keep, once settled
+    target: Option<f64>,
+    correction_ppm: f64,
+}
+
+impl DriftWatchdog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts over, e.g. because the sink was restarted or switched to another sample rate.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn correction_ppm(&self) -> f64 {
+        self.correction_ppm
+    }
+
+    /// Takes the number of frames buffered in the sink after a write, and returns the
+    /// correction to resample with, in parts per million. It is positive when the buffer
+    /// fills up, so that fewer frames are written.
+    pub fn update(&mut self, buffered_frames: usize) -> f64 {
+        self.update_at(buffered_frames, Instant::now())
+    }
+
+    fn update_at(&mut self, buffered_frames: usize, now: Instant) -> f64 {
+        let started_at = *self.started_at.get_or_insert(now);
+        let buffered_frames = buffered_frames as f64;
+
+        let target = match self.target {
+            Some(target) => target,
+            None => {
+                // The buffer fills up while starting, so only the level in the second half
+                // of the settle time is averaged.
+                let elapsed = now.duration_since(started_at);
+                if elapsed >= SETTLE_TIME / 2 {
+                    self.settling_sum += buffered_frames;
+                    self.settling_count += 1;
+                }
+                if elapsed < SETTLE_TIME {
+                    return self.correction_ppm;
+                }
+
+                let target = self.settling_sum / self.settling_count as f64;
+                debug!("Keeping the sink buffered at {:.0} frames", target);
+                self.target = Some(target);
+                target
+            }
+        };
+
+        let level = match self.level {
+            Some(level) => level + (buffered_frames - level) * SMOOTHING,
+            None => target,
+        };
+        self.level = Some(level);
+
+        let correction_ppm =
+            ((level - target) * PPM_PER_FRAME).clamp(-MAX_CORRECTION_PPM, MAX_CORRECTION_PPM);
+        if (correction_ppm - self.correction_ppm).abs() >= HYSTERESIS_PPM {
+            debug!(
+                "Correcting clock drift of the sink by {:.0} ppm",
+                correction_ppm
+            );
+            self.correction_ppm = correction_ppm;
+        }
+        self.correction_ppm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds the buffer levels at 10 updates per second, and returns the last correction.
+    fn feed(
+        watchdog: &mut DriftWatchdog,
+        start: Instant,
+        from: Duration,
+        levels: impl IntoIterator<Item = usize>,
+    ) -> f64 {
+        let mut correction_ppm = watchdog.correction_ppm();
+        for (i, level) in levels.into_iter().enumerate() {
+            let now = start + from + Duration::from_millis(100 * i as u64);
+            correction_ppm = watchdog.update_at(level, now);
+        }
+        correction_ppm
+    }
+
+    #[test]
+    fn settles_before_correcting() {
+        let start = Instant::now();
+        let mut watchdog = DriftWatchdog::new();
+
+        // The buffer fills up while starting, which is no drift.
+        let filling = (0..20).map(|i| i * 200);
+        let levels = filling.chain(std::iter::repeat(4000).take(80));
+        assert_eq!(feed(&mut watchdog, start, Duration::ZERO, levels), 0.0);
+        assert!(watchdog.target.is_none());
+
+        let ppm = feed(&mut watchdog, start, SETTLE_TIME, [4000; 1000]);
+        assert!(watchdog.target.is_some());
+        assert!(ppm.abs() < HYSTERESIS_PPM, "{ppm}");
+    }
+
+    #[test]
+    fn corrects_a_filling_and_a_draining_buffer() {
+        let start = Instant::now();
+        let mut watchdog = DriftWatchdog::new();
+        feed(&mut watchdog, start, Duration::ZERO, [4000; 101]);
+        let target = watchdog.target.unwrap();
+        assert_eq!(target, 4000.0);
+
+        let filling = feed(&mut watchdog, start, SETTLE_TIME, [4200; 2000]);
+        assert!((10.0..=20.0).contains(&filling), "{filling}");
+
+        let draining = feed(&mut watchdog, start, SETTLE_TIME * 30, [3800; 2000]);
+        assert!((-20.0..=-10.0).contains(&draining), "{draining}");
+    }
+
+    #[test]
+    fn limits_the_correction() {
+        let start = Instant::now();
+        let mut watchdog = DriftWatchdog::new();
+        feed(&mut watchdog, start, Duration::ZERO, [4000; 101]);
+
+        let ppm = feed(&mut watchdog, start, SETTLE_TIME, [40000; 2000]);
+        assert_eq!(ppm, MAX_CORRECTION_PPM);
+    }
+
+    #[test]
+    fn ignores_jitter() {
+        let start = Instant::now();
+        let mut watchdog = DriftWatchdog::new();
+        feed(&mut watchdog, start, Duration::ZERO, [4000; 101]);
+
+        let jitter = (0..2000).map(|i| if i % 2 == 0 { 4009 } else { 3991 });
+        assert_eq!(feed(&mut watchdog, start, SETTLE_TIME, jitter), 0.0);
+    }
+
+    #[test]
+    fn starts_over_on_reset() {
+        let start = Instant::now();
+        let mut watchdog = DriftWatchdog::new();
+        feed(&mut watchdog, start, Duration::ZERO, [4000; 101]);
+        feed(&mut watchdog, start, SETTLE_TIME, [4200; 2000]);
+        assert!(watchdog.correction_ppm() > 0.0);
+
+        watchdog.reset();
+        assert_eq!(watchdog.correction_ppm(), 0.0);
+        assert_eq!(
+            feed(&mut watchdog, start, SETTLE_TIME * 30, [8000; 10]),
+            0.0
+        );
+        assert!(watchdog.target.is_none());
+    }
+}
@@ -5,13 +5,22 @@ use librespot_audio as audio;
 use librespot_core as core;
 use librespot_metadata as metadata;
 
+pub mod adaptive;
+pub mod announcement;
 pub mod audio_backend;
 pub mod config;
+pub mod content_policy;
 pub mod convert;
 pub mod decoder;
 pub mod dither;
+pub mod download;
+pub mod drift;
 pub mod mixer;
+pub mod normaliser;
 pub mod player;
+pub mod prefetch;
+pub mod resampler;
+pub mod resolve;
 
 pub const SAMPLE_RATE: u32 = 44100;
 pub const NUM_CHANNELS: u8 = 2;
@@ -0,0 +1,226 @@
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use super::{MappedCtrl, VolumeCtrl};
+use super::{Mixer, MixerConfig};
+
+use crate::core::Error;
+
+/// A volume control outside of the audio path that moves in steps, like a motorized
+/// potentiometer or an amplifier that is controlled by infrared.
+pub trait VolumeActuator: Send + Sync {
+    fn open(config: &MixerConfig) -> Self
+    where
+        Self: Sized;
+
+    /// The lowest and the highest step, e.g. the end positions of a potentiometer.
+    fn bounds(&self) -> RangeInclusive<u32>;
+
+    /// Moves to `step`, which is within [`bounds`](Self::bounds).
+    fn set_step(&self, step: u32) -> Result<(), Error>;
+
+    /// The step the control is at, which may have been changed by hand.
+    fn get_step(&self) -> Result<u32, Error>;
+}
+
+/// Controls the volume with a [`VolumeActuator`], spreading the Connect volume over its
+/// steps like the soft mixer spreads it over the amplitude: with `volume_ctrl`. For a
+/// potentiometer with a linear taper, use a logarithmic control; for an amplifier with steps
+/// of a dB each, use a linear one.
+///
+/// The volume is read back from the actuator, so that the Connect volume picks up changes
+/// made by hand when it is synchronized with the mixer, e.g. when playback resumes.
+pub struct ActuatorMixer<A: VolumeActuator> {
+    actuator: A,
+    volume_ctrl: VolumeCtrl,
+    // the last volume that was set, for when the actuator can't be read
+    volume: AtomicU16,
+}
+
+impl<A: VolumeActuator> ActuatorMixer<A> {
+    pub fn new(actuator: A, volume_ctrl: VolumeCtrl) -> Self {
+        let bounds = actuator.bounds();
+        info!(
+            "Mixing with a volume actuator with steps {}..={} and volume control: {:?}",
+            bounds.start(),
+            bounds.end(),
+            volume_ctrl
+        );
+
+        let mixer = Self {
+            actuator,
+            volume_ctrl,
+            volume: AtomicU16::new(0),
+        };
+        let volume = mixer.volume();
+        mixer.volume.store(volume, Ordering::Relaxed);
+        mixer
+    }
+
+    pub fn actuator(&self) -> &A {
+        &self.actuator
+    }
+
+    fn to_step(&self, volume: u16) -> u32 {
+        let (min, max) = self.actuator.bounds().into_inner();
+        if max <= min {
+            return min;
+        }
+
+        let mapped_volume = self.volume_ctrl.to_mapped(volume);
+        min + ((max - min) as f64 * mapped_volume).round() as u32
+    }
+
+    fn to_volume(&self, step: u32) -> u16 {
+        let (min, max) = self.actuator.bounds().into_inner();
+        if max <= min {
+            return VolumeCtrl::MAX_VOLUME;
+        }
+
+        let mapped_volume = (step.clamp(min, max) - min) as f64 / (max - min) as f64;
+        self.volume_ctrl.as_unmapped(mapped_volume)
+    }
+}
+
+impl<A: VolumeActuator> Mixer for ActuatorMixer<A> {
+    fn open(config: MixerConfig) -> Self {
+        Self::new(A::open(&config), config.volume_ctrl)
+    }
+
+    fn volume(&self) -> u16 {
+        match self.actuator.get_step() {
+            Ok(step) => {
+                if !self.actuator.bounds().contains(&step) {
+                    warn!("Volume actuator reported step {} out of its bounds", step);
+                }
+                self.to_volume(step)
+            }
+            Err(e) => {
+                warn!("Unable to read the step of the volume actuator: {}", e);
+                self.volume.load(Ordering::Relaxed)
+            }
+        }
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let step = self.to_step(volume);
+        debug!("Moving volume actuator to step {}", step);
+        match self.actuator.set_step(step) {
+            Ok(()) => self.volume.store(volume, Ordering::Relaxed),
+            Err(e) => warn!("Unable to move the volume actuator to step {}: {}", step, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Mutex};
+
+    use super::*;
+
+    // A potentiometer from step 10 to step 30.
+    struct Potentiometer {
+        step: Mutex<u32>,
+        broken: AtomicBool,
+    }
+
+    impl VolumeActuator for Potentiometer {
+        fn open(_: &MixerConfig) -> Self {
+            Self {
+                step: Mutex::new(20),
+                broken: AtomicBool::new(false),
+            }
+        }
+
+        fn bounds(&self) -> RangeInclusive<u32> {
+            10..=30
+        }
+
+        fn set_step(&self, step: u32) -> Result<(), Error> {
+            if self.broken.load(Ordering::Relaxed) {
+                return Err(Error::unavailable("stuck"));
+            }
+            *self.step.lock().unwrap() = step;
+            Ok(())
+        }
+
+        fn get_step(&self) -> Result<u32, Error> {
+            if self.broken.load(Ordering::Relaxed) {
+                return Err(Error::unavailable("stuck"));
+            }
+            Ok(*self.step.lock().unwrap())
+        }
+    }
+
+    fn mixer(volume_ctrl: VolumeCtrl) -> ActuatorMixer<Potentiometer> {
+        ActuatorMixer::open(MixerConfig {
+            volume_ctrl,
+            ..MixerConfig::default()
+        })
+    }
+
+    fn step(mixer: &ActuatorMixer<Potentiometer>) -> u32 {
+        mixer.actuator().get_step().unwrap()
+    }
+
+    #[test]
+    fn spreads_the_volume_over_the_steps() {
+        let mixer = mixer(VolumeCtrl::Linear);
+        assert_eq!(mixer.volume(), VolumeCtrl::MAX_VOLUME / 2);
+
+        mixer.set_volume(0);
+        assert_eq!(step(&mixer), 10);
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME);
+        assert_eq!(step(&mixer), 30);
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME / 4);
+        assert_eq!(step(&mixer), 15);
+        assert_eq!(mixer.volume(), VolumeCtrl::MAX_VOLUME / 4);
+    }
+
+    #[test]
+    fn maps_the_volume_with_the_volume_control() {
+        let mixer = mixer(VolumeCtrl::Log(60.0));
+
+        mixer.set_volume(0);
+        assert_eq!(step(&mixer), 10);
+        assert_eq!(mixer.volume(), 0);
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME);
+        assert_eq!(step(&mixer), 30);
+        assert_eq!(mixer.volume(), VolumeCtrl::MAX_VOLUME);
+
+        // half of the volume is far below half of the steps, at -30 dB
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME / 2);
+        assert_eq!(step(&mixer), 11);
+
+        let mut last = 0;
+        for volume in (0..=VolumeCtrl::MAX_VOLUME).step_by(4096) {
+            mixer.set_volume(volume);
+            assert!(step(&mixer) >= last);
+            last = step(&mixer);
+        }
+    }
+
+    #[test]
+    fn picks_up_changes_made_by_hand() {
+        let mixer = mixer(VolumeCtrl::Linear);
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME);
+
+        *mixer.actuator().step.lock().unwrap() = 10;
+        assert_eq!(mixer.volume(), 0);
+        // and clamps steps out of the bounds
+        *mixer.actuator().step.lock().unwrap() = 40;
+        assert_eq!(mixer.volume(), VolumeCtrl::MAX_VOLUME);
+    }
+
+    #[test]
+    fn remembers_the_volume_when_the_actuator_fails() {
+        let mixer = mixer(VolumeCtrl::Linear);
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME / 4);
+
+        mixer.actuator().broken.store(true, Ordering::Relaxed);
+        assert_eq!(mixer.volume(), VolumeCtrl::MAX_VOLUME / 4);
+        // a volume that couldn't be set isn't reported either
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME);
+        assert_eq!(mixer.volume(), VolumeCtrl::MAX_VOLUME / 4);
+    }
+}
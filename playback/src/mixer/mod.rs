@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use crate::config::VolumeCtrl;
 
+pub mod actuator;
 pub mod mappings;
 use self::mappings::MappedCtrl;
 
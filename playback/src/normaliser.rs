@@ -0,0 +1,202 @@
+//! Volume normalisation and attenuation of decoded samples.
+
+use crate::{
+    config::{NormalisationMethod, PlayerConfig},
+    player::{db_to_ratio, ratio_to_db},
+    SAMPLE_RATE,
+};
+
+/// Applies normalisation and volume attenuation to decoded samples. The state of the
+/// dynamic limiter carries over from one packet to the next.
+#[derive(Clone, Debug)]
+pub struct Normaliser {
+    integrator: f64,
+    peak: f64,
+    sample_rate: u32,
+}
+
+impl Default for Normaliser {
+    fn default() -> Self {
+        Self {
+            integrator: 0.0,
+            peak: 0.0,
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+}
+
+impl Normaliser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the sample rate of the samples to process, so that the attack and release of
+    /// the limiter take as long as configured. Starts out as [`SAMPLE_RATE`].
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Normalises `data` in place with `normalisation_factor` according to `config`, then
+    /// attenuates it by `volume`.
+    pub fn process(
+        &mut self,
+        config: &PlayerConfig,
+        data: &mut [f64],
+        normalisation_factor: f64,
+        volume: f64,
+    ) {
+        // For the basic normalisation method, a normalisation factor of 1.0 indicates that
+        // there is nothing to normalise (all samples should pass unaltered). For the
+        // dynamic method, there may still be peaks that we want to shave off.
+
+        // No matter the case we apply volume attenuation last if there is any.
+        if !config.normalisation {
+            if volume < 1.0 {
+                for sample in data.iter_mut() {
+                    *sample *= volume;
+                }
+            }
+        } else if config.normalisation_method == NormalisationMethod::Basic
+            && (normalisation_factor < 1.0 || volume < 1.0)
+        {
+            for sample in data.iter_mut() {
+                *sample *= normalisation_factor * volume;
+            }
+        } else if config.normalisation_method == NormalisationMethod::Dynamic {
+            // zero-cost shorthands
+            let threshold_db = config.normalisation_threshold_dbfs;
+            let knee_db = config.normalisation_knee_db;
+            // The coefficients are for `SAMPLE_RATE`, see `duration_to_coefficient`.
+            let (attack_cf, release_cf) = if self.sample_rate == SAMPLE_RATE {
+                (
+                    config.normalisation_attack_cf,
+                    config.normalisation_release_cf,
+                )
+            } else {
+                let exponent = SAMPLE_RATE as f64 / self.sample_rate as f64;
+                (
+                    config.normalisation_attack_cf.powf(exponent),
+                    config.normalisation_release_cf.powf(exponent),
+                )
+            };
+
+            for sample in data.iter_mut() {
+                *sample *= normalisation_factor;
+
+                // Feedforward limiter in the log domain
+                // After: Giannoulis, D., Massberg, M., & Reiss, J.D. (2012). Digital Dynamic
+                // Range Compressor Design—A Tutorial and Analysis. Journal of The Audio
+                // Engineering Society, 60, 399-408.
+
+                // Some tracks have samples that are precisely 0.0. That's silence
+                // and we know we don't need to limit that, in which we can spare
+                // the CPU cycles.
+                //
+                // Also, calling `ratio_to_db(0.0)` returns `inf` and would get the
+                // peak detector stuck. Also catch the unlikely case where a sample
+                // is decoded as `NaN` or some other non-normal value.
+                let limiter_db = if sample.is_normal() {
+                    // step 1-4: half-wave rectification and conversion into dB
+                    // and gain computer with soft knee and subtractor
+                    let bias_db = ratio_to_db(sample.abs()) - threshold_db;
+                    let knee_boundary_db = bias_db * 2.0;
+
+                    if knee_boundary_db < -knee_db {
+                        0.0
+                    } else if knee_boundary_db.abs() <= knee_db {
+                        // The textbook equation:
+                        // ratio_to_db(sample.abs()) - (ratio_to_db(sample.abs()) - (bias_db + knee_db / 2.0).powi(2) / (2.0 * knee_db))
+                        // Simplifies to:
+                        // ((2.0 * bias_db) + knee_db).powi(2) / (8.0 * knee_db)
+                        // Which in our case further simplifies to:
+                        // (knee_boundary_db + knee_db).powi(2) / (8.0 * knee_db)
+                        // because knee_boundary_db is 2.0 * bias_db.
+                        (knee_boundary_db + knee_db).powi(2) / (8.0 * knee_db)
+                    } else {
+                        // Textbook:
+                        // ratio_to_db(sample.abs()) - threshold_db, which is already our bias_db.
+                        bias_db
+                    }
+                } else {
+                    0.0
+                };
+
+                // Spare the CPU unless (1) the limiter is engaged, (2) we
+                // were in attack or (3) we were in release, and that attack/
+                // release wasn't finished yet.
+                if limiter_db > 0.0 || self.integrator > 0.0 || self.peak > 0.0 {
+                    // step 5: smooth, decoupled peak detector
+                    // Textbook:
+                    // release_cf * self.integrator + (1.0 - release_cf) * limiter_db
+                    // Simplifies to:
+                    // release_cf * self.integrator - release_cf * limiter_db + limiter_db
+                    self.integrator = f64::max(
+                        limiter_db,
+                        release_cf * self.integrator - release_cf * limiter_db + limiter_db,
+                    );
+                    // Textbook:
+                    // attack_cf * self.peak + (1.0 - attack_cf) * self.integrator
+                    // Simplifies to:
+                    // attack_cf * self.peak - attack_cf * self.integrator + self.integrator
+                    self.peak =
+                        attack_cf * self.peak - attack_cf * self.integrator + self.integrator;
+
+                    // step 6: make-up gain applied later (volume attenuation)
+                    // Applying the standard normalisation factor here won't work,
+                    // because there are tracks with peaks as high as 6 dB above
+                    // the default threshold, so that would clip.
+
+                    // steps 7-8: conversion into level and multiplication into gain stage
+                    *sample *= db_to_ratio(-self.peak);
+                }
+
+                *sample *= volume;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::player::duration_to_coefficient;
+
+    // The number of frames until a full scale tone is limited to within 1 dB of the
+    // threshold.
+    fn attack_frames(sample_rate: u32) -> usize {
+        let config = PlayerConfig {
+            normalisation: true,
+            normalisation_method: NormalisationMethod::Dynamic,
+            normalisation_attack_cf: duration_to_coefficient(Duration::from_millis(5)),
+            ..Default::default()
+        };
+        let threshold = db_to_ratio(config.normalisation_threshold_dbfs + 1.0);
+
+        let mut normaliser = Normaliser::new();
+        normaliser.set_sample_rate(sample_rate);
+        let mut data = vec![1.0; 2 * sample_rate as usize / 10];
+        normaliser.process(&config, &mut data, 1.0, 1.0);
+
+        data.iter().position(|sample| *sample < threshold).unwrap() / 2
+    }
+
+    #[test]
+    fn attacks_as_long_at_any_sample_rate() {
+        let at_44100 = attack_frames(44100) as f64 / 44100.0;
+        let at_96000 = attack_frames(96000) as f64 / 96000.0;
+        assert!(
+            (at_44100 - at_96000).abs() < 0.0001,
+            "{at_44100} {at_96000}"
+        );
+    }
+
+    #[test]
+    fn attenuates_by_the_volume() {
+        let config = PlayerConfig::default();
+        let mut data = vec![0.5, -0.5];
+        Normaliser::new().process(&config, &mut data, 1.0, 0.5);
+        assert_eq!(data, [0.25, -0.25]);
+    }
+}
@@ -1,13 +1,15 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     fmt,
+    fs::File,
     future::Future,
     io::{self, Read, Seek, SeekFrom},
     mem,
+    path::Path,
     pin::Pin,
     process::exit,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     task::{Context, Poll},
@@ -15,46 +17,64 @@ use std::{
     time::{Duration, Instant},
 };
 
-use futures_util::{
-    future, future::FusedFuture, stream::futures_unordered::FuturesUnordered, StreamExt,
-    TryFutureExt,
-};
-use parking_lot::Mutex;
+use futures_util::{future::FusedFuture, FutureExt};
+use parking_lot::{Condvar, Mutex};
 use symphonia::core::io::MediaSource;
-use tokio::sync::{mpsc, oneshot};
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tracing::trace_span;
 
 use crate::{
+    adaptive::BitrateAdapter,
+    announcement::{Announcement, AnnouncementMixer},
     audio::{
-        AudioDecrypt, AudioFile, StreamLoaderController, READ_AHEAD_BEFORE_PLAYBACK,
+        AudioDecrypt, AudioFile, StreamLoaderController, StreamPriority, StreamScheduler,
         READ_AHEAD_DURING_PLAYBACK,
     },
     audio_backend::Sink,
-    config::{Bitrate, NormalisationMethod, NormalisationType, PlayerConfig},
+    config::{
+        Bitrate, EventOverflowPolicy, LoadFailurePolicy, NormalisationMethod, NormalisationType,
+        PlayerConfig,
+    },
+    content_policy::{BlockReason, ContentPolicy},
     convert::Converter,
-    core::{util::SeqGenerator, Error, Session, SpotifyId},
-    decoder::{AudioDecoder, AudioPacket, AudioPacketPosition, SymphoniaDecoder},
-    metadata::audio::{AudioFileFormat, AudioFiles, AudioItem},
+    core::{
+        audio_key::AudioKey, metrics::Counter, session::SessionEvent, state::StateEvent,
+        util::SeqGenerator, Error, FileId, Session, SpotifyId,
+    },
+    decoder::{AudioDecoder, AudioPacket, AudioPacketPosition, SeekTable, SymphoniaDecoder},
+    drift::DriftWatchdog,
+    metadata::audio::{AudioFiles, AudioItem},
     mixer::VolumeGetter,
+    normaliser::Normaliser,
+    prefetch::Prefetcher,
+    resampler::Resampler,
+    resolve::{find_available_alternative, select_file, stream_data_rate, SPOTIFY_OGG_HEADER_END},
 };
 
 #[cfg(feature = "passthrough-decoder")]
 use crate::decoder::PassthroughDecoder;
 
-use crate::SAMPLES_PER_SECOND;
+use crate::{SAMPLES_PER_SECOND, SAMPLE_RATE};
 
 const PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS: u32 = 30000;
+// how much of an announcement is written at once while the music is held
+const ANNOUNCEMENT_CHUNK_FRAMES: usize = 1024;
+// how often the allowed hours of the content policy are checked while playing
+const ALLOWED_HOURS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 pub const DB_VOLTAGE_RATIO: f64 = 20.0;
 pub const PCM_AT_0DBFS: f64 = 1.0;
 
-// Spotify inserts a custom Ogg packet at the start with custom metadata values, that you would
-// otherwise expect in Vorbis comments. This packet isn't well-formed and players may balk at it.
-const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
-
 pub type PlayerResult = Result<(), Error>;
 
 pub struct Player {
-    commands: Option<mpsc::UnboundedSender<PlayerCommand>>,
+    commands: Option<PlayerCommandSender>,
     thread_handle: Option<thread::JoinHandle<()>>,
+    event_queue_capacity: usize,
+    event_overflow_policy: EventOverflowPolicy,
+    load_failure_policy: LoadFailurePolicy,
+    load_latency_metrics: Arc<Mutex<LoadLatencyMetrics>>,
+    prefetch_count: usize,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -66,26 +86,117 @@ pub enum SinkStatus {
 
 pub type SinkEventCallback = Box<dyn Fn(SinkStatus) + Send>;
 
+/// When a [`PlayThresholdCallback`] is called, at most once per played track.
+///
+/// Only the audio that the sink played counts, so pausing, seeking and skipped packets
+/// don't advance towards a threshold, and neither does audio still buffered by the sink.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PlayThreshold {
+    /// After playing this percentage of the duration of the track.
+    Percent(u8),
+    /// After playing for this long.
+    Played(Duration),
+    /// When the end of the track was reached.
+    Completed,
+}
+
+/// Called from the player thread with the track and how long it was played, e.g. to
+/// scrobble it. Should return quickly, so as not to interrupt playback.
+pub type PlayThresholdCallback = Box<dyn Fn(SpotifyId, Duration) + Send>;
+
+impl PlayThreshold {
+    fn is_reached(self, played_ms: u64, duration_ms: u32, completed: bool) -> bool {
+        match self {
+            PlayThreshold::Percent(percent) => {
+                duration_ms > 0 && played_ms * 100 >= duration_ms as u64 * percent as u64
+            }
+            PlayThreshold::Played(duration) => played_ms >= duration.as_millis() as u64,
+            PlayThreshold::Completed => completed,
+        }
+    }
+}
+
+struct PlayThresholdEntry {
+    threshold: PlayThreshold,
+    callback: PlayThresholdCallback,
+    reached: bool,
+}
+
+// Calls back the entries that weren't reached before and are now.
+fn check_play_thresholds(
+    entries: &mut [PlayThresholdEntry],
+    track_id: SpotifyId,
+    played_ms: u64,
+    duration_ms: u32,
+    completed: bool,
+) {
+    for entry in entries.iter_mut().filter(|entry| !entry.reached) {
+        entry.reached = entry
+            .threshold
+            .is_reached(played_ms, duration_ms, completed);
+        if entry.reached {
+            (entry.callback)(track_id, Duration::from_millis(played_ms));
+        }
+    }
+}
+
+// How much of what was written to the sink it played, going by the frames it still buffers.
+fn sink_played_ms(written_ms: u64, buffered_frames: Option<usize>, sample_rate: u32) -> u64 {
+    let buffered_ms = buffered_frames.map_or(0, |frames| frames as u64 * 1000 / sample_rate as u64);
+    written_ms.saturating_sub(buffered_ms)
+}
+
 struct PlayerInternal {
     session: Session,
     config: PlayerConfig,
-    commands: mpsc::UnboundedReceiver<PlayerCommand>,
+    commands: PlayerCommandReceiver,
+    session_events: mpsc::UnboundedReceiver<SessionEvent>,
     load_handles: Arc<Mutex<HashMap<thread::ThreadId, thread::JoinHandle<()>>>>,
+    stream_scheduler: Arc<StreamScheduler>,
+    prefetcher: Arc<Prefetcher>,
+    prefetch_task: Option<tokio::task::JoinHandle<()>>,
+    seek_table_scans: SeekTableScans,
 
     state: PlayerState,
     preload: PlayerPreload,
     sink: Box<dyn Sink>,
     sink_status: SinkStatus,
     sink_event_callback: Option<SinkEventCallback>,
+    // the rate the sink plays at, and the resampler to it if the current track differs
+    sink_sample_rate: u32,
+    resampler: Option<Resampler>,
+    // corrects for the clock of the sink drifting from its sample rate, when enabled
+    drift_watchdog: Option<DriftWatchdog>,
+    announcement: Option<AnnouncementMixer>,
+    // adapts the bitrate of `config` to the download throughput, when enabled
+    bitrate_adapter: Option<BitrateAdapter>,
+    play_thresholds: Vec<PlayThresholdEntry>,
+    // how much of the current track was written to the sink
+    written_ms: u64,
+    allowed_hours_checked_at: Instant,
     volume_getter: Box<dyn VolumeGetter + Send>,
-    event_senders: Vec<mpsc::UnboundedSender<PlayerEvent>>,
+    event_senders: Vec<PlayerEventSender>,
     converter: Converter,
 
-    normalisation_integrator: f64,
-    normalisation_peak: f64,
+    normaliser: Normaliser,
 
     auto_normalise_as_album: bool,
 
+    // failed attempts to load the current track, reset once it is loaded
+    load_attempts: u32,
+
+    // diagnostics of the current track
+    load_requested_at: Option<Instant>,
+    time_to_first_audio: Option<Duration>,
+    stalled_reads_at_start: usize,
+
+    // when the command that caused the current load was received, e.g. by Spirc
+    command_received_at: Option<Instant>,
+    // the loader stages of the current track and when it was loaded, until its first audio
+    // was written
+    load_latency: Option<(LoadLatency, Instant)>,
+    load_latency_metrics: Arc<Mutex<LoadLatencyMetrics>>,
+
     player_id: usize,
     play_request_id_generator: SeqGenerator<u64>,
 }
@@ -97,19 +208,25 @@ enum PlayerCommand {
         track_id: SpotifyId,
         play: bool,
         position_ms: u32,
+        command_received_at: Option<Instant>,
     },
     Preload {
         track_id: SpotifyId,
     },
+    Prefetch(Vec<SpotifyId>),
     Play,
     Pause,
     Stop,
     Seek(u32),
     SetSession(Session),
-    AddEventSender(mpsc::UnboundedSender<PlayerEvent>),
+    AddEventSender(PlayerEventSender),
     SetSinkEventCallback(Option<SinkEventCallback>),
+    AddPlayThreshold(PlayThreshold, PlayThresholdCallback),
+    ClearPlayThresholds,
     EmitVolumeChangedEvent(u16),
     SetAutoNormaliseAsAlbum(bool),
+    SetContentPolicy(ContentPolicy),
+    Announce(Announcement),
     EmitSessionDisconnectedEvent {
         connection_id: String,
         user_name: String,
@@ -125,9 +242,92 @@ enum PlayerCommand {
         client_model_name: String,
     },
     EmitFilterExplicitContentChangedEvent(bool),
-    EmitShuffleChangedEvent(bool),
+    EmitShuffleChangedEvent(bool, Option<u64>),
     EmitRepeatChangedEvent(bool),
     EmitAutoPlayChangedEvent(bool),
+    EmitContextSegmentEvent {
+        uri: String,
+        metadata: HashMap<String, String>,
+    },
+}
+
+impl PlayerCommand {
+    // Hints that the player may ignore without consequence. Only these are dropped when the
+    // player is not keeping up with its commands, everything else is always queued.
+    fn is_hint(&self) -> bool {
+        matches!(self, Self::Preload { .. } | Self::Prefetch(..))
+    }
+}
+
+fn command_channel(capacity: usize) -> (PlayerCommandSender, PlayerCommandReceiver) {
+    let (commands, receiver) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let sender = PlayerCommandSender {
+        commands,
+        queued: queued.clone(),
+        capacity: capacity.max(1),
+        dropped: AtomicU64::new(0),
+    };
+    let receiver = PlayerCommandReceiver {
+        commands: receiver,
+        queued,
+    };
+    (sender, receiver)
+}
+
+// The queue of commands is bounded by its capacity for hints only. Commands like loading,
+// seeking or stopping must not get lost, so they are queued past the capacity and wait
+// for the player to catch up.
+struct PlayerCommandSender {
+    commands: mpsc::UnboundedSender<PlayerCommand>,
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+    dropped: AtomicU64,
+}
+
+impl PlayerCommandSender {
+    // Returns `false` when the command was not queued.
+    fn send(&self, cmd: PlayerCommand) -> bool {
+        if self.queued.load(Ordering::Acquire) >= self.capacity {
+            if cmd.is_hint() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Player is not keeping up with its commands, dropping {:?}",
+                    cmd
+                );
+                return false;
+            }
+            warn!(
+                "Player is not keeping up with its commands, queueing {:?} anyway",
+                cmd
+            );
+        }
+
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        match self.commands.send(cmd) {
+            Ok(()) => true,
+            Err(e) => {
+                self.queued.fetch_sub(1, Ordering::AcqRel);
+                error!("Player Commands Error: {}", e);
+                false
+            }
+        }
+    }
+}
+
+struct PlayerCommandReceiver {
+    commands: mpsc::UnboundedReceiver<PlayerCommand>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl PlayerCommandReceiver {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<PlayerCommand>> {
+        let poll = self.commands.poll_recv(cx);
+        if let Poll::Ready(Some(_)) = poll {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+        }
+        poll
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -181,11 +381,38 @@ pub enum PlayerEvent {
         play_request_id: u64,
         track_id: SpotifyId,
     },
+    // The player was unable to load the requested track and retries after a backoff.
+    LoadRetrying {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        attempt: u32,
+        retries: u32,
+    },
     // The player was unable to load the requested track.
     Unavailable {
         play_request_id: u64,
         track_id: SpotifyId,
     },
+    /// The track was not played, or stopped, because the content policy doesn't allow it.
+    Blocked {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        reason: BlockReason,
+    },
+    // The player finished playing a track, either because it ended or because it was
+    // stopped or replaced. Reports how well it played.
+    Diagnostics {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        diagnostics: PlaybackDiagnostics,
+    },
+    // The first audio of a loaded track was written to the sink. Reports how long the
+    // stages from the command to that point took.
+    LoadLatency {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        latency: LoadLatency,
+    },
     // The mixer volume was set to a new level.
     VolumeChanged {
         volume: u16,
@@ -219,6 +446,8 @@ pub enum PlayerEvent {
     },
     ShuffleChanged {
         shuffle: bool,
+        /// The seed the tracks were shuffled with, if they were shuffled by librespot.
+        seed: Option<u64>,
     },
     RepeatChanged {
         repeat: bool,
@@ -229,6 +458,30 @@ pub enum PlayerEvent {
     FilterExplicitContentChanged {
         filter: bool,
     },
+    /// The sink plays at `sample_rate` from now on. The current track is resampled to it
+    /// if its `source_sample_rate` differs.
+    AudioFormatChanged {
+        sample_rate: u32,
+        source_sample_rate: u32,
+    },
+    /// An item of the context that isn't played but announced, like the commentary of the
+    /// DJ, was reached. It introduces the track that is loaded next.
+    ContextSegment {
+        uri: String,
+        metadata: HashMap<String, String>,
+    },
+    /// The tracks that are loaded from now on are played at `bitrate`, because the estimated
+    /// `download_throughput` in bytes per second is too low for the previous bitrate, or
+    /// high enough for a higher one.
+    BitrateChanged {
+        bitrate: Bitrate,
+        download_throughput: usize,
+    },
+    /// The music is fading out for an announcement, see [`Player::announce`].
+    AnnouncementStarted,
+    /// The announcement was played, and the music faded back in. Not sent for announcements
+    /// that were replaced by another one.
+    AnnouncementFinished,
 }
 
 impl PlayerEvent {
@@ -238,9 +491,15 @@ impl PlayerEvent {
             Loading {
                 play_request_id, ..
             }
+            | LoadRetrying {
+                play_request_id, ..
+            }
             | Unavailable {
                 play_request_id, ..
             }
+            | Blocked {
+                play_request_id, ..
+            }
             | Playing {
                 play_request_id, ..
             }
@@ -261,13 +520,262 @@ impl PlayerEvent {
             }
             | Seeked {
                 play_request_id, ..
+            }
+            | Diagnostics {
+                play_request_id, ..
+            }
+            | PlayerEvent::LoadLatency {
+                play_request_id, ..
             } => Some(*play_request_id),
             _ => None,
         }
     }
+
+    // What the event changes of the state of the device, see `Session::state`.
+    fn to_state_event(&self) -> Option<StateEvent> {
+        use PlayerEvent::*;
+        let event = match self {
+            Loading {
+                track_id,
+                position_ms,
+                ..
+            } => StateEvent::Loading {
+                track_id: *track_id,
+                position_ms: *position_ms,
+            },
+            Playing {
+                track_id,
+                position_ms,
+                ..
+            } => StateEvent::Playing {
+                track_id: *track_id,
+                position_ms: *position_ms,
+            },
+            Paused {
+                track_id,
+                position_ms,
+                ..
+            } => StateEvent::Paused {
+                track_id: *track_id,
+                position_ms: *position_ms,
+            },
+            Seeked {
+                track_id,
+                position_ms,
+                ..
+            }
+            | PositionCorrection {
+                track_id,
+                position_ms,
+                ..
+            } => StateEvent::Seeked {
+                track_id: *track_id,
+                position_ms: *position_ms,
+            },
+            Stopped { .. } => StateEvent::Stopped,
+            TrackChanged { audio_item } => StateEvent::TrackChanged {
+                track_id: audio_item.track_id,
+                name: audio_item.name.clone(),
+                duration_ms: audio_item.duration_ms,
+            },
+            VolumeChanged { volume } => StateEvent::VolumeChanged { volume: *volume },
+            ShuffleChanged { shuffle, .. } => StateEvent::ShuffleChanged { shuffle: *shuffle },
+            RepeatChanged { repeat } => StateEvent::RepeatChanged { repeat: *repeat },
+            SessionConnected { user_name, .. } => StateEvent::Activated {
+                username: user_name.clone(),
+            },
+            SessionDisconnected { .. } => StateEvent::Deactivated,
+            SessionClientChanged {
+                client_id,
+                client_name,
+                ..
+            } => StateEvent::ClientChanged {
+                client_id: client_id.clone(),
+                client_name: client_name.clone(),
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+
+    // Events that only report the latest value of some state, so that a newer event
+    // of the same kind makes an older one that is still queued redundant.
+    fn supersedes(&self, older: &PlayerEvent) -> bool {
+        mem::discriminant(self) == mem::discriminant(older) && self.reports_state()
+    }
+
+    fn reports_state(&self) -> bool {
+        use PlayerEvent::*;
+        matches!(
+            self,
+            PlayRequestIdChanged { .. }
+                | VolumeChanged { .. }
+                | PositionCorrection { .. }
+                | ShuffleChanged { .. }
+                | RepeatChanged { .. }
+                | AutoPlayChanged { .. }
+                | FilterExplicitContentChanged { .. }
+                | AudioFormatChanged { .. }
+                | BitrateChanged { .. }
+        )
+    }
+}
+
+#[derive(Default)]
+struct PlayerEventQueueState {
+    events: VecDeque<PlayerEvent>,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+struct PlayerEventQueue {
+    state: Mutex<PlayerEventQueueState>,
+    capacity: usize,
+    policy: EventOverflowPolicy,
+    dropped: AtomicU64,
+    // Signalled whenever an event is pushed or popped, or either side is closed.
+    changed: Condvar,
+    readable: Notify,
+}
+
+impl PlayerEventQueue {
+    fn new(capacity: usize, policy: EventOverflowPolicy) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(PlayerEventQueueState::default()),
+            capacity: capacity.max(1),
+            policy,
+            dropped: AtomicU64::new(0),
+            changed: Condvar::new(),
+            readable: Notify::new(),
+        })
+    }
+
+    fn notify(&self) {
+        self.changed.notify_all();
+        self.readable.notify_one();
+    }
+}
+
+struct PlayerEventSender(Arc<PlayerEventQueue>);
+
+impl PlayerEventSender {
+    // Returns `false` when the receiving end has gone away.
+    fn send(&self, event: PlayerEvent) -> bool {
+        let queue = &self.0;
+        let mut state = queue.state.lock();
+
+        if state.events.len() >= queue.capacity && !state.receiver_closed {
+            match queue.policy {
+                EventOverflowPolicy::Block => {
+                    while state.events.len() >= queue.capacity && !state.receiver_closed {
+                        queue.changed.wait(&mut state);
+                    }
+                }
+                EventOverflowPolicy::Coalesce => {
+                    if let Some(index) = state
+                        .events
+                        .iter()
+                        .position(|queued| event.supersedes(queued))
+                    {
+                        state.events.remove(index);
+                    } else if let Some(index) =
+                        state.events.iter().position(PlayerEvent::reports_state)
+                    {
+                        state.events.remove(index);
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        while state.events.len() >= queue.capacity && !state.receiver_closed {
+                            queue.changed.wait(&mut state);
+                        }
+                    }
+                }
+                EventOverflowPolicy::DropOldest => {
+                    state.events.pop_front();
+                    queue.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        if state.receiver_closed {
+            return false;
+        }
+
+        state.events.push_back(event);
+        drop(state);
+        queue.notify();
+
+        true
+    }
+}
+
+impl Drop for PlayerEventSender {
+    fn drop(&mut self) {
+        self.0.state.lock().sender_closed = true;
+        self.0.notify();
+    }
+}
+
+/// The receiving end of the events emitted by a [`Player`].
+///
+/// Each channel has its own bounded queue. What happens when a consumer falls
+/// behind and its queue fills up is decided by its [`EventOverflowPolicy`].
+pub struct PlayerEventChannel(Arc<PlayerEventQueue>);
+
+impl PlayerEventChannel {
+    fn try_recv(&self) -> Option<Option<PlayerEvent>> {
+        let mut state = self.0.state.lock();
+        match state.events.pop_front() {
+            Some(event) => {
+                drop(state);
+                self.0.changed.notify_all();
+                Some(Some(event))
+            }
+            None if state.sender_closed => Some(None),
+            None => None,
+        }
+    }
+
+    /// Receives the next event, or `None` when the player has shut down.
+    ///
+    /// This method is cancel safe: no events are lost when it is used in `tokio::select!`.
+    pub async fn recv(&mut self) -> Option<PlayerEvent> {
+        loop {
+            if let Some(event) = self.try_recv() {
+                return event;
+            }
+            self.0.readable.notified().await;
+        }
+    }
+
+    /// Blocking variant of [`PlayerEventChannel::recv`]. Must not be called from within
+    /// an async execution context.
+    pub fn blocking_recv(&mut self) -> Option<PlayerEvent> {
+        let mut state = self.0.state.lock();
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                drop(state);
+                self.0.changed.notify_all();
+                return Some(event);
+            }
+            if state.sender_closed {
+                return None;
+            }
+            self.0.changed.wait(&mut state);
+        }
+    }
+
+    /// Returns the number of events that were discarded because this channel was full.
+    pub fn dropped_events(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
 }
 
-pub type PlayerEventChannel = mpsc::UnboundedReceiver<PlayerEvent>;
+impl Drop for PlayerEventChannel {
+    fn drop(&mut self) {
+        self.0.state.lock().receiver_closed = true;
+        self.0.notify();
+    }
+}
 
 pub fn db_to_ratio(db: f64) -> f64 {
     f64::powf(10.0, db / DB_VOLTAGE_RATIO)
@@ -285,6 +793,95 @@ pub fn coefficient_to_duration(coefficient: f64) -> Duration {
     Duration::from_secs_f64(-1.0 / f64::ln(coefficient) / SAMPLES_PER_SECOND as f64)
 }
 
+/// A summary of how well a track played, to tell network problems from device problems.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PlaybackDiagnostics {
+    /// The number of times playback had to wait for data to be downloaded.
+    pub underruns: usize,
+    /// The average download throughput in bytes per second, or `None` if the track was
+    /// played from the cache.
+    pub download_throughput: Option<usize>,
+    /// The share of reads that found their data downloaded already instead of waiting for
+    /// it, from 0.0 to 1.0. Tracks played from the cache always have 1.0.
+    pub buffered_read_ratio: f64,
+    /// The time from the load request until the first audio was written to the sink.
+    pub time_to_first_audio: Option<Duration>,
+}
+
+/// How long the stages from a load command until the first audio was written to the sink
+/// took. The loader stages are zero for tracks that were preloaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadLatency {
+    /// From receiving the command, e.g. in Spirc, until the player started loading.
+    pub command: Duration,
+    /// Fetching the metadata and finding a playable alternative.
+    pub metadata: Duration,
+    /// Opening the audio file from the cache, or starting its download.
+    pub audio_file: Duration,
+    /// Requesting the decryption key.
+    pub audio_key: Duration,
+    /// Creating the decoder and seeking to the start position.
+    pub decoder: Duration,
+    /// Downloading the start buffer, see [`PlayerConfig::start_buffer`].
+    pub buffer: Duration,
+    /// From the track being loaded until its first audio was written to the sink.
+    pub first_audio: Duration,
+    /// From receiving the command until the first audio was written to the sink.
+    pub total: Duration,
+}
+
+impl LoadLatency {
+    // Adds the stages around the loader's, for a track that was loaded at `loaded_at` after
+    // being requested at `requested_at`, of which the first audio was written at `now`.
+    fn complete(
+        mut self,
+        command_received_at: Option<Instant>,
+        requested_at: Instant,
+        loaded_at: Instant,
+        now: Instant,
+    ) -> Self {
+        let started_at = match command_received_at {
+            Some(received_at) => {
+                self.command = requested_at.saturating_duration_since(received_at);
+                received_at
+            }
+            None => requested_at,
+        };
+        self.first_audio = now.saturating_duration_since(loaded_at);
+        self.total = now.saturating_duration_since(started_at);
+        self
+    }
+}
+
+/// Load latencies aggregated over the lifetime of a [`Player`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadLatencyMetrics {
+    /// The number of loads that reached the sink.
+    pub loads: u64,
+    /// The sum of their total latencies.
+    pub total: Duration,
+    /// The largest total latency.
+    pub max: Duration,
+    /// The latency of the most recent load.
+    pub last: Option<LoadLatency>,
+}
+
+impl LoadLatencyMetrics {
+    pub fn mean(&self) -> Option<Duration> {
+        if self.loads == 0 {
+            return None;
+        }
+        Some(self.total / self.loads as u32)
+    }
+
+    fn record(&mut self, latency: LoadLatency) {
+        self.loads += 1;
+        self.total += latency.total;
+        self.max = self.max.max(latency.total);
+        self.last = Some(latency);
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct NormalisationData {
     // Spotify provides these as `f32`, but audio metadata can contain up to `f64`.
@@ -424,7 +1021,12 @@ impl Player {
     where
         F: FnOnce() -> Box<dyn Sink> + Send + 'static,
     {
-        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (cmd_tx, cmd_rx) = command_channel(config.command_queue_capacity);
+
+        let event_queue_capacity = config.event_queue_capacity;
+        let event_overflow_policy = config.event_overflow_policy;
+        let load_failure_policy = config.load_failure_policy;
+        let prefetch_count = config.prefetch_count;
 
         if config.normalisation {
             debug!("Normalisation Type: {:?}", config.normalisation_type);
@@ -452,32 +1054,63 @@ impl Player {
             }
         }
 
+        let load_latency_metrics = Arc::new(Mutex::new(LoadLatencyMetrics::default()));
+        let internal_load_latency_metrics = load_latency_metrics.clone();
+
         let handle = thread::spawn(move || {
             let player_id = PLAYER_COUNTER.fetch_add(1, Ordering::AcqRel);
             debug!("new Player [{}]", player_id);
 
             let converter = Converter::new(config.ditherer);
 
+            let drift_watchdog =
+                (config.drift_correction && !config.passthrough).then(DriftWatchdog::new);
+            let bitrate_adapter = config
+                .adaptive_bitrate
+                .then(|| BitrateAdapter::new(config.bitrate));
+
             let internal = PlayerInternal {
+                session_events: session.get_session_event_channel(),
                 session,
                 config,
                 commands: cmd_rx,
                 load_handles: Arc::new(Mutex::new(HashMap::new())),
+                stream_scheduler: StreamScheduler::new(),
+                prefetcher: Prefetcher::new(),
+                prefetch_task: None,
+                seek_table_scans: Default::default(),
 
                 state: PlayerState::Stopped,
                 preload: PlayerPreload::None,
                 sink: sink_builder(),
                 sink_status: SinkStatus::Closed,
                 sink_event_callback: None,
+                sink_sample_rate: SAMPLE_RATE,
+                resampler: None,
+                drift_watchdog,
+                announcement: None,
+                bitrate_adapter,
+                play_thresholds: Vec::new(),
+                written_ms: 0,
+                allowed_hours_checked_at: Instant::now(),
                 volume_getter,
                 event_senders: vec![],
                 converter,
 
-                normalisation_peak: 0.0,
-                normalisation_integrator: 0.0,
+                normaliser: Normaliser::new(),
 
                 auto_normalise_as_album: false,
 
+                load_attempts: 0,
+
+                load_requested_at: None,
+                time_to_first_audio: None,
+                stalled_reads_at_start: 0,
+
+                command_received_at: None,
+                load_latency: None,
+                load_latency_metrics: internal_load_latency_metrics,
+
                 player_id,
                 play_request_id_generator: SeqGenerator::new(0),
             };
@@ -493,9 +1126,18 @@ impl Player {
         Arc::new(Self {
             commands: Some(cmd_tx),
             thread_handle: Some(handle),
+            event_queue_capacity,
+            event_overflow_policy,
+            load_failure_policy,
+            load_latency_metrics,
+            prefetch_count,
         })
     }
 
+    pub fn load_failure_policy(&self) -> LoadFailurePolicy {
+        self.load_failure_policy
+    }
+
     pub fn is_invalid(&self) -> bool {
         if let Some(handle) = self.thread_handle.as_ref() {
             return handle.is_finished();
@@ -505,24 +1147,68 @@ impl Player {
 
     fn command(&self, cmd: PlayerCommand) {
         if let Some(commands) = self.commands.as_ref() {
-            if let Err(e) = commands.send(cmd) {
-                error!("Player Commands Error: {}", e);
-            }
+            commands.send(cmd);
         }
     }
 
+    /// Returns the number of commands that were rejected because the queue of the player
+    /// was full, see [`PlayerConfig::command_queue_capacity`]. Only hints like preloading
+    /// are ever rejected.
+    pub fn dropped_commands(&self) -> u64 {
+        self.commands
+            .as_ref()
+            .map_or(0, |commands| commands.dropped.load(Ordering::Relaxed))
+    }
+
     pub fn load(&self, track_id: SpotifyId, start_playing: bool, position_ms: u32) {
         self.command(PlayerCommand::Load {
             track_id,
             play: start_playing,
             position_ms,
+            command_received_at: None,
         });
     }
 
+    /// Like [`load`](Self::load), for a load caused by a command that was received at
+    /// `command_received_at`, so that [`LoadLatency`] includes handling the command.
+    pub fn load_for_command(
+        &self,
+        track_id: SpotifyId,
+        start_playing: bool,
+        position_ms: u32,
+        command_received_at: Instant,
+    ) {
+        self.command(PlayerCommand::Load {
+            track_id,
+            play: start_playing,
+            position_ms,
+            command_received_at: Some(command_received_at),
+        });
+    }
+
+    /// The load latencies so far.
+    pub fn load_latency_metrics(&self) -> LoadLatencyMetrics {
+        *self.load_latency_metrics.lock()
+    }
+
     pub fn preload(&self, track_id: SpotifyId) {
         self.command(PlayerCommand::Preload { track_id });
     }
 
+    /// How many of the tracks that are up next are prefetched, see [`prefetch`](Self::prefetch).
+    pub fn prefetch_count(&self) -> usize {
+        self.prefetch_count
+    }
+
+    /// Fetches the metadata and audio keys of the tracks that are up next, in the order they
+    /// are played, so that loading them doesn't wait for it. Only the first
+    /// [`prefetch_count`](Self::prefetch_count) are prefetched, replacing those of the
+    /// previous call.
+    pub fn prefetch(&self, mut track_ids: Vec<SpotifyId>) {
+        track_ids.truncate(self.prefetch_count);
+        self.command(PlayerCommand::Prefetch(track_ids));
+    }
+
     pub fn play(&self) {
         self.command(PlayerCommand::Play)
     }
@@ -539,14 +1225,35 @@ impl Player {
         self.command(PlayerCommand::Seek(position_ms));
     }
 
+    /// Plays `announcement` over the music, which is ducked or held meanwhile and fades back
+    /// in afterwards. It is played on its own when the music isn't playing. Replaces an
+    /// announcement that is still playing. Announcements cannot be mixed into passthrough
+    /// audio, so they are ignored then.
+    pub fn announce(&self, announcement: Announcement) {
+        self.command(PlayerCommand::Announce(announcement));
+    }
+
     pub fn set_session(&self, session: Session) {
         self.command(PlayerCommand::SetSession(session));
     }
 
     pub fn get_player_event_channel(&self) -> PlayerEventChannel {
-        let (event_sender, event_receiver) = mpsc::unbounded_channel();
-        self.command(PlayerCommand::AddEventSender(event_sender));
-        event_receiver
+        self.get_player_event_channel_with_policy(
+            self.event_queue_capacity,
+            self.event_overflow_policy,
+        )
+    }
+
+    pub fn get_player_event_channel_with_policy(
+        &self,
+        capacity: usize,
+        policy: EventOverflowPolicy,
+    ) -> PlayerEventChannel {
+        let queue = PlayerEventQueue::new(capacity, policy);
+        self.command(PlayerCommand::AddEventSender(PlayerEventSender(
+            queue.clone(),
+        )));
+        PlayerEventChannel(queue)
     }
 
     pub async fn await_end_of_track(&self) {
@@ -565,6 +1272,15 @@ impl Player {
         self.command(PlayerCommand::SetSinkEventCallback(callback));
     }
 
+    /// Calls `callback` whenever a track reaches `threshold`, see [`PlayThreshold`].
+    pub fn add_play_threshold(&self, threshold: PlayThreshold, callback: PlayThresholdCallback) {
+        self.command(PlayerCommand::AddPlayThreshold(threshold, callback));
+    }
+
+    pub fn clear_play_thresholds(&self) {
+        self.command(PlayerCommand::ClearPlayThresholds);
+    }
+
     pub fn emit_volume_changed_event(&self, volume: u16) {
         self.command(PlayerCommand::EmitVolumeChangedEvent(volume));
     }
@@ -573,6 +1289,13 @@ impl Player {
         self.command(PlayerCommand::SetAutoNormaliseAsAlbum(setting));
     }
 
+    /// Restricts what is played from now on. If the policy doesn't allow the track that is
+    /// loaded, it is paused when outside of the allowed hours, and otherwise stopped and
+    /// skipped with [`PlayerEvent::Blocked`] followed by [`PlayerEvent::EndOfTrack`].
+    pub fn set_content_policy(&self, policy: ContentPolicy) {
+        self.command(PlayerCommand::SetContentPolicy(policy));
+    }
+
     pub fn emit_filter_explicit_content_changed_event(&self, filter: bool) {
         self.command(PlayerCommand::EmitFilterExplicitContentChangedEvent(filter));
     }
@@ -606,14 +1329,18 @@ impl Player {
         });
     }
 
-    pub fn emit_shuffle_changed_event(&self, shuffle: bool) {
-        self.command(PlayerCommand::EmitShuffleChangedEvent(shuffle));
+    pub fn emit_shuffle_changed_event(&self, shuffle: bool, seed: Option<u64>) {
+        self.command(PlayerCommand::EmitShuffleChangedEvent(shuffle, seed));
     }
 
     pub fn emit_repeat_changed_event(&self, repeat: bool) {
         self.command(PlayerCommand::EmitRepeatChangedEvent(repeat));
     }
 
+    pub fn emit_context_segment_event(&self, uri: String, metadata: HashMap<String, String>) {
+        self.command(PlayerCommand::EmitContextSegmentEvent { uri, metadata });
+    }
+
     pub fn emit_auto_play_changed_event(&self, auto_play: bool) {
         self.command(PlayerCommand::EmitAutoPlayChangedEvent(auto_play));
     }
@@ -640,13 +1367,21 @@ struct PlayerLoadedTrackData {
     duration_ms: u32,
     stream_position_ms: u32,
     is_explicit: bool,
+    load_latency: LoadLatency,
+}
+
+// Why a track could not be loaded. Blocked tracks are not retried.
+#[derive(Debug)]
+enum LoadError {
+    Failed,
+    Blocked(BlockReason),
 }
 
 enum PlayerPreload {
     None,
     Loading {
         track_id: SpotifyId,
-        loader: Pin<Box<dyn FusedFuture<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
+        loader: Pin<Box<dyn FusedFuture<Output = Result<PlayerLoadedTrackData, LoadError>> + Send>>,
     },
     Ready {
         track_id: SpotifyId,
@@ -662,7 +1397,8 @@ enum PlayerState {
         track_id: SpotifyId,
         play_request_id: u64,
         start_playback: bool,
-        loader: Pin<Box<dyn FusedFuture<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
+        position_ms: u32,
+        loader: Pin<Box<dyn FusedFuture<Output = Result<PlayerLoadedTrackData, LoadError>> + Send>>,
     },
     Paused {
         track_id: SpotifyId,
@@ -772,6 +1508,7 @@ impl PlayerState {
                         duration_ms,
                         stream_position_ms,
                         is_explicit,
+                        load_latency: LoadLatency::default(),
                     },
                 };
             }
@@ -875,156 +1612,158 @@ impl PlayerState {
     }
 }
 
+// The files whose seek table is being made, or couldn't be made, so that it is tried once.
+type SeekTableScans = Arc<Mutex<HashSet<FileId>>>;
+
 struct PlayerTrackLoader {
     session: Session,
     config: PlayerConfig,
+    stream_scheduler: Arc<StreamScheduler>,
+    prefetcher: Arc<Prefetcher>,
+    stream_priority: StreamPriority,
+    seek_table_scans: SeekTableScans,
 }
 
 impl PlayerTrackLoader {
-    async fn find_available_alternative(&self, audio_item: AudioItem) -> Option<AudioItem> {
-        if let Err(e) = audio_item.availability {
-            error!("Track is unavailable: {}", e);
-            None
-        } else if !audio_item.files.is_empty() {
-            Some(audio_item)
-        } else if let Some(alternatives) = &audio_item.alternatives {
-            let alternatives: FuturesUnordered<_> = alternatives
-                .iter()
-                .map(|alt_id| AudioItem::get_file(&self.session, *alt_id))
-                .collect();
-
-            alternatives
-                .filter_map(|x| future::ready(x.ok()))
-                .filter(|x| future::ready(x.availability.is_ok()))
-                .next()
-                .await
-        } else {
-            error!("Track should be available, but no alternatives found.");
-            None
-        }
-    }
+    // Returns the seek table of a cached Ogg Vorbis file, or makes it in the background for
+    // the next time the file is played.
+    fn cached_seek_table(
+        &self,
+        file_id: FileId,
+        byte_len: u64,
+        key: Option<AudioKey>,
+    ) -> Option<SeekTable> {
+        let cache = self.session.cache()?;
+        let seek_table = cache
+            .seek_table(file_id)
+            .and_then(|data| SeekTable::from_bytes(&data));
+        match seek_table {
+            Some(seek_table) if seek_table.byte_len == byte_len => Some(seek_table),
+            _ => {
+                let path = cache.file_path(file_id)?;
+                if !self.seek_table_scans.lock().insert(file_id) {
+                    return None;
+                }
 
-    fn stream_data_rate(&self, format: AudioFileFormat) -> usize {
-        let kbps = match format {
-            AudioFileFormat::OGG_VORBIS_96 => 12,
-            AudioFileFormat::OGG_VORBIS_160 => 20,
-            AudioFileFormat::OGG_VORBIS_320 => 40,
-            AudioFileFormat::MP3_256 => 32,
-            AudioFileFormat::MP3_320 => 40,
-            AudioFileFormat::MP3_160 => 20,
-            AudioFileFormat::MP3_96 => 12,
-            AudioFileFormat::MP3_160_ENC => 20,
-            AudioFileFormat::AAC_24 => 3,
-            AudioFileFormat::AAC_48 => 6,
-            AudioFileFormat::FLAC_FLAC => 112, // assume 900 kbit/s on average
-        };
-        kbps * 1024
+                let cache = cache.clone();
+                let scans = self.seek_table_scans.clone();
+                thread::spawn(move || match make_seek_table(&path, key) {
+                    Ok(seek_table) => {
+                        cache.save_seek_table(file_id, &seek_table.to_bytes());
+                        scans.lock().remove(&file_id);
+                    }
+                    Err(e) => debug!("Unable to make a seek table for {}: {}", file_id, e),
+                });
+                None
+            }
+        }
     }
 
     async fn load_track(
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> Option<PlayerLoadedTrackData> {
-        let audio_item = match AudioItem::get_file(&self.session, spotify_id).await {
-            Ok(audio) => match self.find_available_alternative(audio).await {
-                Some(audio) => audio,
-                None => {
-                    warn!(
-                        "<{}> is not available",
-                        spotify_id.to_uri().unwrap_or_default()
-                    );
-                    return None;
+    ) -> Result<PlayerLoadedTrackData, LoadError> {
+        let mut load_latency = LoadLatency::default();
+        let started_at = Instant::now();
+
+        let prefetched = self.prefetcher.take(spotify_id);
+        let (audio_item, prefetched_key) = match prefetched {
+            Some(prefetched) => (prefetched.audio_item, prefetched.key),
+            None => match AudioItem::get_file(&self.session, spotify_id).await {
+                Ok(audio) => match find_available_alternative(&self.session, audio).await {
+                    Some(audio) => (audio, None),
+                    None => {
+                        warn!(
+                            "<{}> is not available",
+                            spotify_id.to_uri().unwrap_or_default()
+                        );
+                        return Err(LoadError::Failed);
+                    }
+                },
+                Err(e) => {
+                    error!("Unable to load audio item: {:?}", e);
+                    return Err(LoadError::Failed);
                 }
             },
-            Err(e) => {
-                error!("Unable to load audio item: {:?}", e);
-                return None;
-            }
         };
 
+        load_latency.metadata = started_at.elapsed();
+
+        if let Err(reason) = self
+            .config
+            .content_policy
+            .check(&audio_item, OffsetDateTime::now_utc())
+        {
+            warn!(
+                "<{}> is blocked by the content policy: {}",
+                audio_item.uri, reason
+            );
+            return Err(LoadError::Blocked(reason));
+        }
+
         info!(
             "Loading <{}> with Spotify URI <{}>",
             audio_item.name, audio_item.uri
         );
 
-        // (Most) podcasts seem to support only 96 kbps Ogg Vorbis, so fall back to it
-        let formats = match self.config.bitrate {
-            Bitrate::Bitrate96 => [
-                AudioFileFormat::OGG_VORBIS_96,
-                AudioFileFormat::MP3_96,
-                AudioFileFormat::OGG_VORBIS_160,
-                AudioFileFormat::MP3_160,
-                AudioFileFormat::MP3_256,
-                AudioFileFormat::OGG_VORBIS_320,
-                AudioFileFormat::MP3_320,
-            ],
-            Bitrate::Bitrate160 => [
-                AudioFileFormat::OGG_VORBIS_160,
-                AudioFileFormat::MP3_160,
-                AudioFileFormat::OGG_VORBIS_96,
-                AudioFileFormat::MP3_96,
-                AudioFileFormat::MP3_256,
-                AudioFileFormat::OGG_VORBIS_320,
-                AudioFileFormat::MP3_320,
-            ],
-            Bitrate::Bitrate320 => [
-                AudioFileFormat::OGG_VORBIS_320,
-                AudioFileFormat::MP3_320,
-                AudioFileFormat::MP3_256,
-                AudioFileFormat::OGG_VORBIS_160,
-                AudioFileFormat::MP3_160,
-                AudioFileFormat::OGG_VORBIS_96,
-                AudioFileFormat::MP3_96,
-            ],
+        let (format, file_id) = match select_file(&audio_item, self.config.bitrate) {
+            Some(t) => t,
+            None => {
+                warn!(
+                    "<{}> is not available in any supported format",
+                    audio_item.name
+                );
+                return Err(LoadError::Failed);
+            }
         };
 
-        let (format, file_id) =
-            match formats
-                .iter()
-                .find_map(|format| match audio_item.files.get(format) {
-                    Some(&file_id) => Some((*format, file_id)),
-                    _ => None,
-                }) {
-                Some(t) => t,
-                None => {
-                    warn!(
-                        "<{}> is not available in any supported format",
-                        audio_item.name
-                    );
-                    return None;
-                }
-            };
-
-        let bytes_per_second = self.stream_data_rate(format);
+        let bytes_per_second = stream_data_rate(format);
 
         // This is only a loop to be able to reload the file if an error occurred
         // while opening a cached file.
         loop {
-            let encrypted_file = AudioFile::open(&self.session, file_id, bytes_per_second);
+            let started_at = Instant::now();
+            let encrypted_file = AudioFile::open_with_priority(
+                &self.session,
+                file_id,
+                bytes_per_second,
+                self.stream_scheduler.clone(),
+                self.stream_priority,
+            );
 
             let encrypted_file = match encrypted_file.await {
                 Ok(encrypted_file) => encrypted_file,
                 Err(e) => {
                     error!("Unable to load encrypted file: {:?}", e);
-                    return None;
+                    return Err(LoadError::Failed);
                 }
             };
 
+            load_latency.audio_file += started_at.elapsed();
+
             let is_cached = encrypted_file.is_cached();
 
-            let stream_loader_controller = encrypted_file.get_stream_loader_controller().ok()?;
+            let stream_loader_controller = encrypted_file
+                .get_stream_loader_controller()
+                .map_err(|_| LoadError::Failed)?;
 
             // Not all audio files are encrypted. If we can't get a key, try loading the track
             // without decryption. If the file was encrypted after all, the decoder will fail
             // parsing and bail out, so we should be safe from outputting ear-piercing noise.
-            let key = match self.session.audio_key().request(spotify_id, file_id).await {
-                Ok(key) => Some(key),
-                Err(e) => {
-                    warn!("Unable to load key, continuing without decryption: {}", e);
-                    None
-                }
+            let started_at = Instant::now();
+            let key = match prefetched_key {
+                Some((prefetched_file_id, key)) if prefetched_file_id == file_id => Some(key),
+                _ => match self.session.audio_key().request(spotify_id, file_id).await {
+                    Ok(key) => Some(key),
+                    Err(e) => {
+                        warn!("Unable to load key, continuing without decryption: {}", e);
+                        None
+                    }
+                },
             };
+            load_latency.audio_key += started_at.elapsed();
+            let started_at = Instant::now();
             let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
 
             let is_ogg_vorbis = AudioFiles::is_ogg_vorbis(format);
@@ -1037,6 +1776,13 @@ impl PlayerTrackLoader {
                 (0, None)
             };
 
+            // Cached files don't change, so where their pages start can be kept.
+            let mut seek_table = if is_cached && is_ogg_vorbis {
+                self.cached_seek_table(file_id, stream_loader_controller.len() as u64, key)
+            } else {
+                None
+            };
+
             let audio_file = match Subfile::new(
                 decrypted_file,
                 offset,
@@ -1045,12 +1791,18 @@ impl PlayerTrackLoader {
                 Ok(audio_file) => audio_file,
                 Err(e) => {
                     error!("PlayerTrackLoader::load_track error opening subfile: {}", e);
-                    return None;
+                    return Err(LoadError::Failed);
                 }
             };
 
             let mut symphonia_decoder = |audio_file, format| {
-                SymphoniaDecoder::new(audio_file, format).map(|mut decoder| {
+                let decoder = match seek_table.take() {
+                    Some(seek_table) => {
+                        SymphoniaDecoder::with_seek_table(audio_file, format, seek_table)
+                    }
+                    None => SymphoniaDecoder::new(audio_file, format),
+                };
+                decoder.map(|mut decoder| {
                     // For formats other that Vorbis, we'll try getting normalisation data from
                     // ReplayGain metadata fields, if present.
                     if normalisation_data.is_none() {
@@ -1087,12 +1839,12 @@ impl PlayerTrackLoader {
                         Some(cache) => {
                             if cache.remove_file(file_id).is_err() {
                                 error!("Error removing file from cache");
-                                return None;
+                                return Err(LoadError::Failed);
                             }
                         }
                         None => {
                             error!("If the audio file is cached, a cache should exist");
-                            return None;
+                            return Err(LoadError::Failed);
                         }
                     }
 
@@ -1101,7 +1853,7 @@ impl PlayerTrackLoader {
                 }
                 Err(e) => {
                     error!("Unable to read audio file: {}", e);
-                    return None;
+                    return Err(LoadError::Failed);
                 }
             };
 
@@ -1127,18 +1879,32 @@ impl PlayerTrackLoader {
                         "PlayerTrackLoader::load_track error seeking to starting position {}: {}",
                         position_ms, e
                     );
-                    return None;
+                    return Err(LoadError::Failed);
                 }
             };
 
+            load_latency.decoder += started_at.elapsed();
+
             // Ensure streaming mode now that we are ready to play from the requested position.
             stream_loader_controller.set_stream_mode();
 
+            // Preloaded tracks have long been downloaded by the time they start playing.
+            if self.stream_priority == StreamPriority::Playback {
+                let started_at = Instant::now();
+                if let Err(e) = stream_loader_controller.fetch_next_and_buffer(
+                    (READ_AHEAD_DURING_PLAYBACK.as_secs_f32() * bytes_per_second as f32) as usize,
+                    (self.config.start_buffer.as_secs_f32() * bytes_per_second as f32) as usize,
+                ) {
+                    warn!("Unable to buffer before playback, starting anyway: {}", e);
+                }
+                load_latency.buffer = started_at.elapsed();
+            }
+
             let is_explicit = audio_item.is_explicit;
 
             info!("<{}> ({} ms) loaded", audio_item.name, duration_ms);
 
-            return Some(PlayerLoadedTrackData {
+            return Ok(PlayerLoadedTrackData {
                 decoder,
                 normalisation_data,
                 stream_loader_controller,
@@ -1147,6 +1913,7 @@ impl PlayerTrackLoader {
                 duration_ms,
                 stream_position_ms,
                 is_explicit,
+                load_latency,
             });
         }
     }
@@ -1179,12 +1946,19 @@ impl Future for PlayerInternal {
                 }
             }
 
+            while let Poll::Ready(Some(event)) = self.session_events.poll_recv(cx) {
+                if let SessionEvent::CountryChanged { .. } = event {
+                    self.handle_country_changed();
+                }
+            }
+
             // Handle loading of a new track to play
             if let PlayerState::Loading {
                 ref mut loader,
                 track_id,
                 start_playback,
                 play_request_id,
+                position_ms,
             } = self.state
             {
                 // The loader may be terminated if we are trying to load the same track
@@ -1203,10 +1977,48 @@ impl Future for PlayerInternal {
                                 exit(1);
                             }
                         }
+                        Poll::Ready(Err(LoadError::Blocked(reason))) => {
+                            self.send_event(PlayerEvent::Blocked {
+                                track_id,
+                                play_request_id,
+                                reason,
+                            })
+                        }
+                        Poll::Ready(Err(e)) if self.load_attempts < self.config.load_retries => {
+                            self.load_attempts += 1;
+                            let backoff = self
+                                .config
+                                .load_retry_backoff
+                                .saturating_mul(1 << (self.load_attempts - 1).min(16));
+                            warn!(
+                                "Unable to load track <{:?}>: {:?}, retrying in {:?} ({}/{})",
+                                track_id, e, backoff, self.load_attempts, self.config.load_retries
+                            );
+                            let (attempt, retries) = (self.load_attempts, self.config.load_retries);
+                            self.send_event(PlayerEvent::LoadRetrying {
+                                track_id,
+                                play_request_id,
+                                attempt,
+                                retries,
+                            });
+                            let retry = self.load_track(
+                                track_id,
+                                position_ms,
+                                StreamPriority::Playback,
+                                backoff,
+                            );
+                            if let PlayerState::Loading { ref mut loader, .. } = self.state {
+                                *loader = Box::pin(retry);
+                            }
+                            // poll the new loader so that it wakes us up
+                            all_futures_completed_or_not_ready = false;
+                        }
                         Poll::Ready(Err(e)) => {
                             error!(
-                                "Skipping to next track, unable to load track <{:?}>: {:?}",
-                                track_id, e
+                                "Giving up on track <{:?}> after {} attempts: {:?}",
+                                track_id,
+                                self.load_attempts + 1,
+                                e
                             );
                             self.send_event(PlayerEvent::Unavailable {
                                 track_id,
@@ -1232,7 +2044,7 @@ impl Future for PlayerInternal {
                             loaded_track: Box::new(loaded_track),
                         };
                     }
-                    Poll::Ready(Err(_)) => {
+                    Poll::Ready(Err(e)) => {
                         debug!("Unable to preload {:?}", track_id);
                         self.preload = PlayerPreload::None;
                         // Let Spirc know that the track was unavailable.
@@ -1243,9 +2055,16 @@ impl Future for PlayerInternal {
                             play_request_id, ..
                         } = self.state
                         {
-                            self.send_event(PlayerEvent::Unavailable {
-                                track_id,
-                                play_request_id,
+                            self.send_event(match e {
+                                LoadError::Blocked(reason) => PlayerEvent::Blocked {
+                                    track_id,
+                                    play_request_id,
+                                    reason,
+                                },
+                                LoadError::Failed => PlayerEvent::Unavailable {
+                                    track_id,
+                                    play_request_id,
+                                },
                             });
                         }
                     }
@@ -1253,6 +2072,18 @@ impl Future for PlayerInternal {
                 }
             }
 
+            if self.play_announcement() {
+                continue;
+            }
+
+            if self.state.is_playing()
+                && self.config.content_policy.allowed_hours.is_some()
+                && self.allowed_hours_checked_at.elapsed() >= ALLOWED_HOURS_CHECK_INTERVAL
+            {
+                self.allowed_hours_checked_at = Instant::now();
+                self.enforce_content_policy();
+            }
+
             if self.state.is_playing() {
                 self.ensure_sink_running();
 
@@ -1261,13 +2092,17 @@ impl Future for PlayerInternal {
                     play_request_id,
                     ref mut decoder,
                     normalisation_factor,
+                    duration_ms,
                     ref mut stream_position_ms,
                     ref mut reported_nominal_start_time,
                     ..
                 } = self.state
                 {
-                    match decoder.next_packet() {
+                    let decoded = trace_span!("decode_packet", track = %track_id)
+                        .in_scope(|| decoder.next_packet());
+                    match decoded {
                         Ok(result) => {
+                            let mut written_ms = 0;
                             if let Some((ref packet_position, ref packet)) = result {
                                 let new_stream_position_ms = packet_position.position_ms;
                                 let expected_position_ms = std::mem::replace(
@@ -1275,6 +2110,13 @@ impl Future for PlayerInternal {
                                     new_stream_position_ms,
                                 );
 
+                                // The previous packet was written, unless packets were skipped.
+                                if !packet_position.skipped {
+                                    written_ms = new_stream_position_ms
+                                        .saturating_sub(expected_position_ms)
+                                        as u64;
+                                }
+
                                 if !passthrough {
                                     match packet.samples() {
                                         Ok(_) => {
@@ -1333,6 +2175,9 @@ impl Future for PlayerInternal {
                                         }
                                         Err(e) => {
                                             error!("Skipping to next track, unable to decode samples for track <{:?}>: {:?}", track_id, e);
+                                            self.session
+                                                .metrics()
+                                                .increment(Counter::DecodeErrors, 1);
                                             self.send_event(PlayerEvent::EndOfTrack {
                                                 track_id,
                                                 play_request_id,
@@ -1342,10 +2187,13 @@ impl Future for PlayerInternal {
                                 }
                             }
 
+                            self.written_ms += written_ms;
                             self.handle_packet(result, normalisation_factor);
+                            self.check_play_thresholds(track_id, duration_ms, false);
                         }
                         Err(e) => {
                             error!("Skipping to next track, unable to get next packet for track <{:?}>: {:?}", track_id, e);
+                            self.session.metrics().increment(Counter::DecodeErrors, 1);
                             self.send_event(PlayerEvent::EndOfTrack {
                                 track_id,
                                 play_request_id,
@@ -1405,7 +2253,12 @@ impl PlayerInternal {
                 callback(SinkStatus::Running);
             }
             match self.sink.start() {
-                Ok(()) => self.sink_status = SinkStatus::Running,
+                Ok(()) => {
+                    self.sink_status = SinkStatus::Running;
+                    if let Some(watchdog) = self.drift_watchdog.as_mut() {
+                        watchdog.reset();
+                    }
+                }
                 Err(e) => {
                     error!("{}", e);
                     self.handle_pause();
@@ -1447,7 +2300,18 @@ impl PlayerInternal {
         }
     }
 
+    // The preloaded track was checked for availability, and its CDN resolved, for the
+    // previous country.
+    fn handle_country_changed(&mut self) {
+        if !matches!(self.preload, PlayerPreload::None) {
+            debug!("Discarding the preloaded track, as the country changed");
+            self.preload = PlayerPreload::None;
+        }
+    }
+
     fn handle_player_stop(&mut self) {
+        self.send_diagnostics();
+
         match self.state {
             PlayerState::Playing {
                 track_id,
@@ -1492,6 +2356,19 @@ impl PlayerInternal {
                 stream_position_ms,
                 ..
             } => {
+                let allowed_hours = self.config.content_policy.allowed_hours;
+                if let Some(hours) = allowed_hours {
+                    if !hours.contains(OffsetDateTime::now_utc()) {
+                        warn!("Not resuming, playback is outside of the allowed hours");
+                        self.send_event(PlayerEvent::Blocked {
+                            track_id,
+                            play_request_id,
+                            reason: BlockReason::OutsideAllowedHours,
+                        });
+                        return;
+                    }
+                }
+
                 self.state.paused_to_playing();
                 self.send_event(PlayerEvent::Playing {
                     track_id,
@@ -1547,131 +2424,53 @@ impl PlayerInternal {
             Some((_, mut packet)) => {
                 if !packet.is_empty() {
                     if let AudioPacket::Samples(ref mut data) = packet {
+                        if let Some(resampler) = self.resampler.as_mut() {
+                            *data = resampler.resample(data);
+                        }
+
                         // Get the volume for the packet.
                         // In the case of hardware volume control this will
                         // always be 1.0 (no change).
                         let volume = self.volume_getter.attenuation_factor();
 
-                        // For the basic normalisation method, a normalisation factor of 1.0 indicates that
-                        // there is nothing to normalise (all samples should pass unaltered). For the
-                        // dynamic method, there may still be peaks that we want to shave off.
+                        self.normaliser
+                            .process(&self.config, data, normalisation_factor, volume);
 
-                        // No matter the case we apply volume attenuation last if there is any.
-                        if !self.config.normalisation {
-                            if volume < 1.0 {
-                                for sample in data.iter_mut() {
-                                    *sample *= volume;
-                                }
-                            }
-                        } else if self.config.normalisation_method == NormalisationMethod::Basic
-                            && (normalisation_factor < 1.0 || volume < 1.0)
-                        {
-                            for sample in data.iter_mut() {
-                                *sample *= normalisation_factor * volume;
-                            }
-                        } else if self.config.normalisation_method == NormalisationMethod::Dynamic {
-                            // zero-cost shorthands
-                            let threshold_db = self.config.normalisation_threshold_dbfs;
-                            let knee_db = self.config.normalisation_knee_db;
-                            let attack_cf = self.config.normalisation_attack_cf;
-                            let release_cf = self.config.normalisation_release_cf;
-
-                            for sample in data.iter_mut() {
-                                *sample *= normalisation_factor;
-
-                                // Feedforward limiter in the log domain
-                                // After: Giannoulis, D., Massberg, M., & Reiss, J.D. (2012). Digital Dynamic
-                                // Range Compressor Design—A Tutorial and Analysis. Journal of The Audio
-                                // Engineering Society, 60, 399-408.
-
-                                // Some tracks have samples that are precisely 0.0. That's silence
-                                // and we know we don't need to limit that, in which we can spare
-                                // the CPU cycles.
-                                //
-                                // Also, calling `ratio_to_db(0.0)` returns `inf` and would get the
-                                // peak detector stuck. Also catch the unlikely case where a sample
-                                // is decoded as `NaN` or some other non-normal value.
-                                let limiter_db = if sample.is_normal() {
-                                    // step 1-4: half-wave rectification and conversion into dB
-                                    // and gain computer with soft knee and subtractor
-                                    let bias_db = ratio_to_db(sample.abs()) - threshold_db;
-                                    let knee_boundary_db = bias_db * 2.0;
-
-                                    if knee_boundary_db < -knee_db {
-                                        0.0
-                                    } else if knee_boundary_db.abs() <= knee_db {
-                                        // The textbook equation:
-                                        // ratio_to_db(sample.abs()) - (ratio_to_db(sample.abs()) - (bias_db + knee_db / 2.0).powi(2) / (2.0 * knee_db))
-                                        // Simplifies to:
-                                        // ((2.0 * bias_db) + knee_db).powi(2) / (8.0 * knee_db)
-                                        // Which in our case further simplifies to:
-                                        // (knee_boundary_db + knee_db).powi(2) / (8.0 * knee_db)
-                                        // because knee_boundary_db is 2.0 * bias_db.
-                                        (knee_boundary_db + knee_db).powi(2) / (8.0 * knee_db)
-                                    } else {
-                                        // Textbook:
-                                        // ratio_to_db(sample.abs()) - threshold_db, which is already our bias_db.
-                                        bias_db
-                                    }
-                                } else {
-                                    0.0
-                                };
-
-                                // Spare the CPU unless (1) the limiter is engaged, (2) we
-                                // were in attack or (3) we were in release, and that attack/
-                                // release wasn't finished yet.
-                                if limiter_db > 0.0
-                                    || self.normalisation_integrator > 0.0
-                                    || self.normalisation_peak > 0.0
-                                {
-                                    // step 5: smooth, decoupled peak detector
-                                    // Textbook:
-                                    // release_cf * self.normalisation_integrator + (1.0 - release_cf) * limiter_db
-                                    // Simplifies to:
-                                    // release_cf * self.normalisation_integrator - release_cf * limiter_db + limiter_db
-                                    self.normalisation_integrator = f64::max(
-                                        limiter_db,
-                                        release_cf * self.normalisation_integrator
-                                            - release_cf * limiter_db
-                                            + limiter_db,
-                                    );
-                                    // Textbook:
-                                    // attack_cf * self.normalisation_peak + (1.0 - attack_cf) * self.normalisation_integrator
-                                    // Simplifies to:
-                                    // attack_cf * self.normalisation_peak - attack_cf * self.normalisation_integrator + self.normalisation_integrator
-                                    self.normalisation_peak = attack_cf * self.normalisation_peak
-                                        - attack_cf * self.normalisation_integrator
-                                        + self.normalisation_integrator;
-
-                                    // step 6: make-up gain applied later (volume attenuation)
-                                    // Applying the standard normalisation factor here won't work,
-                                    // because there are tracks with peaks as high as 6 dB above
-                                    // the default threshold, so that would clip.
-
-                                    // steps 7-8: conversion into level and multiplication into gain stage
-                                    *sample *= db_to_ratio(-self.normalisation_peak);
-                                }
-
-                                *sample *= volume;
+                        if let Some(announcement) = self.announcement.as_mut() {
+                            announcement.mix(data, volume);
+                            if announcement.is_finished() {
+                                self.finish_announcement();
                             }
                         }
                     }
 
-                    if let Err(e) = self.sink.write(packet, &mut self.converter) {
+                    let written = trace_span!("sink_write")
+                        .in_scope(|| self.sink.write(packet, &mut self.converter));
+                    if let Err(e) = written {
                         error!("{}", e);
                         self.handle_pause();
+                    } else {
+                        self.correct_drift();
+                        if self.time_to_first_audio.is_none() {
+                            self.time_to_first_audio =
+                                self.load_requested_at.map(|instant| instant.elapsed());
+                            self.send_load_latency();
+                        }
                     }
                 }
             }
 
             None => {
+                self.send_diagnostics();
                 self.state.playing_to_end_of_track();
                 if let PlayerState::EndOfTrack {
                     track_id,
                     play_request_id,
-                    ..
+                    ref loaded_track,
                 } = self.state
                 {
+                    let duration_ms = loaded_track.duration_ms;
+                    self.check_play_thresholds(track_id, duration_ms, true);
                     self.send_event(PlayerEvent::EndOfTrack {
                         track_id,
                         play_request_id,
@@ -1684,6 +2483,170 @@ impl PlayerInternal {
         }
     }
 
+    // Plays the announcement on its own while the music is held for it, or isn't playing.
+    // Returns whether it did, so that no music is decoded meanwhile.
+    fn play_announcement(&mut self) -> bool {
+        let music_playing = self.state.is_playing();
+        let volume = self.volume_getter.attenuation_factor();
+        let samples = match self.announcement.as_mut() {
+            Some(announcement) if !music_playing || announcement.pauses_music() => {
+                announcement.render(ANNOUNCEMENT_CHUNK_FRAMES, volume)
+            }
+            _ => return false,
+        };
+
+        if samples.is_empty() {
+            // Held music continues with fading in, otherwise there is nothing to fade in.
+            if !music_playing {
+                self.finish_announcement();
+            }
+            return false;
+        }
+
+        self.ensure_sink_running();
+        let written = trace_span!("sink_write").in_scope(|| {
+            self.sink
+                .write(AudioPacket::Samples(samples), &mut self.converter)
+        });
+        if let Err(e) = written {
+            error!("Unable to play announcement: {}", e);
+            self.finish_announcement();
+            return false;
+        }
+        true
+    }
+
+    fn finish_announcement(&mut self) {
+        if self.announcement.take().is_some() {
+            if !self.state.is_playing() {
+                self.ensure_sink_stopped(false);
+            }
+            self.send_event(PlayerEvent::AnnouncementFinished);
+        }
+    }
+
+    fn handle_command_announce(&mut self, announcement: Announcement) {
+        if self.config.passthrough {
+            warn!("Ignoring announcement, which cannot be mixed into passthrough audio");
+            return;
+        }
+
+        let gain = self
+            .announcement
+            .as_ref()
+            .map_or(1.0, AnnouncementMixer::gain);
+        self.announcement = Some(AnnouncementMixer::new(
+            announcement,
+            self.sink_sample_rate,
+            gain,
+        ));
+        self.send_event(PlayerEvent::AnnouncementStarted);
+    }
+
+    fn correct_drift(&mut self) {
+        let watchdog = match self.drift_watchdog.as_mut() {
+            Some(watchdog) => watchdog,
+            None => return,
+        };
+        if let Some(buffered_frames) = self.sink.buffered_frames() {
+            let ppm = watchdog.update(buffered_frames);
+            match self.resampler.as_mut() {
+                Some(resampler) => resampler.set_drift_ppm(ppm),
+                // Tracks at the rate of the sink are only resampled once there is drift.
+                None if ppm != 0.0 => {
+                    debug!("Resampling at {} Hz for drift", self.sink_sample_rate);
+                    let mut resampler =
+                        Resampler::new(self.sink_sample_rate, self.sink_sample_rate);
+                    resampler.set_drift_ppm(ppm);
+                    self.resampler = Some(resampler);
+                }
+                None => (),
+            }
+        }
+    }
+
+    // Switches the sink to the sample rate of a track if possible, or resamples the track.
+    fn configure_sample_rate(&mut self, sample_rate: u32) {
+        // Passthrough packets are written as they are.
+        if self.config.passthrough {
+            return;
+        }
+
+        let previous_source_rate = match self.resampler {
+            Some(ref resampler) => resampler.from_rate(),
+            None => self.sink_sample_rate,
+        };
+        let previous_sink_rate = self.sink_sample_rate;
+
+        if sample_rate != self.sink_sample_rate {
+            match self.sink.set_sample_rate(sample_rate) {
+                Ok(true) => self.sink_sample_rate = sample_rate,
+                Ok(false) => (),
+                Err(e) => error!(
+                    "Unable to switch the sink to a sample rate of {}: {}",
+                    sample_rate, e
+                ),
+            }
+        }
+
+        if previous_sink_rate != self.sink_sample_rate {
+            if let Some(watchdog) = self.drift_watchdog.as_mut() {
+                watchdog.reset();
+            }
+        }
+
+        // Drift is corrected by resampling, so keep a resampler at the same rate while
+        // there is drift to correct.
+        let correcting_drift = self
+            .drift_watchdog
+            .as_ref()
+            .map_or(false, |watchdog| watchdog.correction_ppm() != 0.0);
+        if sample_rate == self.sink_sample_rate && !correcting_drift {
+            self.resampler = None;
+        } else if previous_source_rate != sample_rate
+            || previous_sink_rate != self.sink_sample_rate
+            || self.resampler.is_none()
+        {
+            debug!(
+                "Resampling from {} to {} Hz",
+                sample_rate, self.sink_sample_rate
+            );
+            let mut resampler = Resampler::new(sample_rate, self.sink_sample_rate);
+            if let Some(watchdog) = &self.drift_watchdog {
+                resampler.set_drift_ppm(watchdog.correction_ppm());
+            }
+            self.resampler = Some(resampler);
+        }
+
+        // The new track doesn't follow on from the frames of the previous one.
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+        self.normaliser.set_sample_rate(self.sink_sample_rate);
+
+        if previous_source_rate != sample_rate || previous_sink_rate != self.sink_sample_rate {
+            self.send_event(PlayerEvent::AudioFormatChanged {
+                sample_rate: self.sink_sample_rate,
+                source_sample_rate: sample_rate,
+            });
+        }
+    }
+
+    fn check_play_thresholds(&mut self, track_id: SpotifyId, duration_ms: u32, completed: bool) {
+        let played_ms = sink_played_ms(
+            self.written_ms,
+            self.sink.buffered_frames(),
+            self.sink_sample_rate,
+        );
+        check_play_thresholds(
+            &mut self.play_thresholds,
+            track_id,
+            played_ms,
+            duration_ms,
+            completed,
+        );
+    }
+
     fn start_playback(
         &mut self,
         track_id: SpotifyId,
@@ -1693,6 +2656,23 @@ impl PlayerInternal {
     ) {
         let audio_item = Box::new(loaded_track.audio_item.clone());
 
+        self.load_attempts = 0;
+        self.configure_sample_rate(loaded_track.decoder.sample_rate());
+        self.written_ms = 0;
+        for entry in self.play_thresholds.iter_mut() {
+            entry.reached = false;
+        }
+        self.stalled_reads_at_start = loaded_track
+            .stream_loader_controller
+            .stream_stats()
+            .map_or(0, |stats| stats.stalled_reads);
+        self.load_latency = Some((loaded_track.load_latency, Instant::now()));
+
+        // a preloaded track now gets the bandwidth of the playing track
+        loaded_track
+            .stream_loader_controller
+            .set_priority(StreamPriority::Playback);
+
         self.send_event(PlayerEvent::TrackChanged { audio_item });
 
         let position_ms = loaded_track.stream_position_ms;
@@ -1768,6 +2748,13 @@ impl PlayerInternal {
         let play_request_id =
             play_request_id_option.unwrap_or(self.play_request_id_generator.get());
 
+        self.load_attempts = 0;
+
+        self.send_diagnostics();
+        self.load_requested_at = Some(Instant::now());
+        self.time_to_first_audio = None;
+        self.load_latency = None;
+
         self.send_event(PlayerEvent::PlayRequestIdChanged { play_request_id });
 
         if !self.config.gapless {
@@ -1869,6 +2856,7 @@ impl PlayerInternal {
                         duration_ms,
                         stream_position_ms,
                         is_explicit,
+                        load_latency: LoadLatency::default(),
                     };
 
                     self.preload = PlayerPreload::None;
@@ -1902,6 +2890,8 @@ impl PlayerInternal {
                         // This may be blocking
                         loaded_track.stream_position_ms = loaded_track.decoder.seek(position_ms)?;
                     }
+                    // It was loaded before the command, so nothing was waited for.
+                    loaded_track.load_latency = LoadLatency::default();
                     self.start_playback(track_id, play_request_id, *loaded_track, play);
                     return Ok(());
                 } else {
@@ -1940,19 +2930,44 @@ impl PlayerInternal {
         self.preload = PlayerPreload::None;
 
         // If we don't have a loader yet, create one from scratch.
-        let loader = loader.unwrap_or_else(|| Box::pin(self.load_track(track_id, position_ms)));
+        let loader = loader.unwrap_or_else(|| {
+            Box::pin(self.load_track(
+                track_id,
+                position_ms,
+                StreamPriority::Playback,
+                Duration::ZERO,
+            ))
+        });
 
         // Set ourselves to a loading state.
         self.state = PlayerState::Loading {
             track_id,
             play_request_id,
             start_playback: play,
+            position_ms,
             loader,
         };
 
         Ok(())
     }
 
+    fn handle_command_prefetch(&mut self, track_ids: Vec<SpotifyId>) {
+        // the tracks that are up next have changed, so stop fetching those that were
+        if let Some(prefetch_task) = self.prefetch_task.take() {
+            prefetch_task.abort();
+        }
+
+        let session = self.session.clone();
+        let bitrate = self.config.bitrate;
+        let stream_scheduler = self.stream_scheduler.clone();
+        let prefetcher = self.prefetcher.clone();
+        self.prefetch_task = Some(tokio::spawn(async move {
+            prefetcher
+                .prefetch(&session, bitrate, &stream_scheduler, track_ids)
+                .await
+        }));
+    }
+
     fn handle_command_preload(&mut self, track_id: SpotifyId) {
         debug!("Preloading track");
         let mut preload_track = true;
@@ -1996,7 +3011,7 @@ impl PlayerInternal {
 
         // schedule the preload of the current track if desired.
         if preload_track {
-            let loader = self.load_track(track_id, 0);
+            let loader = self.load_track(track_id, 0, StreamPriority::Preload, Duration::ZERO);
             self.preload = PlayerPreload::Loading {
                 track_id,
                 loader: Box::pin(loader),
@@ -2027,6 +3042,10 @@ impl PlayerInternal {
         if let Some(decoder) = self.state.decoder() {
             match decoder.seek(position_ms) {
                 Ok(new_position_ms) => {
+                    if let Some(resampler) = self.resampler.as_mut() {
+                        resampler.reset();
+                    }
+
                     if let PlayerState::Playing {
                         ref mut stream_position_ms,
                         track_id,
@@ -2077,10 +3096,17 @@ impl PlayerInternal {
                 track_id,
                 play,
                 position_ms,
-            } => self.handle_command_load(track_id, None, play, position_ms)?,
+                command_received_at,
+            } => {
+                // A seek while loading restarts the load, but keeps the time of the command.
+                self.command_received_at = command_received_at;
+                self.handle_command_load(track_id, None, play, position_ms)?
+            }
 
             PlayerCommand::Preload { track_id } => self.handle_command_preload(track_id),
 
+            PlayerCommand::Prefetch(track_ids) => self.handle_command_prefetch(track_ids),
+
             PlayerCommand::Seek(position_ms) => self.handle_command_seek(position_ms)?,
 
             PlayerCommand::Play => self.handle_play(),
@@ -2089,12 +3115,32 @@ impl PlayerInternal {
 
             PlayerCommand::Stop => self.handle_player_stop(),
 
-            PlayerCommand::SetSession(session) => self.session = session,
+            PlayerCommand::Announce(announcement) => self.handle_command_announce(announcement),
+
+            PlayerCommand::SetSession(session) => {
+                self.session_events = session.get_session_event_channel();
+                self.session = session;
+                // what is available differs between accounts
+                if let Some(prefetch_task) = self.prefetch_task.take() {
+                    prefetch_task.abort();
+                }
+                self.prefetcher.clear();
+            }
 
             PlayerCommand::AddEventSender(sender) => self.event_senders.push(sender),
 
             PlayerCommand::SetSinkEventCallback(callback) => self.sink_event_callback = callback,
 
+            PlayerCommand::AddPlayThreshold(threshold, callback) => {
+                self.play_thresholds.push(PlayThresholdEntry {
+                    threshold,
+                    callback,
+                    reached: false,
+                })
+            }
+
+            PlayerCommand::ClearPlayThresholds => self.play_thresholds.clear(),
+
             PlayerCommand::EmitVolumeChangedEvent(volume) => {
                 self.send_event(PlayerEvent::VolumeChanged { volume })
             }
@@ -2103,14 +3149,18 @@ impl PlayerInternal {
                 self.send_event(PlayerEvent::RepeatChanged { repeat })
             }
 
-            PlayerCommand::EmitShuffleChangedEvent(shuffle) => {
-                self.send_event(PlayerEvent::ShuffleChanged { shuffle })
+            PlayerCommand::EmitShuffleChangedEvent(shuffle, seed) => {
+                self.send_event(PlayerEvent::ShuffleChanged { shuffle, seed })
             }
 
             PlayerCommand::EmitAutoPlayChangedEvent(auto_play) => {
                 self.send_event(PlayerEvent::AutoPlayChanged { auto_play })
             }
 
+            PlayerCommand::EmitContextSegmentEvent { uri, metadata } => {
+                self.send_event(PlayerEvent::ContextSegment { uri, metadata })
+            }
+
             PlayerCommand::EmitSessionClientChangedEvent {
                 client_id,
                 client_name,
@@ -2143,6 +3193,10 @@ impl PlayerInternal {
                 self.auto_normalise_as_album = setting
             }
 
+            PlayerCommand::SetContentPolicy(policy) => {
+                self.handle_command_set_content_policy(policy)
+            }
+
             PlayerCommand::EmitFilterExplicitContentChangedEvent(filter) => {
                 self.send_event(PlayerEvent::FilterExplicitContentChanged { filter });
 
@@ -2175,16 +3229,195 @@ impl PlayerInternal {
         Ok(())
     }
 
+    fn handle_command_set_content_policy(&mut self, policy: ContentPolicy) {
+        self.config.content_policy = policy;
+        self.enforce_content_policy();
+
+        // a preloaded track was checked against the previous policy, and is loaded again
+        // when it is up next
+        self.preload = PlayerPreload::None;
+    }
+
+    // Checks the track that is playing or paused against the content policy. Outside of the
+    // allowed hours it is paused, otherwise a track that is blocked is stopped and skipped.
+    fn enforce_content_policy(&mut self) {
+        let (track_id, play_request_id, checked) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id,
+                ref audio_item,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id,
+                play_request_id,
+                ref audio_item,
+                ..
+            } => (
+                track_id,
+                play_request_id,
+                self.config
+                    .content_policy
+                    .check(audio_item, OffsetDateTime::now_utc()),
+            ),
+            _ => return,
+        };
+
+        let reason = match checked {
+            Ok(()) => return,
+            Err(reason) => reason,
+        };
+
+        self.send_event(PlayerEvent::Blocked {
+            track_id,
+            play_request_id,
+            reason,
+        });
+
+        if reason == BlockReason::OutsideAllowedHours {
+            warn!("Pausing, playback is outside of the allowed hours");
+            self.handle_pause();
+        } else {
+            warn!(
+                "Currently loaded track is blocked by the content policy: {} -- skipping to next track.",
+                reason
+            );
+            self.send_diagnostics();
+            self.ensure_sink_stopped(false);
+            self.state = PlayerState::Stopped;
+            self.send_event(PlayerEvent::EndOfTrack {
+                track_id,
+                play_request_id,
+            });
+        }
+    }
+
+    // Reports the diagnostics of the track that is playing or paused. After the end of a
+    // track they have been reported already.
+    fn send_diagnostics(&mut self) {
+        let (track_id, play_request_id, stats) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id,
+                ref stream_loader_controller,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id,
+                play_request_id,
+                ref stream_loader_controller,
+                ..
+            } => (
+                track_id,
+                play_request_id,
+                stream_loader_controller.stream_stats(),
+            ),
+            _ => return,
+        };
+
+        let diagnostics = match stats {
+            Some(stats) => PlaybackDiagnostics {
+                underruns: stats
+                    .stalled_reads
+                    .saturating_sub(self.stalled_reads_at_start),
+                download_throughput: Some(stats.throughput).filter(|&throughput| throughput > 0),
+                buffered_read_ratio: if stats.reads > 0 {
+                    stats.reads.saturating_sub(stats.stalled_reads) as f64 / stats.reads as f64
+                } else {
+                    1.0
+                },
+                time_to_first_audio: self.time_to_first_audio,
+            },
+            None => PlaybackDiagnostics {
+                buffered_read_ratio: 1.0,
+                time_to_first_audio: self.time_to_first_audio,
+                ..Default::default()
+            },
+        };
+
+        debug!(
+            "Diagnostics for <{}>: {:?}",
+            track_id.to_uri().unwrap_or_default(),
+            diagnostics
+        );
+        self.send_event(PlayerEvent::Diagnostics {
+            play_request_id,
+            track_id,
+            diagnostics,
+        });
+
+        if let Some(adapter) = self.bitrate_adapter.as_mut() {
+            let changed = adapter.update(diagnostics.download_throughput, diagnostics.underruns);
+            if let Some(bitrate) = changed {
+                let download_throughput = adapter.throughput().unwrap_or_default();
+                info!(
+                    "Switching to {:?} at a download throughput of {} bytes/s",
+                    bitrate, download_throughput
+                );
+                self.config.bitrate = bitrate;
+                self.send_event(PlayerEvent::BitrateChanged {
+                    bitrate,
+                    download_throughput,
+                });
+            }
+        }
+    }
+
+    // Reports how long it took from the load command until the first audio of the current
+    // track was written to the sink.
+    fn send_load_latency(&mut self) {
+        let (track_id, play_request_id) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id,
+                ..
+            } => (track_id, play_request_id),
+            _ => return,
+        };
+        let (latency, loaded_at) = match self.load_latency.take() {
+            Some(load_latency) => load_latency,
+            None => return,
+        };
+
+        let requested_at = match self.load_requested_at {
+            Some(requested_at) => requested_at,
+            None => return,
+        };
+        let latency = latency.complete(
+            self.command_received_at,
+            requested_at,
+            loaded_at,
+            Instant::now(),
+        );
+
+        debug!(
+            "Load latency for <{}>: {:?}",
+            track_id.to_uri().unwrap_or_default(),
+            latency
+        );
+        self.load_latency_metrics.lock().record(latency);
+        self.send_event(PlayerEvent::LoadLatency {
+            play_request_id,
+            track_id,
+            latency,
+        });
+    }
+
     fn send_event(&mut self, event: PlayerEvent) {
+        if let Some(state_event) = event.to_state_event() {
+            self.session.state().record(state_event);
+        }
         self.event_senders
-            .retain(|sender| sender.send(event.clone()).is_ok());
+            .retain(|sender| sender.send(event.clone()));
     }
 
     fn load_track(
         &mut self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> impl FusedFuture<Output = Result<PlayerLoadedTrackData, ()>> + Send + 'static {
+        stream_priority: StreamPriority,
+        delay: Duration,
+    ) -> impl FusedFuture<Output = Result<PlayerLoadedTrackData, LoadError>> + Send + 'static {
         // This method creates a future that returns the loaded stream and associated info.
         // Ideally all work should be done using asynchronous code. However, seek() on the
         // audio stream is implemented in a blocking fashion. Thus, we can't turn it into future
@@ -2194,6 +3427,10 @@ impl PlayerInternal {
         let loader = PlayerTrackLoader {
             session: self.session.clone(),
             config: self.config.clone(),
+            stream_scheduler: self.stream_scheduler.clone(),
+            prefetcher: self.prefetcher.clone(),
+            stream_priority,
+            seek_table_scans: self.seek_table_scans.clone(),
         };
 
         let (result_tx, result_rx) = oneshot::channel();
@@ -2201,10 +3438,13 @@ impl PlayerInternal {
         let load_handles_clone = self.load_handles.clone();
         let handle = tokio::runtime::Handle::current();
         let load_handle = thread::spawn(move || {
-            let data = handle.block_on(loader.load_track(spotify_id, position_ms));
-            if let Some(data) = data {
-                let _ = result_tx.send(data);
-            }
+            let data = handle.block_on(async {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                loader.load_track(spotify_id, position_ms).await
+            });
+            let _ = result_tx.send(data);
 
             let mut load_handles = load_handles_clone.lock();
             load_handles.remove(&thread::current().id());
@@ -2213,7 +3453,7 @@ impl PlayerInternal {
         let mut load_handles = self.load_handles.lock();
         load_handles.insert(load_handle.thread().id(), load_handle);
 
-        result_rx.map_err(|_| ())
+        result_rx.map(|result| result.unwrap_or(Err(LoadError::Failed)))
     }
 
     fn preload_data_before_playback(&mut self) -> PlayerResult {
@@ -2229,7 +3469,7 @@ impl PlayerInternal {
 
             // Request the part we want to wait for blocking. This effectively means we wait for the previous request to partially complete.
             let wait_for_data_length =
-                (READ_AHEAD_BEFORE_PLAYBACK.as_secs_f32() * bytes_per_second as f32) as usize;
+                (self.config.seek_buffer.as_secs_f32() * bytes_per_second as f32) as usize;
 
             stream_loader_controller
                 .fetch_next_and_wait(request_data_length, wait_for_data_length)
@@ -2277,6 +3517,9 @@ impl fmt::Debug for PlayerCommand {
             PlayerCommand::Preload { track_id } => {
                 f.debug_tuple("Preload").field(&track_id).finish()
             }
+            PlayerCommand::Prefetch(track_ids) => {
+                f.debug_tuple("Prefetch").field(&track_ids).finish()
+            }
             PlayerCommand::Play => f.debug_tuple("Play").finish(),
             PlayerCommand::Pause => f.debug_tuple("Pause").finish(),
             PlayerCommand::Stop => f.debug_tuple("Stop").finish(),
@@ -2286,6 +3529,10 @@ impl fmt::Debug for PlayerCommand {
             PlayerCommand::SetSinkEventCallback(_) => {
                 f.debug_tuple("SetSinkEventCallback").finish()
             }
+            PlayerCommand::AddPlayThreshold(threshold, _) => {
+                f.debug_tuple("AddPlayThreshold").field(&threshold).finish()
+            }
+            PlayerCommand::ClearPlayThresholds => f.debug_tuple("ClearPlayThresholds").finish(),
             PlayerCommand::EmitVolumeChangedEvent(volume) => f
                 .debug_tuple("EmitVolumeChangedEvent")
                 .field(&volume)
@@ -2294,6 +3541,14 @@ impl fmt::Debug for PlayerCommand {
                 .debug_tuple("SetAutoNormaliseAsAlbum")
                 .field(&setting)
                 .finish(),
+            PlayerCommand::SetContentPolicy(policy) => {
+                f.debug_tuple("SetContentPolicy").field(&policy).finish()
+            }
+            PlayerCommand::Announce(announcement) => f
+                .debug_tuple("Announce")
+                .field(&announcement.samples.len())
+                .field(&announcement.ducking)
+                .finish(),
             PlayerCommand::EmitFilterExplicitContentChangedEvent(filter) => f
                 .debug_tuple("EmitFilterExplicitContentChangedEvent")
                 .field(&filter)
@@ -2326,9 +3581,10 @@ impl fmt::Debug for PlayerCommand {
                 .field(&client_brand_name)
                 .field(&client_model_name)
                 .finish(),
-            PlayerCommand::EmitShuffleChangedEvent(shuffle) => f
+            PlayerCommand::EmitShuffleChangedEvent(shuffle, seed) => f
                 .debug_tuple("EmitShuffleChangedEvent")
                 .field(&shuffle)
+                .field(&seed)
                 .finish(),
             PlayerCommand::EmitRepeatChangedEvent(repeat) => f
                 .debug_tuple("EmitRepeatChangedEvent")
@@ -2338,6 +3594,10 @@ impl fmt::Debug for PlayerCommand {
                 .debug_tuple("EmitAutoPlayChangedEvent")
                 .field(&auto_play)
                 .finish(),
+            PlayerCommand::EmitContextSegmentEvent { uri, .. } => f
+                .debug_struct("EmitContextSegmentEvent")
+                .field("uri", &uri)
+                .finish(),
         }
     }
 }
@@ -2388,6 +3648,18 @@ impl fmt::Debug for PlayerState {
     }
 }
 
+// Reads where the pages of a cached Ogg Vorbis file start.
+fn make_seek_table(path: &Path, key: Option<AudioKey>) -> io::Result<SeekTable> {
+    let file = File::open(path)?;
+    let byte_len = file.metadata()?.len();
+    let mut stream = Subfile::new(
+        AudioDecrypt::new(key, file),
+        SPOTIFY_OGG_HEADER_END,
+        byte_len,
+    )?;
+    SeekTable::scan(&mut stream, byte_len)
+}
+
 struct Subfile<T: Read + Seek> {
     stream: T,
     offset: u64,
@@ -2446,3 +3718,260 @@ where
         Some(self.length)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn channel(
+        capacity: usize,
+        policy: EventOverflowPolicy,
+    ) -> (PlayerEventSender, PlayerEventChannel) {
+        let queue = PlayerEventQueue::new(capacity, policy);
+        (PlayerEventSender(queue.clone()), PlayerEventChannel(queue))
+    }
+
+    fn volume(volume: u16) -> PlayerEvent {
+        PlayerEvent::VolumeChanged { volume }
+    }
+
+    fn paused(position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Paused {
+            play_request_id: 0,
+            track_id: SpotifyId::from_base62("4GNcXTGWmnZ3ySrqvol3o4").expect("valid base62"),
+            position_ms,
+        }
+    }
+
+    fn drain(events: PlayerEventChannel) -> Vec<String> {
+        let mut received = Vec::new();
+        while let Some(Some(event)) = events.try_recv() {
+            received.push(format!("{event:?}"));
+        }
+        received
+    }
+
+    #[test]
+    fn drops_oldest_events() {
+        let (sender, events) = channel(2, EventOverflowPolicy::DropOldest);
+        for position_ms in 0..4 {
+            assert!(sender.send(paused(position_ms)));
+        }
+
+        assert_eq!(events.dropped_events(), 2);
+        let expected: Vec<_> = [paused(2), paused(3)]
+            .iter()
+            .map(|event| format!("{event:?}"))
+            .collect();
+        assert_eq!(drain(events), expected);
+    }
+
+    #[test]
+    fn coalesces_state_events() {
+        let (sender, events) = channel(2, EventOverflowPolicy::Coalesce);
+        assert!(sender.send(volume(1)));
+        assert!(sender.send(paused(0)));
+        // replaces the queued volume, which is outdated now
+        assert!(sender.send(volume(2)));
+        assert_eq!(events.dropped_events(), 0);
+        // nothing to replace, so the oldest event that only reports state is dropped
+        assert!(sender.send(paused(1)));
+        assert_eq!(events.dropped_events(), 1);
+
+        let expected: Vec<_> = [paused(0), paused(1)]
+            .iter()
+            .map(|event| format!("{event:?}"))
+            .collect();
+        assert_eq!(drain(events), expected);
+    }
+
+    #[test]
+    fn blocks_until_there_is_room() {
+        let (sender, mut events) = channel(1, EventOverflowPolicy::Block);
+        assert!(sender.send(volume(1)));
+
+        let blocked = thread::spawn(move || sender.send(volume(2)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+
+        assert_eq!(
+            events.blocking_recv().map(|e| format!("{e:?}")),
+            Some(format!("{:?}", volume(1)))
+        );
+        assert!(blocked.join().unwrap());
+        assert_eq!(events.dropped_events(), 0);
+
+        // a closed channel unblocks the sender
+        let (sender, events) = channel(1, EventOverflowPolicy::Block);
+        assert!(sender.send(volume(1)));
+        let blocked = thread::spawn(move || sender.send(volume(2)));
+        thread::sleep(Duration::from_millis(50));
+        drop(events);
+        assert!(!blocked.join().unwrap());
+    }
+
+    fn end_of_track() -> PlayerEvent {
+        PlayerEvent::EndOfTrack {
+            play_request_id: 0,
+            track_id: SpotifyId::from_base62("4GNcXTGWmnZ3ySrqvol3o4").expect("valid base62"),
+        }
+    }
+
+    #[test]
+    fn coalescing_never_drops_the_end_of_a_track() {
+        let (sender, mut events) = channel(1, EventOverflowPolicy::Coalesce);
+        assert!(sender.send(end_of_track()));
+
+        let blocked = thread::spawn(move || sender.send(paused(0)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+
+        assert_eq!(
+            events.blocking_recv().map(|e| format!("{e:?}")),
+            Some(format!("{:?}", end_of_track()))
+        );
+        assert!(blocked.join().unwrap());
+        assert_eq!(events.dropped_events(), 0);
+    }
+
+    #[test]
+    fn drops_only_hints_when_commands_pile_up() {
+        let (sender, mut receiver) = command_channel(1);
+        let track_id = SpotifyId::from_base62("4GNcXTGWmnZ3ySrqvol3o4").expect("valid base62");
+
+        assert!(sender.send(PlayerCommand::Play));
+        assert!(!sender.send(PlayerCommand::Preload { track_id }));
+        assert!(!sender.send(PlayerCommand::Prefetch(vec![track_id])));
+        assert!(sender.send(PlayerCommand::Seek(1000)));
+        assert!(sender.send(PlayerCommand::Stop));
+        assert_eq!(sender.dropped.load(Ordering::Relaxed), 2);
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut received = Vec::new();
+        while let Poll::Ready(Some(cmd)) = receiver.poll_recv(&mut cx) {
+            received.push(format!("{cmd:?}"));
+        }
+        assert_eq!(received, ["Play", "Seek(1000)", "Stop"]);
+
+        // there is room for hints again once the player caught up
+        assert!(sender.send(PlayerCommand::Preload { track_id }));
+    }
+
+    fn threshold(
+        threshold: PlayThreshold,
+        calls: &Arc<Mutex<Vec<(SpotifyId, Duration)>>>,
+    ) -> PlayThresholdEntry {
+        let calls = calls.clone();
+        PlayThresholdEntry {
+            threshold,
+            callback: Box::new(move |track_id, played| calls.lock().push((track_id, played))),
+            reached: false,
+        }
+    }
+
+    #[test]
+    fn play_thresholds_are_reached_by_what_was_played() {
+        let percent = PlayThreshold::Percent(50);
+        assert!(!percent.is_reached(99_999, 200_000, false));
+        assert!(percent.is_reached(100_000, 200_000, false));
+        // without a duration there is no percentage
+        assert!(!percent.is_reached(100_000, 0, true));
+
+        let played = PlayThreshold::Played(Duration::from_secs(30));
+        assert!(!played.is_reached(29_999, 200_000, false));
+        assert!(played.is_reached(30_000, 200_000, false));
+
+        let completed = PlayThreshold::Completed;
+        assert!(!completed.is_reached(200_000, 200_000, false));
+        assert!(completed.is_reached(0, 200_000, true));
+    }
+
+    #[test]
+    fn audio_buffered_by_the_sink_was_not_played() {
+        assert_eq!(sink_played_ms(10_000, None, 44100), 10_000);
+        assert_eq!(sink_played_ms(10_000, Some(0), 44100), 10_000);
+        assert_eq!(sink_played_ms(10_000, Some(44100), 44100), 9_000);
+        assert_eq!(sink_played_ms(10_000, Some(96000), 48000), 8_000);
+        // the buffer may still hold the end of the previous track
+        assert_eq!(sink_played_ms(500, Some(44100), 44100), 0);
+    }
+
+    #[test]
+    fn calls_back_once_per_track() {
+        let track_id = SpotifyId::from_base62("4GNcXTGWmnZ3ySrqvol3o4").expect("valid base62");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut entries = vec![
+            threshold(PlayThreshold::Played(Duration::from_secs(30)), &calls),
+            threshold(PlayThreshold::Completed, &calls),
+        ];
+
+        check_play_thresholds(&mut entries, track_id, 10_000, 60_000, false);
+        assert!(calls.lock().is_empty());
+
+        check_play_thresholds(&mut entries, track_id, 30_000, 60_000, false);
+        check_play_thresholds(&mut entries, track_id, 40_000, 60_000, false);
+        assert_eq!(*calls.lock(), [(track_id, Duration::from_secs(30))]);
+
+        check_play_thresholds(&mut entries, track_id, 60_000, 60_000, true);
+        check_play_thresholds(&mut entries, track_id, 60_000, 60_000, true);
+        assert_eq!(
+            *calls.lock(),
+            [
+                (track_id, Duration::from_secs(30)),
+                (track_id, Duration::from_secs(60))
+            ]
+        );
+        assert!(entries.iter().all(|entry| entry.reached));
+    }
+
+    #[test]
+    fn load_latency_spans_from_the_command_to_the_first_audio() {
+        let received_at = Instant::now();
+        let requested_at = received_at + Duration::from_millis(10);
+        let loaded_at = requested_at + Duration::from_millis(200);
+        let now = loaded_at + Duration::from_millis(50);
+        let loader = LoadLatency {
+            metadata: Duration::from_millis(100),
+            buffer: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let latency = loader.complete(Some(received_at), requested_at, loaded_at, now);
+        assert_eq!(latency.command, Duration::from_millis(10));
+        assert_eq!(latency.metadata, Duration::from_millis(100));
+        assert_eq!(latency.first_audio, Duration::from_millis(50));
+        assert_eq!(latency.total, Duration::from_millis(260));
+
+        // without a command, from the load request
+        let latency = loader.complete(None, requested_at, loaded_at, now);
+        assert_eq!(latency.command, Duration::ZERO);
+        assert_eq!(latency.total, Duration::from_millis(250));
+
+        // a preloaded track was loaded before it was requested
+        let latency = LoadLatency::default().complete(None, now, requested_at, now);
+        assert_eq!(latency.first_audio, Duration::from_millis(250));
+        assert_eq!(latency.total, Duration::ZERO);
+    }
+
+    #[test]
+    fn load_latency_metrics_aggregate_the_loads() {
+        let mut metrics = LoadLatencyMetrics::default();
+        assert_eq!(metrics.mean(), None);
+
+        for total_ms in [100, 300, 200] {
+            metrics.record(LoadLatency {
+                total: Duration::from_millis(total_ms),
+                ..Default::default()
+            });
+        }
+
+        assert_eq!(metrics.loads, 3);
+        assert_eq!(metrics.mean(), Some(Duration::from_millis(200)));
+        assert_eq!(metrics.max, Duration::from_millis(300));
+        assert_eq!(
+            metrics.last.map(|latency| latency.total),
+            Some(Duration::from_millis(200))
+        );
+    }
+}
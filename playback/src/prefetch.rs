@@ -0,0 +1,258 @@
+//! Fetches the metadata and audio keys of the tracks that are up next, so that loading them
+//! doesn't have to wait for these round trips, which can take seconds on a slow link.
+
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+
+use crate::{
+    audio::StreamScheduler,
+    config::Bitrate,
+    core::{audio_key::AudioKey, FileId, Session, SpotifyId},
+    metadata::audio::AudioItem,
+    resolve::{find_available_alternative, select_file},
+};
+
+// How often to check whether playback has caught up again while it is starving.
+const STARVING_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What is known about a track before loading it.
+#[derive(Debug, Clone)]
+pub struct PrefetchedTrack {
+    /// The metadata, or that of the alternative that is played instead.
+    pub audio_item: AudioItem,
+    /// The key of the file that was selected for the bitrate, if it could be obtained.
+    pub key: Option<(FileId, AudioKey)>,
+}
+
+/// The prefetched tracks, at most as many as are prefetched at a time.
+#[derive(Debug, Default)]
+pub struct Prefetcher {
+    tracks: Mutex<HashMap<SpotifyId, PrefetchedTrack>>,
+}
+
+impl Prefetcher {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Removes a track to load it.
+    pub fn take(&self, track_id: SpotifyId) -> Option<PrefetchedTrack> {
+        self.tracks.lock().remove(&track_id)
+    }
+
+    pub fn clear(&self) {
+        self.tracks.lock().clear();
+    }
+
+    /// Fetches the tracks that aren't yet, one at a time and in order, and forgets those
+    /// that aren't up next anymore.
+    ///
+    /// Prefetching waits while any playback stream is starving, so that it doesn't take away
+    /// bandwidth from the track that is playing.
+    pub async fn prefetch(
+        &self,
+        session: &Session,
+        bitrate: Bitrate,
+        stream_scheduler: &StreamScheduler,
+        track_ids: Vec<SpotifyId>,
+    ) {
+        self.prefetch_with(
+            track_ids,
+            || stream_scheduler.is_playback_starving(),
+            |track_id| Self::fetch(session, bitrate, track_id),
+        )
+        .await
+    }
+
+    async fn prefetch_with<F, Fut>(
+        &self,
+        track_ids: Vec<SpotifyId>,
+        is_starving: impl Fn() -> bool,
+        mut fetch: F,
+    ) where
+        F: FnMut(SpotifyId) -> Fut,
+        Fut: Future<Output = Option<PrefetchedTrack>>,
+    {
+        self.tracks
+            .lock()
+            .retain(|track_id, _| track_ids.contains(track_id));
+
+        for track_id in track_ids {
+            if self.tracks.lock().contains_key(&track_id) {
+                continue;
+            }
+
+            while is_starving() {
+                tokio::time::sleep(STARVING_POLL_INTERVAL).await;
+            }
+
+            if let Some(track) = fetch(track_id).await {
+                trace!("Prefetched <{}>", track_id.to_uri().unwrap_or_default());
+                self.tracks.lock().insert(track_id, track);
+            }
+        }
+    }
+
+    async fn fetch(
+        session: &Session,
+        bitrate: Bitrate,
+        track_id: SpotifyId,
+    ) -> Option<PrefetchedTrack> {
+        let audio_item = match AudioItem::get_file(session, track_id).await {
+            Ok(audio_item) => find_available_alternative(session, audio_item).await?,
+            Err(e) => {
+                debug!("Unable to prefetch audio item: {}", e);
+                return None;
+            }
+        };
+
+        // The key is requested the same way as when loading, with the requested track.
+        let key = match select_file(&audio_item, bitrate) {
+            Some((_, file_id)) => match session.audio_key().request(track_id, file_id).await {
+                Ok(key) => Some((file_id, key)),
+                Err(e) => {
+                    debug!("Unable to prefetch key: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Some(PrefetchedTrack { audio_item, key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+    use crate::core::spotify_id::SpotifyItemType;
+    use crate::metadata::{
+        artist::ArtistsWithRole,
+        audio::{AudioFiles, UniqueFields},
+    };
+
+    fn id(n: u128) -> SpotifyId {
+        SpotifyId {
+            id: n,
+            item_type: SpotifyItemType::Track,
+        }
+    }
+
+    fn track(track_id: SpotifyId) -> PrefetchedTrack {
+        PrefetchedTrack {
+            audio_item: AudioItem {
+                track_id,
+                uri: String::new(),
+                files: AudioFiles(HashMap::new()),
+                name: "Track".into(),
+                covers: Vec::new(),
+                language: Vec::new(),
+                duration_ms: 180_000,
+                is_explicit: false,
+                availability: Ok(()),
+                alternatives: None,
+                unique_fields: UniqueFields::Track {
+                    artists: ArtistsWithRole(Vec::new()),
+                    album: "Album".into(),
+                    album_artists: Vec::new(),
+                    popularity: 0,
+                    number: 1,
+                    disc_number: 1,
+                },
+            },
+            key: None,
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    // Prefetches `track_ids`, of which `unavailable` can't be fetched, and returns the
+    // tracks that were fetched in order.
+    fn prefetch(
+        prefetcher: &Prefetcher,
+        track_ids: &[u128],
+        unavailable: &[u128],
+    ) -> Vec<SpotifyId> {
+        let fetched = RefCell::new(Vec::new());
+        block_on(prefetcher.prefetch_with(
+            track_ids.iter().copied().map(id).collect(),
+            || false,
+            |track_id| {
+                fetched.borrow_mut().push(track_id);
+                let available = !unavailable.contains(&track_id.id);
+                async move { available.then(|| track(track_id)) }
+            },
+        ));
+        fetched.into_inner()
+    }
+
+    #[test]
+    fn fetches_the_tracks_up_next_once() {
+        let prefetcher = Prefetcher::default();
+        assert_eq!(
+            prefetch(&prefetcher, &[1, 2, 3], &[]),
+            [id(1), id(2), id(3)]
+        );
+        assert_eq!(prefetch(&prefetcher, &[2, 3, 4], &[]), [id(4)]);
+
+        let track = prefetcher.take(id(3)).unwrap();
+        assert_eq!(track.audio_item.track_id, id(3));
+        assert!(prefetcher.take(id(3)).is_none());
+        // taken to be loaded, so fetched again if it is still up next
+        assert_eq!(prefetch(&prefetcher, &[3, 4], &[]), [id(3)]);
+    }
+
+    #[test]
+    fn forgets_the_tracks_that_are_not_up_next() {
+        let prefetcher = Prefetcher::default();
+        prefetch(&prefetcher, &[1, 2, 3], &[]);
+        prefetch(&prefetcher, &[3, 4], &[]);
+        assert!(prefetcher.take(id(1)).is_none());
+        assert!(prefetcher.take(id(2)).is_none());
+        assert!(prefetcher.take(id(4)).is_some());
+
+        prefetcher.clear();
+        assert!(prefetcher.take(id(3)).is_none());
+    }
+
+    #[test]
+    fn tries_again_when_a_track_could_not_be_fetched() {
+        let prefetcher = Prefetcher::default();
+        assert_eq!(prefetch(&prefetcher, &[1, 2], &[1]), [id(1), id(2)]);
+        assert!(prefetcher.take(id(1)).is_none());
+        assert_eq!(prefetch(&prefetcher, &[1, 2], &[]), [id(1)]);
+        assert!(prefetcher.take(id(1)).is_some());
+    }
+
+    #[test]
+    fn waits_while_playback_is_starving() {
+        let prefetcher = Prefetcher::default();
+        let starving_polls = Cell::new(2_u32);
+        let fetched_after = Cell::new(None);
+
+        block_on(prefetcher.prefetch_with(
+            vec![id(1)],
+            || {
+                let polls = starving_polls.get();
+                starving_polls.set(polls.saturating_sub(1));
+                polls > 0
+            },
+            |track_id| {
+                fetched_after.set(Some(starving_polls.get()));
+                async move { Some(track(track_id)) }
+            },
+        ));
+
+        assert_eq!(fetched_after.get(), Some(0));
+        assert!(prefetcher.take(id(1)).is_some());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::NUM_CHANNELS;
+
+const CHANNELS: usize = NUM_CHANNELS as usize;
+
+/// Converts decoded samples to another sample rate by linear interpolation, for when the
+/// sink can't switch to the sample rate of a track.
+pub struct Resampler {
+    from_rate: u32,
+    to_rate: u32,
+    // The position of the next output frame, in input frames from `last_frame`.
+    position: f64,
+    // The last frame of the previous packet, to interpolate across packets.
+    last_frame: Option<[f64; CHANNELS]>,
+    // A correction for clock drift of the sink, in parts per million.
+    drift_ppm: f64,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        Self {
+            from_rate,
+            to_rate,
+            position: 0.0,
+            last_frame: None,
+            drift_ppm: 0.0,
+        }
+    }
+
+    pub fn from_rate(&self) -> u32 {
+        self.from_rate
+    }
+
+    pub fn to_rate(&self) -> u32 {
+        self.to_rate
+    }
+
+    /// Resamples slightly faster (positive) or slower (negative) than the nominal rates, to
+    /// make up for the clock of the sink drifting from its sample rate.
+    pub fn set_drift_ppm(&mut self, ppm: f64) {
+        self.drift_ppm = ppm;
+    }
+
+    /// Forgets the frames of the previous packets, for when the next packet doesn't follow
+    /// on from them, e.g. after seeking or at the start of another track.
+    pub fn reset(&mut self) {
+        self.position = 0.0;
+        self.last_frame = None;
+    }
+
+    pub fn resample(&mut self, samples: &[f64]) -> Vec<f64> {
+        let step = self.from_rate as f64 / self.to_rate as f64 * (1.0 + self.drift_ppm * 1e-6);
+
+        let mut frames: Vec<&[f64]> = Vec::with_capacity(samples.len() / CHANNELS + 1);
+        if let Some(last_frame) = &self.last_frame {
+            frames.push(last_frame);
+        }
+        frames.extend(samples.chunks_exact(CHANNELS));
+
+        let mut resampled = Vec::with_capacity((samples.len() as f64 / step) as usize + CHANNELS);
+        while self.position + 1.0 < frames.len() as f64 {
+            let index = self.position as usize;
+            let fraction = self.position - index as f64;
+            let (current, next) = (frames[index], frames[index + 1]);
+
+            for (current, next) in current.iter().zip(next) {
+                resampled.push(current + (next - current) * fraction);
+            }
+            self.position += step;
+        }
+
+        if let Some(last) = frames.last() {
+            let mut last_frame = [0.0; CHANNELS];
+            last_frame.copy_from_slice(last);
+            self.position -= (frames.len() - 1) as f64;
+            self.last_frame = Some(last_frame);
+        }
+
+        resampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ramp of frames with the same value on both channels.
+    fn ramp(from: usize, to: usize) -> Vec<f64> {
+        (from..to)
+            .flat_map(|frame| [frame as f64; CHANNELS])
+            .collect()
+    }
+
+    fn left(samples: &[f64]) -> Vec<f64> {
+        samples.iter().step_by(CHANNELS).copied().collect()
+    }
+
+    #[test]
+    fn keeps_the_samples_at_the_same_rate() {
+        let mut resampler = Resampler::new(44100, 44100);
+        let samples = ramp(0, 8);
+
+        let resampled = resampler.resample(&samples);
+        assert_eq!(resampled, samples[..samples.len() - CHANNELS]);
+        // The last frame is held back to interpolate towards the next packet.
+        assert_eq!(left(&resampler.resample(&ramp(8, 10))), [7.0, 8.0]);
+    }
+
+    #[test]
+    fn interpolates_between_frames() {
+        let mut resampler = Resampler::new(48000, 96000);
+        assert_eq!(left(&resampler.resample(&ramp(0, 3))), [0.0, 0.5, 1.0, 1.5]);
+
+        let mut resampler = Resampler::new(96000, 48000);
+        assert_eq!(left(&resampler.resample(&ramp(0, 8))), [0.0, 2.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn continues_across_packets() {
+        let mut resampler = Resampler::new(44100, 48000);
+        let whole = left(&resampler.resample(&ramp(0, 1000)));
+
+        let mut resampler = Resampler::new(44100, 48000);
+        let mut split = Vec::new();
+        for start in (0..1000).step_by(70) {
+            split.extend(left(
+                &resampler.resample(&ramp(start, (start + 70).min(1000))),
+            ));
+        }
+
+        assert_eq!(split.len(), whole.len());
+        for (split, whole) in split.iter().zip(&whole) {
+            assert!((split - whole).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn forgets_the_previous_packet_on_reset() {
+        let mut resampler = Resampler::new(44100, 48000);
+        resampler.resample(&ramp(100, 177));
+        resampler.reset();
+
+        let mut fresh = Resampler::new(44100, 48000);
+        assert_eq!(
+            resampler.resample(&ramp(0, 50)),
+            fresh.resample(&ramp(0, 50))
+        );
+    }
+
+    #[test]
+    fn corrects_drift() {
+        let mut nominal = Resampler::new(48000, 48000);
+        let mut faster = Resampler::new(48000, 48000);
+        faster.set_drift_ppm(1000.0);
+
+        let samples = ramp(0, 10001);
+        let nominal = nominal.resample(&samples).len() / CHANNELS;
+        let faster = faster.resample(&samples).len() / CHANNELS;
+        assert_eq!(nominal, 10000);
+        assert_eq!(faster, 9991);
+    }
+}
@@ -0,0 +1,205 @@
+//! Resolves everything that is needed to stream a track outside of librespot, for example
+//! to hand it off to a GStreamer pipeline while librespot handles authentication, metadata
+//! and Spotify Connect.
+
+use futures_util::{future, stream::futures_unordered::FuturesUnordered, StreamExt};
+use thiserror::Error;
+
+use crate::{
+    config::Bitrate,
+    core::{
+        audio_key::AudioKey,
+        cdn_url::CdnUrl,
+        error::{ErrorCode, ErrorKind, Recovery},
+        Error, FileId, Session, SpotifyId,
+    },
+    metadata::audio::{AudioFileFormat, AudioFiles, AudioItem},
+};
+
+/// Spotify inserts a custom Ogg packet at the start with custom metadata values, that you would
+/// otherwise expect in Vorbis comments. This packet isn't well-formed and players may balk at it.
+pub const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("<{0}> is not available")]
+    Unavailable(String),
+    #[error("<{0}> is not available in any supported format")]
+    NoSupportedFormat(String),
+}
+
+impl From<ResolveError> for Error {
+    fn from(err: ResolveError) -> Self {
+        Error::with_code(ErrorKind::Unavailable, err)
+    }
+}
+
+impl ErrorCode for ResolveError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Unavailable(_) => "resolve.unavailable",
+            Self::NoSupportedFormat(_) => "resolve.no_supported_format",
+        }
+    }
+
+    fn recovery(&self, kind: ErrorKind) -> Option<Recovery> {
+        // neither the track nor its alternatives can be played by this user
+        match kind {
+            ErrorKind::Unavailable => Some(Recovery::GiveUp),
+            _ => None,
+        }
+    }
+}
+
+/// An audio file that has been resolved for streaming.
+#[derive(Debug, Clone)]
+pub struct ResolvedAudioFile {
+    /// The metadata of the track or episode that is streamed. This may be an alternative
+    /// of the requested track when that is not available in the user's region.
+    pub audio_item: AudioItem,
+    pub file_id: FileId,
+    pub format: AudioFileFormat,
+    /// The nominal data rate of the file, which is suitable to size read ahead buffers.
+    pub bytes_per_second: usize,
+    /// The key to decrypt the file with AES-128-CTR and [`AUDIO_AESIV`]. `None` if no key
+    /// could be obtained, in which case the file may not be encrypted at all.
+    ///
+    /// [`AUDIO_AESIV`]: crate::audio::AUDIO_AESIV
+    pub key: Option<AudioKey>,
+    /// The URLs the file can be downloaded from. They expire after a while, at which
+    /// point this has to be resolved again.
+    pub cdn_url: CdnUrl,
+    /// The offset at which the decrypted audio data starts.
+    pub header_offset: u64,
+}
+
+/// Resolves the file of `track_id` that best matches `bitrate`, together with its CDN URLs
+/// and audio key.
+pub async fn resolve_audio_file(
+    session: &Session,
+    track_id: SpotifyId,
+    bitrate: Bitrate,
+) -> Result<ResolvedAudioFile, Error> {
+    let audio_item = AudioItem::get_file(session, track_id).await?;
+    let audio_item = find_available_alternative(session, audio_item)
+        .await
+        .ok_or_else(|| ResolveError::Unavailable(track_id.to_uri().unwrap_or_default()))?;
+
+    let (format, file_id) = select_file(&audio_item, bitrate)
+        .ok_or_else(|| ResolveError::NoSupportedFormat(audio_item.name.clone()))?;
+
+    let cdn_url = CdnUrl::new(file_id).resolve_audio(session).await?;
+
+    let key = match session
+        .audio_key()
+        .request(audio_item.track_id, file_id)
+        .await
+    {
+        Ok(key) => Some(key),
+        Err(e) => {
+            warn!("Unable to load key, continuing without decryption: {}", e);
+            None
+        }
+    };
+
+    let header_offset = if AudioFiles::is_ogg_vorbis(format) {
+        SPOTIFY_OGG_HEADER_END
+    } else {
+        0
+    };
+
+    Ok(ResolvedAudioFile {
+        bytes_per_second: stream_data_rate(format),
+        audio_item,
+        file_id,
+        format,
+        key,
+        cdn_url,
+        header_offset,
+    })
+}
+
+pub(crate) async fn find_available_alternative(
+    session: &Session,
+    audio_item: AudioItem,
+) -> Option<AudioItem> {
+    if let Err(e) = audio_item.availability {
+        error!("Track is unavailable: {}", e);
+        None
+    } else if !audio_item.files.is_empty() {
+        Some(audio_item)
+    } else if let Some(alternatives) = &audio_item.alternatives {
+        let alternatives: FuturesUnordered<_> = alternatives
+            .iter()
+            .map(|alt_id| AudioItem::get_file(session, *alt_id))
+            .collect();
+
+        alternatives
+            .filter_map(|x| future::ready(x.ok()))
+            .filter(|x| future::ready(x.availability.is_ok()))
+            .next()
+            .await
+    } else {
+        error!("Track should be available, but no alternatives found.");
+        None
+    }
+}
+
+/// Returns the format and file of `audio_item` that best match `bitrate`.
+pub fn select_file(audio_item: &AudioItem, bitrate: Bitrate) -> Option<(AudioFileFormat, FileId)> {
+    // (Most) podcasts seem to support only 96 kbps Ogg Vorbis, so fall back to it
+    let formats = match bitrate {
+        Bitrate::Bitrate96 => [
+            AudioFileFormat::OGG_VORBIS_96,
+            AudioFileFormat::MP3_96,
+            AudioFileFormat::OGG_VORBIS_160,
+            AudioFileFormat::MP3_160,
+            AudioFileFormat::MP3_256,
+            AudioFileFormat::OGG_VORBIS_320,
+            AudioFileFormat::MP3_320,
+        ],
+        Bitrate::Bitrate160 => [
+            AudioFileFormat::OGG_VORBIS_160,
+            AudioFileFormat::MP3_160,
+            AudioFileFormat::OGG_VORBIS_96,
+            AudioFileFormat::MP3_96,
+            AudioFileFormat::MP3_256,
+            AudioFileFormat::OGG_VORBIS_320,
+            AudioFileFormat::MP3_320,
+        ],
+        Bitrate::Bitrate320 => [
+            AudioFileFormat::OGG_VORBIS_320,
+            AudioFileFormat::MP3_320,
+            AudioFileFormat::MP3_256,
+            AudioFileFormat::OGG_VORBIS_160,
+            AudioFileFormat::MP3_160,
+            AudioFileFormat::OGG_VORBIS_96,
+            AudioFileFormat::MP3_96,
+        ],
+    };
+
+    formats
+        .iter()
+        .find_map(|format| match audio_item.files.get(format) {
+            Some(&file_id) => Some((*format, file_id)),
+            _ => None,
+        })
+}
+
+/// The nominal data rate of `format` in bytes per second.
+pub fn stream_data_rate(format: AudioFileFormat) -> usize {
+    let kbps = match format {
+        AudioFileFormat::OGG_VORBIS_96 => 12,
+        AudioFileFormat::OGG_VORBIS_160 => 20,
+        AudioFileFormat::OGG_VORBIS_320 => 40,
+        AudioFileFormat::MP3_256 => 32,
+        AudioFileFormat::MP3_320 => 40,
+        AudioFileFormat::MP3_160 => 20,
+        AudioFileFormat::MP3_96 => 12,
+        AudioFileFormat::MP3_160_ENC => 20,
+        AudioFileFormat::AAC_24 => 3,
+        AudioFileFormat::AAC_48 => 6,
+        AudioFileFormat::FLAC_FLAC => 112, // assume 900 kbit/s on average
+    };
+    kbps * 1024
+}
@@ -0,0 +1,236 @@
+#!/usr/bin/env python3
+"""Writes tone.ogg, a short 44.1 kHz stereo Ogg Vorbis file used as a test vector.
+
+There is no encoder involved: the spectrum of every block is set directly, with a flat
+floor and a handful of residue values, so that the output only depends on the decoder.
+Every block is short (2048 samples), which keeps the setup header minimal.
+
+    python3 make_tone.py > tone.ogg
+"""
+
+import struct
+import sys
+
+SAMPLE_RATE = 44100
+CHANNELS = 2
+BLOCKSIZE_EXP = 11
+HALF_BLOCK = 1 << (BLOCKSIZE_EXP - 1)
+PARTITION_SIZE = 32
+PACKETS = 130
+PACKETS_PER_PAGE = 4
+# the end of the last page, which cuts off the padding of the last packet
+END_GRANULE = (PACKETS - 1) * HALF_BLOCK - 96
+SERIAL = 0x5EED
+FLOOR_Y = 160
+
+
+class BitWriter:
+    """Packs bits the way Vorbis does, least significant bit first."""
+
+    def __init__(self):
+        self.data = bytearray()
+        self.bits = 0
+
+    def write(self, value, width):
+        for i in range(width):
+            if self.bits % 8 == 0:
+                self.data.append(0)
+            self.data[-1] |= ((value >> i) & 1) << (self.bits % 8)
+            self.bits += 1
+
+    def write_codeword(self, codeword, length):
+        # Huffman codewords are read from their most significant bit on.
+        for i in reversed(range(length)):
+            self.write((codeword >> i) & 1, 1)
+
+
+def float32_pack(value):
+    sign = 0x80000000 if value < 0 else 0
+    mantissa = abs(int(value))
+    exponent = 788
+    assert mantissa < 1 << 21
+    return sign | (exponent << 21) | mantissa
+
+
+def header(packet_type):
+    return bytes([packet_type]) + b"vorbis"
+
+
+def identification_header():
+    w = BitWriter()
+    w.data += header(1)
+    w.bits = len(w.data) * 8
+    w.write(0, 32)
+    w.write(CHANNELS, 8)
+    w.write(SAMPLE_RATE, 32)
+    w.write(0, 32)
+    w.write(160000, 32)
+    w.write(0, 32)
+    w.write(BLOCKSIZE_EXP, 4)
+    w.write(BLOCKSIZE_EXP, 4)
+    w.write(1, 1)
+    return bytes(w.data)
+
+
+def comment_header():
+    vendor = b"librespot test vector"
+    data = header(3) + struct.pack("<I", len(vendor)) + vendor + struct.pack("<I", 0)
+    return data + b"\x01"
+
+
+def setup_header():
+    w = BitWriter()
+    w.data += header(5)
+    w.bits = len(w.data) * 8
+
+    w.write(2 - 1, 8)
+    # 0: the classbook of the residue, partitions are either class 0 or 1
+    w.write(0x564342, 24)
+    w.write(1, 16)
+    w.write(2, 24)
+    w.write(0, 1)
+    w.write(0, 1)
+    for _ in range(2):
+        w.write(1 - 1, 5)
+    w.write(0, 4)
+    # 1: the residue values, -128..127
+    w.write(0x564342, 24)
+    w.write(1, 16)
+    w.write(256, 24)
+    w.write(0, 1)
+    w.write(0, 1)
+    for _ in range(256):
+        w.write(8 - 1, 5)
+    w.write(1, 4)
+    w.write(float32_pack(-128), 32)
+    w.write(float32_pack(1), 32)
+    w.write(8 - 1, 4)
+    w.write(0, 1)
+    for i in range(256):
+        w.write(i, 8)
+
+    # time domain transforms
+    w.write(1 - 1, 6)
+    w.write(0, 16)
+
+    # one floor 1 with only the two posts at the edges, so a line
+    w.write(1 - 1, 6)
+    w.write(1, 16)
+    w.write(0, 5)
+    w.write(1 - 1, 2)
+    w.write(BLOCKSIZE_EXP - 1, 4)
+
+    # one residue 1 over the whole spectrum: class 0 has values, class 1 is silent
+    w.write(1 - 1, 6)
+    w.write(1, 16)
+    w.write(0, 24)
+    w.write(HALF_BLOCK, 24)
+    w.write(PARTITION_SIZE - 1, 24)
+    w.write(2 - 1, 6)
+    w.write(0, 8)
+    w.write(1, 3)
+    w.write(0, 1)
+    w.write(0, 3)
+    w.write(0, 1)
+    w.write(1, 8)
+
+    # one mapping without coupling
+    w.write(1 - 1, 6)
+    w.write(0, 16)
+    w.write(0, 1)
+    w.write(0, 1)
+    w.write(0, 2)
+    w.write(0, 8)
+    w.write(0, 8)
+    w.write(0, 8)
+
+    # one mode, with short blocks
+    w.write(1 - 1, 6)
+    w.write(0, 1)
+    w.write(0, 16)
+    w.write(0, 16)
+    w.write(0, 8)
+
+    w.write(1, 1)
+    return bytes(w.data)
+
+
+def spectrum(packet, channel):
+    """A few tones per channel that change every 10 blocks."""
+    values = [0] * HALF_BLOCK
+    step = packet // 10
+    if channel == 0:
+        values[20 + 4 * (step % 4)] = 100
+        values[93] = -40
+    else:
+        values[46 + 3 * (step % 5)] = -90
+        values[200 + step] = 30
+    return values
+
+
+def audio_packet(packet):
+    w = BitWriter()
+    w.write(0, 1)
+
+    spectra = [spectrum(packet, channel) for channel in range(CHANNELS)]
+    for _ in spectra:
+        w.write(1, 1)
+        w.write(FLOOR_Y, 8)
+        w.write(FLOOR_Y, 8)
+
+    for start in range(0, HALF_BLOCK, PARTITION_SIZE):
+        silent = [not any(s[start : start + PARTITION_SIZE]) for s in spectra]
+        for is_silent in silent:
+            w.write_codeword(int(is_silent), 1)
+        for s, is_silent in zip(spectra, silent):
+            if not is_silent:
+                for value in s[start : start + PARTITION_SIZE]:
+                    w.write_codeword(value + 128, 8)
+
+    return bytes(w.data)
+
+
+def crc32(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 24
+        for _ in range(8):
+            crc = (crc << 1) ^ 0x04C11DB7 if crc & 0x80000000 else crc << 1
+            crc &= 0xFFFFFFFF
+    return crc
+
+
+def page(packets, granule, sequence, flags):
+    lacing = bytearray()
+    for packet in packets:
+        lacing += b"\xff" * (len(packet) // 255) + bytes([len(packet) % 255])
+    assert len(lacing) <= 255
+    data = (
+        b"OggS"
+        + struct.pack("<BBqIII", 0, flags, granule, SERIAL, sequence, 0)
+        + bytes([len(lacing)])
+        + lacing
+        + b"".join(packets)
+    )
+    return data[:22] + struct.pack("<I", crc32(data)) + data[26:]
+
+
+def main():
+    pages = [
+        page([identification_header()], 0, 0, 0x02),
+        page([comment_header(), setup_header()], 0, 1, 0),
+    ]
+
+    packets = [audio_packet(packet) for packet in range(PACKETS)]
+    for first in range(0, PACKETS, PACKETS_PER_PAGE):
+        last = min(first + PACKETS_PER_PAGE, PACKETS) - 1
+        is_last = last == PACKETS - 1
+        granule = END_GRANULE if is_last else last * HALF_BLOCK
+        flags = 0x04 if is_last else 0
+        pages.append(page(packets[first : last + 1], granule, len(pages), flags))
+
+    sys.stdout.buffer.write(b"".join(pages))
+
+
+if __name__ == "__main__":
+    main()
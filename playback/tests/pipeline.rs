@@ -0,0 +1,371 @@
+//! Runs known inputs through decode → normalise → convert → sink and compares the levels of
+//! the output against golden values, so that changes to the DSP can't go unnoticed.
+//!
+//! The output is compared by the RMS level of a few slices of it, with a tolerance far below
+//! what any change to the DSP makes, but above the rounding differences between the math
+//! libraries of different targets, which an exact checksum would trip over.
+//!
+//! The golden values are kept in `tests/golden/pipeline.txt`. A missing value fails the
+//! test. After adding an input or a case, or an intended change to the output, run the
+//! tests with `LIBRESPOT_BLESS=1` to record the new values, and commit them.
+//!
+//! Besides generated signals, every Ogg Vorbis file in `tests/data` is decoded as a test
+//! vector. They must be 44.1 kHz stereo, like the files Spotify serves.
+//! `tone.ogg` is written by `tests/data/make_tone.py`, so that it's free of any encoder.
+
+use std::{
+    collections::BTreeMap,
+    env, f64,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use librespot_metadata::audio::AudioFileFormat;
+use librespot_playback::{
+    audio_backend,
+    config::{AudioFormat, NormalisationMethod, PlayerConfig},
+    convert::Converter,
+    decoder::{AudioDecoder, AudioPacket, SymphoniaDecoder},
+    dither::{Ditherer, GaussianDitherer, HighPassDitherer, TriangularDitherer},
+    normaliser::Normaliser,
+    SAMPLE_RATE,
+};
+
+const DITHER_SEED: u64 = 0x5eed;
+const PACKET_SIZE: usize = 4096;
+
+// how many slices the output is cut into, and how far their levels may be off
+const SLICES: usize = 16;
+const TOLERANCE: f64 = 1e-6;
+
+fn tpdf() -> Box<dyn Ditherer> {
+    Box::new(TriangularDitherer::with_seed(DITHER_SEED))
+}
+
+fn gpdf() -> Box<dyn Ditherer> {
+    Box::new(GaussianDitherer::with_seed(DITHER_SEED))
+}
+
+fn tpdf_hp() -> Box<dyn Ditherer> {
+    Box::new(HighPassDitherer::with_seed(DITHER_SEED))
+}
+
+struct Case {
+    name: &'static str,
+    config: PlayerConfig,
+    normalisation_factor: f64,
+    volume: f64,
+    format: AudioFormat,
+}
+
+fn cases() -> Vec<Case> {
+    // The default ditherer is seeded randomly, the cases that dither set a seeded one.
+    let plain = PlayerConfig {
+        ditherer: None,
+        ..PlayerConfig::default()
+    };
+    let basic = PlayerConfig {
+        normalisation: true,
+        normalisation_method: NormalisationMethod::Basic,
+        ..plain.clone()
+    };
+    let dynamic = PlayerConfig {
+        normalisation: true,
+        normalisation_method: NormalisationMethod::Dynamic,
+        ..plain.clone()
+    };
+
+    vec![
+        Case {
+            name: "passthrough-f32",
+            config: plain.clone(),
+            normalisation_factor: 1.0,
+            volume: 1.0,
+            format: AudioFormat::F32,
+        },
+        Case {
+            name: "volume-s16",
+            config: plain.clone(),
+            normalisation_factor: 1.0,
+            volume: 0.5,
+            format: AudioFormat::S16,
+        },
+        Case {
+            name: "basic-s16",
+            config: basic.clone(),
+            normalisation_factor: 0.7,
+            volume: 1.0,
+            format: AudioFormat::S16,
+        },
+        Case {
+            name: "dynamic-f64",
+            config: dynamic.clone(),
+            normalisation_factor: 1.4,
+            volume: 1.0,
+            format: AudioFormat::F64,
+        },
+        Case {
+            name: "dynamic-s24-tpdf",
+            config: PlayerConfig {
+                ditherer: Some(tpdf),
+                ..dynamic.clone()
+            },
+            normalisation_factor: 1.4,
+            volume: 0.8,
+            format: AudioFormat::S24,
+        },
+        Case {
+            name: "dynamic-s24_3-gpdf",
+            config: PlayerConfig {
+                ditherer: Some(gpdf),
+                ..dynamic
+            },
+            normalisation_factor: 1.0,
+            volume: 1.0,
+            format: AudioFormat::S24_3,
+        },
+        Case {
+            name: "basic-s16-tpdf_hp",
+            config: PlayerConfig {
+                ditherer: Some(tpdf_hp),
+                ..basic
+            },
+            normalisation_factor: 0.9,
+            volume: 0.25,
+            format: AudioFormat::S16,
+        },
+        Case {
+            name: "volume-s32",
+            config: plain.clone(),
+            normalisation_factor: 1.0,
+            volume: 0.1,
+            format: AudioFormat::S32,
+        },
+    ]
+}
+
+// A second of interleaved stereo samples: a tone that is well below the limiter threshold,
+// a burst that overshoots 0 dBFS, silence, and a quiet tone that is sensitive to dither.
+fn generated_signal() -> Vec<f64> {
+    let frames = SAMPLE_RATE as usize;
+    let mut samples = Vec::with_capacity(frames * 2);
+
+    for frame in 0..frames {
+        let t = frame as f64 / SAMPLE_RATE as f64;
+        let (amplitude, frequency) = match frame * 4 / frames {
+            0 => (0.5, 1000.0),
+            1 => (1.5, 440.0),
+            2 => (0.0, 0.0),
+            _ => (0.001, 3000.0),
+        };
+        let phase = 2.0 * f64::consts::PI * frequency * t;
+        samples.push(amplitude * phase.sin());
+        samples.push(amplitude * phase.cos());
+    }
+
+    samples
+}
+
+fn decoded_signal(path: &Path) -> Vec<f64> {
+    let file = File::open(path).unwrap();
+    let mut decoder = SymphoniaDecoder::new(file, AudioFileFormat::OGG_VORBIS_320)
+        .unwrap_or_else(|e| panic!("cannot decode {path:?}: {e}"));
+
+    let mut samples = Vec::new();
+    while let Some((_, packet)) = decoder.next_packet().unwrap() {
+        if let AudioPacket::Samples(data) = packet {
+            samples.extend_from_slice(&data);
+        }
+    }
+    samples
+}
+
+fn inputs() -> Vec<(String, Vec<f64>)> {
+    let mut inputs = vec![("generated".to_string(), generated_signal())];
+
+    let data_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    if let Ok(entries) = fs::read_dir(data_dir) {
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().map_or(false, |ext| ext == "ogg"))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let samples = decoded_signal(&path);
+            inputs.push((name, samples));
+        }
+    }
+
+    inputs
+}
+
+// Runs `input` through the pipeline the way the player does and returns what the sink wrote.
+fn run_pipeline(case: &Case, input: &[f64], output_path: &Path) -> Vec<u8> {
+    let _ = fs::remove_file(output_path);
+
+    let sink_builder = audio_backend::find(Some("pipe".to_string())).unwrap();
+    let mut sink = sink_builder(
+        Some(output_path.to_string_lossy().into_owned()),
+        case.format,
+    );
+    let mut converter = Converter::new(case.config.ditherer);
+    let mut normaliser = Normaliser::new();
+
+    sink.start().unwrap();
+    for chunk in input.chunks(PACKET_SIZE) {
+        let mut samples = chunk.to_vec();
+        normaliser.process(
+            &case.config,
+            &mut samples,
+            case.normalisation_factor,
+            case.volume,
+        );
+        sink.write(AudioPacket::Samples(samples), &mut converter)
+            .unwrap();
+    }
+    sink.stop().unwrap();
+    drop(sink);
+
+    let output = fs::read(output_path).unwrap();
+    let _ = fs::remove_file(output_path);
+    output
+}
+
+// Reads back what the sink wrote in `format`, from `-1.0` to `1.0`.
+fn samples(format: AudioFormat, output: &[u8]) -> Vec<f64> {
+    fn int(bytes: &[u8]) -> f64 {
+        let mut padded = [0; 4];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        i32::from_be_bytes(padded) as f64 / 2_f64.powi(31)
+    }
+
+    // The integer formats are read from their most significant byte on, and padded below.
+    let reversed = |size: usize| -> Vec<f64> {
+        output
+            .chunks_exact(size)
+            .map(|bytes| {
+                let bytes: Vec<u8> = bytes.iter().rev().copied().collect();
+                int(&bytes)
+            })
+            .collect()
+    };
+
+    match format {
+        AudioFormat::F64 => output
+            .chunks_exact(8)
+            .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect(),
+        AudioFormat::F32 => output
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()) as f64)
+            .collect(),
+        // in the lower three bytes of four
+        AudioFormat::S24 => reversed(4)
+            .into_iter()
+            .map(|sample| sample * 256.0)
+            .collect(),
+        AudioFormat::S32 => reversed(4),
+        AudioFormat::S24_3 => reversed(3),
+        AudioFormat::S16 => reversed(2),
+    }
+}
+
+// The RMS level of each slice of `samples`.
+fn levels(samples: &[f64]) -> Vec<f64> {
+    let size = (samples.len() + SLICES - 1) / SLICES;
+    samples
+        .chunks(size.max(1))
+        .map(|slice| {
+            let power = slice.iter().map(|sample| sample * sample).sum::<f64>();
+            (power / slice.len() as f64).sqrt()
+        })
+        .collect()
+}
+
+fn read_golden(path: &Path) -> BTreeMap<String, Vec<f64>> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?.to_string();
+            let levels = fields
+                .map(|level| level.parse().ok())
+                .collect::<Option<_>>()?;
+            Some((name, levels))
+        })
+        .collect()
+}
+
+fn matches(expected: &[f64], actual: &[f64]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .all(|(expected, actual)| (expected - actual).abs() <= TOLERANCE)
+}
+
+fn write_golden(path: &Path, golden: &BTreeMap<String, Vec<f64>>) {
+    let mut contents = String::from(
+        "# RMS levels of slices of the output of tests/pipeline.rs, on little-endian targets.\n\
+         # Regenerate with `LIBRESPOT_BLESS=1 cargo test -p librespot-playback --test pipeline`.\n",
+    );
+    for (name, levels) in golden {
+        contents.push_str(name);
+        for level in levels {
+            contents.push_str(&format!(" {level:.9}"));
+        }
+        contents.push('\n');
+    }
+
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+#[test]
+#[cfg(target_endian = "little")]
+fn test_pipeline_golden_output() {
+    let golden_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/pipeline.txt");
+    let bless = env::var_os("LIBRESPOT_BLESS").is_some();
+
+    let mut golden = read_golden(&golden_path);
+    let mut mismatches = Vec::new();
+
+    let output_path =
+        env::temp_dir().join(format!("librespot-pipeline-{}.raw", std::process::id()));
+
+    for (input_name, input) in inputs() {
+        for case in cases() {
+            let name = format!("{input_name}/{}", case.name);
+            let output = run_pipeline(&case, &input, &output_path);
+            let actual = levels(&samples(case.format, &output));
+
+            if bless {
+                golden.insert(name, actual);
+                continue;
+            }
+            match golden.get(&name) {
+                Some(expected) if matches(expected, &actual) => (),
+                Some(expected) => {
+                    mismatches.push(format!("{name}: expected {expected:?}, got {actual:?}"))
+                }
+                None => mismatches.push(format!("{name}: no golden output")),
+            }
+        }
+    }
+
+    if bless {
+        write_golden(&golden_path, &golden);
+        eprintln!("Recorded golden output in {golden_path:?}");
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "The output of the audio pipeline changed. If this is intended, run the tests \
+         with LIBRESPOT_BLESS=1 to record the new output.\n{}",
+        mismatches.join("\n")
+    );
+}
@@ -0,0 +1,58 @@
+//! The types most applications need, in one place.
+//!
+//! The workspace crates that are re-exported at the root, like [`crate::core`] and
+//! [`crate::playback`], change between releases as librespot keeps up with Spotify. What is
+//! re-exported here follows semver instead: it is only removed or changed incompatibly in a
+//! major release, after having been deprecated in a minor one. Depend on the crates
+//! themselves for everything else, and expect to adapt to their changes.
+//!
+//! `tests/api.rs` pins these items and the signatures applications use, so CI fails on an
+//! incompatible change. Tools like `cargo semver-checks` don't follow re-exports from other
+//! crates, which is all this module is made of.
+//!
+//! ```no_run
+//! use librespot::api::{Credentials, Session, SessionConfig};
+//!
+//! # async fn run() -> Result<(), librespot::api::Error> {
+//! let session = Session::new(SessionConfig::default(), None);
+//! session
+//!     .connect(Credentials::with_password("user", "password"), false)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+// Sessions and identifiers
+pub use librespot_core::{
+    authentication::Credentials,
+    cache::Cache,
+    config::DeviceType,
+    error::ErrorKind,
+    spotify_id::{SpotifyItemType, SpotifyUser},
+    Error, FileId, Session, SessionConfig, SpotifyId,
+};
+
+// Metadata fetchers
+pub use librespot_metadata::{
+    Album, Artist, Episode, Lyrics, Metadata, Playlist, Show, Track, UserProfile,
+};
+
+// Playback and its events
+#[cfg(feature = "playback")]
+pub use librespot_playback::{
+    audio_backend::{Sink, SinkBuilder},
+    config::{AudioFormat, Bitrate, PlayerConfig, VolumeCtrl},
+    mixer::{Mixer, MixerConfig},
+    player::{Player, PlayerEvent, PlayerEventChannel},
+};
+
+// Spotify Connect
+#[cfg(feature = "connect")]
+pub use librespot_connect::{
+    config::ConnectConfig,
+    spirc::{Spirc, SpircLoadCommand},
+};
+
+// Zeroconf discovery
+#[cfg(feature = "discovery")]
+pub use librespot_discovery::Discovery;
@@ -0,0 +1,344 @@
+use log::{debug, error, warn};
+
+use std::thread;
+
+use futures_util::StreamExt;
+use librespot::core::state::{StateEvent, StateStore};
+use tokio::{runtime::Handle, sync::oneshot};
+
+/// Pauses the other media players on the local system when playback starts, and optionally
+/// resumes them when it is paused or stopped. Uses MPRIS on Linux and the system media
+/// transport controls (SMTC) on Windows.
+pub struct ExclusivePlayback {
+    thread_handle: Option<thread::JoinHandle<()>>,
+    close_tx: Option<oneshot::Sender<()>>,
+}
+
+impl ExclusivePlayback {
+    /// Follows the playback state recorded into `state`. Must be called from within a Tokio
+    /// runtime.
+    pub fn new(state: &StateStore, resume: bool) -> Self {
+        let (_, mut changes) = state.changes();
+        let (close_tx, mut close_rx) = oneshot::channel();
+        let runtime = Handle::current();
+
+        // The platform APIs block, so they are called from a thread of their own. It stops
+        // when dropped, even while the player is still running.
+        let thread_handle = Some(thread::spawn(move || {
+            let mut paused = PausedPlayers::new(resume);
+
+            runtime.block_on(async {
+                loop {
+                    tokio::select! {
+                        _ = &mut close_rx => break,
+                        change = changes.next() => match change {
+                            Some(change) => paused.handle(&SystemPlayers, &change.event),
+                            None => break,
+                        },
+                    }
+                }
+            });
+        }));
+
+        Self {
+            thread_handle,
+            close_tx: Some(close_tx),
+        }
+    }
+}
+
+impl Drop for ExclusivePlayback {
+    fn drop(&mut self) {
+        debug!("Shutting down ExclusivePlayback thread ...");
+        if let Some(close_tx) = self.close_tx.take() {
+            let _ = close_tx.send(());
+        }
+        if let Some(handle) = self.thread_handle.take() {
+            if let Err(e) = handle.join() {
+                error!("ExclusivePlayback thread Error: {:?}", e);
+            }
+        }
+    }
+}
+
+type PlatformResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// The other media players on the local system.
+trait MediaPlayers {
+    /// Pauses the playing media players and returns their names.
+    fn pause_others(&self) -> PlatformResult<Vec<String>>;
+    fn resume(&self, players: &[String]) -> PlatformResult<()>;
+}
+
+struct SystemPlayers;
+
+impl MediaPlayers for SystemPlayers {
+    fn pause_others(&self) -> PlatformResult<Vec<String>> {
+        platform::pause_others()
+    }
+
+    fn resume(&self, players: &[String]) -> PlatformResult<()> {
+        platform::resume(players)
+    }
+}
+
+/// The players we paused, to resume them later.
+struct PausedPlayers {
+    players: Vec<String>,
+    resume: bool,
+}
+
+impl PausedPlayers {
+    fn new(resume: bool) -> Self {
+        Self {
+            players: Vec::new(),
+            resume,
+        }
+    }
+
+    fn handle(&mut self, media_players: &impl MediaPlayers, event: &StateEvent) {
+        match event {
+            StateEvent::Playing { .. } => match media_players.pause_others() {
+                Ok(players) => {
+                    for player in players {
+                        debug!("Paused <{}>", player);
+                        if !self.players.contains(&player) {
+                            self.players.push(player);
+                        }
+                    }
+                }
+                Err(e) => warn!("Unable to pause other media players: {}", e),
+            },
+            StateEvent::Paused { .. } | StateEvent::Stopped if self.resume => {
+                if self.players.is_empty() {
+                    return;
+                }
+                if let Err(e) = media_players.resume(&self.players) {
+                    warn!("Unable to resume other media players: {}", e);
+                }
+                self.players.clear();
+            }
+            _ => (),
+        }
+    }
+}
+
+// Pauses each player with `pause`, which returns whether it was playing. A player that fails,
+// e.g. because it quit in the meantime, doesn't keep the others from being paused.
+#[cfg_attr(not(any(target_os = "linux", windows)), allow(dead_code))]
+fn pause_each<T>(
+    players: impl IntoIterator<Item = (String, T)>,
+    pause: impl Fn(&T) -> PlatformResult<bool>,
+) -> Vec<String> {
+    let mut paused = Vec::new();
+    for (name, player) in players {
+        match pause(&player) {
+            Ok(true) => paused.push(name),
+            Ok(false) => (),
+            Err(e) => debug!("Unable to pause <{}>: {}", name, e),
+        }
+    }
+    paused
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use log::debug;
+    use zbus::blocking::{fdo::DBusProxy, Connection, Proxy};
+
+    use super::{pause_each, PlatformResult};
+
+    const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+    const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+    const MPRIS_PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+
+    fn player<'a>(connection: &Connection, name: &'a str) -> PlatformResult<Proxy<'a>> {
+        Ok(Proxy::new(connection, name, MPRIS_PATH, MPRIS_PLAYER)?)
+    }
+
+    pub fn pause_others() -> PlatformResult<Vec<String>> {
+        let connection = Connection::session()?;
+        let players = DBusProxy::new(&connection)?
+            .list_names()?
+            .into_iter()
+            .map(|name| name.as_str().to_owned())
+            .filter(|name| name.starts_with(MPRIS_PREFIX))
+            .map(|name| (name.clone(), name));
+
+        Ok(pause_each(players, |name| {
+            let player = player(&connection, name)?;
+            let status: String = player.get_property("PlaybackStatus")?;
+            if status != "Playing" {
+                return Ok(false);
+            }
+            player.call_method("Pause", &())?;
+            Ok(true)
+        }))
+    }
+
+    pub fn resume(players: &[String]) -> PlatformResult<()> {
+        let connection = Connection::session()?;
+
+        for name in players {
+            // The player may have quit in the meantime.
+            if let Err(e) = player(&connection, name).and_then(|player| {
+                player.call_method("Play", &())?;
+                Ok(())
+            }) {
+                debug!("Unable to resume <{}>: {}", name, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use log::debug;
+    use windows::Media::Control::{
+        GlobalSystemMediaTransportControlsSession as MediaSession,
+        GlobalSystemMediaTransportControlsSessionManager as MediaSessionManager,
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus as PlaybackStatus,
+    };
+
+    use super::{pause_each, PlatformResult};
+
+    fn sessions() -> PlatformResult<Vec<(String, MediaSession)>> {
+        let manager = MediaSessionManager::RequestAsync()?.get()?;
+
+        let mut sessions = Vec::new();
+        for session in manager.GetSessions()? {
+            let app = session.SourceAppUserModelId()?.to_string();
+            sessions.push((app, session));
+        }
+        Ok(sessions)
+    }
+
+    pub fn pause_others() -> PlatformResult<Vec<String>> {
+        Ok(pause_each(sessions()?, |session| {
+            let status = session.GetPlaybackInfo()?.PlaybackStatus()?;
+            Ok(status == PlaybackStatus::Playing && session.TryPauseAsync()?.get()?)
+        }))
+    }
+
+    pub fn resume(players: &[String]) -> PlatformResult<()> {
+        for (app, session) in sessions()? {
+            if players.contains(&app) {
+                if let Err(e) = session.TryPlayAsync().and_then(|result| result.get()) {
+                    debug!("Unable to resume <{}>: {}", app, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use super::PlatformResult;
+
+    pub fn pause_others() -> PlatformResult<Vec<String>> {
+        Err("pausing other media players is not supported on this platform".into())
+    }
+
+    pub fn resume(_: &[String]) -> PlatformResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use librespot::core::SpotifyId;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakePlayers {
+        playing: RefCell<Vec<String>>,
+        resumed: RefCell<Vec<String>>,
+    }
+
+    impl MediaPlayers for FakePlayers {
+        fn pause_others(&self) -> PlatformResult<Vec<String>> {
+            Ok(self.playing.take())
+        }
+
+        fn resume(&self, players: &[String]) -> PlatformResult<()> {
+            self.resumed.borrow_mut().extend_from_slice(players);
+            Ok(())
+        }
+    }
+
+    fn playing_event() -> StateEvent {
+        StateEvent::Playing {
+            track_id: SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap(),
+            position_ms: 0,
+        }
+    }
+
+    fn paused_event() -> StateEvent {
+        StateEvent::Paused {
+            track_id: SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap(),
+            position_ms: 0,
+        }
+    }
+
+    #[test]
+    fn resumes_paused_players_once() {
+        let players = FakePlayers::default();
+        let mut paused = PausedPlayers::new(true);
+
+        *players.playing.borrow_mut() = vec!["a".to_owned(), "b".to_owned()];
+        paused.handle(&players, &playing_event());
+        *players.playing.borrow_mut() = vec!["a".to_owned()];
+        paused.handle(&players, &playing_event());
+
+        paused.handle(&players, &paused_event());
+        paused.handle(&players, &paused_event());
+        assert_eq!(*players.resumed.borrow(), ["a", "b"]);
+    }
+
+    #[test]
+    fn resumes_when_stopped() {
+        let players = FakePlayers::default();
+        let mut paused = PausedPlayers::new(true);
+
+        *players.playing.borrow_mut() = vec!["a".to_owned()];
+        paused.handle(&players, &playing_event());
+        paused.handle(&players, &StateEvent::VolumeChanged { volume: 0 });
+        assert!(players.resumed.borrow().is_empty());
+
+        paused.handle(&players, &StateEvent::Stopped);
+        assert_eq!(*players.resumed.borrow(), ["a"]);
+    }
+
+    #[test]
+    fn keeps_others_paused_without_resume() {
+        let players = FakePlayers::default();
+        let mut paused = PausedPlayers::new(false);
+
+        *players.playing.borrow_mut() = vec!["a".to_owned()];
+        paused.handle(&players, &playing_event());
+        paused.handle(&players, &paused_event());
+        assert!(players.resumed.borrow().is_empty());
+    }
+
+    #[test]
+    fn skips_players_that_fail() {
+        let players = vec![
+            ("a".to_owned(), 1),
+            ("b".to_owned(), 2),
+            ("c".to_owned(), 3),
+        ];
+        let paused = pause_each(players, |&player| match player {
+            1 => Err("gone".into()),
+            2 => Ok(true),
+            _ => Ok(false),
+        });
+        assert_eq!(paused, ["b"]);
+    }
+}
@@ -1,9 +1,15 @@
 #![crate_name = "librespot"]
 
+pub mod api;
+
+#[cfg(feature = "playback")]
 pub use librespot_audio as audio;
+#[cfg(feature = "connect")]
 pub use librespot_connect as connect;
 pub use librespot_core as core;
+#[cfg(feature = "discovery")]
 pub use librespot_discovery as discovery;
 pub use librespot_metadata as metadata;
+#[cfg(feature = "playback")]
 pub use librespot_playback as playback;
 pub use librespot_protocol as protocol;
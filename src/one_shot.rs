@@ -0,0 +1,116 @@
+use log::info;
+
+use std::time::Duration;
+
+use librespot::connect::remote::Remote;
+use librespot::core::{Error, Session};
+
+// How long to wait for the Connect devices to announce themselves.
+const DEVICES_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub const SUBCOMMANDS: &str = "devices, play [URI], pause, next, prev";
+
+/// An action given as subcommand, which controls another Connect device of the user once
+/// instead of running librespot as a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OneShotAction {
+    Devices,
+    /// Plays the given URI, or resumes playback without one.
+    Play(Option<String>),
+    Pause,
+    Next,
+    Prev,
+}
+
+impl OneShotAction {
+    /// Parses the free arguments of the command line, if there are any.
+    pub fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        let (subcommand, rest) = match args.split_first() {
+            Some((subcommand, rest)) => (subcommand.as_str(), rest),
+            None => return Ok(None),
+        };
+
+        let action = match (subcommand, rest) {
+            ("devices", []) => Self::Devices,
+            ("play", []) => Self::Play(None),
+            ("play", [uri]) => Self::Play(Some(uri.to_owned())),
+            ("pause", []) => Self::Pause,
+            ("next", []) => Self::Next,
+            ("prev", []) => Self::Prev,
+            ("devices" | "play" | "pause" | "next" | "prev", _) => {
+                return Err(format!("Too many arguments for `{subcommand}`: {rest:?}"))
+            }
+            _ => return Err(format!("Unknown subcommand `{subcommand}`")),
+        };
+        Ok(Some(action))
+    }
+
+    /// Performs the action on the device with the given name, or else on the active device,
+    /// through a connected `session`.
+    pub async fn run(&self, session: Session, device_name: Option<&str>) -> Result<(), Error> {
+        let mut remote = Remote::new(session).await;
+
+        if *self == Self::Devices {
+            for device in remote.devices(DEVICES_TIMEOUT).await? {
+                let status = match (device.is_active, device.is_playing) {
+                    (true, true) => "playing",
+                    (true, false) => "active",
+                    _ => "",
+                };
+                let volume = device.volume as u64 * 100 / u16::MAX as u64;
+                println!("{}\t{}%\t{}", device.name, volume, status);
+            }
+            return Ok(());
+        }
+
+        let device = remote.find_device(device_name, DEVICES_TIMEOUT).await?;
+        info!("Controlling <{}>", device.name);
+
+        match self {
+            Self::Devices => Ok(()), // listed above
+            Self::Play(Some(uri)) => remote.load(&device, uri).await,
+            Self::Play(None) => remote.play(&device).await,
+            Self::Pause => remote.pause(&device).await,
+            Self::Next => remote.next(&device).await,
+            Self::Prev => remote.prev(&device).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<OneShotAction>, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        OneShotAction::parse(&args)
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        assert_eq!(parse(&[]), Ok(None));
+        assert_eq!(parse(&["devices"]), Ok(Some(OneShotAction::Devices)));
+        assert_eq!(parse(&["play"]), Ok(Some(OneShotAction::Play(None))));
+        assert_eq!(
+            parse(&["play", "spotify:track:4uLU6hMCjMI75M1A2tKUQC"]),
+            Ok(Some(OneShotAction::Play(Some(
+                "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_owned()
+            ))))
+        );
+        assert_eq!(parse(&["pause"]), Ok(Some(OneShotAction::Pause)));
+        assert_eq!(parse(&["next"]), Ok(Some(OneShotAction::Next)));
+        assert_eq!(parse(&["prev"]), Ok(Some(OneShotAction::Prev)));
+    }
+
+    #[test]
+    fn rejects_unknown_subcommands_and_extra_arguments() {
+        assert!(parse(&["stop"])
+            .unwrap_err()
+            .contains("Unknown subcommand `stop`"));
+        assert!(parse(&["pause", "now"])
+            .unwrap_err()
+            .contains("Too many arguments for `pause`"));
+        assert!(parse(&["play", "a", "b"]).is_err());
+        assert!(parse(&["devices", "all"]).is_err());
+    }
+}
@@ -4,7 +4,10 @@ use std::{collections::HashMap, process::Command, thread};
 
 use librespot::{
     metadata::audio::UniqueFields,
-    playback::player::{PlayerEvent, PlayerEventChannel, SinkStatus},
+    playback::{
+        config::Bitrate,
+        player::{PlayerEvent, PlayerEventChannel, SinkStatus},
+    },
 };
 
 pub struct EventHandler {
@@ -14,12 +17,22 @@ pub struct EventHandler {
 impl EventHandler {
     pub fn new(mut player_events: PlayerEventChannel, onevent: &str) -> Self {
         let on_event = onevent.to_string();
+        let mut dropped_events = 0;
         let thread_handle = Some(thread::spawn(move || loop {
             match player_events.blocking_recv() {
                 None => break,
                 Some(event) => {
                     let mut env_vars = HashMap::new();
 
+                    let newly_dropped = player_events.dropped_events() - dropped_events;
+                    if newly_dropped > 0 {
+                        warn!(
+                            "On event program {} is too slow, {} event(s) were dropped",
+                            on_event, newly_dropped
+                        );
+                        dropped_events += newly_dropped;
+                    }
+
                     match event {
                         PlayerEvent::PlayRequestIdChanged { play_request_id } => {
                             env_vars.insert("PLAYER_EVENT", "play_request_id_changed".to_string());
@@ -157,6 +170,20 @@ impl EventHandler {
                                 env_vars.insert("TRACK_ID", id);
                             }
                         },
+                        PlayerEvent::LoadRetrying {
+                            track_id,
+                            attempt,
+                            retries,
+                            ..
+                        } => match track_id.to_base62() {
+                            Err(e) => warn!("PlayerEvent::LoadRetrying: Invalid track id: {}", e),
+                            Ok(id) => {
+                                env_vars.insert("PLAYER_EVENT", "load_retrying".to_string());
+                                env_vars.insert("TRACK_ID", id);
+                                env_vars.insert("ATTEMPT", attempt.to_string());
+                                env_vars.insert("RETRIES", retries.to_string());
+                            }
+                        },
                         PlayerEvent::Unavailable { track_id, .. } => match track_id.to_base62() {
                             Err(e) => warn!("PlayerEvent::Unavailable: Invalid track id: {}", e),
                             Ok(id) => {
@@ -164,6 +191,62 @@ impl EventHandler {
                                 env_vars.insert("TRACK_ID", id);
                             }
                         },
+                        PlayerEvent::Blocked {
+                            track_id, reason, ..
+                        } => match track_id.to_base62() {
+                            Err(e) => warn!("PlayerEvent::Blocked: Invalid track id: {}", e),
+                            Ok(id) => {
+                                env_vars.insert("PLAYER_EVENT", "blocked".to_string());
+                                env_vars.insert("TRACK_ID", id);
+                                env_vars.insert("REASON", reason.to_string());
+                            }
+                        },
+                        PlayerEvent::Diagnostics {
+                            track_id,
+                            diagnostics,
+                            ..
+                        } => match track_id.to_base62() {
+                            Err(e) => warn!("PlayerEvent::Diagnostics: Invalid track id: {}", e),
+                            Ok(id) => {
+                                env_vars.insert("PLAYER_EVENT", "diagnostics".to_string());
+                                env_vars.insert("TRACK_ID", id);
+                                env_vars.insert("UNDERRUNS", diagnostics.underruns.to_string());
+                                if let Some(throughput) = diagnostics.download_throughput {
+                                    env_vars.insert("DOWNLOAD_THROUGHPUT", throughput.to_string());
+                                }
+                                env_vars.insert(
+                                    "BUFFERED_READ_RATIO",
+                                    format!("{:.3}", diagnostics.buffered_read_ratio),
+                                );
+                                if let Some(duration) = diagnostics.time_to_first_audio {
+                                    env_vars.insert(
+                                        "TIME_TO_FIRST_AUDIO_MS",
+                                        duration.as_millis().to_string(),
+                                    );
+                                }
+                            }
+                        },
+                        PlayerEvent::LoadLatency {
+                            track_id, latency, ..
+                        } => match track_id.to_base62() {
+                            Err(e) => warn!("PlayerEvent::LoadLatency: Invalid track id: {}", e),
+                            Ok(id) => {
+                                env_vars.insert("PLAYER_EVENT", "load_latency".to_string());
+                                env_vars.insert("TRACK_ID", id);
+                                for (name, duration) in [
+                                    ("COMMAND_MS", latency.command),
+                                    ("METADATA_MS", latency.metadata),
+                                    ("AUDIO_FILE_MS", latency.audio_file),
+                                    ("AUDIO_KEY_MS", latency.audio_key),
+                                    ("DECODER_MS", latency.decoder),
+                                    ("BUFFER_MS", latency.buffer),
+                                    ("FIRST_AUDIO_MS", latency.first_audio),
+                                    ("TOTAL_MS", latency.total),
+                                ] {
+                                    env_vars.insert(name, duration.as_millis().to_string());
+                                }
+                            }
+                        },
                         PlayerEvent::VolumeChanged { volume } => {
                             env_vars.insert("PLAYER_EVENT", "volume_changed".to_string());
                             env_vars.insert("VOLUME", volume.to_string());
@@ -222,9 +305,12 @@ impl EventHandler {
                             env_vars.insert("CLIENT_BRAND_NAME", client_brand_name);
                             env_vars.insert("CLIENT_MODEL_NAME", client_model_name);
                         }
-                        PlayerEvent::ShuffleChanged { shuffle } => {
+                        PlayerEvent::ShuffleChanged { shuffle, seed } => {
                             env_vars.insert("PLAYER_EVENT", "shuffle_changed".to_string());
                             env_vars.insert("SHUFFLE", shuffle.to_string());
+                            if let Some(seed) = seed {
+                                env_vars.insert("SHUFFLE_SEED", seed.to_string());
+                            }
                         }
                         PlayerEvent::RepeatChanged { repeat } => {
                             env_vars.insert("PLAYER_EVENT", "repeat_changed".to_string());
@@ -242,6 +328,44 @@ impl EventHandler {
                             );
                             env_vars.insert("FILTER", filter.to_string());
                         }
+                        PlayerEvent::AudioFormatChanged {
+                            sample_rate,
+                            source_sample_rate,
+                        } => {
+                            env_vars.insert("PLAYER_EVENT", "audio_format_changed".to_string());
+                            env_vars.insert("SAMPLE_RATE", sample_rate.to_string());
+                            env_vars.insert("SOURCE_SAMPLE_RATE", source_sample_rate.to_string());
+                        }
+                        PlayerEvent::BitrateChanged {
+                            bitrate,
+                            download_throughput,
+                        } => {
+                            let kbps = match bitrate {
+                                Bitrate::Bitrate96 => "96",
+                                Bitrate::Bitrate160 => "160",
+                                Bitrate::Bitrate320 => "320",
+                            };
+                            env_vars.insert("PLAYER_EVENT", "bitrate_changed".to_string());
+                            env_vars.insert("BITRATE", kbps.to_string());
+                            env_vars.insert("DOWNLOAD_THROUGHPUT", download_throughput.to_string());
+                        }
+                        PlayerEvent::ContextSegment { uri, metadata } => {
+                            let mut metadata: Vec<String> = metadata
+                                .into_iter()
+                                .map(|(key, value)| format!("{}={}", key, value))
+                                .collect();
+                            metadata.sort();
+
+                            env_vars.insert("PLAYER_EVENT", "context_segment".to_string());
+                            env_vars.insert("URI", uri);
+                            env_vars.insert("METADATA", metadata.join("\n"));
+                        }
+                        PlayerEvent::AnnouncementStarted => {
+                            env_vars.insert("PLAYER_EVENT", "announcement_started".to_string());
+                        }
+                        PlayerEvent::AnnouncementFinished => {
+                            env_vars.insert("PLAYER_EVENT", "announcement_finished".to_string());
+                        }
                     }
 
                     if !env_vars.is_empty() {
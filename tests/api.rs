@@ -0,0 +1,140 @@
+//! Pins what `librespot::api` re-exports, and the signatures applications rely on, so that
+//! an incompatible change to it fails to compile here. Changing what this file expects
+//! takes a major release.
+
+use std::path::PathBuf;
+#[cfg(feature = "playback")]
+use std::sync::Arc;
+
+use librespot::api::{
+    Album, Artist, Cache, Credentials, DeviceType, Episode, Error, ErrorKind, FileId, Lyrics,
+    Metadata, Playlist, Session, SessionConfig, Show, SpotifyId, SpotifyItemType, SpotifyUser,
+    Track, UserProfile,
+};
+
+#[cfg(feature = "playback")]
+use librespot::api::{
+    AudioFormat, Bitrate, Mixer, MixerConfig, Player, PlayerConfig, PlayerEvent,
+    PlayerEventChannel, Sink, SinkBuilder, VolumeCtrl,
+};
+
+#[cfg(feature = "connect")]
+use librespot::api::{ConnectConfig, Spirc, SpircLoadCommand};
+
+#[cfg(feature = "discovery")]
+use librespot::api::Discovery;
+
+async fn connect(session: &Session, credentials: Credentials) -> Result<(), Error> {
+    session.connect(credentials, false).await
+}
+
+async fn metadata<T: Metadata>(session: &Session, id: &T::Id) -> Result<T, Error> {
+    T::get(session, id).await
+}
+
+async fn user_profile(session: &Session, user: &SpotifyUser) -> Result<UserProfile, Error> {
+    UserProfile::get(session, user).await
+}
+
+async fn lyrics(session: &Session, id: &SpotifyId) -> Result<Lyrics, Error> {
+    Lyrics::get(session, id).await
+}
+
+#[cfg(feature = "playback")]
+fn player(session: Session, mixer: &dyn Mixer, sink: SinkBuilder) -> Arc<Player> {
+    Player::new(
+        PlayerConfig::default(),
+        session,
+        mixer.get_soft_volume(),
+        move || sink(None, AudioFormat::default()),
+    )
+}
+
+#[cfg(feature = "playback")]
+async fn next_event(events: &mut PlayerEventChannel) -> Option<PlayerEvent> {
+    events.recv().await
+}
+
+#[cfg(feature = "connect")]
+async fn spirc(
+    session: Session,
+    credentials: Credentials,
+    player: Arc<Player>,
+    mixer: Arc<dyn Mixer>,
+) -> Result<Spirc, Error> {
+    let (spirc, task) = Spirc::new(
+        ConnectConfig::default(),
+        session,
+        credentials,
+        player,
+        mixer,
+    )
+    .await?;
+    tokio::spawn(task);
+    spirc.play()?;
+    Ok(spirc)
+}
+
+#[cfg(feature = "discovery")]
+async fn discover(device_id: &str, client_id: &str) -> Result<Option<Credentials>, Error> {
+    use futures_util::StreamExt;
+
+    let mut discovery = Discovery::new(device_id, client_id)?;
+    Ok(discovery.next().await)
+}
+
+#[test]
+fn sessions_and_identifiers() {
+    let _: fn(SessionConfig, Option<Cache>) -> Session = Session::new;
+    let _ = |path: PathBuf| -> Result<Cache, Error> {
+        Cache::new(Some(&path), Some(&path), Some(&path), Some(1 << 30))
+    };
+    let _: fn(String, String) -> Credentials = Credentials::with_password;
+    let _ = connect;
+
+    let id = SpotifyId::from_uri("spotify:track:4GNcXTGWmnZ3ySrqvol3o4").unwrap();
+    assert_eq!(id.item_type, SpotifyItemType::Track);
+    assert_eq!(id.to_base62().unwrap(), "4GNcXTGWmnZ3ySrqvol3o4");
+    let _: fn(&SpotifyUser) -> String = SpotifyUser::to_uri;
+    let _: fn(&FileId) -> Result<String, Error> = FileId::to_base16;
+    let _: DeviceType = DeviceType::default();
+    let _: ErrorKind = Error::unavailable("").kind;
+}
+
+#[test]
+fn metadata_fetchers() {
+    let _ = metadata::<Album>;
+    let _ = metadata::<Artist>;
+    let _ = metadata::<Episode>;
+    let _ = metadata::<Playlist>;
+    let _ = metadata::<Show>;
+    let _ = metadata::<Track>;
+    let _ = user_profile;
+    let _ = lyrics;
+}
+
+#[cfg(feature = "playback")]
+#[test]
+fn playback_and_its_events() {
+    let _ = player;
+    let _ = next_event;
+    let _: fn(SinkBuilder) -> Box<dyn Sink> = |sink| sink(None, AudioFormat::S16);
+    let _ = (
+        Bitrate::default(),
+        MixerConfig::default(),
+        VolumeCtrl::default(),
+    );
+}
+
+#[cfg(feature = "connect")]
+#[test]
+fn spotify_connect() {
+    let _ = spirc;
+    let _: fn(SpircLoadCommand) -> bool = |command| command.start_playing;
+}
+
+#[cfg(feature = "discovery")]
+#[test]
+fn zeroconf_discovery() {
+    let _ = discover;
+}